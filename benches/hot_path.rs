@@ -0,0 +1,182 @@
+// Benchmarks for the work done on every request.
+//
+// Run with `cargo bench`, or `cargo bench -- <group>` for a single group.
+// Covers picking a node, reading the cache, normalizing requests and fanning
+// subscription events out to users. Compare runs with criterion's saved
+// baselines (`--save-baseline`/`--baseline`) before merging changes to any
+// of them.
+use blutgang_core::{
+    balancer::{
+        cache_entry::{
+            insert_entry,
+            CacheKey,
+        },
+        format::{
+            enforce_jsonrpc,
+            normalize_block_param,
+        },
+        hot_cache::{
+            get_tiered,
+            HotCache,
+        },
+        selection::select::{
+            pick,
+            route_available,
+        },
+    },
+    config::types::JsonRpcMode,
+    websocket::types::{
+        RequestResult,
+        SubscriptionData,
+    },
+    Rpc,
+};
+
+use criterion::{
+    black_box,
+    criterion_group,
+    criterion_main,
+    BenchmarkId,
+    Criterion,
+};
+use serde_json::{
+    json,
+    Value,
+};
+use tokio::sync::mpsc;
+
+fn rpc_list(len: usize) -> Vec<Rpc> {
+    (0..len)
+        .map(|i| {
+            let mut rpc = Rpc::new(format!("http://node{}:8545", i), None, 0, 0, 1.0);
+            rpc.update_latency(10.0 + i as f64);
+            rpc
+        })
+        .collect()
+}
+
+fn call(number: u64) -> Value {
+    json!({
+        "jsonrpc": "2.0",
+        "id": 1,
+        "method": "eth_getBlockByNumber",
+        "params": [format!("0x{:x}", number), false],
+    })
+}
+
+fn routing(c: &mut Criterion) {
+    let mut group = c.benchmark_group("routing");
+    let tx = call(1);
+    for len in [2, 8, 32] {
+        let mut list = rpc_list(len);
+        group.bench_with_input(BenchmarkId::new("pick", len), &len, |b, _| {
+            b.iter(|| black_box(pick(&mut list)))
+        });
+        group.bench_with_input(BenchmarkId::new("route_available", len), &len, |b, _| {
+            b.iter(|| {
+                let picked = pick(&mut list);
+                black_box(route_available(&list, picked, &tx))
+            })
+        });
+    }
+    group.finish();
+}
+
+fn cache_lookup(c: &mut Criterion) {
+    let mut group = c.benchmark_group("cache_lookup");
+    let cache = sled::Config::new().temporary(true).open().unwrap();
+    let response = json!({"jsonrpc": "2.0", "id": 1, "result": {"number": "0x1"}}).to_string();
+    let key = CacheKey::new(&call(1));
+    insert_entry(&cache, &key, response.as_bytes()).unwrap();
+
+    group.bench_function("key", |b| b.iter(|| black_box(CacheKey::new(&call(1)))));
+
+    let cold = HotCache::new(0);
+    group.bench_function("sled", |b| {
+        b.iter(|| black_box(get_tiered(&cache, &cold, &key).unwrap()))
+    });
+
+    let hot = HotCache::new(1024 * 1024);
+    get_tiered(&cache, &hot, &key).unwrap();
+    group.bench_function("hot", |b| {
+        b.iter(|| black_box(get_tiered(&cache, &hot, &key).unwrap()))
+    });
+
+    let missing = CacheKey::new(&call(2));
+    group.bench_function("miss", |b| {
+        b.iter(|| black_box(get_tiered(&cache, &cold, &missing).unwrap()))
+    });
+    group.finish();
+}
+
+fn normalization(c: &mut Criterion) {
+    let mut group = c.benchmark_group("normalization");
+    // No `jsonrpc` member and an EIP-1898 block parameter
+    let sloppy = json!({
+        "id": 1,
+        "method": "eth_getBalance",
+        "params": ["0xAbC", {"blockHash": "0xABCDEF", "requireCanonical": false}],
+    });
+
+    group.bench_function("enforce_jsonrpc", |b| {
+        b.iter(|| {
+            let mut tx = sloppy.clone();
+            black_box(enforce_jsonrpc(&mut tx, JsonRpcMode::Lenient).is_ok())
+        })
+    });
+    group.bench_function("normalize_block_param", |b| {
+        b.iter(|| {
+            let mut tx = sloppy.clone();
+            normalize_block_param(&mut tx);
+            black_box(tx)
+        })
+    });
+    group.finish();
+}
+
+fn subscription_fan_out(c: &mut Criterion) {
+    let mut group = c.benchmark_group("subscription_fan_out");
+    let request = json!({"method": "eth_subscribe", "params": ["newHeads"]});
+    let event = RequestResult::Subscription(json!({
+        "jsonrpc": "2.0",
+        "method": "eth_subscription",
+        "params": {
+            "subscription": "0x1",
+            "result": {"number": "0x1", "hash": "0x1", "parentHash": "0x0"},
+        },
+    }));
+
+    for users in [1, 100, 1000] {
+        let sub_data = SubscriptionData::new();
+        sub_data.register_subscription(request.clone(), "0x1".to_string(), 0);
+        let mut receivers: Vec<_> = (0..users)
+            .map(|user_id| {
+                let (tx, rx) = mpsc::unbounded_channel();
+                sub_data.add_user(user_id, tx);
+                sub_data.subscribe_user(user_id, request.clone()).unwrap();
+                rx
+            })
+            .collect();
+
+        group.bench_with_input(BenchmarkId::from_parameter(users), &users, |b, _| {
+            b.iter(|| {
+                futures::executor::block_on(sub_data.dispatch_to_subscribers("0x1", 0, &event))
+                    .unwrap();
+                // Keep the queues from growing between iterations
+                for rx in receivers.iter_mut() {
+                    while rx.try_recv().is_ok() {}
+                }
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    routing,
+    cache_lookup,
+    normalization,
+    subscription_fan_out
+);
+criterion_main!(benches);
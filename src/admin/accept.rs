@@ -22,6 +22,7 @@ use serde_json::{
 };
 
 use std::{
+    collections::BTreeMap,
     convert::Infallible,
     sync::{
         Arc,
@@ -33,8 +34,30 @@ use std::{
 use sled::Db;
 
 use crate::{
-    admin::methods::execute_method,
-    balancer::format::incoming_to_value,
+    admin::{
+        methods::execute_method,
+        metrics::prometheus_metrics,
+    },
+    balancer::format::{
+        enforce_jsonrpc,
+        incoming_to_value,
+    },
+    balancer::{
+        cache_entry::CacheKey,
+        ens::EnsCache,
+        hot_cache::HotCache,
+        memory::MemoryBudget,
+    },
+    beacon::types::BeaconPool,
+    config::types::UsageFormat,
+    health::{
+        anomaly::AnomalyDetector,
+        maintenance::Maintenance,
+    },
+    notify::usage::{
+        export,
+        UsageTracker,
+    },
     Rpc,
     Settings,
 };
@@ -48,12 +71,12 @@ use crate::{
 
 // For decoding JWT
 #[derive(Debug, Serialize, Deserialize)]
-struct Claims {
-    id: Value,
-    jsonrpc: Value,
-    method: Value,
-    params: Value,
-    exp: usize,
+pub(crate) struct Claims {
+    pub(crate) id: Value,
+    pub(crate) jsonrpc: Value,
+    pub(crate) method: Value,
+    pub(crate) params: Value,
+    pub(crate) exp: usize,
 }
 
 // Macro for getting responses from either the cache or RPC nodes.
@@ -68,6 +91,11 @@ macro_rules! get_response {
         $poverty_list_rwlock:expr,
         $config:expr,
         $cache:expr,
+        $memory:expr,
+        $ens:expr,
+        $head_cache:expr,
+        $anomaly:expr,
+        $maintenance:expr,
     ) => {{
         // Execute the request and store it into rx
         let mut rx = match execute_method(
@@ -76,6 +104,11 @@ macro_rules! get_response {
             $poverty_list_rwlock,
             Arc::clone(&$config),
             Arc::clone(&$cache),
+            $memory,
+            $ens,
+            $head_cache,
+            $anomaly,
+            $maintenance,
         ).await {
             Ok(rx) => rx,
             Err(err) => json!({
@@ -95,12 +128,18 @@ macro_rules! get_response {
 }
 
 // Execute request and construct a HTTP response
+#[allow(clippy::too_many_arguments)]
 async fn forward_body(
     mut tx: Value,
     rpc_list_rwlock: &Arc<RwLock<Vec<Rpc>>>,
     poverty_list_rwlock: &Arc<RwLock<Vec<Rpc>>>,
     cache: Arc<Db>,
     config: Arc<RwLock<Settings>>,
+    memory: &Arc<MemoryBudget>,
+    ens: &EnsCache,
+    head_cache: &Arc<RwLock<BTreeMap<u64, Vec<CacheKey>>>>,
+    anomaly: &Arc<AnomalyDetector>,
+    maintenance: &Arc<Maintenance>,
 ) -> Result<hyper::Response<Full<Bytes>>, Infallible> {
     // Get the id of the request and set it to 0 for caching
     //
@@ -110,7 +149,19 @@ async fn forward_body(
     let id = tx["id"].take().as_u64().unwrap_or(0);
 
     // Get the response from either the DB or from a RPC. If it timeouts, retry.
-    let rax = get_response!(tx, id, rpc_list_rwlock, poverty_list_rwlock, config, cache,);
+    let rax = get_response!(
+        tx,
+        id,
+        rpc_list_rwlock,
+        poverty_list_rwlock,
+        config,
+        cache,
+        memory,
+        ens,
+        head_cache,
+        anomaly,
+        maintenance,
+    );
 
     // Convert rx to bytes and but it in a Buf
     let body = hyper::body::Bytes::from(rax);
@@ -124,14 +175,132 @@ async fn forward_body(
     Ok(res)
 }
 
+const USAGE_PATH: &str = "/usage";
+
+// Method tokens for `GET /usage` have to be signed for
+const USAGE_METHOD: &str = "blutgang_usage";
+
+// Method tokens for `GET /metrics` have to be signed for
+const METRICS_METHOD: &str = "blutgang_metrics";
+
+// With JWT enabled, plain GETs carry a token signed for `method` in an
+// `Authorization: Bearer` header
+fn bearer_authorized<B>(tx: &Request<B>, config: &Arc<RwLock<Settings>>, method: &str) -> bool {
+    let config_guard = config.read().unwrap();
+    if !config_guard.admin.jwt {
+        return true;
+    }
+
+    let token = tx
+        .headers()
+        .get("authorization")
+        .and_then(|header| header.to_str().ok())
+        .and_then(|header| header.strip_prefix("Bearer "))
+        .unwrap_or_default();
+    decode::<Claims>(token, &config_guard.admin.key, &Validation::default())
+        .is_ok_and(|token| token.claims.method == method)
+}
+
+// `GET /usage?format=csv&day=2026-10-17`. Both parameters are optional, and
+// we export every day we have in CSV if they're not set. With JWT enabled,
+// the token goes in an `Authorization: Bearer` header.
+fn usage_export<B>(
+    tx: &Request<B>,
+    config: &Arc<RwLock<Settings>>,
+    usage: &UsageTracker,
+) -> hyper::Response<Full<Bytes>> {
+    let response = |status: u16, content_type: &str, body: String| {
+        hyper::Response::builder()
+            .status(status)
+            .header("Content-Type", content_type)
+            .body(Full::new(Bytes::from(body)))
+            .unwrap()
+    };
+
+    if !bearer_authorized(tx, config, USAGE_METHOD) {
+        return response(
+            401,
+            "text/plain",
+            "Unauthorized or invalid token".to_string(),
+        );
+    }
+
+    if !usage.is_enabled() {
+        return response(404, "text/plain", "Usage tracking is disabled".to_string());
+    }
+
+    let mut format = UsageFormat::Csv;
+    let mut day = None;
+    let query = tx.uri().query().unwrap_or_default();
+    for (key, value) in url::form_urlencoded::parse(query.as_bytes()) {
+        match key.as_ref() {
+            "format" => {
+                match value.parse() {
+                    Ok(parsed) => format = parsed,
+                    Err(_) => {
+                        return response(
+                            400,
+                            "text/plain",
+                            "format must be csv or json".to_string(),
+                        )
+                    }
+                }
+            }
+            "day" => {
+                match value.parse() {
+                    Ok(parsed) => day = Some(parsed),
+                    Err(_) => {
+                        return response(400, "text/plain", "day must be YYYY-MM-DD".to_string())
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    let (body, content_type) = export(&usage.rows(day), format);
+    response(200, content_type, body)
+}
+
 // Accept admin request, self explanatory
+#[allow(clippy::too_many_arguments)]
 pub async fn accept_admin_request(
     tx: Request<hyper::body::Incoming>,
     rpc_list_rwlock: Arc<RwLock<Vec<Rpc>>>,
     poverty_list_rwlock: Arc<RwLock<Vec<Rpc>>>,
     cache: Arc<Db>,
     config: Arc<RwLock<Settings>>,
+    memory: Arc<MemoryBudget>,
+    ens: Arc<EnsCache>,
+    head_cache: Arc<RwLock<BTreeMap<u64, Vec<CacheKey>>>>,
+    anomaly: Arc<AnomalyDetector>,
+    usage: Arc<UsageTracker>,
+    hot: Arc<HotCache>,
+    maintenance: Arc<Maintenance>,
+    beacon: Arc<BeaconPool>,
 ) -> Result<hyper::Response<Full<Bytes>>, Infallible> {
+    // Prometheus scrapes metrics with a plain GET
+    if tx.method() == hyper::Method::GET && tx.uri().path() == "/metrics" {
+        if !bearer_authorized(&tx, &config, METRICS_METHOD) {
+            return Ok(hyper::Response::builder()
+                .status(401)
+                .body(Full::new(Bytes::from("Unauthorized or invalid token")))
+                .unwrap());
+        }
+
+        let metrics = prometheus_metrics(&rpc_list_rwlock, &poverty_list_rwlock, &hot, &beacon);
+        return Ok(hyper::Response::builder()
+            .status(200)
+            .header("Content-Type", "text/plain; version=0.0.4")
+            .body(Full::new(Bytes::from(metrics)))
+            .unwrap());
+    }
+
+    // Usage exports for billing
+    if tx.method() == hyper::Method::GET && tx.uri().path() == USAGE_PATH {
+        return Ok(usage_export(&tx, &config, &usage));
+    }
+
     let mut tx = incoming_to_value(tx).await.unwrap();
 
     // If we have JWT enabled check that tx is valid
@@ -165,9 +334,31 @@ pub async fn accept_admin_request(
         });
     }
 
+    // Check the request against the JSON-RPC spec if enabled
+    let jsonrpc_mode = config.read().unwrap().admin.jsonrpc_mode;
+    if let Err(err) = enforce_jsonrpc(&mut tx, jsonrpc_mode) {
+        return Ok(hyper::Response::builder()
+            .status(400)
+            .header("Content-Type", "application/json")
+            .body(Full::new(Bytes::from(err.to_string())))
+            .unwrap());
+    }
+
     // Send the request off to be processed
     let time = Instant::now();
-    let response = forward_body(tx, &rpc_list_rwlock, &poverty_list_rwlock, cache, config).await;
+    let response = forward_body(
+        tx,
+        &rpc_list_rwlock,
+        &poverty_list_rwlock,
+        cache,
+        config,
+        &memory,
+        &ens,
+        &head_cache,
+        &anomaly,
+        &maintenance,
+    )
+    .await;
     let time = time.elapsed();
     log_info!("Request time: {:?}", time);
 
@@ -181,8 +372,10 @@ mod tests {
 
     // Helper function to create a test Settings config
     fn create_test_settings() -> Arc<RwLock<Settings>> {
-        let mut config = Settings::default();
-        config.do_clear = true;
+        let mut config = Settings {
+            do_clear: true,
+            ..Default::default()
+        };
         config.admin.key = DecodingKey::from_secret(b"some-key");
         Arc::new(RwLock::new(config))
     }
@@ -217,6 +410,11 @@ mod tests {
             &poverty_list,
             cache.clone(),
             settings,
+            &Arc::new(MemoryBudget::default()),
+            &EnsCache::default(),
+            &Arc::new(RwLock::new(BTreeMap::new())),
+            &Arc::new(AnomalyDetector::default()),
+            &Arc::new(Maintenance::default()),
         )
         .await;
 
@@ -225,4 +423,121 @@ mod tests {
 
         // Additional assertions can be added based on expected behavior
     }
+
+    #[test]
+    fn test_usage_export() {
+        let settings = create_test_settings();
+        let usage = UsageTracker::new(Some(Default::default()));
+        usage.record(Some("indexer"), "eth_call");
+        let request = |uri: &str, token: Option<&str>| {
+            let mut request = Request::builder().uri(uri);
+            if let Some(token) = token {
+                request = request.header("authorization", format!("Bearer {}", token));
+            }
+            request.body(()).unwrap()
+        };
+
+        let response = usage_export(&request("/usage?format=json", None), &settings, &usage);
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.headers()["Content-Type"], "application/json");
+        assert_eq!(
+            usage_export(&request("/usage?day=yesterday", None), &settings, &usage).status(),
+            400
+        );
+        assert_eq!(
+            usage_export(
+                &request("/usage", None),
+                &settings,
+                &UsageTracker::default()
+            )
+            .status(),
+            404
+        );
+
+        // Tokens have to be signed for `blutgang_usage`
+        settings.write().unwrap().admin.jwt = true;
+        let token = |method: &str| {
+            jsonwebtoken::encode(
+                &jsonwebtoken::Header::default(),
+                &Claims {
+                    id: json!(1),
+                    jsonrpc: json!("2.0"),
+                    method: json!(method),
+                    params: json!([]),
+                    exp: 10_000_000_000,
+                },
+                &jsonwebtoken::EncodingKey::from_secret(b"some-key"),
+            )
+            .unwrap()
+        };
+        assert_eq!(
+            usage_export(&request("/usage", None), &settings, &usage).status(),
+            401
+        );
+        assert_eq!(
+            usage_export(
+                &request("/usage", Some(&token("blutgang_ttl"))),
+                &settings,
+                &usage
+            )
+            .status(),
+            401
+        );
+        assert_eq!(
+            usage_export(
+                &request("/usage", Some(&token(USAGE_METHOD))),
+                &settings,
+                &usage
+            )
+            .status(),
+            200
+        );
+    }
+
+    #[test]
+    fn test_metrics_auth() {
+        let settings = create_test_settings();
+        let request = |token: Option<&str>| {
+            let mut request = Request::builder().uri("/metrics");
+            if let Some(token) = token {
+                request = request.header("authorization", format!("Bearer {}", token));
+            }
+            request.body(()).unwrap()
+        };
+        let token = |method: &str| {
+            jsonwebtoken::encode(
+                &jsonwebtoken::Header::default(),
+                &Claims {
+                    id: json!(1),
+                    jsonrpc: json!("2.0"),
+                    method: json!(method),
+                    params: json!([]),
+                    exp: 10_000_000_000,
+                },
+                &jsonwebtoken::EncodingKey::from_secret(b"some-key"),
+            )
+            .unwrap()
+        };
+
+        // Open without JWT
+        assert!(bearer_authorized(&request(None), &settings, METRICS_METHOD));
+
+        // Tokens have to be signed for `blutgang_metrics`
+        settings.write().unwrap().admin.jwt = true;
+        assert!(!bearer_authorized(
+            &request(None),
+            &settings,
+            METRICS_METHOD
+        ));
+        assert!(!bearer_authorized(
+            &request(Some(&token(USAGE_METHOD))),
+            &settings,
+            METRICS_METHOD
+        ));
+        assert!(bearer_authorized(
+            &request(Some(&token(METRICS_METHOD))),
+            &settings,
+            METRICS_METHOD
+        ));
+    }
 }
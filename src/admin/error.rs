@@ -1,4 +1,6 @@
 // Errors
+use crate::balancer::snapshot::SnapshotError;
+
 use std::error::Error;
 
 #[derive(Debug)]
@@ -14,6 +16,9 @@ pub enum AdminError {
     Inaccessible,
     OutOfBounds,
     InvalidResponse(String),
+    EnsCacheDisabled,
+    UnknownRpc(String),
+    Snapshot(SnapshotError),
 }
 
 impl std::fmt::Display for AdminError {
@@ -31,6 +36,11 @@ impl std::fmt::Display for AdminError {
                 write!(f, "Request out of bounds.")
             }
             AdminError::InvalidResponse(reason) => write!(f, "Invalid RPC response: {}", reason),
+            AdminError::EnsCacheDisabled => {
+                write!(f, "ENS cache is disabled, set ens_cache_ttl to enable it")
+            }
+            AdminError::UnknownRpc(name) => write!(f, "Unknown RPC: {}", name),
+            AdminError::Snapshot(e) => write!(f, "{}", e),
         }
     }
 }
@@ -0,0 +1,89 @@
+// Process wide gauges for `/metrics`.
+//
+// These count things that come and go all over the place, like requests we're
+// answering or WS users we're connected to, so they live here instead of being
+// passed to everything that changes them. Together with the in flight requests
+// of every node they show how close we are to capacity before requests start
+// timing out.
+use std::sync::atomic::{
+    AtomicU64,
+    Ordering,
+};
+
+// HTTP requests from clients we haven't answered yet
+pub static REQUESTS_IN_FLIGHT: Gauge = Gauge::new();
+// Connected WS clients
+pub static WS_CONNECTIONS: Gauge = Gauge::new();
+// Messages from node WS connections the subscription dispatcher hasn't read yet
+pub static WS_RESPONSE_QUEUE: Gauge = Gauge::new();
+// Subscription events waiting on a dispatch worker, one per user they go to
+pub static FANOUT_BACKLOG: Gauge = Gauge::new();
+
+#[derive(Debug, Default)]
+pub struct Gauge(AtomicU64);
+
+impl Gauge {
+    pub const fn new() -> Self {
+        Gauge(AtomicU64::new(0))
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    pub fn set(&self, value: u64) {
+        self.0.store(value, Ordering::Relaxed);
+    }
+
+    pub fn add(&self, value: u64) {
+        self.0.fetch_add(value, Ordering::Relaxed);
+    }
+
+    pub fn sub(&self, value: u64) {
+        // Never wraps, even if something is taken out twice
+        let _ = self
+            .0
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |current| {
+                Some(current.saturating_sub(value))
+            });
+    }
+
+    // Counts one until the guard is dropped
+    pub fn track(&self) -> Tracked<'_> {
+        self.add(1);
+        Tracked(self)
+    }
+}
+
+pub struct Tracked<'a>(&'a Gauge);
+
+impl Drop for Tracked<'_> {
+    fn drop(&mut self) {
+        self.0.sub(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_gauge() {
+        let gauge = Gauge::new();
+        {
+            let _first = gauge.track();
+            let _second = gauge.track();
+            assert_eq!(gauge.get(), 2);
+        }
+        assert_eq!(gauge.get(), 0);
+
+        gauge.add(5);
+        gauge.sub(3);
+        assert_eq!(gauge.get(), 2);
+        gauge.sub(3);
+        assert_eq!(gauge.get(), 0);
+
+        gauge.set(7);
+        assert_eq!(gauge.get(), 7);
+    }
+}
@@ -1,13 +1,28 @@
-use std::sync::{
-    Arc,
-    RwLock,
+use std::{
+    collections::BTreeMap,
+    sync::{
+        Arc,
+        RwLock,
+    },
 };
 
 use sled::Db;
 
 use crate::{
     admin::accept::accept_admin_request,
+    balancer::{
+        cache_entry::CacheKey,
+        ens::EnsCache,
+        hot_cache::HotCache,
+        memory::MemoryBudget,
+    },
+    beacon::types::BeaconPool,
+    health::{
+        anomaly::AnomalyDetector,
+        maintenance::Maintenance,
+    },
     log_info,
+    notify::usage::UsageTracker,
     Rpc,
     Settings,
 };
@@ -26,6 +41,14 @@ macro_rules! accept_admin {
         $poverty_list_rwlock:expr,
         $cache:expr,
         $config:expr,
+        $memory:expr,
+        $ens:expr,
+        $head_cache:expr,
+        $anomaly:expr,
+        $usage:expr,
+        $hot:expr,
+        $maintenance:expr,
+        $beacon:expr,
     ) => {
         // Bind the incoming connection to our service
         if let Err(err) = http1::Builder::new()
@@ -39,6 +62,14 @@ macro_rules! accept_admin {
                         Arc::clone($poverty_list_rwlock),
                         Arc::clone($cache),
                         Arc::clone($config),
+                        Arc::clone($memory),
+                        Arc::clone($ens),
+                        Arc::clone($head_cache),
+                        Arc::clone($anomaly),
+                        Arc::clone($usage),
+                        Arc::clone($hot),
+                        Arc::clone($maintenance),
+                        Arc::clone($beacon),
                     );
                     response
                 }),
@@ -53,11 +84,20 @@ macro_rules! accept_admin {
 // Used for listening to admin requests as its own tokio task.
 //
 // Similar to what you'd find in main/balancer
+#[allow(clippy::too_many_arguments)]
 pub async fn listen_for_admin_requests(
     rpc_list_rwlock: Arc<RwLock<Vec<Rpc>>>,
     poverty_list_rwlock: Arc<RwLock<Vec<Rpc>>>,
     cache: Arc<Db>,
     config: Arc<RwLock<Settings>>,
+    memory: Arc<MemoryBudget>,
+    ens: Arc<EnsCache>,
+    head_cache: Arc<RwLock<BTreeMap<u64, Vec<CacheKey>>>>,
+    anomaly: Arc<AnomalyDetector>,
+    usage: Arc<UsageTracker>,
+    hot: Arc<HotCache>,
+    maintenance: Arc<Maintenance>,
+    beacon: Arc<BeaconPool>,
 ) -> Result<(), Box<dyn std::error::Error>> {
     let address;
     {
@@ -81,6 +121,14 @@ pub async fn listen_for_admin_requests(
         let poverty_list_rwlock_clone = Arc::clone(&poverty_list_rwlock);
         let cache_clone = Arc::clone(&cache);
         let config_clone = Arc::clone(&config);
+        let memory_clone = Arc::clone(&memory);
+        let ens_clone = Arc::clone(&ens);
+        let head_cache_clone = Arc::clone(&head_cache);
+        let anomaly_clone = Arc::clone(&anomaly);
+        let usage_clone = Arc::clone(&usage);
+        let hot_clone = Arc::clone(&hot);
+        let maintenance_clone = Arc::clone(&maintenance);
+        let beacon_clone = Arc::clone(&beacon);
 
         // Spawn a tokio task to serve multiple connections concurrently
         tokio::task::spawn(async move {
@@ -90,6 +138,14 @@ pub async fn listen_for_admin_requests(
                 &poverty_list_rwlock_clone,
                 &cache_clone,
                 &config_clone,
+                &memory_clone,
+                &ens_clone,
+                &head_cache_clone,
+                &anomaly_clone,
+                &usage_clone,
+                &hot_clone,
+                &maintenance_clone,
+                &beacon_clone,
             );
         });
     }
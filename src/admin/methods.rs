@@ -1,15 +1,59 @@
 use crate::{
-    admin::error::AdminError,
+    admin::{
+        error::AdminError,
+        metrics::{
+            latency_report,
+            score_report,
+        },
+    },
+    balancer::{
+        cache_entry::{
+            evict_entries,
+            lookup_entry,
+            CacheKey,
+        },
+        ens::EnsCache,
+        format::normalize_block_param,
+        memory::MemoryBudget,
+        snapshot::{
+            export_cache_to_file,
+            import_cache_from_file,
+        },
+    },
+    config::{
+        system::{
+            debug_enabled,
+            log_level,
+            set_debug,
+            set_log_level,
+            DebugModule,
+            LogLevel,
+            DEBUG_MODULES,
+        },
+        types::CanarySettings,
+        validate::{
+            diff_config,
+            validate_config,
+        },
+    },
+    health::{
+        anomaly::AnomalyDetector,
+        maintenance::Maintenance,
+    },
     Rpc,
     Settings,
 };
 
 use std::{
+    collections::BTreeMap,
     sync::{
         Arc,
         RwLock,
     },
-    time::Instant,
+    time::{
+        Duration,
+        Instant,
+    },
 };
 
 use serde_json::{
@@ -20,13 +64,52 @@ use serde_json::{
 
 use sled::Db;
 
+// Every admin method, and whether it's blocked when `readonly` is set
+pub const ADMIN_METHODS: [(&str, bool); 29] = [
+    ("blutgang_quit", true),
+    ("blutgang_rpc_list", false),
+    ("blutgang_flush_cache", true),
+    ("blutgang_export_cache", true),
+    ("blutgang_import_cache", true),
+    ("blutgang_config", false),
+    ("blutgang_validate_config", false),
+    ("blutgang_poverty_list", false),
+    ("blutgang_quarantine_list", false),
+    ("blutgang_release_from_quarantine", true),
+    ("blutgang_ttl", false),
+    ("blutgang_memory", false),
+    ("blutgang_latency", false),
+    ("blutgang_scores", false),
+    ("blutgang_ens_prewarm", false),
+    ("blutgang_health_check_ttl", false),
+    ("blutgang_set_ttl", true),
+    ("blutgang_log_level", false),
+    ("blutgang_set_log_level", true),
+    ("blutgang_set_debug", true),
+    ("blutgang_set_health_check_ttl", true),
+    ("blutgang_add_to_rpc_list", true),
+    ("blutgang_add_to_poverty_list", true),
+    ("blutgang_remove_from_rpc_list", true),
+    ("blutgang_remove_from_poverty_list", true),
+    ("blutgang_drainRpc", true),
+    ("blutgang_enableRpc", true),
+    ("blutgang_cacheLookup", false),
+    ("blutgang_cacheEvict", true),
+];
+
 // Extract the method, call the appropriate function and return the response
+#[allow(clippy::too_many_arguments)]
 pub async fn execute_method(
     tx: Value,
     rpc_list: &Arc<RwLock<Vec<Rpc>>>,
     poverty_list: &Arc<RwLock<Vec<Rpc>>>,
     config: Arc<RwLock<Settings>>,
     cache: Arc<Db>,
+    memory: &Arc<MemoryBudget>,
+    ens: &EnsCache,
+    head_cache: &Arc<RwLock<BTreeMap<u64, Vec<CacheKey>>>>,
+    anomaly: &AnomalyDetector,
+    maintenance: &Maintenance,
 ) -> Result<Value, AdminError> {
     let method = tx["method"].as_str();
     println!("Method: {:?}", method.unwrap_or("None"));
@@ -50,9 +133,40 @@ pub async fn execute_method(
                 admin_flush_cache(cache).await
             }
         }
+        Some("blutgang_export_cache") => {
+            if write_protection_enabled {
+                Err(AdminError::WriteProtectionEnabled)
+            } else {
+                admin_export_cache(cache, head_cache, tx["params"].as_array()).await
+            }
+        }
+        Some("blutgang_import_cache") => {
+            if write_protection_enabled {
+                Err(AdminError::WriteProtectionEnabled)
+            } else {
+                admin_import_cache(cache, memory, tx["params"].as_array()).await
+            }
+        }
         Some("blutgang_config") => admin_config(config),
+        Some("blutgang_validate_config") => {
+            admin_validate_config(config, rpc_list, poverty_list, tx["params"].as_array())
+        }
         Some("blutgang_poverty_list") => admin_list_rpc(poverty_list),
+        Some("blutgang_quarantine_list") => admin_quarantine_list(anomaly),
+        Some("blutgang_release_from_quarantine") => {
+            if write_protection_enabled {
+                Err(AdminError::WriteProtectionEnabled)
+            } else {
+                admin_release_from_quarantine(rpc_list, anomaly, tx["params"].as_array())
+            }
+        }
         Some("blutgang_ttl") => admin_blutgang_ttl(config),
+        Some("blutgang_memory") => admin_blutgang_memory(memory),
+        Some("blutgang_latency") => admin_blutgang_latency(rpc_list, poverty_list),
+        Some("blutgang_scores") => admin_blutgang_scores(rpc_list, poverty_list),
+        Some("blutgang_ens_prewarm") => {
+            admin_blutgang_ens_prewarm(rpc_list, ens, tx["params"].as_array()).await
+        }
         Some("blutgang_health_check_ttl") => admin_blutgang_health_check_ttl(config),
         Some("blutgang_set_ttl") => {
             if write_protection_enabled {
@@ -61,6 +175,21 @@ pub async fn execute_method(
                 admin_blutgang_set_ttl(config, tx["params"].as_array())
             }
         }
+        Some("blutgang_log_level") => admin_blutgang_log_level(),
+        Some("blutgang_set_log_level") => {
+            if write_protection_enabled {
+                Err(AdminError::WriteProtectionEnabled)
+            } else {
+                admin_blutgang_set_log_level(tx["params"].as_array())
+            }
+        }
+        Some("blutgang_set_debug") => {
+            if write_protection_enabled {
+                Err(AdminError::WriteProtectionEnabled)
+            } else {
+                admin_blutgang_set_debug(tx["params"].as_array())
+            }
+        }
         Some("blutgang_set_health_check_ttl") => {
             if write_protection_enabled {
                 Err(AdminError::WriteProtectionEnabled)
@@ -72,14 +201,15 @@ pub async fn execute_method(
             if write_protection_enabled {
                 Err(AdminError::WriteProtectionEnabled)
             } else {
-                admin_add_rpc(rpc_list, tx["params"].as_array())
+                let canary = config.read().unwrap().canary.clone();
+                admin_add_rpc(rpc_list, canary.as_ref(), tx["params"].as_array())
             }
         }
         Some("blutgang_add_to_poverty_list") => {
             if write_protection_enabled {
                 Err(AdminError::WriteProtectionEnabled)
             } else {
-                admin_add_rpc(poverty_list, tx["params"].as_array())
+                admin_add_rpc(poverty_list, None, tx["params"].as_array())
             }
         }
         Some("blutgang_remove_from_rpc_list") => {
@@ -96,11 +226,138 @@ pub async fn execute_method(
                 admin_remove_rpc(poverty_list, tx["params"].as_array())
             }
         }
+        Some("blutgang_drainRpc") => {
+            if write_protection_enabled {
+                Err(AdminError::WriteProtectionEnabled)
+            } else {
+                admin_drain_rpc(
+                    rpc_list,
+                    poverty_list,
+                    config,
+                    maintenance,
+                    tx["params"].as_array(),
+                )
+                .await
+            }
+        }
+        Some("blutgang_enableRpc") => {
+            if write_protection_enabled {
+                Err(AdminError::WriteProtectionEnabled)
+            } else {
+                admin_enable_rpc(rpc_list, maintenance, tx["params"].as_array())
+            }
+        }
+        Some("blutgang_cacheLookup") => {
+            admin_cache_lookup(&cache, head_cache, tx["params"].as_array())
+        }
+        Some("blutgang_cacheEvict") => {
+            if write_protection_enabled {
+                Err(AdminError::WriteProtectionEnabled)
+            } else {
+                admin_cache_evict(cache, memory, tx["params"].as_array()).await
+            }
+        }
         Some(_) => Err(AdminError::InvalidMethod),
         _ => Ok(().into()),
     }
 }
 
+// Whether a request is cached, since when and who answered it.
+//
+// Params are the method and params of the request as a client would send
+// them, and optionally the namespace of the tenant that sent it.
+fn admin_cache_lookup(
+    cache: &Db,
+    head_cache: &Arc<RwLock<BTreeMap<u64, Vec<CacheKey>>>>,
+    params: Option<&Vec<Value>>,
+) -> Result<Value, AdminError> {
+    let params = match params {
+        Some(params) if (1..=3).contains(&params.len()) => params,
+        _ => return Err(AdminError::InvalidLen),
+    };
+    let method = params[0].as_str().ok_or(AdminError::ParseError)?;
+    let namespace = match params.get(2) {
+        Some(namespace) => Some(namespace.as_str().ok_or(AdminError::ParseError)?),
+        None => None,
+    };
+
+    // Keyed the same way as requests we get from clients
+    let mut request = json!({
+        "jsonrpc": "2.0",
+        "id": Null,
+        "method": method,
+        "params": params.get(1).cloned().unwrap_or(json!([])),
+    });
+    normalize_block_param(&mut request);
+    let key = match namespace {
+        Some(namespace) => CacheKey::namespaced(namespace, &request),
+        None => CacheKey::new(&request),
+    };
+
+    let result = match lookup_entry(cache, &key).map_err(|_| AdminError::RwError)? {
+        Some((response, meta)) => {
+            // Unfinalized entries get dropped if their block reorgs
+            let block = head_cache
+                .read()
+                .unwrap()
+                .iter()
+                .find(|(_, keys)| keys.contains(&key))
+                .map(|(block, _)| *block);
+            let response: Value = serde_json::from_slice(&response).unwrap_or(Null);
+            json!({
+                "key": key.to_string(),
+                "cached": true,
+                "response": response,
+                "storedAt": meta.as_ref().map(|meta| meta.stored_at),
+                "age": meta.as_ref().map(|meta| meta.age()),
+                "source": meta.as_ref().and_then(|meta| meta.source.clone()),
+                "unfinalizedBlock": block,
+            })
+        }
+        None => json!({"key": key.to_string(), "cached": false}),
+    };
+
+    Ok(json!({
+        "id": Null,
+        "jsonrpc": "2.0",
+        "result": result,
+    }))
+}
+
+// Evict a cache entry by its key, or every entry for the methods matching a
+// pattern like `eth_getBalance` or `eth_get*`
+async fn admin_cache_evict(
+    cache: Arc<Db>,
+    memory: &Arc<MemoryBudget>,
+    params: Option<&Vec<Value>>,
+) -> Result<Value, AdminError> {
+    let pattern = match params {
+        Some(params) if params.len() == 1 => {
+            params[0]
+                .as_str()
+                .ok_or(AdminError::ParseError)?
+                .to_string()
+        }
+        _ => return Err(AdminError::InvalidLen),
+    };
+    if pattern.is_empty() {
+        return Err(AdminError::InvalidParams);
+    }
+    let memory = Arc::clone(memory);
+
+    // Method patterns scan the whole cache
+    let evicted = tokio::task::spawn_blocking(move || evict_entries(&cache, &memory, &pattern))
+        .await
+        .map_err(|_| AdminError::Inaccessible)?
+        .map_err(|_| AdminError::RwError)?;
+
+    Ok(json!({
+        "id": Null,
+        "jsonrpc": "2.0",
+        "result": {"evicted": evicted},
+    }))
+}
+
 // Quit Blutgang upon receiving this method
 // We're returning a Null and allowing unreachable code so rustc doesnt cry
 #[allow(unreachable_code)]
@@ -117,6 +374,152 @@ async fn admin_blutgang_quit(cache: Arc<Db>) -> Result<Value, AdminError> {
     Ok(Value::Null)
 }
 
+// Memory used by cached responses and subscription buffers
+fn admin_blutgang_memory(memory: &MemoryBudget) -> Result<Value, AdminError> {
+    let rx = json!({
+        "id": Null,
+        "jsonrpc": "2.0",
+        "result": memory.stats(),
+    });
+
+    Ok(rx)
+}
+
+// Latency percentiles of every node, by method
+fn admin_blutgang_latency(
+    rpc_list: &Arc<RwLock<Vec<Rpc>>>,
+    poverty_list: &Arc<RwLock<Vec<Rpc>>>,
+) -> Result<Value, AdminError> {
+    let rx = json!({
+        "id": Null,
+        "jsonrpc": "2.0",
+        "result": latency_report(rpc_list, poverty_list),
+    });
+
+    Ok(rx)
+}
+
+fn admin_blutgang_scores(
+    rpc_list: &Arc<RwLock<Vec<Rpc>>>,
+    poverty_list: &Arc<RwLock<Vec<Rpc>>>,
+) -> Result<Value, AdminError> {
+    let rx = json!({
+        "id": Null,
+        "jsonrpc": "2.0",
+        "result": score_report(rpc_list, poverty_list),
+    });
+
+    Ok(rx)
+}
+
+// Path of the snapshot file in `params`
+fn snapshot_path(params: Option<&Vec<Value>>) -> Result<String, AdminError> {
+    let params = match params {
+        Some(params) => params,
+        None => return Err(AdminError::InvalidParams),
+    };
+
+    if params.len() != 1 {
+        return Err(AdminError::InvalidLen);
+    }
+
+    match params[0].as_str() {
+        Some(path) => Ok(path.to_string()),
+        None => Err(AdminError::ParseError),
+    }
+}
+
+// Write a snapshot of the finalized part of the cache to disk
+async fn admin_export_cache(
+    cache: Arc<Db>,
+    head_cache: &Arc<RwLock<BTreeMap<u64, Vec<CacheKey>>>>,
+    params: Option<&Vec<Value>>,
+) -> Result<Value, AdminError> {
+    let path = snapshot_path(params)?;
+    let head_cache = head_cache.read().unwrap().clone();
+
+    let exported =
+        tokio::task::spawn_blocking(move || export_cache_to_file(&cache, &head_cache, path))
+            .await
+            .map_err(|_| AdminError::Inaccessible)?
+            .map_err(AdminError::Snapshot)?;
+
+    let rx = json!({
+        "id": Null,
+        "jsonrpc": "2.0",
+        "result": {"exported": exported},
+    });
+
+    Ok(rx)
+}
+
+// Add entries from a snapshot made by another instance to our cache
+async fn admin_import_cache(
+    cache: Arc<Db>,
+    memory: &Arc<MemoryBudget>,
+    params: Option<&Vec<Value>>,
+) -> Result<Value, AdminError> {
+    let path = snapshot_path(params)?;
+    let memory = Arc::clone(memory);
+
+    let imported =
+        tokio::task::spawn_blocking(move || import_cache_from_file(&cache, &memory, path))
+            .await
+            .map_err(|_| AdminError::Inaccessible)?
+            .map_err(AdminError::Snapshot)?;
+
+    let rx = json!({
+        "id": Null,
+        "jsonrpc": "2.0",
+        "result": {"imported": imported},
+    });
+
+    Ok(rx)
+}
+
+// Resolve ENS names ahead of time so lookups for them hit the ENS cache
+async fn admin_blutgang_ens_prewarm(
+    rpc_list: &Arc<RwLock<Vec<Rpc>>>,
+    ens: &EnsCache,
+    params: Option<&Vec<Value>>,
+) -> Result<Value, AdminError> {
+    if !ens.is_enabled() {
+        return Err(AdminError::EnsCacheDisabled);
+    }
+
+    let names = match params {
+        Some(params) if !params.is_empty() => params,
+        _ => return Err(AdminError::InvalidLen),
+    };
+    let names = names
+        .iter()
+        .map(|name| name.as_str().ok_or(AdminError::ParseError))
+        .collect::<Result<Vec<&str>, AdminError>>()?;
+
+    let rpc = match rpc_list.read().unwrap().first() {
+        Some(rpc) => rpc.clone(),
+        None => return Err(AdminError::Inaccessible),
+    };
+
+    let mut failed = Vec::new();
+    for name in &names {
+        if let Err(e) = ens.prewarm(&rpc, name).await {
+            failed.push(json!({"name": name, "error": e.to_string()}));
+        }
+    }
+
+    let rx = json!({
+        "id": Null,
+        "jsonrpc": "2.0",
+        "result": {
+            "warmed": names.len() - failed.len(),
+            "failed": failed,
+        },
+    });
+
+    Ok(rx)
+}
+
 // Flushes sled cache to disk
 async fn admin_flush_cache(cache: Arc<Db>) -> Result<Value, AdminError> {
     let time = Instant::now();
@@ -154,6 +557,49 @@ fn admin_config(config: Arc<RwLock<Settings>>) -> Result<Value, AdminError> {
     Ok(rx)
 }
 
+// Parse a proposed config and report what applying it would change, without applying it
+fn admin_validate_config(
+    config: Arc<RwLock<Settings>>,
+    rpc_list: &Arc<RwLock<Vec<Rpc>>>,
+    poverty_list: &Arc<RwLock<Vec<Rpc>>>,
+    params: Option<&Vec<Value>>,
+) -> Result<Value, AdminError> {
+    let params = match params {
+        Some(params) => params,
+        None => return Err(AdminError::InvalidParams),
+    };
+
+    if params.len() != 1 {
+        return Err(AdminError::InvalidLen);
+    }
+
+    let proposed = match params[0].as_str() {
+        Some(proposed) => proposed,
+        None => return Err(AdminError::ParseError),
+    };
+
+    let result = match validate_config(proposed) {
+        Ok(proposed) => {
+            // Nodes in the poverty list are still ours, just unhealthy
+            let mut nodes = rpc_list.read().unwrap().clone();
+            nodes.extend(poverty_list.read().unwrap().iter().cloned());
+
+            let mut diff = diff_config(&config.read().unwrap(), &nodes, &proposed);
+            diff["valid"] = true.into();
+            diff
+        }
+        Err(e) => json!({"valid": false, "error": e.to_string()}),
+    };
+
+    let rx = json!({
+        "id": Null,
+        "jsonrpc": "2.0",
+        "result": result,
+    });
+
+    Ok(rx)
+}
+
 // List generic Fn to retrieve RPCs from an Arc<RwLock<Vec<Rpc>>>
 // Used for `blutgang_rpc_list` and `blutgang_poverty_list`
 fn admin_list_rpc(rpc_list: &Arc<RwLock<Vec<Rpc>>>) -> Result<Value, AdminError> {
@@ -185,7 +631,135 @@ fn admin_list_rpc(rpc_list: &Arc<RwLock<Vec<Rpc>>>) -> Result<Value, AdminError>
     Ok(rx)
 }
 
-// Pushes an RPC to the end of the list
+// Responds with the nodes quarantined for serving anomalous data
+fn admin_quarantine_list(anomaly: &AnomalyDetector) -> Result<Value, AdminError> {
+    let quarantined: Vec<Value> = anomaly
+        .quarantined()
+        .into_iter()
+        .map(|quarantined| {
+            json!({
+                "name": quarantined.rpc.name,
+                "reason": quarantined.reason,
+                "since": quarantined.since,
+            })
+        })
+        .collect();
+
+    let rx = json!({
+        "id": Null,
+        "jsonrpc": "2.0",
+        "result": quarantined,
+    });
+
+    Ok(rx)
+}
+
+// Moves a quarantined RPC back to the active pool
+//
+// param[0] - index in the quarantine list
+fn admin_release_from_quarantine(
+    rpc_list: &Arc<RwLock<Vec<Rpc>>>,
+    anomaly: &AnomalyDetector,
+    params: Option<&Vec<Value>>,
+) -> Result<Value, AdminError> {
+    let params = match params {
+        Some(params) => params,
+        None => return Err(AdminError::InvalidParams),
+    };
+
+    if params.len() != 1 {
+        return Err(AdminError::InvalidLen);
+    }
+
+    let index = match params[0].to_string().replace('\"', "").parse::<usize>() {
+        Ok(index) => index,
+        Err(_) => return Err(AdminError::ParseError),
+    };
+
+    let rpc = anomaly.release(index).ok_or(AdminError::OutOfBounds)?;
+    let name = rpc.name.clone();
+    rpc_list
+        .write()
+        .map_err(|_| AdminError::Inaccessible)?
+        .push(rpc);
+
+    let rx = json!({
+        "id": Null,
+        "jsonrpc": "2.0",
+        "result": name,
+    });
+
+    Ok(rx)
+}
+
+// Name of the RPC in `params`
+fn rpc_name(params: Option<&Vec<Value>>) -> Result<String, AdminError> {
+    let params = match params {
+        Some(params) => params,
+        None => return Err(AdminError::InvalidParams),
+    };
+
+    if params.len() != 1 {
+        return Err(AdminError::InvalidLen);
+    }
+
+    match params[0].as_str() {
+        Some(name) => Ok(name.to_string()),
+        None => Err(AdminError::ParseError),
+    }
+}
+
+// Takes an RPC out of rotation for maintenance, regardless of its health.
+// Waits up to `ttl` for requests it's still serving.
+//
+// param[0] - RPC name
+async fn admin_drain_rpc(
+    rpc_list: &Arc<RwLock<Vec<Rpc>>>,
+    poverty_list: &Arc<RwLock<Vec<Rpc>>>,
+    config: Arc<RwLock<Settings>>,
+    maintenance: &Maintenance,
+    params: Option<&Vec<Value>>,
+) -> Result<Value, AdminError> {
+    let name = rpc_name(params)?;
+    let wait = Duration::from_millis(config.read().unwrap().ttl.try_into().unwrap_or(u64::MAX));
+
+    let in_flight = maintenance
+        .drain(rpc_list, poverty_list, &name, wait)
+        .await
+        .ok_or_else(|| AdminError::UnknownRpc(name.clone()))?;
+
+    let rx = json!({
+        "id": Null,
+        "jsonrpc": "2.0",
+        "result": {"name": name, "inFlight": in_flight},
+    });
+
+    Ok(rx)
+}
+
+// Puts a drained RPC back in rotation
+//
+// param[0] - RPC name
+fn admin_enable_rpc(
+    rpc_list: &Arc<RwLock<Vec<Rpc>>>,
+    maintenance: &Maintenance,
+    params: Option<&Vec<Value>>,
+) -> Result<Value, AdminError> {
+    let name = rpc_name(params)?;
+    if !maintenance.enable(rpc_list, &name) {
+        return Err(AdminError::UnknownRpc(name));
+    }
+
+    let rx = json!({
+        "id": Null,
+        "jsonrpc": "2.0",
+        "result": name,
+    });
+
+    Ok(rx)
+}
+
+// Pushes an RPC to the end of the list, ramping it up if `canary` is set
 //
 // param[0] - RPC url
 // param[1] - max_consecutive
@@ -193,6 +767,7 @@ fn admin_list_rpc(rpc_list: &Arc<RwLock<Vec<Rpc>>>) -> Result<Value, AdminError>
 // param[3] - ma_len
 fn admin_add_rpc(
     rpc_list: &Arc<RwLock<Vec<Rpc>>>,
+    canary: Option<&CanarySettings>,
     params: Option<&Vec<Value>>,
 ) -> Result<Value, AdminError> {
     let params = match params {
@@ -236,19 +811,20 @@ fn admin_add_rpc(
         .parse::<f64>()
         .unwrap_or(0.0);
 
-    if delta != 0 {
-        delta = 1_000_000 / delta;
-    }
+    delta = 1_000_000u64.checked_div(delta).unwrap_or(0);
 
     let mut rpc_list = rpc_list.write().map_err(|_| AdminError::Inaccessible)?;
 
-    rpc_list.push(Rpc::new(
-        rpc.to_string(),
-        ws_url,
-        max_consecutive,
-        delta.into(),
-        ma_len,
-    ));
+    rpc_list.push(
+        Rpc::new(
+            rpc.to_string(),
+            ws_url,
+            max_consecutive,
+            delta.into(),
+            ma_len,
+        )
+        .with_canary(canary),
+    );
 
     let rx = json!({
         "id": Null,
@@ -391,9 +967,82 @@ fn admin_blutgang_set_ttl(
     Ok(rx)
 }
 
+// Responds with the log level and which debug modules are on
+fn admin_blutgang_log_level() -> Result<Value, AdminError> {
+    let debug: Vec<&str> = DEBUG_MODULES
+        .into_iter()
+        .filter(|module| debug_enabled(*module))
+        .map(|module| module.name())
+        .collect();
+
+    let rx = json!({
+        "id": Null,
+        "jsonrpc": "2.0",
+        "result": {
+            "level": format!("{:?}", log_level()).to_lowercase(),
+            "debug": debug,
+        },
+    });
+
+    Ok(rx)
+}
+
+// Sets the log level
+//
+// param[0] - `error`, `warn` or `info`
+fn admin_blutgang_set_log_level(params: Option<&Vec<Value>>) -> Result<Value, AdminError> {
+    let params = match params {
+        Some(params) => params,
+        None => return Err(AdminError::InvalidParams),
+    };
+
+    if params.len() != 1 {
+        return Err(AdminError::InvalidLen);
+    }
+
+    let level = match params[0].as_str().map(str::parse::<LogLevel>) {
+        Some(Ok(level)) => level,
+        _ => return Err(AdminError::ParseError),
+    };
+    set_log_level(level);
+
+    admin_blutgang_log_level()
+}
+
+// Toggles debug logs for a module
+//
+// param[0] - `http`, `rpc` or `ws`
+// param[1] - bool, enable or disable
+fn admin_blutgang_set_debug(params: Option<&Vec<Value>>) -> Result<Value, AdminError> {
+    let params = match params {
+        Some(params) => params,
+        None => return Err(AdminError::InvalidParams),
+    };
+
+    if params.len() != 2 {
+        return Err(AdminError::InvalidLen);
+    }
+
+    let module = match params[0].as_str().map(str::parse::<DebugModule>) {
+        Some(Ok(module)) => module,
+        _ => return Err(AdminError::ParseError),
+    };
+    let enabled = match params[1].as_bool() {
+        Some(enabled) => enabled,
+        None => return Err(AdminError::ParseError),
+    };
+    set_debug(module, enabled);
+
+    admin_blutgang_log_level()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::balancer::cache_entry::{
+        insert_entry_with_meta,
+        EntryMeta,
+    };
     use jsonwebtoken::DecodingKey;
 
     // Helper function to create a test RPC list
@@ -420,12 +1069,19 @@ mod tests {
 
     // Helper function to create a test Settings config
     fn create_test_settings_config() -> Arc<RwLock<Settings>> {
-        let mut config = Settings::default();
-        config.do_clear = true;
+        let mut config = Settings {
+            do_clear: true,
+            ..Default::default()
+        };
         config.admin.key = DecodingKey::from_secret(b"some-key");
         Arc::new(RwLock::new(config))
     }
 
+    // Helper function to create a test head cache
+    fn create_test_head_cache() -> Arc<RwLock<BTreeMap<u64, Vec<CacheKey>>>> {
+        Arc::new(RwLock::new(BTreeMap::new()))
+    }
+
     // Helper function to create a test cache
     fn create_test_cache() -> Arc<Db> {
         let db = sled::Config::new().temporary(true);
@@ -447,6 +1103,11 @@ mod tests {
             &create_test_poverty_list(),
             create_test_settings_config(),
             cache,
+            &Arc::new(MemoryBudget::default()),
+            &EnsCache::default(),
+            &create_test_head_cache(),
+            &AnomalyDetector::default(),
+            &Maintenance::default(),
         )
         .await;
 
@@ -467,6 +1128,11 @@ mod tests {
             &create_test_poverty_list(),
             create_test_settings_config(),
             cache,
+            &Arc::new(MemoryBudget::default()),
+            &EnsCache::default(),
+            &create_test_head_cache(),
+            &AnomalyDetector::default(),
+            &Maintenance::default(),
         )
         .await;
 
@@ -487,6 +1153,11 @@ mod tests {
             &create_test_poverty_list(),
             create_test_settings_config(),
             cache,
+            &Arc::new(MemoryBudget::default()),
+            &EnsCache::default(),
+            &create_test_head_cache(),
+            &AnomalyDetector::default(),
+            &Maintenance::default(),
         )
         .await;
 
@@ -507,6 +1178,11 @@ mod tests {
             &create_test_poverty_list(),
             create_test_settings_config(),
             cache,
+            &Arc::new(MemoryBudget::default()),
+            &EnsCache::default(),
+            &create_test_head_cache(),
+            &AnomalyDetector::default(),
+            &Maintenance::default(),
         )
         .await;
 
@@ -514,6 +1190,293 @@ mod tests {
         assert!(result.is_ok());
     }
 
+    #[tokio::test]
+    async fn test_execute_method_blutgang_memory() {
+        // Arrange
+        let cache = create_test_cache();
+        let tx = json!({ "id":1,"method": "blutgang_memory" });
+        let memory = Arc::new(MemoryBudget::new(Some(1000), Default::default()));
+        memory.record_insert(b"key", 100);
+
+        // Act
+        let result = execute_method(
+            tx,
+            &create_test_rpc_list(),
+            &create_test_poverty_list(),
+            create_test_settings_config(),
+            cache,
+            &memory,
+            &EnsCache::default(),
+            &create_test_head_cache(),
+            &AnomalyDetector::default(),
+            &Maintenance::default(),
+        )
+        .await;
+
+        // Assert
+        let result = result.unwrap();
+        assert_eq!(result["result"]["budget"], 1000);
+        assert_eq!(result["result"]["cacheBytes"], 100);
+        assert_eq!(result["result"]["policy"], "lru");
+    }
+
+    #[tokio::test]
+    async fn test_execute_method_blutgang_latency() {
+        // Arrange
+        let rpc_list = create_test_rpc_list();
+        rpc_list.read().unwrap()[0]
+            .status
+            .methods
+            .record("eth_call", std::time::Duration::from_millis(4));
+        let tx = json!({ "id":1,"method": "blutgang_latency" });
+
+        // Act
+        let result = execute_method(
+            tx,
+            &rpc_list,
+            &create_test_poverty_list(),
+            create_test_settings_config(),
+            create_test_cache(),
+            &Arc::new(MemoryBudget::new(None, Default::default())),
+            &EnsCache::default(),
+            &create_test_head_cache(),
+            &AnomalyDetector::default(),
+            &Maintenance::default(),
+        )
+        .await;
+
+        // Assert
+        let result = result.unwrap();
+        assert_eq!(
+            result["result"]["http://example.com/"]["eth_call"]["count"],
+            1
+        );
+        assert_eq!(
+            result["result"]["http://example.com/"]["eth_call"]["p99"],
+            4.0
+        );
+    }
+
+    #[tokio::test]
+    async fn test_execute_method_blutgang_scores() {
+        let rpc_list = create_test_rpc_list();
+        rpc_list.read().unwrap()[0].status.score.record(true);
+        let tx = json!({ "id":1,"method": "blutgang_scores" });
+
+        let result = execute_method(
+            tx,
+            &rpc_list,
+            &create_test_poverty_list(),
+            create_test_settings_config(),
+            create_test_cache(),
+            &Arc::new(MemoryBudget::new(None, Default::default())),
+            &EnsCache::default(),
+            &create_test_head_cache(),
+            &AnomalyDetector::default(),
+            &Maintenance::default(),
+        )
+        .await
+        .unwrap();
+
+        let node = &result["result"]["http://example.com/"];
+        assert_eq!(node["errors"], 1);
+        // Not scored until a health check with scoring on
+        assert_eq!(node["score"], Value::Null);
+    }
+
+    #[tokio::test]
+    async fn test_execute_method_blutgang_ens_prewarm() {
+        // Arrange
+        let cache = create_test_cache();
+        let node = crate::mock::node::MockNode::spawn(1).await.unwrap();
+        node.set_response("eth_call", json!(format!("0x{}", "0".repeat(64))));
+        let rpc_list = Arc::new(RwLock::new(vec![Rpc::new(
+            node.http_url(),
+            None,
+            5,
+            1000,
+            0.5,
+        )]));
+        let ens = EnsCache::new(Some(std::time::Duration::from_secs(60)));
+        let tx = json!({ "id":1,"method": "blutgang_ens_prewarm", "params": ["vitalik.eth", "nick.eth"] });
+
+        // Act
+        let result = execute_method(
+            tx.clone(),
+            &rpc_list,
+            &create_test_poverty_list(),
+            create_test_settings_config(),
+            cache.clone(),
+            &Arc::new(MemoryBudget::default()),
+            &ens,
+            &create_test_head_cache(),
+            &AnomalyDetector::default(),
+            &Maintenance::default(),
+        )
+        .await;
+        let disabled = execute_method(
+            tx,
+            &rpc_list,
+            &create_test_poverty_list(),
+            create_test_settings_config(),
+            cache,
+            &Arc::new(MemoryBudget::default()),
+            &EnsCache::default(),
+            &create_test_head_cache(),
+            &AnomalyDetector::default(),
+            &Maintenance::default(),
+        )
+        .await;
+
+        // Assert
+        let result = result.unwrap();
+        assert_eq!(result["result"]["warmed"], 2);
+        // Neither name has a resolver, so only the registry was asked
+        assert_eq!(ens.len(), 2);
+        assert!(matches!(disabled, Err(AdminError::EnsCacheDisabled)));
+    }
+
+    #[tokio::test]
+    async fn test_execute_method_export_import_cache() {
+        // Arrange
+        let source = create_test_cache();
+        source.insert([1u8; 32], b"finalized".as_slice()).unwrap();
+        let target = create_test_cache();
+        let path = std::env::temp_dir().join(format!("blutgang-snapshot-{}", std::process::id()));
+        let tx =
+            |method: &str| json!({ "id":1,"method": method, "params": [path.to_str().unwrap()] });
+
+        // Act
+        let exported = execute_method(
+            tx("blutgang_export_cache"),
+            &create_test_rpc_list(),
+            &create_test_poverty_list(),
+            create_test_settings_config(),
+            source,
+            &Arc::new(MemoryBudget::default()),
+            &EnsCache::default(),
+            &create_test_head_cache(),
+            &AnomalyDetector::default(),
+            &Maintenance::default(),
+        )
+        .await;
+        let imported = execute_method(
+            tx("blutgang_import_cache"),
+            &create_test_rpc_list(),
+            &create_test_poverty_list(),
+            create_test_settings_config(),
+            target.clone(),
+            &Arc::new(MemoryBudget::default()),
+            &EnsCache::default(),
+            &create_test_head_cache(),
+            &AnomalyDetector::default(),
+            &Maintenance::default(),
+        )
+        .await;
+        let _ = std::fs::remove_file(&path);
+
+        // Assert
+        assert_eq!(exported.unwrap()["result"]["exported"], 1);
+        assert_eq!(imported.unwrap()["result"]["imported"], 1);
+        assert_eq!(&*target.get([1u8; 32]).unwrap().unwrap(), b"finalized");
+    }
+
+    #[tokio::test]
+    async fn test_execute_method_cache_lookup_evict() {
+        // Arrange
+        let cache = create_test_cache();
+        let head_cache = create_test_head_cache();
+        let request = json!({
+            "jsonrpc": "2.0",
+            "id": Null,
+            "method": "eth_getBalance",
+            "params": ["0xabc", "0x10"],
+        });
+        let key = CacheKey::new(&request);
+        let meta = EntryMeta::new("eth_getBalance", Some("merkle"));
+        insert_entry_with_meta(&cache, &key, br#"{"result":"0x1"}"#, Some(&meta)).unwrap();
+        head_cache.write().unwrap().insert(16, vec![key]);
+        let (rpc_list, poverty_list) = (create_test_rpc_list(), create_test_poverty_list());
+        let memory = Arc::new(MemoryBudget::default());
+        let (ens, anomaly, maintenance) = (
+            EnsCache::default(),
+            AnomalyDetector::default(),
+            Maintenance::default(),
+        );
+        let call = |tx: Value| {
+            execute_method(
+                tx,
+                &rpc_list,
+                &poverty_list,
+                create_test_settings_config(),
+                Arc::clone(&cache),
+                &memory,
+                &ens,
+                &head_cache,
+                &anomaly,
+                &maintenance,
+            )
+        };
+        let lookup = json!({ "id":1,"method": "blutgang_cacheLookup", "params": ["eth_getBalance", ["0xabc", "0x10"]] });
+
+        // Act
+        let found = call(lookup.clone()).await.unwrap();
+        let other = call(json!({ "id":1,"method": "blutgang_cacheLookup", "params": ["eth_getBalance", ["0xabc", "0x10"], "acme"] }))
+            .await
+            .unwrap();
+        let evicted =
+            call(json!({ "id":1,"method": "blutgang_cacheEvict", "params": ["eth_get*"] }))
+                .await
+                .unwrap();
+        let gone = call(lookup).await.unwrap();
+
+        // Assert
+        assert_eq!(found["result"]["key"], key.to_string());
+        assert_eq!(found["result"]["cached"], true);
+        assert_eq!(found["result"]["response"]["result"], "0x1");
+        assert_eq!(found["result"]["storedAt"], meta.stored_at);
+        assert_eq!(found["result"]["source"], "merkle");
+        assert_eq!(found["result"]["unfinalizedBlock"], 16);
+        // Tenants have their own entries
+        assert_eq!(other["result"]["cached"], false);
+        assert_eq!(evicted["result"]["evicted"], 1);
+        assert_eq!(gone["result"]["cached"], false);
+        assert!(matches!(
+            call(json!({ "id":1,"method": "blutgang_cacheLookup", "params": [] })).await,
+            Err(AdminError::InvalidLen)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_execute_method_blutgang_validate_config() {
+        // Arrange
+        let cache = create_test_cache();
+        let tx = json!({ "id":1,"method": "blutgang_validate_config", "params": ["[blutgang]"] });
+
+        // Act
+        let result = execute_method(
+            tx,
+            &create_test_rpc_list(),
+            &create_test_poverty_list(),
+            create_test_settings_config(),
+            cache,
+            &Arc::new(MemoryBudget::default()),
+            &EnsCache::default(),
+            &create_test_head_cache(),
+            &AnomalyDetector::default(),
+            &Maintenance::default(),
+        )
+        .await;
+
+        // Assert
+        let result = result.unwrap();
+        assert_eq!(result["result"]["valid"], false);
+        assert_eq!(
+            result["result"]["error"],
+            "Invalid config: Missing do_clear toggle!"
+        );
+    }
+
     #[tokio::test]
     async fn test_execute_method_blutgang_ttl() {
         // Arrange
@@ -527,6 +1490,11 @@ mod tests {
             &create_test_poverty_list(),
             create_test_settings_config(),
             cache,
+            &Arc::new(MemoryBudget::default()),
+            &EnsCache::default(),
+            &create_test_head_cache(),
+            &AnomalyDetector::default(),
+            &Maintenance::default(),
         )
         .await;
 
@@ -547,6 +1515,11 @@ mod tests {
             &create_test_poverty_list(),
             create_test_settings_config(),
             cache,
+            &Arc::new(MemoryBudget::default()),
+            &EnsCache::default(),
+            &create_test_head_cache(),
+            &AnomalyDetector::default(),
+            &Maintenance::default(),
         )
         .await;
 
@@ -567,6 +1540,11 @@ mod tests {
             &create_test_poverty_list(),
             create_test_settings_config(),
             cache,
+            &Arc::new(MemoryBudget::default()),
+            &EnsCache::default(),
+            &create_test_head_cache(),
+            &AnomalyDetector::default(),
+            &Maintenance::default(),
         )
         .await;
 
@@ -590,6 +1568,11 @@ mod tests {
             &create_test_poverty_list(),
             create_test_settings_config(),
             cache,
+            &Arc::new(MemoryBudget::default()),
+            &EnsCache::default(),
+            &create_test_head_cache(),
+            &AnomalyDetector::default(),
+            &Maintenance::default(),
         )
         .await;
 
@@ -614,6 +1597,11 @@ mod tests {
             &create_test_poverty_list(),
             create_test_settings_config(),
             cache,
+            &Arc::new(MemoryBudget::default()),
+            &EnsCache::default(),
+            &create_test_head_cache(),
+            &AnomalyDetector::default(),
+            &Maintenance::default(),
         )
         .await;
 
@@ -647,6 +1635,11 @@ mod tests {
             &create_test_poverty_list(),
             create_test_settings_config(),
             cache.clone(),
+            &Arc::new(MemoryBudget::default()),
+            &EnsCache::default(),
+            &create_test_head_cache(),
+            &AnomalyDetector::default(),
+            &Maintenance::default(),
         )
         .await;
 
@@ -664,6 +1657,11 @@ mod tests {
             &binding,
             create_test_settings_config(),
             cache,
+            &Arc::new(MemoryBudget::default()),
+            &EnsCache::default(),
+            &create_test_head_cache(),
+            &AnomalyDetector::default(),
+            &Maintenance::default(),
         )
         .await;
 
@@ -688,6 +1686,11 @@ mod tests {
             &create_test_poverty_list(),
             Arc::clone(&config),
             cache,
+            &Arc::new(MemoryBudget::default()),
+            &EnsCache::default(),
+            &create_test_head_cache(),
+            &AnomalyDetector::default(),
+            &Maintenance::default(),
         )
         .await;
 
@@ -697,6 +1700,187 @@ mod tests {
         assert!(config.read().unwrap().ttl == 9001)
     }
 
+    #[tokio::test]
+    async fn test_execute_method_blutgang_release_from_quarantine() {
+        let rpc_list = create_test_rpc_list();
+        // We don't quarantine our last healthy nodes
+        for url in ["http://example.org", "http://example.net"] {
+            rpc_list
+                .write()
+                .unwrap()
+                .push(Rpc::new(url.to_string(), None, 5, 1000, 0.5));
+        }
+        let name = rpc_list.read().unwrap()[0].name.clone();
+        let anomaly = AnomalyDetector::default();
+        anomaly.quarantine(&rpc_list, &name, "test".to_string());
+        assert_eq!(rpc_list.read().unwrap().len(), 2);
+
+        let poverty_list = create_test_poverty_list();
+        let config = create_test_settings_config();
+        let cache = create_test_cache();
+        let memory = Arc::new(MemoryBudget::default());
+        let ens = EnsCache::default();
+        let head_cache = create_test_head_cache();
+        let maintenance = Maintenance::default();
+        let call = |tx: Value| {
+            execute_method(
+                tx,
+                &rpc_list,
+                &poverty_list,
+                Arc::clone(&config),
+                Arc::clone(&cache),
+                &memory,
+                &ens,
+                &head_cache,
+                &anomaly,
+                &maintenance,
+            )
+        };
+
+        let result = call(json!({ "id":1,"method": "blutgang_quarantine_list" }))
+            .await
+            .unwrap();
+        assert_eq!(result["result"][0]["name"], name);
+        assert_eq!(result["result"][0]["reason"], "test");
+
+        let result =
+            call(json!({ "id":1,"method": "blutgang_release_from_quarantine", "params": [1] }))
+                .await;
+        assert!(matches!(result, Err(AdminError::OutOfBounds)));
+
+        let result =
+            call(json!({ "id":1,"method": "blutgang_release_from_quarantine", "params": [0] }))
+                .await
+                .unwrap();
+        assert_eq!(result["result"], name);
+        assert_eq!(rpc_list.read().unwrap().len(), 3);
+        assert!(anomaly.quarantined().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_execute_method_blutgang_drain_rpc() {
+        let rpc_list = create_test_rpc_list();
+        let poverty_list = create_test_poverty_list();
+        let config = create_test_settings_config();
+        let cache = create_test_cache();
+        let memory = Arc::new(MemoryBudget::default());
+        let ens = EnsCache::default();
+        let head_cache = create_test_head_cache();
+        let anomaly = AnomalyDetector::default();
+        let maintenance = Maintenance::default();
+        let call = |tx: Value| {
+            execute_method(
+                tx,
+                &rpc_list,
+                &poverty_list,
+                Arc::clone(&config),
+                Arc::clone(&cache),
+                &memory,
+                &ens,
+                &head_cache,
+                &anomaly,
+                &maintenance,
+            )
+        };
+        let name = rpc_list.read().unwrap()[0].name.clone();
+
+        let result = call(json!({ "id":1,"method": "blutgang_drainRpc", "params": [name] }))
+            .await
+            .unwrap();
+        assert_eq!(result["result"]["name"], name);
+        assert_eq!(result["result"]["inFlight"], 0);
+        assert!(rpc_list.read().unwrap().is_empty());
+
+        // Only drained nodes can be enabled
+        let result = call(
+            json!({ "id":1,"method": "blutgang_enableRpc", "params": ["http://poverty.com/"] }),
+        )
+        .await;
+        assert!(matches!(result, Err(AdminError::UnknownRpc(_))));
+
+        let result = call(json!({ "id":1,"method": "blutgang_enableRpc", "params": [name] }))
+            .await
+            .unwrap();
+        assert_eq!(result["result"], name);
+        assert_eq!(rpc_list.read().unwrap().len(), 1);
+        assert!(maintenance.drained().is_empty());
+    }
+
+    // Puts the global log level and debug modules back when dropped, even if
+    // the test panics, so other tests keep logging as usual
+    struct RestoreLogging {
+        level: LogLevel,
+        ws: bool,
+    }
+
+    impl RestoreLogging {
+        fn new() -> Self {
+            RestoreLogging {
+                level: log_level(),
+                ws: debug_enabled(DebugModule::Ws),
+            }
+        }
+    }
+
+    impl Drop for RestoreLogging {
+        fn drop(&mut self) {
+            set_log_level(self.level);
+            set_debug(DebugModule::Ws, self.ws);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_execute_method_blutgang_set_log_level() {
+        let _restore = RestoreLogging::new();
+        let rpc_list = create_test_rpc_list();
+        let poverty_list = create_test_poverty_list();
+        let config = create_test_settings_config();
+        let cache = create_test_cache();
+        let memory = Arc::new(MemoryBudget::default());
+        let ens = EnsCache::default();
+        let head_cache = create_test_head_cache();
+        let anomaly = AnomalyDetector::default();
+        let maintenance = Maintenance::default();
+        let call = |tx: Value| {
+            execute_method(
+                tx,
+                &rpc_list,
+                &poverty_list,
+                Arc::clone(&config),
+                Arc::clone(&cache),
+                &memory,
+                &ens,
+                &head_cache,
+                &anomaly,
+                &maintenance,
+            )
+        };
+
+        let result = call(json!({ "id":1,"method": "blutgang_set_log_level", "params": ["warn"] }))
+            .await
+            .unwrap();
+        assert_eq!(result["result"]["level"], "warn");
+        assert_eq!(log_level(), LogLevel::Warn);
+
+        let result = call(json!({ "id":1,"method": "blutgang_set_debug", "params": ["ws", true] }))
+            .await
+            .unwrap();
+        assert!(result["result"]["debug"]
+            .as_array()
+            .unwrap()
+            .contains(&json!("ws")));
+        assert!(debug_enabled(DebugModule::Ws));
+
+        assert!(matches!(
+            call(json!({ "id":1,"method": "blutgang_set_log_level", "params": ["loud"] })).await,
+            Err(AdminError::ParseError)
+        ));
+        assert!(matches!(
+            call(json!({ "id":1,"method": "blutgang_set_debug", "params": ["ws"] })).await,
+            Err(AdminError::InvalidLen)
+        ));
+    }
+
     #[tokio::test]
     async fn test_execute_method_blutgang_set_health_check_ttl() {
         // Arrange
@@ -713,6 +1897,11 @@ mod tests {
             &create_test_poverty_list(),
             Arc::clone(&config),
             cache,
+            &Arc::new(MemoryBudget::default()),
+            &EnsCache::default(),
+            &create_test_head_cache(),
+            &AnomalyDetector::default(),
+            &Maintenance::default(),
         )
         .await;
 
@@ -738,6 +1927,11 @@ mod tests {
             &create_test_poverty_list(),
             Arc::clone(&config),
             cache.clone(),
+            &Arc::new(MemoryBudget::default()),
+            &EnsCache::default(),
+            &create_test_head_cache(),
+            &AnomalyDetector::default(),
+            &Maintenance::default(),
         )
         .await;
 
@@ -752,10 +1946,43 @@ mod tests {
             &create_test_poverty_list(),
             Arc::clone(&config),
             cache,
+            &Arc::new(MemoryBudget::default()),
+            &EnsCache::default(),
+            &create_test_head_cache(),
+            &AnomalyDetector::default(),
+            &Maintenance::default(),
         )
         .await;
 
         // Assert
         assert!(result.is_ok());
     }
+
+    #[tokio::test]
+    async fn test_admin_methods_readonly() {
+        let config = create_test_settings_config();
+        config.write().unwrap().admin.readonly = true;
+
+        // Everything we list as writing has to be blocked
+        for (method, _) in ADMIN_METHODS.iter().filter(|(_, writes)| *writes) {
+            let result = execute_method(
+                json!({ "id":1,"method": method, "params": [] }),
+                &create_test_rpc_list(),
+                &create_test_poverty_list(),
+                Arc::clone(&config),
+                create_test_cache(),
+                &Arc::new(MemoryBudget::default()),
+                &EnsCache::default(),
+                &create_test_head_cache(),
+                &AnomalyDetector::default(),
+                &Maintenance::default(),
+            )
+            .await;
+            assert!(
+                matches!(result, Err(AdminError::WriteProtectionEnabled)),
+                "{}",
+                method
+            );
+        }
+    }
 }
@@ -0,0 +1,580 @@
+// Latency stats and scores of our nodes, for `blutgang_latency`,
+// `blutgang_scores` and Prometheus.
+//
+// Prometheus scrapes `GET /metrics` on the admin address, with a token signed
+// for `blutgang_metrics` as a bearer token if JWT is enabled. Every node
+// and method gets a summary with its p50, p90 and p99, and every node a count
+// of the transactions we rebroadcast to it and whether it's on a diverging fork.
+// Hits and misses of the in-memory cache tier are exported next to them, and
+// so are node counts, requests and mean latency per region for nodes that have
+// a `region` set. Requests in flight, per node and in total, WS connections
+// and internal queue depths are exported as gauges, see `admin::gauges`.
+// Nodes with request budgets get what's left of them for the day and month.
+// Beacon nodes get their health, head slot and requests in flight.
+use crate::{
+    admin::gauges::{
+        FANOUT_BACKLOG,
+        REQUESTS_IN_FLIGHT,
+        WS_CONNECTIONS,
+        WS_RESPONSE_QUEUE,
+    },
+    balancer::hot_cache::HotCache,
+    beacon::types::BeaconPool,
+    rpc::{
+        budget::BudgetPeriod,
+        latency::LatencyHistogram,
+    },
+    Rpc,
+};
+
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::{
+        atomic::Ordering,
+        Arc,
+        RwLock,
+    },
+    time::Duration,
+};
+
+use serde_json::{
+    json,
+    Map,
+    Value,
+};
+
+const QUANTILES: [f64; 3] = [0.5, 0.9, 0.99];
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+// Every node we have, active or not, with its method histograms
+fn histograms(
+    rpc_list: &Arc<RwLock<Vec<Rpc>>>,
+    poverty_list: &Arc<RwLock<Vec<Rpc>>>,
+) -> Vec<(String, Vec<(String, LatencyHistogram)>)> {
+    let rpc_list = rpc_list.read().unwrap_or_else(|e| e.into_inner());
+    let poverty_list = poverty_list.read().unwrap_or_else(|e| e.into_inner());
+
+    rpc_list
+        .iter()
+        .chain(poverty_list.iter())
+        .map(|rpc| (rpc.name.clone(), rpc.status.methods.snapshot()))
+        .collect()
+}
+
+// Per node and method latencies in ms
+pub fn latency_report(
+    rpc_list: &Arc<RwLock<Vec<Rpc>>>,
+    poverty_list: &Arc<RwLock<Vec<Rpc>>>,
+) -> Value {
+    let mut report = Map::new();
+    for (node, methods) in histograms(rpc_list, poverty_list) {
+        let methods: Map<String, Value> = methods
+            .into_iter()
+            .map(|(method, histogram)| {
+                let mean = histogram.sum() / histogram.count().max(1) as u32;
+                let stats = json!({
+                    "count": histogram.count(),
+                    "mean": millis(mean),
+                    "p50": millis(histogram.quantile(0.5)),
+                    "p90": millis(histogram.quantile(0.9)),
+                    "p99": millis(histogram.quantile(0.99)),
+                    "max": millis(histogram.max()),
+                });
+                (method, stats)
+            })
+            .collect();
+        report.insert(node, Value::Object(methods));
+    }
+
+    Value::Object(report)
+}
+
+// Score, error rate and lag of every node, see `rpc::score`
+pub fn score_report(
+    rpc_list: &Arc<RwLock<Vec<Rpc>>>,
+    poverty_list: &Arc<RwLock<Vec<Rpc>>>,
+) -> Value {
+    let rpc_list = rpc_list.read().unwrap_or_else(|e| e.into_inner());
+    let poverty_list = poverty_list.read().unwrap_or_else(|e| e.into_inner());
+
+    let report: Map<String, Value> = rpc_list
+        .iter()
+        .chain(poverty_list.iter())
+        .map(|rpc| (rpc.name.clone(), rpc.status.score.report()))
+        .collect();
+
+    Value::Object(report)
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[derive(Debug, Default)]
+struct RegionStats {
+    healthy: u64,
+    unhealthy: u64,
+    requests: u64,
+    // Total, divided by `requests` for the mean
+    latency: Duration,
+}
+
+// Nodes and traffic per region, for nodes that have one
+fn region_stats(
+    rpc_list: &Arc<RwLock<Vec<Rpc>>>,
+    poverty_list: &Arc<RwLock<Vec<Rpc>>>,
+) -> BTreeMap<String, RegionStats> {
+    let rpc_list = rpc_list.read().unwrap_or_else(|e| e.into_inner());
+    let poverty_list = poverty_list.read().unwrap_or_else(|e| e.into_inner());
+
+    let mut regions: BTreeMap<String, RegionStats> = BTreeMap::new();
+    let nodes = rpc_list
+        .iter()
+        .map(|rpc| (rpc, true))
+        .chain(poverty_list.iter().map(|rpc| (rpc, false)));
+    for (rpc, healthy) in nodes {
+        let region = match &rpc.region {
+            Some(region) => region,
+            None => continue,
+        };
+        let stats = regions.entry(region.clone()).or_default();
+        if healthy {
+            stats.healthy += 1;
+        } else {
+            stats.unhealthy += 1;
+        }
+        for (_, histogram) in rpc.status.methods.snapshot() {
+            stats.requests += histogram.count();
+            stats.latency += histogram.sum();
+        }
+    }
+
+    regions
+}
+
+// Latencies in the Prometheus text format
+pub fn prometheus_metrics(
+    rpc_list: &Arc<RwLock<Vec<Rpc>>>,
+    poverty_list: &Arc<RwLock<Vec<Rpc>>>,
+    hot: &HotCache,
+    beacon: &BeaconPool,
+) -> String {
+    let mut metrics = String::new();
+    metrics.push_str(
+        "# HELP blutgang_request_latency_seconds Latency of requests forwarded to nodes.\n",
+    );
+    metrics.push_str("# TYPE blutgang_request_latency_seconds summary\n");
+
+    for (node, methods) in histograms(rpc_list, poverty_list) {
+        for (method, histogram) in methods {
+            let labels = format!(
+                "node=\"{}\",method=\"{}\"",
+                escape_label(&node),
+                escape_label(&method)
+            );
+            for quantile in QUANTILES {
+                let _ = writeln!(
+                    metrics,
+                    "blutgang_request_latency_seconds{{{},quantile=\"{}\"}} {}",
+                    labels,
+                    quantile,
+                    histogram.quantile(quantile).as_secs_f64()
+                );
+            }
+            let _ = writeln!(
+                metrics,
+                "blutgang_request_latency_seconds_sum{{{}}} {}",
+                labels,
+                histogram.sum().as_secs_f64()
+            );
+            let _ = writeln!(
+                metrics,
+                "blutgang_request_latency_seconds_count{{{}}} {}",
+                labels,
+                histogram.count()
+            );
+        }
+    }
+
+    metrics.push_str(
+        "# HELP blutgang_rebroadcasts_total Transactions rebroadcast to nodes until included.\n",
+    );
+    metrics.push_str("# TYPE blutgang_rebroadcasts_total counter\n");
+    let nodes: Vec<(String, u64, bool, u64)> = {
+        let rpc_list = rpc_list.read().unwrap_or_else(|e| e.into_inner());
+        let poverty_list = poverty_list.read().unwrap_or_else(|e| e.into_inner());
+        rpc_list
+            .iter()
+            .chain(poverty_list.iter())
+            .map(|rpc| {
+                (
+                    rpc.name.clone(),
+                    rpc.status.rebroadcasts.load(Ordering::Relaxed),
+                    rpc.status.fork_divergence,
+                    rpc.status.in_flight.load(Ordering::Relaxed),
+                )
+            })
+            .collect()
+    };
+    for (node, count, _, _) in &nodes {
+        let _ = writeln!(
+            metrics,
+            "blutgang_rebroadcasts_total{{node=\"{}\"}} {}",
+            escape_label(node),
+            count
+        );
+    }
+
+    metrics.push_str(
+        "# HELP blutgang_fork_divergence Whether a node disagrees with its peers on the head hash.\n",
+    );
+    metrics.push_str("# TYPE blutgang_fork_divergence gauge\n");
+    for (node, _, diverged, _) in &nodes {
+        let _ = writeln!(
+            metrics,
+            "blutgang_fork_divergence{{node=\"{}\"}} {}",
+            escape_label(node),
+            u8::from(*diverged)
+        );
+    }
+
+    metrics
+        .push_str("# HELP blutgang_node_in_flight Requests sent to a node and not answered yet.\n");
+    metrics.push_str("# TYPE blutgang_node_in_flight gauge\n");
+    for (node, _, _, in_flight) in &nodes {
+        let _ = writeln!(
+            metrics,
+            "blutgang_node_in_flight{{node=\"{}\"}} {}",
+            escape_label(node),
+            in_flight
+        );
+    }
+    metrics.push_str(
+        "# HELP blutgang_node_budget_remaining Requests a node has left in its budget this period.\n",
+    );
+    metrics.push_str("# TYPE blutgang_node_budget_remaining gauge\n");
+    let budgets: Vec<(String, Vec<(BudgetPeriod, u64)>)> = {
+        let rpc_list = rpc_list.read().unwrap_or_else(|e| e.into_inner());
+        let poverty_list = poverty_list.read().unwrap_or_else(|e| e.into_inner());
+        rpc_list
+            .iter()
+            .chain(poverty_list.iter())
+            .map(|rpc| (rpc.name.clone(), rpc.status.budget.remaining()))
+            .collect()
+    };
+    for (node, remaining) in &budgets {
+        for (period, remaining) in remaining {
+            let _ = writeln!(
+                metrics,
+                "blutgang_node_budget_remaining{{node=\"{}\",period=\"{}\"}} {}",
+                escape_label(node),
+                period.as_str(),
+                remaining
+            );
+        }
+    }
+    metrics.push_str(
+        "# HELP blutgang_requests_in_flight Requests from clients we haven't answered yet.\n",
+    );
+    metrics.push_str("# TYPE blutgang_requests_in_flight gauge\n");
+    let _ = writeln!(
+        metrics,
+        "blutgang_requests_in_flight {}",
+        REQUESTS_IN_FLIGHT.get()
+    );
+    metrics.push_str("# HELP blutgang_ws_connections Connected WS clients.\n");
+    metrics.push_str("# TYPE blutgang_ws_connections gauge\n");
+    let _ = writeln!(metrics, "blutgang_ws_connections {}", WS_CONNECTIONS.get());
+    metrics.push_str("# HELP blutgang_queue_depth Messages waiting in internal queues.\n");
+    metrics.push_str("# TYPE blutgang_queue_depth gauge\n");
+    for (queue, depth) in [
+        ("ws_responses", WS_RESPONSE_QUEUE.get()),
+        ("subscription_fanout", FANOUT_BACKLOG.get()),
+    ] {
+        let _ = writeln!(
+            metrics,
+            "blutgang_queue_depth{{queue=\"{}\"}} {}",
+            queue, depth
+        );
+    }
+
+    metrics.push_str(
+        "# HELP blutgang_node_score_seconds Effective latency nodes are ranked by, if scoring is on.\n",
+    );
+    metrics.push_str("# TYPE blutgang_node_score_seconds gauge\n");
+    let scores: Vec<(String, Option<f64>, Value)> = {
+        let rpc_list = rpc_list.read().unwrap_or_else(|e| e.into_inner());
+        let poverty_list = poverty_list.read().unwrap_or_else(|e| e.into_inner());
+        rpc_list
+            .iter()
+            .chain(poverty_list.iter())
+            .map(|rpc| {
+                (
+                    rpc.name.clone(),
+                    rpc.status.score.score(),
+                    rpc.status.score.report(),
+                )
+            })
+            .collect()
+    };
+    for (node, score, _) in &scores {
+        if let Some(score) = score {
+            let _ = writeln!(
+                metrics,
+                "blutgang_node_score_seconds{{node=\"{}\"}} {}",
+                escape_label(node),
+                score / 1_000_000_000.0
+            );
+        }
+    }
+    metrics.push_str(
+        "# HELP blutgang_node_error_rate Decayed share of requests to a node that failed.\n",
+    );
+    metrics.push_str("# TYPE blutgang_node_error_rate gauge\n");
+    for (node, score, report) in &scores {
+        if score.is_some() {
+            let _ = writeln!(
+                metrics,
+                "blutgang_node_error_rate{{node=\"{}\"}} {}",
+                escape_label(node),
+                report["errorRate"]
+            );
+        }
+    }
+
+    let regions = region_stats(rpc_list, poverty_list);
+    metrics
+        .push_str("# HELP blutgang_region_nodes Nodes in a region, by whether they're healthy.\n");
+    metrics.push_str("# TYPE blutgang_region_nodes gauge\n");
+    for (region, stats) in &regions {
+        for (healthy, nodes) in [("true", stats.healthy), ("false", stats.unhealthy)] {
+            let _ = writeln!(
+                metrics,
+                "blutgang_region_nodes{{region=\"{}\",healthy=\"{}\"}} {}",
+                escape_label(region),
+                healthy,
+                nodes
+            );
+        }
+    }
+    metrics.push_str(
+        "# HELP blutgang_region_requests_total Requests forwarded to nodes in a region.\n",
+    );
+    metrics.push_str("# TYPE blutgang_region_requests_total counter\n");
+    for (region, stats) in &regions {
+        let _ = writeln!(
+            metrics,
+            "blutgang_region_requests_total{{region=\"{}\"}} {}",
+            escape_label(region),
+            stats.requests
+        );
+    }
+    metrics.push_str(
+        "# HELP blutgang_region_latency_seconds Mean latency of requests to nodes in a region.\n",
+    );
+    metrics.push_str("# TYPE blutgang_region_latency_seconds gauge\n");
+    for (region, stats) in &regions {
+        let _ = writeln!(
+            metrics,
+            "blutgang_region_latency_seconds{{region=\"{}\"}} {}",
+            escape_label(region),
+            stats.latency.as_secs_f64() / stats.requests.max(1) as f64
+        );
+    }
+
+    let stats = hot.stats();
+    metrics.push_str(
+        "# HELP blutgang_hot_cache_hits_total Cache reads served from the in-memory tier.\n",
+    );
+    metrics.push_str("# TYPE blutgang_hot_cache_hits_total counter\n");
+    let _ = writeln!(metrics, "blutgang_hot_cache_hits_total {}", hot.hits());
+    metrics
+        .push_str("# HELP blutgang_hot_cache_misses_total Cache reads that had to go to the DB.\n");
+    metrics.push_str("# TYPE blutgang_hot_cache_misses_total counter\n");
+    let _ = writeln!(metrics, "blutgang_hot_cache_misses_total {}", hot.misses());
+    metrics.push_str("# HELP blutgang_hot_cache_entries Responses held in the in-memory tier.\n");
+    metrics.push_str("# TYPE blutgang_hot_cache_entries gauge\n");
+    let _ = writeln!(metrics, "blutgang_hot_cache_entries {}", stats["entries"]);
+    metrics.push_str("# HELP blutgang_hot_cache_bytes Bytes held in the in-memory tier.\n");
+    metrics.push_str("# TYPE blutgang_hot_cache_bytes gauge\n");
+    let _ = writeln!(metrics, "blutgang_hot_cache_bytes {}", stats["usedBytes"]);
+
+    metrics
+        .push_str("# HELP blutgang_beacon_node_healthy Whether a beacon node says it's synced.\n");
+    metrics.push_str("# TYPE blutgang_beacon_node_healthy gauge\n");
+    for node in &beacon.nodes {
+        let _ = writeln!(
+            metrics,
+            "blutgang_beacon_node_healthy{{node=\"{}\"}} {}",
+            escape_label(&node.name),
+            u8::from(node.is_healthy())
+        );
+    }
+    metrics.push_str("# HELP blutgang_beacon_head_slot Slot of a beacon node's head.\n");
+    metrics.push_str("# TYPE blutgang_beacon_head_slot gauge\n");
+    for node in &beacon.nodes {
+        let _ = writeln!(
+            metrics,
+            "blutgang_beacon_head_slot{{node=\"{}\"}} {}",
+            escape_label(&node.name),
+            node.slot()
+        );
+    }
+    metrics.push_str(
+        "# HELP blutgang_beacon_node_in_flight Requests sent to a beacon node and not answered yet.\n",
+    );
+    metrics.push_str("# TYPE blutgang_beacon_node_in_flight gauge\n");
+    for node in &beacon.nodes {
+        let _ = writeln!(
+            metrics,
+            "blutgang_beacon_node_in_flight{{node=\"{}\"}} {}",
+            escape_label(&node.name),
+            node.in_flight.load(Ordering::Relaxed)
+        );
+    }
+
+    metrics
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    type RpcList = Arc<RwLock<Vec<Rpc>>>;
+
+    fn lists() -> (RpcList, RpcList) {
+        let rpc = Rpc::new("https://node.example.com".to_string(), None, 6, 0, 10.0);
+        rpc.status
+            .methods
+            .record("eth_call", Duration::from_millis(10));
+        rpc.status
+            .methods
+            .record("eth_call", Duration::from_millis(20));
+        rpc.status.rebroadcasts.fetch_add(3, Ordering::Relaxed);
+        rpc.status.in_flight.fetch_add(2, Ordering::Relaxed);
+        rpc.status.score.record(true);
+        rpc.status.score.update(
+            2_000_000.0,
+            Some(0),
+            &crate::config::types::ScoringSettings {
+                error_penalty: 1.0,
+                ..Default::default()
+            },
+        );
+
+        let rpc = rpc
+            .with_region(Some("eu-west".to_string()))
+            .with_budget(Some(&crate::config::types::BudgetSettings {
+                daily: Some(100),
+                monthly: None,
+                pacing: false,
+            }));
+        rpc.status.budget.record();
+
+        let mut poor = Rpc::new("https://poor.example.com".to_string(), None, 6, 0, 10.0)
+            .with_region(Some("eu-west".to_string()));
+        poor.status.fork_divergence = true;
+        poor.status
+            .methods
+            .record("eth_getLogs", Duration::from_secs(1));
+
+        (
+            Arc::new(RwLock::new(vec![rpc])),
+            Arc::new(RwLock::new(vec![poor])),
+        )
+    }
+
+    #[test]
+    fn test_latency_report() {
+        let (rpc_list, poverty_list) = lists();
+        let report = latency_report(&rpc_list, &poverty_list);
+
+        let eth_call = &report["https://node.example.com/"]["eth_call"];
+        assert_eq!(eth_call["count"], 2);
+        assert_eq!(eth_call["mean"], 15.0);
+        assert_eq!(eth_call["max"], 20.0);
+        assert_eq!(
+            report["https://poor.example.com/"]["eth_getLogs"]["count"],
+            1
+        );
+    }
+
+    #[test]
+    fn test_score_report() {
+        let (rpc_list, poverty_list) = lists();
+        let report = score_report(&rpc_list, &poverty_list);
+
+        assert_eq!(report["https://node.example.com/"]["score"], 4.0);
+        assert_eq!(report["https://node.example.com/"]["errors"], 1);
+        assert_eq!(report["https://poor.example.com/"]["score"], Value::Null);
+    }
+
+    #[test]
+    fn test_prometheus_metrics() {
+        let (rpc_list, poverty_list) = lists();
+        let hot = HotCache::new(1 << 20);
+        let key = crate::balancer::cache_entry::CacheKey::from_request(b"eth_chainId");
+        hot.insert(&key, b"0x1", hot.generation());
+        assert!(hot.get(&key).is_some());
+        let beacon = BeaconPool::new(Some(crate::config::types::BeaconSettings {
+            nodes: vec!["https://beacon.example.com/key".to_string()],
+            ..Default::default()
+        }));
+        beacon.nodes[0].set_healthy(true);
+        beacon.nodes[0].set_slot(42);
+        let metrics = prometheus_metrics(&rpc_list, &poverty_list, &hot, &beacon);
+
+        assert!(metrics.contains("# TYPE blutgang_request_latency_seconds summary"));
+        assert!(metrics.contains(
+            "blutgang_request_latency_seconds_count{node=\"https://node.example.com/\",method=\"eth_call\"} 2"
+        ));
+        assert!(metrics.contains(
+            "blutgang_request_latency_seconds{node=\"https://poor.example.com/\",method=\"eth_getLogs\",quantile=\"0.99\"} 1"
+        ));
+        assert!(
+            metrics.contains("blutgang_rebroadcasts_total{node=\"https://node.example.com/\"} 3")
+        );
+        assert!(
+            metrics.contains("blutgang_rebroadcasts_total{node=\"https://poor.example.com/\"} 0")
+        );
+        assert!(metrics.contains("blutgang_fork_divergence{node=\"https://poor.example.com/\"} 1"));
+        assert!(metrics.contains("blutgang_fork_divergence{node=\"https://node.example.com/\"} 0"));
+        assert!(metrics.contains("blutgang_node_in_flight{node=\"https://node.example.com/\"} 2"));
+        assert!(metrics.contains(
+            "blutgang_node_budget_remaining{node=\"https://node.example.com/\",period=\"day\"} 99"
+        ));
+        assert!(
+            !metrics.contains("blutgang_node_budget_remaining{node=\"https://poor.example.com/\"")
+        );
+        assert!(metrics.contains("# TYPE blutgang_requests_in_flight gauge"));
+        assert!(metrics.contains("# TYPE blutgang_ws_connections gauge"));
+        assert!(metrics.contains("blutgang_queue_depth{queue=\"subscription_fanout\"}"));
+        assert!(metrics
+            .contains("blutgang_node_score_seconds{node=\"https://node.example.com/\"} 0.004"));
+        assert!(metrics.contains("blutgang_node_error_rate{node=\"https://node.example.com/\"} 1"));
+        assert!(
+            !metrics.contains("blutgang_node_score_seconds{node=\"https://poor.example.com/\"}")
+        );
+        assert!(metrics.contains("blutgang_region_nodes{region=\"eu-west\",healthy=\"true\"} 1"));
+        assert!(metrics.contains("blutgang_region_nodes{region=\"eu-west\",healthy=\"false\"} 1"));
+        assert!(metrics.contains("blutgang_region_requests_total{region=\"eu-west\"} 3"));
+        assert!(metrics.contains("blutgang_hot_cache_hits_total 1"));
+        assert!(metrics.contains("blutgang_hot_cache_misses_total 0"));
+        assert!(metrics.contains("blutgang_hot_cache_bytes 3"));
+        assert!(metrics
+            .contains("blutgang_beacon_node_healthy{node=\"https://beacon.example.com/\"} 1"));
+        assert!(
+            metrics.contains("blutgang_beacon_head_slot{node=\"https://beacon.example.com/\"} 42")
+        );
+        assert!(metrics
+            .contains("blutgang_beacon_node_in_flight{node=\"https://beacon.example.com/\"} 0"));
+        assert_eq!(escape_label("a\"b"), "a\\\"b");
+    }
+}
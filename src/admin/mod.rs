@@ -1,4 +1,10 @@
-mod accept;
+pub(crate) mod accept;
 mod error;
+pub mod gauges;
 pub mod listener;
 mod methods;
+pub mod metrics;
+#[cfg(unix)]
+pub mod signals;
+
+pub use methods::ADMIN_METHODS;
@@ -0,0 +1,251 @@
+// Unix signals for operators running blutgang as a daemon.
+//
+// SIGHUP reloads the config file, SIGUSR1 logs a report of our nodes,
+// subscriptions and caches, and SIGUSR2 reopens the log file so it can be
+// rotated by something like logrotate.
+use crate::{
+    balancer::{
+        hot_cache::HotCache,
+        memory::MemoryBudget,
+    },
+    config::{
+        error::ConfigError,
+        system::reopen_log_file,
+        validate::{
+            apply_config,
+            validate_config,
+        },
+    },
+    health::anomaly::AnomalyDetector,
+    log_err,
+    log_info,
+    log_wrn,
+    websocket::types::SubscriptionData,
+    Rpc,
+    Settings,
+};
+
+use std::{
+    fs,
+    sync::{
+        Arc,
+        RwLock,
+    },
+};
+
+use serde_json::{
+    json,
+    Value,
+};
+use sled::Db;
+use tokio::signal::unix::{
+    signal,
+    SignalKind,
+};
+
+// Everything signals act on
+#[derive(Clone)]
+pub struct SignalTargets {
+    pub config: Arc<RwLock<Settings>>,
+    pub rpc_list: Arc<RwLock<Vec<Rpc>>>,
+    pub poverty_list: Arc<RwLock<Vec<Rpc>>>,
+    pub cache: Arc<Db>,
+    pub memory: Arc<MemoryBudget>,
+    pub hot: Arc<HotCache>,
+    pub sub_data: Arc<SubscriptionData>,
+    pub anomaly: Arc<AnomalyDetector>,
+}
+
+// Read the config file we were started with again and apply it
+pub fn reload_config(targets: &SignalTargets) -> Result<Value, ConfigError> {
+    let path = targets
+        .config
+        .read()
+        .unwrap_or_else(|e| e.into_inner())
+        .config_path
+        .clone()
+        .ok_or(ConfigError::InvalidConfig(
+            "blutgang was not started with a config file".to_string(),
+        ))?;
+
+    let proposed = fs::read_to_string(&path)
+        .map_err(|e| ConfigError::InvalidConfig(format!("Could not read {}: {}", path, e)))?;
+    let proposed = validate_config(&proposed)?;
+
+    Ok(apply_config(
+        &targets.config,
+        &targets.rpc_list,
+        &targets.poverty_list,
+        &proposed,
+    ))
+}
+
+fn node_report(rpc_list: &Arc<RwLock<Vec<Rpc>>>) -> Vec<Value> {
+    rpc_list
+        .read()
+        .unwrap_or_else(|e| e.into_inner())
+        .iter()
+        .map(|rpc| {
+            json!({
+                "name": rpc.name,
+                "latency": rpc.status.latency,
+                "score": rpc.status.score.report()["score"],
+                "canaryShare": rpc.canary_share(),
+                "profile": rpc.profile.map(|profile| profile.to_string()),
+                "region": rpc.region,
+                "cooldownMs": rpc.status.cooldown.remaining().as_millis() as u64,
+                "isErroring": rpc.status.is_erroring,
+                "lastError": rpc.status.last_error,
+            })
+        })
+        .collect()
+}
+
+// Snapshot of our nodes, subscriptions and caches
+pub fn state_report(targets: &SignalTargets) -> Value {
+    let quarantined: Vec<Value> = targets
+        .anomaly
+        .quarantined()
+        .into_iter()
+        .map(|quarantined| json!({"name": quarantined.rpc.name, "reason": quarantined.reason}))
+        .collect();
+
+    json!({
+        "nodes": node_report(&targets.rpc_list),
+        "poverty": node_report(&targets.poverty_list),
+        "quarantined": quarantined,
+        "subscriptions": {
+            "users": targets.sub_data.user_count(),
+            "upstream": targets.sub_data.subscription_count(),
+        },
+        "cache": {
+            "entries": targets.cache.len(),
+            "sizeOnDisk": targets.cache.size_on_disk().ok(),
+            "memory": targets.memory.stats(),
+            "hot": targets.hot.stats(),
+        },
+    })
+}
+
+pub async fn listen_for_signals(targets: SignalTargets) -> Result<(), std::io::Error> {
+    let mut hangup = signal(SignalKind::hangup())?;
+    let mut user_defined1 = signal(SignalKind::user_defined1())?;
+    let mut user_defined2 = signal(SignalKind::user_defined2())?;
+
+    loop {
+        tokio::select! {
+            _ = hangup.recv() => {
+                log_info!("SIGHUP received, reloading config...");
+                match reload_config(&targets) {
+                    Ok(diff) => {
+                        log_info!("Config reloaded: {}", diff);
+                        if diff["requiresRestart"].as_array().is_some_and(|names| !names.is_empty()) {
+                            log_wrn!(
+                                "Some changes only take effect after a restart: {}",
+                                diff["requiresRestart"]
+                            );
+                        }
+                    }
+                    Err(e) => log_err!("Could not reload config: {}", e),
+                }
+            }
+            _ = user_defined1.recv() => {
+                log_info!("State report: {}", state_report(&targets));
+            }
+            _ = user_defined2.recv() => {
+                match reopen_log_file() {
+                    Ok(Some(path)) => log_info!("Reopened log file at {}", path),
+                    Ok(None) => log_wrn!("SIGUSR2 received, but no log_file is set!"),
+                    Err(e) => log_err!("Could not reopen log file: {}", e),
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn targets() -> SignalTargets {
+        let rpc = Rpc::new("https://node.example.com".to_string(), None, 6, 1, 10.0);
+        SignalTargets {
+            config: Arc::new(RwLock::new(Settings::default())),
+            rpc_list: Arc::new(RwLock::new(vec![rpc])),
+            poverty_list: Arc::new(RwLock::new(Vec::new())),
+            cache: Arc::new(sled::Config::new().temporary(true).open().unwrap()),
+            memory: Arc::new(MemoryBudget::new(None, Default::default())),
+            hot: Arc::new(HotCache::new(1 << 20)),
+            sub_data: Arc::new(SubscriptionData::new()),
+            anomaly: Arc::new(AnomalyDetector::default()),
+        }
+    }
+
+    #[test]
+    fn test_state_report() {
+        let targets = targets();
+        targets.cache.insert(b"key", b"value").unwrap();
+
+        let report = state_report(&targets);
+        assert_eq!(report["nodes"][0]["name"], "https://node.example.com/");
+        assert_eq!(report["poverty"], json!([]));
+        assert_eq!(report["subscriptions"]["users"], 0);
+        assert_eq!(report["cache"]["entries"], 1);
+        assert_eq!(report["cache"]["hot"]["entries"], 0);
+    }
+
+    #[test]
+    fn test_reload_config() {
+        let targets = targets();
+        assert!(reload_config(&targets).is_err());
+
+        let path = std::env::temp_dir().join(format!(
+            "blutgang-reload-{}-{:x}.toml",
+            std::process::id(),
+            rand::random::<u64>()
+        ));
+        fs::write(
+            &path,
+            r#"
+            [blutgang]
+            do_clear = false
+            address = "127.0.0.1:3000"
+            ma_length = 100
+            sort_on_startup = false
+            health_check = false
+            ttl = 60
+            max_retries = 32
+            expected_block_time = 13000
+            supress_rpc_check = false
+
+            [admin]
+            enabled = false
+
+            [sled]
+            db_path = "./blutgang-cache"
+            mode = "HighThroughput"
+            cache_capacity = 1000000000
+            compression = false
+            print_profile = false
+            flush_every_ms = 24000
+
+            [other]
+            url = "https://other.example.com"
+            max_consecutive = 150
+            max_per_second = 200
+            "#,
+        )
+        .unwrap();
+        targets.config.write().unwrap().config_path = Some(path.to_string_lossy().to_string());
+
+        let diff = reload_config(&targets).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(diff["nodesRemoved"], json!(["https://node.example.com/"]));
+        assert_eq!(targets.config.read().unwrap().ttl, 60);
+        assert_eq!(
+            targets.rpc_list.read().unwrap()[0].name,
+            "https://other.example.com/"
+        );
+    }
+}
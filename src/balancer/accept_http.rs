@@ -1,35 +1,250 @@
 use crate::{
+    admin::gauges::REQUESTS_IN_FLIGHT,
     balancer::{
+        block_hashes::{
+            block_not_found,
+            BlockHashes,
+        },
+        block_range::{
+            get_block_range,
+            BlockRange,
+            BLOCK_RANGE,
+        },
+        cache_entry::{
+            CacheKey,
+            EntryMeta,
+        },
+        consistent_reads::{
+            ConsistentReads,
+            SESSION_HEADER,
+        },
+        cors::{
+            origin,
+            Cors,
+        },
+        deprecations::add_warning,
+        ens::{
+            ens_key,
+            EnsCache,
+        },
+        error_map::map_errors,
+        estimate_gas::GasEstimator,
         format::{
-            incoming_to_value,
+            block_hash_param,
+            body_to_value,
+            enforce_jsonrpc,
+            get_block_number_from_request,
+            is_pending_request,
+            normalize_block_param,
             replace_block_tags,
         },
+        graphql::{
+            self,
+            Graphql,
+            GRAPHQL_PATH,
+        },
+        head_retry::{
+            is_near_head,
+            is_not_found,
+            retry_not_found,
+        },
+        hot_cache::{
+            get_tiered,
+            HotCache,
+        },
+        http_get::get_request,
+        logs_paged::{
+            get_logs_page,
+            LogsPage,
+            LOGS_PAGED,
+        },
+        memory::MemoryBudget,
+        mtls::ClientIdentity,
+        openrpc::{
+            openrpc_document,
+            OPENRPC_PATH,
+        },
+        passthrough::{
+            forwarded_headers,
+            negotiate_subprotocol,
+            SUBPROTOCOL_HEADER,
+        },
+        peer_count::PeerCounts,
         processing::{
             cache_querry,
+            can_cache,
+            insert_response,
             update_rpc_latency,
             CacheArgs,
         },
-        selection::select::pick,
+        profiling::StageTimer,
+        quorum::quorum_response,
+        recording::Recorder,
+        request_body::{
+            read_capped,
+            read_prefix,
+            BodyError,
+            Prefix,
+            Streamed,
+            DEFAULT_MAX_SIZE,
+        },
+        rest::{
+            into_rest_response,
+            rest_request,
+            RestError,
+        },
+        revalidate::Revalidator,
+        routing_hints::{
+            RoutingHints,
+            HINTS_HEADER,
+        },
+        schema::check_response,
+        selection::{
+            groups::{
+                group_from_path,
+                pick_group,
+            },
+            routing::Routing,
+            schedules::{
+                pick_preferred,
+                Scheduler,
+            },
+            select::{
+                pick,
+                route_available,
+            },
+        },
+        syncing::syncing_response,
+        tenants::{
+            request_host,
+            request_key,
+            TenantError,
+            Tenants,
+        },
+        wallet::{
+            into_raw_transaction,
+            is_wallet_method,
+            sign_for_broadcast,
+            wallet_call,
+        },
+    },
+    beacon::{
+        proxy::{
+            forward,
+            BEACON_METHOD,
+        },
+        types::BeaconPool,
     },
     cache_error,
+    cluster::{
+        fallback::{
+            request_hops,
+            Fallback,
+            HOPS_HEADER,
+        },
+        peers::{
+            PeerCache,
+            PEER_HEADER,
+        },
+    },
+    config::{
+        system::DebugModule,
+        types::{
+            AdaptiveTimeouts,
+            DeprecationSettings,
+            ErrorMapSettings,
+            HeadRetrySettings,
+            JsonRpcMode,
+            PeerCountSettings,
+            QuorumSettings,
+            RateLimitSettings,
+            RegionSettings,
+            RequestBodySettings,
+            RoutingHintsSettings,
+            SelectionMode,
+            Settings,
+            Tenant,
+            WalletPolicy,
+        },
+    },
+    health::{
+        anomaly::AnomalyDetector,
+        horizon::StateHorizons,
+        safe_block::NamedBlocknumbers,
+    },
+    log_dbg,
     log_err,
     log_info,
     log_wrn,
+    malformed_response,
+    middleware::types::{
+        MiddlewareStack,
+        RequestAction,
+    },
     no_rpc_available,
+    notify::{
+        firehose::{
+            Firehose,
+            FirehoseRecord,
+        },
+        usage::UsageTracker,
+        webhook::{
+            HealthEvent,
+            Notifier,
+        },
+    },
     print_cache_error,
-    rpc::types::Rpc,
+    rate_limited,
+    rpc::{
+        error::RpcError,
+        types::Rpc,
+    },
     rpc_response,
+    store::{
+        logs::LogIndex,
+        receipts::ReceiptStore,
+    },
     timed_out,
+    transactions::{
+        replay::{
+            ReplayGuard,
+            ALLOW_REPLAY_HEADER,
+        },
+        tracker::{
+            TxTracker,
+            NEXT_NONCE,
+        },
+    },
+    verify::{
+        error::VerifyError,
+        state::{
+            is_verifiable,
+            verify_state_response,
+            TrackedHeaders,
+        },
+    },
     websocket::{
+        long_poll::{
+            LongPoll,
+            PollError,
+            PollRequest,
+            POLL_PATH,
+        },
         server::serve_websocket,
+        sessions::Sessions,
+        sse::{
+            subscribe,
+            subscription_call,
+            SseError,
+            SSE_PATH,
+        },
+        stream::StreamOptions,
         types::{
             IncomingResponse,
             SubscriptionData,
+            WsconnMessage,
         },
     },
-    NamedBlocknumbers,
-    Settings,
-    WsconnMessage,
 };
 
 use tokio::sync::{
@@ -38,21 +253,23 @@ use tokio::sync::{
     watch,
 };
 
-use serde_json::Value;
-
-// Select either blake3 or xxhash based on the features
-#[cfg(not(feature = "xxhash"))]
-use blake3::hash;
-
-#[cfg(feature = "xxhash")]
-use xxhash_rust::xxh3::xxh3_64;
-#[cfg(feature = "xxhash")]
-use zerocopy::AsBytes; // Impls AsBytes trait for u64
+use serde_json::{
+    json,
+    Value,
+};
 
 use http_body_util::Full;
 use hyper::{
-    body::Bytes,
-    header::HeaderValue,
+    body::{
+        Body,
+        Bytes,
+    },
+    header::{
+        HeaderValue,
+        CACHE_CONTROL,
+        CONTENT_LENGTH,
+    },
+    Method,
     Request,
 };
 use hyper_tungstenite::{
@@ -67,6 +284,7 @@ use tokio::time::timeout;
 use std::{
     collections::BTreeMap,
     convert::Infallible,
+    fmt::Debug,
     println,
     sync::{
         Arc,
@@ -83,21 +301,85 @@ pub struct ConnectionParams {
     pub rpc_list_rwlock: Arc<RwLock<Vec<Rpc>>>,
     pub channels: RequestChannels,
     pub named_numbers: Arc<RwLock<NamedBlocknumbers>>,
-    pub head_cache: Arc<RwLock<BTreeMap<u64, Vec<String>>>>,
+    pub head_cache: Arc<RwLock<BTreeMap<u64, Vec<CacheKey>>>>,
     pub sub_data: Arc<SubscriptionData>,
+    pub sessions: Arc<Sessions>,
     pub cache: Arc<Db>,
     pub config: Arc<RwLock<Settings>>,
+    pub recorder: Option<Arc<Recorder>>,
+    pub middleware: Arc<MiddlewareStack>,
+    pub notifier: Notifier,
+    pub memory: Arc<MemoryBudget>,
+    pub ens: Arc<EnsCache>,
+    pub hot: Arc<HotCache>,
+    pub gas_estimator: Arc<GasEstimator>,
+    pub tx_tracker: Arc<TxTracker>,
+    pub consistent_reads: Arc<ConsistentReads>,
+    pub block_hashes: Arc<BlockHashes>,
+    pub horizons: Arc<StateHorizons>,
+    pub firehose: Firehose,
+    pub anomaly: Arc<AnomalyDetector>,
+    pub usage: Arc<UsageTracker>,
+    pub replay: Arc<ReplayGuard>,
+    pub revalidator: Arc<Revalidator>,
+    pub cors: Arc<Cors>,
+    pub tenants: Arc<Tenants>,
+    pub scheduler: Arc<Scheduler>,
+    pub peer_cache: Arc<PeerCache>,
+    pub fallback: Arc<Fallback>,
+    pub peer_counts: Arc<PeerCounts>,
+    pub graphql: Arc<Graphql>,
+    pub beacon: Arc<BeaconPool>,
+    pub long_poll: Arc<LongPoll>,
+    pub log_index: Arc<LogIndex>,
+    pub receipts: Arc<ReceiptStore>,
+    // State roots of the heads we saw, to verify proofs against
+    pub headers: Arc<TrackedHeaders>,
+    // Set for clients that authenticated with a certificate
+    pub identity: Option<ClientIdentity>,
+    // Host the client asked for in the TLS handshake
+    pub server_name: Option<String>,
 }
 
 impl ConnectionParams {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         rpc_list_rwlock: &Arc<RwLock<Vec<Rpc>>>,
         channels: RequestChannels,
         named_numbers: &Arc<RwLock<NamedBlocknumbers>>,
-        head_cache: &Arc<RwLock<BTreeMap<u64, Vec<String>>>>,
+        head_cache: &Arc<RwLock<BTreeMap<u64, Vec<CacheKey>>>>,
         sub_data: &Arc<SubscriptionData>,
+        sessions: &Arc<Sessions>,
         cache: &Arc<Db>,
         config: &Arc<RwLock<Settings>>,
+        recorder: &Option<Arc<Recorder>>,
+        middleware: &Arc<MiddlewareStack>,
+        notifier: &Notifier,
+        memory: &Arc<MemoryBudget>,
+        ens: &Arc<EnsCache>,
+        hot: &Arc<HotCache>,
+        gas_estimator: &Arc<GasEstimator>,
+        tx_tracker: &Arc<TxTracker>,
+        consistent_reads: &Arc<ConsistentReads>,
+        block_hashes: &Arc<BlockHashes>,
+        horizons: &Arc<StateHorizons>,
+        firehose: &Firehose,
+        anomaly: &Arc<AnomalyDetector>,
+        usage: &Arc<UsageTracker>,
+        replay: &Arc<ReplayGuard>,
+        revalidator: &Arc<Revalidator>,
+        cors: &Arc<Cors>,
+        tenants: &Arc<Tenants>,
+        scheduler: &Arc<Scheduler>,
+        peer_cache: &Arc<PeerCache>,
+        fallback: &Arc<Fallback>,
+        peer_counts: &Arc<PeerCounts>,
+        graphql: &Arc<Graphql>,
+        beacon: &Arc<BeaconPool>,
+        long_poll: &Arc<LongPoll>,
+        log_index: &Arc<LogIndex>,
+        receipts: &Arc<ReceiptStore>,
+        headers: &Arc<TrackedHeaders>,
     ) -> Self {
         ConnectionParams {
             rpc_list_rwlock: rpc_list_rwlock.clone(),
@@ -105,15 +387,87 @@ impl ConnectionParams {
             named_numbers: named_numbers.clone(),
             head_cache: head_cache.clone(),
             sub_data: sub_data.clone(),
+            sessions: sessions.clone(),
             cache: cache.clone(),
             config: config.clone(),
+            recorder: recorder.clone(),
+            middleware: middleware.clone(),
+            notifier: notifier.clone(),
+            memory: memory.clone(),
+            ens: ens.clone(),
+            hot: hot.clone(),
+            gas_estimator: gas_estimator.clone(),
+            tx_tracker: tx_tracker.clone(),
+            consistent_reads: consistent_reads.clone(),
+            block_hashes: block_hashes.clone(),
+            horizons: horizons.clone(),
+            firehose: firehose.clone(),
+            anomaly: anomaly.clone(),
+            usage: usage.clone(),
+            replay: replay.clone(),
+            revalidator: revalidator.clone(),
+            cors: cors.clone(),
+            tenants: tenants.clone(),
+            scheduler: scheduler.clone(),
+            peer_cache: peer_cache.clone(),
+            fallback: fallback.clone(),
+            peer_counts: peer_counts.clone(),
+            graphql: graphql.clone(),
+            beacon: beacon.clone(),
+            long_poll: long_poll.clone(),
+            log_index: log_index.clone(),
+            receipts: receipts.clone(),
+            headers: headers.clone(),
+            identity: None,
+            server_name: None,
         }
     }
 }
 
 struct RequestParams {
     ttl: u128,
+    adaptive_timeouts: Option<AdaptiveTimeouts>,
     max_retries: u32,
+    jsonrpc_mode: JsonRpcMode,
+    // Heads to verify state responses against, if `verify_proofs` is on
+    verify_proofs: Option<Arc<TrackedHeaders>>,
+    validate_responses: bool,
+    wallet: WalletPolicy,
+    routing_hints: Option<RoutingHintsSettings>,
+    deprecations: Option<DeprecationSettings>,
+    error_map: Option<ErrorMapSettings>,
+    rate_limits: RateLimitSettings,
+    regions: Option<RegionSettings>,
+    quorum: Option<QuorumSettings>,
+    head_retry: Option<HeadRetrySettings>,
+    selection: SelectionMode,
+    // Client headers that go upstream with the request
+    forwarded_headers: Vec<(String, String)>,
+    request_body: Option<RequestBodySettings>,
+    // Cache namespace of the tenant the request is for
+    namespace: Option<String>,
+    identity: Option<ClientIdentity>,
+    // Only forward to nodes in this group
+    group: Option<String>,
+    // Nodes to try first while a schedule prefers them
+    prefer: Option<String>,
+    // Instances to ask before going upstream, unless a peer sent the request
+    peer_cache: Option<Arc<PeerCache>>,
+    // Instance to ask when our nodes can't answer, unless we'd be passing
+    // the request around too many times
+    fallback: Option<Arc<Fallback>>,
+    // Instances the request went through before us
+    hops: u32,
+    // Call made with a GET instead of a body, if `http_get` is on
+    query: Option<Value>,
+    // Answers `net_peerCount` for all our nodes
+    peer_counts: Arc<PeerCounts>,
+    peer_count: PeerCountSettings,
+    // Answers `eth_getLogs` over ranges it indexed
+    log_index: Arc<LogIndex>,
+    // Answers `eth_getTransactionReceipt` for finalized transactions it saw
+    receipts: Arc<ReceiptStore>,
+    timer: StageTimer,
 }
 
 #[derive(Debug)]
@@ -147,6 +501,15 @@ impl Clone for RequestChannels {
     }
 }
 
+// JSON answer with `status`, how we respond to most requests
+pub fn json_response(status: u16, body: impl Into<Bytes>) -> hyper::Response<Full<Bytes>> {
+    hyper::Response::builder()
+        .status(status)
+        .header("Content-Type", "application/json")
+        .body(Full::new(body.into()))
+        .unwrap()
+}
+
 // Macros for accepting requests
 #[macro_export]
 macro_rules! accept {
@@ -161,7 +524,8 @@ macro_rules! accept {
                 $io,
                 service_fn(|req| {
                     let response = accept_request(req, $connection_params);
-                    response
+                    // SSE responses are streamed, the rest are sent as they are
+                    async move { response.await.map($crate::websocket::sse::into_streaming) }
                 }),
             )
             .with_upgrades()
@@ -172,6 +536,14 @@ macro_rules! accept {
     };
 }
 
+// Why a try to get a response from a node failed
+enum Failure {
+    TimedOut,
+    Malformed,
+    RateLimited,
+    NoRpc,
+}
+
 // Macro for getting responses from either the cache or RPC nodes
 macro_rules! get_response {
     (
@@ -185,83 +557,196 @@ macro_rules! get_response {
         $named_numbers:expr,
         $head_cache:expr,
         $ttl:expr,
-        $max_retries:expr
+        $adaptive_timeouts:expr,
+        $max_retries:expr,
+        $routing:expr,
+        $notifier:expr,
+        $memory:expr,
+        $ens:expr,
+        $hot:expr,
+        $hints:expr,
+        $validate_responses:expr,
+        $anomaly:expr,
+        $rate_limits:expr,
+        $forwarded_headers:expr,
+        $peer_cache:expr,
+        $fallback:expr,
+        $hops:expr
     ) => {
-        match $cache.get($tx_hash.as_bytes()) {
+        // Pretend nothing is cached if the client asked us to skip the cache
+        match if $hints.no_cache { Ok(None) } else { get_tiered(&$cache, &$hot, &$tx_hash) } {
             Ok(Some(mut rax)) => {
                 $rpc_position = None;
+                $memory.record_hit($tx_hash.as_bytes());
                 // Reconstruct ID
-                let mut cached: Value = simd_json::serde::from_slice(&mut rax).unwrap();
+                let mut cached: Value = match simd_json::serde::from_slice(&mut rax) {
+                    Ok(cached) => cached,
+                    Err(e) => {
+                        print_cache_error!();
+                        $notifier.notify(HealthEvent::CacheCorruption {
+                            reason: format!("unparsable cache entry: {}", e),
+                        });
+                        return (cache_error!(), $rpc_position);
+                    }
+                };
 
                 cached["id"] = $id.into();
                 cached.to_string()
             },
-            Ok(None) => {
+            Ok(None) => 'miss: {
                 // Kinda jank but set the id back to what it was before
                 $tx["id"] = $id.into();
 
-                // Loop until we get a response
-                let mut rx;
+                // Keys another instance owns come from it, it caches them for the fleet
+                if !$hints.no_cache && $hints.node.is_none() {
+                    if let Some(peer_cache) = &$peer_cache {
+                        if let Some(rax) = peer_cache.fetch(&$tx, &$tx_hash).await {
+                            $rpc_position = None;
+                            break 'miss rax;
+                        }
+                    }
+                }
+
+                // Loop until we get a response, and who it's from
                 let mut retries = 0;
-                loop {
+                // Why the last try failed
+                let mut failure;
+                let answer = loop {
                     // Get the next Rpc in line.
                     let mut rpc;
                     {
                         let mut rpc_list = $rpc_list_rwlock.write().unwrap();
-                        (rpc, $rpc_position) = pick(&mut rpc_list);
+                        (rpc, $rpc_position) = $routing.pick(&mut rpc_list, &$tx, $tx_hash.as_bytes(), retries as usize);
                     }
                     log_info!("Forwarding to: {}", rpc.name);
 
                     // Check if we have any RPCs in the list, if not return error
                     if $rpc_position == None {
-                        return (no_rpc_available!(), None);
+                        break Err(Failure::NoRpc);
                     }
 
                     // Send the request. And return a timeout if it takes too long
                     //
                     // Check if it contains any errors or if its `latest` and insert it if it isn't
+                    let method = $tx["method"].as_str().unwrap_or_default().to_string();
+                    // Slow methods get more time if we can tell how slow they usually are
+                    let request_timeout = match (&$hints.timeout, &$adaptive_timeouts) {
+                        (Some(timeout), _) => *timeout,
+                        (None, Some(adaptive_timeouts)) => adaptive_timeouts.timeout(&rpc.status.methods, &method, $ttl),
+                        (None, None) => Duration::from_millis($ttl.try_into().unwrap()),
+                    };
+                    let time = Instant::now();
                     match timeout(
-                        Duration::from_millis($ttl.try_into().unwrap()),
-                        rpc.send_request($tx.clone()),
+                        request_timeout,
+                        rpc.send_request_with_headers($tx.clone(), &$forwarded_headers),
                     )
                     .await
                     {
-                        Ok(rxa) => {
-                            rx = rxa.unwrap();
-                            break;
+                        Ok(Err(RpcError::RateLimited(retry_after))) => {
+                            let cooldown = rpc.status.cooldown.start(retry_after, &$rate_limits);
+                            log_wrn!("\x1b[93mWrn:\x1b[0m {} rate limited us, cooling down for {}ms, picking new RPC and retrying.", rpc.name, cooldown.as_millis());
+                            failure = Failure::RateLimited;
+                            retries += 1;
+                        },
+                        Ok(Err(err)) => {
+                            log_wrn!("\x1b[93mWrn:\x1b[0m {} failed to answer ({}), picking new RPC and retrying.", rpc.name, err);
+                            rpc.status.score.record(true);
+                            failure = Failure::TimedOut;
+                            retries += 1;
+                        },
+                        Ok(Ok(rxa)) => {
+                            rpc.status.methods.record(&method, time.elapsed());
+                            rpc.status.cooldown.reset();
+
+                            // Broken responses count against the node, try another one
+                            match if $validate_responses { check_response(&$tx, &rxa) } else { Ok(()) } {
+                                Ok(()) => {
+                                    rpc.status.score.record(false);
+                                    break Ok((rxa, rpc.name.clone()));
+                                },
+                                Err(reason) => {
+                                    rpc.status.score.record(true);
+                                    log_wrn!("\x1b[93mWrn:\x1b[0m {} returned a malformed {} response ({}), picking new RPC and retrying.", rpc.name, method, reason);
+                                    if let Some(position) = $rpc_position {
+                                        if let Some(listed) = $rpc_list_rwlock.write().unwrap().get_mut(position) {
+                                            listed.update_latency($ttl as f64);
+                                        }
+                                    }
+                                    $anomaly.malformed_response($rpc_list_rwlock, &rpc.name, &reason);
+                                    failure = Failure::Malformed;
+                                    retries += 1;
+                                },
+                            }
                         },
                         Err(_) => {
                             log_wrn!("\x1b[93mWrn:\x1b[0m An RPC request has timed out, picking new RPC and retrying.");
+                            // Not a latency sample, it's just the timeout we picked
+                            rpc.status.score.record(true);
                             rpc.update_latency($ttl as f64);
+                            failure = Failure::TimedOut;
                             retries += 1;
                         },
                     };
 
                     if retries == $max_retries {
-                        return (timed_out!(), $rpc_position,);
+                        break Err(failure);
                     }
-                }
+                };
+
+                // Another instance might still have nodes that can answer
+                let (mut rx, source) = match answer {
+                    Ok(answer) => answer,
+                    Err(failure) => {
+                        let fallback = match &$fallback {
+                            Some(fallback) => fallback.fetch(&$tx, $hops).await.zip(fallback.url()),
+                            None => None,
+                        };
+                        match fallback {
+                            Some((rax, url)) => {
+                                $rpc_position = None;
+                                (rax, url.to_string())
+                            },
+                            None => {
+                                return match failure {
+                                    Failure::Malformed => (malformed_response!(), $rpc_position,),
+                                    Failure::RateLimited => (rate_limited!(), $rpc_position,),
+                                    Failure::TimedOut => (timed_out!(), $rpc_position,),
+                                    Failure::NoRpc => (no_rpc_available!(), None),
+                                };
+                            },
+                        }
+                    },
+                };
 
                 let cache_args = CacheArgs {
                     finalized_rx: $finalized_rx,
                     named_numbers: $named_numbers,
                     cache: $cache,
                     head_cache: $head_cache,
+                    memory: $memory.clone(),
+                    ens: $ens.clone(),
+                    hot: $hot.clone(),
                 };
 
                 // Don't cache responses that contain errors or missing trie nodes
-                cache_querry(
-                    &mut rx,
-                    $tx,
-                    $tx_hash,
-                    &cache_args,
-                );
+                if !$hints.skips_store() {
+                    cache_querry(
+                        &mut rx,
+                        $tx,
+                        $tx_hash,
+                        Some(&source),
+                        &cache_args,
+                    );
+                }
 
                 rx
             }
-            Err(_) => {
+            Err(e) => {
                 // If anything errors send an rpc request and see if it works, if not then gg
                 print_cache_error!();
+                $notifier.notify(HealthEvent::CacheCorruption {
+                    reason: e.to_string(),
+                });
                 $rpc_position = None;
                 return (cache_error!(), $rpc_position);
             }
@@ -269,22 +754,106 @@ macro_rules! get_response {
     };
 }
 
+// Verify `rax` against a proof from a node other than the one at `position`,
+// and the state root we saw for the block.
+//
+// Returns an error if the node returned data that doesn't match the proof,
+// after evicting it from the cache and flagging the node. A proof that
+// doesn't match our state root is the proof node's fault, so we flag it and
+// keep the response.
+#[allow(clippy::too_many_arguments)]
+async fn verify_response(
+    tx: &Value,
+    rax: &str,
+    position: usize,
+    headers: &TrackedHeaders,
+    rpc_list_rwlock: &Arc<RwLock<Vec<Rpc>>>,
+    cache: &Db,
+    memory: &MemoryBudget,
+    tx_hash: &[u8],
+    notifier: &Notifier,
+) -> Result<(), VerifyError> {
+    let (node, proof_rpc) = {
+        let rpc_list = rpc_list_rwlock.read().unwrap();
+        if rpc_list.is_empty() {
+            return Ok(());
+        }
+
+        // Fall back to the same node if it's the only one we have,
+        // it still has to agree with the state root.
+        let node = rpc_list.get(position).map(|rpc| rpc.name.clone());
+        (node, rpc_list[(position + 1) % rpc_list.len()].clone())
+    };
+    let node = node.unwrap_or_else(|| format!("node {}", position));
+
+    match verify_state_response(tx, rax, &proof_rpc, headers).await {
+        Ok(_) => Ok(()),
+        Err(VerifyError::ProofUnavailable(reason)) => {
+            log_wrn!("Could not verify response from {}: {}", node, reason);
+            Ok(())
+        }
+        Err(err @ VerifyError::InvalidProof(_)) => {
+            log_wrn!("{} sent a proof we can't verify: {}", proof_rpc.name, err);
+            notifier.notify(HealthEvent::UnverifiableResponse {
+                node: proof_rpc.name,
+                method: "eth_getProof".to_string(),
+            });
+            Ok(())
+        }
+        Err(err) => {
+            log_wrn!("{} returned unverifiable data: {}", node, err);
+            if let Ok(Some(entry)) = cache.remove(tx_hash) {
+                memory.release(tx_hash, entry.len());
+            }
+            notifier.notify(HealthEvent::UnverifiableResponse {
+                node,
+                method: tx["method"].as_str().unwrap_or_default().to_string(),
+            });
+            Err(err)
+        }
+    }
+}
+
 // Pick RPC and send request to it. In case the result is cached,
 // read and return from the cache.
-async fn forward_body(
-    tx: Request<hyper::body::Incoming>,
+#[allow(clippy::too_many_arguments)]
+async fn forward_body<B>(
+    tx: Request<B>,
     rpc_list_rwlock: &Arc<RwLock<Vec<Rpc>>>,
     finalized_rx: &watch::Receiver<u64>,
     named_numbers: &Arc<RwLock<NamedBlocknumbers>>,
-    head_cache: &Arc<RwLock<BTreeMap<u64, Vec<String>>>>,
+    head_cache: &Arc<RwLock<BTreeMap<u64, Vec<CacheKey>>>>,
     cache: Arc<Db>,
-    params: RequestParams,
+    recorder: &Option<Arc<Recorder>>,
+    middleware: &MiddlewareStack,
+    notifier: &Notifier,
+    memory: &Arc<MemoryBudget>,
+    ens: &Arc<EnsCache>,
+    hot: &Arc<HotCache>,
+    gas_estimator: &GasEstimator,
+    tx_tracker: &TxTracker,
+    consistent_reads: &ConsistentReads,
+    block_hashes: &BlockHashes,
+    horizons: &StateHorizons,
+    firehose: &Firehose,
+    anomaly: &Arc<AnomalyDetector>,
+    usage: &UsageTracker,
+    replay: &ReplayGuard,
+    revalidator: &Revalidator,
+    mut params: RequestParams,
 ) -> (
     Result<hyper::Response<Full<Bytes>>, Infallible>,
     Option<usize>,
-) {
-    // Check if body has application/json
-    if tx.headers().get("content-type") != Some(&HeaderValue::from_static("application/json")) {
+)
+where
+    B: Body + Debug,
+    B::Error: Debug,
+{
+    // Check if body has application/json, GETs don't have one
+    let query = params.query.take();
+    if query.is_none()
+        && tx.headers().get("content-type") != Some(&HeaderValue::from_static("application/json"))
+    {
         return (
             Ok(hyper::Response::builder()
                 .status(400)
@@ -294,8 +863,514 @@ async fn forward_body(
         );
     }
 
-    // Convert incoming body to serde value
-    let mut tx = incoming_to_value(tx).await.unwrap();
+    let session = tx
+        .headers()
+        .get(SESSION_HEADER)
+        .and_then(|session| session.to_str().ok())
+        .map(str::to_string);
+    let hints_header = tx
+        .headers()
+        .get(HINTS_HEADER)
+        .and_then(|hints| hints.to_str().ok())
+        .map(str::to_string);
+    let cache_control = tx
+        .headers()
+        .get(CACHE_CONTROL)
+        .and_then(|cache_control| cache_control.to_str().ok())
+        .map(str::to_string);
+    let allow_replay = tx
+        .headers()
+        .get(ALLOW_REPLAY_HEADER)
+        .is_some_and(|allow| allow.as_bytes().eq_ignore_ascii_case(b"true"));
+
+    // Start timing before the body is read if we're mirroring this request
+    let mirror_time = firehose.sample().then(Instant::now);
+
+    // Convert incoming body to serde value, big ones go to a node as they come in
+    log_dbg!(DebugModule::Http, "Incoming request: {:?}", tx);
+    let max_size = params
+        .request_body
+        .map_or(DEFAULT_MAX_SIZE, |request_body| request_body.max_size);
+    let content_length = tx
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|length| length.to_str().ok())
+        .and_then(|length| length.parse::<usize>().ok());
+    if query.is_none() && content_length.is_some_and(|length| length > max_size) {
+        return (
+            Ok(BodyError::TooLarge(max_size).into_response(Value::Null)),
+            None,
+        );
+    }
+    // Requests that need all of their body checked or changed are never streamed
+    let stream = params
+        .request_body
+        .and_then(|request_body| request_body.stream_above)
+        .is_some_and(|stream_above| content_length.is_some_and(|length| length > stream_above))
+        && params.jsonrpc_mode == JsonRpcMode::Off
+        && middleware.is_empty()
+        && hints_header.is_none();
+    let body = if let Some(query) = query {
+        Ok(query)
+    } else if stream {
+        match read_prefix(tx.into_body(), max_size).await {
+            Ok(Prefix::Streamed(streamed)) => {
+                return forward_streamed(streamed, rpc_list_rwlock, usage, params).await
+            }
+            Ok(Prefix::Whole(body)) => Ok(body_to_value(&body)),
+            Err(e) => Err(e),
+        }
+    } else {
+        read_capped(tx.into_body(), max_size)
+            .await
+            .map(|body| body_to_value(&body))
+    };
+    let mut tx = match body {
+        Ok(tx) => tx,
+        Err(e) => return (Ok(e.into_response(Value::Null)), None),
+    };
+
+    // Check the request against the JSON-RPC spec and reject/repair it if needed
+    if let Err(err) = enforce_jsonrpc(&mut tx, params.jsonrpc_mode) {
+        return (
+            Ok(hyper::Response::builder()
+                .status(400)
+                .header("Content-Type", "application/json")
+                .body(Full::new(Bytes::from(err.to_string())))
+                .unwrap()),
+            None,
+        );
+    }
+
+    // Warn clients still calling methods we're about to stop serving
+    let deprecation = params.deprecations.as_ref().and_then(|deprecations| {
+        let method = tx["method"].as_str().unwrap_or_default();
+        deprecations
+            .warning(method)
+            .map(|warning| (warning.to_string(), deprecations.warn_in))
+    });
+    if deprecation.is_some() {
+        log_info!(
+            "Deprecated method {} called by {}",
+            tx["method"],
+            params
+                .identity
+                .as_ref()
+                .map_or("an anonymous client", |identity| identity.name.as_str())
+        );
+    }
+    params.timer.mark("parse");
+
+    let (response, rpc_position) = forward_value(
+        tx,
+        session,
+        hints_header,
+        cache_control,
+        allow_replay,
+        mirror_time,
+        rpc_list_rwlock,
+        finalized_rx,
+        named_numbers,
+        head_cache,
+        cache,
+        recorder,
+        middleware,
+        notifier,
+        memory,
+        ens,
+        hot,
+        gas_estimator,
+        tx_tracker,
+        consistent_reads,
+        block_hashes,
+        horizons,
+        firehose,
+        anomaly,
+        usage,
+        replay,
+        revalidator,
+        params,
+    )
+    .await;
+
+    match (response, deprecation) {
+        (Ok(response), Some((warning, warn_in))) => {
+            (
+                Ok(add_warning(response, &warning, warn_in).await),
+                rpc_position,
+            )
+        }
+        (response, _) => (response, rpc_position),
+    }
+}
+
+// Send a big request to a node as its body comes in, see `request_body`
+async fn forward_streamed<B>(
+    mut streamed: Streamed<B>,
+    rpc_list_rwlock: &Arc<RwLock<Vec<Rpc>>>,
+    usage: &UsageTracker,
+    params: RequestParams,
+) -> (
+    Result<hyper::Response<Full<Bytes>>, Infallible>,
+    Option<usize>,
+)
+where
+    B: Body + Debug,
+    B::Error: Debug,
+{
+    let method = streamed.method().to_string();
+
+    // Same checks as `forward_value`, on what we know of the request so far
+    if let Some(rax) = params
+        .identity
+        .as_ref()
+        .and_then(|identity| identity.deny(&json!({ "id": streamed.id(), "method": method })))
+    {
+        return (Ok(json_response(403, rax.to_string())), None);
+    }
+    usage.record(
+        params
+            .identity
+            .as_ref()
+            .map(|identity| identity.name.as_str()),
+        &method,
+    );
+
+    let (rpc, rpc_position) = {
+        let mut rpc_list = rpc_list_rwlock.write().unwrap();
+        let preferred = params
+            .prefer
+            .as_deref()
+            .and_then(|prefer| pick_preferred(&mut rpc_list, prefer, false));
+        let picked = if let Some(group) = &params.group {
+            pick_group(&mut rpc_list, group, false)
+        } else if let Some(preferred) = preferred {
+            preferred
+        } else {
+            pick(&mut rpc_list)
+        };
+        route_available(&rpc_list, picked, &json!({ "method": method }))
+    };
+    if rpc_position.is_none() {
+        return (no_rpc_available!(), None);
+    }
+    log_info!("Streaming {} to: {}", method, rpc.name);
+
+    let time = Instant::now();
+    let response = timeout(
+        Duration::from_millis(params.ttl.try_into().unwrap_or(u64::MAX)),
+        streamed.send(&rpc, &params.forwarded_headers),
+    )
+    .await;
+    let response = match response {
+        Ok(Ok(response)) => response,
+        Ok(Err(e)) => return (Ok(e.into_response(streamed.id())), rpc_position),
+        Err(_) => {
+            return (
+                Ok(BodyError::Upstream("timed out".to_string()).into_response(streamed.id())),
+                rpc_position,
+            )
+        }
+    };
+    rpc.status.methods.record(&method, time.elapsed());
+
+    (Ok(json_response(200, response)), rpc_position)
+}
+
+// Answer an already parsed request, see `forward_body`
+#[allow(clippy::too_many_arguments)]
+async fn forward_value(
+    mut tx: Value,
+    session: Option<String>,
+    hints_header: Option<String>,
+    cache_control: Option<String>,
+    allow_replay: bool,
+    mirror_time: Option<Instant>,
+    rpc_list_rwlock: &Arc<RwLock<Vec<Rpc>>>,
+    finalized_rx: &watch::Receiver<u64>,
+    named_numbers: &Arc<RwLock<NamedBlocknumbers>>,
+    head_cache: &Arc<RwLock<BTreeMap<u64, Vec<CacheKey>>>>,
+    cache: Arc<Db>,
+    recorder: &Option<Arc<Recorder>>,
+    middleware: &MiddlewareStack,
+    notifier: &Notifier,
+    memory: &Arc<MemoryBudget>,
+    ens: &Arc<EnsCache>,
+    hot: &Arc<HotCache>,
+    gas_estimator: &GasEstimator,
+    tx_tracker: &TxTracker,
+    consistent_reads: &ConsistentReads,
+    block_hashes: &BlockHashes,
+    horizons: &StateHorizons,
+    firehose: &Firehose,
+    anomaly: &Arc<AnomalyDetector>,
+    usage: &UsageTracker,
+    replay: &ReplayGuard,
+    revalidator: &Revalidator,
+    mut params: RequestParams,
+) -> (
+    Result<hyper::Response<Full<Bytes>>, Infallible>,
+    Option<usize>,
+) {
+    // Certificate identities can only call what their policy allows
+    if let Some(rax) = params
+        .identity
+        .as_ref()
+        .and_then(|identity| identity.deny(&tx))
+    {
+        return (Ok(json_response(403, rax.to_string())), None);
+    }
+
+    // Count the call towards the client's usage
+    usage.record(
+        params
+            .identity
+            .as_ref()
+            .map(|identity| identity.name.as_str()),
+        tx["method"].as_str().unwrap_or_default(),
+    );
+
+    // Clients can steer single requests if their policy allows it
+    let hints = match &params.routing_hints {
+        Some(settings) => {
+            let hints = RoutingHints::take(&mut tx, hints_header.as_deref()).and_then(|hints| {
+                let identity = params
+                    .identity
+                    .as_ref()
+                    .map(|identity| identity.name.as_str());
+                let mut hints = match hints {
+                    Some(hints) => {
+                        // Nodes outside the group can't be picked through its path
+                        let rpc_list = rpc_list_rwlock
+                            .read()
+                            .unwrap()
+                            .iter()
+                            .filter(|rpc| {
+                                params
+                                    .group
+                                    .as_ref()
+                                    .map_or(true, |group| rpc.in_group(group))
+                            })
+                            .cloned()
+                            .collect::<Vec<Rpc>>();
+                        hints.check(settings, identity, &rpc_list)?;
+                        hints
+                    }
+                    None => RoutingHints::default(),
+                };
+                if let Some(cache_control) =
+                    cache_control.as_deref().filter(|_| settings.cache_control)
+                {
+                    hints.add_cache_control(cache_control, settings, identity);
+                }
+                Ok(hints)
+            });
+            match hints {
+                Ok(hints) => hints,
+                Err(err) => {
+                    return (
+                        Ok(json_response(200, err.to_response(&tx["id"]).to_string())),
+                        None,
+                    );
+                }
+            }
+        }
+        None => {
+            RoutingHints::strip(&mut tx);
+            RoutingHints::default()
+        }
+    };
+
+    // Let middleware modify the request or answer it on its own
+    if let RequestAction::Respond(rax) = middleware.on_request(&mut tx) {
+        return (Ok(json_response(200, rax.to_string())), None);
+    }
+
+    // Wallet methods never go to the regular rotation. Transactions signed by
+    // an external signer get broadcast like any other raw transaction.
+    if tx["method"].as_str().is_some_and(is_wallet_method) {
+        let rax = match sign_for_broadcast(&tx, &params.wallet).await {
+            Some(Ok(raw)) => {
+                into_raw_transaction(&mut tx, raw);
+                None
+            }
+            Some(Err(rax)) => Some(rax),
+            None => Some(wallet_call(&tx, &params.wallet).await),
+        };
+
+        if let Some(rax) = rax {
+            return (Ok(json_response(200, rax.to_string())), None);
+        }
+    }
+
+    // Clients stuck in a retry loop don't get to send the same transaction forever
+    if tx["method"] == "eth_sendRawTransaction" && !allow_replay {
+        if let Some(rax) = replay.check(
+            &tx,
+            params
+                .identity
+                .as_ref()
+                .map(|identity| identity.name.as_str()),
+        ) {
+            return (Ok(json_response(200, rax.to_string())), None);
+        }
+    }
+
+    // Block ranges get split into regular `eth_getBlockByNumber` requests
+    if tx["method"] == BLOCK_RANGE {
+        let cache_args = CacheArgs {
+            finalized_rx: finalized_rx.clone(),
+            named_numbers: named_numbers.clone(),
+            cache: cache.clone(),
+            head_cache: head_cache.clone(),
+            memory: memory.clone(),
+            ens: ens.clone(),
+            hot: hot.clone(),
+        };
+        let blocks = match BlockRange::from_params(&tx["params"]) {
+            Ok(range) => {
+                get_block_range(
+                    range,
+                    rpc_list_rwlock,
+                    params.group.as_deref(),
+                    params.namespace.as_deref(),
+                    &cache_args,
+                    params.ttl,
+                )
+                .await
+            }
+            Err(err) => Err(err),
+        };
+        let rax = match blocks {
+            Ok(blocks) => json!({"jsonrpc": "2.0", "id": tx["id"], "result": blocks}),
+            Err(err) => {
+                json!({
+                    "jsonrpc": "2.0",
+                    "id": tx["id"],
+                    "error": {"code": err.code(), "message": err.to_string()},
+                })
+            }
+        };
+
+        return (Ok(json_response(200, rax.to_string())), None);
+    }
+
+    // Log pages get split into regular `eth_getLogs` requests
+    if tx["method"] == LOGS_PAGED {
+        let cache_args = CacheArgs {
+            finalized_rx: finalized_rx.clone(),
+            named_numbers: named_numbers.clone(),
+            cache: cache.clone(),
+            head_cache: head_cache.clone(),
+            memory: memory.clone(),
+            ens: ens.clone(),
+            hot: hot.clone(),
+        };
+        // Split so every node we could ask can answer the parts
+        let max_log_range = rpc_list_rwlock
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|rpc| {
+                params
+                    .group
+                    .as_deref()
+                    .map_or(true, |group| rpc.in_group(group))
+            })
+            .filter_map(|rpc| rpc.profile.and_then(|profile| profile.max_log_range()))
+            .min();
+        let blocks = StreamOptions::default()
+            .blocks
+            .min(max_log_range.unwrap_or(u64::MAX));
+
+        let page = match LogsPage::from_params(&tx["params"]) {
+            Ok(page) => {
+                get_logs_page(
+                    &page,
+                    blocks,
+                    rpc_list_rwlock,
+                    params.group.as_deref(),
+                    params.namespace.as_deref(),
+                    &cache_args,
+                    params.ttl,
+                )
+                .await
+            }
+            Err(err) => Err(err),
+        };
+        let rax = match page {
+            Ok(page) => json!({"jsonrpc": "2.0", "id": tx["id"], "result": page}),
+            Err(err) => {
+                json!({
+                    "jsonrpc": "2.0",
+                    "id": tx["id"],
+                    "error": {"code": err.code(), "message": err.to_string()},
+                })
+            }
+        };
+
+        return (Ok(json_response(200, rax.to_string())), None);
+    }
+
+    // Nonces are answered from the tracker and every node we ask
+    if tx["method"] == NEXT_NONCE && tx_tracker.nonce_tracking() {
+        let rax = tx_tracker
+            .next_nonce(&tx, rpc_list_rwlock, params.group.as_deref(), params.ttl)
+            .await;
+        return (Ok(json_response(200, rax.to_string())), None);
+    }
+
+    // Equivalent EIP-1898 block parameters should hit the same cache entry
+    normalize_block_param(&mut tx);
+
+    // Reads within a session all see the same block
+    if let Some(session) = session.filter(|_| consistent_reads.is_enabled()) {
+        let latest = named_numbers.read().unwrap().latest;
+        // Clients only see their own sessions
+        let scope = format!(
+            "{}/{}",
+            params.namespace.as_deref().unwrap_or_default(),
+            params
+                .identity
+                .as_ref()
+                .map_or("", |identity| identity.name.as_str())
+        );
+        if let Some(snapshot) = consistent_reads
+            .snapshot(
+                &scope,
+                &session,
+                rpc_list_rwlock,
+                params.group.as_deref(),
+                latest,
+                params.ttl,
+            )
+            .await
+        {
+            if tx["method"] == "eth_blockNumber" {
+                let rax = snapshot.block_number(&tx["id"]);
+                return (Ok(json_response(200, rax.to_string())), None);
+            }
+            block_hashes.insert(&snapshot.hash, snapshot.number);
+            snapshot.pin(&mut tx);
+        }
+    }
+
+    // Requests pinned to a block hash need to know which block that is
+    let hash_number = match block_hash_param(&tx) {
+        Some(hash) => {
+            match block_hashes
+                .resolve(&hash, rpc_list_rwlock, params.ttl)
+                .await
+            {
+                Some(number) => Some(number),
+                None => {
+                    let rax = block_not_found(&tx["id"], &hash);
+                    return (Ok(json_response(200, rax.to_string())), None);
+                }
+            }
+        }
+        None => None,
+    };
 
     // Get the id of the request and set it to 0 for caching
     //
@@ -305,26 +1380,180 @@ async fn forward_body(
     let id = tx["id"].take().as_u64().unwrap_or(0);
 
     // Hash the request with either blake3 or xxhash depending on the enabled feature
-    let tx_hash;
-    #[cfg(not(feature = "xxhash"))]
-    {
-        tx_hash = hash(tx.to_string().as_bytes());
+    let tx_hash = match &params.namespace {
+        Some(namespace) => CacheKey::namespaced(namespace, &tx),
+        None => CacheKey::new(&tx),
+    };
+
+    // Count `latest` requests so the hottest get fetched ahead of clients.
+    // Tenants' entries are refreshed by their own requests, not from our nodes.
+    if !hints.no_cache && params.namespace.is_none() {
+        revalidator.record(&tx, tx_hash);
     }
-    #[cfg(feature = "xxhash")]
-    {
-        tx_hash = xxh3_64(tx.to_string().as_bytes());
+
+    // When replaying, answer only from the recording and never touch the RPCs
+    if let Some(recorder) = recorder.as_ref().filter(|recorder| recorder.is_replay()) {
+        let rax = recorder.replay(&tx, id.into());
+        return (Ok(json_response(200, rax)), None);
+    }
+
+    // Local copies came from a single node, so they don't make a quorum
+    let needs_quorum = params.quorum.as_ref().is_some_and(|quorum| {
+        tx["method"]
+            .as_str()
+            .is_some_and(|method| quorum.class(method).is_some())
+    });
+
+    // Logs of ranges we indexed don't need a node
+    if tx["method"] == "eth_getLogs" && !needs_quorum && params.log_index.is_enabled() {
+        let log_index = params.log_index.clone();
+        let filter = tx["params"][0].clone();
+        if let Ok(Some(logs)) = tokio::task::spawn_blocking(move || log_index.query(&filter)).await
+        {
+            let rax = json!({"jsonrpc": "2.0", "id": id, "result": logs});
+            return (Ok(json_response(200, rax.to_string())), None);
+        }
+    }
+
+    // So do receipts of finalized transactions we've seen before
+    if tx["method"] == "eth_getTransactionReceipt" && !needs_quorum {
+        if let Some(receipt) = params.receipts.get(&tx["params"][0]) {
+            let rax = json!({"jsonrpc": "2.0", "id": id, "result": receipt});
+            return (Ok(json_response(200, rax.to_string())), None);
+        }
+    }
+
+    // Whether we're syncing depends on all our nodes, not the one we'd pick
+    if hints.node.is_none() {
+        if let Some(rax) = syncing_response(
+            &tx,
+            id.into(),
+            rpc_list_rwlock,
+            params.group.as_deref(),
+            params.ttl,
+        )
+        .await
+        {
+            return (Ok(json_response(200, rax.to_string())), None);
+        }
+    }
+
+    // And so do our peers
+    if hints.node.is_none() {
+        if let Some(rax) = params
+            .peer_counts
+            .response(
+                &tx,
+                id.into(),
+                rpc_list_rwlock,
+                params.group.as_deref(),
+                &params.peer_count,
+                params.ttl,
+            )
+            .await
+        {
+            return (Ok(json_response(200, rax.to_string())), None);
+        }
+    }
+
+    // Gas estimates come from several nodes if configured
+    if gas_estimator.is_enabled() {
+        if let Some(mut rax) = gas_estimator
+            .estimate(
+                &tx,
+                rpc_list_rwlock,
+                params.group.as_deref(),
+                params.namespace.as_deref(),
+                params.ttl,
+            )
+            .await
+        {
+            rax["id"] = id.into();
+            return (Ok(json_response(200, rax.to_string())), None);
+        }
+    }
+
+    // ENS lookups at the tip get their own cache, keyed before we pin `latest`
+    let ens_key = ens_key(&tx, params.namespace.as_deref())
+        .filter(|_| ens.is_enabled() && !hints.no_cache && !needs_quorum);
+    if let Some(result) = ens_key.as_ref().and_then(|key| ens.get(key)) {
+        return (
+            Ok(json_response(
+                200,
+                json!({"jsonrpc": "2.0", "id": id, "result": result}).to_string(),
+            )),
+            None,
+        );
     }
 
+    // Keep the request as the user sent it so we can record it, mirror it
+    // and pass it to middleware later
+    let original_tx =
+        (recorder.is_some() || !middleware.is_empty() || mirror_time.is_some()).then(|| tx.clone());
+
     // RPC used to get the response, we use it to update the latency for it later.
     let mut rpc_position;
 
+    // Pending state lives in the mempool of designated nodes
+    let pending = is_pending_request(&tx);
+
     // Rewrite named block parameters if possible
     let mut tx = replace_block_tags(&mut tx, named_numbers);
 
+    // Methods that need a quorum never trust a single node, or the cache
+    if let Some(quorum) = &params.quorum {
+        if let Some(rax) = quorum_response(
+            quorum,
+            &tx,
+            id.into(),
+            rpc_list_rwlock,
+            params.group.as_deref(),
+            params.ttl,
+        )
+        .await
+        {
+            return (Ok(json_response(200, rax.to_string())), None);
+        }
+    }
+
+    // Keep the pinned request around if we need to verify the response
+    let verify_tx = (params.verify_proofs.is_some()
+        && tx["method"].as_str().is_some_and(is_verifiable))
+    .then(|| tx.clone());
+    let cross_check_tx = anomaly.is_cross_checked(&tx).then(|| tx.clone());
+    let hash_block = hash_number.map(|number| {
+        (
+            number,
+            tx["method"].as_str().unwrap_or_default().to_string(),
+        )
+    });
+    // Historical calls go to the node with the least history that still has them
+    let history_block = horizons
+        .is_enabled()
+        .then(|| hash_number.or_else(|| get_block_number_from_request(tx.clone(), named_numbers)))
+        .flatten();
+    let broadcast_raw = (tx_tracker.is_enabled() && tx["method"] == "eth_sendRawTransaction")
+        .then(|| tx["params"][0].as_str().map(str::to_string))
+        .flatten();
+    let store_receipt = params.receipts.is_enabled() && tx["method"] == "eth_getTransactionReceipt";
+    // Blocks at the head might not have reached the node we ask yet
+    let head_retry_tx = params
+        .head_retry
+        .as_ref()
+        .filter(|_| hints.node.is_none())
+        .and_then(|head_retry| {
+            let block =
+                hash_number.or_else(|| get_block_number_from_request(tx.clone(), named_numbers))?;
+            let latest = named_numbers.read().unwrap().latest;
+            is_near_head(block, latest, head_retry.depth).then(|| tx.clone())
+        });
+
+    params.timer.mark("prepare");
+
     // Get the response from either the DB or from a RPC. If it timeouts, retry.
-    let rax = get_response!(
+    let mut rax = get_response!(
         tx,
-        cache,
+        cache.clone(),
         tx_hash,
         rpc_position,
         id,
@@ -333,9 +1562,196 @@ async fn forward_body(
         named_numbers.clone(),
         head_cache.clone(),
         params.ttl,
-        params.max_retries
+        params.adaptive_timeouts,
+        params.max_retries,
+        Routing {
+            node: hints.node.as_deref(),
+            history_block,
+            horizons,
+            group: params.group.as_deref(),
+            prefer: params.prefer.as_deref(),
+            regions: params.regions.as_ref(),
+            selection: params.selection,
+            pending,
+        },
+        notifier,
+        memory,
+        ens,
+        hot,
+        hints,
+        params.validate_responses,
+        anomaly,
+        params.rate_limits,
+        params.forwarded_headers,
+        params.peer_cache,
+        params.fallback,
+        params.hops
     );
 
+    // Other nodes might have the block the one we asked doesn't have yet
+    if let (Some(head_retry), Some(head_retry_tx), Some(position)) =
+        (&params.head_retry, head_retry_tx, rpc_position)
+    {
+        if is_not_found(&head_retry_tx, &rax) {
+            if let Some((answer, position)) = retry_not_found(
+                head_retry,
+                &head_retry_tx,
+                position,
+                params.group.as_deref(),
+                rpc_list_rwlock,
+                &params.forwarded_headers,
+                params.ttl,
+            )
+            .await
+            {
+                rax = answer;
+                rpc_position = Some(position);
+            }
+        }
+    }
+    params.timer.mark(if rpc_position.is_some() {
+        "upstream"
+    } else {
+        "cache"
+    });
+
+    if let Some(key) = ens_key.filter(|_| !hints.no_store) {
+        ens.insert(key, &rax);
+    }
+
+    // Responses for finalized block hashes never change
+    if let (Some((number, method)), Some(position)) = (hash_block, rpc_position) {
+        if number <= *finalized_rx.borrow() && !hints.skips_store() && can_cache(&method, &rax) {
+            let cache_args = CacheArgs {
+                finalized_rx: finalized_rx.clone(),
+                named_numbers: named_numbers.clone(),
+                cache: cache.clone(),
+                head_cache: head_cache.clone(),
+                memory: memory.clone(),
+                ens: ens.clone(),
+                hot: hot.clone(),
+            };
+            let source = rpc_list_rwlock
+                .read()
+                .unwrap()
+                .get(position)
+                .map(|rpc| rpc.name.clone());
+            let meta = EntryMeta::new(&method, source.as_deref());
+            insert_response(&mut rax.clone(), tx_hash, number, &meta, &cache_args);
+        }
+    }
+
+    // Remember the nonces of transactions we broadcast, and rebroadcast them if asked to
+    if let Some(raw) = broadcast_raw {
+        if serde_json::from_str::<Value>(&rax).is_ok_and(|response| response["result"].is_string())
+        {
+            tx_tracker.track(
+                &raw,
+                params
+                    .identity
+                    .as_ref()
+                    .map(|identity| identity.name.as_str()),
+            );
+        }
+    }
+
+    // Empty results get checked against another node in the background
+    if let (Some(cross_check_tx), Some(position)) = (cross_check_tx, rpc_position) {
+        anomaly.cross_check(cross_check_tx, &rax, position, rpc_list_rwlock);
+    }
+
+    // Cross-check the response with a proof from another node.
+    // Cached responses were already checked when they were inserted.
+    if let (Some(verify_tx), Some(position), Some(headers)) =
+        (verify_tx, rpc_position, params.verify_proofs.as_deref())
+    {
+        if let Err(err) = verify_response(
+            &verify_tx,
+            &rax,
+            position,
+            headers,
+            rpc_list_rwlock,
+            &cache,
+            memory,
+            tx_hash.as_bytes(),
+            notifier,
+        )
+        .await
+        {
+            return (
+                rpc_response!(
+                    200,
+                    Full::new(Bytes::from(
+                        json!({
+                            "jsonrpc": "2.0",
+                            "id": id,
+                            "error": {"code": -32603, "message": err.to_string()},
+                        })
+                        .to_string()
+                    ))
+                ),
+                rpc_position,
+            );
+        }
+    }
+
+    // Keep receipts from nodes once they're finalized
+    if store_receipt && rpc_position.is_some() {
+        params
+            .receipts
+            .insert_response(&rax, *finalized_rx.borrow());
+    }
+
+    // Nodes with their own error codes get theirs replaced by the standard ones
+    let profile = rpc_position.and_then(|position| {
+        rpc_list_rwlock
+            .read()
+            .unwrap()
+            .get(position)
+            .and_then(|rpc| rpc.profile)
+    });
+    let rax = match profile {
+        Some(profile) => profile.standardize_errors(rax),
+        None => rax,
+    };
+
+    // Clients see the same errors no matter which node answered
+    let rax = match &params.error_map {
+        Some(error_map) => map_errors(rax, error_map),
+        None => rax,
+    };
+
+    let rax = match original_tx {
+        Some(original_tx) => {
+            if let Some(recorder) = recorder {
+                if let Err(e) = recorder.record(&original_tx, &rax) {
+                    log_err!("Failed to record response: {}", e);
+                }
+            }
+
+            let rax = middleware.on_response(&original_tx, rax);
+
+            if let Some(time) = mirror_time {
+                let node = rpc_position.and_then(|position| {
+                    rpc_list_rwlock
+                        .read()
+                        .unwrap()
+                        .get(position)
+                        .map(|rpc| rpc.name.clone())
+                });
+                firehose.send(FirehoseRecord {
+                    request: original_tx,
+                    response: rax.clone(),
+                    node,
+                    latency: time.elapsed(),
+                });
+            }
+
+            rax
+        }
+        None => rax,
+    };
+
     // Convert rx to bytes and but it in a Buf
     let body = hyper::body::Bytes::from(rax);
 
@@ -343,28 +1759,362 @@ async fn forward_body(
     let body = Full::new(body);
 
     // Build the response
-    let res = hyper::Response::builder()
+    let mut res = hyper::Response::builder()
         .status(200)
         .header("Content-Type", "application/json")
-        .header("Access-Control-Allow-Origin", "*")
         .body(body)
         .unwrap();
 
+    params.timer.mark("post");
+    params.timer.apply(&mut res);
+
     (Ok(res), rpc_position)
 }
 
+// Answer a request to `GRAPHQL_PATH`
+async fn graphql_request<B>(
+    tx: Request<B>,
+    tenant: Option<&(String, Tenant)>,
+    connection_params: &ConnectionParams,
+) -> hyper::Response<Full<Bytes>>
+where
+    B: Body,
+    B::Error: Debug,
+{
+    if tx.method() != Method::POST {
+        return hyper::Response::builder()
+            .status(405)
+            .header("Allow", "POST")
+            .body(Full::new(Bytes::new()))
+            .unwrap();
+    }
+
+    let (max_size, rate_limits) = {
+        let config_guard = connection_params.config.read().unwrap();
+        (
+            config_guard
+                .request_body
+                .map_or(DEFAULT_MAX_SIZE, |request_body| request_body.max_size),
+            config_guard.rate_limits,
+        )
+    };
+    let body = match read_capped(tx.into_body(), max_size).await {
+        Ok(body) => body,
+        Err(e) => return e.into_response(Value::Null),
+    };
+    let request = match graphql::parse(&body) {
+        Some(request) => request,
+        None => return graphql::error_response(400, "Expected a JSON body with a query"),
+    };
+
+    // Same policies and usage as JSON-RPC calls
+    let method = graphql::method(&request);
+    let identity = connection_params.identity.as_ref();
+    if let Some(rax) = identity.and_then(|identity| identity.deny(&json!({ "method": method }))) {
+        return graphql::error_response(403, rax["error"]["message"].as_str().unwrap_or_default());
+    }
+    connection_params
+        .usage
+        .record(identity.map(|identity| identity.name.as_str()), method);
+
+    let head = connection_params.named_numbers.read().unwrap().latest;
+    connection_params
+        .graphql
+        .serve(
+            &request,
+            &connection_params.rpc_list_rwlock,
+            head,
+            &rate_limits,
+            tenant.and_then(|(_, tenant)| tenant.group.as_deref()),
+            tenant.map(|(_, tenant)| tenant.namespace.as_str()),
+        )
+        .await
+}
+
+// Send a request under the `[beacon]` prefix to a beacon node as `path`
+async fn beacon_request<B>(
+    tx: Request<B>,
+    path: String,
+    tenant: Option<&(String, Tenant)>,
+    connection_params: &ConnectionParams,
+) -> hyper::Response<Full<Bytes>>
+where
+    B: Body,
+    B::Error: Debug,
+{
+    // Beacon nodes are shared, tenants with their own nodes can't use ours
+    if let Some((name, _)) = tenant.filter(|(_, tenant)| tenant.group.is_some()) {
+        return TenantError::NoBeacon(name.clone()).into_response();
+    }
+
+    // Same policies and usage as JSON-RPC calls
+    let identity = connection_params.identity.as_ref();
+    if let Some(rax) =
+        identity.and_then(|identity| identity.deny(&json!({ "method": BEACON_METHOD })))
+    {
+        return json_response(403, rax.to_string());
+    }
+    connection_params.usage.record(
+        identity.map(|identity| identity.name.as_str()),
+        BEACON_METHOD,
+    );
+
+    let max_size = connection_params
+        .config
+        .read()
+        .unwrap()
+        .request_body
+        .map_or(DEFAULT_MAX_SIZE, |request_body| request_body.max_size);
+    let (parts, body) = tx.into_parts();
+    let body = match read_capped(body, max_size).await {
+        Ok(body) => body,
+        Err(e) => return e.into_response(Value::Null),
+    };
+
+    let path = match parts.uri.query() {
+        Some(query) => format!("{}?{}", path, query),
+        None => path,
+    };
+    forward(
+        &connection_params.beacon,
+        parts.method,
+        &path,
+        &parts.headers,
+        body,
+    )
+    .await
+}
+
+// Answer a request to `SSE_PATH` with a stream of subscription events
+async fn sse_request<B>(
+    tx: &Request<B>,
+    tenant: Option<&(String, Tenant)>,
+    connection_params: &ConnectionParams,
+) -> Result<hyper::Response<Full<Bytes>>, SseError> {
+    let (is_ws, coalesce_new_heads) = {
+        let config_guard = connection_params.config.read().unwrap();
+        (config_guard.is_ws, config_guard.coalesce_new_heads)
+    };
+    if !is_ws {
+        return Err(SseError::Disabled);
+    }
+
+    let call = subscription_call(tx.uri().query())?;
+    if let Some(response) = check_subscriber(&call, tenant, connection_params) {
+        return Ok(response);
+    }
+    record_subscriber(connection_params);
+    let cache_args = CacheArgs {
+        finalized_rx: connection_params.channels.finalized_rx.as_ref().clone(),
+        named_numbers: connection_params.named_numbers.clone(),
+        cache: connection_params.cache.clone(),
+        head_cache: connection_params.head_cache.clone(),
+        memory: connection_params.memory.clone(),
+        ens: connection_params.ens.clone(),
+        hot: connection_params.hot.clone(),
+    };
+    subscribe(
+        call,
+        &connection_params.sub_data,
+        &connection_params.channels.incoming_tx,
+        &connection_params.channels.outgoing_rx,
+        &cache_args,
+        coalesce_new_heads,
+    )
+    .await
+}
+
+// Checks a subscription over SSE or long-polling goes through, like an
+// `eth_subscribe` over WS would. Subscriptions are shared between everyone,
+// so tenants with their own nodes can't have them.
+fn check_subscriber(
+    call: &Value,
+    tenant: Option<&(String, Tenant)>,
+    connection_params: &ConnectionParams,
+) -> Option<hyper::Response<Full<Bytes>>> {
+    if let Some((name, _)) = tenant.filter(|(_, tenant)| tenant.group.is_some()) {
+        return Some(TenantError::NoSubscriptions(name.clone()).into_response());
+    }
+
+    connection_params
+        .identity
+        .as_ref()
+        .and_then(|identity| identity.deny(call))
+        .map(|rax| json_response(403, rax.to_string()))
+}
+
+// Count a new subscription towards the client's usage
+fn record_subscriber(connection_params: &ConnectionParams) {
+    connection_params.usage.record(
+        connection_params
+            .identity
+            .as_ref()
+            .map(|identity| identity.name.as_str()),
+        "eth_subscribe",
+    );
+}
+
+// Answer a request to `POLL_PATH`
+async fn poll_request<B>(
+    tx: &Request<B>,
+    tenant: Option<&(String, Tenant)>,
+    connection_params: &ConnectionParams,
+) -> Result<hyper::Response<Full<Bytes>>, PollError> {
+    let delete = match *tx.method() {
+        Method::GET => false,
+        Method::DELETE => true,
+        _ => {
+            return Ok(hyper::Response::builder()
+                .status(405)
+                .header("Allow", "GET, DELETE")
+                .body(Full::new(Bytes::new()))
+                .unwrap())
+        }
+    };
+    if !connection_params.config.read().unwrap().is_ws {
+        return Err(SseError::Disabled.into());
+    }
+
+    // Pollers can only be read by whoever created them
+    let owner = match (tenant, &connection_params.identity) {
+        (None, None) => None,
+        (tenant, identity) => {
+            Some(format!(
+                "{}/{}",
+                tenant.map_or("", |(name, _)| name.as_str()),
+                identity
+                    .as_ref()
+                    .map_or("", |identity| identity.name.as_str())
+            ))
+        }
+    };
+
+    let long_poll = &connection_params.long_poll;
+    let request = PollRequest::from_query(delete, tx.uri().query())?;
+    let call = match &request {
+        PollRequest::Subscribe(call) => call.clone(),
+        _ => json!({"method": "eth_subscribe"}),
+    };
+    if let Some(response) = check_subscriber(&call, tenant, connection_params) {
+        return Ok(response);
+    }
+
+    let rax = match request {
+        PollRequest::Subscribe(call) => {
+            record_subscriber(connection_params);
+            let cache_args = CacheArgs {
+                finalized_rx: connection_params.channels.finalized_rx.as_ref().clone(),
+                named_numbers: connection_params.named_numbers.clone(),
+                cache: connection_params.cache.clone(),
+                head_cache: connection_params.head_cache.clone(),
+                memory: connection_params.memory.clone(),
+                ens: connection_params.ens.clone(),
+                hot: connection_params.hot.clone(),
+            };
+            long_poll
+                .subscribe(
+                    call,
+                    owner,
+                    &connection_params.sub_data,
+                    &connection_params.channels.incoming_tx,
+                    &connection_params.channels.outgoing_rx,
+                    &cache_args,
+                )
+                .await?
+        }
+        PollRequest::Poll { id, cursor, wait } => {
+            long_poll.poll(&id, owner.as_deref(), cursor, wait).await?
+        }
+        PollRequest::Unsubscribe(id) => {
+            long_poll.unsubscribe(&id, owner.as_deref(), &connection_params.sub_data)?;
+            json!({"id": id, "result": true})
+        }
+    };
+
+    Ok(hyper::Response::builder()
+        .status(200)
+        .header("Content-Type", "application/json")
+        .header("Cache-Control", "no-store")
+        .body(Full::new(Bytes::from(rax.to_string())))
+        .unwrap())
+}
+
 // Forward the request to *a* RPC picked by the algo set by the user.
 // Measures the time needed for a request, and updates the respective
 // RPC lself.
 // In case of a timeout, returns an error.
-pub async fn accept_request(
-    mut tx: Request<hyper::body::Incoming>,
+pub async fn accept_request<B>(
+    mut tx: Request<B>,
     connection_params: ConnectionParams,
-) -> Result<hyper::Response<Full<Bytes>>, Infallible> {
+) -> Result<hyper::Response<Full<Bytes>>, Infallible>
+where
+    B: Body + Debug,
+    B::Error: Debug,
+{
+    let _in_flight = REQUESTS_IN_FLIGHT.track();
+
+    // Browsers ask before sending anything cross-origin
+    let origin = origin(&tx);
+    if tx.method() == Method::OPTIONS {
+        return Ok(connection_params.cors.preflight(origin.as_deref()));
+    }
+    if let Err(err) = connection_params.cors.check(origin.as_deref()) {
+        let mut response = err.into_response();
+        connection_params
+            .cors
+            .apply(&mut response, origin.as_deref());
+        return Ok(response);
+    }
+
+    // The host picks the tenant, if we serve several
+    let tenant = match connection_params.tenants.admit(
+        request_host(&tx, connection_params.server_name.as_deref()),
+        request_key(&tx),
+    ) {
+        Ok(tenant) => tenant.map(|(name, tenant)| (name.to_string(), tenant.clone())),
+        Err(err) => {
+            let mut response = err.into_response();
+            connection_params
+                .cors
+                .apply(&mut response, origin.as_deref());
+            return Ok(response);
+        }
+    };
+
+    // Subscriptions over SSE, for clients that can't keep a WS open
+    if tx.method() == Method::GET && tx.uri().path() == SSE_PATH {
+        let mut response = sse_request(&tx, tenant.as_ref(), &connection_params)
+            .await
+            .unwrap_or_else(|e| e.into_response());
+        connection_params
+            .cors
+            .apply(&mut response, origin.as_deref());
+        return Ok(response);
+    }
+
+    // And over long-polling, for clients that can't keep anything open
+    if tx.uri().path() == POLL_PATH {
+        let mut response = poll_request(&tx, tenant.as_ref(), &connection_params)
+            .await
+            .unwrap_or_else(|e| e.into_response());
+        connection_params
+            .cors
+            .apply(&mut response, origin.as_deref());
+        return Ok(response);
+    }
+
     // Check if the request is a websocket upgrade request.
     if is_upgrade_request(&tx) {
         log_info!("Received WS upgrade request");
 
+        if let Some((name, _)) = tenant {
+            let mut response = TenantError::NoWebSockets(name).into_response();
+            connection_params
+                .cors
+                .apply(&mut response, origin.as_deref());
+            return Ok(response);
+        }
+
         if !connection_params.config.read().unwrap().is_ws {
             return rpc_response!(
                 500,
@@ -374,8 +2124,24 @@ pub async fn accept_request(
             );
         }
 
+        let subprotocol = negotiate_subprotocol(
+            connection_params
+                .config
+                .read()
+                .unwrap()
+                .passthrough
+                .as_ref(),
+            tx.headers(),
+        );
         let (response, websocket) = match upgrade(&mut tx, None) {
-            Ok((response, websocket)) => (response, websocket),
+            Ok((mut response, websocket)) => {
+                if let Some(subprotocol) = subprotocol {
+                    response
+                        .headers_mut()
+                        .insert(SUBPROTOCOL_HEADER, subprotocol);
+                }
+                (response, websocket)
+            }
             Err(e) => {
                 log_err!("Websocket upgrade error: {}", e);
                 return rpc_response!(500, Full::new(Bytes::from(
@@ -385,11 +2151,33 @@ pub async fn accept_request(
             }
         };
 
+        let (jsonrpc_mode, wallet, coalesce_new_heads, idle, error_map) = {
+            let config_guard = connection_params.config.read().unwrap();
+            (
+                config_guard.jsonrpc_mode,
+                config_guard.wallet.clone(),
+                config_guard.coalesce_new_heads,
+                config_guard.idle_connections.clone(),
+                config_guard.error_map.clone(),
+            )
+        };
+        // Streamed log ranges are split so every node we have can answer them
+        let max_log_range = connection_params
+            .rpc_list_rwlock
+            .read()
+            .unwrap()
+            .iter()
+            .filter_map(|rpc| rpc.profile.and_then(|profile| profile.max_log_range()))
+            .min();
+
         let cache_args = CacheArgs {
             finalized_rx: connection_params.channels.finalized_rx.as_ref().clone(),
             named_numbers: connection_params.named_numbers.clone(),
             cache: connection_params.cache,
             head_cache: connection_params.head_cache.clone(),
+            memory: connection_params.memory.clone(),
+            ens: connection_params.ens.clone(),
+            hot: connection_params.hot.clone(),
         };
 
         // Spawn a task to handle the websocket connection.
@@ -399,7 +2187,17 @@ pub async fn accept_request(
                 connection_params.channels.incoming_tx,
                 connection_params.channels.outgoing_rx,
                 connection_params.sub_data.clone(),
+                connection_params.sessions.clone(),
                 cache_args,
+                jsonrpc_mode,
+                wallet,
+                connection_params.middleware.clone(),
+                connection_params.identity,
+                connection_params.usage.clone(),
+                coalesce_new_heads,
+                idle,
+                error_map,
+                max_log_range,
             )
             .await
             {
@@ -411,19 +2209,183 @@ pub async fn accept_request(
         return Ok(response);
     }
 
+    // Tooling can ask what we accept
+    if tx.method() == Method::GET && tx.uri().path() == OPENRPC_PATH {
+        let document = openrpc_document(
+            &connection_params.config.read().unwrap(),
+            connection_params.identity.as_ref(),
+        );
+        let mut response = json_response(200, document.to_string());
+        connection_params
+            .cors
+            .apply(&mut response, origin.as_deref());
+        return Ok(response);
+    }
+
+    // GraphQL goes to the nodes that serve it, as it is
+    if tx.uri().path() == GRAPHQL_PATH {
+        let mut response = graphql_request(tx, tenant.as_ref(), &connection_params).await;
+        connection_params
+            .cors
+            .apply(&mut response, origin.as_deref());
+        return Ok(response);
+    }
+
+    // Consensus layer requests go to beacon nodes, if we have any
+    if let Some(path) = connection_params.beacon.strip_prefix(tx.uri().path()) {
+        let path = path.to_string();
+        let mut response = beacon_request(tx, path, tenant.as_ref(), &connection_params).await;
+        connection_params
+            .cors
+            .apply(&mut response, origin.as_deref());
+        return Ok(response);
+    }
+
+    // Simple calls can be made with a GET, if we allow them
+    let http_get = connection_params.config.read().unwrap().http_get;
+    let query = (tx.method() == Method::GET && http_get)
+        .then(|| get_request(tx.uri().path(), tx.uri().query()))
+        .flatten();
+    let query = match query.transpose() {
+        Ok(query) => query,
+        Err(err) => {
+            let mut response = err.into_response();
+            connection_params
+                .cors
+                .apply(&mut response, origin.as_deref());
+            return Ok(response);
+        }
+    };
+
+    // So can REST calls under `[blutgang.rest]`, which get a REST answer
+    let rest_settings = connection_params.config.read().unwrap().rest.clone();
+    let rest = rest_settings.and_then(|settings| {
+        let call = rest_request(tx.uri().path(), tx.uri().query(), &settings)?.and_then(|call| {
+            match tx.method() == Method::GET {
+                true => Ok(call),
+                false => Err(RestError::MethodNotAllowed),
+            }
+        });
+        Some(call.map(|call| (call, settings)))
+    });
+    let (query, rest) = match rest.transpose() {
+        Ok(Some((call, settings))) => (Some(call.request.clone()), Some((call, settings))),
+        Ok(None) => (query, None),
+        Err(err) => {
+            let mut response = err.into_response();
+            connection_params
+                .cors
+                .apply(&mut response, origin.as_deref());
+            return Ok(response);
+        }
+    };
+
+    // Requests to `/group/<name>` only go to nodes in that group, and
+    // tenants with their own nodes can't reach anyone else's
+    let tenant_group = tenant.as_ref().and_then(|(_, tenant)| tenant.group.clone());
+    let group = group_from_path(tx.uri().path()).map(str::to_string);
+    if let Some(group) = &group {
+        let exists = tenant_group
+            .as_ref()
+            .map_or(true, |tenant_group| tenant_group == group)
+            && connection_params
+                .config
+                .read()
+                .unwrap()
+                .rpc_list
+                .iter()
+                .any(|rpc| rpc.in_group(group));
+        if !exists {
+            let mut response = json_response(
+                404,
+                json!({
+                    "jsonrpc": "2.0",
+                    "id": null,
+                    "error": {"code": -32601, "message": format!("Unknown node group: {}", group)},
+                })
+                .to_string(),
+            );
+            connection_params
+                .cors
+                .apply(&mut response, origin.as_deref());
+            return Ok(response);
+        }
+    }
+    let group = group.or(tenant_group);
+
     // Send request and measure time
-    let response: Result<hyper::Response<Full<Bytes>>, Infallible>;
+    let mut response: Result<hyper::Response<Full<Bytes>>, Infallible>;
     let rpc_position: Option<usize>;
 
-    // RequestParams from config
+    // Other instances forwarding to us as their fallback tell us how many
+    // went before, so we don't send it around in circles
+    let hops = request_hops(
+        tx.headers()
+            .get(HOPS_HEADER)
+            .and_then(|hops| hops.to_str().ok()),
+    );
+
+    // RequestParams from config, and the schedules active right now
+    let policy = connection_params.scheduler.policy();
     let params = {
         let config_guard = connection_params.config.read().unwrap();
         RequestParams {
             ttl: config_guard.ttl,
+            adaptive_timeouts: config_guard.adaptive_timeouts.clone(),
             max_retries: config_guard.max_retries,
+            jsonrpc_mode: config_guard.jsonrpc_mode,
+            verify_proofs: config_guard
+                .verify_proofs
+                .then(|| connection_params.headers.clone()),
+            validate_responses: config_guard.validate_responses,
+            wallet: config_guard.wallet.clone(),
+            routing_hints: config_guard.routing_hints.clone(),
+            deprecations: config_guard.deprecations.clone(),
+            error_map: config_guard.error_map.clone(),
+            rate_limits: config_guard.rate_limits,
+            regions: config_guard.regions.clone(),
+            quorum: config_guard.quorum.clone(),
+            head_retry: config_guard.head_retry.clone(),
+            selection: policy.selection.unwrap_or(config_guard.selection),
+            forwarded_headers: forwarded_headers(config_guard.passthrough.as_ref(), tx.headers()),
+            request_body: config_guard.request_body,
+            namespace: tenant.as_ref().map(|(_, tenant)| tenant.namespace.clone()),
+            identity: connection_params.identity.clone(),
+            group,
+            prefer: policy.prefer,
+            // Peers don't know which tenant a request is for, so tenants
+            // only share our own cache
+            peer_cache: (tenant.is_none()
+                && !connection_params.peer_cache.is_peer(
+                    tx.headers()
+                        .get(PEER_HEADER)
+                        .and_then(|peer| peer.to_str().ok()),
+                ))
+            .then(|| connection_params.peer_cache.clone()),
+            // Neither does the instance we fall back to
+            fallback: (tenant.is_none() && connection_params.fallback.allows(hops))
+                .then(|| connection_params.fallback.clone()),
+            hops,
+            query,
+            peer_counts: connection_params.peer_counts.clone(),
+            peer_count: config_guard.peer_count,
+            log_index: connection_params.log_index.clone(),
+            receipts: connection_params.receipts.clone(),
+            timer: StageTimer::new(),
         }
     };
 
+    #[cfg(feature = "http3")]
+    let alt_svc = connection_params
+        .config
+        .read()
+        .unwrap()
+        .http3
+        .as_ref()
+        .and_then(|http3| {
+            HeaderValue::from_str(&format!("h3=\":{}\"; ma=86400", http3.address.port())).ok()
+        });
+
     // Check if we have the response hashed, and if not forward it
     // to the best available RPC.
     //
@@ -436,12 +2398,45 @@ pub async fn accept_request(
         &connection_params.named_numbers,
         &connection_params.head_cache,
         connection_params.cache,
+        &connection_params.recorder,
+        &connection_params.middleware,
+        &connection_params.notifier,
+        &connection_params.memory,
+        &connection_params.ens,
+        &connection_params.hot,
+        &connection_params.gas_estimator,
+        &connection_params.tx_tracker,
+        &connection_params.consistent_reads,
+        &connection_params.block_hashes,
+        &connection_params.horizons,
+        &connection_params.firehose,
+        &connection_params.anomaly,
+        &connection_params.usage,
+        &connection_params.replay,
+        &connection_params.revalidator,
         params,
     )
     .await;
     let time = time.elapsed();
     log_info!("Request time: {:?}", time);
 
+    if let Some((call, settings)) = &rest {
+        response = match response {
+            Ok(response) => Ok(into_rest_response(response, call, settings).await),
+            Err(e) => Err(e),
+        };
+    }
+
+    if let Ok(response) = response.as_mut() {
+        connection_params.cors.apply(response, origin.as_deref());
+
+        // Let clients know they can switch to HTTP/3
+        #[cfg(feature = "http3")]
+        if let Some(alt_svc) = alt_svc {
+            response.headers_mut().insert("Alt-Svc", alt_svc);
+        }
+    }
+
     // `rpc_position` is an Option<> that either contains the index of the RPC
     // we forwarded our request to, or is None if the result was cached.
     //
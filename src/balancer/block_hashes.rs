@@ -0,0 +1,215 @@
+// Block numbers of EIP-1898 `{"blockHash": ..}` block parameters.
+//
+// Requests pinned to a block hash can't tell us how old that block is, so we
+// ask a node for its header before forwarding them. That tells us whether
+// the response can be cached as immutable, and lets us answer with a clean
+// error if the block doesn't exist instead of whatever the node says. A hash
+// always has the same number, so we remember the ones we've seen.
+use crate::{
+    rpc::types::hex_to_decimal,
+    Rpc,
+};
+
+use std::{
+    collections::{
+        BTreeMap,
+        HashMap,
+    },
+    sync::{
+        Arc,
+        Mutex,
+        RwLock,
+    },
+    time::Duration,
+};
+
+use futures::future::join_all;
+use serde_json::{
+    json,
+    Value,
+};
+use tokio::time::timeout;
+
+// Forget the least recently used hash once we have this many
+const MAX_ENTRIES: usize = 10_000;
+
+// Nodes we ask about a hash we don't know. Anyone can send us random
+// hashes, so each one should cost a bounded amount of upstream work.
+const MAX_LOOKUPS: usize = 3;
+
+// EIP-1898 error for block hashes no node knows about
+pub fn block_not_found(id: &Value, hash: &str) -> Value {
+    json!({
+        "jsonrpc": "2.0",
+        "id": id,
+        "error": {"code": -32001, "message": format!("Block {} not found", hash)},
+    })
+}
+
+#[derive(Debug, Default)]
+struct Numbers {
+    // Hash -> (number, last access)
+    entries: HashMap<String, (u64, u64)>,
+    // Last access -> hash, oldest first
+    order: BTreeMap<u64, String>,
+    // Logical clock for LRU
+    clock: u64,
+}
+
+impl Numbers {
+    fn tick(&mut self) -> u64 {
+        self.clock += 1;
+        self.clock
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct BlockHashes {
+    numbers: Mutex<Numbers>,
+}
+
+impl BlockHashes {
+    pub fn get(&self, hash: &str) -> Option<u64> {
+        let mut numbers = self.numbers.lock().unwrap_or_else(|e| e.into_inner());
+        let tick = numbers.tick();
+        let numbers = &mut *numbers;
+        let (number, last_access) = numbers.entries.get_mut(hash)?;
+        let hash = numbers.order.remove(last_access)?;
+        numbers.order.insert(tick, hash);
+        *last_access = tick;
+        Some(*number)
+    }
+
+    pub fn insert(&self, hash: &str, number: u64) {
+        let hash = hash.to_lowercase();
+        let mut numbers = self.numbers.lock().unwrap_or_else(|e| e.into_inner());
+        let tick = numbers.tick();
+        if let Some((_, last_access)) = numbers.entries.remove(&hash) {
+            numbers.order.remove(&last_access);
+        }
+        while numbers.entries.len() >= MAX_ENTRIES {
+            let Some((_, oldest)) = numbers.order.pop_first() else {
+                break;
+            };
+            numbers.entries.remove(&oldest);
+        }
+        numbers.order.insert(tick, hash.clone());
+        numbers.entries.insert(hash, (number, tick));
+    }
+
+    // Number of the block with `hash`, asking up to `MAX_LOOKUPS` of our
+    // nodes at once. Returns `None` if none of them have it.
+    pub async fn resolve(
+        &self,
+        hash: &str,
+        rpc_list: &Arc<RwLock<Vec<Rpc>>>,
+        ttl: u128,
+    ) -> Option<u64> {
+        if let Some(number) = self.get(hash) {
+            return Some(number);
+        }
+
+        let nodes: Vec<Rpc> = {
+            let rpc_list = rpc_list.read().unwrap_or_else(|e| e.into_inner());
+            rpc_list.iter().take(MAX_LOOKUPS).cloned().collect()
+        };
+        let request_timeout = Duration::from_millis(ttl.try_into().unwrap_or(u64::MAX));
+        let numbers = join_all(nodes.iter().map(|rpc| {
+            async move {
+                let header = timeout(
+                    request_timeout,
+                    rpc.call("eth_getBlockByHash", json!([hash, false])),
+                )
+                .await;
+                match header {
+                    Ok(Ok(header)) => {
+                        header["number"]
+                            .as_str()
+                            .and_then(|n| hex_to_decimal(n).ok())
+                    }
+                    _ => None,
+                }
+            }
+        }))
+        .await;
+
+        let number = numbers.into_iter().flatten().next()?;
+        self.insert(hash, number);
+        Some(number)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::node::MockNode;
+
+    const HASH: &str = "0x88df016429689c079f3b2f6ad39fa052532c56795b733da78a91ebe6a713944b";
+
+    #[tokio::test]
+    async fn test_resolve() {
+        let behind = MockNode::spawn(1).await.unwrap();
+        behind.set_response("eth_getBlockByHash", Value::Null);
+        let synced = MockNode::spawn(1).await.unwrap();
+        synced.set_response("eth_getBlockByHash", json!({"number": "0x10"}));
+        let rpc_list = Arc::new(RwLock::new(vec![
+            Rpc::new(behind.http_url(), None, 6, 0, 10.0),
+            Rpc::new(synced.http_url(), None, 6, 0, 10.0),
+        ]));
+
+        // The first node doesn't have it yet
+        let block_hashes = BlockHashes::default();
+        assert_eq!(block_hashes.resolve(HASH, &rpc_list, 1000).await, Some(16));
+
+        // Remembered after that
+        assert_eq!(block_hashes.resolve(HASH, &rpc_list, 1000).await, Some(16));
+        assert_eq!(synced.request_count(), 1);
+
+        synced.set_response("eth_getBlockByHash", Value::Null);
+        assert!(BlockHashes::default()
+            .resolve(HASH, &rpc_list, 1000)
+            .await
+            .is_none());
+
+        let error = block_not_found(&json!(1), HASH);
+        assert_eq!(error["error"]["code"], -32001);
+    }
+
+    #[tokio::test]
+    async fn test_resolve_is_bounded() {
+        let mut nodes = Vec::new();
+        for _ in 0..MAX_LOOKUPS + 1 {
+            let node = MockNode::spawn(1).await.unwrap();
+            node.set_response("eth_getBlockByHash", Value::Null);
+            nodes.push(node);
+        }
+        let rpc_list = Arc::new(RwLock::new(
+            nodes
+                .iter()
+                .map(|node| Rpc::new(node.http_url(), None, 6, 0, 10.0))
+                .collect(),
+        ));
+
+        assert!(BlockHashes::default()
+            .resolve(HASH, &rpc_list, 1000)
+            .await
+            .is_none());
+        assert_eq!(nodes[MAX_LOOKUPS].request_count(), 0);
+    }
+
+    #[test]
+    fn test_insert_evicts_least_recently_used() {
+        let block_hashes = BlockHashes::default();
+        for number in 0..MAX_ENTRIES as u64 {
+            block_hashes.insert(&format!("0x{:x}", number), number);
+        }
+
+        // Reading the oldest hash keeps it around
+        assert_eq!(block_hashes.get("0x0"), Some(0));
+        block_hashes.insert(HASH, 16);
+        assert_eq!(block_hashes.get("0x0"), Some(0));
+        assert_eq!(block_hashes.get("0x1"), None);
+        assert_eq!(block_hashes.get(HASH), Some(16));
+        assert_eq!(block_hashes.get("0x2"), Some(2));
+    }
+}
@@ -0,0 +1,464 @@
+// `blutgang_getBlockRange(start, end, full_tx)`
+//
+// Fetches every block in `[start, end]` and returns them as one array, so
+// clients don't have to send thousands of `eth_getBlockByNumber` calls.
+// Blocks are fetched in parallel from nodes picked with the regular algo,
+// and go through the cache just like regular `eth_getBlockByNumber` requests
+// do. A block a node fails to get is retried on the others before we give up.
+// Tenants' blocks only come from their group, and go into their namespace.
+use crate::{
+    balancer::{
+        cache_entry::CacheKey,
+        hot_cache::get_tiered,
+        processing::{
+            cache_querry,
+            CacheArgs,
+        },
+        selection::select::pick_available,
+    },
+    rpc::types::Rpc,
+};
+
+use std::{
+    fmt,
+    sync::{
+        Arc,
+        RwLock,
+    },
+    time::Duration,
+};
+
+use futures::stream::{
+    self,
+    StreamExt,
+};
+use serde_json::{
+    json,
+    Value,
+};
+use tokio::time::timeout;
+
+pub const BLOCK_RANGE: &str = "blutgang_getBlockRange";
+
+// Most blocks we serve in a single request
+pub const MAX_BLOCK_RANGE: u64 = 1000;
+
+// Blocks we fetch at the same time
+const CONCURRENCY: usize = 32;
+
+// Errors
+#[derive(Debug, PartialEq, Eq)]
+pub enum BlockRangeError {
+    InvalidParams(String),
+    NoRpcAvailable,
+    TimedOut(u64),
+    InvalidResponse(String),
+}
+
+impl fmt::Display for BlockRangeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BlockRangeError::InvalidParams(msg) => write!(f, "Invalid params: {}", msg),
+            BlockRangeError::NoRpcAvailable => write!(f, "No RPC available"),
+            BlockRangeError::TimedOut(number) => write!(f, "Timed out fetching block {}", number),
+            BlockRangeError::InvalidResponse(msg) => write!(f, "Invalid response: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for BlockRangeError {}
+
+impl BlockRangeError {
+    // JSON-RPC error code we return to the client
+    pub fn code(&self) -> i64 {
+        match self {
+            BlockRangeError::InvalidParams(_) => -32602,
+            _ => -32603,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlockRange {
+    pub start: u64,
+    pub end: u64,
+    pub full_tx: bool,
+}
+
+// Hex or decimal, as a string or a plain number
+pub fn parse_number(value: &Value) -> Option<u64> {
+    match value {
+        Value::String(number) => {
+            match number.strip_prefix("0x") {
+                Some(hex) => u64::from_str_radix(hex, 16).ok(),
+                None => number.parse().ok(),
+            }
+        }
+        number => number.as_u64(),
+    }
+}
+
+impl BlockRange {
+    // Parse `[start, end, full_tx]`. `full_tx` defaults to false.
+    pub fn from_params(params: &Value) -> Result<Self, BlockRangeError> {
+        let start = parse_number(&params[0]).ok_or(BlockRangeError::InvalidParams(
+            "start must be a block number".to_string(),
+        ))?;
+        let end = parse_number(&params[1]).ok_or(BlockRangeError::InvalidParams(
+            "end must be a block number".to_string(),
+        ))?;
+        let full_tx = match &params[2] {
+            Value::Null => false,
+            full_tx => {
+                full_tx.as_bool().ok_or(BlockRangeError::InvalidParams(
+                    "full_tx must be a boolean".to_string(),
+                ))?
+            }
+        };
+
+        if end < start {
+            return Err(BlockRangeError::InvalidParams(
+                "end must not be lower than start".to_string(),
+            ));
+        }
+        if end - start >= MAX_BLOCK_RANGE {
+            return Err(BlockRangeError::InvalidParams(format!(
+                "range is larger than {} blocks",
+                MAX_BLOCK_RANGE
+            )));
+        }
+
+        Ok(BlockRange {
+            start,
+            end,
+            full_tx,
+        })
+    }
+
+    // `eth_getBlockByNumber` requests for every block in the range, without an id
+    // so they hash the same as the ones clients send us
+    pub fn requests(&self) -> impl Iterator<Item = (u64, Value)> + '_ {
+        (self.start..=self.end).map(|number| {
+            (
+                number,
+                json!({
+                    "jsonrpc": "2.0",
+                    "id": null,
+                    "method": "eth_getBlockByNumber",
+                    "params": [format!("0x{:x}", number), self.full_tx],
+                }),
+            )
+        })
+    }
+}
+
+// Get a single block from `rpc`
+async fn send_block(
+    number: u64,
+    request: &Value,
+    rpc: &Rpc,
+    ttl: u128,
+) -> Result<(String, Value), BlockRangeError> {
+    let rx = match timeout(
+        Duration::from_millis(ttl.try_into().unwrap_or(u64::MAX)),
+        rpc.send_request(request.clone()),
+    )
+    .await
+    {
+        Ok(Ok(rx)) => rx,
+        Ok(Err(e)) => return Err(BlockRangeError::InvalidResponse(e.to_string())),
+        Err(_) => return Err(BlockRangeError::TimedOut(number)),
+    };
+
+    let response: Value =
+        serde_json::from_str(&rx).map_err(|e| BlockRangeError::InvalidResponse(e.to_string()))?;
+    if let Some(error) = response.get("error") {
+        return Err(BlockRangeError::InvalidResponse(error.to_string()));
+    }
+
+    Ok((rx, response))
+}
+
+// Get a single block, either from the cache or from our nodes. Every node
+// that can take it gets a go before we give up on the block.
+async fn fetch_block(
+    number: u64,
+    request: Value,
+    rpc_list: &Arc<RwLock<Vec<Rpc>>>,
+    group: Option<&str>,
+    namespace: Option<&str>,
+    cache_args: &CacheArgs,
+    ttl: u128,
+) -> Result<Value, BlockRangeError> {
+    let tx_hash = match namespace {
+        Some(namespace) => CacheKey::namespaced(namespace, &request),
+        None => CacheKey::new(&request),
+    };
+
+    if let Ok(Some(cached)) = get_tiered(&cache_args.cache, &cache_args.hot, &tx_hash) {
+        if let Ok(cached) = serde_json::from_slice::<Value>(&cached) {
+            cache_args.memory.record_hit(tx_hash.as_bytes());
+            return Ok(cached["result"].clone());
+        }
+    }
+
+    // Nodes treat requests without an id as notifications, and we don't
+    // cache requests containing `null`
+    let mut request = request;
+    request["id"] = 1.into();
+
+    let mut tried = Vec::new();
+    let mut failure = BlockRangeError::NoRpcAvailable;
+    loop {
+        let rpc = match pick_available(
+            &mut rpc_list.write().unwrap_or_else(|e| e.into_inner()),
+            &request,
+            group,
+            &tried,
+        ) {
+            (rpc, Some(_)) => rpc,
+            (_, None) => return Err(failure),
+        };
+        match send_block(number, &request, &rpc, ttl).await {
+            Ok((mut rx, response)) => {
+                cache_querry(&mut rx, request, tx_hash, Some(&rpc.name), cache_args);
+                return Ok(response["result"].clone());
+            }
+            Err(err) => {
+                failure = err;
+                tried.push(rpc.name);
+            }
+        }
+    }
+}
+
+// Fetch all the blocks in `range` in order. Blocks the nodes don't have yet
+// are `null`. Only nodes in `group` are asked if it's set.
+pub async fn get_block_range(
+    range: BlockRange,
+    rpc_list: &Arc<RwLock<Vec<Rpc>>>,
+    group: Option<&str>,
+    namespace: Option<&str>,
+    cache_args: &CacheArgs,
+    ttl: u128,
+) -> Result<Vec<Value>, BlockRangeError> {
+    if rpc_list.read().unwrap().is_empty() {
+        return Err(BlockRangeError::NoRpcAvailable);
+    }
+
+    stream::iter(range.requests())
+        .map(|(number, request)| {
+            fetch_block(number, request, rpc_list, group, namespace, cache_args, ttl)
+        })
+        .buffered(CONCURRENCY)
+        .collect::<Vec<_>>()
+        .await
+        .into_iter()
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::balancer::cache_entry::{
+        get_entry,
+        insert_entry,
+    };
+
+    #[test]
+    fn test_block_range_from_params() {
+        assert_eq!(
+            BlockRange::from_params(&json!(["0x10", "0x12", true])),
+            Ok(BlockRange {
+                start: 16,
+                end: 18,
+                full_tx: true,
+            })
+        );
+        assert_eq!(
+            BlockRange::from_params(&json!([16, "18"])),
+            Ok(BlockRange {
+                start: 16,
+                end: 18,
+                full_tx: false,
+            })
+        );
+
+        assert!(BlockRange::from_params(&json!(["latest", "0x12"])).is_err());
+        assert!(BlockRange::from_params(&json!(["0x12", "0x10"])).is_err());
+        assert!(BlockRange::from_params(&json!([0, MAX_BLOCK_RANGE])).is_err());
+        assert!(BlockRange::from_params(&json!([0, MAX_BLOCK_RANGE - 1])).is_ok());
+        assert!(BlockRange::from_params(&json!([0, 1, "yes"])).is_err());
+    }
+
+    #[test]
+    fn test_block_range_requests() {
+        let range = BlockRange {
+            start: 9,
+            end: 10,
+            full_tx: false,
+        };
+        let requests: Vec<_> = range.requests().collect();
+
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[1].0, 10);
+        assert_eq!(
+            requests[1].1,
+            json!({"jsonrpc": "2.0", "id": null, "method": "eth_getBlockByNumber", "params": ["0xa", false]})
+        );
+    }
+
+    #[tokio::test]
+    async fn test_get_block_range_from_cache() {
+        let cache_args = CacheArgs {
+            cache: Arc::new(sled::Config::new().temporary(true).open().unwrap()),
+            ..Default::default()
+        };
+        let range = BlockRange {
+            start: 1,
+            end: 2,
+            full_tx: false,
+        };
+
+        for (number, request) in range.requests() {
+            let tx_hash = CacheKey::new(&request);
+            let cached = json!({"jsonrpc": "2.0", "id": null, "result": {"number": number}});
+            insert_entry(&cache_args.cache, &tx_hash, cached.to_string().as_bytes()).unwrap();
+        }
+
+        // The node is unreachable, so everything has to come from the cache
+        let rpc = Rpc::new("http://127.0.0.1:1".to_string(), None, 10, 0, 1.0);
+        let rpc_list = Arc::new(RwLock::new(vec![rpc]));
+
+        let blocks = get_block_range(range, &rpc_list, None, None, &cache_args, 1000)
+            .await
+            .unwrap();
+        assert_eq!(blocks, vec![json!({"number": 1}), json!({"number": 2})]);
+
+        let empty = Arc::new(RwLock::new(vec![]));
+        assert_eq!(
+            get_block_range(range, &empty, None, None, &cache_args, 1000).await,
+            Err(BlockRangeError::NoRpcAvailable)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_get_block_range_caches_blocks() {
+        let cache_args = CacheArgs {
+            cache: Arc::new(sled::Config::new().temporary(true).open().unwrap()),
+            ..Default::default()
+        };
+        let node = crate::mock::node::MockNode::spawn(1).await.unwrap();
+        node.set_response("eth_getBlockByNumber", json!({"number": "0x1"}));
+        let rpc_list = Arc::new(RwLock::new(vec![Rpc::new(
+            node.http_url(),
+            None,
+            10,
+            0,
+            1.0,
+        )]));
+        let range = BlockRange {
+            start: 1,
+            end: 2,
+            full_tx: false,
+        };
+
+        get_block_range(range, &rpc_list, None, None, &cache_args, 1000)
+            .await
+            .unwrap();
+        get_block_range(range, &rpc_list, None, None, &cache_args, 1000)
+            .await
+            .unwrap();
+
+        // The second range is served from the cache
+        assert_eq!(node.request_count(), 2);
+        for (_, request) in range.requests() {
+            let tx_hash = CacheKey::new(&request);
+            assert!(get_entry(&cache_args.cache, &tx_hash).unwrap().is_some());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_get_block_range_retries_blocks() {
+        let cache_args = CacheArgs {
+            cache: Arc::new(sled::Config::new().temporary(true).open().unwrap()),
+            ..Default::default()
+        };
+        let node = crate::mock::node::MockNode::spawn(1).await.unwrap();
+        node.set_response("eth_getBlockByNumber", json!({"number": "0x1"}));
+
+        // The unreachable node is the fastest, so it gets picked first
+        let mut down = Rpc::new("http://127.0.0.1:1".to_string(), None, 1, 0, 1.0);
+        down.status.latency = 1.0;
+        let mut up = Rpc::new(node.http_url(), None, 1, 0, 1.0);
+        up.status.latency = 10.0;
+        let rpc_list = Arc::new(RwLock::new(vec![down, up]));
+        let range = BlockRange {
+            start: 1,
+            end: 4,
+            full_tx: false,
+        };
+
+        let blocks = get_block_range(range, &rpc_list, None, None, &cache_args, 1000)
+            .await
+            .unwrap();
+        assert_eq!(blocks.len(), 4);
+        assert_eq!(node.request_count(), 4);
+
+        // Nothing left to retry on
+        rpc_list.write().unwrap().truncate(1);
+        let range = BlockRange {
+            start: 5,
+            end: 5,
+            full_tx: false,
+        };
+        assert!(matches!(
+            get_block_range(range, &rpc_list, None, None, &cache_args, 1000).await,
+            Err(BlockRangeError::InvalidResponse(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_get_block_range_tenant() {
+        let cache_args = CacheArgs {
+            cache: Arc::new(sled::Config::new().temporary(true).open().unwrap()),
+            ..Default::default()
+        };
+        let tenant = crate::mock::node::MockNode::spawn(1).await.unwrap();
+        let shared = crate::mock::node::MockNode::spawn(1).await.unwrap();
+        tenant.set_response("eth_getBlockByNumber", json!({"number": "0x1"}));
+        shared.set_response("eth_getBlockByNumber", json!({"number": "0x1"}));
+        let mut shared_rpc = Rpc::new(shared.http_url(), None, 10, 0, 1.0);
+        shared_rpc.status.latency = 1.0;
+        let mut tenant_rpc =
+            Rpc::new(tenant.http_url(), None, 10, 0, 1.0).with_groups(vec!["acme".to_string()]);
+        tenant_rpc.status.latency = 10.0;
+        let rpc_list = Arc::new(RwLock::new(vec![shared_rpc, tenant_rpc]));
+        let range = BlockRange {
+            start: 1,
+            end: 2,
+            full_tx: false,
+        };
+
+        get_block_range(
+            range,
+            &rpc_list,
+            Some("acme"),
+            Some("acme"),
+            &cache_args,
+            1000,
+        )
+        .await
+        .unwrap();
+        assert_eq!(tenant.request_count(), 2);
+        assert_eq!(shared.request_count(), 0);
+
+        // Cached for the tenant only
+        for (_, request) in range.requests() {
+            let tx_hash = CacheKey::namespaced("acme", &request);
+            assert!(get_entry(&cache_args.cache, &tx_hash).unwrap().is_some());
+            let tx_hash = CacheKey::new(&request);
+            assert!(get_entry(&cache_args.cache, &tx_hash).unwrap().is_none());
+        }
+    }
+}
@@ -0,0 +1,513 @@
+// Keys and values of cached responses.
+//
+// Requests are keyed by a fixed-size digest of the request JSON, blake3 by
+// default or xxh3-128 with the `xxhash` feature. Next to the digest we keep a
+// cheap fingerprint of the request that's stored with the entry, so that in
+// the unlikely case two requests share a digest we answer with a cache miss
+// instead of the other request's response.
+//
+// Entry layout:
+//
+// format (1 byte) | fingerprint (u64 LE) | [meta] | response
+//
+// Responses past `COMPRESS_THRESHOLD` are zstd compressed, which shrinks
+// logs and full blocks several times over. Entries written before this
+// layout existed are plain JSON and are read back as they are.
+//
+// Entries with the `META` bit set in their format also say when they were
+// stored, for which method and which node answered, so we can tell clients
+// why they got what they got:
+//
+// stored at (u64 LE, unix seconds) | method len (u8) | method | source len (u8) | source
+use crate::balancer::memory::MemoryBudget;
+
+use std::{
+    fmt,
+    io,
+    time::{
+        SystemTime,
+        UNIX_EPOCH,
+    },
+};
+
+use serde_json::Value;
+use sled::Db;
+
+#[cfg(not(feature = "xxhash"))]
+const DIGEST_LEN: usize = 32;
+#[cfg(feature = "xxhash")]
+const DIGEST_LEN: usize = 16;
+
+#[cfg(feature = "xxhash")]
+const FINGERPRINT_SEED: u64 = 0x626c7574_67616e67;
+
+const PLAIN: u8 = 1;
+const ZSTD: u8 = 2;
+const META: u8 = 0x10;
+const HEADER_LEN: usize = 9;
+
+// Small responses don't get any smaller
+const COMPRESS_THRESHOLD: usize = 256;
+const COMPRESSION_LEVEL: i32 = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct CacheKey {
+    digest: [u8; DIGEST_LEN],
+    fingerprint: u64,
+}
+
+impl CacheKey {
+    pub fn new(request: &Value) -> Self {
+        Self::from_request(request.to_string().as_bytes())
+    }
+
+    // Key of `request` for tenants sharing the cache `namespace`
+    pub fn namespaced(namespace: &str, request: &Value) -> Self {
+        let mut bytes = namespace.as_bytes().to_vec();
+        // Namespaces can't contain a NUL, so they can't run into the request
+        bytes.push(0);
+        bytes.extend_from_slice(request.to_string().as_bytes());
+        Self::from_request(&bytes)
+    }
+
+    pub fn from_request(request: &[u8]) -> Self {
+        #[cfg(not(feature = "xxhash"))]
+        {
+            // Nothing collides with a 256-bit digest, the length is only
+            // here so both features share the entry layout
+            CacheKey {
+                digest: *blake3::hash(request).as_bytes(),
+                fingerprint: request.len() as u64,
+            }
+        }
+        #[cfg(feature = "xxhash")]
+        {
+            use xxhash_rust::xxh3::{
+                xxh3_128,
+                xxh3_64_with_seed,
+            };
+
+            CacheKey {
+                digest: xxh3_128(request).to_le_bytes(),
+                fingerprint: xxh3_64_with_seed(request, FINGERPRINT_SEED),
+            }
+        }
+    }
+
+    // What the entry is stored under in the DB
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.digest
+    }
+}
+
+impl fmt::Display for CacheKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", hex::encode(self.digest))
+    }
+}
+
+#[derive(Debug)]
+pub enum EntryError {
+    Db(sled::Error),
+    Corrupt(io::Error),
+}
+
+impl fmt::Display for EntryError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EntryError::Db(e) => write!(f, "Cache error: {}", e),
+            EntryError::Corrupt(e) => write!(f, "Corrupt cache entry: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for EntryError {}
+
+impl From<sled::Error> for EntryError {
+    fn from(e: sled::Error) -> Self {
+        EntryError::Db(e)
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |now| now.as_secs())
+}
+
+// Lengths in the meta have to fit in a byte
+fn truncated(field: &str) -> &[u8] {
+    &field.as_bytes()[..field.len().min(u8::MAX as usize)]
+}
+
+// Where a cached response came from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EntryMeta {
+    // Unix seconds
+    pub stored_at: u64,
+    pub method: String,
+    // Name of the node that answered, if we know it
+    pub source: Option<String>,
+}
+
+impl EntryMeta {
+    pub fn new(method: &str, source: Option<&str>) -> Self {
+        EntryMeta {
+            stored_at: unix_now(),
+            method: method.to_string(),
+            source: source.map(str::to_string),
+        }
+    }
+
+    // Seconds since the entry was stored
+    pub fn age(&self) -> u64 {
+        unix_now().saturating_sub(self.stored_at)
+    }
+
+    fn encode(&self, entry: &mut Vec<u8>) {
+        let method = truncated(&self.method);
+        let source = truncated(self.source.as_deref().unwrap_or_default());
+
+        entry.extend_from_slice(&self.stored_at.to_le_bytes());
+        entry.push(method.len() as u8);
+        entry.extend_from_slice(method);
+        entry.push(source.len() as u8);
+        entry.extend_from_slice(source);
+    }
+
+    // Meta at the start of `bytes`, and how many bytes it took up
+    fn decode(bytes: &[u8]) -> Option<(Self, usize)> {
+        let stored_at = u64::from_le_bytes(bytes.get(..8)?.try_into().unwrap());
+        let method_len = *bytes.get(8)? as usize;
+        let method = bytes.get(9..9 + method_len)?;
+        let source_len = *bytes.get(9 + method_len)? as usize;
+        let source_start = 10 + method_len;
+        let source = bytes.get(source_start..source_start + source_len)?;
+
+        let meta = EntryMeta {
+            stored_at,
+            method: String::from_utf8_lossy(method).into_owned(),
+            source: (!source.is_empty()).then(|| String::from_utf8_lossy(source).into_owned()),
+        };
+        Some((meta, source_start + source_len))
+    }
+}
+
+pub fn encode_entry(key: &CacheKey, response: &[u8]) -> Vec<u8> {
+    encode_entry_with_meta(key, response, None)
+}
+
+pub fn encode_entry_with_meta(
+    key: &CacheKey,
+    response: &[u8],
+    meta: Option<&EntryMeta>,
+) -> Vec<u8> {
+    let compressed = (response.len() >= COMPRESS_THRESHOLD)
+        .then(|| zstd::encode_all(response, COMPRESSION_LEVEL).ok())
+        .flatten()
+        .filter(|compressed| compressed.len() < response.len());
+
+    let (format, payload) = match &compressed {
+        Some(compressed) => (ZSTD, compressed.as_slice()),
+        None => (PLAIN, response),
+    };
+
+    let mut entry = Vec::with_capacity(HEADER_LEN + payload.len());
+    entry.push(if meta.is_some() {
+        format | META
+    } else {
+        format
+    });
+    entry.extend_from_slice(&key.fingerprint.to_le_bytes());
+    if let Some(meta) = meta {
+        meta.encode(&mut entry);
+    }
+    entry.extend_from_slice(payload);
+    entry
+}
+
+// Format of `entry` without the `META` bit, and its meta with where the
+// response starts. `None` for entries from before we had a header.
+#[allow(clippy::type_complexity)]
+fn split_entry(entry: &[u8]) -> Option<Result<(u8, Option<EntryMeta>, usize), EntryError>> {
+    let format = match entry.first() {
+        Some(&format) if matches!(format & !META, PLAIN | ZSTD) => format,
+        _ => return None,
+    };
+    if entry.len() < HEADER_LEN {
+        return Some(Err(EntryError::Corrupt(
+            io::ErrorKind::UnexpectedEof.into(),
+        )));
+    }
+
+    if format & META == 0 {
+        return Some(Ok((format, None, HEADER_LEN)));
+    }
+    Some(match EntryMeta::decode(&entry[HEADER_LEN..]) {
+        Some((meta, len)) => Ok((format & !META, Some(meta), HEADER_LEN + len)),
+        None => Err(EntryError::Corrupt(io::ErrorKind::UnexpectedEof.into())),
+    })
+}
+
+// Meta of `entry`, if it has any
+pub fn entry_meta(entry: &[u8]) -> Option<EntryMeta> {
+    split_entry(entry)?.ok()?.1
+}
+
+// The response stored in `entry`, or `None` if it belongs to another request
+pub fn decode_entry(key: &CacheKey, entry: &[u8]) -> Result<Option<Vec<u8>>, EntryError> {
+    let (format, _, start) = match split_entry(entry) {
+        Some(split) => split?,
+        // Plain JSON from before we had a header
+        None => return Ok(Some(entry.to_vec())),
+    };
+
+    let fingerprint = u64::from_le_bytes(entry[1..HEADER_LEN].try_into().unwrap());
+    if fingerprint != key.fingerprint {
+        return Ok(None);
+    }
+
+    let payload = &entry[start..];
+    match format {
+        ZSTD => {
+            zstd::decode_all(payload)
+                .map(Some)
+                .map_err(EntryError::Corrupt)
+        }
+        _ => Ok(Some(payload.to_vec())),
+    }
+}
+
+// Cached response to the request behind `key`
+pub fn get_entry(cache: &Db, key: &CacheKey) -> Result<Option<Vec<u8>>, EntryError> {
+    match cache.get(key.as_bytes())? {
+        Some(entry) => decode_entry(key, &entry),
+        None => Ok(None),
+    }
+}
+
+// Cached response to the request behind `key` with its meta, if it has any
+#[allow(clippy::type_complexity)]
+pub fn lookup_entry(
+    cache: &Db,
+    key: &CacheKey,
+) -> Result<Option<(Vec<u8>, Option<EntryMeta>)>, EntryError> {
+    let entry = match cache.get(key.as_bytes())? {
+        Some(entry) => entry,
+        None => return Ok(None),
+    };
+    Ok(decode_entry(key, &entry)?.map(|response| (response, entry_meta(&entry))))
+}
+
+// Cache `response` under `key`. Returns how many bytes the entry takes up.
+pub fn insert_entry(cache: &Db, key: &CacheKey, response: &[u8]) -> Result<usize, sled::Error> {
+    insert_entry_with_meta(cache, key, response, None)
+}
+
+// Whether `key` is one of a cached response, and not of our own metadata
+pub fn is_entry_key(key: &[u8]) -> bool {
+    key.len() == DIGEST_LEN
+}
+
+// Remove the entry whose key is the hex `pattern`, or every entry for a
+// method matching it. Patterns ending in `*` match method prefixes, entries
+// without meta never match a method. Returns how many entries were removed.
+pub fn evict_entries(
+    cache: &Db,
+    memory: &MemoryBudget,
+    pattern: &str,
+) -> Result<usize, sled::Error> {
+    if let Some(key) = hex::decode(pattern).ok().filter(|key| is_entry_key(key)) {
+        return Ok(match cache.remove(&key)? {
+            Some(entry) => {
+                memory.release(&key, entry.len());
+                1
+            }
+            None => 0,
+        });
+    }
+
+    let matches = |method: &str| {
+        match pattern.strip_suffix('*') {
+            Some(prefix) => method.starts_with(prefix),
+            None => method == pattern,
+        }
+    };
+    let mut evicted = 0;
+    for entry in cache.iter() {
+        let (key, entry) = entry?;
+        if is_entry_key(&key) && entry_meta(&entry).is_some_and(|meta| matches(&meta.method)) {
+            if let Some(entry) = cache.remove(&key)? {
+                memory.release(&key, entry.len());
+                evicted += 1;
+            }
+        }
+    }
+    Ok(evicted)
+}
+
+pub fn insert_entry_with_meta(
+    cache: &Db,
+    key: &CacheKey,
+    response: &[u8],
+    meta: Option<&EntryMeta>,
+) -> Result<usize, sled::Error> {
+    let entry = encode_entry_with_meta(key, response, meta);
+    cache.insert(key.as_bytes(), entry.as_slice())?;
+    Ok(entry.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_cache_key() {
+        let request = json!({"jsonrpc": "2.0", "id": null, "method": "eth_chainId", "params": []});
+        let key = CacheKey::new(&request);
+
+        assert_eq!(key, CacheKey::from_request(request.to_string().as_bytes()));
+        assert_eq!(key.as_bytes().len(), DIGEST_LEN);
+        assert_eq!(key.to_string(), hex::encode(key.as_bytes()));
+        assert_ne!(key, CacheKey::new(&json!({"method": "eth_blockNumber"})));
+    }
+
+    #[test]
+    fn test_namespaced_cache_key() {
+        let request = json!({"jsonrpc": "2.0", "id": null, "method": "eth_chainId", "params": []});
+        let acme = CacheKey::namespaced("acme", &request);
+
+        assert_eq!(acme, CacheKey::namespaced("acme", &request));
+        assert_ne!(acme, CacheKey::namespaced("globex", &request));
+        assert_ne!(acme, CacheKey::new(&request));
+    }
+
+    #[test]
+    fn test_entry_roundtrip() {
+        let key = CacheKey::from_request(b"eth_getLogs");
+        let logs: Vec<Value> = (0..100)
+            .map(
+                |i| json!({"address": "0x6b175474e89094c44da98b954eedeac495271d0f", "logIndex": i}),
+            )
+            .collect();
+        let response = json!({"jsonrpc": "2.0", "id": null, "result": logs}).to_string();
+
+        let entry = encode_entry(&key, response.as_bytes());
+        assert_eq!(entry[0], ZSTD);
+        assert!(entry.len() * 4 < response.len());
+        assert_eq!(
+            decode_entry(&key, &entry).unwrap().unwrap(),
+            response.as_bytes()
+        );
+
+        // Too small to bother
+        let entry = encode_entry(&key, b"{\"result\":\"0x1\"}");
+        assert_eq!(entry[0], PLAIN);
+        assert_eq!(
+            decode_entry(&key, &entry).unwrap().unwrap(),
+            b"{\"result\":\"0x1\"}"
+        );
+    }
+
+    #[test]
+    fn test_fingerprint_mismatch() {
+        let key = CacheKey::from_request(b"a");
+        let mut colliding = CacheKey::from_request(b"bb");
+        colliding.digest = key.digest;
+
+        let entry = encode_entry(&key, b"{\"result\":\"0x1\"}");
+        assert!(decode_entry(&colliding, &entry).unwrap().is_none());
+
+        // Entries from before the header are plain JSON
+        assert_eq!(
+            decode_entry(&key, b"{\"result\":\"0x1\"}")
+                .unwrap()
+                .unwrap(),
+            b"{\"result\":\"0x1\"}"
+        );
+        assert!(decode_entry(&key, &[ZSTD, 1, 2]).is_err());
+    }
+
+    #[test]
+    fn test_get_insert_entry() {
+        let cache = sled::Config::new().temporary(true).open().unwrap();
+        let key = CacheKey::from_request(b"eth_chainId");
+
+        assert!(get_entry(&cache, &key).unwrap().is_none());
+        let size = insert_entry(&cache, &key, b"{\"result\":\"0x1\"}").unwrap();
+        assert_eq!(size, HEADER_LEN + 16);
+        assert_eq!(
+            get_entry(&cache, &key).unwrap().unwrap(),
+            b"{\"result\":\"0x1\"}"
+        );
+    }
+
+    #[test]
+    fn test_entry_meta() {
+        let cache = sled::Config::new().temporary(true).open().unwrap();
+        let key = CacheKey::from_request(b"eth_getLogs");
+        let response =
+            json!({"result": vec!["0x6b175474e89094c44da98b954eedeac495271d0f"; 20]}).to_string();
+        let meta = EntryMeta::new("eth_getLogs", Some("merkle"));
+
+        insert_entry_with_meta(&cache, &key, response.as_bytes(), Some(&meta)).unwrap();
+        let entry = cache.get(key.as_bytes()).unwrap().unwrap();
+        assert_eq!(entry[0], ZSTD | META);
+        assert_eq!(entry_meta(&entry), Some(meta.clone()));
+        assert_eq!(
+            lookup_entry(&cache, &key).unwrap(),
+            Some((response.into_bytes(), Some(meta)))
+        );
+        assert!(EntryMeta::new("eth_getLogs", None).age() < 5);
+
+        // Older entries don't know where they came from
+        insert_entry(&cache, &key, b"{\"result\":\"0x1\"}").unwrap();
+        assert_eq!(
+            lookup_entry(&cache, &key).unwrap(),
+            Some((b"{\"result\":\"0x1\"}".to_vec(), None))
+        );
+        assert!(decode_entry(&key, &[PLAIN | META, 0, 0, 0, 0, 0, 0, 0, 0, 1]).is_err());
+    }
+
+    #[test]
+    fn test_evict_entries() {
+        let cache = sled::Config::new().temporary(true).open().unwrap();
+        let memory = MemoryBudget::default();
+        let insert = |request: &[u8], method: Option<&str>| {
+            let key = CacheKey::from_request(request);
+            let meta = method.map(|method| EntryMeta::new(method, None));
+            let size = insert_entry_with_meta(&cache, &key, b"{\"result\":\"0x1\"}", meta.as_ref())
+                .unwrap();
+            memory.record_insert(key.as_bytes(), size);
+            key
+        };
+        let balance = insert(b"balance", Some("eth_getBalance"));
+        let block = insert(b"block", Some("eth_getBlockByNumber"));
+        let receipts = insert(b"receipts", Some("eth_getBlockReceipts"));
+        let legacy = insert(b"legacy", None);
+
+        assert_eq!(
+            evict_entries(&cache, &memory, &balance.to_string()).unwrap(),
+            1
+        );
+        assert_eq!(
+            evict_entries(&cache, &memory, &balance.to_string()).unwrap(),
+            0
+        );
+        assert_eq!(
+            evict_entries(&cache, &memory, "eth_getBlockByNumber").unwrap(),
+            1
+        );
+        assert!(get_entry(&cache, &receipts).unwrap().is_some());
+        assert_eq!(evict_entries(&cache, &memory, "eth_*").unwrap(), 1);
+
+        assert!(get_entry(&cache, &block).unwrap().is_none());
+        assert!(get_entry(&cache, &receipts).unwrap().is_none());
+        assert!(get_entry(&cache, &legacy).unwrap().is_some());
+
+        // Only what's left counts against the budget
+        let left = cache.get(legacy.as_bytes()).unwrap().unwrap().len();
+        assert_eq!(memory.used_bytes(), left);
+    }
+}
@@ -0,0 +1,312 @@
+// Consistent reads across a session.
+//
+// Consecutive reads can land on nodes a block apart, so an indexer can see
+// state from two different blocks in what it thinks is one snapshot. Clients
+// that send an `x-blutgang-session` header get `latest` pinned to the same
+// block for every request in that session. Methods that take an EIP-1898
+// block parameter get the block hash, so a reorg makes them fail instead of
+// silently reading another block, and the rest get the block number.
+//
+// Sessions are scoped to the tenant and certificate identity that sent them,
+// so clients can't read or pin each other's snapshots by guessing ids. We
+// keep up to `MAX_SESSIONS`, dropping the oldest once we have that many.
+use crate::{
+    balancer::{
+        format::block_param_position,
+        selection::{
+            groups::pick_group,
+            select::{
+                pick,
+                route_available,
+            },
+        },
+    },
+    config::types::ConsistentReadsSettings,
+    Rpc,
+};
+
+use std::{
+    collections::HashMap,
+    sync::{
+        Arc,
+        RwLock,
+    },
+    time::{
+        Duration,
+        Instant,
+    },
+};
+
+use serde_json::{
+    json,
+    Value,
+};
+use tokio::time::timeout;
+
+pub const SESSION_HEADER: &str = "x-blutgang-session";
+
+// Sessions we keep at most
+const MAX_SESSIONS: usize = 10_000;
+
+// Methods whose block parameter can be `{"blockHash": ..}`
+fn accepts_block_hash(method: &str) -> bool {
+    matches!(
+        method,
+        "eth_getBalance"
+            | "eth_getStorageAt"
+            | "eth_getTransactionCount"
+            | "eth_getCode"
+            | "eth_call"
+    )
+}
+
+// Block every read in a session sees
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Snapshot {
+    pub number: u64,
+    pub hash: String,
+}
+
+impl Snapshot {
+    fn number_hex(&self) -> Value {
+        json!(format!("0x{:x}", self.number))
+    }
+
+    // Answer `eth_blockNumber` from the snapshot
+    pub fn block_number(&self, id: &Value) -> Value {
+        json!({"jsonrpc": "2.0", "id": id, "result": self.number_hex()})
+    }
+
+    // Rewrite `latest`, or a missing block parameter, to this snapshot
+    pub fn pin(&self, tx: &mut Value) {
+        let method = tx["method"].as_str().unwrap_or_default().to_string();
+
+        if method == "eth_getLogs" {
+            let filter = tx["params"][0]
+                .as_object_mut()
+                .filter(|filter| !filter.contains_key("blockHash"));
+            if let Some(filter) = filter {
+                for key in ["fromBlock", "toBlock"] {
+                    if filter.get(key).map_or(true, |block| block == "latest") {
+                        filter.insert(key.to_string(), self.number_hex());
+                    }
+                }
+            }
+            return;
+        }
+
+        let position = match block_param_position(&method) {
+            Some(position) => position,
+            None => return,
+        };
+        let params = match tx["params"].as_array_mut() {
+            Some(params) => params,
+            None => return,
+        };
+
+        let block = if accepts_block_hash(&method) {
+            json!({"blockHash": self.hash})
+        } else {
+            self.number_hex()
+        };
+        if params.len() == position {
+            params.push(block);
+        } else if params.get(position).is_some_and(|param| param == "latest") {
+            params[position] = block;
+        }
+    }
+}
+
+// Scope the session is in, and the id the client gave it
+type SessionKey = (String, String);
+
+#[derive(Debug, Default)]
+pub struct ConsistentReads {
+    settings: Option<ConsistentReadsSettings>,
+    sessions: RwLock<HashMap<SessionKey, (Snapshot, Instant)>>,
+}
+
+impl ConsistentReads {
+    pub fn new(settings: Option<ConsistentReadsSettings>) -> Self {
+        ConsistentReads {
+            settings,
+            sessions: RwLock::new(HashMap::new()),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.settings.is_some()
+    }
+
+    fn get(&self, key: &SessionKey, session_ttl: Duration) -> Option<Snapshot> {
+        let sessions = self.sessions.read().unwrap_or_else(|e| e.into_inner());
+        match sessions.get(key) {
+            Some((snapshot, started)) if started.elapsed() < session_ttl => Some(snapshot.clone()),
+            _ => None,
+        }
+    }
+
+    // Snapshot of `session` in `scope`, taken at `latest` if it's new or
+    // expired. Returns `None` if we couldn't get the hash of `latest` from
+    // a node in `group`.
+    pub async fn snapshot(
+        &self,
+        scope: &str,
+        session: &str,
+        rpc_list: &Arc<RwLock<Vec<Rpc>>>,
+        group: Option<&str>,
+        latest: u64,
+        ttl: u128,
+    ) -> Option<Snapshot> {
+        let session_ttl = self.settings.as_ref()?.session_ttl;
+        let key = (scope.to_string(), session.to_string());
+        if let Some(snapshot) = self.get(&key, session_ttl) {
+            return Some(snapshot);
+        }
+        if latest == 0 {
+            return None;
+        }
+
+        let params = json!([format!("0x{:x}", latest), false]);
+        let rpc = {
+            let request = json!({"method": "eth_getBlockByNumber", "params": params});
+            let mut rpc_list = rpc_list.write().unwrap_or_else(|e| e.into_inner());
+            let picked = match group {
+                Some(group) => pick_group(&mut rpc_list, group, false),
+                None => pick(&mut rpc_list),
+            };
+            match route_available(&rpc_list, picked, &request) {
+                (rpc, Some(_)) => rpc,
+                (_, None) => return None,
+            }
+        };
+        let block = timeout(
+            Duration::from_millis(ttl.try_into().unwrap_or(u64::MAX)),
+            rpc.call("eth_getBlockByNumber", params),
+        )
+        .await
+        .ok()?
+        .ok()?;
+        let snapshot = Snapshot {
+            number: latest,
+            hash: block["hash"].as_str()?.to_string(),
+        };
+
+        let mut sessions = self.sessions.write().unwrap_or_else(|e| e.into_inner());
+        if sessions.len() >= MAX_SESSIONS && !sessions.contains_key(&key) {
+            sessions.retain(|_, (_, started)| started.elapsed() < session_ttl);
+        }
+        if sessions.len() >= MAX_SESSIONS && !sessions.contains_key(&key) {
+            let oldest = sessions
+                .iter()
+                .min_by_key(|(_, (_, started))| *started)
+                .map(|(key, _)| key.clone());
+            if let Some(oldest) = oldest {
+                sessions.remove(&oldest);
+            }
+        }
+        sessions.insert(key, (snapshot.clone(), Instant::now()));
+
+        Some(snapshot)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::node::MockNode;
+
+    fn snapshot() -> Snapshot {
+        Snapshot {
+            number: 16,
+            hash: "0xabc".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_pin() {
+        let mut tx = json!({"method": "eth_getBalance", "params": ["0x1", "latest"]});
+        snapshot().pin(&mut tx);
+        assert_eq!(tx["params"][1], json!({"blockHash": "0xabc"}));
+
+        // Missing block parameters default to `latest`
+        let mut tx = json!({"method": "eth_call", "params": [{"to": "0x1"}]});
+        snapshot().pin(&mut tx);
+        assert_eq!(tx["params"][1], json!({"blockHash": "0xabc"}));
+
+        let mut tx = json!({"method": "eth_getBlockByNumber", "params": ["latest", false]});
+        snapshot().pin(&mut tx);
+        assert_eq!(tx["params"][0], "0x10");
+
+        let mut tx = json!({"method": "eth_getLogs", "params": [{"fromBlock": "0x1"}]});
+        snapshot().pin(&mut tx);
+        assert_eq!(
+            tx["params"][0],
+            json!({"fromBlock": "0x1", "toBlock": "0x10"})
+        );
+
+        // Explicit blocks are left alone
+        let mut tx = json!({"method": "eth_getCode", "params": ["0x1", "0x5"]});
+        snapshot().pin(&mut tx);
+        assert_eq!(tx["params"][1], "0x5");
+
+        assert_eq!(snapshot().block_number(&json!(4))["result"], "0x10");
+    }
+
+    #[tokio::test]
+    async fn test_snapshot() {
+        let node = MockNode::spawn(1).await.unwrap();
+        node.set_response("eth_getBlockByNumber", json!({"hash": "0xabc"}));
+        let rpc_list = Arc::new(RwLock::new(vec![Rpc::new(
+            node.http_url(),
+            None,
+            6,
+            0,
+            10.0,
+        )]));
+
+        let reads = ConsistentReads::new(Some(ConsistentReadsSettings::default()));
+        assert_eq!(
+            reads.snapshot("", "a", &rpc_list, None, 16, 1000).await,
+            Some(snapshot())
+        );
+
+        // Same block for the rest of the session, even if the head moved
+        node.set_response("eth_getBlockByNumber", json!({"hash": "0xdef"}));
+        assert_eq!(
+            reads.snapshot("", "a", &rpc_list, None, 17, 1000).await,
+            Some(snapshot())
+        );
+        assert_eq!(node.request_count(), 1);
+
+        let other = reads
+            .snapshot("", "b", &rpc_list, None, 17, 1000)
+            .await
+            .unwrap();
+        assert_eq!(other.number, 17);
+        assert_eq!(other.hash, "0xdef");
+
+        // Other clients' sessions are their own
+        let scoped = reads
+            .snapshot("tenant/", "a", &rpc_list, None, 17, 1000)
+            .await
+            .unwrap();
+        assert_eq!(scoped.number, 17);
+
+        // Only nodes in the group are asked
+        assert!(reads
+            .snapshot("", "d", &rpc_list, Some("archive"), 17, 1000)
+            .await
+            .is_none());
+
+        // Nothing to pin to before we know the head
+        assert!(reads
+            .snapshot("", "c", &rpc_list, None, 0, 1000)
+            .await
+            .is_none());
+        assert!(ConsistentReads::default()
+            .snapshot("", "a", &rpc_list, None, 16, 1000)
+            .await
+            .is_none());
+    }
+}
@@ -0,0 +1,420 @@
+// CORS for browser dApps calling us directly.
+//
+// Requests without an `Origin` header aren't from a browser and are never
+// limited here. Browser requests from origins we don't allow are rejected
+// before they go anywhere, instead of being forwarded and then blocked by
+// the browser, and every origin gets its own rate limit. We count requests
+// of up to `MAX_WINDOWS` origins at once, forgetting the ones we haven't
+// heard from in a while to make room.
+//
+// Preflight `OPTIONS` requests are answered here and never reach a node. The
+// headers of those answers only depend on the origin, so they're built once
+// per origin we allow and reused after that.
+use crate::config::types::CorsSettings;
+
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::Mutex,
+    time::{
+        Duration,
+        Instant,
+    },
+};
+
+use http_body_util::Full;
+use hyper::{
+    body::Bytes,
+    header::{
+        HeaderMap,
+        HeaderValue,
+        VARY,
+    },
+    Request,
+    Response,
+};
+use serde_json::json;
+
+// Origins we count requests of at once
+const MAX_WINDOWS: usize = 10_000;
+
+// Errors
+#[derive(Debug, PartialEq)]
+pub enum CorsError {
+    OriginNotAllowed(String),
+    RateLimited(String),
+}
+
+impl fmt::Display for CorsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CorsError::OriginNotAllowed(origin) => write!(f, "Origin {} is not allowed", origin),
+            CorsError::RateLimited(origin) => write!(f, "Rate limit exceeded for {}", origin),
+        }
+    }
+}
+
+impl std::error::Error for CorsError {}
+
+impl CorsError {
+    pub fn code(&self) -> i64 {
+        match self {
+            CorsError::OriginNotAllowed(_) => -32007,
+            CorsError::RateLimited(_) => -32008,
+        }
+    }
+
+    fn status(&self) -> u16 {
+        match self {
+            CorsError::OriginNotAllowed(_) => 403,
+            CorsError::RateLimited(_) => 429,
+        }
+    }
+
+    pub fn into_response(self) -> Response<Full<Bytes>> {
+        let body = json!({
+            "jsonrpc": "2.0",
+            "id": null,
+            "error": {"code": self.code(), "message": self.to_string()},
+        });
+
+        Response::builder()
+            .status(self.status())
+            .header("Content-Type", "application/json")
+            .body(Full::new(Bytes::from(body.to_string())))
+            .unwrap()
+    }
+}
+
+// Forget origins whose window is over, or the one we heard from least
+// recently if they're all still counting
+fn prune_windows(windows: &mut HashMap<String, (Instant, u32)>, now: Instant) {
+    windows.retain(|_, (start, _)| now.duration_since(*start) < Duration::from_secs(1));
+    if windows.len() < MAX_WINDOWS {
+        return;
+    }
+
+    let oldest = windows
+        .iter()
+        .min_by_key(|(_, (start, _))| *start)
+        .map(|(origin, _)| origin.clone());
+    if let Some(oldest) = oldest {
+        windows.remove(&oldest);
+    }
+}
+
+// Whether `Vary` already lists `Origin`
+fn varies_by_origin(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(VARY)
+        .iter()
+        .filter_map(|vary| vary.to_str().ok())
+        .flat_map(|vary| vary.split(','))
+        .any(|name| {
+            let name = name.trim();
+            name == "*" || name.eq_ignore_ascii_case("origin")
+        })
+}
+
+pub fn origin<B>(tx: &Request<B>) -> Option<String> {
+    tx.headers()
+        .get("origin")
+        .and_then(|origin| origin.to_str().ok())
+        .map(str::to_string)
+}
+
+#[derive(Debug)]
+pub struct Cors {
+    settings: CorsSettings,
+    // Start of the current one second window and requests in it, per origin
+    windows: Mutex<HashMap<String, (Instant, u32)>>,
+    // Preflight response headers, per `Access-Control-Allow-Origin` we answer
+    // with. Only allowed origins end up here, so it can't grow past them.
+    preflights: Mutex<HashMap<Option<String>, HeaderMap>>,
+}
+
+impl Default for Cors {
+    fn default() -> Self {
+        Cors::new(CorsSettings::default())
+    }
+}
+
+impl Cors {
+    pub fn new(settings: CorsSettings) -> Self {
+        Cors {
+            settings,
+            windows: Mutex::new(HashMap::new()),
+            preflights: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Whether we answer every origin the same way
+    fn is_wildcard(&self) -> bool {
+        self.settings.origins.iter().any(|allowed| allowed == "*")
+    }
+
+    fn is_allowed(&self, origin: &str) -> bool {
+        self.settings
+            .origins
+            .iter()
+            .any(|allowed| allowed == "*" || allowed == origin)
+    }
+
+    // Value of `Access-Control-Allow-Origin` for `origin`
+    fn allow_origin(&self, origin: Option<&str>) -> Option<String> {
+        if self.is_wildcard() {
+            return Some("*".to_string());
+        }
+        origin
+            .filter(|origin| self.is_allowed(origin))
+            .map(str::to_string)
+    }
+
+    fn rate_limit(&self, origin: &str) -> u32 {
+        self.settings
+            .rate_limits
+            .get(origin)
+            .copied()
+            .unwrap_or(self.settings.rate_limit)
+    }
+
+    // Check a request from `origin` against the allowed origins and its rate limit
+    pub fn check(&self, origin: Option<&str>) -> Result<(), CorsError> {
+        let origin = match origin {
+            Some(origin) => origin,
+            None => return Ok(()),
+        };
+        if !self.is_allowed(origin) {
+            return Err(CorsError::OriginNotAllowed(origin.to_string()));
+        }
+
+        let limit = self.rate_limit(origin);
+        if limit == 0 {
+            return Ok(());
+        }
+
+        let mut windows = self.windows.lock().unwrap_or_else(|e| e.into_inner());
+        let now = Instant::now();
+        if windows.len() >= MAX_WINDOWS && !windows.contains_key(origin) {
+            prune_windows(&mut windows, now);
+        }
+        let (start, count) = windows.entry(origin.to_string()).or_insert((now, 0));
+        if now.duration_since(*start) >= Duration::from_secs(1) {
+            (*start, *count) = (now, 0);
+        }
+        if *count >= limit {
+            return Err(CorsError::RateLimited(origin.to_string()));
+        }
+        *count += 1;
+
+        Ok(())
+    }
+
+    // Answer an `OPTIONS` preflight request
+    pub fn preflight(&self, origin: Option<&str>) -> Response<Full<Bytes>> {
+        if let Some(origin) = origin.filter(|origin| !self.is_allowed(origin)) {
+            return CorsError::OriginNotAllowed(origin.to_string()).into_response();
+        }
+
+        let mut response = Response::builder()
+            .status(204)
+            .body(Full::new(Bytes::new()))
+            .unwrap();
+        let mut preflights = self.preflights.lock().unwrap_or_else(|e| e.into_inner());
+        *response.headers_mut() = preflights
+            .entry(self.allow_origin(origin))
+            .or_insert_with(|| {
+                let mut response = Response::builder()
+                    .header("Access-Control-Allow-Methods", "POST, OPTIONS")
+                    .header(
+                        "Access-Control-Allow-Headers",
+                        self.settings.headers.join(", "),
+                    )
+                    .header("Access-Control-Max-Age", self.settings.max_age)
+                    .body(Full::new(Bytes::new()))
+                    .unwrap();
+                self.apply(&mut response, origin);
+                response.headers().clone()
+            })
+            .clone();
+
+        response
+    }
+
+    // Set the CORS headers on a response to `origin`
+    pub fn apply(&self, response: &mut Response<Full<Bytes>>, origin: Option<&str>) {
+        let allow_origin = self
+            .allow_origin(origin)
+            .and_then(|allow_origin| HeaderValue::from_str(&allow_origin).ok());
+
+        match allow_origin {
+            Some(allow_origin) => {
+                response
+                    .headers_mut()
+                    .insert("Access-Control-Allow-Origin", allow_origin);
+            }
+            None => {
+                response.headers_mut().remove("Access-Control-Allow-Origin");
+            }
+        }
+
+        // Unless every origin gets the same answer, caches between us and
+        // browsers must keep them apart. That includes responses to requests
+        // without an origin, which lack the header browsers need.
+        if !self.is_wildcard() && !varies_by_origin(response.headers()) {
+            response
+                .headers_mut()
+                .append(VARY, HeaderValue::from_static("Origin"));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::BTreeMap;
+
+    fn cors() -> Cors {
+        Cors::new(CorsSettings {
+            origins: vec![
+                "https://app.example.com".to_string(),
+                "https://other.example.com".to_string(),
+            ],
+            rate_limit: 2,
+            rate_limits: BTreeMap::from([("https://other.example.com".to_string(), 0)]),
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn test_allowed_origins() {
+        let cors = cors();
+        assert_eq!(cors.check(None), Ok(()));
+        assert_eq!(
+            cors.check(Some("https://evil.example.com")),
+            Err(CorsError::OriginNotAllowed(
+                "https://evil.example.com".to_string()
+            ))
+        );
+
+        let mut response = Response::new(Full::new(Bytes::new()));
+        cors.apply(&mut response, Some("https://app.example.com"));
+        assert_eq!(
+            response.headers()["Access-Control-Allow-Origin"],
+            "https://app.example.com"
+        );
+        cors.apply(&mut response, None);
+        assert!(response
+            .headers()
+            .get("Access-Control-Allow-Origin")
+            .is_none());
+
+        // Anything goes by default
+        let mut response = Response::new(Full::new(Bytes::new()));
+        Cors::default().apply(&mut response, Some("https://evil.example.com"));
+        assert_eq!(response.headers()["Access-Control-Allow-Origin"], "*");
+    }
+
+    #[test]
+    fn test_rate_limits() {
+        let cors = cors();
+        let app = Some("https://app.example.com");
+        assert!(cors.check(app).is_ok());
+        assert!(cors.check(app).is_ok());
+        assert_eq!(
+            cors.check(app),
+            Err(CorsError::RateLimited(
+                "https://app.example.com".to_string()
+            ))
+        );
+
+        // Overridden to no limit
+        for _ in 0..10 {
+            assert!(cors.check(Some("https://other.example.com")).is_ok());
+        }
+    }
+
+    #[test]
+    fn test_prune_windows() {
+        let now = Instant::now();
+        let mut windows: HashMap<String, (Instant, u32)> = (1..MAX_WINDOWS)
+            .map(|i| (i.to_string(), (now, 1)))
+            .collect();
+        windows.insert("old".to_string(), (now - Duration::from_secs(2), 1));
+
+        // Finished windows go first
+        prune_windows(&mut windows, now);
+        assert_eq!(windows.len(), MAX_WINDOWS - 1);
+        assert!(!windows.contains_key("old"));
+
+        // Then the one we heard from least recently
+        windows.insert("0".to_string(), (now - Duration::from_millis(10), 1));
+        prune_windows(&mut windows, now);
+        assert_eq!(windows.len(), MAX_WINDOWS - 1);
+        assert!(!windows.contains_key("0"));
+
+        // Wildcard origins don't grow past the cap
+        let cors = Cors::new(CorsSettings {
+            origins: vec!["*".to_string()],
+            rate_limit: 2,
+            ..Default::default()
+        });
+        for i in 0..MAX_WINDOWS + 10 {
+            assert!(cors.check(Some(&i.to_string())).is_ok());
+        }
+        assert_eq!(cors.windows.lock().unwrap().len(), MAX_WINDOWS);
+    }
+
+    #[test]
+    fn test_preflight() {
+        let cors = cors();
+        let response = cors.preflight(Some("https://app.example.com"));
+        assert_eq!(response.status(), 204);
+        assert_eq!(response.headers()["Access-Control-Max-Age"], "86400");
+        assert_eq!(
+            response.headers()["Access-Control-Allow-Headers"],
+            "content-type"
+        );
+
+        let response = cors.preflight(Some("https://evil.example.com"));
+        assert_eq!(response.status(), 403);
+
+        // Built once per allowed origin
+        let response = cors.preflight(Some("https://app.example.com"));
+        assert_eq!(
+            response.headers()["Access-Control-Allow-Origin"],
+            "https://app.example.com"
+        );
+        let response = cors.preflight(Some("https://other.example.com"));
+        assert_eq!(
+            response.headers()["Access-Control-Allow-Origin"],
+            "https://other.example.com"
+        );
+        assert_eq!(cors.preflights.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_vary() {
+        let cors = cors();
+        let response = cors.preflight(Some("https://app.example.com"));
+        assert_eq!(response.headers()[VARY], "Origin");
+
+        // Also when the request has no origin, or the response already varies
+        let mut response = Response::new(Full::new(Bytes::new()));
+        response
+            .headers_mut()
+            .insert(VARY, HeaderValue::from_static("Accept-Encoding, origin"));
+        cors.apply(&mut response, None);
+        assert_eq!(response.headers().get_all(VARY).iter().count(), 1);
+
+        let mut response = Response::new(Full::new(Bytes::new()));
+        cors.apply(&mut response, None);
+        assert_eq!(response.headers()[VARY], "Origin");
+
+        // Everyone gets `*`
+        let response = Cors::default().preflight(Some("https://app.example.com"));
+        assert_eq!(response.headers()["Access-Control-Allow-Origin"], "*");
+        assert!(response.headers().get(VARY).is_none());
+    }
+}
@@ -0,0 +1,97 @@
+// Soft-deprecation warnings.
+//
+// Before blocking a method outright, operators can list it under
+// `[blutgang.deprecations]`. Calls still get answered as usual, but the
+// response carries the configured warning in a header, a `blutgang_warning`
+// member, or both, so client developers notice before anything breaks.
+use crate::config::types::WarningPlacement;
+
+use http_body_util::{
+    BodyExt,
+    Full,
+};
+use hyper::{
+    body::Bytes,
+    header::HeaderValue,
+    Response,
+};
+use serde_json::Value;
+
+pub const WARNING_HEADER: &str = "x-blutgang-warning";
+pub const WARNING_FIELD: &str = "blutgang_warning";
+
+// Attach `warning` to `response` wherever `warn_in` says
+pub async fn add_warning(
+    response: Response<Full<Bytes>>,
+    warning: &str,
+    warn_in: WarningPlacement,
+) -> Response<Full<Bytes>> {
+    let (mut parts, body) = response.into_parts();
+
+    if warn_in != WarningPlacement::Field {
+        if let Ok(warning) = HeaderValue::from_str(warning) {
+            parts.headers.insert(WARNING_HEADER, warning);
+        }
+    }
+
+    let body = match warn_in {
+        WarningPlacement::Header => body,
+        WarningPlacement::Field | WarningPlacement::Both => {
+            // `Full` can't fail
+            let bytes = body.collect().await.unwrap().to_bytes();
+            match serde_json::from_slice::<Value>(&bytes) {
+                Ok(Value::Object(mut rax)) if rax.contains_key("jsonrpc") => {
+                    rax.insert(WARNING_FIELD.to_string(), warning.into());
+                    Full::new(Bytes::from(Value::Object(rax).to_string()))
+                }
+                // Not something we can add a member to
+                _ => Full::new(bytes),
+            }
+        }
+    };
+
+    Response::from_parts(parts, body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn response(body: &str) -> Response<Full<Bytes>> {
+        Response::builder()
+            .status(200)
+            .header("Content-Type", "application/json")
+            .body(Full::new(Bytes::from(body.to_string())))
+            .unwrap()
+    }
+
+    async fn body(response: Response<Full<Bytes>>) -> Value {
+        let bytes = response.into_body().collect().await.unwrap().to_bytes();
+        serde_json::from_slice(&bytes).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_add_warning() {
+        let rax = json!({"jsonrpc": "2.0", "id": 1, "result": "0x1"}).to_string();
+
+        let warned = add_warning(response(&rax), "going away", WarningPlacement::Header).await;
+        assert_eq!(warned.headers()[WARNING_HEADER], "going away");
+        assert!(body(warned).await.get(WARNING_FIELD).is_none());
+
+        let warned = add_warning(response(&rax), "going away", WarningPlacement::Field).await;
+        assert!(warned.headers().get(WARNING_HEADER).is_none());
+        let warned = body(warned).await;
+        assert_eq!(warned[WARNING_FIELD], "going away");
+        assert_eq!(warned["result"], "0x1");
+
+        let warned = add_warning(response(&rax), "going away", WarningPlacement::Both).await;
+        assert_eq!(warned.headers()[WARNING_HEADER], "going away");
+        assert_eq!(body(warned).await[WARNING_FIELD], "going away");
+
+        // Bodies that aren't JSON-RPC responses are left alone
+        let warned = add_warning(response("nope"), "going away", WarningPlacement::Both).await;
+        let bytes = warned.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(bytes, "nope");
+    }
+}
@@ -0,0 +1,319 @@
+// Cache for ENS resolution calls.
+//
+// dApps resolve the same names over and over with `eth_call`s against the
+// ENS registry and resolvers at `latest`, which the regular cache can't
+// store. When `ens_cache_ttl` is set we keep the results of these calls for
+// that long, keyed by the contract, selector and name hash in the call and
+// any state overrides. Tenants sharing our cache get their own entries.
+//
+// The `blutgang_ens_prewarm` admin method resolves a list of names ahead of
+// time so the first lookups are served from the cache too.
+use crate::{
+    rpc::error::RpcError,
+    verify::proof::keccak256,
+    Rpc,
+};
+
+use std::{
+    collections::HashMap,
+    sync::RwLock,
+    time::{
+        Duration,
+        Instant,
+    },
+};
+
+use serde_json::{
+    json,
+    Value,
+};
+
+pub const ENS_REGISTRY: &str = "0x00000000000c2e074ec69a0dfb2997ba6c7d2e1e";
+
+// `resolver(bytes32)`
+const RESOLVER_SELECTOR: &str = "0178b8bf";
+// `addr(bytes32)`
+const ADDR_SELECTOR: &str = "3b3b57de";
+
+// Registry and resolver functions taking the name hash as the first argument
+const ENS_SELECTORS: [&str; 6] = [
+    RESOLVER_SELECTOR,
+    ADDR_SELECTOR,
+    // `addr(bytes32,uint256)`
+    "f1cb7e06",
+    // `name(bytes32)`
+    "691f3431",
+    // `text(bytes32,string)`
+    "59d1d43c",
+    // `contenthash(bytes32)`
+    "bc1c58d1",
+];
+
+// ENS name hash as defined in EIP-137
+pub fn namehash(name: &str) -> [u8; 32] {
+    let mut node = [0u8; 32];
+    if name.is_empty() {
+        return node;
+    }
+
+    for label in name.rsplit('.') {
+        let label = keccak256(label.to_lowercase().as_bytes());
+        node = keccak256(&[node, label].concat());
+    }
+    node
+}
+
+// Key for the ENS cache if `tx` is an ENS resolution call at the tip, in
+// the cache `namespace` if the tenant has one.
+//
+// Calls pinned to a block are left to the regular cache.
+pub fn ens_key(tx: &Value, namespace: Option<&str>) -> Option<String> {
+    if tx["method"] != "eth_call" {
+        return None;
+    }
+    match &tx["params"][1] {
+        Value::Null => {}
+        Value::String(tag) if tag == "latest" => {}
+        _ => return None,
+    }
+
+    let call = &tx["params"][0];
+    let to = call["to"].as_str()?.to_lowercase();
+    let data = call["data"]
+        .as_str()
+        .or(call["input"].as_str())?
+        .trim_start_matches("0x")
+        .to_lowercase();
+
+    // Selector followed by at least the name hash
+    if data.len() < 8 + 64 || !ENS_SELECTORS.contains(&&data[..8]) {
+        return None;
+    }
+    // Only the registry knows about resolvers
+    if data.starts_with(RESOLVER_SELECTOR) && to != ENS_REGISTRY {
+        return None;
+    }
+
+    // Overrides can make the lookup return anything
+    let overrides = match &tx["params"][2] {
+        Value::Null => String::new(),
+        overrides => overrides.to_string(),
+    };
+
+    Some(format!(
+        "{}\0{}:{}:{}",
+        namespace.unwrap_or_default(),
+        to,
+        data,
+        overrides
+    ))
+}
+
+#[derive(Debug, Default)]
+pub struct EnsCache {
+    ttl: Option<Duration>,
+    entries: RwLock<HashMap<String, (Value, Instant)>>,
+}
+
+impl EnsCache {
+    pub fn new(ttl: Option<Duration>) -> Self {
+        EnsCache {
+            ttl,
+            entries: RwLock::new(HashMap::new()),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.ttl.is_some()
+    }
+
+    // Get the cached result for `key` if it hasn't expired yet
+    pub fn get(&self, key: &str) -> Option<Value> {
+        let ttl = self.ttl?;
+        let entries = self.entries.read().unwrap_or_else(|e| e.into_inner());
+
+        match entries.get(key) {
+            Some((result, inserted)) if inserted.elapsed() < ttl => Some(result.clone()),
+            _ => None,
+        }
+    }
+
+    // Cache the result in `response` unless it's an error
+    pub fn insert(&self, key: String, response: &str) {
+        if self.ttl.is_none() {
+            return;
+        }
+
+        let response: Value = match serde_json::from_str(response) {
+            Ok(response) => response,
+            Err(_) => return,
+        };
+        if response.get("error").is_some() || response["result"].is_null() {
+            return;
+        }
+
+        let mut entries = self.entries.write().unwrap_or_else(|e| e.into_inner());
+        // Drop expired entries so names we stopped seeing don't stick around
+        if let Some(ttl) = self.ttl {
+            entries.retain(|_, (_, inserted)| inserted.elapsed() < ttl);
+        }
+        entries.insert(key, (response["result"].clone(), Instant::now()));
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.read().unwrap_or_else(|e| e.into_inner()).len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Make `call` at `latest` and cache the result
+    async fn cached_call(&self, rpc: &Rpc, to: &str, data: String) -> Result<Value, RpcError> {
+        let tx = json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "method": "eth_call",
+            "params": [{"to": to, "data": data}, "latest"],
+        });
+        let key =
+            ens_key(&tx, None).ok_or(RpcError::InvalidResponse("not an ENS call".to_string()))?;
+
+        let response = rpc.send_request(tx).await?;
+        self.insert(key.clone(), &response);
+
+        self.get(&key).ok_or(RpcError::InvalidResponse(response))
+    }
+
+    // Resolve `name` to its resolver and address, caching both lookups
+    pub async fn prewarm(&self, rpc: &Rpc, name: &str) -> Result<(), RpcError> {
+        let node = hex::encode(namehash(name));
+
+        let resolver = self
+            .cached_call(
+                rpc,
+                ENS_REGISTRY,
+                format!("0x{}{}", RESOLVER_SELECTOR, node),
+            )
+            .await?;
+
+        // The resolver address is the last 20 bytes of the returned word
+        let resolver = resolver
+            .as_str()
+            .unwrap_or_default()
+            .trim_start_matches("0x");
+        if resolver.len() != 64 || resolver.chars().all(|c| c == '0') {
+            return Ok(());
+        }
+        let resolver = format!("0x{}", &resolver[24..]);
+
+        self.cached_call(rpc, &resolver, format!("0x{}{}", ADDR_SELECTOR, node))
+            .await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::node::MockNode;
+
+    #[test]
+    fn test_namehash() {
+        assert_eq!(namehash(""), [0u8; 32]);
+        // From EIP-137
+        assert_eq!(
+            hex::encode(namehash("eth")),
+            "93cdeb708b7545dc668eb9280176169d1c33cfd8ed6f04690a0bcc88a93fc4ae"
+        );
+        assert_eq!(
+            hex::encode(namehash("foo.eth")),
+            "de9b09fd7c5f901e23a3f19fecc54828e9c848539801e86591bd9801b019f84f"
+        );
+    }
+
+    #[test]
+    fn test_ens_key() {
+        let node = hex::encode(namehash("vitalik.eth"));
+        let resolver_call = |to: &str, block: Value| {
+            json!({
+                "method": "eth_call",
+                "params": [{"to": to, "data": format!("0x{}{}", RESOLVER_SELECTOR, node)}, block],
+            })
+        };
+
+        assert!(ens_key(&resolver_call(ENS_REGISTRY, json!("latest")), None).is_some());
+        assert_eq!(
+            ens_key(
+                &resolver_call("0x00000000000C2E074eC69A0dFb2997BA6C7d2e1e", Value::Null),
+                None
+            ),
+            ens_key(&resolver_call(ENS_REGISTRY, json!("latest")), None)
+        );
+        // Pinned calls and resolver lookups on other contracts
+        assert!(ens_key(&resolver_call(ENS_REGISTRY, json!("0x10")), None).is_none());
+        assert!(ens_key(&resolver_call("0x01", json!("latest")), None).is_none());
+
+        // Overrides and tenants get their own entries
+        let key = ens_key(&resolver_call(ENS_REGISTRY, json!("latest")), None);
+        let mut overridden = resolver_call(ENS_REGISTRY, json!("latest"));
+        overridden["params"]
+            .as_array_mut()
+            .unwrap()
+            .push(json!({ENS_REGISTRY: {"code": "0x00"}}));
+        assert!(ens_key(&overridden, None).is_some());
+        assert_ne!(ens_key(&overridden, None), key);
+        assert_ne!(
+            ens_key(
+                &resolver_call(ENS_REGISTRY, json!("latest")),
+                Some("tenant")
+            ),
+            key
+        );
+
+        // `addr` works on any resolver
+        let addr_call = json!({
+            "method": "eth_call",
+            "params": [{"to": "0x02", "input": format!("0x{}{}", ADDR_SELECTOR, node)}],
+        });
+        assert!(ens_key(&addr_call, None).is_some());
+
+        let transfer =
+            json!({"method": "eth_call", "params": [{"to": "0x02", "data": "0xa9059cbb"}]});
+        assert!(ens_key(&transfer, None).is_none());
+    }
+
+    #[test]
+    fn test_ens_cache_ttl() {
+        let cache = EnsCache::new(Some(Duration::from_millis(50)));
+        cache.insert("a".to_string(), r#"{"id":1,"result":"0x01"}"#);
+        cache.insert("b".to_string(), r#"{"id":1,"error":{"code":3}}"#);
+
+        assert_eq!(cache.get("a"), Some(json!("0x01")));
+        assert_eq!(cache.get("b"), None);
+
+        std::thread::sleep(Duration::from_millis(60));
+        assert_eq!(cache.get("a"), None);
+
+        let disabled = EnsCache::default();
+        disabled.insert("a".to_string(), r#"{"id":1,"result":"0x01"}"#);
+        assert!(disabled.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_prewarm() {
+        let node = MockNode::spawn(1).await.unwrap();
+        node.set_response(
+            "eth_call",
+            json!(format!("0x{}{}", "0".repeat(24), "1".repeat(40))),
+        );
+        let rpc = Rpc::new(node.http_url(), None, 0, 0, 1.0);
+
+        let cache = EnsCache::new(Some(Duration::from_secs(60)));
+        cache.prewarm(&rpc, "vitalik.eth").await.unwrap();
+
+        // Resolver and address lookups
+        assert_eq!(cache.len(), 2);
+        assert_eq!(node.request_count(), 2);
+    }
+}
@@ -0,0 +1,254 @@
+// Uniform errors no matter which node failed.
+//
+// Every provider words its errors differently, and some use their own codes
+// for the same thing: a rate limit is -32005 on one, 429 on another and -32007
+// on a third. With `[blutgang.error_map]` set, errors in upstream responses
+// get the standard JSON-RPC code for what went wrong, checked against the
+// configured rules first and then the errors of providers we know about.
+// Rate limits also get a uniform message, since providers advertise their
+// paid plans in theirs. Everything else in the error is kept as it is.
+use crate::config::types::ErrorMapSettings;
+
+use serde_json::Value;
+
+// Standard codes, see EIP-1474
+pub const METHOD_NOT_FOUND: i64 = -32601;
+pub const RESOURCE_NOT_FOUND: i64 = -32001;
+pub const RESOURCE_UNAVAILABLE: i64 = -32002;
+pub const TRANSACTION_REJECTED: i64 = -32003;
+pub const LIMIT_EXCEEDED: i64 = -32005;
+
+const RATE_LIMITED: Option<&str> = Some("Limit exceeded");
+
+// Codes providers answer rate limited requests with
+const RATE_LIMIT_CODES: [i64; 2] = [429, -32007];
+
+// Lowercase parts of error messages we know, what they map to and the
+// message they get if we replace it
+const KNOWN_ERRORS: [(&str, i64, Option<&str>); 18] = [
+    ("rate limit", LIMIT_EXCEEDED, RATE_LIMITED),
+    ("too many requests", LIMIT_EXCEEDED, RATE_LIMITED),
+    ("compute units", LIMIT_EXCEEDED, RATE_LIMITED),
+    ("daily request count exceeded", LIMIT_EXCEEDED, RATE_LIMITED),
+    ("capacity exceeded", LIMIT_EXCEEDED, RATE_LIMITED),
+    ("query returned more than", LIMIT_EXCEEDED, None),
+    ("block range", LIMIT_EXCEEDED, None),
+    ("response size exceeded", LIMIT_EXCEEDED, None),
+    ("does not exist/is not available", METHOD_NOT_FOUND, None),
+    ("method not found", METHOD_NOT_FOUND, None),
+    ("unsupported method", METHOD_NOT_FOUND, None),
+    ("header not found", RESOURCE_NOT_FOUND, None),
+    ("unknown block", RESOURCE_NOT_FOUND, None),
+    ("missing trie node", RESOURCE_UNAVAILABLE, None),
+    ("nonce too low", TRANSACTION_REJECTED, None),
+    ("already known", TRANSACTION_REJECTED, None),
+    (
+        "replacement transaction underpriced",
+        TRANSACTION_REJECTED,
+        None,
+    ),
+    ("insufficient funds", TRANSACTION_REJECTED, None),
+];
+
+fn builtin_rule(code: Option<i64>, message: &str) -> Option<(i64, Option<&'static str>)> {
+    if code.is_some_and(|code| RATE_LIMIT_CODES.contains(&code)) {
+        return Some((LIMIT_EXCEEDED, RATE_LIMITED));
+    }
+
+    KNOWN_ERRORS
+        .iter()
+        .find(|(known, _, _)| message.contains(known))
+        .map(|(_, code, message)| (*code, *message))
+}
+
+// Whether `error` is a node telling us we're rate limited
+pub fn is_rate_limit(error: &Value) -> bool {
+    let message = error["message"].as_str().unwrap_or_default().to_lowercase();
+    builtin_rule(error["code"].as_i64(), &message)
+        .is_some_and(|(_, message)| message == RATE_LIMITED)
+}
+
+// Whether `error` is a node telling us it doesn't have the block asked for
+pub fn is_not_found(error: &Value) -> bool {
+    let code = error["code"].as_i64();
+    let message = error["message"].as_str().unwrap_or_default().to_lowercase();
+    code == Some(RESOURCE_NOT_FOUND)
+        || builtin_rule(code, &message).is_some_and(|(code, _)| code == RESOURCE_NOT_FOUND)
+}
+
+fn map_response(response: &mut Value, settings: &ErrorMapSettings) -> bool {
+    let error = match response.get_mut("error") {
+        Some(error) if error.is_object() => error,
+        _ => return false,
+    };
+
+    let code = error["code"].as_i64();
+    let message = error["message"].as_str().unwrap_or_default().to_lowercase();
+    let (mapped_code, mapped_message) = match settings.rule(code, &message) {
+        Some(rule) => (rule.code, rule.message.as_deref()),
+        None if settings.builtin_rules => {
+            match builtin_rule(code, &message) {
+                Some(mapped) => mapped,
+                None => return false,
+            }
+        }
+        None => return false,
+    };
+
+    error["code"] = mapped_code.into();
+    if let Some(mapped_message) = mapped_message {
+        error["message"] = mapped_message.into();
+    }
+    true
+}
+
+// `rax` with the errors in it mapped according to `settings`
+pub fn map_errors(rax: String, settings: &ErrorMapSettings) -> String {
+    // Most responses aren't errors, don't parse those
+    if !rax.contains("\"error\"") {
+        return rax;
+    }
+
+    let mut response: Value = match serde_json::from_str(&rax) {
+        Ok(response) => response,
+        Err(_) => return rax,
+    };
+    let mapped = match &mut response {
+        Value::Array(responses) => {
+            // Every response in a batch, not just the first error
+            let mut mapped = false;
+            for response in responses.iter_mut() {
+                mapped |= map_response(response, settings);
+            }
+            mapped
+        }
+        response => map_response(response, settings),
+    };
+
+    if mapped {
+        response.to_string()
+    } else {
+        rax
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::types::{
+        ErrorMatch,
+        ErrorRule,
+    };
+    use serde_json::json;
+
+    fn error(code: i64, message: &str) -> String {
+        json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "error": {"code": code, "message": message, "data": "0x"},
+        })
+        .to_string()
+    }
+
+    fn mapped(rax: String, settings: &ErrorMapSettings) -> Value {
+        serde_json::from_str(&map_errors(rax, settings)).unwrap()
+    }
+
+    #[test]
+    fn test_map_errors_builtin() {
+        let settings = ErrorMapSettings::default();
+
+        let rax = mapped(
+            error(
+                429,
+                "Your app has exceeded its compute units per second capacity",
+            ),
+            &settings,
+        );
+        assert_eq!(rax["error"]["code"], LIMIT_EXCEEDED);
+        assert_eq!(rax["error"]["message"], "Limit exceeded");
+        assert_eq!(rax["error"]["data"], "0x");
+
+        let rax = mapped(error(-32000, "header not found"), &settings);
+        assert_eq!(rax["error"]["code"], RESOURCE_NOT_FOUND);
+        assert_eq!(rax["error"]["message"], "header not found");
+
+        // Errors we don't know, and successful responses, are left alone
+        let unknown = error(3, "execution reverted");
+        assert_eq!(map_errors(unknown.clone(), &settings), unknown);
+        let result = json!({"jsonrpc": "2.0", "id": 1, "result": "0x1"}).to_string();
+        assert_eq!(map_errors(result.clone(), &settings), result);
+
+        let batch = format!(
+            "[{},{}]",
+            error(-32000, "nonce too low"),
+            error(3, "reverted")
+        );
+        let rax = mapped(batch, &settings);
+        assert_eq!(rax[0]["error"]["code"], TRANSACTION_REJECTED);
+        assert_eq!(rax[1]["error"]["code"], 3);
+    }
+
+    #[test]
+    fn test_is_rate_limit() {
+        assert!(is_rate_limit(
+            &json!({"code": 429, "message": "Too many requests"})
+        ));
+        assert!(is_rate_limit(
+            &json!({"code": -32005, "message": "daily request count exceeded, request rate limited"})
+        ));
+        // Same code, but the request was too big rather than too frequent
+        assert!(!is_rate_limit(
+            &json!({"code": -32005, "message": "query returned more than 10000 results"})
+        ));
+        assert!(!is_rate_limit(
+            &json!({"code": 3, "message": "execution reverted"})
+        ));
+    }
+
+    #[test]
+    fn test_is_not_found() {
+        assert!(is_not_found(
+            &json!({"code": -32000, "message": "header not found"})
+        ));
+        assert!(is_not_found(
+            &json!({"code": -32001, "message": "Block 0x12 not found"})
+        ));
+        assert!(!is_not_found(
+            &json!({"code": -32601, "message": "Method not found"})
+        ));
+        assert!(!is_not_found(
+            &json!({"code": -32000, "message": "missing trie node"})
+        ));
+    }
+
+    #[test]
+    fn test_map_errors_rules() {
+        let settings = ErrorMapSettings {
+            builtin_rules: false,
+            rules: vec![
+                ErrorRule {
+                    matches: ErrorMatch::Code(-32099),
+                    code: -32603,
+                    message: None,
+                },
+                ErrorRule {
+                    matches: ErrorMatch::Message("reverted".to_string()),
+                    code: -32000,
+                    message: Some("Reverted".to_string()),
+                },
+            ],
+        };
+
+        assert_eq!(
+            mapped(error(-32099, "oops"), &settings)["error"]["code"],
+            -32603
+        );
+        let rax = mapped(error(3, "execution reverted"), &settings);
+        assert_eq!(rax["error"]["code"], -32000);
+        assert_eq!(rax["error"]["message"], "Reverted");
+        // Built-in rules are off
+        let limited = error(429, "Too many requests");
+        assert_eq!(map_errors(limited.clone(), &settings), limited);
+    }
+}
@@ -0,0 +1,287 @@
+// Smoothed `eth_estimateGas`.
+//
+// A single node occasionally under-estimates, and transactions sent with that
+// estimate run out of gas. When `[blutgang.estimate_gas]` is set we ask
+// several nodes and answer with the highest estimate (or a lower percentile
+// of them). Estimates are cached for a short while per call, block and state
+// overrides, since wallets tend to ask for the same one a few times in a
+// row. Tenants sharing our cache get their own estimates, and requests for a
+// group only go to its nodes.
+use crate::{
+    balancer::{
+        cache_entry::CacheKey,
+        selection::select::argsort,
+    },
+    config::types::EstimateGasSettings,
+    rpc::types::hex_to_decimal,
+    Rpc,
+};
+
+use std::{
+    collections::HashMap,
+    sync::{
+        Arc,
+        RwLock,
+    },
+    time::{
+        Duration,
+        Instant,
+    },
+};
+
+use futures::future::join_all;
+use serde_json::{
+    json,
+    Value,
+};
+use tokio::time::timeout;
+
+// Prune expired estimates once we have this many
+const MAX_ENTRIES: usize = 10_000;
+
+// Key for the estimate cache if `tx` is an `eth_estimateGas` call. Covers
+// the whole call, gas and fee fields and access list included, the block
+// and any state overrides, in the cache `namespace` if the tenant has one.
+pub fn estimate_key(tx: &Value, namespace: Option<&str>) -> Option<CacheKey> {
+    if tx["method"] != "eth_estimateGas" {
+        return None;
+    }
+
+    let block = match &tx["params"][1] {
+        Value::Null => json!("latest"),
+        block => block.clone(),
+    };
+    let call = json!([tx["params"][0], block, tx["params"][2]]);
+    Some(match namespace {
+        Some(namespace) => CacheKey::namespaced(namespace, &call),
+        None => CacheKey::new(&call),
+    })
+}
+
+// Estimate at `percentile` (0-100) of `estimates`. `estimates` must be sorted.
+fn pick_estimate(estimates: &[u64], percentile: f64) -> u64 {
+    let rank = (percentile / 100.0 * estimates.len() as f64).ceil() as usize;
+    estimates[rank.clamp(1, estimates.len()) - 1]
+}
+
+#[derive(Debug, Default)]
+pub struct GasEstimator {
+    settings: Option<EstimateGasSettings>,
+    entries: RwLock<HashMap<CacheKey, (u64, Instant)>>,
+}
+
+impl GasEstimator {
+    pub fn new(settings: Option<EstimateGasSettings>) -> Self {
+        GasEstimator {
+            settings,
+            entries: RwLock::new(HashMap::new()),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.settings.is_some()
+    }
+
+    fn get(&self, key: &CacheKey, ttl: Duration) -> Option<u64> {
+        let entries = self.entries.read().unwrap_or_else(|e| e.into_inner());
+        match entries.get(key) {
+            Some((estimate, inserted)) if inserted.elapsed() < ttl => Some(*estimate),
+            _ => None,
+        }
+    }
+
+    fn insert(&self, key: CacheKey, estimate: u64, ttl: Duration) {
+        let mut entries = self.entries.write().unwrap_or_else(|e| e.into_inner());
+        if entries.len() >= MAX_ENTRIES {
+            entries.retain(|_, (_, inserted)| inserted.elapsed() < ttl);
+        }
+        entries.insert(key, (estimate, Instant::now()));
+    }
+
+    // Answer `tx` with an estimate from several nodes, the fastest ones in
+    // `group` if it's set.
+    //
+    // Returns `None` if none of the nodes answered, so the request can go
+    // through the regular rotation instead.
+    pub async fn estimate(
+        &self,
+        tx: &Value,
+        rpc_list: &Arc<RwLock<Vec<Rpc>>>,
+        group: Option<&str>,
+        namespace: Option<&str>,
+        ttl: u128,
+    ) -> Option<Value> {
+        let settings = self.settings.as_ref()?;
+        let key = estimate_key(tx, namespace)?;
+
+        if let Some(estimate) = self.get(&key, settings.cache_ttl) {
+            return Some(
+                json!({"jsonrpc": "2.0", "id": tx["id"], "result": format!("0x{:x}", estimate)}),
+            );
+        }
+
+        let nodes: Vec<Rpc> = {
+            let rpc_list = rpc_list.read().unwrap_or_else(|e| e.into_inner());
+            argsort(&rpc_list)
+                .into_iter()
+                .map(|index| &rpc_list[index])
+                .filter(|rpc| group.map_or(true, |group| rpc.in_group(group)))
+                .filter(|rpc| rpc.is_available(tx))
+                .take(settings.nodes)
+                .cloned()
+                .collect()
+        };
+        let request_timeout = Duration::from_millis(ttl.try_into().unwrap_or(u64::MAX));
+        let responses = join_all(
+            nodes
+                .iter()
+                .map(|rpc| timeout(request_timeout, rpc.send_request(tx.clone()))),
+        )
+        .await;
+
+        let mut estimates = Vec::new();
+        let mut error = None;
+        for response in responses {
+            let response: Value = match response {
+                Ok(Ok(response)) => serde_json::from_str(&response).unwrap_or_default(),
+                _ => continue,
+            };
+
+            match response["result"].as_str().map(hex_to_decimal) {
+                Some(Ok(estimate)) => estimates.push(estimate),
+                // Keep the revert reason around in case every node fails
+                _ if response.get("error").is_some() => error = error.or(Some(response)),
+                _ => {}
+            }
+        }
+
+        if estimates.is_empty() {
+            return error.map(|mut error| {
+                error["id"] = tx["id"].clone();
+                error
+            });
+        }
+
+        estimates.sort_unstable();
+        let estimate = pick_estimate(&estimates, settings.percentile);
+        self.insert(key, estimate, settings.cache_ttl);
+
+        Some(json!({"jsonrpc": "2.0", "id": tx["id"], "result": format!("0x{:x}", estimate)}))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::node::MockNode;
+
+    fn estimate_tx() -> Value {
+        json!({
+            "jsonrpc": "2.0",
+            "id": 7,
+            "method": "eth_estimateGas",
+            "params": [{"from": "0xAbC", "to": "0xdef", "data": "0x1234"}],
+        })
+    }
+
+    #[test]
+    fn test_estimate_key() {
+        let key = estimate_key(&estimate_tx(), None).unwrap();
+        let mut latest = estimate_tx();
+        latest["params"]
+            .as_array_mut()
+            .unwrap()
+            .push(json!("latest"));
+        assert_eq!(estimate_key(&latest, None), Some(key));
+
+        // Anything that changes the estimate changes the key
+        let mut gas = estimate_tx();
+        gas["params"][0]["gas"] = json!("0x5208");
+        let mut fees = estimate_tx();
+        fees["params"][0]["maxFeePerGas"] = json!("0x1");
+        let mut access_list = estimate_tx();
+        access_list["params"][0]["accessList"] = json!([{"address": "0xdef", "storageKeys": []}]);
+        let mut overrides = latest.clone();
+        overrides["params"]
+            .as_array_mut()
+            .unwrap()
+            .push(json!({"0xabc": {"balance": "0xffff"}}));
+        for tx in [gas, fees, access_list, overrides] {
+            assert_ne!(estimate_key(&tx, None), Some(key));
+        }
+        assert_ne!(estimate_key(&estimate_tx(), Some("tenant")), Some(key));
+
+        assert!(estimate_key(&json!({"method": "eth_call", "params": []}), None).is_none());
+    }
+
+    #[test]
+    fn test_pick_estimate() {
+        let estimates = [21000, 50000, 60000, 90000];
+        assert_eq!(pick_estimate(&estimates, 100.0), 90000);
+        assert_eq!(pick_estimate(&estimates, 50.0), 50000);
+        assert_eq!(pick_estimate(&estimates, 0.0), 21000);
+    }
+
+    #[tokio::test]
+    async fn test_estimate() {
+        let low = MockNode::spawn(1).await.unwrap();
+        low.set_response("eth_estimateGas", json!("0x5208"));
+        let high = MockNode::spawn(1).await.unwrap();
+        high.set_response("eth_estimateGas", json!("0x7530"));
+        let rpc_list = Arc::new(RwLock::new(vec![
+            Rpc::new(low.http_url(), None, 6, 0, 10.0),
+            Rpc::new(high.http_url(), None, 6, 0, 10.0),
+        ]));
+
+        let estimator = GasEstimator::new(Some(EstimateGasSettings::default()));
+        let response = estimator
+            .estimate(&estimate_tx(), &rpc_list, None, None, 1000)
+            .await
+            .unwrap();
+        assert_eq!(response["result"], "0x7530");
+        assert_eq!(response["id"], 7);
+
+        // Served from the cache the second time
+        estimator
+            .estimate(&estimate_tx(), &rpc_list, None, None, 1000)
+            .await
+            .unwrap();
+        assert_eq!(low.request_count(), 1);
+        assert_eq!(high.request_count(), 1);
+
+        // Only nodes in the group are asked
+        let mut other = estimate_tx();
+        other["params"][0]["value"] = json!("0x1");
+        let response = estimator
+            .estimate(&other, &rpc_list, Some("archive"), None, 1000)
+            .await;
+        assert!(response.is_none());
+
+        // Nothing to do when disabled
+        assert!(GasEstimator::default()
+            .estimate(&estimate_tx(), &rpc_list, None, None, 1000)
+            .await
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn test_estimate_errors() {
+        // The mock node doesn't know `eth_estimateGas`
+        let node = MockNode::spawn(1).await.unwrap();
+        let rpc_list = Arc::new(RwLock::new(vec![Rpc::new(
+            node.http_url(),
+            None,
+            6,
+            0,
+            10.0,
+        )]));
+
+        let estimator = GasEstimator::new(Some(EstimateGasSettings::default()));
+        let response = estimator
+            .estimate(&estimate_tx(), &rpc_list, None, None, 1000)
+            .await
+            .unwrap();
+        assert_eq!(response["error"]["code"], -32601);
+        assert_eq!(response["id"], 7);
+    }
+}
@@ -1,7 +1,14 @@
-use crate::NamedBlocknumbers;
+use crate::{
+    config::{
+        system::DebugModule,
+        types::JsonRpcMode,
+    },
+    health::safe_block::NamedBlocknumbers,
+    log_dbg,
+};
 use http_body_util::BodyExt;
 use hyper::{
-    body::Incoming,
+    body::Body,
     Request,
 };
 use memchr::memmem;
@@ -12,6 +19,7 @@ use serde_json::{
 };
 use simd_json::serde::from_str;
 use std::{
+    fmt::Debug,
     str::from_utf8,
     sync::{
         Arc,
@@ -50,6 +58,72 @@ fn has_named_number(param: &str) -> NamedNumber {
     NamedNumber::Null
 }
 
+// Index of the block parameter for methods that take one.
+//
+// The JSON-RPC standard is all over the place so depending on the method, we need to look at
+// different param indexes. Why? Has i ever???
+pub fn block_param_position(method: &str) -> Option<usize> {
+    match method {
+        "eth_getBalance" | "eth_getTransactionCount" | "eth_getCode" | "eth_call" => Some(1),
+        "eth_getStorageAt" => Some(2),
+        "eth_getBlockTransactionCountByNumber"
+        | "eth_getUncleCountByBlockNumber"
+        | "eth_getBlockByNumber"
+        | "eth_getTransactionByBlockNumberAndIndex"
+        | "eth_getUncleByBlockNumberAndIndex" => Some(0),
+        _ => None,
+    }
+}
+
+// Returns true if `tx` asks about the pending block, like
+// `eth_getTransactionCount(address, "pending")`, or goes to the mempool
+pub fn is_pending_request(tx: &Value) -> bool {
+    let method = tx["method"].as_str().unwrap_or_default();
+    match method {
+        "eth_sendRawTransaction" => true,
+        "eth_estimateGas" | "eth_createAccessList" => tx["params"][1] == "pending",
+        "eth_getLogs" => {
+            tx["params"][0]["fromBlock"] == "pending" || tx["params"][0]["toBlock"] == "pending"
+        }
+        _ => {
+            block_param_position(method).is_some_and(|position| tx["params"][position] == "pending")
+        }
+    }
+}
+
+// Normalize EIP-1898 block parameters so equivalent requests hash the same.
+// `{"blockNumber": n}` becomes `n`, block hashes get lowercased and the
+// default `"requireCanonical": false` is dropped.
+pub fn normalize_block_param(tx: &mut Value) {
+    let position = match tx["method"].as_str().and_then(block_param_position) {
+        Some(position) => position,
+        None => return,
+    };
+    let param = match tx["params"].get_mut(position) {
+        Some(param) if param.is_object() => param,
+        _ => return,
+    };
+
+    if let Some(number) = param.get("blockNumber").cloned() {
+        *param = number;
+        return;
+    }
+    if let Some(hash) = param["blockHash"].as_str().map(str::to_lowercase) {
+        param["blockHash"] = json!(hash);
+        if param.get("requireCanonical") == Some(&json!(false)) {
+            param.as_object_mut().unwrap().remove("requireCanonical");
+        }
+    }
+}
+
+// Block hash of an EIP-1898 `{"blockHash": ..}` block parameter
+pub fn block_hash_param(tx: &Value) -> Option<String> {
+    let position = tx["method"].as_str().and_then(block_param_position)?;
+    tx["params"][position]["blockHash"]
+        .as_str()
+        .map(str::to_lowercase)
+}
+
 // Return the blocknumber from a json-rpc request as a Option<String>, returning None if it cant find anything
 pub fn get_block_number_from_request(
     tx: Value,
@@ -65,24 +139,17 @@ pub fn get_block_number_from_request(
         return None;
     }
 
-    // The JSON-RPC standard is all over the place so depending on the method, we need to look at
-    // different param indexes. Why? Has i ever???
-    let position = match tx["method"].as_str() {
-        Some("eth_getBalance") => 1,
-        Some("eth_getStorageAt") => 2,
-        Some("eth_getTransactionCount") => 1,
-        Some("eth_getBlockTransactionCountByNumber") => 0,
-        Some("eth_getUncleCountByBlockNumber") => 0,
-        Some("eth_getCode") => 1,
-        Some("eth_call") => 1,
-        Some("eth_getBlockByNumber") => 0,
-        Some("eth_getTransactionByBlockNumberAndIndex") => 0,
-        Some("eth_getUncleByBlockNumberAndIndex") => 0,
-        _ => return None,
-    };
+    let position = tx["method"].as_str().and_then(block_param_position)?;
+
+    // Block hashes don't tell us the number, EIP-1898 block numbers do
+    let block_param = &tx["params"][position];
+    if block_param.get("blockHash").is_some() {
+        return None;
+    }
+    let block_param = block_param.get("blockNumber").unwrap_or(block_param);
 
     // Get the corresponding blockbumber from the params
-    let block_number = tx["params"][position].to_string().replace('\"', "");
+    let block_number = block_param.to_string().replace('\"', "");
 
     // Return the corresponding named parameter from the RwLock is present
     let nn = has_named_number(&block_number);
@@ -120,18 +187,9 @@ pub fn replace_block_tags(
     }
 
     // Determine the correct parameter index based on the method
-    let position = match tx["method"].as_str() {
-        Some("eth_getBalance")
-        | Some("eth_getTransactionCount")
-        | Some("eth_getCode")
-        | Some("eth_call") => 1,
-        Some("eth_getStorageAt") => 2,
-        Some("eth_getBlockTransactionCountByNumber")
-        | Some("eth_getUncleCountByBlockNumber")
-        | Some("eth_getBlockByNumber")
-        | Some("eth_getTransactionByBlockNumberAndIndex")
-        | Some("eth_getUncleByBlockNumberAndIndex") => 0,
-        _ => return tx.to_owned(),
+    let position = match tx["method"].as_str().and_then(block_param_position) {
+        Some(position) => position,
+        None => return tx.to_owned(),
     };
 
     // Extract the block number parameter
@@ -144,15 +202,11 @@ pub fn replace_block_tags(
 
         // Replace the named block tag with its corresponding hex value
         match nn {
-            NamedNumber::Latest => {
-                if rwlock_guard.latest != 0 {
-                    tx["params"][position] = json!(format!("0x{:x}", rwlock_guard.latest));
-                }
+            NamedNumber::Latest if rwlock_guard.latest != 0 => {
+                tx["params"][position] = json!(format!("0x{:x}", rwlock_guard.latest));
             }
-            NamedNumber::Finalized => {
-                if rwlock_guard.finalized != 0 {
-                    tx["params"][position] = json!(format!("0x{:x}", rwlock_guard.finalized));
-                }
+            NamedNumber::Finalized if rwlock_guard.finalized != 0 => {
+                tx["params"][position] = json!(format!("0x{:x}", rwlock_guard.finalized));
             }
             _ => (),
         }
@@ -161,28 +215,129 @@ pub fn replace_block_tags(
     tx.to_owned()
 }
 
-pub async fn incoming_to_value(tx: Request<Incoming>) -> Result<Value, hyper::Error> {
-    #[cfg(feature = "debug-verbose")]
-    println!("Incoming request: {:?}", tx);
+// Check the request against the JSON-RPC 2.0 spec depending on `mode`.
+//
+// Lenient mode repairs missing or wrong `jsonrpc`/`params` fields in place.
+// Strict mode leaves the request alone and returns a conformant error object
+// that should be sent back to the user instead of forwarding the request.
+// Batches are checked call by call, and if any of them is malformed we answer
+// with an error for each call in it.
+pub fn enforce_jsonrpc(tx: &mut Value, mode: JsonRpcMode) -> Result<(), Value> {
+    match mode {
+        JsonRpcMode::Off => Ok(()),
+        JsonRpcMode::Lenient => {
+            match tx {
+                Value::Array(calls) => calls.iter_mut().for_each(repair_call),
+                call => repair_call(call),
+            }
+            Ok(())
+        }
+        JsonRpcMode::Strict => {
+            let calls = match tx.as_array() {
+                // An empty batch is a single invalid request
+                Some(calls) if calls.is_empty() => {
+                    return Err(invalid_request(&Null, "batch must not be empty"))
+                }
+                Some(calls) => calls,
+                None => {
+                    return match invalid_reason(tx) {
+                        Some(reason) => Err(invalid_request(tx, reason)),
+                        None => Ok(()),
+                    }
+                }
+            };
+
+            let reasons: Vec<Option<&str>> = calls.iter().map(invalid_reason).collect();
+            if reasons.iter().all(Option::is_none) {
+                return Ok(());
+            }
+
+            Err(Value::Array(
+                calls
+                    .iter()
+                    .zip(reasons)
+                    .map(|(call, reason)| {
+                        invalid_request(call, reason.unwrap_or("batch contains malformed requests"))
+                    })
+                    .collect(),
+            ))
+        }
+    }
+}
+
+// Fill in the `jsonrpc` and `params` fields of a single call if needed
+fn repair_call(tx: &mut Value) {
+    if let Some(tx) = tx.as_object_mut() {
+        if tx.get("jsonrpc") != Some(&json!("2.0")) {
+            tx.insert("jsonrpc".to_string(), json!("2.0"));
+        }
+        if tx.get("params").map_or(true, |p| p.is_null()) {
+            tx.insert("params".to_string(), json!([]));
+        }
+    }
+}
+
+// Why a single call doesn't conform to the spec, if it doesn't
+fn invalid_reason(tx: &Value) -> Option<&'static str> {
+    let reason = if !tx.is_object() {
+        "request must be an object"
+    } else if tx["jsonrpc"] != "2.0" {
+        "`jsonrpc` must be exactly \"2.0\""
+    } else if !tx["method"].is_string() {
+        "`method` must be a string"
+    } else if !(tx["params"].is_null() || tx["params"].is_array() || tx["params"].is_object()) {
+        "`params` must be an array or object"
+    } else if !(tx["id"].is_null() || tx["id"].is_number() || tx["id"].is_string()) {
+        "`id` must be a number, string or null"
+    } else {
+        return None;
+    };
+    Some(reason)
+}
+
+fn invalid_request(tx: &Value, reason: &str) -> Value {
+    // Only echo back ids that are valid to begin with
+    let id = match &tx["id"] {
+        Value::Number(_) | Value::String(_) => tx["id"].clone(),
+        _ => Null,
+    };
+
+    json!({
+        "jsonrpc": "2.0",
+        "id": id,
+        "error": {
+            "code": -32600,
+            "message": format!("Invalid Request: {}", reason),
+        },
+    })
+}
+
+pub async fn incoming_to_value<B>(tx: Request<B>) -> Result<Value, B::Error>
+where
+    B: Body + Debug,
+{
+    log_dbg!(DebugModule::Http, "Incoming request: {:?}", tx);
+
+    let tx = tx.collect().await?.to_bytes();
 
-    let tx = tx.collect().await?.to_bytes().clone();
-    let mut tx = from_utf8(&tx).unwrap().to_owned();
+    Ok(body_to_value(&tx))
+}
+
+// Parse a request body we already read
+pub fn body_to_value(tx: &[u8]) -> Value {
+    let mut tx = from_utf8(tx).unwrap().to_owned();
 
-    let ret = match unsafe { from_str(&mut tx) } {
+    match unsafe { from_str(&mut tx) } {
         Ok(ret) => ret,
         Err(_) => {
             // Insane error handling
-            let ret = json!({
+            json!({
                 "id": Null,
                 "jsonrpc": "2.0",
                 "result": "Invalid JSON",
-            });
-
-            return Ok(ret);
+            })
         }
-    };
-
-    Ok(ret)
+    }
 }
 
 #[cfg(test)]
@@ -213,6 +368,107 @@ mod tests {
         assert_eq!(has_named_number("0"), NamedNumber::Null);
     }
 
+    #[test]
+    fn normalize_block_param_test() {
+        let mut request = json!({
+            "method": "eth_getBalance",
+            "params": ["0x1", {"blockHash": "0xABC", "requireCanonical": false}],
+        });
+        normalize_block_param(&mut request);
+        assert_eq!(request["params"][1], json!({"blockHash": "0xabc"}));
+        assert_eq!(block_hash_param(&request), Some("0xabc".to_string()));
+
+        // `requireCanonical: true` changes the answer, so it stays
+        let mut request = json!({
+            "method": "eth_call",
+            "params": [{}, {"blockHash": "0xabc", "requireCanonical": true}],
+        });
+        normalize_block_param(&mut request);
+        assert_eq!(request["params"][1]["requireCanonical"], true);
+
+        let mut request = json!({
+            "method": "eth_getCode",
+            "params": ["0x1", {"blockNumber": "0x10"}],
+        });
+        normalize_block_param(&mut request);
+        assert_eq!(request["params"][1], "0x10");
+        assert_eq!(block_hash_param(&request), None);
+
+        let request = json!({
+            "method": "eth_getBalance",
+            "params": ["0x1", {"blockHash": "0xabc"}],
+        });
+        assert_eq!(
+            get_block_number_from_request(request, &dummy_named_blocknumbers()),
+            None
+        );
+        let request = json!({
+            "method": "eth_getBalance",
+            "params": ["0x1", {"blockNumber": "latest"}],
+        });
+        assert_eq!(
+            get_block_number_from_request(request, &dummy_named_blocknumbers()),
+            Some(10)
+        );
+    }
+
+    #[test]
+    fn is_pending_request_test() {
+        let request = json!({
+            "id":1,
+            "jsonrpc":"2.0",
+            "method":"eth_getTransactionCount",
+            "params":["0x407d73d8a49eeb85d32cf465507dd71d507100c1", "pending"]
+        });
+        assert!(is_pending_request(&request));
+
+        let request = json!({
+            "id":1,
+            "jsonrpc":"2.0",
+            "method":"eth_getBlockByNumber",
+            "params":["pending", false]
+        });
+        assert!(is_pending_request(&request));
+
+        let request = json!({
+            "id":1,
+            "jsonrpc":"2.0",
+            "method":"eth_getTransactionCount",
+            "params":["0x407d73d8a49eeb85d32cf465507dd71d507100c1", "latest"]
+        });
+        assert!(!is_pending_request(&request));
+
+        let request = json!({"id":1, "jsonrpc":"2.0", "method":"eth_blockNumber", "params":[]});
+        assert!(!is_pending_request(&request));
+
+        let request = json!({
+            "id":1,
+            "jsonrpc":"2.0",
+            "method":"eth_getLogs",
+            "params":[{"fromBlock": "0x10", "toBlock": "pending"}]
+        });
+        assert!(is_pending_request(&request));
+
+        let request = json!({
+            "id":1,
+            "jsonrpc":"2.0",
+            "method":"eth_estimateGas",
+            "params":[{"to": "0x407d73d8a49eeb85d32cf465507dd71d507100c1"}, "pending"]
+        });
+        assert!(is_pending_request(&request));
+        let request = json!({
+            "id":1,
+            "jsonrpc":"2.0",
+            "method":"eth_createAccessList",
+            "params":[{"to": "0x407d73d8a49eeb85d32cf465507dd71d507100c1"}]
+        });
+        assert!(!is_pending_request(&request));
+
+        let request =
+            json!({"id":1, "jsonrpc":"2.0", "method":"eth_sendRawTransaction", "params":["0x01"]});
+        assert!(is_pending_request(&request));
+    }
+
     #[test]
     fn get_block_number_from_request_test() {
         // Set up a fake NamedBlocknumbers
@@ -520,4 +776,84 @@ mod tests {
 
         assert_eq!(replace_block_tags(&mut tx, &named_blocknumbers), tx);
     }
+
+    #[test]
+    fn enforce_jsonrpc_off_test() {
+        let mut tx = json!({"method": "eth_blockNumber", "id": 1});
+        let expected = tx.clone();
+
+        assert!(enforce_jsonrpc(&mut tx, JsonRpcMode::Off).is_ok());
+        assert_eq!(tx, expected);
+    }
+
+    #[test]
+    fn enforce_jsonrpc_lenient_test() {
+        let mut tx = json!({"method": "eth_blockNumber", "id": 1, "jsonrpc": "1.0"});
+
+        assert!(enforce_jsonrpc(&mut tx, JsonRpcMode::Lenient).is_ok());
+        assert_eq!(
+            tx,
+            json!({"method": "eth_blockNumber", "id": 1, "jsonrpc": "2.0", "params": []})
+        );
+    }
+
+    #[test]
+    fn enforce_jsonrpc_strict_test() {
+        let mut tx = json!({"method": "eth_blockNumber", "id": 1, "jsonrpc": "2.0"});
+        assert!(enforce_jsonrpc(&mut tx, JsonRpcMode::Strict).is_ok());
+
+        let mut tx = json!({"method": "eth_blockNumber", "id": 7});
+        let err = enforce_jsonrpc(&mut tx, JsonRpcMode::Strict).unwrap_err();
+        assert_eq!(err["id"], 7);
+        assert_eq!(err["error"]["code"], -32600);
+
+        let mut tx = json!({"method": 1, "id": 1, "jsonrpc": "2.0"});
+        assert!(enforce_jsonrpc(&mut tx, JsonRpcMode::Strict).is_err());
+
+        let mut tx = json!({"method": "eth_call", "params": "0x1", "id": 1, "jsonrpc": "2.0"});
+        assert!(enforce_jsonrpc(&mut tx, JsonRpcMode::Strict).is_err());
+
+        let mut tx = json!({"method": "eth_call", "id": [1], "jsonrpc": "2.0"});
+        let err = enforce_jsonrpc(&mut tx, JsonRpcMode::Strict).unwrap_err();
+        assert_eq!(err["id"], Null);
+    }
+
+    #[test]
+    fn enforce_jsonrpc_batch_test() {
+        let mut tx = json!([
+            {"method": "eth_blockNumber", "id": 1},
+            {"method": "eth_chainId", "id": 2, "jsonrpc": "2.0", "params": null},
+        ]);
+        assert!(enforce_jsonrpc(&mut tx, JsonRpcMode::Lenient).is_ok());
+        assert_eq!(tx[0]["jsonrpc"], "2.0");
+        assert_eq!(tx[1]["params"], json!([]));
+        assert!(enforce_jsonrpc(&mut tx, JsonRpcMode::Strict).is_ok());
+
+        // Every call gets an answer, with the reason for the malformed ones
+        let mut tx = json!([
+            {"method": "eth_blockNumber", "id": 1, "jsonrpc": "2.0"},
+            {"method": 1, "id": 2, "jsonrpc": "2.0"},
+            "oops",
+        ]);
+        let err = enforce_jsonrpc(&mut tx, JsonRpcMode::Strict).unwrap_err();
+        let err = err.as_array().unwrap();
+        assert_eq!(err.len(), 3);
+        assert_eq!(err[0]["id"], 1);
+        assert_eq!(
+            err[0]["error"]["message"],
+            "Invalid Request: batch contains malformed requests"
+        );
+        assert_eq!(err[1]["id"], 2);
+        assert_eq!(
+            err[1]["error"]["message"],
+            "Invalid Request: `method` must be a string"
+        );
+        assert_eq!(err[2]["id"], Null);
+        assert_eq!(err[2]["error"]["code"], -32600);
+
+        let mut tx = json!([]);
+        let err = enforce_jsonrpc(&mut tx, JsonRpcMode::Strict).unwrap_err();
+        assert!(err.is_object());
+        assert_eq!(err["error"]["code"], -32600);
+    }
 }
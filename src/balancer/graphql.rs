@@ -0,0 +1,427 @@
+// Ethereum GraphQL (EIP-1767) passthrough.
+//
+// Requests to `/graphql` go to nodes with a `graphql_url`, fastest first,
+// skipping nodes that are cooling down, and move on to the next node if one
+// can't answer. Queries that don't depend on anything but the chain are the
+// same for everyone, so answers are cached by the query, with whitespace and
+// comments stripped, and its variables. Most queries look at the head one way
+// or another, so answers are only kept until the head moves.
+//
+// Requests are checked against identity policies and counted as `graphql`
+// calls, or `eth_sendRawTransaction` for mutations since that's what they
+// do. Tenants only reach nodes in their group, and only share cached answers
+// within their namespace.
+use crate::{
+    config::types::RateLimitSettings,
+    log_wrn,
+    rpc::error::RpcError,
+    Rpc,
+};
+
+use std::{
+    collections::HashMap,
+    sync::{
+        Arc,
+        Mutex,
+        RwLock,
+    },
+};
+
+use http_body_util::Full;
+use hyper::{
+    body::Bytes,
+    Response,
+};
+use serde_json::{
+    json,
+    Value,
+};
+
+pub const GRAPHQL_PATH: &str = "/graphql";
+
+// What policies and usage call GraphQL queries
+pub const GRAPHQL_METHOD: &str = "graphql";
+
+// Answers we keep for one head at most
+const MAX_CACHED: usize = 4096;
+
+// Characters GraphQL doesn't need whitespace around
+const PUNCTUATORS: &[char] = &[
+    '{', '}', '(', ')', '[', ']', ':', ',', '!', '=', '$', '@', '|',
+];
+
+// Strip comments and whitespace that don't change what `query` means
+pub fn normalize_query(query: &str) -> String {
+    let mut normalized = String::with_capacity(query.len());
+    let mut chars = query.chars();
+    let mut pending_space = false;
+
+    while let Some(c) = chars.next() {
+        match c {
+            // Strings are kept as they are
+            '"' => {
+                if pending_space && !normalized.ends_with(PUNCTUATORS) {
+                    normalized.push(' ');
+                }
+                pending_space = false;
+                normalized.push(c);
+                while let Some(c) = chars.next() {
+                    normalized.push(c);
+                    match c {
+                        '\\' => normalized.extend(chars.next()),
+                        '"' => break,
+                        _ => {}
+                    }
+                }
+            }
+            '#' => {
+                for c in chars.by_ref() {
+                    if c == '\n' {
+                        break;
+                    }
+                }
+                pending_space = true;
+            }
+            // Commas are whitespace to GraphQL
+            c if c.is_whitespace() || c == ',' => pending_space = true,
+            c => {
+                if pending_space
+                    && !normalized.is_empty()
+                    && !normalized.ends_with(PUNCTUATORS)
+                    && !PUNCTUATORS.contains(&c)
+                {
+                    normalized.push(' ');
+                }
+                pending_space = false;
+                normalized.push(c);
+            }
+        }
+    }
+
+    normalized
+}
+
+// Mutations send transactions
+fn is_mutation(query: &str) -> bool {
+    query.starts_with("mutation") || query.contains("}mutation")
+}
+
+// Method `request` is checked and counted as
+pub fn method(request: &Value) -> &'static str {
+    match request["query"]
+        .as_str()
+        .is_some_and(|query| is_mutation(&normalize_query(query)))
+    {
+        true => "eth_sendRawTransaction",
+        false => GRAPHQL_METHOD,
+    }
+}
+
+// What we cache an answer to `request` under. `None` if it can't be cached.
+pub fn cache_key(request: &Value) -> Option<String> {
+    let query = normalize_query(request["query"].as_str()?);
+    if is_mutation(&query) {
+        return None;
+    }
+
+    // Object keys are sorted, so the same variables always look the same
+    Some(format!(
+        "{}\n{}\n{}",
+        query, request["operationName"], request["variables"]
+    ))
+}
+
+// Parse a GraphQL request body, `None` if it isn't one
+pub fn parse(body: &[u8]) -> Option<Value> {
+    serde_json::from_slice::<Value>(body)
+        .ok()
+        .filter(|request| request["query"].is_string())
+}
+
+fn response(status: u16, body: String) -> Response<Full<Bytes>> {
+    Response::builder()
+        .status(status)
+        .header("Content-Type", "application/json")
+        .body(Full::new(Bytes::from(body)))
+        .unwrap()
+}
+
+pub fn error_response(status: u16, message: &str) -> Response<Full<Bytes>> {
+    response(
+        status,
+        json!({"errors": [{"message": message}]}).to_string(),
+    )
+}
+
+#[derive(Debug, Default)]
+struct Cached {
+    // Head the answers are for
+    head: u64,
+    answers: HashMap<String, String>,
+}
+
+#[derive(Debug, Default)]
+pub struct Graphql {
+    cache: Mutex<Cached>,
+}
+
+impl Graphql {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn cached(&self, key: &str, head: u64) -> Option<String> {
+        let cache = self.cache.lock().unwrap_or_else(|e| e.into_inner());
+        (cache.head == head).then(|| cache.answers.get(key).cloned())?
+    }
+
+    fn insert(&self, key: String, head: u64, rax: String) {
+        let mut cache = self.cache.lock().unwrap_or_else(|e| e.into_inner());
+        if cache.head != head {
+            cache.head = head;
+            cache.answers.clear();
+        }
+        if cache.answers.len() < MAX_CACHED {
+            cache.answers.insert(key, rax);
+        }
+    }
+
+    // Answer a parsed GraphQL request, while `head` is the latest block. Only
+    // nodes in `group` are asked, and answers are cached in `namespace`.
+    pub async fn serve(
+        &self,
+        request: &Value,
+        rpc_list: &Arc<RwLock<Vec<Rpc>>>,
+        head: u64,
+        rate_limits: &RateLimitSettings,
+        group: Option<&str>,
+        namespace: Option<&str>,
+    ) -> Response<Full<Bytes>> {
+        let key =
+            cache_key(request).map(|key| format!("{}\n{}", namespace.unwrap_or_default(), key));
+        if let Some(rax) = key.as_ref().and_then(|key| self.cached(key, head)) {
+            return response(200, rax);
+        }
+
+        // Fastest first
+        let mut nodes: Vec<Rpc> = rpc_list
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .iter()
+            .filter(|rpc| {
+                rpc.graphql_url.is_some()
+                    && group.map_or(true, |group| rpc.in_group(group))
+                    && !rpc.status.cooldown.is_cooling_down()
+                    && rpc.status.budget.has_budget()
+            })
+            .cloned()
+            .collect();
+        if nodes.is_empty() {
+            return error_response(503, "No node serves GraphQL");
+        }
+        nodes.sort_by(|a, b| a.status.latency.total_cmp(&b.status.latency));
+
+        let body = request.to_string().into_bytes();
+        for rpc in nodes {
+            let (status, rax) = match rpc.send_graphql(body.clone()).await {
+                Ok(answer) => answer,
+                Err(RpcError::RateLimited(wait)) => {
+                    rpc.status.cooldown.start(wait, rate_limits);
+                    continue;
+                }
+                Err(e) => {
+                    log_wrn!("GraphQL request to {} failed: {}", rpc.name, e);
+                    continue;
+                }
+            };
+
+            // Errors can depend on who's asking, e.g. through limits
+            let failed = status != 200
+                || serde_json::from_str::<Value>(&rax)
+                    .map_or(true, |rax| rax.get("errors").is_some());
+            if let (Some(key), false) = (key, failed) {
+                self.insert(key, head, rax.clone());
+            }
+            return response(status, rax);
+        }
+
+        error_response(502, "No node could answer")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::{
+        io::{
+            Read,
+            Write,
+        },
+        net::TcpListener,
+        sync::atomic::{
+            AtomicUsize,
+            Ordering,
+        },
+        thread,
+    };
+
+    // GraphQL node that answers every request with `status` and `body`
+    fn graphql_node(status: u16, body: &'static str) -> (String, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counter = requests.clone();
+
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut buf = [0u8; 4096];
+                let _ = stream.read(&mut buf);
+                counter.fetch_add(1, Ordering::Relaxed);
+
+                let _ = write!(
+                    stream,
+                    "HTTP/1.1 {} OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    status,
+                    body.len(),
+                    body
+                );
+            }
+        });
+
+        (format!("http://{}/graphql", address), requests)
+    }
+
+    fn node(graphql_url: Option<String>, latency: f64) -> Rpc {
+        let mut rpc = Rpc::new("http://127.0.0.1:1".to_string(), None, 6, 0, 10.0)
+            .with_graphql_url(graphql_url);
+        rpc.status.latency = latency;
+        rpc
+    }
+
+    #[test]
+    fn test_normalize_query() {
+        assert_eq!(
+            normalize_query("query Head {\n  block { # the head\n    number, hash }\n}"),
+            "query Head{block{number hash}}"
+        );
+        assert_eq!(
+            normalize_query(
+                "{ block(number: 16) { logs(filter: {topics: [\"a  b\"]}) { data } } }"
+            ),
+            "{block(number:16){logs(filter:{topics:[\"a  b\"]}){data}}}"
+        );
+        assert_eq!(
+            normalize_query("query($n: Long!) { block(number: $n) { hash } }"),
+            "query($n:Long!){block(number:$n){hash}}"
+        );
+    }
+
+    #[test]
+    fn test_cache_key() {
+        let key = |request: Value| cache_key(&request);
+        assert_eq!(
+            key(json!({"query": "{ block { number } }"})),
+            key(json!({"query": "{block{number}}", "variables": null}))
+        );
+        assert_ne!(
+            key(
+                json!({"query": "query($n: Long) { block(number: $n) { hash } }", "variables": {"n": 1}})
+            ),
+            key(
+                json!({"query": "query($n: Long) { block(number: $n) { hash } }", "variables": {"n": 2}})
+            )
+        );
+        assert_eq!(
+            key(json!({"query": "mutation { sendRawTransaction(data: \"0x00\") }"})),
+            None
+        );
+        assert_eq!(key(json!({"variables": {}})), None);
+    }
+
+    #[test]
+    fn test_method() {
+        assert_eq!(
+            method(&json!({"query": "{ block { number } }"})),
+            GRAPHQL_METHOD
+        );
+        assert_eq!(
+            method(&json!({"query": "mutation { sendRawTransaction(data: \"0x00\") }"})),
+            "eth_sendRawTransaction"
+        );
+        assert!(parse(b"not json").is_none());
+        assert!(parse(br#"{"variables": {}}"#).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_graphql_serve() {
+        let (up, up_requests) = graphql_node(200, r#"{"data":{"block":{"number":"0x10"}}}"#);
+        let (down, down_requests) = graphql_node(500, "oops");
+
+        // The broken node is faster, the node without GraphQL is never asked
+        let rpc_list = Arc::new(RwLock::new(vec![
+            node(None, 0.0),
+            node(Some(down), 1.0),
+            node(Some(up), 2.0),
+        ]));
+        let graphql = Graphql::new();
+        let limits = RateLimitSettings::default();
+        let request = json!({"query": "{ block { number } }"});
+
+        let response = graphql
+            .serve(&request, &rpc_list, 16, &limits, None, None)
+            .await;
+        assert_eq!(response.status(), 200);
+        assert_eq!(down_requests.load(Ordering::Relaxed), 1);
+        assert_eq!(up_requests.load(Ordering::Relaxed), 1);
+
+        // Same query, same head
+        let request = json!({"query": "{block {number}}"});
+        assert_eq!(
+            graphql
+                .serve(&request, &rpc_list, 16, &limits, None, None)
+                .await
+                .status(),
+            200
+        );
+        assert_eq!(up_requests.load(Ordering::Relaxed), 1);
+
+        // Other tenants don't share the answer
+        assert_eq!(
+            graphql
+                .serve(&request, &rpc_list, 16, &limits, None, Some("acme"))
+                .await
+                .status(),
+            200
+        );
+        assert_eq!(up_requests.load(Ordering::Relaxed), 2);
+
+        // New head
+        assert_eq!(
+            graphql
+                .serve(&request, &rpc_list, 17, &limits, None, None)
+                .await
+                .status(),
+            200
+        );
+        assert_eq!(up_requests.load(Ordering::Relaxed), 3);
+
+        // No GraphQL nodes in the group, or at all
+        assert_eq!(
+            graphql
+                .serve(&request, &rpc_list, 18, &limits, Some("archive"), None)
+                .await
+                .status(),
+            503
+        );
+        let no_graphql = Arc::new(RwLock::new(vec![node(None, 0.0)]));
+        assert_eq!(
+            graphql
+                .serve(&request, &no_graphql, 18, &limits, None, None)
+                .await
+                .status(),
+            503
+        );
+    }
+}
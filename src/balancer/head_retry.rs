@@ -0,0 +1,219 @@
+// Retries for blocks that didn't reach every node yet.
+//
+// A block that was just mined takes a moment to propagate, so a request for
+// it can land on a node that says it doesn't have it while others do. With
+// `[blutgang.head_retry]` set, requests for one of the last `depth` blocks,
+// or the next `depth`, that get a "not found" error, or a `null` block, are
+// sent to our other nodes fastest first. If none of them have it either we wait `delay` plus a
+// random share of `jitter` and ask all of them once more before answering
+// with the error. Requests for older blocks are answered as they are.
+use crate::{
+    balancer::{
+        error_map::is_not_found as is_not_found_error,
+        selection::select::argsort,
+    },
+    config::types::HeadRetrySettings,
+    log_info,
+    log_wrn,
+    Rpc,
+};
+
+use std::{
+    sync::{
+        Arc,
+        RwLock,
+    },
+    time::Duration,
+};
+
+use serde_json::Value;
+use tokio::time::{
+    sleep,
+    timeout,
+};
+
+// Methods that answer `null` for blocks the node doesn't have
+const BLOCK_LOOKUPS: [&str; 9] = [
+    "eth_getBlockByNumber",
+    "eth_getBlockByHash",
+    "eth_getBlockReceipts",
+    "eth_getBlockTransactionCountByNumber",
+    "eth_getBlockTransactionCountByHash",
+    "eth_getUncleCountByBlockNumber",
+    "eth_getUncleCountByBlockHash",
+    "eth_getHeaderByNumber",
+    "eth_getHeaderByHash",
+];
+
+// Whether `block` is one of the last `depth` blocks before `latest`, or up
+// to `depth` after it since other nodes can be ahead of us
+pub fn is_near_head(block: u64, latest: u64, depth: u64) -> bool {
+    latest != 0 && block.saturating_add(depth) > latest && block <= latest.saturating_add(depth)
+}
+
+// Whether `rax` is the node telling us it doesn't have the block `tx` is for
+pub fn is_not_found(tx: &Value, rax: &str) -> bool {
+    let response: Value = match serde_json::from_str(rax) {
+        Ok(response) => response,
+        Err(_) => return false,
+    };
+
+    match response.get("error") {
+        Some(error) => is_not_found_error(error),
+        None => {
+            response["result"].is_null()
+                && tx["method"]
+                    .as_str()
+                    .is_some_and(|method| BLOCK_LOOKUPS.contains(&method))
+        }
+    }
+}
+
+// Ask the other nodes for `tx` after the one at `answered` didn't have the
+// block, and all of them once more after a jittered delay if they didn't
+// either. Returns the answer and the position of the node it's from.
+pub async fn retry_not_found(
+    settings: &HeadRetrySettings,
+    tx: &Value,
+    answered: usize,
+    group: Option<&str>,
+    rpc_list: &Arc<RwLock<Vec<Rpc>>>,
+    headers: &[(String, String)],
+    ttl: u128,
+) -> Option<(String, usize)> {
+    let method = tx["method"].as_str().unwrap_or_default();
+    let request_timeout = Duration::from_millis(ttl.try_into().unwrap_or(u64::MAX));
+
+    for round in 0..2 {
+        if round > 0 {
+            let delay = settings.delay + settings.jitter.mul_f64(rand::random::<f64>());
+            log_info!(
+                "No node had the block for {} yet, asking again in {}ms",
+                method,
+                delay.as_millis()
+            );
+            sleep(delay).await;
+        }
+
+        // Fastest first, nodes can come and go while we wait
+        let nodes: Vec<(usize, Rpc)> = {
+            let rpc_list = rpc_list.read().unwrap_or_else(|e| e.into_inner());
+            argsort(&rpc_list)
+                .into_iter()
+                .filter(|&index| round > 0 || index != answered)
+                .filter(|&index| group.map_or(true, |group| rpc_list[index].in_group(group)))
+                .filter(|&index| rpc_list[index].is_available(tx))
+                .map(|index| (index, rpc_list[index].clone()))
+                .collect()
+        };
+
+        for (position, rpc) in nodes {
+            match timeout(
+                request_timeout,
+                rpc.send_request_with_headers(tx.clone(), headers),
+            )
+            .await
+            {
+                Ok(Ok(rax)) if !is_not_found(tx, &rax) => return Some((rax, position)),
+                _ => continue,
+            }
+        }
+    }
+
+    log_wrn!("No node had the block for {}", method);
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::node::MockNode;
+
+    use serde_json::json;
+
+    fn settings() -> HeadRetrySettings {
+        HeadRetrySettings {
+            depth: 3,
+            delay: Duration::from_millis(200),
+            jitter: Duration::from_millis(10),
+        }
+    }
+
+    fn block_tx() -> Value {
+        json!({"jsonrpc": "2.0", "id": 1, "method": "eth_getBlockByNumber", "params": ["0x10", false]})
+    }
+
+    #[test]
+    fn test_is_near_head() {
+        assert!(is_near_head(100, 100, 3));
+        assert!(is_near_head(98, 100, 3));
+        assert!(!is_near_head(97, 100, 3));
+        assert!(is_near_head(101, 100, 3));
+        assert!(is_near_head(103, 100, 3));
+        assert!(!is_near_head(104, 100, 3));
+        assert!(!is_near_head(0xffffffff, 100, 3));
+        assert!(!is_near_head(0, 0, 3));
+    }
+
+    #[test]
+    fn test_is_not_found() {
+        let tx = block_tx();
+        assert!(is_not_found(&tx, r#"{"id":1,"result":null}"#));
+        assert!(!is_not_found(&tx, r#"{"id":1,"result":{"number":"0x10"}}"#));
+        assert!(is_not_found(
+            &tx,
+            r#"{"id":1,"error":{"code":-32000,"message":"header not found"}}"#
+        ));
+
+        // Null is a fine answer for calls that aren't block lookups
+        let receipt = json!({"method": "eth_getTransactionReceipt", "params": ["0x01"]});
+        assert!(!is_not_found(&receipt, r#"{"id":1,"result":null}"#));
+        assert!(!is_not_found(&tx, "oops"));
+    }
+
+    #[tokio::test]
+    async fn test_retry_not_found() {
+        let lagging = MockNode::spawn(1).await.unwrap();
+        lagging.set_response("eth_getBlockByNumber", Value::Null);
+        let synced = Arc::new(MockNode::spawn(1).await.unwrap());
+        synced.set_response("eth_getBlockByNumber", Value::Null);
+
+        let mut lagging_rpc = Rpc::new(lagging.http_url(), None, 6, 0, 10.0);
+        let mut synced_rpc = Rpc::new(synced.http_url(), None, 6, 0, 10.0);
+        lagging_rpc.status.latency = 1.0;
+        synced_rpc.status.latency = 2.0;
+        let rpc_list = Arc::new(RwLock::new(vec![lagging_rpc, synced_rpc]));
+
+        // The block reaches the slower node while we wait
+        let catching_up = synced.clone();
+        tokio::spawn(async move {
+            sleep(Duration::from_millis(100)).await;
+            catching_up.set_response("eth_getBlockByNumber", json!({"number": "0x10"}));
+        });
+
+        let (rax, position) =
+            retry_not_found(&settings(), &block_tx(), 0, None, &rpc_list, &[], 1000)
+                .await
+                .unwrap();
+        assert_eq!(position, 1);
+        assert!(rax.contains("0x10"));
+        // Asked once by itself, and once more after the delay
+        assert_eq!(lagging.request_count(), 1);
+        assert_eq!(synced.request_count(), 2);
+
+        // Nodes outside the group aren't asked
+        assert!(retry_not_found(
+            &settings(),
+            &block_tx(),
+            1,
+            Some("archive"),
+            &rpc_list,
+            &[],
+            1000
+        )
+        .await
+        .is_none());
+        assert_eq!(lagging.request_count(), 1);
+        assert_eq!(synced.request_count(), 2);
+    }
+}
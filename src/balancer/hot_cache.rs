@@ -0,0 +1,330 @@
+// In-memory tier in front of the sled cache.
+//
+// Most cache hits are for a handful of keys: `eth_chainId`, the latest
+// block, the same popular `eth_call`s over and over. Each of those still goes
+// through sled and gets decompressed. We keep the decoded responses of the
+// most recently read keys in memory, up to `hot_cache_size` bytes, and serve
+// them from there instead.
+//
+// The tier is split into shards with their own lock, so concurrent readers
+// rarely wait on each other. It stays coherent with sled by watching it for
+// writes: any key that's overwritten or removed (reorgs, evictions, failed
+// verification) is dropped from the tier too.
+use crate::balancer::cache_entry::{
+    get_entry,
+    CacheKey,
+    EntryError,
+};
+
+use std::{
+    collections::{
+        BTreeMap,
+        HashMap,
+    },
+    sync::{
+        atomic::{
+            AtomicU64,
+            Ordering,
+        },
+        Arc,
+        Mutex,
+    },
+};
+
+use serde_json::{
+    json,
+    Value,
+};
+use sled::{
+    Db,
+    Event,
+};
+
+const SHARDS: usize = 16;
+
+// Responses bigger than this fraction of a shard would push out everything else
+const MAX_ENTRY_FRACTION: usize = 4;
+
+#[derive(Debug)]
+struct HotEntry {
+    key: CacheKey,
+    response: Arc<[u8]>,
+    last_access: u64,
+}
+
+#[derive(Debug, Default)]
+struct Shard {
+    entries: HashMap<Vec<u8>, HotEntry>,
+    // Last access -> digest, oldest first
+    order: BTreeMap<u64, Vec<u8>>,
+    bytes: usize,
+}
+
+impl Shard {
+    fn remove(&mut self, digest: &[u8]) {
+        if let Some(entry) = self.entries.remove(digest) {
+            self.order.remove(&entry.last_access);
+            self.bytes -= entry.response.len();
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct HotCache {
+    capacity: usize,
+    shards: Vec<Mutex<Shard>>,
+    // Logical clock for LRU
+    clock: AtomicU64,
+    // Bumped on every removal from sled, see `insert`
+    generation: AtomicU64,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl HotCache {
+    // `capacity` is in bytes, 0 disables the tier
+    pub fn new(capacity: usize) -> Self {
+        HotCache {
+            capacity,
+            shards: (0..SHARDS).map(|_| Mutex::new(Shard::default())).collect(),
+            ..Default::default()
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.capacity > 0
+    }
+
+    fn shard(&self, digest: &[u8]) -> &Mutex<Shard> {
+        &self.shards[digest.first().copied().unwrap_or_default() as usize % SHARDS]
+    }
+
+    fn tick(&self) -> u64 {
+        self.clock.fetch_add(1, Ordering::Relaxed)
+    }
+
+    pub fn get(&self, key: &CacheKey) -> Option<Arc<[u8]>> {
+        if !self.is_enabled() {
+            return None;
+        }
+
+        let tick = self.tick();
+        let mut shard = self.shard(key.as_bytes()).lock().unwrap();
+        let shard = &mut *shard;
+        match shard.entries.get_mut(key.as_bytes()) {
+            Some(entry) if entry.key == *key => {
+                shard.order.remove(&entry.last_access);
+                shard.order.insert(tick, key.as_bytes().to_vec());
+                entry.last_access = tick;
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                Some(entry.response.clone())
+            }
+            _ => {
+                self.misses.fetch_add(1, Ordering::Relaxed);
+                None
+            }
+        }
+    }
+
+    pub fn generation(&self) -> u64 {
+        self.generation.load(Ordering::Acquire)
+    }
+
+    // Keep `response` we read from sled at `generation`. If anything was
+    // removed from sled since, what we read might be gone already and we
+    // don't want to bring it back.
+    pub fn insert(&self, key: &CacheKey, response: &[u8], generation: u64) {
+        let shard_capacity = self.capacity / SHARDS;
+        if !self.is_enabled() || response.len() > shard_capacity / MAX_ENTRY_FRACTION {
+            return;
+        }
+
+        let tick = self.tick();
+        let mut shard = self.shard(key.as_bytes()).lock().unwrap();
+        if self.generation() != generation {
+            return;
+        }
+
+        shard.remove(key.as_bytes());
+        while shard.bytes + response.len() > shard_capacity {
+            let oldest = match shard.order.first_key_value() {
+                Some((_, digest)) => digest.clone(),
+                None => break,
+            };
+            shard.remove(&oldest);
+        }
+
+        shard.order.insert(tick, key.as_bytes().to_vec());
+        shard.bytes += response.len();
+        shard.entries.insert(
+            key.as_bytes().to_vec(),
+            HotEntry {
+                key: *key,
+                response: response.into(),
+                last_access: tick,
+            },
+        );
+    }
+
+    // Drop `digest`, because sled overwrote or `removed` it
+    pub fn invalidate(&self, digest: &[u8], removed: bool) {
+        if !self.is_enabled() {
+            return;
+        }
+
+        let mut shard = self.shard(digest).lock().unwrap();
+        if removed {
+            self.generation.fetch_add(1, Ordering::Release);
+        }
+        shard.remove(digest);
+    }
+
+    fn usage(&self) -> (usize, usize) {
+        self.shards.iter().fold((0, 0), |(entries, bytes), shard| {
+            let shard = shard.lock().unwrap();
+            (entries + shard.entries.len(), bytes + shard.bytes)
+        })
+    }
+
+    // Bytes of responses we hold
+    pub fn used_bytes(&self) -> usize {
+        self.usage().1
+    }
+
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    pub fn misses(&self) -> u64 {
+        self.misses.load(Ordering::Relaxed)
+    }
+
+    pub fn stats(&self) -> Value {
+        let (entries, bytes) = self.usage();
+        json!({
+            "capacity": self.capacity,
+            "usedBytes": bytes,
+            "entries": entries,
+            "hits": self.hits(),
+            "misses": self.misses(),
+        })
+    }
+}
+
+// Cached response for `key`, from the hot tier if we can
+pub fn get_tiered(
+    cache: &Db,
+    hot: &HotCache,
+    key: &CacheKey,
+) -> Result<Option<Vec<u8>>, EntryError> {
+    if let Some(response) = hot.get(key) {
+        return Ok(Some(response.to_vec()));
+    }
+
+    let generation = hot.generation();
+    let response = get_entry(cache, key)?;
+    if let Some(response) = &response {
+        hot.insert(key, response, generation);
+    }
+
+    Ok(response)
+}
+
+// Drop everything sled overwrites or removes from the hot tier.
+//
+// Runs on its own thread since sled blocks writers if we fall behind.
+pub fn watch_cache(hot: Arc<HotCache>, cache: &Db) {
+    if !hot.is_enabled() {
+        return;
+    }
+
+    let subscriber = cache.watch_prefix(vec![]);
+    std::thread::spawn(move || {
+        for event in subscriber {
+            match event {
+                Event::Insert { key, .. } => hot.invalidate(&key, false),
+                Event::Remove { key } => hot.invalidate(&key, true),
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::balancer::cache_entry::insert_entry;
+    use std::time::Duration;
+
+    #[test]
+    fn test_hot_cache_lru() {
+        // 64 bytes per shard
+        let hot = HotCache::new(64 * SHARDS);
+        let keys: Vec<CacheKey> = (0..1000)
+            .map(|i: u32| CacheKey::from_request(&i.to_le_bytes()))
+            .filter(|key| key.as_bytes()[0] as usize % SHARDS == 0)
+            .take(5)
+            .collect();
+
+        for key in &keys[..4] {
+            hot.insert(key, &[0; 16], hot.generation());
+        }
+        // Make the first one the most recently used
+        assert!(hot.get(&keys[0]).is_some());
+        hot.insert(&keys[4], &[0; 16], hot.generation());
+
+        assert!(hot.get(&keys[0]).is_some());
+        assert!(hot.get(&keys[1]).is_none());
+        assert!(hot.get(&keys[4]).is_some());
+        assert_eq!(hot.stats()["usedBytes"], 64);
+        assert_eq!(hot.hits(), 3);
+        assert_eq!(hot.misses(), 1);
+
+        // Too big for the tier
+        hot.insert(&keys[1], &[0; 17], hot.generation());
+        assert!(hot.get(&keys[1]).is_none());
+    }
+
+    #[test]
+    fn test_hot_cache_generation() {
+        let hot = HotCache::new(1 << 20);
+        let key = CacheKey::from_request(b"eth_chainId");
+
+        // Something got removed between reading sled and inserting
+        let generation = hot.generation();
+        hot.invalidate(b"other", true);
+        hot.insert(&key, b"0x1", generation);
+        assert!(hot.get(&key).is_none());
+
+        hot.insert(&key, b"0x1", hot.generation());
+        assert_eq!(&*hot.get(&key).unwrap(), b"0x1");
+        hot.invalidate(key.as_bytes(), false);
+        assert!(hot.get(&key).is_none());
+
+        let disabled = HotCache::new(0);
+        disabled.insert(&key, b"0x1", disabled.generation());
+        assert!(disabled.get(&key).is_none());
+    }
+
+    #[test]
+    fn test_watch_cache() {
+        let cache = sled::Config::new().temporary(true).open().unwrap();
+        let key = CacheKey::from_request(b"eth_chainId");
+        insert_entry(&cache, &key, b"{\"result\":\"0x1\"}").unwrap();
+
+        let hot = Arc::new(HotCache::new(1 << 20));
+        watch_cache(hot.clone(), &cache);
+        assert!(get_tiered(&cache, &hot, &key).unwrap().is_some());
+        assert!(hot.get(&key).is_some());
+
+        // Reorgs remove entries from sled, and the tier follows
+        cache.remove(key.as_bytes()).unwrap();
+        for _ in 0..100 {
+            if hot.get(&key).is_none() {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert!(hot.get(&key).is_none());
+        assert!(get_tiered(&cache, &hot, &key).unwrap().is_none());
+    }
+}
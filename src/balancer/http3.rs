@@ -0,0 +1,605 @@
+// HTTP/3 listener for downstream clients.
+//
+// QUIC copes better with lossy mobile networks than TCP. Requests coming in
+// over HTTP/3 are turned into regular requests and go through the same
+// `accept_request` as HTTP/1.1 ones, so caching, routing and everything
+// else behaves the same.
+//
+// WebSockets aren't available over HTTP/3, clients keep using the regular
+// listener for subscriptions. Client certificates are checked against the
+// identities in `[blutgang.tls]` like on the regular listener.
+use crate::{
+    balancer::{
+        accept_http::{
+            accept_request,
+            ConnectionParams,
+        },
+        mtls::{
+            identify,
+            AuthPolicy,
+            ClientIdentity,
+            MtlsError,
+        },
+        request_body::{
+            read_capped,
+            DEFAULT_MAX_SIZE,
+        },
+    },
+    config::types::Http3Settings,
+    log_err,
+    log_info,
+    log_wrn,
+    websocket::sse::SseEvents,
+};
+
+use std::{
+    collections::BTreeMap,
+    fmt,
+    fs::File,
+    io::{
+        self,
+        BufReader,
+    },
+    sync::Arc,
+};
+
+use futures::stream;
+use h3::{
+    error::{
+        ConnectionError,
+        StreamError,
+    },
+    server::RequestStream,
+};
+use http_body_util::{
+    BodyExt,
+    Full,
+    StreamBody,
+};
+use hyper::{
+    body::{
+        Buf,
+        Bytes,
+        Frame,
+    },
+    Request,
+    Response,
+};
+use openssl::x509::X509;
+use quinn::crypto::rustls::QuicServerConfig;
+use rustls::{
+    pki_types::CertificateDer,
+    server::WebPkiClientVerifier,
+    RootCertStore,
+};
+use serde_json::Value;
+
+// Errors
+#[derive(Debug)]
+pub enum Http3Error {
+    Io(String, io::Error),
+    InvalidCertificate(String),
+    Connection(ConnectionError),
+    Stream(StreamError),
+}
+
+impl fmt::Display for Http3Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Http3Error::Io(what, e) => write!(f, "HTTP/3 error on {}: {}", what, e),
+            Http3Error::InvalidCertificate(reason) => {
+                write!(f, "Invalid HTTP/3 certificate or key: {}", reason)
+            }
+            Http3Error::Connection(e) => write!(f, "HTTP/3 connection error: {}", e),
+            Http3Error::Stream(e) => write!(f, "HTTP/3 stream error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for Http3Error {}
+
+impl From<ConnectionError> for Http3Error {
+    fn from(e: ConnectionError) -> Self {
+        Http3Error::Connection(e)
+    }
+}
+
+impl From<StreamError> for Http3Error {
+    fn from(e: StreamError) -> Self {
+        Http3Error::Stream(e)
+    }
+}
+
+fn open(path: &str) -> Result<BufReader<File>, Http3Error> {
+    File::open(path)
+        .map(BufReader::new)
+        .map_err(|e| Http3Error::Io(path.to_string(), e))
+}
+
+// QUIC needs TLS 1.3 and the `h3` ALPN
+fn server_config(settings: &Http3Settings) -> Result<quinn::ServerConfig, Http3Error> {
+    let certs = rustls_pemfile::certs(&mut open(&settings.cert)?)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| Http3Error::Io(settings.cert.clone(), e))?;
+    let key = rustls_pemfile::private_key(&mut open(&settings.key)?)
+        .map_err(|e| Http3Error::Io(settings.key.clone(), e))?
+        .ok_or_else(|| Http3Error::InvalidCertificate(format!("no key in {}", settings.key)))?;
+
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let builder = rustls::ServerConfig::builder_with_provider(provider.clone())
+        .with_protocol_versions(&[&rustls::version::TLS13])
+        .map_err(|e| Http3Error::InvalidCertificate(e.to_string()))?;
+    let builder = match &settings.client_ca {
+        Some(client_ca) => {
+            let mut roots = RootCertStore::empty();
+            for cert in rustls_pemfile::certs(&mut open(client_ca)?) {
+                roots
+                    .add(cert.map_err(|e| Http3Error::Io(client_ca.clone(), e))?)
+                    .map_err(|e| Http3Error::InvalidCertificate(e.to_string()))?;
+            }
+
+            let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider);
+            let verifier = match settings.require_client_cert {
+                true => verifier,
+                false => verifier.allow_unauthenticated(),
+            };
+            builder.with_client_cert_verifier(
+                verifier
+                    .build()
+                    .map_err(|e| Http3Error::InvalidCertificate(e.to_string()))?,
+            )
+        }
+        None => builder.with_no_client_auth(),
+    };
+    let mut tls = builder
+        .with_single_cert(certs, key)
+        .map_err(|e| Http3Error::InvalidCertificate(e.to_string()))?;
+    tls.alpn_protocols = vec![b"h3".to_vec()];
+
+    let crypto = QuicServerConfig::try_from(tls)
+        .map_err(|e| Http3Error::InvalidCertificate(e.to_string()))?;
+    Ok(quinn::ServerConfig::with_crypto(Arc::new(crypto)))
+}
+
+// Read the body of a request and answer it like any other
+async fn serve_request<S>(
+    request: Request<()>,
+    mut stream: RequestStream<S, Bytes>,
+    connection_params: ConnectionParams,
+) -> Result<(), Http3Error>
+where
+    S: h3::quic::BidiStream<Bytes>,
+{
+    let max_size = connection_params
+        .config
+        .read()
+        .unwrap()
+        .request_body
+        .map_or(DEFAULT_MAX_SIZE, |request_body| request_body.max_size);
+    let chunks = stream::unfold(&mut stream, |stream| {
+        async move {
+            match stream.recv_data().await {
+                Ok(Some(mut chunk)) => {
+                    let chunk = chunk.copy_to_bytes(chunk.remaining());
+                    Some((Ok(Frame::data(chunk)), stream))
+                }
+                Ok(None) => None,
+                Err(e) => Some((Err(e), stream)),
+            }
+        }
+    });
+    let body = read_capped(StreamBody::new(chunks), max_size).await;
+
+    let response = match body {
+        Ok(body) => {
+            let (parts, _) = request.into_parts();
+            let request = Request::from_parts(parts, Full::new(body));
+            match accept_request(request, connection_params).await {
+                Ok(response) => response,
+                Err(infallible) => match infallible {},
+            }
+        }
+        Err(e) => e.into_response(Value::Null),
+    };
+
+    let (mut parts, body) = response.into_parts();
+    let events = parts
+        .extensions
+        .remove::<SseEvents>()
+        .and_then(|events| events.take());
+    stream
+        .send_response(Response::from_parts(parts, ()))
+        .await?;
+
+    // SSE events are sent as they come, until the client goes away
+    if let Some(mut events) = events {
+        while let Some(Ok(frame)) = events.recv().await {
+            if let Ok(data) = frame.into_data() {
+                stream.send_data(data).await?;
+            }
+        }
+        stream.finish().await?;
+        return Ok(());
+    }
+
+    let body = match body.collect().await {
+        Ok(body) => body.to_bytes(),
+        Err(infallible) => match infallible {},
+    };
+    stream.send_data(body).await?;
+    stream.finish().await?;
+
+    Ok(())
+}
+
+async fn serve_connection(
+    connection: quinn::Connection,
+    connection_params: ConnectionParams,
+) -> Result<(), Http3Error> {
+    let mut connection = h3::server::Connection::new(h3_quinn::Connection::new(connection)).await?;
+
+    loop {
+        match connection.accept().await {
+            Ok(Some(resolver)) => {
+                let connection_params = connection_params.clone();
+                tokio::task::spawn(async move {
+                    let result = match resolver.resolve_request().await {
+                        Ok((request, stream)) => {
+                            serve_request(request, stream, connection_params).await
+                        }
+                        Err(e) => Err(e.into()),
+                    };
+                    if let Err(e) = result {
+                        log_err!("Error serving HTTP/3 request: {}", e);
+                    }
+                });
+            }
+            Ok(None) => return Ok(()),
+            Err(e) if e.is_h3_no_error() => return Ok(()),
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+// Who is on the other end of `connection`, from the certificate it sent if any
+fn client_identity(
+    connection: &quinn::Connection,
+    identities: &BTreeMap<String, AuthPolicy>,
+) -> Result<Option<ClientIdentity>, MtlsError> {
+    let cert = connection
+        .peer_identity()
+        .and_then(|certs| certs.downcast::<Vec<CertificateDer<'static>>>().ok())
+        .and_then(|certs| certs.first().map(|cert| X509::from_der(cert)))
+        .transpose()?;
+
+    identify(identities, cert.as_deref())
+}
+
+// Accept QUIC connections on `settings.address` until the endpoint closes
+pub async fn listen_http3(
+    settings: Http3Settings,
+    connection_params: ConnectionParams,
+) -> Result<(), Http3Error> {
+    let endpoint = quinn::Endpoint::server(server_config(&settings)?, settings.address)
+        .map_err(|e| Http3Error::Io(settings.address.to_string(), e))?;
+    log_info!("HTTP/3 bound to: {}", settings.address);
+    let identities = Arc::new(settings.identities);
+
+    while let Some(incoming) = endpoint.accept().await {
+        let mut connection_params = connection_params.clone();
+        let identities = identities.clone();
+        tokio::task::spawn(async move {
+            let connection = match incoming.await {
+                Ok(connection) => connection,
+                Err(e) => {
+                    log_err!("HTTP/3 handshake failed: {}", e);
+                    return;
+                }
+            };
+            log_info!("HTTP/3 connection from: {}", connection.remote_address());
+
+            connection_params.identity = match client_identity(&connection, &identities) {
+                Ok(identity) => identity,
+                Err(e) => {
+                    log_wrn!(
+                        "Rejected HTTP/3 connection from {}: {}",
+                        connection.remote_address(),
+                        e
+                    );
+                    connection.close(0u32.into(), b"forbidden");
+                    return;
+                }
+            };
+            if let Some(identity) = &connection_params.identity {
+                log_info!(
+                    "{} authenticated as {}",
+                    connection.remote_address(),
+                    identity.name
+                );
+            }
+
+            if let Err(e) = serve_connection(connection, connection_params).await {
+                log_err!("Error serving HTTP/3 connection: {}", e);
+            }
+        });
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        balancer::{
+            accept_http::RequestChannels,
+            block_hashes::BlockHashes,
+            consistent_reads::ConsistentReads,
+            cors::Cors,
+            ens::EnsCache,
+            estimate_gas::GasEstimator,
+            graphql::Graphql,
+            hot_cache::HotCache,
+            memory::MemoryBudget,
+            peer_count::PeerCounts,
+            revalidate::Revalidator,
+            selection::schedules::Scheduler,
+            tenants::Tenants,
+        },
+        beacon::types::BeaconPool,
+        cluster::{
+            fallback::Fallback,
+            peers::PeerCache,
+        },
+        config::types::{
+            RequestBodySettings,
+            Settings,
+        },
+        health::{
+            anomaly::AnomalyDetector,
+            horizon::StateHorizons,
+            safe_block::NamedBlocknumbers,
+        },
+        middleware::types::MiddlewareStack,
+        mock::node::MockNode,
+        notify::{
+            firehose::Firehose,
+            usage::UsageTracker,
+            webhook::Notifier,
+        },
+        store::{
+            logs::LogIndex,
+            receipts::ReceiptStore,
+        },
+        transactions::{
+            replay::ReplayGuard,
+            tracker::TxTracker,
+        },
+        verify::state::TrackedHeaders,
+        websocket::{
+            long_poll::LongPoll,
+            sessions::Sessions,
+            types::SubscriptionData,
+        },
+        Rpc,
+    };
+
+    use std::{
+        collections::BTreeMap,
+        sync::RwLock,
+    };
+
+    use openssl::{
+        asn1::Asn1Time,
+        ec::{
+            EcGroup,
+            EcKey,
+        },
+        hash::MessageDigest,
+        nid::Nid,
+        pkey::PKey,
+        x509::{
+            extension::SubjectAlternativeName,
+            X509NameBuilder,
+            X509,
+        },
+    };
+    use quinn::crypto::rustls::QuicClientConfig;
+    use serde_json::{
+        json,
+        Value,
+    };
+    use tokio::sync::{
+        broadcast,
+        mpsc,
+        watch,
+    };
+
+    // Self-signed certificate and key for `localhost`, as PEM
+    fn localhost_certificate() -> (Vec<u8>, Vec<u8>) {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        let key = PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap();
+
+        let mut name = X509NameBuilder::new().unwrap();
+        name.append_entry_by_nid(Nid::COMMONNAME, "localhost")
+            .unwrap();
+        let name = name.build();
+
+        let mut builder = X509::builder().unwrap();
+        builder.set_version(2).unwrap();
+        builder.set_subject_name(&name).unwrap();
+        builder.set_issuer_name(&name).unwrap();
+        builder.set_pubkey(&key).unwrap();
+        builder
+            .set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        builder
+            .set_not_after(&Asn1Time::days_from_now(1).unwrap())
+            .unwrap();
+        let san = SubjectAlternativeName::new()
+            .dns("localhost")
+            .build(&builder.x509v3_context(None, None))
+            .unwrap();
+        builder.append_extension(san).unwrap();
+        builder.sign(&key, MessageDigest::sha256()).unwrap();
+
+        (
+            builder.build().to_pem().unwrap(),
+            key.private_key_to_pem_pkcs8().unwrap(),
+        )
+    }
+
+    fn connection_params(rpc: Rpc) -> ConnectionParams {
+        let (_, finalized_rx) = watch::channel(0);
+        let (incoming_tx, _) = mpsc::unbounded_channel();
+        let (_, outgoing_rx) = broadcast::channel(1);
+
+        ConnectionParams::new(
+            &Arc::new(RwLock::new(vec![rpc])),
+            RequestChannels::new(Arc::new(finalized_rx), incoming_tx, outgoing_rx),
+            &Arc::new(RwLock::new(NamedBlocknumbers::default())),
+            &Arc::new(RwLock::new(BTreeMap::new())),
+            &Arc::new(SubscriptionData::new()),
+            &Arc::new(Sessions::default()),
+            &Arc::new(sled::Config::new().temporary(true).open().unwrap()),
+            &Arc::new(RwLock::new(Settings::default())),
+            &None,
+            &Arc::new(MiddlewareStack::default()),
+            &Notifier::disabled(),
+            &Arc::new(MemoryBudget::default()),
+            &Arc::new(EnsCache::default()),
+            &Arc::new(HotCache::default()),
+            &Arc::new(GasEstimator::default()),
+            &Arc::new(TxTracker::default()),
+            &Arc::new(ConsistentReads::default()),
+            &Arc::new(BlockHashes::default()),
+            &Arc::new(StateHorizons::default()),
+            &Firehose::disabled(),
+            &Arc::new(AnomalyDetector::default()),
+            &Arc::new(UsageTracker::default()),
+            &Arc::new(ReplayGuard::default()),
+            &Arc::new(Revalidator::default()),
+            &Arc::new(Cors::default()),
+            &Arc::new(Tenants::default()),
+            &Arc::new(Scheduler::default()),
+            &Arc::new(PeerCache::default()),
+            &Arc::new(Fallback::default()),
+            &Arc::new(PeerCounts::default()),
+            &Arc::new(Graphql::default()),
+            &Arc::new(BeaconPool::default()),
+            &Arc::new(LongPoll::default()),
+            &Arc::new(LogIndex::default()),
+            &Arc::new(ReceiptStore::default()),
+            &Arc::new(TrackedHeaders::default()),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_http3_request() {
+        let node = MockNode::spawn(1).await.unwrap();
+        node.set_response("eth_chainId", json!("0x1"));
+
+        let (cert, key) = localhost_certificate();
+        let path = |name: &str| {
+            std::env::temp_dir()
+                .join(format!("blutgang-http3-{}-{}", std::process::id(), name))
+                .to_string_lossy()
+                .to_string()
+        };
+        let settings = Http3Settings {
+            address: "127.0.0.1:0".parse().unwrap(),
+            cert: path("cert.pem"),
+            key: path("key.pem"),
+            client_ca: None,
+            require_client_cert: false,
+            identities: BTreeMap::new(),
+        };
+        std::fs::write(&settings.cert, &cert).unwrap();
+        std::fs::write(&settings.key, &key).unwrap();
+
+        let endpoint =
+            quinn::Endpoint::server(server_config(&settings).unwrap(), settings.address).unwrap();
+        let _ = std::fs::remove_file(&settings.cert);
+        let _ = std::fs::remove_file(&settings.key);
+        let address = endpoint.local_addr().unwrap();
+
+        let params = connection_params(Rpc::new(node.http_url(), None, 5, 0, 1.0));
+        let config = params.config.clone();
+        tokio::task::spawn(async move {
+            let connection = endpoint.accept().await.unwrap().await.unwrap();
+            let _ = serve_connection(connection, params).await;
+        });
+
+        // Client trusting our certificate
+        let mut roots = rustls::RootCertStore::empty();
+        for cert in rustls_pemfile::certs(&mut cert.as_slice()) {
+            roots.add(cert.unwrap()).unwrap();
+        }
+        let mut tls = rustls::ClientConfig::builder_with_provider(Arc::new(
+            rustls::crypto::ring::default_provider(),
+        ))
+        .with_protocol_versions(&[&rustls::version::TLS13])
+        .unwrap()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+        tls.alpn_protocols = vec![b"h3".to_vec()];
+
+        let mut client = quinn::Endpoint::client("127.0.0.1:0".parse().unwrap()).unwrap();
+        client.set_default_client_config(quinn::ClientConfig::new(Arc::new(
+            QuicClientConfig::try_from(tls).unwrap(),
+        )));
+        let connection = client.connect(address, "localhost").unwrap().await.unwrap();
+        let (mut driver, mut send_request) = h3::client::new(h3_quinn::Connection::new(connection))
+            .await
+            .unwrap();
+        tokio::task::spawn(async move {
+            let _ = futures::future::poll_fn(|cx| driver.poll_close(cx)).await;
+        });
+
+        let request = Request::post("https://localhost/")
+            .header("content-type", "application/json")
+            .body(())
+            .unwrap();
+        let mut stream = send_request.send_request(request).await.unwrap();
+        stream
+            .send_data(Bytes::from(
+                json!({"jsonrpc": "2.0", "id": 7, "method": "eth_chainId", "params": []})
+                    .to_string(),
+            ))
+            .await
+            .unwrap();
+        stream.finish().await.unwrap();
+
+        let response = stream.recv_response().await.unwrap();
+        assert_eq!(response.status(), 200);
+        let mut body = Vec::new();
+        while let Some(chunk) = stream.recv_data().await.unwrap() {
+            body.extend_from_slice(chunk.chunk());
+        }
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["id"], 7);
+        assert_eq!(body["result"], "0x1");
+        assert_eq!(node.request_count(), 1);
+
+        // Bodies over the cap aren't read past it
+        config.write().unwrap().request_body = Some(RequestBodySettings {
+            max_size: 16,
+            stream_above: None,
+        });
+        let request = Request::post("https://localhost/")
+            .header("content-type", "application/json")
+            .body(())
+            .unwrap();
+        let mut stream = send_request.send_request(request).await.unwrap();
+        stream
+            .send_data(Bytes::from(
+                json!({"jsonrpc": "2.0", "id": 8, "method": "eth_chainId", "params": []})
+                    .to_string(),
+            ))
+            .await
+            .unwrap();
+        stream.finish().await.unwrap();
+        assert_eq!(stream.recv_response().await.unwrap().status(), 413);
+        assert_eq!(node.request_count(), 1);
+    }
+}
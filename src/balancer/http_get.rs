@@ -0,0 +1,197 @@
+// JSON-RPC over `GET`, for curl and health dashboards.
+//
+// With `http_get` enabled, `GET /?method=eth_blockNumber&params=[]` is
+// answered as if the call was POSTed, and a few paths like `/blocknumber` are
+// shorthand for the calls dashboards make the most. Both go through the same
+// cache and routing as any other request. `params` is the URL encoded JSON
+// array of params, and can be left out for methods without any.
+use std::fmt;
+
+use http_body_util::Full;
+use hyper::{
+    body::Bytes,
+    Response,
+};
+use serde_json::{
+    json,
+    Value,
+};
+
+// Errors
+#[derive(Debug, PartialEq)]
+pub enum GetError {
+    InvalidParams(String),
+    NotAllowed(String),
+}
+
+impl fmt::Display for GetError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            GetError::InvalidParams(params) => {
+                write!(f, "params must be a JSON array, got {}", params)
+            }
+            GetError::NotAllowed(method) => write!(f, "{} can't be called with GET", method),
+        }
+    }
+}
+
+impl std::error::Error for GetError {}
+
+impl GetError {
+    pub fn code(&self) -> i64 {
+        match self {
+            GetError::InvalidParams(_) => -32602,
+            GetError::NotAllowed(_) => -32601,
+        }
+    }
+
+    pub fn into_response(self) -> Response<Full<Bytes>> {
+        let body = json!({
+            "jsonrpc": "2.0",
+            "id": null,
+            "error": {"code": self.code(), "message": self.to_string()},
+        });
+
+        Response::builder()
+            .status(400)
+            .header("Content-Type", "application/json")
+            .body(Full::new(Bytes::from(body.to_string())))
+            .unwrap()
+    }
+}
+
+// Block tags and numbers in `/block/<block>`, numbers can be decimal
+fn block_param(block: &str) -> Value {
+    match block.parse::<u64>() {
+        Ok(number) => json!(format!("0x{:x}", number)),
+        Err(_) => json!(block),
+    }
+}
+
+// Method and params a shorthand path stands for
+fn path_call(path: &str) -> Option<(String, Value)> {
+    let path = path.trim_end_matches('/');
+    let call = match path {
+        "/blocknumber" => ("eth_blockNumber", json!([])),
+        "/chainid" => ("eth_chainId", json!([])),
+        "/gasprice" => ("eth_gasPrice", json!([])),
+        "/syncing" => ("eth_syncing", json!([])),
+        "/peercount" => ("net_peerCount", json!([])),
+        _ => {
+            let block = path
+                .strip_prefix("/block/")
+                .filter(|block| !block.is_empty())?;
+            ("eth_getBlockByNumber", json!([block_param(block), false]))
+        }
+    };
+
+    Some((call.0.to_string(), call.1))
+}
+
+// Method and params from `?method=...&params=...`
+fn query_call(query: &str) -> Option<Result<(String, Value), GetError>> {
+    let mut method = None;
+    let mut params = None;
+    for (key, value) in url::form_urlencoded::parse(query.as_bytes()) {
+        match key.as_ref() {
+            "method" => method = Some(value.into_owned()),
+            "params" => params = Some(value.into_owned()),
+            _ => {}
+        }
+    }
+
+    let method = method.filter(|method| !method.is_empty())?;
+    let params = match params {
+        Some(params) => {
+            match serde_json::from_str::<Value>(&params) {
+                Ok(params) if params.is_array() => params,
+                _ => return Some(Err(GetError::InvalidParams(params))),
+            }
+        }
+        None => json!([]),
+    };
+
+    Some(Ok((method, params)))
+}
+
+// JSON-RPC request a `GET` to `path` with `query` stands for. `None` if it
+// isn't one we answer.
+pub fn get_request(path: &str, query: Option<&str>) -> Option<Result<Value, GetError>> {
+    let call = match query.and_then(query_call) {
+        Some(call) => call,
+        None => Ok(path_call(path)?),
+    };
+
+    // Anything between the client and us can repeat a GET, so they can't
+    // change anything
+    Some(call.and_then(|(method, params)| {
+        if method.starts_with("eth_send") || method.starts_with("eth_sign") {
+            return Err(GetError::NotAllowed(method));
+        }
+        Ok(json!({"jsonrpc": "2.0", "id": 1, "method": method, "params": params}))
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_path_calls() {
+        assert_eq!(
+            get_request("/blocknumber", None),
+            Some(Ok(
+                json!({"jsonrpc": "2.0", "id": 1, "method": "eth_blockNumber", "params": []})
+            ))
+        );
+        assert_eq!(
+            get_request("/chainid/", None).unwrap().unwrap()["method"],
+            "eth_chainId"
+        );
+        assert_eq!(
+            get_request("/block/16", None).unwrap().unwrap()["params"],
+            json!(["0x10", false])
+        );
+        assert_eq!(
+            get_request("/block/finalized", None).unwrap().unwrap()["params"],
+            json!(["finalized", false])
+        );
+
+        assert_eq!(get_request("/", None), None);
+        assert_eq!(get_request("/block/", None), None);
+        assert_eq!(get_request("/group/archive", None), None);
+    }
+
+    #[test]
+    fn test_query_calls() {
+        let request = get_request(
+            "/",
+            Some("method=eth_getBalance&params=%5B%220x0%22%2C%22latest%22%5D"),
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(request["method"], "eth_getBalance");
+        assert_eq!(request["params"], json!(["0x0", "latest"]));
+
+        // Queries win over the path, and params can be left out
+        let request = get_request("/blocknumber", Some("method=eth_chainId"))
+            .unwrap()
+            .unwrap();
+        assert_eq!(request["method"], "eth_chainId");
+        assert_eq!(request["params"], json!([]));
+
+        // Queries without a method are just queries
+        assert_eq!(get_request("/", Some("foo=bar")), None);
+
+        assert_eq!(
+            get_request("/", Some("method=eth_getBalance&params=0x0")),
+            Some(Err(GetError::InvalidParams("0x0".to_string())))
+        );
+        assert_eq!(
+            get_request("/", Some("method=eth_sendRawTransaction&params=[\"0x00\"]")),
+            Some(Err(GetError::NotAllowed(
+                "eth_sendRawTransaction".to_string()
+            )))
+        );
+    }
+}
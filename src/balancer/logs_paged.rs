@@ -0,0 +1,491 @@
+// `blutgang_getLogsPaged(filter, options)`
+//
+// `eth_getLogs` over a huge range can return more logs than a client can
+// hold. This returns them a page at a time instead:
+//
+// {"jsonrpc": "2.0", "id": 1, "method": "blutgang_getLogsPaged",
+//   "params": [{"fromBlock": "0x0", "toBlock": "0x1000000", "address": "0x..."},
+//              {"pageSize": 500}]}
+//
+// answers with `{"logs": [...], "cursor": "0x2a1:3"}`. Passing `cursor` back
+// in the options, with the same filter, returns the next page, until the
+// cursor is `null`. The range is split into regular `eth_getLogs` requests
+// the same way streamed calls are, and those go through the cache. Pages
+// stop early, with a cursor, after `MAX_CALLS_PER_PAGE` requests, so sparse
+// filters over long ranges don't hold the client up for minutes. Tenants'
+// logs only come from their group, and go into their namespace.
+use crate::{
+    balancer::{
+        block_range::parse_number,
+        cache_entry::CacheKey,
+        hot_cache::get_tiered,
+        processing::{
+            cache_querry,
+            CacheArgs,
+        },
+        selection::select::pick_available,
+    },
+    rpc::types::Rpc,
+    websocket::stream::StreamOptions,
+};
+
+use std::{
+    fmt,
+    sync::{
+        Arc,
+        RwLock,
+    },
+    time::Duration,
+};
+
+use serde_json::{
+    json,
+    Value,
+};
+use tokio::time::timeout;
+
+pub const LOGS_PAGED: &str = "blutgang_getLogsPaged";
+
+pub const DEFAULT_PAGE_SIZE: usize = 1000;
+pub const MAX_PAGE_SIZE: usize = 10_000;
+
+// Upstream requests we make for a single page at most
+const MAX_CALLS_PER_PAGE: usize = 100;
+
+// Errors
+#[derive(Debug, PartialEq, Eq)]
+pub enum LogsPageError {
+    InvalidParams(String),
+    NoRpcAvailable,
+    TimedOut(u64),
+    InvalidResponse(String),
+}
+
+impl fmt::Display for LogsPageError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LogsPageError::InvalidParams(msg) => write!(f, "Invalid params: {}", msg),
+            LogsPageError::NoRpcAvailable => write!(f, "No RPC available"),
+            LogsPageError::TimedOut(from) => {
+                write!(f, "Timed out fetching logs from block {}", from)
+            }
+            LogsPageError::InvalidResponse(msg) => write!(f, "Invalid response: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for LogsPageError {}
+
+impl LogsPageError {
+    // JSON-RPC error code we return to the client
+    pub fn code(&self) -> i64 {
+        match self {
+            LogsPageError::InvalidParams(_) => -32602,
+            _ => -32603,
+        }
+    }
+}
+
+// Where the next page starts: the first block we haven't finished, and how
+// many of its logs were on earlier pages
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Cursor {
+    block: u64,
+    skip: usize,
+}
+
+impl Cursor {
+    fn parse(cursor: &str) -> Option<Self> {
+        let (block, skip) = cursor.split_once(':')?;
+        Some(Cursor {
+            block: u64::from_str_radix(block.strip_prefix("0x")?, 16).ok()?,
+            skip: skip.parse().ok()?,
+        })
+    }
+
+    fn encode(&self) -> String {
+        format!("0x{:x}:{}", self.block, self.skip)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LogsPage {
+    filter: Value,
+    to: u64,
+    page_size: usize,
+    cursor: Cursor,
+}
+
+impl LogsPage {
+    // Parse `[filter, options]`. The filter needs a numbered block range.
+    pub fn from_params(params: &Value) -> Result<Self, LogsPageError> {
+        let filter = params[0].clone();
+        if !filter.is_object() {
+            return Err(LogsPageError::InvalidParams(
+                "filter must be an object".to_string(),
+            ));
+        }
+        let from = parse_number(&filter["fromBlock"]).ok_or(LogsPageError::InvalidParams(
+            "fromBlock must be a block number".to_string(),
+        ))?;
+        let to = parse_number(&filter["toBlock"]).ok_or(LogsPageError::InvalidParams(
+            "toBlock must be a block number".to_string(),
+        ))?;
+        if to < from {
+            return Err(LogsPageError::InvalidParams(
+                "toBlock must not be lower than fromBlock".to_string(),
+            ));
+        }
+
+        let options = &params[1];
+        let page_size = match &options["pageSize"] {
+            Value::Null => DEFAULT_PAGE_SIZE,
+            page_size => {
+                match page_size.as_u64() {
+                    Some(page_size) if (1..=MAX_PAGE_SIZE as u64).contains(&page_size) => {
+                        page_size as usize
+                    }
+                    _ => {
+                        return Err(LogsPageError::InvalidParams(format!(
+                            "pageSize must be between 1 and {}",
+                            MAX_PAGE_SIZE
+                        )))
+                    }
+                }
+            }
+        };
+        let cursor = match &options["cursor"] {
+            Value::Null => {
+                Cursor {
+                    block: from,
+                    skip: 0,
+                }
+            }
+            cursor => {
+                cursor
+                    .as_str()
+                    .and_then(Cursor::parse)
+                    .filter(|cursor| cursor.block >= from)
+                    .ok_or(LogsPageError::InvalidParams("invalid cursor".to_string()))?
+            }
+        };
+
+        Ok(LogsPage {
+            filter,
+            to,
+            page_size,
+            cursor,
+        })
+    }
+
+    // `eth_getLogs` requests for the next `MAX_CALLS_PER_PAGE * blocks`
+    // blocks of the range, `blocks` at a time, without an id so they hash
+    // the same as the ones clients send us
+    fn requests(&self, blocks: u64) -> Result<Vec<Value>, LogsPageError> {
+        if self.cursor.block > self.to {
+            return Ok(Vec::new());
+        }
+
+        let end = self.to.min(
+            self.cursor
+                .block
+                .saturating_add(blocks.saturating_mul(MAX_CALLS_PER_PAGE as u64) - 1),
+        );
+        let mut filter = self.filter.clone();
+        filter["fromBlock"] = format!("0x{:x}", self.cursor.block).into();
+        filter["toBlock"] = format!("0x{:x}", end).into();
+        let options = StreamOptions {
+            blocks,
+            ..Default::default()
+        };
+        options
+            .split_call(json!({
+                "jsonrpc": "2.0",
+                "id": null,
+                "method": "eth_getLogs",
+                "params": [filter],
+            }))
+            .map_err(|e| LogsPageError::InvalidParams(e.to_string()))
+    }
+}
+
+// Logs for one request, either from the cache or from a node
+async fn fetch_logs(
+    request: Value,
+    rpc_list: &Arc<RwLock<Vec<Rpc>>>,
+    group: Option<&str>,
+    namespace: Option<&str>,
+    cache_args: &CacheArgs,
+    ttl: u128,
+) -> Result<Vec<Value>, LogsPageError> {
+    let tx_hash = match namespace {
+        Some(namespace) => CacheKey::namespaced(namespace, &request),
+        None => CacheKey::new(&request),
+    };
+
+    if let Ok(Some(cached)) = get_tiered(&cache_args.cache, &cache_args.hot, &tx_hash) {
+        if let Ok(cached) = serde_json::from_slice::<Value>(&cached) {
+            if let Some(logs) = cached["result"].as_array() {
+                cache_args.memory.record_hit(tx_hash.as_bytes());
+                return Ok(logs.clone());
+            }
+        }
+    }
+
+    // Nodes that can't serve the range leave it to ones that can
+    let rpc = {
+        let mut rpc_list = rpc_list.write().unwrap();
+        match pick_available(&mut rpc_list, &request, group, &[]) {
+            (rpc, Some(_)) => rpc,
+            (_, None) => return Err(LogsPageError::NoRpcAvailable),
+        }
+    };
+
+    // Nodes treat requests without an id as notifications
+    let mut request = request;
+    request["id"] = 1.into();
+    let from = parse_number(&request["params"][0]["fromBlock"]).unwrap_or_default();
+
+    let mut rx = match timeout(
+        Duration::from_millis(ttl.try_into().unwrap_or(u64::MAX)),
+        rpc.send_request(request.clone()),
+    )
+    .await
+    {
+        Ok(Ok(rx)) => rx,
+        Ok(Err(e)) => return Err(LogsPageError::InvalidResponse(e.to_string())),
+        Err(_) => return Err(LogsPageError::TimedOut(from)),
+    };
+
+    let response: Value =
+        serde_json::from_str(&rx).map_err(|e| LogsPageError::InvalidResponse(e.to_string()))?;
+    if let Some(error) = response.get("error") {
+        return Err(LogsPageError::InvalidResponse(error.to_string()));
+    }
+    let logs = response["result"]
+        .as_array()
+        .cloned()
+        .ok_or(LogsPageError::InvalidResponse(
+            "result is not a list".to_string(),
+        ))?;
+
+    cache_querry(&mut rx, request, tx_hash, Some(&rpc.name), cache_args);
+
+    Ok(logs)
+}
+
+// Add the logs of one request to `logs`, counting where we are in
+// `position`. Returns the cursor of the next page once `logs` is full.
+fn fill_page(
+    page: &LogsPage,
+    fetched: Vec<Value>,
+    logs: &mut Vec<Value>,
+    position: &mut Cursor,
+) -> Result<Option<Cursor>, LogsPageError> {
+    for log in fetched {
+        let block = parse_number(&log["blockNumber"]).ok_or(LogsPageError::InvalidResponse(
+            "log without a blockNumber".to_string(),
+        ))?;
+        if block != position.block {
+            *position = Cursor { block, skip: 0 };
+        }
+        position.skip += 1;
+
+        // Sent on an earlier page
+        if block == page.cursor.block && position.skip <= page.cursor.skip {
+            continue;
+        }
+        if logs.len() == page.page_size {
+            return Ok(Some(Cursor {
+                block,
+                skip: position.skip - 1,
+            }));
+        }
+        logs.push(log);
+    }
+
+    Ok(None)
+}
+
+// The page of logs `page` points at, and the cursor of the next one if
+// there's more. `blocks` is how many blocks we ask a node for at once. Only
+// nodes in `group` are asked if it's set.
+pub async fn get_logs_page(
+    page: &LogsPage,
+    blocks: u64,
+    rpc_list: &Arc<RwLock<Vec<Rpc>>>,
+    group: Option<&str>,
+    namespace: Option<&str>,
+    cache_args: &CacheArgs,
+    ttl: u128,
+) -> Result<Value, LogsPageError> {
+    let mut logs = Vec::new();
+    let mut position = Cursor {
+        block: page.cursor.block,
+        skip: 0,
+    };
+
+    for request in page.requests(blocks)? {
+        let end = parse_number(&request["params"][0]["toBlock"]).unwrap_or(page.to);
+        let fetched = fetch_logs(request, rpc_list, group, namespace, cache_args, ttl).await?;
+        if let Some(next) = fill_page(page, fetched, &mut logs, &mut position)? {
+            return Ok(json!({"logs": logs, "cursor": next.encode()}));
+        }
+        position = Cursor {
+            block: end + 1,
+            skip: 0,
+        };
+    }
+
+    let cursor = (position.block <= page.to).then(|| position.encode());
+    Ok(json!({"logs": logs, "cursor": cursor}))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn log(block: u64, index: u64) -> Value {
+        json!({"blockNumber": format!("0x{:x}", block), "logIndex": format!("0x{:x}", index)})
+    }
+
+    #[test]
+    fn test_logs_page_from_params() {
+        let page = LogsPage::from_params(&json!([
+            {"fromBlock": "0x10", "toBlock": "0x20", "address": "0xabc"},
+            {"pageSize": 2, "cursor": "0x12:1"},
+        ]))
+        .unwrap();
+        assert_eq!(page.to, 0x20);
+        assert_eq!(page.page_size, 2);
+        assert_eq!(
+            page.cursor,
+            Cursor {
+                block: 0x12,
+                skip: 1
+            }
+        );
+        assert_eq!(Cursor::parse(&page.cursor.encode()), Some(page.cursor));
+
+        let page =
+            LogsPage::from_params(&json!([{"fromBlock": "0x10", "toBlock": "0x10"}])).unwrap();
+        assert_eq!(page.page_size, DEFAULT_PAGE_SIZE);
+        assert_eq!(
+            page.cursor,
+            Cursor {
+                block: 0x10,
+                skip: 0
+            }
+        );
+
+        for params in [
+            json!([{"fromBlock": "latest", "toBlock": "0x10"}]),
+            json!([{"fromBlock": "0x20", "toBlock": "0x10"}]),
+            json!([{"fromBlock": "0x10", "toBlock": "0x20"}, {"pageSize": 0}]),
+            json!([{"fromBlock": "0x10", "toBlock": "0x20"}, {"cursor": "0x1:0"}]),
+            json!([{"fromBlock": "0x10", "toBlock": "0x20"}, {"cursor": "next"}]),
+            json!(["0x10"]),
+        ] {
+            assert!(LogsPage::from_params(&params).is_err(), "{}", params);
+        }
+    }
+
+    #[test]
+    fn test_logs_page_requests() {
+        let page = LogsPage::from_params(&json!([
+            {"fromBlock": "0x0", "toBlock": "0x9", "topics": ["0x1"]},
+            {"cursor": "0x4:2"},
+        ]))
+        .unwrap();
+        let requests = page.requests(3).unwrap();
+
+        let ranges: Vec<(&str, &str)> = requests
+            .iter()
+            .map(|request| {
+                (
+                    request["params"][0]["fromBlock"].as_str().unwrap(),
+                    request["params"][0]["toBlock"].as_str().unwrap(),
+                )
+            })
+            .collect();
+        assert_eq!(ranges, vec![("0x4", "0x6"), ("0x7", "0x9")]);
+        assert_eq!(requests[0]["params"][0]["topics"], json!(["0x1"]));
+        assert_eq!(requests[0]["id"], Value::Null);
+    }
+
+    #[test]
+    fn test_fill_page() {
+        let mut page = LogsPage::from_params(&json!([
+            {"fromBlock": "0x1", "toBlock": "0x9"},
+            {"pageSize": 3},
+        ]))
+        .unwrap();
+        let fetched = vec![log(1, 0), log(2, 1), log(2, 2), log(2, 3), log(3, 4)];
+
+        // First page ends in the middle of block 2
+        let mut logs = Vec::new();
+        let mut position = Cursor { block: 1, skip: 0 };
+        let next = fill_page(&page, fetched.clone(), &mut logs, &mut position).unwrap();
+        assert_eq!(logs, fetched[..3]);
+        assert_eq!(next, Some(Cursor { block: 2, skip: 2 }));
+
+        // The next one picks up right after
+        page.cursor = next.unwrap();
+        let mut logs = Vec::new();
+        let mut position = Cursor { block: 2, skip: 0 };
+        let next = fill_page(&page, fetched[1..].to_vec(), &mut logs, &mut position).unwrap();
+        assert_eq!(logs, fetched[3..]);
+        assert_eq!(next, None);
+
+        assert!(fill_page(&page, vec![json!({})], &mut Vec::new(), &mut position).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_get_logs_page_tenant() {
+        use crate::{
+            balancer::cache_entry::insert_entry,
+            mock::node::MockNode,
+        };
+
+        let cache_args = CacheArgs {
+            cache: Arc::new(sled::Config::new().temporary(true).open().unwrap()),
+            ..Default::default()
+        };
+        let tenant = MockNode::spawn(1).await.unwrap();
+        let shared = MockNode::spawn(1).await.unwrap();
+        tenant.set_response("eth_getLogs", json!([log(1, 0), log(2, 0)]));
+        shared.set_response("eth_getLogs", json!([log(1, 0), log(2, 0)]));
+        let mut shared_rpc = Rpc::new(shared.http_url(), None, 10, 0, 1.0);
+        shared_rpc.status.latency = 1.0;
+        let mut tenant_rpc =
+            Rpc::new(tenant.http_url(), None, 10, 0, 1.0).with_groups(vec!["acme".to_string()]);
+        tenant_rpc.status.latency = 10.0;
+        let rpc_list = Arc::new(RwLock::new(vec![shared_rpc, tenant_rpc]));
+        let page = LogsPage::from_params(&json!([{"fromBlock": "0x1", "toBlock": "0x2"}])).unwrap();
+
+        // Whatever is cached for everyone else isn't the tenant's
+        let request = &page.requests(1000).unwrap()[0];
+        let cached = json!({"jsonrpc": "2.0", "id": null, "result": [log(1, 7)]});
+        insert_entry(
+            &cache_args.cache,
+            &CacheKey::new(request),
+            cached.to_string().as_bytes(),
+        )
+        .unwrap();
+
+        let response = get_logs_page(
+            &page,
+            1000,
+            &rpc_list,
+            Some("acme"),
+            Some("acme"),
+            &cache_args,
+            1000,
+        )
+        .await
+        .unwrap();
+        assert_eq!(response["logs"], json!([log(1, 0), log(2, 0)]));
+        assert_eq!(tenant.request_count(), 1);
+        assert_eq!(shared.request_count(), 0);
+    }
+}
@@ -0,0 +1,379 @@
+// Memory budget for cached responses and subscription buffers.
+//
+// We keep track of the size of every response we cache, of the copies the
+// hot tier keeps in memory and of the logs held back for
+// `blutgang_confirmedLogs` subscriptions. When the total goes over
+// `memory_budget`, cache entries get evicted according to the eviction
+// policy until we're back under the low watermark. Subscription buffers are
+// only accounted for, since dropping them would lose events. The hot tier
+// drops whatever we evict from sled along with it.
+//
+// Entries persisted in the DB from a previous run are counted at startup,
+// as if they were the least recently used. Everything that removes entries
+// from the cache releases them here, so the total stays what sled holds.
+use crate::{
+    balancer::{
+        cache_entry::is_entry_key,
+        hot_cache::HotCache,
+    },
+    config::types::EvictionPolicy,
+    log_info,
+    websocket::types::SubscriptionData,
+};
+
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{
+            AtomicU64,
+            AtomicUsize,
+            Ordering,
+        },
+        Arc,
+        Mutex,
+    },
+    time::Duration,
+};
+
+use serde_json::{
+    json,
+    Value,
+};
+use sled::Db;
+use tokio::time::sleep;
+
+// Evict down to this fraction of the budget so we don't evict on every insert
+const LOW_WATERMARK: f64 = 0.9;
+
+// How often we check if we're over budget
+const ENFORCE_INTERVAL: Duration = Duration::from_millis(1000);
+
+#[derive(Debug, Clone, Copy)]
+struct Entry {
+    size: usize,
+    last_access: u64,
+    hits: u64,
+}
+
+#[derive(Debug, Default)]
+pub struct MemoryBudget {
+    limit: Option<usize>,
+    policy: EvictionPolicy,
+    // Only populated if we have a limit
+    entries: Mutex<HashMap<Vec<u8>, Entry>>,
+    // Logical clock for LRU
+    clock: AtomicU64,
+    cache_bytes: AtomicUsize,
+    hot_bytes: AtomicUsize,
+    buffered_bytes: AtomicUsize,
+    evictions: AtomicU64,
+}
+
+impl MemoryBudget {
+    pub fn new(limit: Option<usize>, policy: EvictionPolicy) -> Self {
+        MemoryBudget {
+            limit,
+            policy,
+            ..Default::default()
+        }
+    }
+
+    // Starts at 1, entries from before startup were last used at 0
+    fn tick(&self) -> u64 {
+        self.clock.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    // Account for a response we just inserted into the cache
+    pub fn record_insert(&self, key: &[u8], size: usize) {
+        if self.limit.is_none() {
+            self.cache_bytes.fetch_add(size, Ordering::Relaxed);
+            return;
+        }
+
+        let entry = Entry {
+            size,
+            last_access: self.tick(),
+            hits: 0,
+        };
+        let previous = self.entries.lock().unwrap().insert(key.to_vec(), entry);
+
+        // Overwriting an entry replaces its size
+        if let Some(previous) = previous {
+            self.cache_bytes.fetch_sub(previous.size, Ordering::Relaxed);
+        }
+        self.cache_bytes.fetch_add(size, Ordering::Relaxed);
+    }
+
+    // Account for an entry we removed from the cache, `size` being what sled
+    // gave back for it
+    pub fn release(&self, key: &[u8], size: usize) {
+        let size = match self.limit {
+            Some(_) => {
+                match self.entries.lock().unwrap().remove(key) {
+                    Some(entry) => entry.size,
+                    None => return,
+                }
+            }
+            None => size,
+        };
+
+        let _ = self
+            .cache_bytes
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |used| {
+                Some(used.saturating_sub(size))
+            });
+    }
+
+    // Account for the entries already in `cache` when we start. Keys that
+    // were cached in the meantime are left as they are.
+    pub fn seed(&self, cache: &Db) -> usize {
+        let mut seeded = 0;
+        for (key, entry) in cache.iter().flatten() {
+            if !is_entry_key(&key) {
+                continue;
+            }
+
+            if self.limit.is_some() {
+                let mut entries = self.entries.lock().unwrap();
+                if entries.contains_key(key.as_ref()) {
+                    continue;
+                }
+                entries.insert(
+                    key.to_vec(),
+                    Entry {
+                        size: entry.len(),
+                        last_access: 0,
+                        hits: 0,
+                    },
+                );
+            }
+            self.cache_bytes.fetch_add(entry.len(), Ordering::Relaxed);
+            seeded += 1;
+        }
+        seeded
+    }
+
+    // Account for a response we served from the cache
+    pub fn record_hit(&self, key: &[u8]) {
+        if self.limit.is_none() {
+            return;
+        }
+
+        let tick = self.tick();
+        if let Some(entry) = self.entries.lock().unwrap().get_mut(key) {
+            entry.last_access = tick;
+            entry.hits += 1;
+        }
+    }
+
+    pub fn set_buffered_bytes(&self, bytes: usize) {
+        self.buffered_bytes.store(bytes, Ordering::Relaxed);
+    }
+
+    pub fn set_hot_bytes(&self, bytes: usize) {
+        self.hot_bytes.store(bytes, Ordering::Relaxed);
+    }
+
+    pub fn used_bytes(&self) -> usize {
+        self.cache_bytes.load(Ordering::Relaxed)
+            + self.hot_bytes.load(Ordering::Relaxed)
+            + self.buffered_bytes.load(Ordering::Relaxed)
+    }
+
+    // Evict cache entries until we're under the low watermark.
+    //
+    // Returns how many entries were evicted.
+    pub fn evict(&self, cache: &Db) -> usize {
+        let limit = match self.limit {
+            Some(limit) => limit,
+            None => return 0,
+        };
+        let used = self.used_bytes();
+        if used <= limit {
+            return 0;
+        }
+        let mut to_free = used - (limit as f64 * LOW_WATERMARK) as usize;
+
+        let mut entries = self.entries.lock().unwrap();
+        let mut candidates: Vec<(Vec<u8>, Entry)> = entries
+            .iter()
+            .map(|(key, entry)| (key.clone(), *entry))
+            .collect();
+        match self.policy {
+            EvictionPolicy::Lru => candidates.sort_unstable_by_key(|(_, entry)| entry.last_access),
+            EvictionPolicy::Lfu => {
+                candidates.sort_unstable_by_key(|(_, entry)| (entry.hits, entry.last_access))
+            }
+        }
+
+        let mut evicted = 0;
+        for (key, entry) in candidates {
+            if to_free == 0 {
+                break;
+            }
+
+            // The entry might already be gone because of a reorg or a flush,
+            // stop tracking it either way
+            let _ = cache.remove(&key);
+            entries.remove(&key);
+            self.cache_bytes.fetch_sub(entry.size, Ordering::Relaxed);
+            to_free = to_free.saturating_sub(entry.size);
+            evicted += 1;
+        }
+
+        self.evictions.fetch_add(evicted as u64, Ordering::Relaxed);
+        evicted
+    }
+
+    pub fn stats(&self) -> Value {
+        json!({
+            "budget": self.limit,
+            "policy": format!("{:?}", self.policy).to_lowercase(),
+            "usedBytes": self.used_bytes(),
+            "cacheBytes": self.cache_bytes.load(Ordering::Relaxed),
+            "hotCacheBytes": self.hot_bytes.load(Ordering::Relaxed),
+            "subscriptionBufferBytes": self.buffered_bytes.load(Ordering::Relaxed),
+            "trackedEntries": self.entries.lock().unwrap().len(),
+            "evictions": self.evictions.load(Ordering::Relaxed),
+        })
+    }
+}
+
+// Keep cached responses, the hot tier and subscription buffers within the
+// memory budget
+pub async fn enforce_memory_budget(
+    budget: Arc<MemoryBudget>,
+    cache: Arc<Db>,
+    hot: Arc<HotCache>,
+    sub_data: Arc<SubscriptionData>,
+) {
+    loop {
+        sleep(ENFORCE_INTERVAL).await;
+
+        budget.set_buffered_bytes(sub_data.buffered_bytes());
+        budget.set_hot_bytes(hot.used_bytes());
+        let evicted = budget.evict(&cache);
+        if evicted > 0 {
+            log_info!(
+                "Over memory budget, evicted {} cache entries. Using {} bytes.",
+                evicted,
+                budget.used_bytes()
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::balancer::cache_entry::CacheKey;
+
+    fn cache() -> Db {
+        sled::Config::new().temporary(true).open().unwrap()
+    }
+
+    fn insert(budget: &MemoryBudget, cache: &Db, key: &[u8], size: usize) {
+        cache.insert(key, vec![0; size]).unwrap();
+        budget.record_insert(key, size);
+    }
+
+    #[test]
+    fn test_lru_eviction() {
+        let cache = cache();
+        let budget = MemoryBudget::new(Some(300), EvictionPolicy::Lru);
+
+        insert(&budget, &cache, b"a", 100);
+        insert(&budget, &cache, b"b", 100);
+        insert(&budget, &cache, b"c", 100);
+        budget.record_hit(b"a");
+        assert_eq!(budget.evict(&cache), 0);
+
+        // `b` is the least recently used now
+        insert(&budget, &cache, b"d", 100);
+        assert_eq!(budget.evict(&cache), 2);
+        assert!(cache.get(b"b").unwrap().is_none());
+        assert!(cache.get(b"c").unwrap().is_none());
+        assert!(cache.get(b"a").unwrap().is_some());
+        assert_eq!(budget.used_bytes(), 200);
+    }
+
+    #[test]
+    fn test_lfu_eviction() {
+        let cache = cache();
+        let budget = MemoryBudget::new(Some(250), EvictionPolicy::Lfu);
+
+        insert(&budget, &cache, b"a", 100);
+        insert(&budget, &cache, b"b", 100);
+        budget.record_hit(b"a");
+        budget.record_hit(b"a");
+        budget.record_hit(b"b");
+
+        insert(&budget, &cache, b"c", 100);
+        budget.record_hit(b"c");
+        budget.record_hit(b"c");
+        budget.record_hit(b"c");
+
+        assert_eq!(budget.evict(&cache), 1);
+        assert!(cache.get(b"b").unwrap().is_none());
+    }
+
+    #[test]
+    fn test_subscription_buffers_count_towards_budget() {
+        let cache = cache();
+        let budget = MemoryBudget::new(Some(300), EvictionPolicy::Lru);
+
+        insert(&budget, &cache, b"a", 100);
+        insert(&budget, &cache, b"b", 100);
+        budget.set_buffered_bytes(200);
+
+        assert_eq!(budget.evict(&cache), 2);
+        assert_eq!(budget.stats()["subscriptionBufferBytes"], 200);
+        assert_eq!(budget.stats()["evictions"], 2);
+    }
+
+    #[test]
+    fn test_release() {
+        let cache = cache();
+        let key = CacheKey::from_request(b"a");
+        cache.insert(key.as_bytes(), vec![0; 100]).unwrap();
+        cache.insert(b"not an entry", vec![0; 100]).unwrap();
+
+        // Entries from a previous run are counted, and evicted first
+        let budget = MemoryBudget::new(Some(250), EvictionPolicy::Lru);
+        assert_eq!(budget.seed(&cache), 1);
+        insert(&budget, &cache, b"b", 100);
+        insert(&budget, &cache, b"c", 100);
+        assert_eq!(budget.evict(&cache), 1);
+        assert!(cache.get(key.as_bytes()).unwrap().is_none());
+
+        // Entries removed elsewhere stop counting
+        let removed = cache.remove(b"b").unwrap().unwrap();
+        budget.release(b"b", removed.len());
+        budget.release(b"b", removed.len());
+        assert_eq!(budget.used_bytes(), 100);
+        assert_eq!(budget.stats()["trackedEntries"], 1);
+
+        let budget = MemoryBudget::default();
+        assert_eq!(budget.seed(&cache), 0);
+        insert(&budget, &cache, b"a", 100);
+        budget.release(b"a", 100);
+        budget.release(b"a", 100);
+        assert_eq!(budget.used_bytes(), 0);
+
+        budget.set_hot_bytes(50);
+        assert_eq!(budget.used_bytes(), 50);
+    }
+
+    #[test]
+    fn test_unlimited_budget() {
+        let cache = cache();
+        let budget = MemoryBudget::default();
+
+        insert(&budget, &cache, b"a", 100);
+        insert(&budget, &cache, b"b", 100);
+
+        assert_eq!(budget.used_bytes(), 200);
+        assert_eq!(budget.evict(&cache), 0);
+        assert_eq!(budget.stats()["trackedEntries"], 0);
+    }
+}
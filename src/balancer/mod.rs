@@ -1,5 +1,39 @@
 pub mod accept_http;
+pub mod block_hashes;
+pub mod block_range;
+pub mod cache_entry;
+pub mod consistent_reads;
+pub mod cors;
+pub mod deprecations;
+pub mod ens;
+pub mod error_map;
+pub mod estimate_gas;
 pub mod format;
+pub mod graphql;
+pub mod head_retry;
+pub mod hot_cache;
+#[cfg(feature = "http3")]
+pub mod http3;
+pub mod http_get;
+pub mod logs_paged;
+pub mod memory;
+pub mod mtls;
+pub mod openrpc;
+pub mod passthrough;
+pub mod peer_count;
+pub mod prewarm;
 pub mod processing;
+pub mod profiling;
+pub mod quorum;
+pub mod recording;
+pub mod request_body;
 mod response_errors;
+pub mod rest;
+pub mod revalidate;
+pub mod routing_hints;
+pub mod schema;
 pub mod selection;
+pub mod snapshot;
+pub mod syncing;
+pub mod tenants;
+pub mod wallet;
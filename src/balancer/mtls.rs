@@ -0,0 +1,642 @@
+// TLS on the downstream listener, with optional client certificates.
+//
+// Internal services can authenticate with a certificate from our own CA
+// instead of an API key. Set in an optional `[blutgang.tls]` table:
+//
+// cert = "/path/to/server.pem"           # certificate chain we serve
+// key = "/path/to/server-key.pem"        # its private key
+// client_ca = "/path/to/internal-ca.pem" # CAs client certificates must chain to
+// require_client_cert = true             # reject clients without a certificate
+//
+// [blutgang.tls.identities]
+// "indexer.internal" = ["eth_getLogs", "eth_getBlockBy*"]
+// "spiffe://prod/payments" = ["*"]
+//
+// A client's identity is the common name or any DNS/URI SAN of its
+// certificate. If identities are listed, certificates that don't match any
+// of them are rejected, and matching ones can only call the methods of their
+// policy. Methods ending with `*` match by prefix. Clients without a
+// certificate get the policy of the `anonymous` identity, or are rejected if
+// there isn't one.
+use crate::config::error::{
+    ConfigError,
+    OrInvalid,
+};
+
+use std::{
+    collections::BTreeMap,
+    fmt,
+    fs,
+    io,
+    pin::Pin,
+};
+
+use openssl::{
+    error::ErrorStack,
+    nid::Nid,
+    pkey::PKey,
+    ssl::{
+        Ssl,
+        SslAcceptor,
+        SslMethod,
+        SslVerifyMode,
+    },
+    x509::{
+        store::X509StoreBuilder,
+        X509Ref,
+        X509,
+    },
+};
+use serde_json::{
+    json,
+    Value,
+};
+use tokio::net::TcpStream;
+use tokio_openssl::SslStream;
+use toml::Value as TomlValue;
+
+// Identity clients without a certificate get
+pub const ANONYMOUS: &str = "anonymous";
+
+// Errors
+#[derive(Debug)]
+pub enum MtlsError {
+    Io(String, io::Error),
+    Ssl(ErrorStack),
+    Handshake(String),
+    UnknownIdentity(Vec<String>),
+}
+
+impl fmt::Display for MtlsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MtlsError::Io(path, e) => write!(f, "Could not read {}: {}", path, e),
+            MtlsError::Ssl(e) => write!(f, "Invalid TLS settings: {}", e),
+            MtlsError::Handshake(reason) => write!(f, "TLS handshake failed: {}", reason),
+            MtlsError::UnknownIdentity(names) if names.is_empty() => {
+                write!(f, "No policy for clients without a certificate")
+            }
+            MtlsError::UnknownIdentity(names) => {
+                write!(f, "No policy for client certificate {:?}", names)
+            }
+        }
+    }
+}
+
+impl std::error::Error for MtlsError {}
+
+impl From<ErrorStack> for MtlsError {
+    fn from(e: ErrorStack) -> Self {
+        MtlsError::Ssl(e)
+    }
+}
+
+// Methods a client identity is allowed to call
+#[derive(Debug, Clone, PartialEq)]
+pub struct AuthPolicy {
+    pub methods: Vec<String>,
+}
+
+impl AuthPolicy {
+    pub fn allows(&self, method: &str) -> bool {
+        self.methods.iter().any(|allowed| {
+            match allowed.strip_suffix('*') {
+                Some(prefix) => method.starts_with(prefix),
+                None => allowed == method,
+            }
+        })
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ListenerTlsSettings {
+    pub cert: String,
+    pub key: String,
+    pub client_ca: Option<String>,
+    pub require_client_cert: bool,
+    pub identities: BTreeMap<String, AuthPolicy>,
+}
+
+impl ListenerTlsSettings {
+    // Parse the optional `[blutgang.tls]` table
+    pub fn from_table(table: Option<&TomlValue>) -> Result<Option<Self>, ConfigError> {
+        let table = match table {
+            Some(table) => {
+                table
+                    .as_table()
+                    .or_invalid("Could not parse blutgang tls table!")?
+            }
+            None => return Ok(None),
+        };
+
+        let path = |key: &str| {
+            table
+                .get(key)
+                .map(|path| {
+                    path.as_str().map(str::to_string).ok_or_else(|| {
+                        ConfigError::InvalidConfig(format!(
+                            "Could not parse blutgang tls {} as str!",
+                            key
+                        ))
+                    })
+                })
+                .transpose()
+        };
+        let require_client_cert = match table.get("require_client_cert") {
+            Some(require) => {
+                require
+                    .as_bool()
+                    .or_invalid("Could not parse blutgang tls require_client_cert as bool!")?
+            }
+            None => false,
+        };
+
+        let identities = match table.get("identities") {
+            Some(identities) => {
+                identities
+                    .as_table()
+                    .or_invalid("Could not parse blutgang tls identities table!")?
+                    .iter()
+                    .map(|(identity, methods)| {
+                        let methods = methods
+                            .as_array()
+                            .and_then(|methods| {
+                                methods
+                                    .iter()
+                                    .map(|method| method.as_str().map(str::to_string))
+                                    .collect::<Option<Vec<String>>>()
+                            })
+                            .ok_or_else(|| {
+                                ConfigError::InvalidConfig(format!(
+                                    "Methods for identity {} must be a list of strings!",
+                                    identity
+                                ))
+                            })?;
+                        Ok((identity.clone(), AuthPolicy { methods }))
+                    })
+                    .collect::<Result<_, ConfigError>>()?
+            }
+            None => BTreeMap::new(),
+        };
+
+        let client_ca = path("client_ca")?;
+        if (require_client_cert || !identities.is_empty()) && client_ca.is_none() {
+            return Err(ConfigError::InvalidConfig(
+                "Client certificates need a client_ca to verify them!".to_string(),
+            ));
+        }
+
+        Ok(Some(ListenerTlsSettings {
+            cert: path("cert")?.or_invalid("Missing cert from blutgang tls!")?,
+            key: path("key")?.or_invalid("Missing key from blutgang tls!")?,
+            client_ca,
+            require_client_cert,
+            identities,
+        }))
+    }
+}
+
+// Who is on the other end of a connection, and what they can call
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClientIdentity {
+    pub name: String,
+    pub policy: Option<AuthPolicy>,
+}
+
+impl ClientIdentity {
+    // Error response for requests the policy doesn't allow
+    pub fn deny(&self, tx: &Value) -> Option<Value> {
+        let policy = self.policy.as_ref()?;
+        let method = tx["method"].as_str().unwrap_or_default();
+        if policy.allows(method) {
+            return None;
+        }
+
+        Some(json!({
+            "jsonrpc": "2.0",
+            "id": tx["id"],
+            "error": {
+                "code": -32006,
+                "message": format!("{} is not allowed to call {}", self.name, method),
+            },
+        }))
+    }
+}
+
+// Identity of a client without a certificate. Anyone can connect without
+// one unless identities are listed, then only with an `anonymous` policy.
+fn anonymous(
+    identities: &BTreeMap<String, AuthPolicy>,
+) -> Result<Option<ClientIdentity>, MtlsError> {
+    if identities.is_empty() {
+        return Ok(None);
+    }
+
+    match identities.get(ANONYMOUS) {
+        Some(policy) => {
+            Ok(Some(ClientIdentity {
+                name: ANONYMOUS.to_string(),
+                policy: Some(policy.clone()),
+            }))
+        }
+        None => Err(MtlsError::UnknownIdentity(Vec::new())),
+    }
+}
+
+// Match the names on a client certificate against the configured identities
+fn authorize(
+    identities: &BTreeMap<String, AuthPolicy>,
+    names: Vec<String>,
+) -> Result<ClientIdentity, MtlsError> {
+    if identities.is_empty() {
+        return Ok(ClientIdentity {
+            name: names.into_iter().next().unwrap_or_default(),
+            policy: None,
+        });
+    }
+
+    names
+        .iter()
+        .find_map(|name| {
+            identities.get(name).map(|policy| {
+                ClientIdentity {
+                    name: name.clone(),
+                    policy: Some(policy.clone()),
+                }
+            })
+        })
+        .ok_or(MtlsError::UnknownIdentity(names))
+}
+
+// Identity of a client that connected with `cert`, if it sent one
+pub fn identify(
+    identities: &BTreeMap<String, AuthPolicy>,
+    cert: Option<&X509Ref>,
+) -> Result<Option<ClientIdentity>, MtlsError> {
+    match cert {
+        Some(cert) => authorize(identities, certificate_names(cert)).map(Some),
+        None => anonymous(identities),
+    }
+}
+
+// Common name and DNS/URI SANs of a certificate
+fn certificate_names(cert: &X509Ref) -> Vec<String> {
+    let mut names: Vec<String> = cert
+        .subject_name()
+        .entries_by_nid(Nid::COMMONNAME)
+        .filter_map(|entry| entry.data().as_utf8().ok().map(|name| name.to_string()))
+        .collect();
+
+    if let Some(sans) = cert.subject_alt_names() {
+        names.extend(
+            sans.iter()
+                .filter_map(|san| san.dnsname().or_else(|| san.uri()))
+                .map(str::to_string),
+        );
+    }
+
+    names
+}
+
+fn read(path: &str) -> Result<Vec<u8>, MtlsError> {
+    fs::read(path).map_err(|e| MtlsError::Io(path.to_string(), e))
+}
+
+// Terminates TLS for downstream connections
+pub struct ListenerTls {
+    acceptor: SslAcceptor,
+    identities: BTreeMap<String, AuthPolicy>,
+}
+
+impl ListenerTls {
+    pub fn load(settings: &ListenerTlsSettings) -> Result<Self, MtlsError> {
+        let mut builder = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls_server())?;
+
+        let mut chain = X509::stack_from_pem(&read(&settings.cert)?)?.into_iter();
+        let leaf = chain.next().ok_or(MtlsError::Ssl(ErrorStack::get()))?;
+        builder.set_certificate(&leaf)?;
+        for cert in chain {
+            builder.add_extra_chain_cert(cert)?;
+        }
+        let key = PKey::private_key_from_pem(&read(&settings.key)?)?;
+        builder.set_private_key(&key)?;
+        builder.check_private_key()?;
+
+        if let Some(client_ca) = &settings.client_ca {
+            let mut store = X509StoreBuilder::new()?;
+            for cert in X509::stack_from_pem(&read(client_ca)?)? {
+                builder.add_client_ca(&cert)?;
+                store.add_cert(cert)?;
+            }
+            builder.set_verify_cert_store(store.build())?;
+
+            let mut mode = SslVerifyMode::PEER;
+            if settings.require_client_cert {
+                mode |= SslVerifyMode::FAIL_IF_NO_PEER_CERT;
+            }
+            builder.set_verify(mode);
+            // Resumed sessions keep the verified certificate
+            builder.set_session_id_context(b"blutgang")?;
+        }
+
+        Ok(ListenerTls {
+            acceptor: builder.build(),
+            identities: settings.identities.clone(),
+        })
+    }
+
+    // Do the handshake. The identity is `None` for clients without a
+    // certificate if there are no identities to check them against.
+    pub async fn accept(
+        &self,
+        stream: TcpStream,
+    ) -> Result<(SslStream<TcpStream>, Option<ClientIdentity>), MtlsError> {
+        let ssl = Ssl::new(self.acceptor.context())?;
+        let mut stream = SslStream::new(ssl, stream)?;
+        Pin::new(&mut stream)
+            .accept()
+            .await
+            .map_err(|e| MtlsError::Handshake(e.to_string()))?;
+
+        let identity = identify(&self.identities, stream.ssl().peer_certificate().as_deref())?;
+
+        Ok((stream, identity))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::path::PathBuf;
+
+    use openssl::{
+        asn1::Asn1Time,
+        bn::BigNum,
+        ec::{
+            EcGroup,
+            EcKey,
+        },
+        hash::MessageDigest,
+        pkey::Private,
+        ssl::SslConnector,
+        x509::{
+            extension::{
+                BasicConstraints,
+                SubjectAlternativeName,
+            },
+            X509NameBuilder,
+        },
+    };
+    use tokio::{
+        io::{
+            AsyncReadExt,
+            AsyncWriteExt,
+        },
+        net::TcpListener,
+    };
+
+    fn key() -> PKey<Private> {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap()
+    }
+
+    // Certificate for `name`, signed by `issuer` or self-signed
+    fn certificate(
+        name: &str,
+        san: Option<&str>,
+        key: &PKey<Private>,
+        issuer: Option<(&X509, &PKey<Private>)>,
+    ) -> X509 {
+        let mut subject = X509NameBuilder::new().unwrap();
+        subject.append_entry_by_nid(Nid::COMMONNAME, name).unwrap();
+        let subject = subject.build();
+
+        let mut builder = X509::builder().unwrap();
+        builder.set_version(2).unwrap();
+        builder
+            .set_serial_number(&BigNum::from_u32(1).unwrap().to_asn1_integer().unwrap())
+            .unwrap();
+        builder.set_subject_name(&subject).unwrap();
+        builder.set_pubkey(key).unwrap();
+        builder
+            .set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        builder
+            .set_not_after(&Asn1Time::days_from_now(1).unwrap())
+            .unwrap();
+        if let Some(san) = san {
+            let san = SubjectAlternativeName::new()
+                .dns(san)
+                .build(&builder.x509v3_context(None, None))
+                .unwrap();
+            builder.append_extension(san).unwrap();
+        }
+
+        match issuer {
+            Some((issuer, issuer_key)) => {
+                builder.set_issuer_name(issuer.subject_name()).unwrap();
+                builder.sign(issuer_key, MessageDigest::sha256()).unwrap();
+            }
+            None => {
+                builder
+                    .append_extension(BasicConstraints::new().critical().ca().build().unwrap())
+                    .unwrap();
+                builder.set_issuer_name(&subject).unwrap();
+                builder.sign(key, MessageDigest::sha256()).unwrap();
+            }
+        }
+        builder.build()
+    }
+
+    fn write_temp(name: &str, contents: &[u8]) -> String {
+        let path: PathBuf =
+            std::env::temp_dir().join(format!("blutgang-mtls-{}-{}", std::process::id(), name));
+        fs::write(&path, contents).unwrap();
+        path.to_string_lossy().to_string()
+    }
+
+    #[test]
+    fn test_auth_policy() {
+        let policy = AuthPolicy {
+            methods: vec!["eth_getLogs".to_string(), "eth_getBlockBy*".to_string()],
+        };
+        assert!(policy.allows("eth_getLogs"));
+        assert!(policy.allows("eth_getBlockByHash"));
+        assert!(!policy.allows("eth_sendRawTransaction"));
+
+        let everything = AuthPolicy {
+            methods: vec!["*".to_string()],
+        };
+        assert!(everything.allows("eth_sendRawTransaction"));
+
+        let identity = ClientIdentity {
+            name: "indexer".to_string(),
+            policy: Some(policy),
+        };
+        let denied = identity
+            .deny(&json!({"jsonrpc": "2.0", "id": 7, "method": "eth_call", "params": []}))
+            .unwrap();
+        assert_eq!(denied["id"], 7);
+        assert_eq!(denied["error"]["code"], -32006);
+        assert!(identity
+            .deny(&json!({"jsonrpc": "2.0", "id": 7, "method": "eth_getLogs", "params": []}))
+            .is_none());
+    }
+
+    #[test]
+    fn test_listener_tls_settings_from_table() {
+        assert_eq!(ListenerTlsSettings::from_table(None).unwrap(), None);
+
+        let table: TomlValue = toml::from_str(
+            r#"
+            cert = "/etc/blutgang/server.pem"
+            key = "/etc/blutgang/server-key.pem"
+            client_ca = "/etc/blutgang/ca.pem"
+            require_client_cert = true
+
+            [identities]
+            "indexer.internal" = ["eth_getLogs"]
+            "#,
+        )
+        .unwrap();
+        let settings = ListenerTlsSettings::from_table(Some(&table))
+            .unwrap()
+            .unwrap();
+        assert!(settings.require_client_cert);
+        assert_eq!(settings.client_ca.as_deref(), Some("/etc/blutgang/ca.pem"));
+        assert_eq!(
+            settings.identities["indexer.internal"].methods,
+            vec!["eth_getLogs"]
+        );
+    }
+
+    #[test]
+    fn test_anonymous() {
+        assert!(anonymous(&BTreeMap::new()).unwrap().is_none());
+
+        let mut identities = BTreeMap::from([(
+            "indexer.internal".to_string(),
+            AuthPolicy {
+                methods: vec!["*".to_string()],
+            },
+        )]);
+        assert!(matches!(
+            anonymous(&identities),
+            Err(MtlsError::UnknownIdentity(names)) if names.is_empty()
+        ));
+
+        identities.insert(
+            ANONYMOUS.to_string(),
+            AuthPolicy {
+                methods: vec!["eth_blockNumber".to_string()],
+            },
+        );
+        let identity = anonymous(&identities).unwrap().unwrap();
+        assert_eq!(identity.name, ANONYMOUS);
+        assert!(identity
+            .deny(&json!({"method": "eth_blockNumber"}))
+            .is_none());
+        assert!(identity.deny(&json!({"method": "eth_getLogs"})).is_some());
+    }
+
+    #[tokio::test]
+    async fn test_client_certificates() {
+        let ca_key = key();
+        let ca = certificate("blutgang test ca", None, &ca_key, None);
+        let server_key = key();
+        let server = certificate(
+            "localhost",
+            Some("localhost"),
+            &server_key,
+            Some((&ca, &ca_key)),
+        );
+        let client_key = key();
+        let client = certificate(
+            "client",
+            Some("indexer.internal"),
+            &client_key,
+            Some((&ca, &ca_key)),
+        );
+        let stranger_key = key();
+        let stranger = certificate("stranger", None, &stranger_key, Some((&ca, &ca_key)));
+
+        let settings = ListenerTlsSettings {
+            cert: write_temp("server.pem", &server.to_pem().unwrap()),
+            key: write_temp(
+                "server-key.pem",
+                &server_key.private_key_to_pem_pkcs8().unwrap(),
+            ),
+            client_ca: Some(write_temp("ca.pem", &ca.to_pem().unwrap())),
+            require_client_cert: true,
+            identities: BTreeMap::from([(
+                "indexer.internal".to_string(),
+                AuthPolicy {
+                    methods: vec!["eth_getLogs".to_string()],
+                },
+            )]),
+        };
+        let tls = ListenerTls::load(&settings).unwrap();
+        let _ = fs::remove_file(&settings.cert);
+        let _ = fs::remove_file(&settings.key);
+        let _ = fs::remove_file(settings.client_ca.as_ref().unwrap());
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+
+        // Connect with an optional client certificate and say hi
+        let connect = |identity: Option<(X509, PKey<Private>)>| {
+            let ca = ca.clone();
+            async move {
+                let mut connector = SslConnector::builder(SslMethod::tls_client()).unwrap();
+                connector.cert_store_mut().add_cert(ca).unwrap();
+                if let Some((cert, key)) = identity {
+                    connector.set_certificate(&cert).unwrap();
+                    connector.set_private_key(&key).unwrap();
+                }
+                let ssl = connector
+                    .build()
+                    .configure()
+                    .unwrap()
+                    .into_ssl("localhost")
+                    .unwrap();
+
+                let stream = TcpStream::connect(address).await.unwrap();
+                let mut stream = SslStream::new(ssl, stream).unwrap();
+                if Pin::new(&mut stream).connect().await.is_ok() {
+                    let _ = stream.write_all(b"hi").await;
+                }
+            }
+        };
+
+        // Known identity
+        let client_task = tokio::spawn(connect(Some((client, client_key))));
+        let (stream, _) = listener.accept().await.unwrap();
+        let (mut stream, identity) = tls.accept(stream).await.unwrap();
+        let identity = identity.unwrap();
+        assert_eq!(identity.name, "indexer.internal");
+        assert!(identity.policy.unwrap().allows("eth_getLogs"));
+        let mut hi = [0u8; 2];
+        stream.read_exact(&mut hi).await.unwrap();
+        assert_eq!(&hi, b"hi");
+        client_task.await.unwrap();
+
+        // Valid certificate, but not one we have a policy for
+        let client_task = tokio::spawn(connect(Some((stranger, stranger_key))));
+        let (stream, _) = listener.accept().await.unwrap();
+        assert!(matches!(
+            tls.accept(stream).await,
+            Err(MtlsError::UnknownIdentity(names)) if names == vec!["stranger"]
+        ));
+        client_task.await.unwrap();
+
+        // No certificate at all
+        let client_task = tokio::spawn(connect(None));
+        let (stream, _) = listener.accept().await.unwrap();
+        assert!(matches!(
+            tls.accept(stream).await,
+            Err(MtlsError::Handshake(_))
+        ));
+        client_task.await.unwrap();
+    }
+}
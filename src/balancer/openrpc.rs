@@ -0,0 +1,578 @@
+// OpenRPC document served at `GET /openrpc.json`.
+//
+// Lists every method blutgang accepts so tooling can introspect the gateway.
+// The document is built from the running config on every request: extensions
+// that are turned off are left out, wallet methods only show up if there's
+// something to send them to, and clients with a certificate policy only see
+// the methods they're allowed to call. Admin methods are never listed, the
+// admin namespace isn't something to advertise to everyone who can reach us.
+// Methods with a deprecation warning are flagged as deprecated.
+use crate::{
+    balancer::{
+        block_range::{
+            BLOCK_RANGE,
+            MAX_BLOCK_RANGE,
+        },
+        logs_paged::{
+            LOGS_PAGED,
+            MAX_PAGE_SIZE,
+        },
+        mtls::ClientIdentity,
+        wallet::WALLET_METHODS,
+    },
+    config::types::{
+        Settings,
+        WalletPolicy,
+    },
+    transactions::tracker::NEXT_NONCE,
+    websocket::{
+        confirmed::CONFIRMED_LOGS,
+        reorgs::REORGS,
+        tx_status::TX_STATUS,
+    },
+};
+
+use serde_json::{
+    json,
+    Value,
+};
+
+pub const OPENRPC_PATH: &str = "/openrpc.json";
+
+// Version of the OpenRPC spec we follow
+const OPENRPC_VERSION: &str = "1.2.6";
+
+// Rough shape of a param or result
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Quantity,
+    Data,
+    Address,
+    Hash,
+    Block,
+    Bool,
+    Object,
+    Array,
+    String,
+    Any,
+}
+
+impl Kind {
+    fn schema(self) -> Value {
+        let quantity = json!({"type": "string", "pattern": "^0x([1-9a-f][0-9a-f]*|0)$"});
+        let hash = json!({"type": "string", "pattern": "^0x[0-9a-f]{64}$"});
+        match self {
+            Kind::Quantity => quantity,
+            Kind::Data => json!({"type": "string", "pattern": "^0x[0-9a-f]*$"}),
+            Kind::Address => json!({"type": "string", "pattern": "^0x[0-9a-fA-F]{40}$"}),
+            Kind::Hash => hash,
+            Kind::Block => {
+                json!({"oneOf": [
+                    quantity,
+                    {"type": "string", "enum": ["earliest", "latest", "safe", "finalized", "pending"]},
+                    {"type": "object"},
+                ]})
+            }
+            Kind::Bool => json!({"type": "boolean"}),
+            Kind::Object => json!({"type": "object"}),
+            Kind::Array => json!({"type": "array"}),
+            Kind::String => json!({"type": "string"}),
+            Kind::Any => json!({}),
+        }
+    }
+}
+
+// Param name, shape and whether it's required
+type Param = (&'static str, Kind, bool);
+
+struct MethodDoc {
+    name: &'static str,
+    summary: &'static str,
+    params: &'static [Param],
+    result: Kind,
+}
+
+const fn method(
+    name: &'static str,
+    summary: &'static str,
+    params: &'static [Param],
+    result: Kind,
+) -> MethodDoc {
+    MethodDoc {
+        name,
+        summary,
+        params,
+        result,
+    }
+}
+
+// Standard methods we forward to our nodes
+const STANDARD_METHODS: &[MethodDoc] = &[
+    method(
+        "web3_clientVersion",
+        "Client version of the node",
+        &[],
+        Kind::String,
+    ),
+    method(
+        "web3_sha3",
+        "Keccak-256 of the given data",
+        &[("data", Kind::Data, true)],
+        Kind::Hash,
+    ),
+    method("net_version", "Network id", &[], Kind::String),
+    method(
+        "net_listening",
+        "Whether the node listens for peers",
+        &[],
+        Kind::Bool,
+    ),
+    method(
+        "net_peerCount",
+        "Connected peers of all our nodes, summed or the largest",
+        &[],
+        Kind::Quantity,
+    ),
+    method("eth_chainId", "Chain id", &[], Kind::Quantity),
+    method(
+        "eth_syncing",
+        "False if any of our nodes is synced, else the most advanced sync status",
+        &[],
+        Kind::Any,
+    ),
+    method(
+        "eth_blockNumber",
+        "Number of the most recent block",
+        &[],
+        Kind::Quantity,
+    ),
+    method("eth_gasPrice", "Current gas price", &[], Kind::Quantity),
+    method(
+        "eth_maxPriorityFeePerGas",
+        "Suggested priority fee",
+        &[],
+        Kind::Quantity,
+    ),
+    method(
+        "eth_blobBaseFee",
+        "Current blob base fee",
+        &[],
+        Kind::Quantity,
+    ),
+    method(
+        "eth_feeHistory",
+        "Fee history over a range of blocks",
+        &[
+            ("blockCount", Kind::Quantity, true),
+            ("newestBlock", Kind::Block, true),
+            ("rewardPercentiles", Kind::Array, false),
+        ],
+        Kind::Object,
+    ),
+    method(
+        "eth_getBalance",
+        "Balance of an account",
+        &[
+            ("address", Kind::Address, true),
+            ("block", Kind::Block, true),
+        ],
+        Kind::Quantity,
+    ),
+    method(
+        "eth_getCode",
+        "Code of an account",
+        &[
+            ("address", Kind::Address, true),
+            ("block", Kind::Block, true),
+        ],
+        Kind::Data,
+    ),
+    method(
+        "eth_getStorageAt",
+        "Value of a storage slot",
+        &[
+            ("address", Kind::Address, true),
+            ("slot", Kind::Quantity, true),
+            ("block", Kind::Block, true),
+        ],
+        Kind::Data,
+    ),
+    method(
+        "eth_getTransactionCount",
+        "Nonce of an account",
+        &[
+            ("address", Kind::Address, true),
+            ("block", Kind::Block, true),
+        ],
+        Kind::Quantity,
+    ),
+    method(
+        "eth_getProof",
+        "Merkle proof of an account and its storage",
+        &[
+            ("address", Kind::Address, true),
+            ("storageKeys", Kind::Array, true),
+            ("block", Kind::Block, true),
+        ],
+        Kind::Object,
+    ),
+    method(
+        "eth_call",
+        "Execute a call without creating a transaction",
+        &[
+            ("transaction", Kind::Object, true),
+            ("block", Kind::Block, false),
+            ("stateOverrides", Kind::Object, false),
+        ],
+        Kind::Data,
+    ),
+    method(
+        "eth_estimateGas",
+        "Gas a transaction would use",
+        &[
+            ("transaction", Kind::Object, true),
+            ("block", Kind::Block, false),
+        ],
+        Kind::Quantity,
+    ),
+    method(
+        "eth_createAccessList",
+        "Access list a transaction would use",
+        &[
+            ("transaction", Kind::Object, true),
+            ("block", Kind::Block, false),
+        ],
+        Kind::Object,
+    ),
+    method(
+        "eth_getBlockByNumber",
+        "Block by number",
+        &[
+            ("block", Kind::Block, true),
+            ("fullTransactions", Kind::Bool, true),
+        ],
+        Kind::Object,
+    ),
+    method(
+        "eth_getBlockByHash",
+        "Block by hash",
+        &[
+            ("blockHash", Kind::Hash, true),
+            ("fullTransactions", Kind::Bool, true),
+        ],
+        Kind::Object,
+    ),
+    method(
+        "eth_getBlockTransactionCountByNumber",
+        "Number of transactions in a block",
+        &[("block", Kind::Block, true)],
+        Kind::Quantity,
+    ),
+    method(
+        "eth_getBlockTransactionCountByHash",
+        "Number of transactions in a block",
+        &[("blockHash", Kind::Hash, true)],
+        Kind::Quantity,
+    ),
+    method(
+        "eth_getBlockReceipts",
+        "Receipts of every transaction in a block",
+        &[("block", Kind::Block, true)],
+        Kind::Array,
+    ),
+    method(
+        "eth_getTransactionByHash",
+        "Transaction by hash",
+        &[("transactionHash", Kind::Hash, true)],
+        Kind::Object,
+    ),
+    method(
+        "eth_getTransactionByBlockNumberAndIndex",
+        "Transaction by block number and index",
+        &[
+            ("block", Kind::Block, true),
+            ("index", Kind::Quantity, true),
+        ],
+        Kind::Object,
+    ),
+    method(
+        "eth_getTransactionByBlockHashAndIndex",
+        "Transaction by block hash and index",
+        &[
+            ("blockHash", Kind::Hash, true),
+            ("index", Kind::Quantity, true),
+        ],
+        Kind::Object,
+    ),
+    method(
+        "eth_getTransactionReceipt",
+        "Receipt of a transaction",
+        &[("transactionHash", Kind::Hash, true)],
+        Kind::Object,
+    ),
+    method(
+        "eth_sendRawTransaction",
+        "Broadcast a signed transaction",
+        &[("transaction", Kind::Data, true)],
+        Kind::Hash,
+    ),
+    method(
+        "eth_getLogs",
+        "Logs matching a filter",
+        &[("filter", Kind::Object, true)],
+        Kind::Array,
+    ),
+    method(
+        "eth_newFilter",
+        "Install a log filter",
+        &[("filter", Kind::Object, true)],
+        Kind::Quantity,
+    ),
+    method(
+        "eth_newBlockFilter",
+        "Install a block filter",
+        &[],
+        Kind::Quantity,
+    ),
+    method(
+        "eth_newPendingTransactionFilter",
+        "Install a pending transaction filter",
+        &[],
+        Kind::Quantity,
+    ),
+    method(
+        "eth_getFilterChanges",
+        "Changes since the filter was last polled",
+        &[("filterId", Kind::Quantity, true)],
+        Kind::Array,
+    ),
+    method(
+        "eth_getFilterLogs",
+        "Every log matching a filter",
+        &[("filterId", Kind::Quantity, true)],
+        Kind::Array,
+    ),
+    method(
+        "eth_uninstallFilter",
+        "Uninstall a filter",
+        &[("filterId", Kind::Quantity, true)],
+        Kind::Bool,
+    ),
+];
+
+// Methods only available over WS
+const SUBSCRIPTION_METHODS: &[MethodDoc] = &[
+    method(
+        "eth_subscribe",
+        "Subscribe to newHeads, logs, newPendingTransactions or blutgang_confirmedLogs (WS only)",
+        &[
+            ("kind", Kind::String, true),
+            ("options", Kind::Object, false),
+        ],
+        Kind::String,
+    ),
+    method(
+        "eth_unsubscribe",
+        "Cancel an eth_subscribe subscription (WS only)",
+        &[("subscriptionId", Kind::String, true)],
+        Kind::Bool,
+    ),
+    method(
+        "blutgang_subscribe",
+        "Subscribe to reorgs or txStatus events, sent as blutgang_subscription (WS only)",
+        &[
+            ("kind", Kind::String, true),
+            ("transactionHash", Kind::Hash, false),
+        ],
+        Kind::String,
+    ),
+    method(
+        "blutgang_unsubscribe",
+        "Cancel a blutgang_subscribe subscription (WS only)",
+        &[("subscriptionId", Kind::String, true)],
+        Kind::Bool,
+    ),
+];
+
+fn method_to_value(method: &MethodDoc) -> Value {
+    let params = method
+        .params
+        .iter()
+        .map(|(name, kind, required)| {
+            json!({"name": name, "required": required, "schema": kind.schema()})
+        })
+        .collect::<Vec<Value>>();
+
+    json!({
+        "name": method.name,
+        "summary": method.summary,
+        "params": params,
+        "result": {"name": "result", "schema": method.result.schema()},
+    })
+}
+
+// Methods blutgang handles itself on the regular address
+fn extension_methods(settings: &Settings) -> Vec<Value> {
+    let mut methods = vec![
+        json!({
+            "name": BLOCK_RANGE,
+            "summary": format!("Every block in [start, end], at most {} at a time", MAX_BLOCK_RANGE),
+            "params": [
+                {"name": "start", "required": true, "schema": Kind::Quantity.schema()},
+                {"name": "end", "required": true, "schema": Kind::Quantity.schema()},
+                {"name": "fullTransactions", "required": false, "schema": Kind::Bool.schema()},
+            ],
+            "result": {"name": "blocks", "schema": Kind::Array.schema()},
+        }),
+        json!({
+            "name": LOGS_PAGED,
+            "summary": format!("eth_getLogs a page of at most {} logs at a time, with a cursor for the next", MAX_PAGE_SIZE),
+            "params": [
+                {"name": "filter", "required": true, "schema": Kind::Object.schema()},
+                {"name": "options", "required": false, "schema": Kind::Object.schema()},
+            ],
+            "result": {"name": "page", "schema": Kind::Object.schema()},
+        }),
+    ];
+
+    if settings.nonce_tracking {
+        methods.push(json!({
+            "name": NEXT_NONCE,
+            "summary": "Nonce the next transaction of an account should use, counting ones still pending",
+            "params": [{"name": "address", "required": true, "schema": Kind::Address.schema()}],
+            "result": {"name": "nonce", "schema": Kind::Quantity.schema()},
+        }));
+    }
+
+    if settings.is_ws {
+        methods.extend(SUBSCRIPTION_METHODS.iter().map(method_to_value));
+    }
+
+    methods
+}
+
+// OpenRPC document for `settings`, as seen by `identity`
+pub fn openrpc_document(settings: &Settings, identity: Option<&ClientIdentity>) -> Value {
+    let mut methods: Vec<Value> = STANDARD_METHODS.iter().map(method_to_value).collect();
+
+    // No point listing methods we'd reject anyway
+    if !matches!(settings.wallet, WalletPolicy::Reject) {
+        methods.extend(WALLET_METHODS.iter().map(|name| {
+            json!({
+                "name": name,
+                "summary": "Wallet method, sent to the configured wallet node or signer",
+                "params": [],
+                "result": {"name": "result", "schema": Kind::Any.schema()},
+            })
+        }));
+    }
+    methods.extend(extension_methods(settings));
+
+    // Only what the client's certificate policy lets it call
+    let policy = identity.and_then(|identity| identity.policy.as_ref());
+    if let Some(policy) = policy {
+        methods.retain(|method| policy.allows(method["name"].as_str().unwrap_or_default()));
+    }
+
+    // Let tooling flag methods we're about to stop serving
+    if let Some(deprecations) = &settings.deprecations {
+        for method in methods.iter_mut() {
+            let name = method["name"].as_str().unwrap_or_default();
+            if let Some(warning) = deprecations.warning(name) {
+                method["deprecated"] = true.into();
+                method["description"] = warning.into();
+            }
+        }
+    }
+
+    json!({
+        "openrpc": OPENRPC_VERSION,
+        "info": {
+            "title": "blutgang",
+            "description": "Ethereum JSON-RPC load balancer",
+            "version": env!("CARGO_PKG_VERSION"),
+        },
+        "servers": [{"name": "blutgang", "url": format!("http://{}", settings.address)}],
+        "methods": methods,
+        "x-subscriptions": if settings.is_ws {
+            json!(["newHeads", "logs", "newPendingTransactions", CONFIRMED_LOGS, REORGS, TX_STATUS])
+        } else {
+            json!([])
+        },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        balancer::mtls::AuthPolicy,
+        config::types::DeprecationSettings,
+    };
+
+    fn names(document: &Value) -> Vec<&str> {
+        document["methods"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|method| method["name"].as_str().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_openrpc_document() {
+        let mut settings = Settings::default();
+        let document = openrpc_document(&settings, None);
+        let methods = names(&document);
+        assert_eq!(document["openrpc"], OPENRPC_VERSION);
+        assert!(methods.contains(&"eth_getLogs"));
+        assert!(methods.contains(&BLOCK_RANGE));
+        assert!(methods.contains(&LOGS_PAGED));
+        assert!(methods.contains(&"blutgang_subscribe"));
+        assert!(!methods.contains(&"blutgang_flush_cache"));
+        assert!(!methods.contains(&"blutgang_rpc_list"));
+        assert!(!methods.contains(&NEXT_NONCE));
+        assert!(!methods.contains(&"eth_sendTransaction"));
+
+        let call = &document["methods"][methods.iter().position(|m| *m == "eth_call").unwrap()];
+        assert_eq!(call["params"][0]["name"], "transaction");
+        assert_eq!(call["params"][1]["required"], false);
+
+        settings.is_ws = false;
+        settings.nonce_tracking = true;
+        let document = openrpc_document(&settings, None);
+        let methods = names(&document);
+        assert!(methods.contains(&NEXT_NONCE));
+        assert!(!methods.contains(&"blutgang_subscribe"));
+
+        settings.deprecations = Some(DeprecationSettings {
+            methods: [("eth_getCode".to_string(), "going away".to_string())].into(),
+            ..Default::default()
+        });
+        let document = openrpc_document(&settings, None);
+        let code = &document["methods"][names(&document)
+            .iter()
+            .position(|m| *m == "eth_getCode")
+            .unwrap()];
+        assert_eq!(code["deprecated"], true);
+        assert_eq!(code["description"], "going away");
+    }
+
+    #[test]
+    fn test_openrpc_identity() {
+        let identity = ClientIdentity {
+            name: "indexer".to_string(),
+            policy: Some(AuthPolicy {
+                methods: vec!["eth_get*".to_string(), BLOCK_RANGE.to_string()],
+            }),
+        };
+        let document = openrpc_document(&Settings::default(), Some(&identity));
+        let methods = names(&document);
+        assert!(methods.contains(&"eth_getLogs"));
+        assert!(methods.contains(&BLOCK_RANGE));
+        assert!(!methods.contains(&"eth_sendRawTransaction"));
+        assert!(methods
+            .iter()
+            .all(|m| !m.starts_with("blutgang_") || *m == BLOCK_RANGE));
+    }
+}
@@ -0,0 +1,115 @@
+// Client headers and WS subprotocols we pass on.
+//
+// Some providers key analytics or auth off request headers, like the
+// `User-Agent` of the app calling them or a tracing header. Headers listed in
+// `[blutgang.passthrough] headers` are copied from the client's request onto
+// the requests we send upstream for it. Everything else stays with us, like
+// before.
+//
+// Our WS connections to nodes are shared by every client, so they can't
+// carry any one client's headers. What clients can do is negotiate one of
+// the listed `subprotocols` when they connect to us over WS.
+use crate::config::types::PassthroughSettings;
+
+use hyper::{
+    header::HeaderValue,
+    HeaderMap,
+};
+
+pub const SUBPROTOCOL_HEADER: &str = "sec-websocket-protocol";
+
+// Allowed headers of a client request, to send upstream with it
+pub fn forwarded_headers(
+    settings: Option<&PassthroughSettings>,
+    headers: &HeaderMap,
+) -> Vec<(String, String)> {
+    let settings = match settings {
+        Some(settings) => settings,
+        None => return Vec::new(),
+    };
+
+    settings
+        .headers
+        .iter()
+        .flat_map(|name| {
+            headers
+                .get_all(name.as_str())
+                .iter()
+                .filter_map(|value| value.to_str().ok())
+                .map(move |value| (name.clone(), value.to_string()))
+        })
+        .collect()
+}
+
+// First subprotocol the client offered that we allow, if any
+pub fn negotiate_subprotocol(
+    settings: Option<&PassthroughSettings>,
+    headers: &HeaderMap,
+) -> Option<HeaderValue> {
+    let settings = settings?;
+
+    headers
+        .get_all(SUBPROTOCOL_HEADER)
+        .iter()
+        .filter_map(|offered| offered.to_str().ok())
+        .flat_map(|offered| offered.split(','))
+        .map(str::trim)
+        .find(|offered| {
+            settings
+                .subprotocols
+                .iter()
+                .any(|allowed| allowed == offered)
+        })
+        .and_then(|protocol| HeaderValue::from_str(protocol).ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings() -> PassthroughSettings {
+        PassthroughSettings {
+            headers: vec!["user-agent".to_string(), "x-trace-id".to_string()],
+            subprotocols: vec!["jsonrpc".to_string(), "jsonrpc-batch".to_string()],
+        }
+    }
+
+    #[test]
+    fn test_forwarded_headers() {
+        let mut headers = HeaderMap::new();
+        headers.insert("User-Agent", HeaderValue::from_static("wallet/1.0"));
+        headers.append("x-trace-id", HeaderValue::from_static("a"));
+        headers.append("x-trace-id", HeaderValue::from_static("b"));
+        headers.insert("authorization", HeaderValue::from_static("Bearer secret"));
+
+        assert_eq!(
+            forwarded_headers(Some(&settings()), &headers),
+            vec![
+                ("user-agent".to_string(), "wallet/1.0".to_string()),
+                ("x-trace-id".to_string(), "a".to_string()),
+                ("x-trace-id".to_string(), "b".to_string()),
+            ]
+        );
+        assert!(forwarded_headers(None, &headers).is_empty());
+    }
+
+    #[test]
+    fn test_negotiate_subprotocol() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            SUBPROTOCOL_HEADER,
+            HeaderValue::from_static("graphql-ws, jsonrpc-batch"),
+        );
+        headers.append(SUBPROTOCOL_HEADER, HeaderValue::from_static("jsonrpc"));
+
+        // The client's preference wins
+        assert_eq!(
+            negotiate_subprotocol(Some(&settings()), &headers),
+            Some(HeaderValue::from_static("jsonrpc-batch"))
+        );
+        assert_eq!(negotiate_subprotocol(None, &headers), None);
+
+        headers.insert(SUBPROTOCOL_HEADER, HeaderValue::from_static("graphql-ws"));
+        assert_eq!(negotiate_subprotocol(Some(&settings()), &headers), None);
+    }
+}
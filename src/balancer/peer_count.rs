@@ -0,0 +1,208 @@
+// `net_peerCount` for the whole fleet.
+//
+// Every node has its own peers, so the count of whichever node we'd pick
+// changes from one request to the next. We ask all active nodes instead and
+// answer with the sum of their counts, or the largest one, depending on
+// `[blutgang.peer_count]`. Peers come and go slowly, so the answer is kept for
+// `cache_ttl` instead of asking every node on every request.
+use crate::{
+    config::types::{
+        PeerCountAggregate,
+        PeerCountSettings,
+    },
+    rpc::types::hex_to_decimal,
+    Rpc,
+};
+
+use std::{
+    collections::HashMap,
+    sync::{
+        Arc,
+        Mutex,
+        RwLock,
+    },
+    time::{
+        Duration,
+        Instant,
+    },
+};
+
+use futures::future::join_all;
+use serde_json::{
+    json,
+    Value,
+};
+use tokio::time::timeout;
+
+// Fleet count from the counts of our nodes. `None` if none of them answered.
+pub fn aggregate_peer_count(counts: &[u64], aggregate: PeerCountAggregate) -> Option<u64> {
+    match aggregate {
+        PeerCountAggregate::Sum if !counts.is_empty() => {
+            Some(
+                counts
+                    .iter()
+                    .fold(0u64, |sum, count| sum.saturating_add(*count)),
+            )
+        }
+        PeerCountAggregate::Sum => None,
+        PeerCountAggregate::Max => counts.iter().max().copied(),
+    }
+}
+
+// Last count we answered with, per node group
+#[derive(Debug, Default)]
+pub struct PeerCounts {
+    cached: Mutex<HashMap<Option<String>, (u64, Instant)>>,
+}
+
+impl PeerCounts {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn cached(&self, group: Option<&str>, cache_ttl: Duration) -> Option<u64> {
+        let cached = self.cached.lock().unwrap_or_else(|e| e.into_inner());
+        cached
+            .get(&group.map(str::to_string))
+            .filter(|(_, at)| at.elapsed() < cache_ttl)
+            .map(|(count, _)| *count)
+    }
+
+    // Answer to `tx` from all our active nodes, or the ones in `group` if
+    // set, if it's a `net_peerCount` call
+    pub async fn response(
+        &self,
+        tx: &Value,
+        id: Value,
+        rpc_list: &Arc<RwLock<Vec<Rpc>>>,
+        group: Option<&str>,
+        settings: &PeerCountSettings,
+        ttl: u128,
+    ) -> Option<Value> {
+        if tx["method"] != "net_peerCount" {
+            return None;
+        }
+
+        let count = match self.cached(group, settings.cache_ttl) {
+            Some(count) => count,
+            None => {
+                let count = self.fetch(rpc_list, group, settings.aggregate, ttl).await?;
+                self.cached
+                    .lock()
+                    .unwrap_or_else(|e| e.into_inner())
+                    .insert(group.map(str::to_string), (count, Instant::now()));
+                count
+            }
+        };
+
+        Some(json!({"jsonrpc": "2.0", "id": id, "result": format!("0x{:x}", count)}))
+    }
+
+    async fn fetch(
+        &self,
+        rpc_list: &Arc<RwLock<Vec<Rpc>>>,
+        group: Option<&str>,
+        aggregate: PeerCountAggregate,
+        ttl: u128,
+    ) -> Option<u64> {
+        let nodes: Vec<Rpc> = rpc_list
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .iter()
+            .filter(|rpc| group.map_or(true, |group| rpc.in_group(group)))
+            .cloned()
+            .collect();
+        let request_timeout = Duration::from_millis(ttl.try_into().unwrap_or(u64::MAX));
+        let responses = join_all(
+            nodes
+                .iter()
+                .map(|rpc| timeout(request_timeout, rpc.call("net_peerCount", json!([])))),
+        )
+        .await;
+
+        let counts: Vec<u64> = responses
+            .into_iter()
+            .filter_map(|response| {
+                let count = response.ok()?.ok()?;
+                hex_to_decimal(count.as_str()?).ok()
+            })
+            .collect();
+
+        // Let the request go upstream as usual if nobody answered, it gets the error
+        aggregate_peer_count(&counts, aggregate)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::node::MockNode;
+
+    #[test]
+    fn test_aggregate_peer_count() {
+        assert_eq!(aggregate_peer_count(&[], PeerCountAggregate::Sum), None);
+        assert_eq!(aggregate_peer_count(&[], PeerCountAggregate::Max), None);
+        assert_eq!(
+            aggregate_peer_count(&[3, 10, 5], PeerCountAggregate::Sum),
+            Some(18)
+        );
+        assert_eq!(
+            aggregate_peer_count(&[3, 10, 5], PeerCountAggregate::Max),
+            Some(10)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_peer_count_response() {
+        let first = MockNode::spawn(1).await.unwrap();
+        first.set_response("net_peerCount", json!("0x3"));
+        let second = MockNode::spawn(1).await.unwrap();
+        second.set_response("net_peerCount", json!("0xa"));
+
+        let rpc = |node: &MockNode| Rpc::new(node.http_url(), None, 6, 0, 10.0);
+        let rpc_list = Arc::new(RwLock::new(vec![rpc(&first), rpc(&second)]));
+        let tx = json!({"jsonrpc": "2.0", "id": 7, "method": "net_peerCount", "params": []});
+        let peer_counts = PeerCounts::new();
+
+        let settings = PeerCountSettings::default();
+        let response = peer_counts
+            .response(&tx, 7.into(), &rpc_list, None, &settings, 1000)
+            .await
+            .unwrap();
+        assert_eq!(response["result"], "0xd");
+        assert_eq!(response["id"], 7);
+
+        // Cached, the nodes aren't asked again
+        second.set_response("net_peerCount", json!("0x14"));
+        let response = peer_counts
+            .response(&tx, 7.into(), &rpc_list, None, &settings, 1000)
+            .await
+            .unwrap();
+        assert_eq!(response["result"], "0xd");
+        assert_eq!(first.request_count(), 1);
+
+        let settings = PeerCountSettings {
+            aggregate: PeerCountAggregate::Max,
+            cache_ttl: Duration::ZERO,
+        };
+        let response = peer_counts
+            .response(&tx, 7.into(), &rpc_list, None, &settings, 1000)
+            .await
+            .unwrap();
+        assert_eq!(response["result"], "0x14");
+
+        // Groups only see their own nodes
+        assert_eq!(
+            peer_counts
+                .response(&tx, 7.into(), &rpc_list, Some("archive"), &settings, 1000)
+                .await,
+            None
+        );
+
+        let other = json!({"jsonrpc": "2.0", "id": 7, "method": "eth_chainId", "params": []});
+        assert!(peer_counts
+            .response(&other, 7.into(), &rpc_list, None, &settings, 1000)
+            .await
+            .is_none());
+    }
+}
@@ -0,0 +1,313 @@
+// Fill the cache before we start serving requests.
+//
+// A freshly restarted instance has nothing cached for the tip, so the first
+// wave of traffic all goes upstream at once. With a `[prewarm]` table we fetch
+// the latest blocks, the chain id and any configured `eth_call`s on startup and
+// cache them under the same keys client requests hash to.
+use crate::{
+    balancer::{
+        block_range::{
+            get_block_range,
+            BlockRange,
+        },
+        cache_entry::{
+            insert_entry,
+            CacheKey,
+        },
+        processing::{
+            cache_querry,
+            CacheArgs,
+        },
+    },
+    config::types::{
+        PrewarmCall,
+        PrewarmSettings,
+    },
+    log_info,
+    log_wrn,
+    rpc::types::Rpc,
+};
+
+use std::{
+    sync::{
+        Arc,
+        RwLock,
+    },
+    time::{
+        Duration,
+        Instant,
+    },
+};
+
+use futures::stream::{
+    self,
+    StreamExt,
+};
+use serde_json::{
+    json,
+    Value,
+};
+use tokio::time::timeout;
+
+// Calls we make at the same time
+const CONCURRENCY: usize = 16;
+
+// What ended up in the cache
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct PrewarmStats {
+    pub blocks: usize,
+    pub chain_id: bool,
+    pub calls: usize,
+}
+
+// The request as clients send it, without an id so it hashes the same
+fn client_request(method: &str, params: Value) -> Value {
+    json!({"jsonrpc": "2.0", "id": null, "method": method, "params": params})
+}
+
+async fn send(rpc: &Rpc, mut request: Value, ttl: u128) -> Option<String> {
+    request["id"] = 1.into();
+    match timeout(
+        Duration::from_millis(ttl.try_into().unwrap_or(u64::MAX)),
+        rpc.send_request(request),
+    )
+    .await
+    {
+        Ok(Ok(rx)) => Some(rx),
+        Ok(Err(e)) => {
+            log_wrn!("Pre-warm request failed: {}", e);
+            None
+        }
+        Err(_) => {
+            log_wrn!("Pre-warm request timed out");
+            None
+        }
+    }
+}
+
+// The chain id never changes, so it's cached as is
+async fn prewarm_chain_id(rpc: &Rpc, cache_args: &CacheArgs, ttl: u128) -> bool {
+    let request = client_request("eth_chainId", json!([]));
+    let rx = match send(rpc, request.clone(), ttl).await {
+        Some(rx) => rx,
+        None => return false,
+    };
+
+    let mut response: Value = match serde_json::from_str(&rx) {
+        Ok(response) => response,
+        Err(_) => return false,
+    };
+    if !response["result"].is_string() {
+        return false;
+    }
+    response["id"] = Value::Null;
+
+    let tx_hash = CacheKey::new(&request);
+    let size = match insert_entry(&cache_args.cache, &tx_hash, response.to_string().as_bytes()) {
+        Ok(size) => size,
+        Err(_) => return false,
+    };
+    cache_args.memory.record_insert(tx_hash.as_bytes(), size);
+    true
+}
+
+// Calls at `latest` are pinned to the current head and keyed by its number,
+// so they're never served as `latest` once the head moves on
+async fn prewarm_call(
+    rpc: &Rpc,
+    call: &PrewarmCall,
+    latest: u64,
+    cache_args: &CacheArgs,
+    ttl: u128,
+) -> bool {
+    let mut request = client_request(
+        "eth_call",
+        json!([{"to": call.to, "data": call.data}, call.block]),
+    );
+    if call.block == "latest" {
+        request["params"][1] = format!("0x{:x}", latest).into();
+    }
+    let tx_hash = CacheKey::new(&request);
+
+    let mut pinned = request.clone();
+    pinned["id"] = 1.into();
+
+    let mut rx = match send(rpc, pinned.clone(), ttl).await {
+        Some(rx) => rx,
+        None => return false,
+    };
+    cache_querry(&mut rx, pinned, tx_hash, Some(&rpc.name), cache_args);
+
+    cache_args
+        .cache
+        .contains_key(tx_hash.as_bytes())
+        .unwrap_or(false)
+}
+
+pub async fn prewarm_cache(
+    settings: &PrewarmSettings,
+    rpc_list: &Arc<RwLock<Vec<Rpc>>>,
+    cache_args: &CacheArgs,
+    ttl: u128,
+) -> PrewarmStats {
+    let mut stats = PrewarmStats::default();
+
+    let rpc = match rpc_list.read().unwrap().first() {
+        Some(rpc) => rpc.clone(),
+        None => return stats,
+    };
+    let time = Instant::now();
+
+    if settings.chain_id {
+        stats.chain_id = prewarm_chain_id(&rpc, cache_args, ttl).await;
+    }
+
+    // Blocks and calls are relative to the current head
+    let latest = match timeout(
+        Duration::from_millis(ttl.try_into().unwrap_or(u64::MAX)),
+        rpc.block_number(),
+    )
+    .await
+    {
+        Ok(Ok(latest)) => latest,
+        Ok(Err(e)) => {
+            log_wrn!(
+                "Could not get the latest block to pre-warm the cache: {}",
+                e
+            );
+            return stats;
+        }
+        Err(_) => {
+            log_wrn!("Timed out getting the latest block to pre-warm the cache");
+            return stats;
+        }
+    };
+
+    if settings.blocks > 0 {
+        let range = BlockRange {
+            start: latest.saturating_sub(settings.blocks - 1),
+            end: latest,
+            full_tx: false,
+        };
+        match get_block_range(range, rpc_list, None, None, cache_args, ttl).await {
+            Ok(blocks) => stats.blocks = blocks.iter().filter(|block| !block.is_null()).count(),
+            Err(e) => {
+                log_wrn!("Could not pre-warm blocks: {}", e);
+            }
+        }
+    }
+
+    stats.calls = stream::iter(&settings.calls)
+        .map(|call| prewarm_call(&rpc, call, latest, cache_args, ttl))
+        .buffer_unordered(CONCURRENCY)
+        .filter(|cached| futures::future::ready(*cached))
+        .count()
+        .await;
+
+    log_info!(
+        "Pre-warmed the cache with {} blocks, {} calls{} in {:?}",
+        stats.blocks,
+        stats.calls,
+        if stats.chain_id {
+            " and the chain id"
+        } else {
+            ""
+        },
+        time.elapsed()
+    );
+
+    stats
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        balancer::cache_entry::get_entry,
+        mock::node::MockNode,
+    };
+
+    fn cache_args() -> CacheArgs {
+        CacheArgs {
+            cache: Arc::new(sled::Config::new().temporary(true).open().unwrap()),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_prewarm_cache() {
+        let node = MockNode::spawn(1).await.unwrap();
+        node.set_response("eth_blockNumber", json!("0x10"));
+        node.set_response("eth_chainId", json!("0x1"));
+        node.set_response("eth_getBlockByNumber", json!({"number": "0x10"}));
+        node.set_response("eth_call", json!("0x01"));
+        let rpc_list = Arc::new(RwLock::new(vec![Rpc::new(
+            node.http_url(),
+            None,
+            10,
+            0,
+            1.0,
+        )]));
+
+        let settings = PrewarmSettings {
+            blocks: 4,
+            chain_id: true,
+            calls: vec![PrewarmCall {
+                to: "0x01".to_string(),
+                data: "0x02".to_string(),
+                block: "latest".to_string(),
+            }],
+        };
+        let cache_args = cache_args();
+
+        let stats = prewarm_cache(&settings, &rpc_list, &cache_args, 1000).await;
+        assert_eq!(
+            stats,
+            PrewarmStats {
+                blocks: 4,
+                chain_id: true,
+                calls: 1,
+            }
+        );
+
+        // Client requests hash to the pre-warmed keys
+        let chain_id = client_request("eth_chainId", json!([]));
+        let cached = get_entry(&cache_args.cache, &CacheKey::new(&chain_id))
+            .unwrap()
+            .unwrap();
+        let cached: Value = serde_json::from_slice(&cached).unwrap();
+        assert_eq!(cached["result"], "0x1");
+
+        let block = client_request("eth_getBlockByNumber", json!(["0xd", false]));
+        assert!(cache_args
+            .cache
+            .contains_key(CacheKey::new(&block).as_bytes())
+            .unwrap());
+
+        // The `latest` call is cached for the head it ran at, not as `latest`
+        let call = |block: &str| {
+            client_request("eth_call", json!([{"to": "0x01", "data": "0x02"}, block]))
+        };
+        assert!(cache_args
+            .cache
+            .contains_key(CacheKey::new(&call("0x10")).as_bytes())
+            .unwrap());
+        assert!(!cache_args
+            .cache
+            .contains_key(CacheKey::new(&call("latest")).as_bytes())
+            .unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_prewarm_without_rpcs() {
+        let rpc_list = Arc::new(RwLock::new(vec![]));
+        let settings = PrewarmSettings {
+            blocks: 4,
+            chain_id: true,
+            calls: Vec::new(),
+        };
+
+        let stats = prewarm_cache(&settings, &rpc_list, &cache_args(), 1000).await;
+        assert_eq!(stats, PrewarmStats::default());
+    }
+}
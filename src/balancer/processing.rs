@@ -1,6 +1,14 @@
 use crate::{
     balancer::{
+        cache_entry::{
+            insert_entry_with_meta,
+            CacheKey,
+            EntryMeta,
+        },
+        ens::EnsCache,
         format::get_block_number_from_request,
+        hot_cache::HotCache,
+        memory::MemoryBudget,
         selection::cache_rules::{
             cache_method,
             cache_result,
@@ -22,7 +30,6 @@ use std::{
 
 use tokio::sync::watch;
 
-use blake3::Hash;
 use serde_json::Value;
 use simd_json::to_vec;
 use sled::Db;
@@ -32,17 +39,22 @@ pub struct CacheArgs {
     pub finalized_rx: watch::Receiver<u64>,
     pub named_numbers: Arc<RwLock<NamedBlocknumbers>>,
     pub cache: Arc<Db>,
-    pub head_cache: Arc<RwLock<BTreeMap<u64, Vec<String>>>>,
+    pub head_cache: Arc<RwLock<BTreeMap<u64, Vec<CacheKey>>>>,
+    pub memory: Arc<MemoryBudget>,
+    pub ens: Arc<EnsCache>,
+    pub hot: Arc<HotCache>,
 }
 
-impl CacheArgs {
-    #[allow(dead_code)]
-    pub fn default() -> Self {
+impl Default for CacheArgs {
+    fn default() -> Self {
         CacheArgs {
             finalized_rx: watch::channel(0).1,
             named_numbers: Arc::new(RwLock::new(NamedBlocknumbers::default())),
             cache: Arc::new(sled::Config::default().open().unwrap()),
             head_cache: Arc::new(RwLock::new(BTreeMap::new())),
+            memory: Arc::new(MemoryBudget::default()),
+            ens: Arc::new(EnsCache::default()),
+            hot: Arc::new(HotCache::default()),
         }
     }
 }
@@ -56,34 +68,53 @@ pub fn can_cache(method: &str, result: &str) -> bool {
 }
 
 // Check if we should cache the querry, and if so cache it in the DB
-pub fn cache_querry(rx: &mut str, method: Value, tx_hash: Hash, cache_args: &CacheArgs) {
+//
+// `source` is the name of the node that answered, if we know it
+pub fn cache_querry(
+    rx: &mut str,
+    method: Value,
+    tx_hash: CacheKey,
+    source: Option<&str>,
+    cache_args: &CacheArgs,
+) {
     let tx_string = method.to_string();
 
     if can_cache(&tx_string, rx) {
+        let meta = EntryMeta::new(method["method"].as_str().unwrap_or_default(), source);
         // Insert the response hash into the head_cache
         let num = get_block_number_from_request(method, &cache_args.named_numbers);
 
-        // Insert the key of the request we made into our `head_cache`
-        // so we can invalidate it and remove it from the DB if it reorgs.
         if let Some(num) = num {
-            if num > *cache_args.finalized_rx.borrow() {
-                let mut head_cache = cache_args.head_cache.write().unwrap();
-                head_cache.entry(num).or_default().push(tx_hash.to_string());
-            }
-
-            // Replace the id with Value::Null and insert the request
-            // TODO: kinda cringe how we do this gymnasctics of changing things back and forth
-            let mut rx_value: Value = unsafe { simd_json::serde::from_str(rx).unwrap() };
-            rx_value["id"] = Value::Null;
-
-            cache_args
-                .cache
-                .insert(tx_hash.as_bytes(), to_vec(&rx_value).unwrap().as_slice())
-                .unwrap();
+            insert_response(rx, tx_hash, num, &meta, cache_args);
         }
     }
 }
 
+// Cache `rx` as the response to a request for block `num`
+pub fn insert_response(
+    rx: &mut str,
+    tx_hash: CacheKey,
+    num: u64,
+    meta: &EntryMeta,
+    cache_args: &CacheArgs,
+) {
+    // Insert the key of the request we made into our `head_cache`
+    // so we can invalidate it and remove it from the DB if it reorgs.
+    if num > *cache_args.finalized_rx.borrow() {
+        let mut head_cache = cache_args.head_cache.write().unwrap();
+        head_cache.entry(num).or_default().push(tx_hash);
+    }
+
+    // Replace the id with Value::Null and insert the request
+    // TODO: kinda cringe how we do this gymnasctics of changing things back and forth
+    let mut rx_value: Value = unsafe { simd_json::serde::from_str(rx).unwrap() };
+    rx_value["id"] = Value::Null;
+
+    let rx_bytes = to_vec(&rx_value).unwrap();
+    let size = insert_entry_with_meta(&cache_args.cache, &tx_hash, &rx_bytes, Some(meta)).unwrap();
+    cache_args.memory.record_insert(tx_hash.as_bytes(), size);
+}
+
 pub fn update_rpc_latency(rpc_list: &Arc<RwLock<Vec<Rpc>>>, rpc_position: usize, time: Duration) {
     let mut rpc_list_guard = rpc_list.write().unwrap_or_else(|e| {
         // Handle the case where the RwLock is poisoned
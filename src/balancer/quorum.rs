@@ -0,0 +1,327 @@
+// Answers only enough nodes agree on.
+//
+// For methods in a `[blutgang.quorum.<class>]`, we send the request to
+// `nodes` nodes at once and only answer once `agree` of them returned the
+// same result, so a single lying or broken provider can't make us return a
+// wrong one. Results are compared after normalizing them: object keys are
+// sorted and hex strings lowercased, so nodes that format the same answer
+// differently still agree. Errors don't count towards any result.
+//
+// The request goes to the fastest nodes that can take it right now, from the
+// request's group if it has one. Without enough of them, or without a quorum,
+// we answer with an error instead of falling back to a single node, and these
+// requests never come from or go into the cache.
+use crate::{
+    balancer::selection::select::argsort,
+    config::types::QuorumSettings,
+    log_wrn,
+    Rpc,
+};
+
+use std::{
+    collections::HashMap,
+    sync::{
+        Arc,
+        RwLock,
+    },
+    time::Duration,
+};
+
+use futures::future::join_all;
+use serde_json::{
+    json,
+    Value,
+};
+use tokio::time::timeout;
+
+fn lowercase_hex(value: &mut Value) {
+    match value {
+        Value::String(string) if string.starts_with("0x") => {
+            string.make_ascii_lowercase();
+        }
+        Value::Array(values) => values.iter_mut().for_each(lowercase_hex),
+        Value::Object(map) => map.values_mut().for_each(lowercase_hex),
+        _ => {}
+    }
+}
+
+// Result of `response` the way we compare it, `None` if it doesn't have one
+fn normalize(response: &str) -> Option<String> {
+    let mut response: Value = serde_json::from_str(response).ok()?;
+    let mut result = response.get_mut("result")?.take();
+    lowercase_hex(&mut result);
+    // Keys of serde_json maps are sorted
+    Some(result.to_string())
+}
+
+// The result `agree` of `results` are the same for, if there is one, and how
+// many agreed on the most common one otherwise
+fn agreed(results: &[String], agree: usize) -> Result<&str, usize> {
+    let mut counts: HashMap<&str, usize> = HashMap::new();
+    for result in results {
+        *counts.entry(result).or_default() += 1;
+    }
+    match counts.into_iter().max_by_key(|(_, count)| *count) {
+        Some((result, count)) if count >= agree => Ok(result),
+        Some((_, count)) => Err(count),
+        None => Err(0),
+    }
+}
+
+// Answer `tx` once enough nodes agree on it, if it's in a quorum class.
+// Only nodes in `group` are asked if it's set.
+pub async fn quorum_response(
+    settings: &QuorumSettings,
+    tx: &Value,
+    id: Value,
+    rpc_list: &Arc<RwLock<Vec<Rpc>>>,
+    group: Option<&str>,
+    ttl: u128,
+) -> Option<Value> {
+    let method = tx["method"].as_str()?;
+    let (name, class) = settings.class(method)?;
+
+    let nodes: Vec<Rpc> = {
+        let rpc_list = rpc_list.read().unwrap_or_else(|e| e.into_inner());
+        argsort(&rpc_list)
+            .into_iter()
+            .map(|index| &rpc_list[index])
+            .filter(|rpc| group.map_or(true, |group| rpc.in_group(group)))
+            .filter(|rpc| rpc.is_available(tx))
+            .take(class.nodes)
+            .cloned()
+            .collect()
+    };
+    if nodes.len() < class.nodes {
+        log_wrn!(
+            "Not enough nodes for a quorum on {} ({}): {} available, {} needed",
+            method,
+            name,
+            nodes.len(),
+            class.nodes
+        );
+        return Some(json!({
+            "jsonrpc": "2.0",
+            "id": id,
+            "error": {
+                "code": -32603,
+                "message": format!(
+                    "Not enough nodes for a quorum: {} available, {} needed",
+                    nodes.len(),
+                    class.nodes
+                ),
+            },
+        }));
+    }
+    // Nodes take requests without an id as notifications
+    let mut request = tx.clone();
+    request["id"] = 1.into();
+    let request_timeout = Duration::from_millis(ttl.try_into().unwrap_or(u64::MAX));
+    let responses = join_all(
+        nodes
+            .iter()
+            .map(|rpc| timeout(request_timeout, rpc.send_request(request.clone()))),
+    )
+    .await;
+
+    let results: Vec<String> = responses
+        .into_iter()
+        .filter_map(|response| response.ok()?.ok())
+        .filter_map(|response| normalize(&response))
+        .collect();
+
+    match agreed(&results, class.agree) {
+        Ok(result) => {
+            let result: Value = serde_json::from_str(result).unwrap_or_default();
+            Some(json!({"jsonrpc": "2.0", "id": id, "result": result}))
+        }
+        Err(count) => {
+            log_wrn!(
+                "No quorum for {} ({}): {} of {} nodes agreed, {} needed",
+                method,
+                name,
+                count,
+                nodes.len(),
+                class.agree
+            );
+            Some(json!({
+                "jsonrpc": "2.0",
+                "id": id,
+                "error": {
+                    "code": -32603,
+                    "message": format!(
+                        "No quorum: {} of {} nodes agreed, {} needed",
+                        count,
+                        nodes.len(),
+                        class.agree
+                    ),
+                },
+            }))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::types::QuorumClass,
+        mock::node::MockNode,
+    };
+    use std::collections::BTreeMap;
+
+    fn settings(nodes: usize, agree: usize) -> QuorumSettings {
+        QuorumSettings {
+            classes: BTreeMap::from([(
+                "deposits".to_string(),
+                QuorumClass {
+                    methods: vec!["eth_getTransactionReceipt".to_string()],
+                    nodes,
+                    agree,
+                },
+            )]),
+        }
+    }
+
+    fn receipt_tx() -> Value {
+        json!({"jsonrpc": "2.0", "id": null, "method": "eth_getTransactionReceipt", "params": ["0x01"]})
+    }
+
+    async fn node(receipt: Value) -> (MockNode, Rpc) {
+        let node = MockNode::spawn(1).await.unwrap();
+        node.set_response("eth_getTransactionReceipt", receipt);
+        let rpc = Rpc::new(node.http_url(), None, 6, 0, 10.0);
+        (node, rpc)
+    }
+
+    #[test]
+    fn test_normalize() {
+        assert_eq!(
+            normalize(r#"{"jsonrpc":"2.0","id":1,"result":{"to":"0xABC","status":"0x1"}}"#),
+            normalize(r#"{"id":7,"result":{"status":"0x1","to":"0xabc"}}"#),
+        );
+        assert_eq!(normalize(r#"{"id":1,"error":{"code":-32000}}"#), None);
+        assert_eq!(
+            normalize(r#"{"id":1,"result":null}"#).as_deref(),
+            Some("null")
+        );
+    }
+
+    #[test]
+    fn test_agreed() {
+        let results = ["a", "b", "a"].map(str::to_string);
+        assert_eq!(agreed(&results, 2), Ok("a"));
+        assert_eq!(agreed(&results, 3), Err(2));
+        assert_eq!(agreed(&[], 1), Err(0));
+    }
+
+    #[tokio::test]
+    async fn test_quorum_response() {
+        let (_honest, honest_rpc) = node(json!({"status": "0x1", "to": "0xabc"})).await;
+        let (_shouting, shouting_rpc) = node(json!({"status": "0x1", "to": "0xABC"})).await;
+        let (_liar, liar_rpc) = node(json!({"status": "0x0", "to": "0xabc"})).await;
+        let mut rpcs = vec![honest_rpc, liar_rpc, shouting_rpc];
+        for (latency, rpc) in rpcs.iter_mut().enumerate() {
+            rpc.status.latency = latency as f64;
+        }
+        let rpc_list = Arc::new(RwLock::new(rpcs));
+
+        let response = quorum_response(
+            &settings(3, 2),
+            &receipt_tx(),
+            7.into(),
+            &rpc_list,
+            None,
+            1000,
+        )
+        .await
+        .unwrap();
+        assert_eq!(response["result"], json!({"status": "0x1", "to": "0xabc"}));
+        assert_eq!(response["id"], 7);
+
+        // Not enough of the two fastest agree
+        let response = quorum_response(
+            &settings(2, 2),
+            &receipt_tx(),
+            7.into(),
+            &rpc_list,
+            None,
+            1000,
+        )
+        .await
+        .unwrap();
+        assert_eq!(response["error"]["code"], -32603);
+        assert_eq!(
+            response["error"]["message"],
+            "No quorum: 1 of 2 nodes agreed, 2 needed"
+        );
+
+        // Other methods aren't our business
+        let call = json!({"jsonrpc": "2.0", "id": null, "method": "eth_call", "params": []});
+        assert!(
+            quorum_response(&settings(3, 2), &call, 7.into(), &rpc_list, None, 1000)
+                .await
+                .is_none()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_quorum_nodes() {
+        let (grouped, grouped_rpc) = node(json!({"status": "0x1"})).await;
+        let (other, other_rpc) = node(json!({"status": "0x1"})).await;
+        let (cooling, cooling_rpc) = node(json!({"status": "0x1"})).await;
+        let grouped_rpc = grouped_rpc.with_groups(vec!["archive".to_string()]);
+        let cooling_rpc = cooling_rpc.with_groups(vec!["archive".to_string()]);
+        cooling_rpc
+            .status
+            .cooldown
+            .start(Some(Duration::from_secs(60)), &Default::default());
+        let rpc_list = Arc::new(RwLock::new(vec![grouped_rpc, other_rpc, cooling_rpc]));
+
+        // Nodes outside the group and cooling down don't count
+        let response = quorum_response(
+            &settings(2, 1),
+            &receipt_tx(),
+            7.into(),
+            &rpc_list,
+            Some("archive"),
+            1000,
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            response["error"]["message"],
+            "Not enough nodes for a quorum: 1 available, 2 needed"
+        );
+        assert_eq!(grouped.request_count(), 0);
+
+        let response = quorum_response(
+            &settings(1, 1),
+            &receipt_tx(),
+            7.into(),
+            &rpc_list,
+            Some("archive"),
+            1000,
+        )
+        .await
+        .unwrap();
+        assert_eq!(response["result"], json!({"status": "0x1"}));
+        assert_eq!(grouped.request_count(), 1);
+        assert_eq!(other.request_count(), 0);
+        assert_eq!(cooling.request_count(), 0);
+
+        // Without a group, any two available nodes do
+        let response = quorum_response(
+            &settings(2, 2),
+            &receipt_tx(),
+            7.into(),
+            &rpc_list,
+            None,
+            1000,
+        )
+        .await
+        .unwrap();
+        assert_eq!(response["result"], json!({"status": "0x1"}));
+        assert_eq!(cooling.request_count(), 0);
+    }
+}
@@ -0,0 +1,135 @@
+use crate::config::types::RecordingMode;
+
+use serde_json::{
+    json,
+    Value,
+};
+use sled::Db;
+
+// Records responses to disk, or serves them back from a previous recording.
+//
+// Requests are keyed by their canonical form, which is the request without
+// its `id`. serde_json sorts object keys, so field order does not matter.
+#[derive(Debug)]
+pub struct Recorder {
+    db: Db,
+    replay: bool,
+}
+
+impl Recorder {
+    // Open the recording DB if recording or replaying is enabled
+    pub fn open(mode: &RecordingMode) -> Result<Option<Self>, sled::Error> {
+        let (path, replay) = match mode {
+            RecordingMode::Off => return Ok(None),
+            RecordingMode::Record(path) => (path, false),
+            RecordingMode::Replay(path) => (path, true),
+        };
+
+        let db = sled::Config::new().path(path).open()?;
+
+        Ok(Some(Recorder { db, replay }))
+    }
+
+    pub fn is_replay(&self) -> bool {
+        self.replay
+    }
+
+    // Canonical key of a request. Expects the `id` to already be taken out.
+    fn canonical_key(tx: &Value) -> String {
+        let mut tx = tx.clone();
+        tx["id"] = Value::Null;
+        tx.to_string()
+    }
+
+    // Store the response for `tx`, overwriting older recordings
+    pub fn record(&self, tx: &Value, response: &str) -> Result<(), sled::Error> {
+        let mut response: Value = match serde_json::from_str(response) {
+            Ok(response) => response,
+            Err(_) => return Ok(()),
+        };
+        response["id"] = Value::Null;
+
+        self.db
+            .insert(Self::canonical_key(tx), response.to_string().as_bytes())?;
+
+        Ok(())
+    }
+
+    // Return the recorded response for `tx` with its `id` set to `id`.
+    //
+    // If the request was never recorded, return a JSON-RPC error instead.
+    pub fn replay(&self, tx: &Value, id: Value) -> String {
+        let recorded = self
+            .db
+            .get(Self::canonical_key(tx))
+            .ok()
+            .flatten()
+            .and_then(|rax| serde_json::from_slice::<Value>(&rax).ok());
+
+        match recorded {
+            Some(mut rax) => {
+                rax["id"] = id;
+                rax.to_string()
+            }
+            None => {
+                json!({
+                    "jsonrpc": "2.0",
+                    "id": id,
+                    "error": {
+                        "code": -32000,
+                        "message": "Request not present in recording",
+                    },
+                })
+                .to_string()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn create_test_recorder(replay: bool) -> Recorder {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        Recorder { db, replay }
+    }
+
+    #[test]
+    fn test_record_and_replay() {
+        let recorder = create_test_recorder(false);
+        let tx = json!({"jsonrpc": "2.0", "method": "eth_chainId", "params": []});
+
+        recorder
+            .record(&tx, r#"{"jsonrpc":"2.0","id":4,"result":"0x1"}"#)
+            .unwrap();
+
+        let rax: Value = serde_json::from_str(&recorder.replay(&tx, json!(9))).unwrap();
+        assert_eq!(rax["result"], "0x1");
+        assert_eq!(rax["id"], 9);
+    }
+
+    #[test]
+    fn test_replay_ignores_id_and_key_order() {
+        let recorder = create_test_recorder(true);
+        let tx = json!({"method": "eth_chainId", "jsonrpc": "2.0", "id": 1});
+
+        recorder
+            .record(&tx, r#"{"jsonrpc":"2.0","id":1,"result":"0x1"}"#)
+            .unwrap();
+
+        let other = json!({"jsonrpc": "2.0", "id": 2, "method": "eth_chainId"});
+        let rax: Value = serde_json::from_str(&recorder.replay(&other, json!(2))).unwrap();
+        assert_eq!(rax["result"], "0x1");
+    }
+
+    #[test]
+    fn test_replay_missing() {
+        let recorder = create_test_recorder(true);
+        let tx = json!({"jsonrpc": "2.0", "method": "eth_blockNumber", "params": []});
+
+        let rax: Value = serde_json::from_str(&recorder.replay(&tx, json!(1))).unwrap();
+        assert_eq!(rax["error"]["code"], -32000);
+        assert_eq!(rax["id"], 1);
+    }
+}
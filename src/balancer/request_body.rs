@@ -0,0 +1,551 @@
+// Request body limits, and streaming of big bodies.
+//
+// Normally we read a whole request body into memory before parsing it.
+// Bodies over `max_size` bytes, 16MiB unless `[blutgang.request_body]` says
+// otherwise, are rejected without reading past the cap. Bodies over
+// `stream_above` bytes, like `eth_call`s with megabytes of calldata, aren't
+// buffered at all: we read until we've seen the `method`, check that the
+// client may call it, pick a node for it, and send the node what we've read
+// so far followed by the rest of the body as it comes in.
+//
+// Since we never hold a whole streamed request, it can't be cached, retried
+// on another node or go through middleware. Only read-only calls in
+// `STREAMED_METHODS` are streamed, everything else, batches included, is
+// read whole and goes through the regular checks.
+use crate::Rpc;
+
+use std::{
+    fmt::{
+        self,
+        Debug,
+    },
+    io,
+    pin::Pin,
+};
+
+use http_body_util::{
+    BodyExt,
+    Full,
+};
+use hyper::{
+    body::{
+        Body,
+        Buf,
+        Bytes,
+    },
+    Response,
+};
+use serde_json::{
+    json,
+    Value,
+};
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+
+// Chunks we hold while the node is slower to take them than the client is
+// to send them
+const STREAM_BUFFER: usize = 16;
+
+// Cap on request bodies if `[blutgang.request_body]` isn't set
+pub const DEFAULT_MAX_SIZE: usize = 16 * 1024 * 1024;
+
+// Calls that can carry huge calldata and are fine to send on unchecked
+const STREAMED_METHODS: [&str; 4] = [
+    "eth_call",
+    "eth_estimateGas",
+    "eth_createAccessList",
+    "debug_traceCall",
+];
+
+// Errors
+#[derive(Debug, PartialEq)]
+pub enum BodyError {
+    TooLarge(usize),
+    Unreadable(String),
+    Upstream(String),
+}
+
+impl fmt::Display for BodyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BodyError::TooLarge(max_size) => {
+                write!(f, "Request body is over the {} byte limit", max_size)
+            }
+            BodyError::Unreadable(e) => write!(f, "Could not read request body: {}", e),
+            BodyError::Upstream(e) => write!(f, "Node failed to answer: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for BodyError {}
+
+impl BodyError {
+    pub fn code(&self) -> i64 {
+        match self {
+            BodyError::TooLarge(_) => -32010,
+            BodyError::Unreadable(_) => -32700,
+            BodyError::Upstream(_) => -32603,
+        }
+    }
+
+    fn status(&self) -> u16 {
+        match self {
+            BodyError::TooLarge(_) => 413,
+            BodyError::Unreadable(_) => 400,
+            BodyError::Upstream(_) => 502,
+        }
+    }
+
+    pub fn into_response(self, id: Value) -> Response<Full<Bytes>> {
+        let body = json!({
+            "jsonrpc": "2.0",
+            "id": id,
+            "error": {"code": self.code(), "message": self.to_string()},
+        });
+
+        Response::builder()
+            .status(self.status())
+            .header("Content-Type", "application/json")
+            .body(Full::new(Bytes::from(body.to_string())))
+            .unwrap()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Field {
+    Method,
+    Id,
+}
+
+// Finds the top level `method` and `id` of a request as its body comes in,
+// without holding on to anything else
+#[derive(Debug, Default)]
+struct FieldScanner {
+    depth: usize,
+    in_string: bool,
+    escaped: bool,
+    started: bool,
+    batch: bool,
+    // Whether the next top level string is a key
+    expect_key: bool,
+    key: Option<Vec<u8>>,
+    // Field whose value comes next, and its value as far as we've read it
+    field: Option<Field>,
+    value: Option<Vec<u8>>,
+    method: Option<String>,
+    id: Option<Value>,
+}
+
+impl FieldScanner {
+    fn feed(&mut self, chunk: &[u8]) {
+        for &byte in chunk {
+            if self.in_string {
+                if let Some(value) = &mut self.value {
+                    value.push(byte);
+                } else if let Some(key) = &mut self.key {
+                    if self.escaped || byte != b'"' {
+                        key.push(byte);
+                    }
+                }
+
+                if self.escaped {
+                    self.escaped = false;
+                } else if byte == b'\\' {
+                    self.escaped = true;
+                } else if byte == b'"' {
+                    self.in_string = false;
+                    if let Some(key) = self.key.take() {
+                        self.field = match key.as_slice() {
+                            b"method" => Some(Field::Method),
+                            b"id" => Some(Field::Id),
+                            _ => None,
+                        };
+                    }
+                }
+                continue;
+            }
+
+            if !byte.is_ascii_whitespace() && !self.started {
+                self.started = true;
+                self.batch = byte == b'[';
+            }
+            match byte {
+                b'"' => {
+                    self.in_string = true;
+                    if self.depth == 1 && self.expect_key {
+                        self.expect_key = false;
+                        self.key = Some(Vec::new());
+                    } else if let Some(value) = &mut self.value {
+                        value.push(byte);
+                    }
+                }
+                b'{' | b'[' => {
+                    // Objects and arrays are never what we're looking for
+                    self.value = None;
+                    self.depth += 1;
+                    self.expect_key = self.depth == 1;
+                }
+                b'}' | b']' => {
+                    if self.depth == 1 {
+                        self.finish_value();
+                    }
+                    self.depth = self.depth.saturating_sub(1);
+                }
+                b':' if self.depth == 1 => {
+                    if self.field.is_some() {
+                        self.value = Some(Vec::new());
+                    }
+                }
+                b',' if self.depth == 1 => {
+                    self.finish_value();
+                    self.expect_key = true;
+                }
+                byte if byte.is_ascii_whitespace() => {}
+                byte => {
+                    if let Some(value) = &mut self.value {
+                        value.push(byte);
+                    }
+                }
+            }
+        }
+    }
+
+    fn finish_value(&mut self) {
+        let (field, value) = match (self.field.take(), self.value.take()) {
+            (Some(field), Some(value)) => (field, value),
+            _ => return,
+        };
+        if let Ok(value) = serde_json::from_slice::<Value>(&value) {
+            match field {
+                Field::Method => self.method = value.as_str().map(str::to_string),
+                Field::Id => self.id = Some(value),
+            }
+        }
+    }
+}
+
+// Next chunk of `body`, `None` once it's done
+async fn next_chunk<B>(body: &mut Pin<Box<B>>) -> Option<Result<Bytes, BodyError>>
+where
+    B: Body,
+    B::Error: Debug,
+{
+    loop {
+        let frame = match body.frame().await? {
+            Ok(frame) => frame,
+            Err(e) => return Some(Err(BodyError::Unreadable(format!("{:?}", e)))),
+        };
+        // Trailers don't go anywhere
+        if let Ok(mut data) = frame.into_data() {
+            return Some(Ok(data.copy_to_bytes(data.remaining())));
+        }
+    }
+}
+
+// Read the rest of `body` onto `read`, as long as it stays under `max_size`
+async fn read_rest<B>(
+    body: &mut Pin<Box<B>>,
+    read: &mut Vec<u8>,
+    max_size: usize,
+) -> Result<(), BodyError>
+where
+    B: Body,
+    B::Error: Debug,
+{
+    while let Some(chunk) = next_chunk(body).await {
+        let chunk = chunk?;
+        if read.len() + chunk.len() > max_size {
+            return Err(BodyError::TooLarge(max_size));
+        }
+        read.extend_from_slice(&chunk);
+    }
+    Ok(())
+}
+
+// Read all of `body`, as long as it stays under `max_size`
+pub async fn read_capped<B>(body: B, max_size: usize) -> Result<Bytes, BodyError>
+where
+    B: Body,
+    B::Error: Debug,
+{
+    let mut read = Vec::new();
+    read_rest(&mut Box::pin(body), &mut read, max_size).await?;
+    Ok(read.into())
+}
+
+// What we got reading the start of a body we might stream
+pub enum Prefix<B> {
+    // Enough to pick a node, and the rest of the body
+    Streamed(Streamed<B>),
+    // All of it, it didn't make sense to stream it
+    Whole(Bytes),
+}
+
+pub struct Streamed<B> {
+    read: Vec<u8>,
+    rest: Pin<Box<B>>,
+    scanner: FieldScanner,
+    max_size: usize,
+}
+
+// Read `body` until we know where to send it
+pub async fn read_prefix<B>(body: B, max_size: usize) -> Result<Prefix<B>, BodyError>
+where
+    B: Body,
+    B::Error: Debug,
+{
+    let mut body = Box::pin(body);
+    let mut scanner = FieldScanner::default();
+    let mut read = Vec::new();
+
+    while let Some(chunk) = next_chunk(&mut body).await {
+        let chunk = chunk?;
+        if read.len() + chunk.len() > max_size {
+            return Err(BodyError::TooLarge(max_size));
+        }
+        scanner.feed(&chunk);
+        read.extend_from_slice(&chunk);
+
+        let streamable = match &scanner.method {
+            Some(method) => STREAMED_METHODS.contains(&method.as_str()),
+            None => !scanner.batch,
+        };
+        if !streamable {
+            read_rest(&mut body, &mut read, max_size).await?;
+            break;
+        }
+        if scanner.method.is_some() {
+            return Ok(Prefix::Streamed(Streamed {
+                read,
+                rest: body,
+                scanner,
+                max_size,
+            }));
+        }
+    }
+
+    Ok(Prefix::Whole(read.into()))
+}
+
+// Send `first`, then the rest of `body` down `sender` as it comes in
+async fn pump<B>(
+    first: Vec<u8>,
+    body: &mut Pin<Box<B>>,
+    scanner: &mut FieldScanner,
+    max_size: usize,
+    sender: mpsc::Sender<Result<Bytes, io::Error>>,
+) -> Result<(), BodyError>
+where
+    B: Body,
+    B::Error: Debug,
+{
+    let mut len = first.len();
+    if sender.send(Ok(first.into())).await.is_err() {
+        // The node stopped reading, its answer says why
+        return Ok(());
+    }
+
+    while let Some(chunk) = next_chunk(body).await {
+        let chunk = match chunk {
+            Ok(chunk) => chunk,
+            Err(e) => {
+                let _ = sender
+                    .send(Err(io::Error::new(io::ErrorKind::Other, e.to_string())))
+                    .await;
+                return Err(e);
+            }
+        };
+        len += chunk.len();
+        if len > max_size {
+            let e = BodyError::TooLarge(max_size);
+            let _ = sender
+                .send(Err(io::Error::new(io::ErrorKind::Other, e.to_string())))
+                .await;
+            return Err(e);
+        }
+        scanner.feed(&chunk);
+        if sender.send(Ok(chunk)).await.is_err() {
+            return Ok(());
+        }
+    }
+    Ok(())
+}
+
+impl<B> Streamed<B>
+where
+    B: Body,
+    B::Error: Debug,
+{
+    pub fn method(&self) -> &str {
+        self.scanner.method.as_deref().unwrap_or_default()
+    }
+
+    // Request id, as far as we've read the body
+    pub fn id(&self) -> Value {
+        self.scanner.id.clone().unwrap_or_default()
+    }
+
+    // Send the body to `rpc` as it comes in, and return its answer
+    pub async fn send(
+        &mut self,
+        rpc: &Rpc,
+        headers: &[(String, String)],
+    ) -> Result<String, BodyError> {
+        let (sender, receiver) = mpsc::channel(STREAM_BUFFER);
+        let body = reqwest::Body::wrap_stream(ReceiverStream::new(receiver));
+
+        let (pumped, response) = tokio::join!(
+            pump(
+                std::mem::take(&mut self.read),
+                &mut self.rest,
+                &mut self.scanner,
+                self.max_size,
+                sender,
+            ),
+            rpc.send_body(body, headers),
+        );
+        pumped?;
+        response.map_err(|e| BodyError::Upstream(e.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::node::MockNode;
+    use futures::stream;
+    use http_body_util::StreamBody;
+    use hyper::body::Frame;
+    use std::convert::Infallible;
+
+    type Chunks = stream::Iter<std::vec::IntoIter<Result<Frame<Bytes>, Infallible>>>;
+
+    fn body(chunks: Vec<String>) -> StreamBody<Chunks> {
+        StreamBody::new(stream::iter(
+            chunks
+                .into_iter()
+                .map(|chunk| Ok(Frame::data(Bytes::from(chunk))))
+                .collect::<Vec<_>>(),
+        ))
+    }
+
+    fn scan(request: &str) -> FieldScanner {
+        // Byte by byte, so every field is split across chunks
+        let mut scanner = FieldScanner::default();
+        for byte in request.as_bytes() {
+            scanner.feed(&[*byte]);
+        }
+        scanner
+    }
+
+    fn call(calldata_len: usize) -> String {
+        format!(
+            r#"{{"jsonrpc":"2.0","id":7,"method":"eth_call","params":[{{"to":"0x01","data":"0x{}"}},"latest"]}}"#,
+            "ab".repeat(calldata_len)
+        )
+    }
+
+    #[test]
+    fn test_field_scanner() {
+        let scanner = scan(
+            r#" { "params": [{"method": "nope", "id": 1}, "a \"quoted\" \\ string"], "id" : "abc", "method":"eth_call" } "#,
+        );
+        assert_eq!(scanner.method.as_deref(), Some("eth_call"));
+        assert_eq!(scanner.id, Some(json!("abc")));
+        assert!(!scanner.batch);
+
+        let scanner = scan(r#"{"id":{"a":1},"method":"eth_call","id":null}"#);
+        assert_eq!(scanner.method.as_deref(), Some("eth_call"));
+        assert_eq!(scanner.id, Some(Value::Null));
+
+        let scanner = scan(r#"[{"id":1,"method":"eth_call"}]"#);
+        assert!(scanner.batch);
+        assert_eq!(scanner.method, None);
+    }
+
+    #[tokio::test]
+    async fn test_read_prefix() {
+        let request = call(1000);
+        let chunks: Vec<String> = request
+            .as_bytes()
+            .chunks(100)
+            .map(|chunk| String::from_utf8(chunk.to_vec()).unwrap())
+            .collect();
+
+        // Stops reading once it knows the method
+        match read_prefix(body(chunks.clone()), 1 << 20).await.unwrap() {
+            Prefix::Streamed(streamed) => {
+                assert_eq!(streamed.method(), "eth_call");
+                assert_eq!(streamed.id(), json!(7));
+                assert_eq!(streamed.read.len(), 100);
+            }
+            Prefix::Whole(_) => panic!("should stream"),
+        }
+
+        // Batches are read whole
+        let batch = format!("[{}]", request);
+        match read_prefix(body(vec![batch.clone()]), 1 << 20)
+            .await
+            .unwrap()
+        {
+            Prefix::Whole(whole) => assert_eq!(whole, batch.as_bytes()),
+            Prefix::Streamed(_) => panic!("should not stream"),
+        }
+
+        // So are calls we don't stream
+        let wallet = request.replace("eth_call", "eth_sendTransaction");
+        match read_prefix(body(vec![wallet.clone()]), 1 << 20)
+            .await
+            .unwrap()
+        {
+            Prefix::Whole(whole) => assert_eq!(whole, wallet.as_bytes()),
+            Prefix::Streamed(_) => panic!("should not stream"),
+        }
+
+        assert_eq!(
+            read_prefix(body(chunks), 50).await.err(),
+            Some(BodyError::TooLarge(50))
+        );
+        assert_eq!(
+            read_capped(body(vec![request.clone()]), request.len() - 1).await,
+            Err(BodyError::TooLarge(request.len() - 1))
+        );
+        assert_eq!(
+            read_capped(body(vec![request.clone()]), request.len()).await,
+            Ok(Bytes::from(request))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_streamed_send() {
+        let node = MockNode::spawn(1).await.unwrap();
+        node.set_response("eth_call", json!("0x1234"));
+        let rpc = Rpc::new(node.http_url(), None, 6, 0, 10.0);
+
+        let request = call(100_000);
+        let chunks: Vec<String> = request
+            .as_bytes()
+            .chunks(4096)
+            .map(|chunk| String::from_utf8(chunk.to_vec()).unwrap())
+            .collect();
+
+        let mut streamed = match read_prefix(body(chunks.clone()), 1 << 20).await.unwrap() {
+            Prefix::Streamed(streamed) => streamed,
+            Prefix::Whole(_) => panic!("should stream"),
+        };
+        let response: Value =
+            serde_json::from_str(&streamed.send(&rpc, &[]).await.unwrap()).unwrap();
+        assert_eq!(response["result"], "0x1234");
+        assert_eq!(response["id"], 7);
+
+        // Over the cap halfway through
+        let mut streamed = match read_prefix(body(chunks), request.len() / 2).await.unwrap() {
+            Prefix::Streamed(streamed) => streamed,
+            Prefix::Whole(_) => panic!("should stream"),
+        };
+        assert_eq!(
+            streamed.send(&rpc, &[]).await,
+            Err(BodyError::TooLarge(request.len() / 2))
+        );
+    }
+}
@@ -7,7 +7,7 @@ macro_rules! no_rpc_available {
         Ok(hyper::Response::builder()
             .status(500)
             .body(Full::new(Bytes::from(
-                "{code:-32002, message:\"error: No working RPC available! Try again later...\"}"
+                "{\"jsonrpc\":\"2.0\",\"id\":null,\"error\":{\"code\":-32002,\"message\":\"error: No working RPC available! Try again later...\"}}"
                     .to_string(),
             )))
             .unwrap())
@@ -20,7 +20,33 @@ macro_rules! timed_out {
         Ok(hyper::Response::builder()
             .status(408)
             .body(Full::new(Bytes::from(
-                "{code:-32001, message:\"error: Request timed out! Try again later...\"}"
+                "{\"jsonrpc\":\"2.0\",\"id\":null,\"error\":{\"code\":-32001,\"message\":\"error: Request timed out! Try again later...\"}}"
+                    .to_string(),
+            )))
+            .unwrap())
+    };
+}
+
+#[macro_export]
+macro_rules! malformed_response {
+    () => {
+        Ok(hyper::Response::builder()
+            .status(502)
+            .body(Full::new(Bytes::from(
+                "{\"jsonrpc\":\"2.0\",\"id\":null,\"error\":{\"code\":-32009,\"message\":\"error: RPCs returned malformed responses! Try again later...\"}}"
+                    .to_string(),
+            )))
+            .unwrap())
+    };
+}
+
+#[macro_export]
+macro_rules! rate_limited {
+    () => {
+        Ok(hyper::Response::builder()
+            .status(429)
+            .body(Full::new(Bytes::from(
+                "{\"jsonrpc\":\"2.0\",\"id\":null,\"error\":{\"code\":-32005,\"message\":\"error: All RPCs are rate limiting us! Try again later...\"}}"
                     .to_string(),
             )))
             .unwrap())
@@ -42,7 +68,7 @@ macro_rules! cache_error {
         Ok(hyper::Response::builder()
             .status(500)
             .body(Full::new(Bytes::from(
-                "{code:-32003, message:\"error: Cache error! Try again later...\"}".to_string(),
+                "{\"jsonrpc\":\"2.0\",\"id\":null,\"error\":{\"code\":-32003,\"message\":\"error: Cache error! Try again later...\"}}".to_string(),
             )))
             .unwrap())
     };
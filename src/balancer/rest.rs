@@ -0,0 +1,333 @@
+// REST gateway in front of JSON-RPC.
+//
+// With `[blutgang.rest]` set, `GET <prefix>/...` paths are turned into the
+// JSON-RPC call they stand for and go through the same cache and routing as
+// any other request. The response is the bare `result`, missing blocks and
+// transactions are a 404, and `Cache-Control` tells HTTP caches in front of us
+// what they can keep. Only blocks asked for by hash never change, everything
+// else can be reorged and is kept for `max_age` seconds at most.
+//
+// Paths, relative to the prefix:
+//   /chain/id, /chain/head, /chain/gas_price
+//   /block/{number|tag|hash}[?full=true]
+//   /block/{number|tag|hash}/receipts
+//   /tx/{hash}
+//   /tx/{hash}/receipt
+//   /account/{address}/{balance|nonce|code}[?block=<number|tag>]
+use crate::config::types::RestSettings;
+
+use std::fmt;
+
+use http_body_util::{
+    BodyExt,
+    Full,
+};
+use hyper::{
+    body::Bytes,
+    header::{
+        HeaderValue,
+        CACHE_CONTROL,
+    },
+    Response,
+};
+use serde_json::{
+    json,
+    Value,
+};
+
+// Responses that never change are kept this long, in seconds
+const IMMUTABLE_MAX_AGE: u64 = 31_536_000;
+
+// Errors
+#[derive(Debug, PartialEq)]
+pub enum RestError {
+    NotFound(String),
+    InvalidParam(String),
+    MethodNotAllowed,
+}
+
+impl fmt::Display for RestError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RestError::NotFound(path) => write!(f, "No such resource: {}", path),
+            RestError::InvalidParam(param) => write!(f, "Invalid parameter: {}", param),
+            RestError::MethodNotAllowed => write!(f, "Only GET is supported"),
+        }
+    }
+}
+
+impl std::error::Error for RestError {}
+
+impl RestError {
+    fn status(&self) -> u16 {
+        match self {
+            RestError::NotFound(_) => 404,
+            RestError::InvalidParam(_) => 400,
+            RestError::MethodNotAllowed => 405,
+        }
+    }
+
+    pub fn into_response(self) -> Response<Full<Bytes>> {
+        let body = json!({"error": self.to_string()});
+
+        Response::builder()
+            .status(self.status())
+            .header("Content-Type", "application/json")
+            .body(Full::new(Bytes::from(body.to_string())))
+            .unwrap()
+    }
+}
+
+// JSON-RPC call a REST path stands for
+#[derive(Debug, PartialEq)]
+pub struct RestCall {
+    pub request: Value,
+    // Whether the answer can never change
+    pub immutable: bool,
+}
+
+fn is_hex(value: &str, len: usize) -> bool {
+    value.len() == len + 2
+        && value.starts_with("0x")
+        && value[2..].bytes().all(|byte| byte.is_ascii_hexdigit())
+}
+
+// Block numbers can be decimal or hex, tags are passed on as they are
+fn block_number(block: &str) -> Result<Value, RestError> {
+    if let Ok(number) = block.parse::<u64>() {
+        return Ok(json!(format!("0x{:x}", number)));
+    }
+    match block {
+        "latest" | "earliest" | "pending" | "safe" | "finalized" => Ok(json!(block)),
+        _ if (3..=18).contains(&block.len()) && is_hex(block, block.len() - 2) => Ok(json!(block)),
+        _ => Err(RestError::InvalidParam(block.to_string())),
+    }
+}
+
+fn hash(hash: &str) -> Result<Value, RestError> {
+    match is_hex(hash, 64) {
+        true => Ok(json!(hash)),
+        false => Err(RestError::InvalidParam(hash.to_string())),
+    }
+}
+
+fn address(address: &str) -> Result<Value, RestError> {
+    match is_hex(address, 40) {
+        true => Ok(json!(address)),
+        false => Err(RestError::InvalidParam(address.to_string())),
+    }
+}
+
+fn query_param(query: Option<&str>, name: &str) -> Option<String> {
+    url::form_urlencoded::parse(query.unwrap_or_default().as_bytes())
+        .find(|(key, _)| key == name)
+        .map(|(_, value)| value.into_owned())
+}
+
+// Call for `path` under `settings.prefix`. `None` if the path isn't ours.
+pub fn rest_request(
+    path: &str,
+    query: Option<&str>,
+    settings: &RestSettings,
+) -> Option<Result<RestCall, RestError>> {
+    let path = path.strip_prefix(settings.prefix.as_str())?;
+    if !path.is_empty() && !path.starts_with('/') {
+        return None;
+    }
+    let segments: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+
+    let rpc = |method: &str, params: Value, immutable: bool| {
+        RestCall {
+            request: json!({"jsonrpc": "2.0", "id": 1, "method": method, "params": params}),
+            immutable,
+        }
+    };
+    let block_id = |block: &str| {
+        match block.len() {
+            66 => hash(block).map(|hash| ("eth_getBlockByHash", hash, true)),
+            _ => block_number(block).map(|number| ("eth_getBlockByNumber", number, false)),
+        }
+    };
+
+    let call = match segments.as_slice() {
+        ["chain", "id"] => Ok(rpc("eth_chainId", json!([]), true)),
+        ["chain", "head"] => Ok(rpc("eth_blockNumber", json!([]), false)),
+        ["chain", "gas_price"] => Ok(rpc("eth_gasPrice", json!([]), false)),
+        ["block", block] => {
+            let full = query_param(query, "full").is_some_and(|full| full == "true");
+            block_id(block)
+                .map(|(method, block, immutable)| rpc(method, json!([block, full]), immutable))
+        }
+        ["block", block, "receipts"] => {
+            block_id(block)
+                .map(|(_, block, immutable)| rpc("eth_getBlockReceipts", json!([block]), immutable))
+        }
+        ["tx", tx_hash] => {
+            hash(tx_hash).map(|tx_hash| rpc("eth_getTransactionByHash", json!([tx_hash]), false))
+        }
+        ["tx", tx_hash, "receipt"] => {
+            hash(tx_hash).map(|tx_hash| rpc("eth_getTransactionReceipt", json!([tx_hash]), false))
+        }
+        ["account", account, field] => {
+            let method = match *field {
+                "balance" => "eth_getBalance",
+                "nonce" => "eth_getTransactionCount",
+                "code" => "eth_getCode",
+                _ => return Some(Err(RestError::NotFound(path.to_string()))),
+            };
+            let block = query_param(query, "block").unwrap_or_else(|| "latest".to_string());
+            address(account).and_then(|account| {
+                block_number(&block).map(|block| rpc(method, json!([account, block]), false))
+            })
+        }
+        _ => Err(RestError::NotFound(path.to_string())),
+    };
+
+    Some(call)
+}
+
+// Turn the JSON-RPC response to `call` into a REST one
+pub async fn into_rest_response(
+    response: Response<Full<Bytes>>,
+    call: &RestCall,
+    settings: &RestSettings,
+) -> Response<Full<Bytes>> {
+    let (mut parts, body) = response.into_parts();
+    // `Full` can't fail
+    let bytes = body.collect().await.unwrap().to_bytes();
+    let mut rax = match serde_json::from_slice::<Value>(&bytes) {
+        Ok(rax) if rax.is_object() => rax,
+        // Errors of our own, pass them on as they are
+        _ => return Response::from_parts(parts, Full::new(bytes)),
+    };
+
+    let (status, body) = match (rax.get("error").cloned(), rax["result"].take()) {
+        (Some(error), _) => (502, json!({"error": error})),
+        (None, Value::Null) => (404, json!({"error": "Not found"})),
+        (None, result) => (200, result),
+    };
+
+    let cache_control = match (status, call.immutable) {
+        (200, true) => format!("public, max-age={}, immutable", IMMUTABLE_MAX_AGE),
+        (200, false) if settings.max_age > 0 => format!("public, max-age={}", settings.max_age),
+        _ => "no-cache".to_string(),
+    };
+    if parts.status.is_success() {
+        parts.status = hyper::StatusCode::from_u16(status).unwrap();
+    }
+    if let Ok(cache_control) = HeaderValue::from_str(&cache_control) {
+        parts.headers.insert(CACHE_CONTROL, cache_control);
+    }
+
+    Response::from_parts(parts, Full::new(Bytes::from(body.to_string())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HASH: &str = "0x88df016429689c079f3b2f6ad39fa052532c56795b733da78a91ebe6a713944b";
+    const ADDRESS: &str = "0x00000000219ab540356cbb839cbe05303d7705fa";
+
+    fn request(path: &str, query: Option<&str>) -> Result<RestCall, RestError> {
+        rest_request(path, query, &RestSettings::default()).unwrap()
+    }
+
+    fn response(rax: Value) -> Response<Full<Bytes>> {
+        Response::builder()
+            .status(200)
+            .header("Content-Type", "application/json")
+            .body(Full::new(Bytes::from(rax.to_string())))
+            .unwrap()
+    }
+
+    async fn body(response: Response<Full<Bytes>>) -> Value {
+        let bytes = response.into_body().collect().await.unwrap().to_bytes();
+        serde_json::from_slice(&bytes).unwrap()
+    }
+
+    #[test]
+    fn test_rest_request() {
+        let call = request("/eth/v1/block/16", Some("full=true")).unwrap();
+        assert_eq!(call.request["method"], "eth_getBlockByNumber");
+        assert_eq!(call.request["params"], json!(["0x10", true]));
+        assert!(!call.immutable);
+
+        let call = request(&format!("/eth/v1/block/{}", HASH), None).unwrap();
+        assert_eq!(call.request["method"], "eth_getBlockByHash");
+        assert_eq!(call.request["params"], json!([HASH, false]));
+        assert!(call.immutable);
+
+        let call = request(&format!("/eth/v1/block/{}/receipts", HASH), None).unwrap();
+        assert_eq!(call.request["method"], "eth_getBlockReceipts");
+        assert_eq!(call.request["params"], json!([HASH]));
+
+        let call = request(&format!("/eth/v1/tx/{}/receipt", HASH), None).unwrap();
+        assert_eq!(call.request["method"], "eth_getTransactionReceipt");
+
+        let call = request(
+            &format!("/eth/v1/account/{}/nonce", ADDRESS),
+            Some("block=finalized"),
+        )
+        .unwrap();
+        assert_eq!(call.request["method"], "eth_getTransactionCount");
+        assert_eq!(call.request["params"], json!([ADDRESS, "finalized"]));
+
+        assert_eq!(
+            request("/eth/v1/chain/id", None).unwrap().request["method"],
+            "eth_chainId"
+        );
+
+        // Not ours
+        assert!(rest_request("/eth/v10/chain/id", None, &RestSettings::default()).is_none());
+        assert!(rest_request("/blocknumber", None, &RestSettings::default()).is_none());
+
+        assert_eq!(
+            request("/eth/v1/tx/0x1234", None),
+            Err(RestError::InvalidParam("0x1234".to_string()))
+        );
+        assert_eq!(
+            request("/eth/v1/block/yesterday", None),
+            Err(RestError::InvalidParam("yesterday".to_string()))
+        );
+        assert_eq!(
+            request(&format!("/eth/v1/account/{}/storage", ADDRESS), None),
+            Err(RestError::NotFound(format!("/account/{}/storage", ADDRESS)))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_rest_response() {
+        let settings = RestSettings {
+            max_age: 2,
+            ..Default::default()
+        };
+        let by_hash = request(&format!("/eth/v1/block/{}", HASH), None).unwrap();
+        let head = request("/eth/v1/chain/head", None).unwrap();
+
+        let rax = json!({"jsonrpc": "2.0", "id": 1, "result": {"number": "0x10"}});
+        let rest = into_rest_response(response(rax), &by_hash, &settings).await;
+        assert_eq!(rest.status(), 200);
+        assert_eq!(
+            rest.headers()[CACHE_CONTROL],
+            "public, max-age=31536000, immutable"
+        );
+        assert_eq!(body(rest).await, json!({"number": "0x10"}));
+
+        let rax = json!({"jsonrpc": "2.0", "id": 1, "result": "0x10"});
+        let rest = into_rest_response(response(rax), &head, &settings).await;
+        assert_eq!(rest.headers()[CACHE_CONTROL], "public, max-age=2");
+        assert_eq!(body(rest).await, json!("0x10"));
+
+        // Blocks we don't have yet might show up later
+        let rax = json!({"jsonrpc": "2.0", "id": 1, "result": null});
+        let rest = into_rest_response(response(rax), &by_hash, &settings).await;
+        assert_eq!(rest.status(), 404);
+        assert_eq!(rest.headers()[CACHE_CONTROL], "no-cache");
+
+        let rax = json!({"jsonrpc": "2.0", "id": 1, "error": {"code": -32000, "message": "nope"}});
+        let rest = into_rest_response(response(rax), &head, &settings).await;
+        assert_eq!(rest.status(), 502);
+        assert_eq!(body(rest).await["error"]["message"], "nope");
+    }
+}
@@ -0,0 +1,326 @@
+// Re-fetch the hottest `latest` requests after every new block.
+//
+// dApps poll balances, `eth_call`s and the latest block at `latest`, so right
+// after a new head every one of them misses the cache at once and we forward
+// the whole burst upstream. With a `[blutgang.revalidation]` table we count
+// how often each `latest` request is made, and as soon as a new block comes in
+// fetch the `top_n` most requested ones at that block and cache them under the
+// same keys clients hash to. Every tracked key is dropped from the cache first,
+// so an answer for the previous head is never served as `latest`. Counts are
+// halved every block so keys that went cold drop out of the top.
+use crate::{
+    balancer::{
+        cache_entry::CacheKey,
+        format::block_param_position,
+        memory::MemoryBudget,
+        processing::{
+            cache_querry,
+            CacheArgs,
+        },
+    },
+    config::types::RevalidationSettings,
+    log_info,
+    rpc::types::Rpc,
+};
+
+use std::{
+    collections::HashMap,
+    sync::{
+        Arc,
+        Mutex,
+        RwLock,
+    },
+    time::{
+        Duration,
+        Instant,
+    },
+};
+
+use futures::stream::{
+    self,
+    StreamExt,
+};
+use serde_json::Value;
+use sled::Db;
+use tokio::{
+    sync::watch,
+    time::timeout,
+};
+use tokio_stream::wrappers::WatchStream;
+
+// Stop tracking new requests once we know this many
+const MAX_TRACKED: usize = 10_000;
+
+// Requests at `latest` and how often they were made
+#[derive(Debug, Default)]
+pub struct Revalidator {
+    settings: Option<RevalidationSettings>,
+    tracked: Mutex<HashMap<CacheKey, (Value, u64)>>,
+}
+
+impl Revalidator {
+    pub fn new(settings: Option<RevalidationSettings>) -> Self {
+        Self {
+            settings,
+            tracked: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.settings.is_some()
+    }
+
+    // Count a request `key` was hashed from, if it's for `latest`
+    pub fn record(&self, tx: &Value, key: CacheKey) {
+        if !self.is_enabled() || !is_latest_request(tx) {
+            return;
+        }
+
+        let mut tracked = self.tracked.lock().unwrap_or_else(|e| e.into_inner());
+        let tracked_len = tracked.len();
+        match tracked.get_mut(&key) {
+            Some((_, hits)) => *hits += 1,
+            None if tracked_len < MAX_TRACKED => {
+                tracked.insert(key, (tx.clone(), 1));
+            }
+            None => {}
+        }
+    }
+
+    // The `top_n` most requested, most requested first. Halves every count
+    // and forgets requests nobody made since the last round, once what we
+    // cache for them now was dropped again.
+    fn hottest(&self) -> Vec<(CacheKey, Value)> {
+        let top_n = match &self.settings {
+            Some(settings) => settings.top_n,
+            None => return Vec::new(),
+        };
+
+        let mut tracked = self.tracked.lock().unwrap_or_else(|e| e.into_inner());
+        let mut hottest: Vec<(CacheKey, Value, u64)> = tracked
+            .iter()
+            .filter(|(_, (_, hits))| *hits > 0)
+            .map(|(key, (tx, hits))| (*key, tx.clone(), *hits))
+            .collect();
+        hottest.sort_unstable_by_key(|(_, _, hits)| std::cmp::Reverse(*hits));
+        hottest.truncate(top_n);
+
+        tracked.retain(|key, (_, hits)| {
+            *hits /= 2;
+            *hits > 0 || hottest.iter().any(|(hot, _, _)| hot == key)
+        });
+
+        hottest.into_iter().map(|(key, tx, _)| (key, tx)).collect()
+    }
+
+    // Remove the answers cached for the previous head. Returns how many there were.
+    fn drop_stale(&self, cache: &Db, memory: &MemoryBudget) -> usize {
+        let tracked = self.tracked.lock().unwrap_or_else(|e| e.into_inner());
+        tracked
+            .keys()
+            .filter(|key| {
+                match cache.remove(key.as_bytes()) {
+                    Ok(Some(entry)) => {
+                        memory.release(key.as_bytes(), entry.len());
+                        true
+                    }
+                    _ => false,
+                }
+            })
+            .count()
+    }
+}
+
+fn is_latest_request(tx: &Value) -> bool {
+    match tx["method"].as_str().and_then(block_param_position) {
+        Some(position) => tx["params"][position] == "latest",
+        None => false,
+    }
+}
+
+// Fetch `tx` at block `number` from `rpc` and cache it under `key`
+async fn revalidate_request(
+    key: CacheKey,
+    tx: Value,
+    number: u64,
+    rpc: Rpc,
+    cache_args: &CacheArgs,
+    ttl: u128,
+) -> bool {
+    let position = match tx["method"].as_str().and_then(block_param_position) {
+        Some(position) => position,
+        None => return false,
+    };
+    let mut pinned = tx;
+    pinned["params"][position] = format!("0x{:x}", number).into();
+    pinned["id"] = 1.into();
+
+    let mut rx = match timeout(
+        Duration::from_millis(ttl.try_into().unwrap_or(u64::MAX)),
+        rpc.send_request(pinned.clone()),
+    )
+    .await
+    {
+        Ok(Ok(rx)) => rx,
+        _ => return false,
+    };
+    cache_querry(&mut rx, pinned, key, Some(&rpc.name), cache_args);
+
+    cache_args
+        .cache
+        .contains_key(key.as_bytes())
+        .unwrap_or(false)
+}
+
+// Cache the hottest `latest` requests at block `number`. Returns how many got cached.
+pub async fn revalidate(
+    revalidator: &Revalidator,
+    rpc_list: &Arc<RwLock<Vec<Rpc>>>,
+    cache_args: &CacheArgs,
+    number: u64,
+    ttl: u128,
+) -> usize {
+    let concurrency = match &revalidator.settings {
+        Some(settings) => settings.concurrency,
+        None => return 0,
+    };
+    // Whatever we don't get again below has to go upstream, not be served stale
+    revalidator.drop_stale(&cache_args.cache, &cache_args.memory);
+
+    let rpcs = rpc_list.read().unwrap().clone();
+    if rpcs.is_empty() {
+        return 0;
+    }
+
+    // Spread the requests over every node, same as block ranges
+    stream::iter(revalidator.hottest().into_iter().enumerate())
+        .map(|(i, (key, tx))| {
+            let rpc = rpcs[i % rpcs.len()].clone();
+            revalidate_request(key, tx, number, rpc, cache_args, ttl)
+        })
+        .buffer_unordered(concurrency)
+        .filter(|cached| futures::future::ready(*cached))
+        .count()
+        .await
+}
+
+// Revalidate the hottest requests every time we see a new head
+pub async fn revalidate_on_new_blocks(
+    revalidator: Arc<Revalidator>,
+    rpc_list: Arc<RwLock<Vec<Rpc>>>,
+    cache_args: CacheArgs,
+    blocknum_rx: watch::Receiver<u64>,
+    ttl: u128,
+) {
+    if !revalidator.is_enabled() {
+        return;
+    }
+
+    let mut blocknum_stream = WatchStream::new(blocknum_rx.clone());
+    while blocknum_stream.next().await.is_some() {
+        let number = *blocknum_rx.borrow();
+        if number == 0 {
+            continue;
+        }
+
+        let time = Instant::now();
+        let cached = revalidate(&revalidator, &rpc_list, &cache_args, number, ttl).await;
+        if cached > 0 {
+            log_info!(
+                "Revalidated {} hot requests at block {} in {:?}",
+                cached,
+                number,
+                time.elapsed()
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        balancer::cache_entry::get_entry,
+        mock::node::MockNode,
+    };
+    use serde_json::json;
+
+    fn balance(address: &str) -> Value {
+        json!({"jsonrpc": "2.0", "id": null, "method": "eth_getBalance", "params": [address, "latest"]})
+    }
+
+    fn revalidator(top_n: usize) -> Revalidator {
+        Revalidator::new(Some(RevalidationSettings {
+            top_n,
+            ..Default::default()
+        }))
+    }
+
+    #[test]
+    fn test_hottest() {
+        let revalidator = revalidator(2);
+        for (address, hits) in [("0x01", 1), ("0x02", 5), ("0x03", 3)] {
+            for _ in 0..hits {
+                revalidator.record(&balance(address), CacheKey::new(&balance(address)));
+            }
+        }
+        // Only `latest` requests are worth revalidating
+        let pinned = json!({"jsonrpc": "2.0", "id": null, "method": "eth_getBalance", "params": ["0x04", "0x10"]});
+        revalidator.record(&pinned, CacheKey::new(&pinned));
+
+        let hottest: Vec<Value> = revalidator
+            .hottest()
+            .into_iter()
+            .map(|(_, tx)| tx)
+            .collect();
+        assert_eq!(hottest, vec![balance("0x02"), balance("0x03")]);
+
+        // Counts decay, so the single hit is forgotten
+        assert_eq!(revalidator.tracked.lock().unwrap().len(), 2);
+        assert!(Revalidator::default().hottest().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_revalidate() {
+        let node = MockNode::spawn(1).await.unwrap();
+        node.set_response("eth_getBalance", json!("0x64"));
+        let rpc_list = Arc::new(RwLock::new(vec![Rpc::new(
+            node.http_url(),
+            None,
+            10,
+            0,
+            1.0,
+        )]));
+        let cache_args = CacheArgs {
+            cache: Arc::new(sled::Config::new().temporary(true).open().unwrap()),
+            ..Default::default()
+        };
+
+        let revalidator = revalidator(10);
+        let key = CacheKey::new(&balance("0x01"));
+        revalidator.record(&balance("0x01"), key);
+
+        assert_eq!(
+            revalidate(&revalidator, &rpc_list, &cache_args, 16, 1000).await,
+            1
+        );
+        let cached: Value =
+            serde_json::from_slice(&get_entry(&cache_args.cache, &key).unwrap().unwrap()).unwrap();
+        assert_eq!(cached["result"], "0x64");
+        assert_eq!(cache_args.head_cache.read().unwrap()[&16], vec![key]);
+
+        // Nothing to fetch it from at the next head, so the old answer is gone
+        assert_eq!(
+            revalidate(
+                &revalidator,
+                &Arc::new(RwLock::new(vec![])),
+                &cache_args,
+                17,
+                1000
+            )
+            .await,
+            0
+        );
+        assert!(get_entry(&cache_args.cache, &key).unwrap().is_none());
+    }
+}
@@ -0,0 +1,407 @@
+// Per-request routing hints.
+//
+// Power users sometimes need one call to go to a specific node, skip the
+// cache or give up sooner than usual, without us exposing a separate endpoint
+// for each. Requests can carry a `blutgang` object, or an `x-blutgang-hints`
+// header with the same JSON, with any of:
+//
+// {"node": "archive-1", "no_cache": true, "no_store": true, "timeout_ms": 500}
+//
+// `no_cache` skips the cache entirely and always asks a node, `no_store`
+// answers from the cache as usual but doesn't keep what we fetch.
+//
+// Hints are only read if `[blutgang.routing_hints]` is set, and every hint
+// has to be allowed for the client sending it. The `blutgang` object is
+// removed before the request is cached or forwarded. With `cache_control`
+// set, the `no-cache` and `no-store` directives of a `Cache-Control` header
+// work like the hints of the same name. Browsers send those on their own, so
+// directives the client isn't allowed are ignored instead of rejected.
+use crate::{
+    config::types::RoutingHintsSettings,
+    Rpc,
+};
+
+use std::{
+    fmt,
+    time::Duration,
+};
+
+use serde_json::{
+    json,
+    Value,
+};
+
+pub const HINTS_HEADER: &str = "x-blutgang-hints";
+
+// Field of the request object the hints live in
+const HINTS_FIELD: &str = "blutgang";
+
+// Every hint we understand
+pub const ROUTING_HINTS: [&str; 4] = ["node", "no_cache", "no_store", "timeout_ms"];
+
+// Errors
+#[derive(Debug, PartialEq, Eq)]
+pub enum HintError {
+    Invalid(String),
+    NotAllowed(String),
+    UnknownNode(String),
+}
+
+impl fmt::Display for HintError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HintError::Invalid(msg) => write!(f, "Invalid routing hints: {}", msg),
+            HintError::NotAllowed(hint) => write!(f, "Routing hint {} is not allowed", hint),
+            HintError::UnknownNode(node) => write!(f, "Unknown node: {}", node),
+        }
+    }
+}
+
+impl std::error::Error for HintError {}
+
+impl HintError {
+    // JSON-RPC error code we return to the client
+    pub fn code(&self) -> i64 {
+        match self {
+            HintError::Invalid(_) => -32600,
+            HintError::NotAllowed(_) => -32006,
+            HintError::UnknownNode(_) => -32602,
+        }
+    }
+
+    pub fn to_response(&self, id: &Value) -> Value {
+        json!({
+            "jsonrpc": "2.0",
+            "id": id,
+            "error": {"code": self.code(), "message": self.to_string()},
+        })
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RoutingHints {
+    // Name of the node the request has to go to
+    pub node: Option<String>,
+    // Neither read from nor write to the cache
+    pub no_cache: bool,
+    // Read from the cache, but don't write the response to it
+    pub no_store: bool,
+    // Overrides the regular request timeout
+    pub timeout: Option<Duration>,
+}
+
+impl RoutingHints {
+    // Remove the hints from `tx` without looking at them, so they never reach
+    // our nodes or the cache key
+    pub fn strip(tx: &mut Value) {
+        if let Some(tx) = tx.as_object_mut() {
+            tx.remove(HINTS_FIELD);
+        }
+    }
+
+    // Remove the hints from `tx` and parse them, falling back to the `header`
+    // if the request doesn't have any
+    pub fn take(tx: &mut Value, header: Option<&str>) -> Result<Option<Self>, HintError> {
+        let field = tx.as_object_mut().and_then(|tx| tx.remove(HINTS_FIELD));
+
+        let hints = match (field, header) {
+            (Some(hints), _) => hints,
+            (None, Some(header)) => {
+                serde_json::from_str(header)
+                    .map_err(|e| HintError::Invalid(format!("{} header: {}", HINTS_HEADER, e)))?
+            }
+            (None, None) => return Ok(None),
+        };
+
+        Self::from_value(&hints).map(Some)
+    }
+
+    fn from_value(hints: &Value) -> Result<Self, HintError> {
+        let hints = hints
+            .as_object()
+            .ok_or(HintError::Invalid("hints must be an object".to_string()))?;
+
+        if let Some(unknown) = hints
+            .keys()
+            .find(|hint| !ROUTING_HINTS.contains(&hint.as_str()))
+        {
+            return Err(HintError::Invalid(format!("unknown hint {}", unknown)));
+        }
+
+        let node = match hints.get("node") {
+            Some(node) => {
+                Some(
+                    node.as_str()
+                        .ok_or(HintError::Invalid("node must be a string".to_string()))?
+                        .to_string(),
+                )
+            }
+            None => None,
+        };
+        let flag = |name: &str| {
+            match hints.get(name) {
+                Some(flag) => {
+                    flag.as_bool()
+                        .ok_or(HintError::Invalid(format!("{} must be a boolean", name)))
+                }
+                None => Ok(false),
+            }
+        };
+        let no_cache = flag("no_cache")?;
+        let no_store = flag("no_store")?;
+        let timeout =
+            match hints.get("timeout_ms") {
+                Some(timeout) => {
+                    let timeout = timeout.as_u64().filter(|timeout| *timeout > 0).ok_or(
+                        HintError::Invalid("timeout_ms must be a positive integer".to_string()),
+                    )?;
+                    Some(Duration::from_millis(timeout))
+                }
+                None => None,
+            };
+
+        Ok(RoutingHints {
+            node,
+            no_cache,
+            no_store,
+            timeout,
+        })
+    }
+
+    // Add the directives of a `Cache-Control` header the client `identity`
+    // is allowed to use
+    pub fn add_cache_control(
+        &mut self,
+        header: &str,
+        settings: &RoutingHintsSettings,
+        identity: Option<&str>,
+    ) {
+        for directive in header.split(',').map(str::trim) {
+            if directive.eq_ignore_ascii_case("no-cache") && settings.allows(identity, "no_cache") {
+                self.no_cache = true;
+            } else if directive.eq_ignore_ascii_case("no-store")
+                && settings.allows(identity, "no_store")
+            {
+                self.no_store = true;
+            }
+        }
+    }
+
+    // Whether the response must not be written to the cache
+    pub fn skips_store(&self) -> bool {
+        self.no_cache || self.no_store
+    }
+
+    // The hints as clients send them
+    pub fn to_value(&self) -> Value {
+        let mut hints = json!({});
+        if let Some(node) = &self.node {
+            hints["node"] = json!(node);
+        }
+        if self.no_cache {
+            hints["no_cache"] = json!(true);
+        }
+        if self.no_store {
+            hints["no_store"] = json!(true);
+        }
+        if let Some(timeout) = self.timeout {
+            hints["timeout_ms"] = json!(timeout.as_millis() as u64);
+        }
+        hints
+    }
+
+    // Hints we were actually sent, by their request name
+    fn sent(&self) -> impl Iterator<Item = &'static str> + '_ {
+        [
+            self.node.is_some().then_some("node"),
+            self.no_cache.then_some("no_cache"),
+            self.no_store.then_some("no_store"),
+            self.timeout.is_some().then_some("timeout_ms"),
+        ]
+        .into_iter()
+        .flatten()
+    }
+
+    // Check the hints against what the client `identity` is allowed to send
+    // and the nodes we have
+    pub fn check(
+        &self,
+        settings: &RoutingHintsSettings,
+        identity: Option<&str>,
+        rpc_list: &[Rpc],
+    ) -> Result<(), HintError> {
+        if let Some(hint) = self.sent().find(|hint| !settings.allows(identity, hint)) {
+            return Err(HintError::NotAllowed(hint.to_string()));
+        }
+
+        if let Some(node) = &self.node {
+            if !rpc_list.iter().any(|rpc| &rpc.name == node) {
+                return Err(HintError::UnknownNode(node.clone()));
+            }
+        }
+
+        if self
+            .timeout
+            .is_some_and(|timeout| timeout > settings.max_timeout)
+        {
+            return Err(HintError::Invalid(format!(
+                "timeout_ms can be at most {}",
+                settings.max_timeout.as_millis()
+            )));
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    fn rpc(name: &str) -> Rpc {
+        let mut rpc = Rpc::default();
+        rpc.name = name.to_string();
+        rpc
+    }
+
+    #[test]
+    fn test_take_hints() {
+        let mut tx = json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "method": "eth_blockNumber",
+            "blutgang": {"node": "archive-1", "no_cache": true, "no_store": true, "timeout_ms": 500},
+        });
+        let hints = RoutingHints::take(&mut tx, Some(r#"{"no_cache": false}"#)).unwrap();
+
+        assert_eq!(
+            hints,
+            Some(RoutingHints {
+                node: Some("archive-1".to_string()),
+                no_cache: true,
+                no_store: true,
+                timeout: Some(Duration::from_millis(500)),
+            })
+        );
+        assert!(tx.get(HINTS_FIELD).is_none());
+        assert_eq!(
+            RoutingHints::from_value(&hints.as_ref().unwrap().to_value()),
+            Ok(hints.unwrap())
+        );
+
+        // The header is only used if the request has no hints
+        let mut tx = json!({"jsonrpc": "2.0", "id": 1, "method": "eth_blockNumber"});
+        assert_eq!(
+            RoutingHints::take(&mut tx, Some(r#"{"no_cache": true}"#)).unwrap(),
+            Some(RoutingHints {
+                no_cache: true,
+                ..Default::default()
+            })
+        );
+        assert_eq!(RoutingHints::take(&mut tx, None).unwrap(), None);
+
+        let mut tx =
+            json!({"jsonrpc": "2.0", "id": 1, "method": "eth_call", "blutgang": {"node": 1}});
+        RoutingHints::strip(&mut tx);
+        assert_eq!(tx, json!({"jsonrpc": "2.0", "id": 1, "method": "eth_call"}));
+    }
+
+    #[test]
+    fn test_invalid_hints() {
+        let take = |hints: Value| {
+            RoutingHints::take(&mut json!({"method": "eth_call", "blutgang": hints}), None)
+        };
+
+        assert!(take(json!("archive-1")).is_err());
+        assert!(take(json!({"node": 1})).is_err());
+        assert!(take(json!({"no_cache": "yes"})).is_err());
+        assert!(take(json!({"no_store": 1})).is_err());
+        assert!(take(json!({"timeout_ms": 0})).is_err());
+        assert!(take(json!({"priority": "high"})).is_err());
+        assert!(RoutingHints::take(&mut json!({}), Some("{")).is_err());
+    }
+
+    #[test]
+    fn test_check_hints() {
+        let settings = RoutingHintsSettings {
+            allow: vec!["no_cache".to_string()],
+            identities: BTreeMap::from([(
+                "indexer.internal".to_string(),
+                vec!["node".to_string(), "timeout_ms".to_string()],
+            )]),
+            max_timeout: Duration::from_secs(1),
+            cache_control: false,
+        };
+        let rpc_list = [rpc("archive-1"), rpc("full-1")];
+
+        let no_cache = RoutingHints {
+            no_cache: true,
+            ..Default::default()
+        };
+        assert_eq!(no_cache.check(&settings, None, &rpc_list), Ok(()));
+        assert_eq!(
+            no_cache.check(&settings, Some("indexer.internal"), &rpc_list),
+            Err(HintError::NotAllowed("no_cache".to_string()))
+        );
+
+        let node = |node: &str| {
+            RoutingHints {
+                node: Some(node.to_string()),
+                ..Default::default()
+            }
+        };
+        assert_eq!(
+            node("archive-1").check(&settings, None, &rpc_list),
+            Err(HintError::NotAllowed("node".to_string()))
+        );
+        assert_eq!(
+            node("archive-1").check(&settings, Some("indexer.internal"), &rpc_list),
+            Ok(())
+        );
+        assert_eq!(
+            node("archive-2").check(&settings, Some("indexer.internal"), &rpc_list),
+            Err(HintError::UnknownNode("archive-2".to_string()))
+        );
+
+        let timeout = RoutingHints {
+            timeout: Some(Duration::from_secs(2)),
+            ..Default::default()
+        };
+        assert!(timeout
+            .check(&settings, Some("indexer.internal"), &rpc_list)
+            .is_err());
+    }
+
+    #[test]
+    fn test_cache_control() {
+        let settings = RoutingHintsSettings {
+            allow: vec!["no_store".to_string()],
+            identities: BTreeMap::from([(
+                "indexer.internal".to_string(),
+                vec!["no_cache".to_string(), "no_store".to_string()],
+            )]),
+            ..Default::default()
+        };
+
+        let mut hints = RoutingHints::default();
+        hints.add_cache_control("No-Cache, max-age=0, no-store", &settings, None);
+        // Only what the client is allowed is honored
+        assert_eq!(
+            hints,
+            RoutingHints {
+                no_store: true,
+                ..Default::default()
+            }
+        );
+        assert!(hints.skips_store());
+
+        let mut hints = RoutingHints::default();
+        hints.add_cache_control("no-cache", &settings, Some("indexer.internal"));
+        assert!(hints.no_cache && hints.skips_store());
+
+        let mut hints = RoutingHints::default();
+        hints.add_cache_control("max-age=60", &settings, Some("indexer.internal"));
+        assert_eq!(hints, RoutingHints::default());
+    }
+}
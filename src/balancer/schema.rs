@@ -0,0 +1,175 @@
+// Structural checks for upstream responses.
+//
+// A node that's reachable and following the head can still hand back broken
+// data: receipts without logs, blocks without a hash, truncated JSON. Once
+// something like that lands in the cache every client gets it for good. For
+// well-known methods we check that the response has the shape it must have
+// before it gets cached or returned, and retry on another node if it doesn't.
+//
+// This is deliberately shallow, we only look at the fields clients can't do
+// without. Errors from the node are passed through, they're not our business.
+use serde_json::Value;
+
+// Methods whose responses get checked
+const VALIDATED_METHODS: [&str; 7] = [
+    "eth_getBlockByNumber",
+    "eth_getBlockByHash",
+    "eth_getTransactionReceipt",
+    "eth_getBlockReceipts",
+    "eth_getLogs",
+    "eth_blockNumber",
+    "eth_chainId",
+];
+
+pub fn is_validated(method: &str) -> bool {
+    VALIDATED_METHODS.contains(&method)
+}
+
+fn is_quantity(value: &Value) -> bool {
+    value
+        .as_str()
+        .and_then(|quantity| quantity.strip_prefix("0x"))
+        .is_some_and(|digits| !digits.is_empty() && digits.chars().all(|c| c.is_ascii_hexdigit()))
+}
+
+fn is_hash(value: &Value) -> bool {
+    value.as_str().is_some_and(|hash| hash.len() == 66) && is_quantity(value)
+}
+
+// Pending blocks don't have a hash or number yet
+fn check_block(block: &Value, pending: bool) -> Result<(), String> {
+    if !block.is_object() {
+        return Err("block is not an object".to_string());
+    }
+    if !(is_hash(&block["hash"]) || pending && block["hash"].is_null()) {
+        return Err("block has no valid hash".to_string());
+    }
+    if !(is_quantity(&block["number"]) || pending && block["number"].is_null()) {
+        return Err("block has no valid number".to_string());
+    }
+
+    Ok(())
+}
+
+// Receipts from before Byzantium have a state `root` instead of a `status`
+fn check_receipt(receipt: &Value) -> Result<(), String> {
+    if !receipt.is_object() {
+        return Err("receipt is not an object".to_string());
+    }
+    if !(is_quantity(&receipt["status"]) || is_hash(&receipt["root"])) {
+        return Err("receipt has no valid status".to_string());
+    }
+    if !receipt["logs"].is_array() {
+        return Err("receipt has no logs".to_string());
+    }
+
+    Ok(())
+}
+
+// Check the response `rax` to `tx`, returning why it's malformed if it is
+pub fn check_response(tx: &Value, rax: &str) -> Result<(), String> {
+    let method = tx["method"].as_str().unwrap_or_default();
+    if !is_validated(method) {
+        return Ok(());
+    }
+
+    let response: Value =
+        serde_json::from_str(rax).map_err(|_| "response is not valid JSON".to_string())?;
+    if !response.is_object() {
+        return Err("response is not a JSON-RPC object".to_string());
+    }
+    if response.get("error").is_some() {
+        return Ok(());
+    }
+    let result = response
+        .get("result")
+        .ok_or_else(|| "response has neither a result nor an error".to_string())?;
+
+    match method {
+        "eth_getBlockByNumber" | "eth_getBlockByHash" if !result.is_null() => {
+            check_block(result, tx["params"][0] == "pending")
+        }
+        "eth_getTransactionReceipt" if !result.is_null() => check_receipt(result),
+        "eth_getBlockReceipts" if !result.is_null() => {
+            match result.as_array() {
+                Some(receipts) => receipts.iter().try_for_each(check_receipt),
+                None => Err("receipts are not a list".to_string()),
+            }
+        }
+        "eth_getLogs" if !result.is_array() => Err("logs are not a list".to_string()),
+        "eth_blockNumber" | "eth_chainId" if !is_quantity(result) => {
+            Err("result is not a quantity".to_string())
+        }
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    const HASH: &str = "0x88e96d4537bea4d9c05d12549907b32561d3bf31f45aae734cdc119f13406cb6";
+
+    fn request(method: &str, params: Value) -> Value {
+        json!({"jsonrpc": "2.0", "id": 1, "method": method, "params": params})
+    }
+
+    fn response(result: Value) -> String {
+        json!({"jsonrpc": "2.0", "id": 1, "result": result}).to_string()
+    }
+
+    #[test]
+    fn test_check_block() {
+        let tx = request("eth_getBlockByNumber", json!(["0x1", false]));
+
+        assert!(check_response(&tx, &response(json!({"hash": HASH, "number": "0x1"}))).is_ok());
+        assert!(check_response(&tx, &response(Value::Null)).is_ok());
+        assert!(check_response(&tx, &response(json!({"number": "0x1"}))).is_err());
+        assert!(check_response(&tx, &response(json!({"hash": "0x12", "number": "0x1"}))).is_err());
+        assert!(check_response(&tx, &response(json!({"hash": HASH, "number": "1"}))).is_err());
+
+        // Pending blocks aren't sealed yet
+        let pending = request("eth_getBlockByNumber", json!(["pending", false]));
+        let unsealed = response(json!({"hash": null, "number": null}));
+        assert!(check_response(&pending, &unsealed).is_ok());
+        assert!(check_response(&tx, &unsealed).is_err());
+    }
+
+    #[test]
+    fn test_check_receipt() {
+        let tx = request("eth_getTransactionReceipt", json!([HASH]));
+
+        assert!(check_response(&tx, &response(json!({"status": "0x1", "logs": []}))).is_ok());
+        assert!(check_response(&tx, &response(json!({"root": HASH, "logs": []}))).is_ok());
+        assert!(check_response(&tx, &response(json!({"status": "0x1"}))).is_err());
+        assert!(check_response(&tx, &response(json!({"logs": []}))).is_err());
+
+        let tx = request("eth_getBlockReceipts", json!(["0x1"]));
+        let receipts = json!([{"status": "0x1", "logs": []}, {"status": "0x0"}]);
+        assert!(check_response(&tx, &response(receipts)).is_err());
+        assert!(check_response(&tx, &response(json!([]))).is_ok());
+    }
+
+    #[test]
+    fn test_check_response() {
+        let tx = request("eth_blockNumber", json!([]));
+
+        assert!(check_response(&tx, &response(json!("0x10"))).is_ok());
+        assert!(check_response(&tx, &response(json!("0x"))).is_err());
+        assert!(check_response(&tx, "{\"jsonrpc\":\"2.0\",\"id\":1,\"res").is_err());
+        assert!(check_response(&tx, "{\"jsonrpc\":\"2.0\",\"id\":1}").is_err());
+
+        // Node errors are passed through
+        let error =
+            json!({"jsonrpc": "2.0", "id": 1, "error": {"code": -32000, "message": "nope"}});
+        assert!(check_response(&tx, &error.to_string()).is_ok());
+
+        assert!(
+            check_response(&request("eth_getLogs", json!([{}])), &response(json!({}))).is_err()
+        );
+
+        // Methods we don't know the shape of aren't checked
+        assert!(check_response(&request("eth_call", json!([])), "garbage").is_ok());
+    }
+}
@@ -0,0 +1,71 @@
+// Named node groups.
+//
+// Nodes list the groups they belong to with `groups = ["archive", "fast"]`.
+// Requests sent to `/group/<name>` are only forwarded to healthy nodes in
+// that group, so teams can target a subset of the fleet explicitly while
+// still sharing the cache with everyone else.
+use crate::{
+    balancer::selection::select::pick_among,
+    Rpc,
+};
+
+pub const GROUP_PATH: &str = "/group/";
+
+// Name of the group a request `path` targets, if any
+pub fn group_from_path(path: &str) -> Option<&str> {
+    path.strip_prefix(GROUP_PATH)
+        .map(|group| group.trim_end_matches('/'))
+        .filter(|group| !group.is_empty())
+}
+
+// Pick a node from `group` with the regular algo, as if the rest of the
+// nodes didn't exist
+pub fn pick_group(list: &mut [Rpc], group: &str, pending: bool) -> (Rpc, Option<usize>) {
+    let members = list
+        .iter()
+        .enumerate()
+        .filter(|(_, rpc)| rpc.in_group(group))
+        .map(|(index, _)| index)
+        .collect::<Vec<usize>>();
+
+    pick_among(list, &members, pending)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rpc(latency: f64, groups: &[&str]) -> Rpc {
+        let mut rpc =
+            Rpc::default().with_groups(groups.iter().map(|group| group.to_string()).collect());
+        rpc.status.latency = latency;
+        rpc.max_consecutive = 10;
+        rpc
+    }
+
+    #[test]
+    fn test_group_from_path() {
+        assert_eq!(group_from_path("/group/archive"), Some("archive"));
+        assert_eq!(group_from_path("/group/archive/"), Some("archive"));
+        assert_eq!(group_from_path("/group/"), None);
+        assert_eq!(group_from_path("/"), None);
+    }
+
+    #[test]
+    fn test_pick_group() {
+        let mut rpc_list = vec![
+            rpc(1.0, &["fast"]),
+            rpc(5.0, &["archive"]),
+            rpc(3.0, &["archive", "fast"]),
+        ];
+
+        let (rpc, index) = pick_group(&mut rpc_list, "archive", false);
+        assert_eq!(index, Some(2));
+        assert_eq!(rpc.status.latency, 3.0);
+        assert_eq!(rpc_list[2].consecutive, 1);
+
+        rpc_list[2].status.latency = 10.0;
+        assert_eq!(pick_group(&mut rpc_list, "archive", false).1, Some(1));
+        assert_eq!(pick_group(&mut rpc_list, "private", false).1, None);
+    }
+}
@@ -1,2 +1,7 @@
 pub mod cache_rules;
+pub mod groups;
+pub mod regions;
+pub mod rendezvous;
+pub mod routing;
+pub mod schedules;
 pub mod select;
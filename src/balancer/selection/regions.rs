@@ -0,0 +1,90 @@
+// Region aware node selection.
+//
+// Nodes say where they run with `region = "eu-west"`, and
+// `[blutgang.regions]` says where we run and which regions to fall back to,
+// closest first. Requests go to nodes in our own region as long as any of
+// them is healthy and can take the request, and only then to the next region
+// in the failover order. Nodes failing health checks are out of the rotation,
+// so that's what moves traffic to remote regions when local nodes go down.
+use crate::{
+    balancer::selection::select::{
+        pick,
+        pick_among,
+        pick_pending,
+    },
+    config::types::RegionSettings,
+    Rpc,
+};
+
+use serde_json::Value;
+
+// Pick a node for `tx` from the closest region that has one available
+pub fn pick_region(
+    list: &mut [Rpc],
+    regions: &RegionSettings,
+    tx: &Value,
+    pending: bool,
+) -> (Rpc, Option<usize>) {
+    let rank = |rpc: &Rpc| regions.rank(rpc.region.as_deref());
+    let closest = match list
+        .iter()
+        .filter(|rpc| rpc.is_available(tx))
+        .map(rank)
+        .min()
+    {
+        Some(closest) => closest,
+        // Nobody can take it, leave it to the regular algo
+        None if pending => return pick_pending(list),
+        None => return pick(list),
+    };
+
+    let members = list
+        .iter()
+        .enumerate()
+        .filter(|(_, rpc)| rpc.is_available(tx) && rank(rpc) == closest)
+        .map(|(index, _)| index)
+        .collect::<Vec<usize>>();
+
+    pick_among(list, &members, pending)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn rpc(latency: f64, region: Option<&str>) -> Rpc {
+        let mut rpc = Rpc::default().with_region(region.map(str::to_string));
+        rpc.status.latency = latency;
+        rpc.max_consecutive = 10;
+        rpc
+    }
+
+    #[test]
+    fn test_pick_region() {
+        let regions = RegionSettings {
+            local: "eu-west".to_string(),
+            failover: vec!["eu-central".to_string()],
+        };
+        let tx = json!({"method": "eth_blockNumber"});
+        let mut rpc_list = vec![
+            rpc(1.0, Some("us-east")),
+            rpc(5.0, Some("eu-central")),
+            rpc(9.0, Some("eu-west")),
+            rpc(0.5, None),
+        ];
+
+        // Local nodes win even if they're slower
+        assert_eq!(pick_region(&mut rpc_list, &regions, &tx, false).1, Some(2));
+
+        // Then the failover order
+        rpc_list.remove(2);
+        assert_eq!(pick_region(&mut rpc_list, &regions, &tx, false).1, Some(1));
+
+        // Then whatever's fastest
+        rpc_list.remove(1);
+        assert_eq!(pick_region(&mut rpc_list, &regions, &tx, false).1, Some(1));
+
+        assert_eq!(pick_region(&mut [], &regions, &tx, false).1, None);
+    }
+}
@@ -0,0 +1,137 @@
+// Deterministic node selection.
+//
+// With `selection = "rendezvous"`, every node gets a score for each request
+// from a hash of the request and the node's name, and the request goes to the
+// available node with the highest score. The same request always lands on the
+// same node, which helps providers that cache on their side and makes it easy
+// to tell which node answered what. When a node leaves the rotation only the
+// requests it had move, spread over the rest, and they move back once it's
+// healthy again.
+//
+// Retries go down the same order, so a request that failed on its node is
+// retried on its second choice, then its third.
+use crate::{
+    balancer::selection::select::pick,
+    Rpc,
+};
+
+use serde_json::Value;
+
+fn score(key: &[u8], name: &str) -> u64 {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(key);
+    hasher.update(name.as_bytes());
+    u64::from_be_bytes(hasher.finalize().as_bytes()[..8].try_into().unwrap())
+}
+
+// Pick the node for `tx`, hashed to `key`, skipping the first `skip` choices
+pub fn pick_rendezvous(
+    list: &mut [Rpc],
+    key: &[u8],
+    tx: &Value,
+    pending: bool,
+    skip: usize,
+) -> (Rpc, Option<usize>) {
+    let available = list
+        .iter()
+        .enumerate()
+        .filter(|(_, rpc)| rpc.is_available(tx))
+        .map(|(index, rpc)| (index, rpc.pending_state));
+    let mut candidates: Vec<usize> = if pending {
+        // Nodes with pending state if any of them can take it
+        let (pending_nodes, others): (Vec<_>, Vec<_>) =
+            available.partition(|(_, pending)| *pending);
+        if pending_nodes.is_empty() {
+            others
+        } else {
+            pending_nodes
+        }
+        .into_iter()
+        .map(|(index, _)| index)
+        .collect()
+    } else {
+        available.map(|(index, _)| index).collect()
+    };
+
+    if candidates.is_empty() {
+        // Nobody can take it, leave it to the regular algo
+        return pick(list);
+    }
+
+    candidates.sort_unstable_by_key(|&index| std::cmp::Reverse(score(key, &list[index].name)));
+    let index = candidates[skip % candidates.len()];
+    (list[index].clone(), Some(index))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::time::Duration;
+
+    fn rpc_list() -> Vec<Rpc> {
+        (0..4)
+            .map(|i| Rpc::new(format!("https://node{}.example.com", i), None, 6, 0, 10.0))
+            .collect()
+    }
+
+    fn keys() -> Vec<[u8; 32]> {
+        (0..200u32)
+            .map(|i| *blake3::hash(&i.to_le_bytes()).as_bytes())
+            .collect()
+    }
+
+    #[test]
+    fn test_pick_rendezvous() {
+        let tx = json!({"method": "eth_call"});
+        let mut list = rpc_list();
+
+        let picks: Vec<usize> = keys()
+            .iter()
+            .map(|key| pick_rendezvous(&mut list, key, &tx, false, 0).1.unwrap())
+            .collect();
+        // Same request, same node, and every node gets some
+        for (key, pick) in keys().iter().zip(&picks) {
+            assert_eq!(
+                pick_rendezvous(&mut list, key, &tx, false, 0).1,
+                Some(*pick)
+            );
+        }
+        assert!((0..4).all(|node| picks.contains(&node)));
+
+        // Retries go to another node
+        let key = &keys()[0];
+        let second = pick_rendezvous(&mut list, key, &tx, false, 1).1.unwrap();
+        assert_ne!(second, picks[0]);
+
+        // Only requests of a node that's cooling down move
+        list[picks[0]].status.cooldown.hold(Duration::from_secs(10));
+        for (key, pick) in keys().iter().zip(&picks) {
+            let moved = pick_rendezvous(&mut list, key, &tx, false, 0).1.unwrap();
+            if *pick == picks[0] {
+                assert_ne!(moved, *pick);
+            } else {
+                assert_eq!(moved, *pick);
+            }
+        }
+        assert_eq!(
+            pick_rendezvous(&mut list, key, &tx, false, 0).1,
+            Some(second)
+        );
+    }
+
+    #[test]
+    fn test_pick_rendezvous_pending() {
+        let tx = json!({"method": "eth_getBalance"});
+        let mut list = rpc_list();
+        list[2].pending_state = true;
+
+        for key in keys() {
+            assert_eq!(pick_rendezvous(&mut list, &key, &tx, true, 0).1, Some(2));
+        }
+        assert_eq!(
+            pick_rendezvous(&mut Vec::new(), &keys()[0], &tx, false, 0).1,
+            None
+        );
+    }
+}
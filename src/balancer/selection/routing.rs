@@ -0,0 +1,86 @@
+// Which node a request goes to.
+//
+// A node the client named with a routing hint gets it no matter what.
+// Otherwise historical calls go to the node with the least history that still
+// has their block, then group members, nodes a schedule prefers, the closest
+// region, and our selection mode in that order. Nodes that can't take the
+// request right now are skipped, see `route_available`.
+use crate::{
+    balancer::selection::{
+        groups::pick_group,
+        regions::pick_region,
+        rendezvous::pick_rendezvous,
+        schedules::pick_preferred,
+        select::{
+            pick,
+            pick_named,
+            pick_pending,
+            route_available,
+        },
+    },
+    config::types::{
+        RegionSettings,
+        SelectionMode,
+    },
+    health::horizon::StateHorizons,
+    Rpc,
+};
+
+use serde_json::Value;
+
+#[derive(Clone, Copy)]
+pub struct Routing<'a> {
+    // Node named in the request's routing hints
+    pub node: Option<&'a str>,
+    // Block a historical call needs state for, and who has it
+    pub history_block: Option<u64>,
+    pub horizons: &'a StateHorizons,
+    // Only nodes in this group
+    pub group: Option<&'a str>,
+    // Nodes to try first while a schedule prefers them
+    pub prefer: Option<&'a str>,
+    pub regions: Option<&'a RegionSettings>,
+    pub selection: SelectionMode,
+    // Whether the request is for pending state
+    pub pending: bool,
+}
+
+impl Routing<'_> {
+    // Node for `tx` after `retries` failed tries. `key` orders the nodes for
+    // rendezvous hashing.
+    pub fn pick(
+        &self,
+        list: &mut [Rpc],
+        tx: &Value,
+        key: &[u8],
+        retries: usize,
+    ) -> (Rpc, Option<usize>) {
+        if let Some(node) = self.node {
+            return pick_named(list, node);
+        }
+
+        let picked = if let Some(picked) = self
+            .history_block
+            .and_then(|block| self.horizons.pick(list, block, self.group))
+        {
+            picked
+        } else if let Some(group) = self.group {
+            pick_group(list, group, self.pending)
+        } else if let Some(picked) = self
+            .prefer
+            .and_then(|prefer| pick_preferred(list, prefer, self.pending))
+        {
+            picked
+        } else if let Some(regions) = self.regions {
+            pick_region(list, regions, tx, self.pending)
+        } else if self.selection == SelectionMode::Rendezvous {
+            // Retries go to the next node in the request's order
+            pick_rendezvous(list, key, tx, self.pending, retries)
+        } else if self.pending {
+            pick_pending(list)
+        } else {
+            pick(list)
+        };
+        route_available(list, picked, tx)
+    }
+}
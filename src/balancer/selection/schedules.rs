@@ -0,0 +1,413 @@
+// Time-windowed routing policies.
+//
+// `[blutgang.schedules.<name>]` tables change how we route while their
+// `when` matches the current time, in UTC. `when` is a cron expression
+// (minute hour day-of-month month day-of-week) matching every minute the
+// schedule is active, so `* 0-7,20-23 * * *` is active from 20:00 to 07:59
+// and `0-29 3 * * *` from 03:00 to 03:29. While active, a schedule can:
+//
+// - `prefer` a node group, which gets every request that doesn't target a
+//   group of its own as long as any of its nodes is healthy
+// - override `selection`, e.g. with `rendezvous` to spread requests over
+//   every node instead of sending them to the fastest
+// - `drain` nodes by name, like `blutgang_drainRpc` does, and put them back
+//   once it's over
+//
+// If several schedules are active, the first by name to set `prefer` or
+// `selection` wins, and every node any of them drains is drained.
+use crate::{
+    balancer::selection::groups::pick_group,
+    config::types::{
+        ScheduleSettings,
+        SelectionMode,
+    },
+    health::maintenance::Maintenance,
+    log_info,
+    Rpc,
+    Settings,
+};
+
+use std::{
+    fmt,
+    str::FromStr,
+    sync::{
+        Arc,
+        Mutex,
+        RwLock,
+    },
+    time::Duration,
+};
+
+use chrono::{
+    DateTime,
+    Datelike,
+    Timelike,
+    Utc,
+};
+use tokio::time::{
+    interval,
+    MissedTickBehavior,
+};
+
+// How often we check which schedules are active
+const SCHEDULE_INTERVAL: Duration = Duration::from_secs(10);
+
+// Set of values a cron field matches, as bits
+fn parse_field(field: &str, min: u32, max: u32) -> Result<u64, String> {
+    let mut bits = 0u64;
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => {
+                let step = step
+                    .parse::<u32>()
+                    .ok()
+                    .filter(|step| *step > 0)
+                    .ok_or_else(|| format!("invalid step in {}", part))?;
+                (range, step)
+            }
+            None => (part, 1),
+        };
+        let value = |value: &str| {
+            value
+                .parse::<u32>()
+                .ok()
+                .filter(|value| (min..=max).contains(value))
+                .ok_or_else(|| format!("{} is not between {} and {}", value, min, max))
+        };
+        let (start, end) = match range.split_once('-') {
+            _ if range == "*" => (min, max),
+            Some((start, end)) => (value(start)?, value(end)?),
+            // `5/15` is every 15 from 5 on
+            None if step > 1 => (value(range)?, max),
+            None => (value(range)?, value(range)?),
+        };
+        if start > end {
+            return Err(format!("{} is an empty range", range));
+        }
+
+        for value in (start..=end).step_by(step as usize) {
+            bits |= 1 << value;
+        }
+    }
+    Ok(bits)
+}
+
+// Cron expression, matched against every minute
+#[derive(Clone, PartialEq, Eq)]
+pub struct Cron {
+    expr: String,
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    // Like in cron, if both days and weekdays are restricted either can match
+    any_day: bool,
+    any_weekday: bool,
+}
+
+impl fmt::Debug for Cron {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self.expr)
+    }
+}
+
+impl FromStr for Cron {
+    type Err = String;
+
+    fn from_str(expr: &str) -> Result<Self, Self::Err> {
+        let fields: Vec<&str> = expr.split_whitespace().collect();
+        if fields.len() != 5 {
+            return Err(format!("{} doesn't have 5 fields", expr));
+        }
+
+        let mut weekdays = parse_field(fields[4], 0, 7)?;
+        // Sunday is 0 and 7
+        if weekdays & (1 << 7) != 0 {
+            weekdays = (weekdays | 1) & !(1 << 7);
+        }
+
+        Ok(Cron {
+            expr: expr.to_string(),
+            minutes: parse_field(fields[0], 0, 59)?,
+            hours: parse_field(fields[1], 0, 23)?,
+            days: parse_field(fields[2], 1, 31)?,
+            months: parse_field(fields[3], 1, 12)?,
+            weekdays,
+            any_day: fields[2] == "*",
+            any_weekday: fields[4] == "*",
+        })
+    }
+}
+
+impl Cron {
+    pub fn matches(&self, time: &DateTime<Utc>) -> bool {
+        let has = |bits: u64, value: u32| bits & (1 << value) != 0;
+        let day = has(self.days, time.day());
+        let weekday = has(self.weekdays, time.weekday().num_days_from_sunday());
+        let day = if self.any_day || self.any_weekday {
+            day && weekday
+        } else {
+            day || weekday
+        };
+
+        day && has(self.minutes, time.minute())
+            && has(self.hours, time.hour())
+            && has(self.months, time.month())
+    }
+}
+
+// Pick from the `prefer`red group, if any of its nodes is in rotation
+pub fn pick_preferred(
+    list: &mut [Rpc],
+    prefer: &str,
+    pending: bool,
+) -> Option<(Rpc, Option<usize>)> {
+    list.iter()
+        .any(|rpc| rpc.in_group(prefer))
+        .then(|| pick_group(list, prefer, pending))
+}
+
+// What the active schedules change about routing
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Policy {
+    pub prefer: Option<String>,
+    pub selection: Option<SelectionMode>,
+}
+
+#[derive(Debug, Default)]
+pub struct Scheduler {
+    policy: RwLock<Policy>,
+    // Nodes we drained, so we only ever put back those
+    drained: Mutex<Vec<String>>,
+}
+
+impl Scheduler {
+    pub fn policy(&self) -> Policy {
+        self.policy
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    // Apply the schedules active at `now`, waiting up to `wait` for nodes we
+    // drain to finish their requests
+    pub async fn apply(
+        &self,
+        settings: Option<&ScheduleSettings>,
+        now: DateTime<Utc>,
+        rpc_list: &Arc<RwLock<Vec<Rpc>>>,
+        poverty_list: &Arc<RwLock<Vec<Rpc>>>,
+        maintenance: &Maintenance,
+        wait: Duration,
+    ) {
+        let active: Vec<_> = settings
+            .iter()
+            .flat_map(|settings| settings.schedules.iter())
+            .filter(|(_, schedule)| schedule.when.matches(&now))
+            .collect();
+
+        let policy = Policy {
+            prefer: active
+                .iter()
+                .find_map(|(_, schedule)| schedule.prefer.clone()),
+            selection: active.iter().find_map(|(_, schedule)| schedule.selection),
+        };
+        {
+            let mut current = self.policy.write().unwrap_or_else(|e| e.into_inner());
+            if *current != policy {
+                log_info!("Routing schedule changed to {:?}", policy);
+                *current = policy;
+            }
+        }
+
+        let wanted: Vec<&String> = active
+            .iter()
+            .flat_map(|(_, schedule)| &schedule.drain)
+            .collect();
+
+        // Put back nodes whose schedules are over
+        let over: Vec<String> = {
+            let mut drained = self.drained.lock().unwrap_or_else(|e| e.into_inner());
+            let (over, still) = drained.drain(..).partition(|name| !wanted.contains(&name));
+            *drained = still;
+            over
+        };
+        for name in over {
+            maintenance.enable(rpc_list, &name);
+        }
+
+        for name in wanted {
+            if self
+                .drained
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .contains(name)
+            {
+                continue;
+            }
+            // Nodes we don't have, or that were drained by hand, stay as they are
+            if maintenance
+                .drain(rpc_list, poverty_list, name, wait)
+                .await
+                .is_some()
+            {
+                self.drained
+                    .lock()
+                    .unwrap_or_else(|e| e.into_inner())
+                    .push(name.clone());
+            }
+        }
+    }
+}
+
+// Keep applying the schedules in `config`, picking up changes to them
+pub async fn run_schedules(
+    scheduler: Arc<Scheduler>,
+    config: Arc<RwLock<Settings>>,
+    rpc_list: Arc<RwLock<Vec<Rpc>>>,
+    poverty_list: Arc<RwLock<Vec<Rpc>>>,
+    maintenance: Arc<Maintenance>,
+) {
+    let mut ticker = interval(SCHEDULE_INTERVAL);
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        ticker.tick().await;
+        let (settings, ttl) = {
+            let config = config.read().unwrap();
+            (config.schedules.clone(), config.ttl)
+        };
+        let wait = Duration::from_millis(ttl.try_into().unwrap_or(u64::MAX));
+
+        scheduler
+            .apply(
+                settings.as_ref(),
+                Utc::now(),
+                &rpc_list,
+                &poverty_list,
+                &maintenance,
+                wait,
+            )
+            .await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::types::Schedule;
+    use chrono::TimeZone;
+    use std::collections::BTreeMap;
+
+    fn at(day: u32, hour: u32, minute: u32) -> DateTime<Utc> {
+        // October 2026 starts on a Thursday
+        Utc.with_ymd_and_hms(2026, 10, day, hour, minute, 0)
+            .unwrap()
+    }
+
+    fn rpc(name: &str) -> Rpc {
+        let mut rpc = Rpc::default();
+        rpc.name = name.to_string();
+        rpc
+    }
+
+    #[test]
+    fn test_pick_preferred() {
+        let mut list = vec![
+            rpc("fast"),
+            rpc("cheap").with_groups(vec!["cheap".to_string()]),
+        ];
+        assert_eq!(
+            pick_preferred(&mut list, "cheap", false).unwrap().1,
+            Some(1)
+        );
+        // Everyone else takes over if the group is out of rotation
+        list.pop();
+        assert!(pick_preferred(&mut list, "cheap", false).is_none());
+    }
+
+    #[test]
+    fn test_cron() {
+        let off_peak: Cron = "* 0-7,20-23 * * *".parse().unwrap();
+        assert!(off_peak.matches(&at(1, 20, 0)));
+        assert!(off_peak.matches(&at(1, 7, 59)));
+        assert!(!off_peak.matches(&at(1, 8, 0)));
+
+        let backups: Cron = "*/15 3 * * 6,7".parse().unwrap();
+        // Saturday and Sunday
+        assert!(backups.matches(&at(3, 3, 45)));
+        assert!(backups.matches(&at(4, 3, 0)));
+        assert!(!backups.matches(&at(4, 3, 1)));
+        assert!(!backups.matches(&at(5, 3, 0)));
+
+        // Either the 1st or a Monday
+        let either: Cron = "0 12 1 * 1".parse().unwrap();
+        assert!(either.matches(&at(1, 12, 0)));
+        assert!(either.matches(&at(5, 12, 0)));
+        assert!(!either.matches(&at(6, 12, 0)));
+
+        assert!("* * * *".parse::<Cron>().is_err());
+        assert!("60 * * * *".parse::<Cron>().is_err());
+        assert!("*/0 * * * *".parse::<Cron>().is_err());
+        assert!("* 5-2 * * *".parse::<Cron>().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_apply_schedules() {
+        let settings = ScheduleSettings {
+            schedules: BTreeMap::from([
+                (
+                    "backups".to_string(),
+                    Schedule {
+                        when: "0-29 3 * * *".parse().unwrap(),
+                        prefer: None,
+                        selection: Some(SelectionMode::Rendezvous),
+                        drain: vec!["archive".to_string(), "unknown".to_string()],
+                    },
+                ),
+                (
+                    "off_peak".to_string(),
+                    Schedule {
+                        when: "* 0-7 * * *".parse().unwrap(),
+                        prefer: Some("cheap".to_string()),
+                        selection: Some(SelectionMode::Latency),
+                        drain: Vec::new(),
+                    },
+                ),
+            ]),
+        };
+        let rpc_list = Arc::new(RwLock::new(vec![rpc("archive"), rpc("fast")]));
+        let poverty_list = Arc::new(RwLock::new(Vec::new()));
+        let maintenance = Maintenance::default();
+        let scheduler = Scheduler::default();
+        let apply = |now| {
+            scheduler.apply(
+                Some(&settings),
+                now,
+                &rpc_list,
+                &poverty_list,
+                &maintenance,
+                Duration::ZERO,
+            )
+        };
+
+        apply(at(1, 3, 0)).await;
+        assert_eq!(
+            scheduler.policy(),
+            Policy {
+                prefer: Some("cheap".to_string()),
+                selection: Some(SelectionMode::Rendezvous),
+            }
+        );
+        assert_eq!(maintenance.drained().len(), 1);
+        assert_eq!(rpc_list.read().unwrap().len(), 1);
+
+        apply(at(1, 3, 30)).await;
+        assert_eq!(scheduler.policy().selection, Some(SelectionMode::Latency));
+        assert!(maintenance.drained().is_empty());
+        assert_eq!(rpc_list.read().unwrap().len(), 2);
+
+        apply(at(1, 12, 0)).await;
+        assert_eq!(scheduler.policy(), Policy::default());
+    }
+}
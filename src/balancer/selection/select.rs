@@ -1,8 +1,9 @@
 use crate::Rpc;
+use serde_json::Value;
 use std::time::SystemTime;
 
 // Generic entry point fn to select the next rpc and return its position
-pub fn pick(list: &mut Vec<Rpc>) -> (Rpc, Option<usize>) {
+pub fn pick(list: &mut [Rpc]) -> (Rpc, Option<usize>) {
     // If len is 1, return the only element
     if list.len() == 1 {
         return (list[0].clone(), Some(0));
@@ -10,16 +11,128 @@ pub fn pick(list: &mut Vec<Rpc>) -> (Rpc, Option<usize>) {
         return (Rpc::default(), None);
     }
 
-    algo(list)
+    let picked = algo(list);
+    ramp_canaries(list, picked, rand::random())
+}
+
+// Nodes still ramping up only take their share of the requests they're picked
+// for, the rest go to the best node that's done ramping
+fn ramp_canaries(list: &[Rpc], picked: (Rpc, Option<usize>), roll: f64) -> (Rpc, Option<usize>) {
+    let canary = match picked
+        .1
+        .and_then(|index| list[index].status.canary.as_ref())
+    {
+        Some(canary) => canary,
+        None => return picked,
+    };
+    if canary.admits(roll) {
+        return picked;
+    }
+
+    match argsort(list)
+        .into_iter()
+        .find(|&index| list[index].canary_share() >= 1.0)
+    {
+        Some(index) => (list[index].clone(), Some(index)),
+        None => picked,
+    }
+}
+
+// Nodes whose profile says they can't answer `tx`, that are cooling down
+// after rate limiting us or out of request budget don't get it, the best node
+// that can does. If none of them can we leave it to the one we picked.
+pub fn route_available(
+    list: &[Rpc],
+    picked: (Rpc, Option<usize>),
+    tx: &Value,
+) -> (Rpc, Option<usize>) {
+    if picked.1.is_none() || picked.0.is_available(tx) {
+        return picked;
+    }
+
+    match argsort(list)
+        .into_iter()
+        .find(|&index| list[index].is_available(tx))
+    {
+        Some(index) => (list[index].clone(), Some(index)),
+        None => picked,
+    }
+}
+
+// Pick the fastest node designated for pending state, falling back to the
+// regular rotation if none of them are active
+pub fn pick_pending(list: &mut [Rpc]) -> (Rpc, Option<usize>) {
+    let choice = list
+        .iter()
+        .enumerate()
+        .filter(|(_, rpc)| rpc.pending_state)
+        .min_by(|a, b| a.1.status.latency.total_cmp(&b.1.status.latency))
+        .map(|(index, _)| index);
+
+    match choice {
+        Some(index) => (list[index].clone(), Some(index)),
+        None => pick(list),
+    }
+}
+
+// Pick the node called `name`, for requests that asked for a specific one
+pub fn pick_named(list: &mut [Rpc], name: &str) -> (Rpc, Option<usize>) {
+    match list.iter().position(|rpc| rpc.name == name) {
+        Some(index) => (list[index].clone(), Some(index)),
+        None => (Rpc::default(), None),
+    }
+}
+
+// Pick from the nodes at `members` with the regular algo, as if the rest of
+// `list` didn't exist
+pub fn pick_among(list: &mut [Rpc], members: &[usize], pending: bool) -> (Rpc, Option<usize>) {
+    let mut subset = members
+        .iter()
+        .map(|&index| list[index].clone())
+        .collect::<Vec<Rpc>>();
+
+    let (rpc, position) = if pending {
+        pick_pending(&mut subset)
+    } else {
+        pick(&mut subset)
+    };
+
+    // Keep whatever the algo updated so limits still apply across subsets
+    for (rpc, &index) in subset.iter().zip(members) {
+        list[index].consecutive = rpc.consecutive;
+        list[index].last_used = rpc.last_used;
+    }
+
+    (rpc, position.map(|position| members[position]))
+}
+
+// Pick with the regular algo among the nodes that can take `tx` right now,
+// leaving out the ones named in `tried`. Only nodes in `group` count if it's
+// set. Unlike `route_available`, we never fall back to any other node.
+pub fn pick_available(
+    list: &mut [Rpc],
+    tx: &Value,
+    group: Option<&str>,
+    tried: &[String],
+) -> (Rpc, Option<usize>) {
+    let members = list
+        .iter()
+        .enumerate()
+        .filter(|(_, rpc)| group.map_or(true, |group| rpc.in_group(group)))
+        .filter(|(_, rpc)| rpc.is_available(tx) && !tried.contains(&rpc.name))
+        .map(|(index, _)| index)
+        .collect::<Vec<usize>>();
+
+    pick_among(list, &members, false)
 }
 
 // Sorting algo
 pub fn argsort(data: &[Rpc]) -> Vec<usize> {
     let mut indices = (0..data.len()).collect::<Vec<usize>>();
 
-    // Use sort_by_cached_key with a closure that compares latency
-    // Uses pdqsort and does not allocate so should be fast
-    indices.sort_unstable_by_key(|&index| data[index].status.latency as u64);
+    // Use sort_by_cached_key with a closure that compares latency, or the
+    // score if scoring is on. Uses pdqsort and does not allocate so should be fast
+    indices.sort_unstable_by_key(|&index| data[index].rank_latency() as u64);
 
     indices
 }
@@ -30,12 +143,21 @@ pub fn argsort(data: &[Rpc]) -> Vec<usize> {
 // In order to have custom algos, you must add and enable the feature,
 // as well as modify the cfg of the default algo to accomodate your new feature.
 //
+#[cfg(all(
+    feature = "selection-epsilon-greedy",
+    any(feature = "selection-random", feature = "old-weighted-round-robin"),
+))]
+compile_error!(
+    "selection-epsilon-greedy can't be combined with selection-random or old-weighted-round-robin, enable only one selection algo"
+);
+
 #[cfg(all(
     feature = "selection-weighed-round-robin",
     not(feature = "selection-random"),
     not(feature = "old-weighted-round-robin"),
+    not(feature = "selection-epsilon-greedy"),
 ))]
-fn algo(list: &mut Vec<Rpc>) -> (Rpc, Option<usize>) {
+fn algo(list: &mut [Rpc]) -> (Rpc, Option<usize>) {
     // Sort by latency
     let indices = argsort(list);
 
@@ -84,7 +206,7 @@ fn algo(list: &mut [Rpc]) -> (Rpc, Option<usize>) {
     feature = "selection-weighed-round-robin",
     feature = "old-weighted-round-robin",
 ))]
-fn algo(list: &mut Vec<Rpc>) -> (Rpc, Option<usize>) {
+fn algo(list: &mut [Rpc]) -> (Rpc, Option<usize>) {
     // Sort by latency
     let indices = argsort(list);
 
@@ -99,6 +221,75 @@ fn algo(list: &mut Vec<Rpc>) -> (Rpc, Option<usize>) {
     (list[indices[0]].clone(), Some(indices[0]))
 }
 
+// Share of requests sent to a random node, so we notice when slow nodes get better
+#[cfg(all(
+    feature = "selection-weighed-round-robin",
+    feature = "selection-epsilon-greedy"
+))]
+const EXPLORATION_RATE: f64 = 0.05;
+
+#[cfg(all(
+    feature = "selection-weighed-round-robin",
+    feature = "selection-epsilon-greedy"
+))]
+fn algo(list: &mut [Rpc]) -> (Rpc, Option<usize>) {
+    use rand::Rng;
+
+    let mut rng = rand::thread_rng();
+    let explore = if rng.gen::<f64>() < EXPLORATION_RATE {
+        Some(rng.gen_range(0..list.len()))
+    } else {
+        None
+    };
+
+    epsilon_greedy(list, explore)
+}
+
+// Picks the node with the best long term latency that meets our requirements,
+// or the node at `explore` if set.
+#[cfg(all(
+    feature = "selection-weighed-round-robin",
+    feature = "selection-epsilon-greedy"
+))]
+fn epsilon_greedy(list: &mut [Rpc], explore: Option<usize>) -> (Rpc, Option<usize>) {
+    let time = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .expect("Failed to get current time")
+        .as_micros();
+
+    let choice = match explore {
+        Some(index) => index,
+        None => {
+            let mut indices = (0..list.len()).collect::<Vec<usize>>();
+            indices.sort_unstable_by(|a, b| {
+                list[*a]
+                    .latency_score()
+                    .total_cmp(&list[*b].latency_score())
+            });
+
+            // Fall back to the best node if all of them are maxed out
+            indices
+                .iter()
+                .copied()
+                .find(|i| {
+                    list[*i].max_consecutive > list[*i].consecutive
+                        && time.saturating_sub(list[*i].last_used) > list[*i].min_time_delta
+                })
+                .unwrap_or(indices[0])
+        }
+    };
+
+    for (i, rpc) in list.iter_mut().enumerate() {
+        if i != choice {
+            rpc.consecutive = 0;
+        }
+    }
+
+    list[choice].consecutive += 1;
+    list[choice].last_used = time;
+    (list[choice].clone(), Some(choice))
+}
+
 // Tests
 #[cfg(test)]
 mod tests {
@@ -121,6 +312,24 @@ mod tests {
         assert_eq!(v[0].get_url(), vx[0].get_url());
     }
 
+    #[test]
+    fn test_sort_by_score() {
+        let mut rpc1 = Rpc::default();
+        let mut rpc2 = Rpc::default();
+        rpc1.status.latency = 1.0;
+        rpc2.status.latency = 2.0;
+
+        // The faster node keeps timing out
+        rpc1.status.score.record(true);
+        rpc1.status.score.update(
+            1.0,
+            Some(0),
+            &crate::config::types::ScoringSettings::default(),
+        );
+
+        assert_eq!(argsort(&[rpc1, rpc2]), &[1, 0]);
+    }
+
     // Test picking the fastest RPC
     // Change the latencies of the other ones to simulate
     // real network fluctuations.
@@ -200,4 +409,182 @@ mod tests {
         assert_eq!(rpc.status.latency, 7.0);
         assert_eq!(index, Some(1));
     }
+
+    #[test]
+    fn test_pick_canary() {
+        let settings = crate::config::types::CanarySettings {
+            start_share: 0.5,
+            window: std::time::Duration::from_secs(3600),
+        };
+        let mut rpc1 = Rpc::default().with_canary(Some(&settings));
+        let mut rpc2 = Rpc::default();
+        let mut rpc3 = Rpc::default();
+        rpc1.status.latency = 1.0;
+        rpc2.status.latency = 7.0;
+        rpc3.status.latency = 5.0;
+        let list = vec![rpc1, rpc2, rpc3];
+
+        // The fastest node is new, so half the time we go for the next best
+        let picked = (list[0].clone(), Some(0));
+        assert_eq!(ramp_canaries(&list, picked.clone(), 0.25).1, Some(0));
+        assert_eq!(ramp_canaries(&list, picked, 0.75).1, Some(2));
+
+        // Only canaries left, nothing to fall back to
+        let list = vec![list[0].clone()];
+        let picked = (list[0].clone(), Some(0));
+        assert_eq!(ramp_canaries(&list, picked, 0.75).1, Some(0));
+    }
+
+    #[test]
+    fn test_route_available() {
+        use crate::rpc::profile::Provider;
+        use serde_json::json;
+
+        let mut rpc1 = Rpc::default().with_profile(Some(Provider::Geth));
+        let mut rpc2 = Rpc::default().with_profile(Some(Provider::Erigon));
+        let mut rpc3 = Rpc::default().with_profile(Some(Provider::Alchemy));
+        rpc1.status.latency = 1.0;
+        rpc2.status.latency = 7.0;
+        rpc3.status.latency = 5.0;
+        let mut list = vec![rpc1, rpc2, rpc3];
+
+        let trace = json!({"method": "trace_block", "params": ["0x1"]});
+        let picked = (list[0].clone(), Some(0));
+        assert_eq!(route_available(&list, picked.clone(), &trace).1, Some(2));
+        let call = json!({"method": "eth_call", "params": []});
+        assert_eq!(route_available(&list, picked, &call).1, Some(0));
+
+        // Too wide for Alchemy
+        let logs = json!({"method": "eth_getLogs", "params": [{"fromBlock": "0x0", "toBlock": "0xfffff"}]});
+        let picked = (list[2].clone(), Some(2));
+        assert_eq!(route_available(&list, picked, &logs).1, Some(0));
+
+        // Cooling down after a rate limit
+        list[0].status.cooldown.start(
+            Some(std::time::Duration::from_secs(60)),
+            &Default::default(),
+        );
+        let picked = (list[0].clone(), Some(0));
+        assert_eq!(route_available(&list, picked.clone(), &call).1, Some(2));
+
+        // Out of budget for the day
+        list[2] = list[2]
+            .clone()
+            .with_budget(Some(&crate::config::types::BudgetSettings {
+                daily: Some(1),
+                monthly: None,
+                pacing: false,
+            }));
+        list[2].status.budget.record();
+        assert_eq!(route_available(&list, picked, &call).1, Some(1));
+
+        // Nobody can, so the picked node gets it
+        let sign = json!({"method": "eth_sign", "params": []});
+        let list = vec![list[2].clone()];
+        let picked = (list[0].clone(), Some(0));
+        assert_eq!(route_available(&list, picked, &sign).1, Some(0));
+    }
+
+    #[test]
+    fn test_pick_available() {
+        use serde_json::json;
+
+        let rpc = |name: &str, latency: f64, group: &str| {
+            let mut rpc = Rpc::default().with_groups(vec![group.to_string()]);
+            rpc.name = name.to_string();
+            rpc.status.latency = latency;
+            rpc.max_consecutive = 10;
+            rpc
+        };
+        let mut list = vec![
+            rpc("fast", 1.0, "public"),
+            rpc("slow", 5.0, "archive"),
+            rpc("archive", 3.0, "archive"),
+        ];
+        let call = json!({"method": "eth_call", "params": []});
+
+        assert_eq!(pick_available(&mut list, &call, None, &[]).1, Some(0));
+        assert_eq!(
+            pick_available(&mut list, &call, Some("archive"), &[]).1,
+            Some(2)
+        );
+
+        // Cooling down, or already tried
+        list[2].status.cooldown.start(
+            Some(std::time::Duration::from_secs(60)),
+            &Default::default(),
+        );
+        assert_eq!(
+            pick_available(&mut list, &call, Some("archive"), &[]).1,
+            Some(1)
+        );
+        assert_eq!(
+            pick_available(&mut list, &call, Some("archive"), &["slow".to_string()]).1,
+            None
+        );
+    }
+
+    #[test]
+    fn test_pick_pending() {
+        let mut rpc1 = Rpc::default();
+        let mut rpc2 = Rpc::default().with_pending_state(true);
+        let mut rpc3 = Rpc::default().with_pending_state(true);
+
+        rpc1.status.latency = 1.0;
+        rpc2.status.latency = 7.0;
+        rpc3.status.latency = 5.0;
+
+        let mut rpc_list = vec![rpc1, rpc2, rpc3];
+        let (_, index) = pick_pending(&mut rpc_list);
+        assert_eq!(index, Some(2));
+
+        // Regular rotation if no node has pending state
+        let mut rpc_list = vec![Rpc::default()];
+        let (_, index) = pick_pending(&mut rpc_list);
+        assert_eq!(index, Some(0));
+    }
+
+    #[cfg(all(
+        feature = "selection-weighed-round-robin",
+        feature = "selection-epsilon-greedy"
+    ))]
+    #[test]
+    fn test_epsilon_greedy_prefers_consistent() {
+        let mut rpc1 = Rpc::new("http://127.0.0.1:8545".to_string(), None, 10, 0, 10.0);
+        let mut rpc2 = Rpc::new("http://127.0.0.1:8546".to_string(), None, 10, 0, 10.0);
+
+        // rpc1 is faster on average, but all over the place
+        for latency in [1.0, 20.0, 1.0, 20.0, 1.0, 20.0] {
+            rpc1.update_latency(latency);
+        }
+        for latency in [9.0, 10.0, 9.0, 10.0, 9.0, 10.0] {
+            rpc2.update_latency(latency);
+        }
+
+        let mut rpc_list = vec![rpc1, rpc2];
+        let (_, index) = epsilon_greedy(&mut rpc_list, None);
+        assert_eq!(index, Some(1));
+
+        // Exploring picks whatever we rolled
+        let (_, index) = epsilon_greedy(&mut rpc_list, Some(0));
+        assert_eq!(index, Some(0));
+        assert_eq!(rpc_list[1].consecutive, 0);
+    }
+
+    #[cfg(all(
+        feature = "selection-weighed-round-robin",
+        feature = "selection-epsilon-greedy"
+    ))]
+    #[test]
+    fn test_epsilon_greedy_respects_max_consecutive() {
+        let mut rpc1 = Rpc::new("http://127.0.0.1:8545".to_string(), None, 1, 0, 10.0);
+        let mut rpc2 = Rpc::new("http://127.0.0.1:8546".to_string(), None, 10, 0, 10.0);
+        rpc1.update_latency(1.0);
+        rpc1.consecutive = 1;
+        rpc2.update_latency(5.0);
+
+        let mut rpc_list = vec![rpc1, rpc2];
+        let (_, index) = epsilon_greedy(&mut rpc_list, None);
+        assert_eq!(index, Some(1));
+    }
 }
@@ -0,0 +1,263 @@
+// Snapshots of the cache that can be shipped to other instances.
+//
+// A snapshot is a zstd compressed stream of every cached response that can't
+// be reorged anymore. Entries we're still tracking in the head cache are left
+// out, so importing a snapshot never brings in data from a stale fork.
+//
+// Layout, before compression:
+//
+// magic | hash algo (1 byte) | (key len u32 | key | value len u32 | value)* | u32::MAX
+//
+// Importing only adds entries we don't have yet.
+use crate::balancer::{
+    cache_entry::CacheKey,
+    memory::MemoryBudget,
+};
+
+use std::{
+    collections::{
+        BTreeMap,
+        HashSet,
+    },
+    fmt,
+    fs::File,
+    io::{
+        self,
+        BufReader,
+        BufWriter,
+        Read,
+        Write,
+    },
+    path::Path,
+};
+
+use sled::Db;
+
+const MAGIC: &[u8; 8] = b"BGSNAP01";
+
+// Marks the end of the entries
+const END: u32 = u32::MAX;
+
+const COMPRESSION_LEVEL: i32 = 3;
+
+// Snapshots only work with caches keyed with the same hash function
+#[cfg(not(feature = "xxhash"))]
+const HASH_ALGO: u8 = 0;
+#[cfg(feature = "xxhash")]
+const HASH_ALGO: u8 = 1;
+
+// Errors
+#[derive(Debug)]
+pub enum SnapshotError {
+    Io(io::Error),
+    Db(sled::Error),
+    InvalidSnapshot,
+    HashMismatch,
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SnapshotError::Io(e) => write!(f, "Snapshot IO error: {}", e),
+            SnapshotError::Db(e) => write!(f, "Cache error: {}", e),
+            SnapshotError::InvalidSnapshot => write!(f, "Not a valid cache snapshot"),
+            SnapshotError::HashMismatch => {
+                write!(
+                    f,
+                    "Snapshot was made with a different hash function than the one we use"
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for SnapshotError {}
+
+impl From<io::Error> for SnapshotError {
+    fn from(e: io::Error) -> Self {
+        SnapshotError::Io(e)
+    }
+}
+
+impl From<sled::Error> for SnapshotError {
+    fn from(e: sled::Error) -> Self {
+        SnapshotError::Db(e)
+    }
+}
+
+fn write_record(writer: &mut impl Write, bytes: &[u8]) -> io::Result<()> {
+    writer.write_all(&(bytes.len() as u32).to_le_bytes())?;
+    writer.write_all(bytes)
+}
+
+// Read a length prefixed record, `None` at the end marker
+fn read_record(reader: &mut impl Read) -> Result<Option<Vec<u8>>, SnapshotError> {
+    let mut len = [0u8; 4];
+    reader.read_exact(&mut len)?;
+    let len = u32::from_le_bytes(len);
+    if len == END {
+        return Ok(None);
+    }
+
+    let mut bytes = vec![0u8; len as usize];
+    reader.read_exact(&mut bytes)?;
+    Ok(Some(bytes))
+}
+
+// Write every finalized cache entry to `writer`. Returns how many were written.
+pub fn export_cache(
+    cache: &Db,
+    head_cache: &BTreeMap<u64, Vec<CacheKey>>,
+    writer: impl Write,
+) -> Result<usize, SnapshotError> {
+    let unfinalized: HashSet<&[u8]> = head_cache
+        .values()
+        .flatten()
+        .map(CacheKey::as_bytes)
+        .collect();
+
+    let mut encoder = zstd::Encoder::new(writer, COMPRESSION_LEVEL)?;
+    encoder.write_all(MAGIC)?;
+    encoder.write_all(&[HASH_ALGO])?;
+
+    let mut exported = 0;
+    for entry in cache.iter() {
+        let (key, value) = entry?;
+        if unfinalized.contains(&*key) {
+            continue;
+        }
+
+        write_record(&mut encoder, &key)?;
+        write_record(&mut encoder, &value)?;
+        exported += 1;
+    }
+
+    encoder.write_all(&END.to_le_bytes())?;
+    encoder.finish()?.flush()?;
+
+    Ok(exported)
+}
+
+// Add the entries in the snapshot we don't have yet. Returns how many were added.
+pub fn import_cache(
+    cache: &Db,
+    memory: &MemoryBudget,
+    reader: impl Read,
+) -> Result<usize, SnapshotError> {
+    let mut decoder = zstd::Decoder::new(reader)?;
+
+    let mut magic = [0u8; 8];
+    let mut algo = [0u8; 1];
+    if decoder.read_exact(&mut magic).is_err() || &magic != MAGIC {
+        return Err(SnapshotError::InvalidSnapshot);
+    }
+    decoder.read_exact(&mut algo)?;
+    if algo[0] != HASH_ALGO {
+        return Err(SnapshotError::HashMismatch);
+    }
+
+    let mut imported = 0;
+    while let Some(key) = read_record(&mut decoder)? {
+        let value = read_record(&mut decoder)?.ok_or(SnapshotError::InvalidSnapshot)?;
+
+        if cache
+            .compare_and_swap(&key, None as Option<&[u8]>, Some(value.as_slice()))?
+            .is_ok()
+        {
+            memory.record_insert(&key, value.len());
+            imported += 1;
+        }
+    }
+
+    Ok(imported)
+}
+
+pub fn export_cache_to_file(
+    cache: &Db,
+    head_cache: &BTreeMap<u64, Vec<CacheKey>>,
+    path: impl AsRef<Path>,
+) -> Result<usize, SnapshotError> {
+    export_cache(cache, head_cache, BufWriter::new(File::create(path)?))
+}
+
+pub fn import_cache_from_file(
+    cache: &Db,
+    memory: &MemoryBudget,
+    path: impl AsRef<Path>,
+) -> Result<usize, SnapshotError> {
+    import_cache(cache, memory, BufReader::new(File::open(path)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cache() -> Db {
+        sled::Config::new().temporary(true).open().unwrap()
+    }
+
+    #[test]
+    fn test_snapshot_roundtrip() {
+        let finalized = CacheKey::from_request(b"finalized");
+        let tip = CacheKey::from_request(b"at the tip");
+        let source = cache();
+        source
+            .insert(finalized.as_bytes(), b"finalized".as_slice())
+            .unwrap();
+        source
+            .insert(tip.as_bytes(), b"at the tip".as_slice())
+            .unwrap();
+
+        let mut head_cache = BTreeMap::new();
+        head_cache.insert(100, vec![tip]);
+
+        let mut snapshot = Vec::new();
+        assert_eq!(
+            export_cache(&source, &head_cache, &mut snapshot).unwrap(),
+            1
+        );
+
+        // Entries we already have are kept
+        let target = cache();
+        target.insert([3u8; 32], b"ours".as_slice()).unwrap();
+        let memory = MemoryBudget::default();
+        assert_eq!(
+            import_cache(&target, &memory, snapshot.as_slice()).unwrap(),
+            1
+        );
+
+        assert_eq!(
+            &*target.get(finalized.as_bytes()).unwrap().unwrap(),
+            b"finalized"
+        );
+        assert!(target.get(tip.as_bytes()).unwrap().is_none());
+        assert_eq!(&*target.get([3u8; 32]).unwrap().unwrap(), b"ours");
+        assert_eq!(memory.used_bytes(), b"finalized".len());
+
+        // Importing again doesn't add anything
+        assert_eq!(
+            import_cache(&target, &memory, snapshot.as_slice()).unwrap(),
+            0
+        );
+    }
+
+    #[test]
+    fn test_import_invalid_snapshot() {
+        let target = cache();
+        let memory = MemoryBudget::default();
+
+        let garbage = zstd::encode_all(b"not a snapshot".as_slice(), 0).unwrap();
+        assert!(matches!(
+            import_cache(&target, &memory, garbage.as_slice()),
+            Err(SnapshotError::InvalidSnapshot)
+        ));
+
+        let mut other_hash = MAGIC.to_vec();
+        other_hash.push(HASH_ALGO + 1);
+        let other_hash = zstd::encode_all(other_hash.as_slice(), 0).unwrap();
+        assert!(matches!(
+            import_cache(&target, &memory, other_hash.as_slice()),
+            Err(SnapshotError::HashMismatch)
+        ));
+    }
+}
@@ -0,0 +1,149 @@
+// `eth_syncing` for the whole fleet.
+//
+// Asking a single node whether it's syncing says little about us, since we
+// only route to nodes that are healthy. We ask every active node instead and
+// answer `false` if at least one of them is fully synced. If none are, we
+// answer with the sync status of the node that's furthest along, so tooling
+// pointed at us sees how far the fleet is from the tip.
+use crate::{
+    rpc::types::hex_to_decimal,
+    Rpc,
+};
+
+use std::{
+    sync::{
+        Arc,
+        RwLock,
+    },
+    time::Duration,
+};
+
+use futures::future::join_all;
+use serde_json::{
+    json,
+    Value,
+};
+use tokio::time::timeout;
+
+// Block a node reports it's synced up to
+fn current_block(status: &Value) -> u64 {
+    status["currentBlock"]
+        .as_str()
+        .and_then(|block| hex_to_decimal(block).ok())
+        .unwrap_or(0)
+}
+
+// Fleet answer from the `eth_syncing` results of our nodes. `None` if none
+// of them answered.
+pub fn aggregate_syncing(results: &[Value]) -> Option<Value> {
+    if results.contains(&Value::Bool(false)) {
+        return Some(Value::Bool(false));
+    }
+
+    results
+        .iter()
+        .filter(|result| result.is_object())
+        .max_by_key(|result| current_block(result))
+        .cloned()
+}
+
+// Answer to `tx` from all our active nodes, or the ones in `group` if set,
+// if it's an `eth_syncing` call
+pub async fn syncing_response(
+    tx: &Value,
+    id: Value,
+    rpc_list: &Arc<RwLock<Vec<Rpc>>>,
+    group: Option<&str>,
+    ttl: u128,
+) -> Option<Value> {
+    if tx["method"] != "eth_syncing" {
+        return None;
+    }
+
+    let nodes: Vec<Rpc> = rpc_list
+        .read()
+        .unwrap_or_else(|e| e.into_inner())
+        .iter()
+        .filter(|rpc| group.map_or(true, |group| rpc.in_group(group)))
+        .cloned()
+        .collect();
+    let request_timeout = Duration::from_millis(ttl.try_into().unwrap_or(u64::MAX));
+    let responses = join_all(
+        nodes
+            .iter()
+            .map(|rpc| timeout(request_timeout, rpc.call("eth_syncing", json!([])))),
+    )
+    .await;
+
+    let results: Vec<Value> = responses
+        .into_iter()
+        .filter_map(|response| response.ok()?.ok())
+        .collect();
+
+    // Let the request go upstream as usual if nobody answered, it gets the error
+    let result = aggregate_syncing(&results)?;
+    Some(json!({"jsonrpc": "2.0", "id": id, "result": result}))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::node::MockNode;
+
+    fn syncing(current: u64) -> Value {
+        json!({
+            "startingBlock": "0x0",
+            "currentBlock": format!("0x{:x}", current),
+            "highestBlock": "0x100",
+        })
+    }
+
+    #[test]
+    fn test_aggregate_syncing() {
+        assert_eq!(aggregate_syncing(&[]), None);
+        assert_eq!(
+            aggregate_syncing(&[syncing(10), Value::Bool(false)]),
+            Some(Value::Bool(false))
+        );
+        assert_eq!(
+            aggregate_syncing(&[syncing(10), syncing(200), syncing(20)]),
+            Some(syncing(200))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_syncing_response() {
+        let synced = MockNode::spawn(1).await.unwrap();
+        synced.set_response("eth_syncing", Value::Bool(false));
+        let behind = MockNode::spawn(1).await.unwrap();
+        behind.set_response("eth_syncing", syncing(10));
+
+        let rpc = |node: &MockNode| Rpc::new(node.http_url(), None, 6, 0, 10.0);
+        let rpc_list = Arc::new(RwLock::new(vec![rpc(&behind), rpc(&synced)]));
+        let tx = json!({"jsonrpc": "2.0", "id": 7, "method": "eth_syncing", "params": []});
+
+        let response = syncing_response(&tx, 7.into(), &rpc_list, None, 1000)
+            .await
+            .unwrap();
+        assert_eq!(response["result"], false);
+        assert_eq!(response["id"], 7);
+
+        // Groups only see their own nodes
+        assert_eq!(
+            syncing_response(&tx, 7.into(), &rpc_list, Some("archive"), 1000).await,
+            None
+        );
+
+        // Only the node that's behind is left
+        rpc_list.write().unwrap().pop();
+        let response = syncing_response(&tx, 7.into(), &rpc_list, None, 1000)
+            .await
+            .unwrap();
+        assert_eq!(response["result"], syncing(10));
+
+        let other = json!({"jsonrpc": "2.0", "id": 7, "method": "eth_chainId", "params": []});
+        assert!(syncing_response(&other, 7.into(), &rpc_list, None, 1000)
+            .await
+            .is_none());
+    }
+}
@@ -0,0 +1,308 @@
+// Virtual host style multi-tenancy.
+//
+// With `[tenants.<name>]` tables set, the host a request is for picks the
+// tenant it's served as: the SNI the client sent over TLS, or its `Host`
+// header otherwise. Each tenant can have its own pool of nodes (a node
+// group), API keys, rate limit and cache namespace, so one deployment can
+// serve several customers without them reaching each other's nodes or
+// reading each other's cached responses. Tenants are on the same chain, so
+// the head, finalized blocks and local indexes are shared.
+//
+// Requests for hosts no tenant lists are rejected. So are WS upgrades from
+// tenants, since our WS connections to nodes are shared by everyone, and
+// SSE subscriptions and beacon requests from tenants with their own nodes.
+use crate::config::types::{
+    Tenant,
+    TenantSettings,
+};
+
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::Mutex,
+    time::{
+        Duration,
+        Instant,
+    },
+};
+
+use http_body_util::Full;
+use hyper::{
+    body::Bytes,
+    header::{
+        AUTHORIZATION,
+        HOST,
+    },
+    Request,
+    Response,
+};
+use serde_json::json;
+
+// Hosts no other tenant lists
+const ANY_HOST: &str = "*";
+
+// Errors
+#[derive(Debug, PartialEq)]
+pub enum TenantError {
+    UnknownHost(String),
+    Unauthorized(String),
+    RateLimited(String),
+    NoWebSockets(String),
+    NoBeacon(String),
+    NoSubscriptions(String),
+}
+
+impl fmt::Display for TenantError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TenantError::UnknownHost(host) => write!(f, "Unknown host: {}", host),
+            TenantError::Unauthorized(tenant) => write!(f, "Missing or wrong key for {}", tenant),
+            TenantError::RateLimited(tenant) => write!(f, "Rate limit exceeded for {}", tenant),
+            TenantError::NoWebSockets(tenant) => {
+                write!(f, "WebSockets aren't available to {}", tenant)
+            }
+            TenantError::NoBeacon(tenant) => {
+                write!(f, "Beacon nodes aren't available to {}", tenant)
+            }
+            TenantError::NoSubscriptions(tenant) => {
+                write!(f, "Subscriptions aren't available to {}", tenant)
+            }
+        }
+    }
+}
+
+impl std::error::Error for TenantError {}
+
+impl TenantError {
+    pub fn code(&self) -> i64 {
+        match self {
+            TenantError::UnknownHost(_) => -32011,
+            TenantError::Unauthorized(_) => -32012,
+            TenantError::RateLimited(_) => -32008,
+            TenantError::NoWebSockets(_)
+            | TenantError::NoBeacon(_)
+            | TenantError::NoSubscriptions(_) => -32005,
+        }
+    }
+
+    fn status(&self) -> u16 {
+        match self {
+            TenantError::UnknownHost(_) => 404,
+            TenantError::Unauthorized(_) => 401,
+            TenantError::RateLimited(_) => 429,
+            TenantError::NoWebSockets(_) => 400,
+            TenantError::NoBeacon(_) | TenantError::NoSubscriptions(_) => 403,
+        }
+    }
+
+    pub fn into_response(self) -> Response<Full<Bytes>> {
+        let body = json!({
+            "jsonrpc": "2.0",
+            "id": null,
+            "error": {"code": self.code(), "message": self.to_string()},
+        });
+
+        Response::builder()
+            .status(self.status())
+            .header("Content-Type", "application/json")
+            .body(Full::new(Bytes::from(body.to_string())))
+            .unwrap()
+    }
+}
+
+// Host `tx` is for, preferring the SNI of its connection
+pub fn request_host<'a, B>(tx: &'a Request<B>, server_name: Option<&'a str>) -> Option<&'a str> {
+    server_name
+        .or_else(|| tx.headers().get(HOST).and_then(|host| host.to_str().ok()))
+        .or_else(|| tx.uri().host())
+}
+
+// API key `tx` was sent with, if any
+pub fn request_key<B>(tx: &Request<B>) -> Option<&str> {
+    tx.headers()
+        .get(AUTHORIZATION)
+        .and_then(|key| key.to_str().ok())
+        .and_then(|key| key.strip_prefix("Bearer "))
+        .map(str::trim)
+}
+
+// `host` without its port, lowercase
+fn host_name(host: &str) -> String {
+    let name = match host.strip_prefix('[') {
+        // IPv6 addresses keep their brackets
+        Some(rest) => &host[..rest.find(']').map_or(host.len(), |end| end + 2)],
+        None => host.split(':').next().unwrap_or_default(),
+    };
+    name.trim_end_matches('.').to_lowercase()
+}
+
+#[derive(Debug, Default)]
+pub struct Tenants {
+    settings: TenantSettings,
+    // Start of the current one second window and requests in it, per tenant
+    windows: Mutex<HashMap<String, (Instant, u32)>>,
+}
+
+impl Tenants {
+    pub fn new(settings: TenantSettings) -> Self {
+        Tenants {
+            settings,
+            windows: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.settings.tenants.is_empty()
+    }
+
+    fn find(&self, host: &str) -> Option<(&str, &Tenant)> {
+        let listed = |host: &str| {
+            self.settings
+                .tenants
+                .iter()
+                .find(|(_, tenant)| tenant.hosts.iter().any(|listed| listed == host))
+                .map(|(name, tenant)| (name.as_str(), tenant))
+        };
+        listed(&host_name(host)).or_else(|| listed(ANY_HOST))
+    }
+
+    // Tenant a request for `host` is served as, once its `key` and rate
+    // limit check out. `None` if there are no tenants.
+    pub fn admit(
+        &self,
+        host: Option<&str>,
+        key: Option<&str>,
+    ) -> Result<Option<(&str, &Tenant)>, TenantError> {
+        if !self.is_enabled() {
+            return Ok(None);
+        }
+        let host = host.unwrap_or_default();
+        let (name, tenant) = self
+            .find(host)
+            .ok_or_else(|| TenantError::UnknownHost(host.to_string()))?;
+
+        if !tenant.keys.is_empty()
+            && !key.is_some_and(|key| tenant.keys.iter().any(|listed| listed == key))
+        {
+            return Err(TenantError::Unauthorized(name.to_string()));
+        }
+
+        if tenant.rate_limit > 0 {
+            let mut windows = self.windows.lock().unwrap_or_else(|e| e.into_inner());
+            let now = Instant::now();
+            let (start, count) = windows.entry(name.to_string()).or_insert((now, 0));
+            if now.duration_since(*start) >= Duration::from_secs(1) {
+                (*start, *count) = (now, 0);
+            }
+            if *count >= tenant.rate_limit {
+                return Err(TenantError::RateLimited(name.to_string()));
+            }
+            *count += 1;
+        }
+
+        Ok(Some((name, tenant)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::BTreeMap;
+
+    fn tenant(hosts: &[&str], keys: &[&str], rate_limit: u32) -> Tenant {
+        Tenant {
+            hosts: hosts.iter().map(|host| host.to_string()).collect(),
+            group: None,
+            keys: keys.iter().map(|key| key.to_string()).collect(),
+            rate_limit,
+            namespace: String::new(),
+        }
+    }
+
+    fn tenants() -> Tenants {
+        Tenants::new(TenantSettings {
+            tenants: BTreeMap::from([
+                (
+                    "acme".to_string(),
+                    tenant(&["rpc.acme.com"], &["acme-key"], 2),
+                ),
+                ("public".to_string(), tenant(&["*"], &[], 0)),
+            ]),
+        })
+    }
+
+    #[test]
+    fn test_host_name() {
+        assert_eq!(host_name("RPC.acme.com:443"), "rpc.acme.com");
+        assert_eq!(host_name("rpc.acme.com."), "rpc.acme.com");
+        assert_eq!(host_name("[::1]:3000"), "[::1]");
+        assert_eq!(host_name("127.0.0.1"), "127.0.0.1");
+    }
+
+    #[test]
+    fn test_request_host_and_key() {
+        let tx = Request::builder()
+            .uri("/")
+            .header(HOST, "rpc.acme.com")
+            .header(AUTHORIZATION, "Bearer acme-key")
+            .body(())
+            .unwrap();
+        assert_eq!(request_host(&tx, None), Some("rpc.acme.com"));
+        assert_eq!(
+            request_host(&tx, Some("sni.acme.com")),
+            Some("sni.acme.com")
+        );
+        assert_eq!(request_key(&tx), Some("acme-key"));
+
+        let tx = Request::builder()
+            .uri("http://rpc.globex.com/")
+            .body(())
+            .unwrap();
+        assert_eq!(request_host(&tx, None), Some("rpc.globex.com"));
+        assert_eq!(request_key(&tx), None);
+    }
+
+    #[test]
+    fn test_admit() {
+        let tenants = tenants();
+        let (name, _) = tenants
+            .admit(Some("rpc.acme.com:443"), Some("acme-key"))
+            .unwrap()
+            .unwrap();
+        assert_eq!(name, "acme");
+        assert_eq!(
+            tenants.admit(Some("rpc.acme.com"), Some("globex-key")),
+            Err(TenantError::Unauthorized("acme".to_string()))
+        );
+        assert_eq!(
+            tenants.admit(Some("rpc.acme.com"), None),
+            Err(TenantError::Unauthorized("acme".to_string()))
+        );
+
+        // Everyone else is the catch-all tenant, without a key or limit
+        for _ in 0..10 {
+            let (name, _) = tenants.admit(Some("localhost"), None).unwrap().unwrap();
+            assert_eq!(name, "public");
+        }
+
+        // Unknown hosts without a catch-all
+        let strict = Tenants::new(TenantSettings {
+            tenants: BTreeMap::from([("acme".to_string(), tenant(&["rpc.acme.com"], &[], 0))]),
+        });
+        assert_eq!(
+            strict.admit(Some("localhost"), None),
+            Err(TenantError::UnknownHost("localhost".to_string()))
+        );
+        assert_eq!(Tenants::default().admit(None, None), Ok(None));
+    }
+
+    #[test]
+    fn test_tenant_rate_limit() {
+        let tenants = tenants();
+        let acme = || tenants.admit(Some("rpc.acme.com"), Some("acme-key"));
+        assert!(acme().is_ok());
+        assert!(acme().is_ok());
+        assert_eq!(acme(), Err(TenantError::RateLimited("acme".to_string())));
+    }
+}
@@ -0,0 +1,254 @@
+// Wallet methods like `eth_accounts`, `eth_sign` and `eth_sendTransaction`
+// only make sense on a node that holds the keys. We never send them through
+// the regular rotation. Depending on the `[wallet]` policy they're rejected,
+// or forwarded to a dedicated node or external signer.
+//
+// With an external signer, `eth_sendTransaction` gets signed by the signer
+// and the raw transaction is broadcast like any other `eth_sendRawTransaction`.
+// Clef speaks its own `account_*` API, so calls to it get translated.
+use crate::{
+    config::types::{
+        SignerKind,
+        WalletPolicy,
+    },
+    Rpc,
+};
+
+use serde_json::{
+    json,
+    Value,
+};
+
+pub const WALLET_METHODS: [&str; 11] = [
+    "eth_accounts",
+    "eth_requestAccounts",
+    "eth_sign",
+    "eth_signTransaction",
+    "eth_sendTransaction",
+    "eth_signTypedData",
+    "eth_signTypedData_v3",
+    "eth_signTypedData_v4",
+    "personal_sign",
+    "personal_listAccounts",
+    "personal_sendTransaction",
+];
+
+pub fn is_wallet_method(method: &str) -> bool {
+    WALLET_METHODS.contains(&method)
+}
+
+fn wallet_error(id: &Value, code: i64, message: String) -> Value {
+    json!({"jsonrpc": "2.0", "id": id, "error": {"code": code, "message": message}})
+}
+
+fn is_send_transaction(method: &Value) -> bool {
+    method == "eth_sendTransaction" || method == "personal_sendTransaction"
+}
+
+// Translate a wallet method into the call we send to the signer
+fn to_signer_call(tx: &Value, kind: SignerKind) -> Value {
+    let params = &tx["params"];
+
+    // We broadcast transactions ourselves, signers only sign them
+    let (method, params) = match (kind, tx["method"].as_str().unwrap_or_default()) {
+        (SignerKind::Web3Signer, "eth_sendTransaction" | "personal_sendTransaction") => {
+            ("eth_signTransaction", json!([params[0]]))
+        }
+        (SignerKind::Web3Signer, method) => (method, params.clone()),
+        (SignerKind::Clef, "eth_accounts" | "eth_requestAccounts" | "personal_listAccounts") => {
+            ("account_list", json!([]))
+        }
+        (
+            SignerKind::Clef,
+            "eth_signTransaction" | "eth_sendTransaction" | "personal_sendTransaction",
+        ) => ("account_signTransaction", json!([params[0]])),
+        (SignerKind::Clef, "eth_sign") => {
+            (
+                "account_signData",
+                json!(["text/plain", params[0], params[1]]),
+            )
+        }
+        (SignerKind::Clef, "personal_sign") => {
+            (
+                "account_signData",
+                json!(["text/plain", params[1], params[0]]),
+            )
+        }
+        (SignerKind::Clef, method) if method.starts_with("eth_signTypedData") => {
+            ("account_signTypedData", params.clone())
+        }
+        (SignerKind::Clef, method) => (method, params.clone()),
+    };
+
+    json!({"jsonrpc": "2.0", "id": tx["id"], "method": method, "params": params})
+}
+
+// Web3Signer returns the raw transaction, Clef returns `{"raw": ..., "tx": ...}`
+fn raw_transaction(result: &Value, kind: SignerKind) -> Option<String> {
+    let raw = match kind {
+        SignerKind::Web3Signer => result.as_str(),
+        SignerKind::Clef => result["raw"].as_str(),
+    };
+    raw.map(str::to_string)
+}
+
+// Send `call` to `rpc` and return its response with the user's id
+async fn forward(rpc: &Rpc, call: Value, id: &Value) -> Value {
+    let rax = match rpc.send_request(call).await {
+        Ok(rax) => rax,
+        Err(e) => return wallet_error(id, -32603, e.to_string()),
+    };
+
+    match serde_json::from_str::<Value>(&rax) {
+        Ok(mut rax) => {
+            rax["id"] = id.clone();
+            rax
+        }
+        Err(_) => wallet_error(id, -32603, format!("Invalid response from wallet: {}", rax)),
+    }
+}
+
+// Answer a wallet method according to `policy`
+pub async fn wallet_call(tx: &Value, policy: &WalletPolicy) -> Value {
+    let id = &tx["id"];
+
+    match policy {
+        WalletPolicy::Reject => {
+            wallet_error(
+                id,
+                -32601,
+                format!(
+                    "{} is not supported, no wallet is configured",
+                    tx["method"].as_str().unwrap_or_default()
+                ),
+            )
+        }
+        WalletPolicy::Node(rpc) => forward(rpc, tx.clone(), id).await,
+        WalletPolicy::Signer { rpc, kind } => forward(rpc, to_signer_call(tx, *kind), id).await,
+    }
+}
+
+// Sign a transaction sent with `eth_sendTransaction` with the external signer.
+//
+// Returns `None` if the transaction isn't ours to sign, otherwise the raw
+// transaction to broadcast or the error response to send back.
+pub async fn sign_for_broadcast(
+    tx: &Value,
+    policy: &WalletPolicy,
+) -> Option<Result<String, Value>> {
+    let (rpc, kind) = match policy {
+        WalletPolicy::Signer { rpc, kind } if is_send_transaction(&tx["method"]) => (rpc, *kind),
+        _ => return None,
+    };
+
+    let signed = forward(rpc, to_signer_call(tx, kind), &tx["id"]).await;
+    if signed.get("error").is_some() {
+        return Some(Err(signed));
+    }
+
+    Some(raw_transaction(&signed["result"], kind).ok_or(wallet_error(
+        &tx["id"],
+        -32603,
+        "Signer did not return a raw transaction".to_string(),
+    )))
+}
+
+// Turn a signed transaction into the `eth_sendRawTransaction` we broadcast
+pub fn into_raw_transaction(tx: &mut Value, raw: String) {
+    tx["method"] = "eth_sendRawTransaction".into();
+    tx["params"] = json!([raw]);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::node::MockNode;
+
+    #[test]
+    fn test_is_wallet_method() {
+        assert!(is_wallet_method("eth_accounts"));
+        assert!(is_wallet_method("eth_sendTransaction"));
+        assert!(is_wallet_method("personal_sign"));
+        assert!(!is_wallet_method("eth_sendRawTransaction"));
+        assert!(!is_wallet_method("eth_call"));
+    }
+
+    #[tokio::test]
+    async fn test_wallet_call_rejected() {
+        let tx = json!({"jsonrpc": "2.0", "id": 3, "method": "eth_sign", "params": []});
+        let rax = wallet_call(&tx, &WalletPolicy::Reject).await;
+
+        assert_eq!(rax["id"], 3);
+        assert_eq!(rax["error"]["code"], -32601);
+        assert!(rax.get("result").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_wallet_call_forwarded() {
+        let node = MockNode::spawn(1).await.unwrap();
+        node.set_response("eth_accounts", json!(["0x01"]));
+        let rpc = Rpc::new(node.http_url(), None, 0, 0, 1.0);
+
+        let tx = json!({"jsonrpc": "2.0", "id": 5, "method": "eth_accounts", "params": []});
+        let rax = wallet_call(&tx, &WalletPolicy::Node(rpc)).await;
+
+        assert_eq!(rax["id"], 5);
+        assert_eq!(rax["result"], json!(["0x01"]));
+        assert_eq!(node.request_count(), 1);
+    }
+
+    #[test]
+    fn test_to_signer_call() {
+        let send = json!({"id": 1, "method": "eth_sendTransaction", "params": [{"from": "0x01"}]});
+        let call = to_signer_call(&send, SignerKind::Web3Signer);
+        assert_eq!(call["method"], "eth_signTransaction");
+        assert_eq!(call["params"], json!([{"from": "0x01"}]));
+
+        let call = to_signer_call(&send, SignerKind::Clef);
+        assert_eq!(call["method"], "account_signTransaction");
+
+        let sign = json!({"id": 1, "method": "personal_sign", "params": ["0xdead", "0x01"]});
+        let call = to_signer_call(&sign, SignerKind::Clef);
+        assert_eq!(call["method"], "account_signData");
+        assert_eq!(call["params"], json!(["text/plain", "0x01", "0xdead"]));
+
+        let accounts = json!({"id": 1, "method": "eth_accounts", "params": []});
+        assert_eq!(
+            to_signer_call(&accounts, SignerKind::Clef)["method"],
+            "account_list"
+        );
+        assert_eq!(
+            to_signer_call(&accounts, SignerKind::Web3Signer)["method"],
+            "eth_accounts"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_sign_for_broadcast() {
+        let signer = MockNode::spawn(1).await.unwrap();
+        signer.set_response(
+            "account_signTransaction",
+            json!({"raw": "0xf86c", "tx": {}}),
+        );
+        let policy = WalletPolicy::Signer {
+            rpc: Rpc::new(signer.http_url(), None, 0, 0, 1.0),
+            kind: SignerKind::Clef,
+        };
+
+        let send = json!({"id": 1, "method": "eth_sendTransaction", "params": [{"from": "0x01"}]});
+        let raw = sign_for_broadcast(&send, &policy).await.unwrap().unwrap();
+        assert_eq!(raw, "0xf86c");
+
+        let mut tx = send.clone();
+        into_raw_transaction(&mut tx, raw);
+        assert_eq!(tx["method"], "eth_sendRawTransaction");
+        assert_eq!(tx["params"], json!(["0xf86c"]));
+
+        // Other methods and policies are left alone
+        let accounts = json!({"id": 1, "method": "eth_accounts", "params": []});
+        assert!(sign_for_broadcast(&accounts, &policy).await.is_none());
+        assert!(sign_for_broadcast(&send, &WalletPolicy::Reject)
+            .await
+            .is_none());
+    }
+}
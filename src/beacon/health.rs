@@ -0,0 +1,210 @@
+use crate::{
+    beacon::types::{
+        BeaconNode,
+        BeaconPool,
+    },
+    log_info,
+    log_wrn,
+};
+
+use std::{
+    sync::Arc,
+    time::Duration,
+};
+
+use futures::StreamExt;
+use reqwest::Client;
+use serde_json::Value;
+use tokio::time::sleep;
+
+pub const HEALTH_PATH: &str = "/eth/v1/node/health";
+pub const HEAD_EVENTS_PATH: &str = "/eth/v1/events?topics=head";
+
+// Ask `node` whether it's synced. Nodes answer 200 when they are, 206 while
+// syncing and 503 when they can't serve anything.
+pub async fn check_health(client: &Client, node: &BeaconNode, timeout: Duration) -> bool {
+    let healthy = match client
+        .get(format!("{}{}", node.url, HEALTH_PATH))
+        .timeout(timeout)
+        .send()
+        .await
+    {
+        Ok(response) => response.status() == reqwest::StatusCode::OK,
+        Err(_) => false,
+    };
+
+    if healthy != node.is_healthy() {
+        match healthy {
+            true => log_info!("Beacon node {} is healthy", node.name),
+            false => log_wrn!("Beacon node {} is unhealthy", node.name),
+        }
+    }
+    node.set_healthy(healthy);
+
+    healthy
+}
+
+// Check all nodes in `pool` every `health_check_interval`
+pub async fn beacon_health_check(pool: Arc<BeaconPool>) {
+    let settings = match &pool.settings {
+        Some(settings) => settings.clone(),
+        None => return,
+    };
+
+    loop {
+        futures::future::join_all(
+            pool.nodes
+                .iter()
+                .map(|node| check_health(&pool.client, node, settings.timeout)),
+        )
+        .await;
+        sleep(settings.health_check_interval).await;
+    }
+}
+
+// Slot of a `head` event, from the `data:` line of a server-sent event
+pub fn head_slot(event: &str) -> Option<u64> {
+    event.lines().find_map(|line| {
+        let data: Value = serde_json::from_str(line.strip_prefix("data:")?.trim()).ok()?;
+        data["slot"].as_str()?.parse().ok()
+    })
+}
+
+// Follow the `head` events of `node`, reconnecting if the stream ends
+pub async fn follow_head(client: Client, node: Arc<BeaconNode>, retry: Duration) {
+    loop {
+        let response = client
+            .get(format!("{}{}", node.url, HEAD_EVENTS_PATH))
+            .header("accept", "text/event-stream")
+            .send()
+            .await;
+        let mut stream = match response {
+            Ok(response) if response.status().is_success() => response.bytes_stream(),
+            Ok(response) => {
+                log_wrn!(
+                    "Beacon node {} refused head events: {}",
+                    node.name,
+                    response.status()
+                );
+                sleep(retry).await;
+                continue;
+            }
+            Err(e) => {
+                log_wrn!("Could not follow head of beacon node {}: {}", node.name, e);
+                sleep(retry).await;
+                continue;
+            }
+        };
+
+        // Events are separated by an empty line and can span chunks
+        let mut buffer = String::new();
+        while let Some(Ok(chunk)) = stream.next().await {
+            buffer.push_str(&String::from_utf8_lossy(&chunk));
+            let buffered = buffer.replace("\r\n", "\n");
+            let mut events: Vec<&str> = buffered.split("\n\n").collect();
+            let rest = events.pop().unwrap_or_default().to_string();
+            for event in events {
+                if let Some(slot) = head_slot(event) {
+                    node.set_slot(slot);
+                }
+            }
+            buffer = rest;
+        }
+
+        sleep(retry).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::types::BeaconSettings;
+
+    use std::{
+        io::{
+            Read,
+            Write,
+        },
+        net::TcpListener,
+        thread,
+    };
+
+    // Beacon node answering health checks with `status` and streaming `events`
+    fn beacon_node(status: u16, events: &'static str) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut buf = [0u8; 4096];
+                let read = stream.read(&mut buf).unwrap_or(0);
+                let request = String::from_utf8_lossy(&buf[..read]).to_string();
+
+                let (status, content_type, body) = match request.contains("/events") {
+                    true => (200, "text/event-stream", events),
+                    false => (status, "application/json", ""),
+                };
+                let _ = write!(
+                    stream,
+                    "HTTP/1.1 {} OK\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    status,
+                    content_type,
+                    body.len(),
+                    body
+                );
+            }
+        });
+
+        format!("http://{}", address)
+    }
+
+    #[test]
+    fn test_head_slot() {
+        assert_eq!(
+            head_slot("event: head\ndata: {\"slot\":\"10\", \"block\":\"0x9a\"}"),
+            Some(10)
+        );
+        assert_eq!(head_slot("event: head\ndata: {}"), None);
+        assert_eq!(head_slot(": keepalive"), None);
+    }
+
+    #[tokio::test]
+    async fn test_check_health() {
+        let client = Client::new();
+        let synced = BeaconNode::new(beacon_node(200, ""));
+        let syncing = BeaconNode::new(beacon_node(206, ""));
+        let down = BeaconNode::new("http://127.0.0.1:1".to_string());
+
+        let timeout = Duration::from_secs(1);
+        assert!(check_health(&client, &synced, timeout).await);
+        assert!(synced.is_healthy());
+        assert!(!check_health(&client, &syncing, timeout).await);
+        assert!(!check_health(&client, &down, timeout).await);
+    }
+
+    #[tokio::test]
+    async fn test_follow_head() {
+        let url = beacon_node(
+            200,
+            "event: head\ndata: {\"slot\":\"7\"}\n\nevent: head\r\ndata: {\"slot\":\"8\"}\r\n\r\n",
+        );
+        let pool = Arc::new(BeaconPool::new(Some(BeaconSettings {
+            nodes: vec![url],
+            ..Default::default()
+        })));
+
+        tokio::spawn(follow_head(
+            pool.client.clone(),
+            pool.nodes[0].clone(),
+            Duration::from_millis(10),
+        ));
+        for _ in 0..100 {
+            if pool.head_slot() == 8 {
+                break;
+            }
+            sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(pool.head_slot(), 8);
+    }
+}
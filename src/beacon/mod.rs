@@ -0,0 +1,11 @@
+// Load balancing for consensus layer beacon nodes.
+//
+// With `[beacon]` set, requests under its `prefix` go to beacon nodes over
+// their REST API instead of to our execution nodes. Beacon nodes are health
+// checked through `/eth/v1/node/health` and their head is followed through
+// the `head` event stream, so requests only go to nodes that are synced and
+// close to the best head we know of. They show up in `/metrics` next to our
+// execution nodes.
+pub mod health;
+pub mod proxy;
+pub mod types;
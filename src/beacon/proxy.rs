@@ -0,0 +1,265 @@
+use crate::{
+    beacon::types::BeaconPool,
+    log_wrn,
+    rpc::types::InFlight,
+};
+
+use std::time::Instant;
+
+use http_body_util::Full;
+use hyper::{
+    body::Bytes,
+    HeaderMap,
+    Method,
+    Response,
+};
+use serde_json::json;
+
+// What policies and usage call beacon requests
+pub const BEACON_METHOD: &str = "beacon";
+
+// Request headers beacon nodes care about
+const FORWARDED_HEADERS: &[&str] = &["accept", "content-type", "eth-consensus-version"];
+
+// Response headers we pass back
+const RETURNED_HEADERS: &[&str] = &["content-type", "eth-consensus-version"];
+
+fn error_response(status: u16, message: &str) -> Response<Full<Bytes>> {
+    Response::builder()
+        .status(status)
+        .header("Content-Type", "application/json")
+        .body(Full::new(Bytes::from(
+            json!({"code": status, "message": message}).to_string(),
+        )))
+        .unwrap()
+}
+
+// Read the body of `response`, `None` if it's over `max_size` bytes
+async fn read_capped(
+    mut response: reqwest::Response,
+    max_size: usize,
+) -> Result<Option<Bytes>, reqwest::Error> {
+    if response
+        .content_length()
+        .is_some_and(|length| length > max_size as u64)
+    {
+        return Ok(None);
+    }
+
+    let mut body = Vec::new();
+    while let Some(chunk) = response.chunk().await? {
+        if body.len() + chunk.len() > max_size {
+            return Ok(None);
+        }
+        body.extend_from_slice(&chunk);
+    }
+    Ok(Some(body.into()))
+}
+
+// Send a request for `path` to the best available beacon node, moving on to
+// the next one if a node can't answer
+pub async fn forward(
+    pool: &BeaconPool,
+    method: Method,
+    path: &str,
+    headers: &HeaderMap,
+    body: Bytes,
+) -> Response<Full<Bytes>> {
+    // Event streams would hold a connection per client open
+    if path.starts_with("/eth/v1/events") {
+        return error_response(501, "Event streams are not proxied");
+    }
+
+    let (timeout, max_response_size) = pool
+        .settings
+        .as_ref()
+        .map(|settings| (settings.timeout, settings.max_response_size))
+        .unwrap_or_default();
+
+    // reqwest and hyper don't share `http` versions
+    let method = match reqwest::Method::from_bytes(method.as_str().as_bytes()) {
+        Ok(method) => method,
+        Err(_) => return error_response(405, "Unsupported method"),
+    };
+
+    let nodes = pool.available();
+    if nodes.is_empty() {
+        return error_response(503, "No beacon node is available");
+    }
+
+    for node in nodes {
+        let mut request = pool
+            .client
+            .request(method.clone(), format!("{}{}", node.url, path))
+            .timeout(timeout)
+            .body(body.clone());
+        for name in FORWARDED_HEADERS {
+            if let Some(value) = headers.get(*name) {
+                request = request.header(*name, value.as_bytes());
+            }
+        }
+
+        let start = Instant::now();
+        let result = {
+            let _in_flight = InFlight::new(&node.in_flight);
+            async {
+                let response = request.send().await?;
+                let status = response.status();
+                let returned = response.headers().clone();
+                let rax = read_capped(response, max_response_size).await?;
+                Ok::<_, reqwest::Error>((status, returned, rax))
+            }
+            .await
+        };
+
+        let (status, returned, rax) = match result {
+            Ok((status, returned, Some(rax))) => (status, returned, rax),
+            Ok((_, _, None)) => {
+                log_wrn!("Beacon node {} answer is too large", node.name);
+                return error_response(502, "Beacon node answer is too large");
+            }
+            Err(e) => {
+                log_wrn!("Request to beacon node {} failed: {}", node.name, e);
+                continue;
+            }
+        };
+        if status.is_server_error() {
+            log_wrn!("Beacon node {} answered {}", node.name, status);
+            continue;
+        }
+        node.record_latency(start.elapsed());
+
+        let mut response = Response::builder().status(status.as_u16());
+        for name in RETURNED_HEADERS {
+            if let Some(value) = returned.get(*name) {
+                response = response.header(*name, value.as_bytes());
+            }
+        }
+        return response.body(Full::new(rax)).unwrap();
+    }
+
+    error_response(502, "No beacon node could answer")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::types::BeaconSettings;
+
+    use http_body_util::BodyExt;
+    use std::{
+        io::{
+            Read,
+            Write,
+        },
+        net::TcpListener,
+        sync::{
+            atomic::{
+                AtomicUsize,
+                Ordering,
+            },
+            Arc,
+        },
+        thread,
+    };
+
+    // Beacon node that answers every request with `status` and `body`
+    fn beacon_node(status: u16, body: &'static str) -> (String, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counter = requests.clone();
+
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut buf = [0u8; 4096];
+                let _ = stream.read(&mut buf);
+                counter.fetch_add(1, Ordering::Relaxed);
+
+                let _ = write!(
+                    stream,
+                    "HTTP/1.1 {} OK\r\nContent-Type: application/json\r\nEth-Consensus-Version: deneb\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    status,
+                    body.len(),
+                    body
+                );
+            }
+        });
+
+        (format!("http://{}", address), requests)
+    }
+
+    #[tokio::test]
+    async fn test_forward() {
+        let (down, down_requests) = beacon_node(500, "oops");
+        let (up, up_requests) = beacon_node(200, r#"{"data":{"slot":"1"}}"#);
+        let pool = BeaconPool::new(Some(BeaconSettings {
+            nodes: vec![down, up],
+            ..Default::default()
+        }));
+
+        let path = "/eth/v2/beacon/blocks/head";
+        assert_eq!(
+            forward(&pool, Method::GET, path, &HeaderMap::new(), Bytes::new())
+                .await
+                .status(),
+            503
+        );
+
+        // The broken node is faster
+        for (node, latency) in pool.nodes.iter().zip([1, 2]) {
+            node.set_healthy(true);
+            node.record_latency(std::time::Duration::from_millis(latency));
+        }
+        let response = forward(&pool, Method::GET, path, &HeaderMap::new(), Bytes::new()).await;
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.headers()["eth-consensus-version"], "deneb");
+        assert_eq!(
+            response.into_body().collect().await.unwrap().to_bytes(),
+            r#"{"data":{"slot":"1"}}"#
+        );
+        assert_eq!(down_requests.load(Ordering::Relaxed), 1);
+        assert_eq!(up_requests.load(Ordering::Relaxed), 1);
+        assert!(pool
+            .nodes
+            .iter()
+            .all(|node| node.in_flight.load(Ordering::Relaxed) == 0));
+
+        assert_eq!(
+            forward(
+                &pool,
+                Method::GET,
+                "/eth/v1/events?topics=head",
+                &HeaderMap::new(),
+                Bytes::new()
+            )
+            .await
+            .status(),
+            501
+        );
+    }
+
+    #[tokio::test]
+    async fn test_forward_too_large() {
+        let (up, up_requests) = beacon_node(200, r#"{"data":{"slot":"1"}}"#);
+        let pool = BeaconPool::new(Some(BeaconSettings {
+            nodes: vec![up],
+            max_response_size: 8,
+            ..Default::default()
+        }));
+        pool.nodes[0].set_healthy(true);
+
+        let response = forward(
+            &pool,
+            Method::GET,
+            "/eth/v2/beacon/blocks/head",
+            &HeaderMap::new(),
+            Bytes::new(),
+        )
+        .await;
+        assert_eq!(response.status(), 502);
+        assert_eq!(up_requests.load(Ordering::Relaxed), 1);
+        assert_eq!(pool.nodes[0].in_flight.load(Ordering::Relaxed), 0);
+    }
+}
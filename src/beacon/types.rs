@@ -0,0 +1,201 @@
+use crate::{
+    config::types::BeaconSettings,
+    rpc::types::sanitize_url,
+};
+
+use std::{
+    sync::{
+        atomic::{
+            AtomicBool,
+            AtomicU64,
+            Ordering,
+        },
+        Arc,
+    },
+    time::Duration,
+};
+
+use reqwest::Client;
+
+#[derive(Debug)]
+pub struct BeaconNode {
+    // Sanitized url, for logs and metrics
+    pub name: String,
+    pub url: String,
+    // Whether `/eth/v1/node/health` says the node is synced
+    healthy: AtomicBool,
+    // Slot of the last head the node told us about
+    slot: AtomicU64,
+    // Moving average of how long requests take, in microseconds
+    latency: AtomicU64,
+    pub in_flight: AtomicU64,
+}
+
+impl BeaconNode {
+    pub fn new(url: String) -> Self {
+        BeaconNode {
+            name: sanitize_url(&url).unwrap_or(url.clone()),
+            url: url.trim_end_matches('/').to_string(),
+            healthy: AtomicBool::new(false),
+            slot: AtomicU64::new(0),
+            latency: AtomicU64::new(0),
+            in_flight: AtomicU64::new(0),
+        }
+    }
+
+    pub fn is_healthy(&self) -> bool {
+        self.healthy.load(Ordering::Relaxed)
+    }
+
+    pub fn set_healthy(&self, healthy: bool) {
+        self.healthy.store(healthy, Ordering::Relaxed);
+    }
+
+    pub fn slot(&self) -> u64 {
+        self.slot.load(Ordering::Relaxed)
+    }
+
+    // Heads only move forward, reorgs to an earlier slot are rare and short
+    pub fn set_slot(&self, slot: u64) {
+        self.slot.fetch_max(slot, Ordering::Relaxed);
+    }
+
+    pub fn latency(&self) -> Duration {
+        Duration::from_micros(self.latency.load(Ordering::Relaxed))
+    }
+
+    pub fn record_latency(&self, latency: Duration) {
+        let sample = latency.as_micros().try_into().unwrap_or(u64::MAX);
+        let _ = self
+            .latency
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |average| {
+                Some(match average {
+                    0 => sample,
+                    average => average - average / 8 + sample / 8,
+                })
+            });
+    }
+}
+
+// Beacon nodes we balance between, empty if `[beacon]` isn't set
+#[derive(Debug, Default)]
+pub struct BeaconPool {
+    pub settings: Option<BeaconSettings>,
+    pub nodes: Vec<Arc<BeaconNode>>,
+    pub client: Client,
+}
+
+impl BeaconPool {
+    pub fn new(settings: Option<BeaconSettings>) -> Self {
+        let nodes = settings
+            .iter()
+            .flat_map(|settings| settings.nodes.iter())
+            .map(|url| Arc::new(BeaconNode::new(url.clone())))
+            .collect();
+
+        BeaconPool {
+            settings,
+            nodes,
+            client: Client::new(),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.nodes.is_empty()
+    }
+
+    // Path a request to `path` has on a beacon node, if it's for one
+    pub fn strip_prefix<'a>(&self, path: &'a str) -> Option<&'a str> {
+        let settings = self.settings.as_ref()?;
+        let path = path.strip_prefix(settings.prefix.as_str())?;
+        path.starts_with('/').then_some(path)
+    }
+
+    // Best head any of our nodes knows of
+    pub fn head_slot(&self) -> u64 {
+        self.nodes.iter().map(|node| node.slot()).max().unwrap_or(0)
+    }
+
+    // Healthy nodes close enough to the best head, fastest first
+    pub fn available(&self) -> Vec<Arc<BeaconNode>> {
+        let max_slot_lag = self
+            .settings
+            .as_ref()
+            .map_or(0, |settings| settings.max_slot_lag);
+        let head = self.head_slot();
+
+        let mut nodes: Vec<Arc<BeaconNode>> = self
+            .nodes
+            .iter()
+            .filter(|node| node.is_healthy() && node.slot() + max_slot_lag >= head)
+            .cloned()
+            .collect();
+        nodes.sort_by_key(|node| node.latency());
+        nodes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings(nodes: Vec<String>) -> BeaconSettings {
+        BeaconSettings {
+            nodes,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_available() {
+        let pool = BeaconPool::new(Some(settings(vec![
+            "http://a.example.com".to_string(),
+            "http://b.example.com/key".to_string(),
+            "http://c.example.com".to_string(),
+        ])));
+        assert!(pool.available().is_empty());
+        assert_eq!(pool.nodes[1].name, "http://b.example.com/");
+
+        for (node, (slot, latency)) in pool.nodes.iter().zip([(100, 30), (99, 10), (90, 5)]) {
+            node.set_healthy(true);
+            node.set_slot(slot);
+            node.record_latency(Duration::from_millis(latency));
+        }
+        assert_eq!(pool.head_slot(), 100);
+
+        // The lagging node is out, the rest go fastest first
+        let available: Vec<String> = pool
+            .available()
+            .iter()
+            .map(|node| node.url.clone())
+            .collect();
+        assert_eq!(
+            available,
+            vec!["http://b.example.com/key", "http://a.example.com"]
+        );
+
+        pool.nodes[1].set_healthy(false);
+        assert_eq!(pool.available().len(), 1);
+    }
+
+    #[test]
+    fn test_strip_prefix() {
+        let pool = BeaconPool::new(Some(settings(vec!["http://a.example.com".to_string()])));
+        assert_eq!(
+            pool.strip_prefix("/beacon/eth/v1/node/health"),
+            Some("/eth/v1/node/health")
+        );
+        assert_eq!(pool.strip_prefix("/beaconx/eth/v1"), None);
+        assert_eq!(pool.strip_prefix("/"), None);
+        assert_eq!(BeaconPool::default().strip_prefix("/beacon/eth/v1"), None);
+    }
+
+    #[test]
+    fn test_latency() {
+        let node = BeaconNode::new("http://a.example.com".to_string());
+        node.record_latency(Duration::from_millis(80));
+        assert_eq!(node.latency(), Duration::from_millis(80));
+        node.record_latency(Duration::from_millis(0));
+        assert_eq!(node.latency(), Duration::from_millis(70));
+    }
+}
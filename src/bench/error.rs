@@ -0,0 +1,25 @@
+// Errors
+use std::error::Error;
+
+#[derive(Debug)]
+pub enum BenchError {
+    InvalidMix(String),
+    InvalidTarget(String),
+    NoRequests,
+    InvalidArg(String),
+    UnusableFleet,
+}
+
+impl std::fmt::Display for BenchError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            BenchError::InvalidMix(mix) => write!(f, "Invalid method mix: {}", mix),
+            BenchError::InvalidTarget(target) => write!(f, "Invalid bench target: {}", target),
+            BenchError::NoRequests => write!(f, "No requests completed during the benchmark"),
+            BenchError::InvalidArg(arg) => write!(f, "Invalid argument: {}", arg),
+            BenchError::UnusableFleet => write!(f, "None of the configured nodes are usable"),
+        }
+    }
+}
+
+impl Error for BenchError {}
@@ -0,0 +1,351 @@
+use crate::{
+    bench::error::BenchError,
+    log_info,
+};
+
+use std::{
+    sync::{
+        atomic::{
+            AtomicUsize,
+            Ordering,
+        },
+        Arc,
+    },
+    time::{
+        Duration,
+        Instant,
+    },
+};
+
+use clap::ArgMatches;
+use rand::Rng;
+use reqwest::Client;
+use serde_json::{
+    json,
+    Value,
+};
+
+// Settings for a single bench run
+#[derive(Debug, Clone)]
+pub struct BenchSettings {
+    pub targets: Vec<String>,
+    pub requests: usize,
+    pub concurrency: usize,
+    pub mix: Vec<(String, u32)>,
+}
+
+impl BenchSettings {
+    pub fn from_matches(matches: &ArgMatches) -> Result<Self, BenchError> {
+        let targets: Vec<String> = arg(matches, "target")?
+            .split(',')
+            .map(|target| target.trim().to_string())
+            .filter(|target| !target.is_empty())
+            .collect();
+
+        for target in &targets {
+            if url::Url::parse(target).is_err() {
+                return Err(BenchError::InvalidTarget(target.to_string()));
+            }
+        }
+        if targets.is_empty() {
+            return Err(BenchError::InvalidTarget("no targets supplied".to_string()));
+        }
+
+        let requests = arg(matches, "requests")?;
+        let requests = requests
+            .parse::<usize>()
+            .map_err(|_| BenchError::InvalidArg(format!("requests {}", requests)))?;
+
+        let concurrency = arg(matches, "concurrency")?;
+        let concurrency = concurrency
+            .parse::<usize>()
+            .map_err(|_| BenchError::InvalidArg(format!("concurrency {}", concurrency)))?
+            .max(1);
+
+        let mix = parse_mix(arg(matches, "mix")?)?;
+
+        Ok(BenchSettings {
+            targets,
+            requests,
+            concurrency,
+            mix,
+        })
+    }
+}
+
+// Value of the argument `name`, erroring out if it's missing
+fn arg<'a>(matches: &'a ArgMatches, name: &str) -> Result<&'a String, BenchError> {
+    matches
+        .get_one::<String>(name)
+        .ok_or_else(|| BenchError::InvalidArg(format!("missing {}", name)))
+}
+
+// Aggregated results of a bench run
+#[derive(Debug, Clone, PartialEq)]
+pub struct BenchReport {
+    pub completed: usize,
+    pub errors: usize,
+    pub elapsed: Duration,
+    pub p50: Duration,
+    pub p90: Duration,
+    pub p99: Duration,
+    pub max: Duration,
+}
+
+impl BenchReport {
+    pub fn throughput(&self) -> f64 {
+        self.completed as f64 / self.elapsed.as_secs_f64()
+    }
+}
+
+// Parse a mix in the form of `method:weight,method:weight`.
+//
+// The weight is optional and defaults to 1.
+fn parse_mix(mix: &str) -> Result<Vec<(String, u32)>, BenchError> {
+    let mut parsed = Vec::new();
+
+    for item in mix.split(',').map(|item| item.trim()) {
+        if item.is_empty() {
+            continue;
+        }
+
+        let (method, weight) = match item.split_once(':') {
+            Some((method, weight)) => {
+                let weight = weight
+                    .parse::<u32>()
+                    .map_err(|_| BenchError::InvalidMix(item.to_string()))?;
+                (method, weight)
+            }
+            None => (item, 1),
+        };
+
+        if method.is_empty() || weight == 0 {
+            return Err(BenchError::InvalidMix(item.to_string()));
+        }
+
+        parsed.push((method.to_string(), weight));
+    }
+
+    if parsed.is_empty() {
+        return Err(BenchError::InvalidMix(mix.to_string()));
+    }
+
+    Ok(parsed)
+}
+
+// Pick a method from the mix according to its weight
+fn pick_method(mix: &[(String, u32)], roll: u32) -> &str {
+    let mut roll = roll;
+    for (method, weight) in mix {
+        if roll < *weight {
+            return method;
+        }
+        roll -= weight;
+    }
+
+    &mix[mix.len() - 1].0
+}
+
+// Build a request with sensible default params for common methods
+fn build_request(method: &str, id: usize) -> Value {
+    let params = match method {
+        "eth_getBlockByNumber" => json!(["latest", false]),
+        "eth_getBalance" | "eth_getTransactionCount" | "eth_getCode" => {
+            json!(["0x0000000000000000000000000000000000000000", "latest"])
+        }
+        "eth_call" => {
+            json!([{"to": "0x0000000000000000000000000000000000000000", "data": "0x"}, "latest"])
+        }
+        _ => json!([]),
+    };
+
+    json!({
+        "jsonrpc": "2.0",
+        "id": id,
+        "method": method,
+        "params": params,
+    })
+}
+
+// Return the latency at percentile `p`. `latencies` must be sorted.
+fn percentile(latencies: &[Duration], p: f64) -> Duration {
+    if latencies.is_empty() {
+        return Duration::ZERO;
+    }
+
+    let index = ((latencies.len() - 1) as f64 * p).round() as usize;
+    latencies[index]
+}
+
+// Fire `requests` calls at the targets using `concurrency` workers
+pub async fn run_bench(settings: BenchSettings) -> Result<BenchReport, BenchError> {
+    let client = Client::new();
+    let counter = Arc::new(AtomicUsize::new(0));
+    let settings = Arc::new(settings);
+    let total_weight: u32 = settings.mix.iter().map(|(_, weight)| weight).sum();
+
+    log_info!(
+        "Benchmarking {:?} with {} requests over {} workers",
+        settings.targets,
+        settings.requests,
+        settings.concurrency
+    );
+
+    let time = Instant::now();
+
+    let mut handles = Vec::new();
+    for _ in 0..settings.concurrency {
+        let client = client.clone();
+        let counter = Arc::clone(&counter);
+        let settings = Arc::clone(&settings);
+
+        handles.push(tokio::spawn(async move {
+            let mut latencies = Vec::new();
+            let mut errors = 0;
+
+            loop {
+                let id = counter.fetch_add(1, Ordering::Relaxed);
+                if id >= settings.requests {
+                    break;
+                }
+
+                let roll = rand::thread_rng().gen_range(0..total_weight);
+                let request = build_request(pick_method(&settings.mix, roll), id);
+                let target = &settings.targets[id % settings.targets.len()];
+
+                let start = Instant::now();
+                let response = client.post(target).json(&request).send().await;
+                let ok = match response {
+                    Ok(response) => {
+                        match response.json::<Value>().await {
+                            Ok(body) => body.get("error").is_none(),
+                            Err(_) => false,
+                        }
+                    }
+                    Err(_) => false,
+                };
+
+                if ok {
+                    latencies.push(start.elapsed());
+                } else {
+                    errors += 1;
+                }
+            }
+
+            (latencies, errors)
+        }));
+    }
+
+    let mut latencies = Vec::new();
+    let mut errors = 0;
+    for handle in handles {
+        if let Ok((worker_latencies, worker_errors)) = handle.await {
+            latencies.extend(worker_latencies);
+            errors += worker_errors;
+        }
+    }
+
+    let elapsed = time.elapsed();
+
+    if latencies.is_empty() {
+        return Err(BenchError::NoRequests);
+    }
+
+    latencies.sort_unstable();
+
+    Ok(BenchReport {
+        completed: latencies.len(),
+        errors,
+        elapsed,
+        p50: percentile(&latencies, 0.5),
+        p90: percentile(&latencies, 0.9),
+        p99: percentile(&latencies, 0.99),
+        max: latencies[latencies.len() - 1],
+    })
+}
+
+pub fn print_report(report: &BenchReport) {
+    println!("Completed: {} ({} errors)", report.completed, report.errors);
+    println!("Elapsed: {:?}", report.elapsed);
+    println!("Throughput: {:.2} req/s", report.throughput());
+    println!("Latency p50: {:?}", report.p50);
+    println!("Latency p90: {:?}", report.p90);
+    println!("Latency p99: {:?}", report.p99);
+    println!("Latency max: {:?}", report.max);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::cli_args::create_match;
+
+    #[test]
+    fn test_from_matches() {
+        let bench = |args: &[&str]| {
+            let matches =
+                create_match().get_matches_from(["blutgang", "bench"].iter().chain(args.iter()));
+            BenchSettings::from_matches(matches.subcommand_matches("bench").unwrap())
+        };
+
+        let settings = bench(&["--requests", "10", "--concurrency", "0"]).unwrap();
+        assert_eq!(settings.requests, 10);
+        assert_eq!(settings.concurrency, 1);
+
+        // Bad values are errors instead of panics
+        assert!(matches!(
+            bench(&["--requests", "lots"]),
+            Err(BenchError::InvalidArg(_))
+        ));
+        assert!(matches!(
+            bench(&["--concurrency", "many"]),
+            Err(BenchError::InvalidArg(_))
+        ));
+        assert!(matches!(
+            bench(&["--mix", "eth_call:x"]),
+            Err(BenchError::InvalidMix(_))
+        ));
+    }
+
+    #[test]
+    fn test_parse_mix() {
+        let mix = parse_mix("eth_blockNumber:3, eth_chainId").unwrap();
+        assert_eq!(
+            mix,
+            vec![
+                ("eth_blockNumber".to_string(), 3),
+                ("eth_chainId".to_string(), 1)
+            ]
+        );
+
+        assert!(parse_mix("").is_err());
+        assert!(parse_mix("eth_blockNumber:abc").is_err());
+        assert!(parse_mix("eth_blockNumber:0").is_err());
+    }
+
+    #[test]
+    fn test_pick_method() {
+        let mix = vec![("a".to_string(), 2), ("b".to_string(), 1)];
+
+        assert_eq!(pick_method(&mix, 0), "a");
+        assert_eq!(pick_method(&mix, 1), "a");
+        assert_eq!(pick_method(&mix, 2), "b");
+    }
+
+    #[test]
+    fn test_percentile() {
+        let latencies: Vec<Duration> = (1..=100).map(Duration::from_millis).collect();
+
+        assert_eq!(percentile(&latencies, 0.5), Duration::from_millis(51));
+        assert_eq!(percentile(&latencies, 0.99), Duration::from_millis(99));
+        assert_eq!(percentile(&[], 0.5), Duration::ZERO);
+    }
+
+    #[test]
+    fn test_build_request() {
+        let request = build_request("eth_getBlockByNumber", 5);
+
+        assert_eq!(request["id"], 5);
+        assert_eq!(request["params"], json!(["latest", false]));
+        assert_eq!(build_request("eth_chainId", 1)["params"], json!([]));
+    }
+}
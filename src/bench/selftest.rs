@@ -0,0 +1,546 @@
+// Startup self-test for the configured nodes.
+//
+// Runs a few representative calls against every node, reports what each one
+// can do and how fast it does it, and recommends config changes. Nothing is
+// cached and blutgang doesn't start serving requests.
+use crate::{
+    bench::error::BenchError,
+    log_info,
+    rpc::types::hex_to_decimal,
+    Rpc,
+    Settings,
+};
+
+use std::time::{
+    Duration,
+    Instant,
+};
+
+use clap::ArgMatches;
+use futures::{
+    future::join_all,
+    SinkExt,
+    StreamExt,
+};
+use serde_json::{
+    json,
+    Value,
+};
+use tokio_tungstenite::{
+    connect_async_tls_with_config,
+    tungstenite::protocol::Message,
+    Connector,
+};
+
+// Blocks we ask `eth_getLogs` for
+const LOGS_RANGE: u64 = 1000;
+// Nodes further behind the best head than this are flagged
+const MAX_LAG: u64 = 5;
+// Nodes this many times slower than the fastest one are flagged
+const SLOW_FACTOR: u32 = 5;
+
+#[derive(Debug, Clone)]
+pub struct SelftestSettings {
+    // How long we listen to `newHeads` on each node
+    pub ws_duration: Duration,
+}
+
+impl SelftestSettings {
+    pub fn from_matches(matches: &ArgMatches) -> Result<Self, BenchError> {
+        let ws_duration = matches
+            .get_one::<String>("ws_duration")
+            .expect("Invalid ws_duration");
+        let ws_duration = ws_duration
+            .parse::<u64>()
+            .map_err(|_| BenchError::InvalidArg(format!("ws_duration {}", ws_duration)))?;
+
+        Ok(SelftestSettings {
+            ws_duration: Duration::from_secs(ws_duration),
+        })
+    }
+}
+
+// Outcome of one call against one node
+#[derive(Debug, Clone, PartialEq)]
+pub struct Check {
+    pub ok: bool,
+    pub latency: Duration,
+    pub detail: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct NodeReport {
+    pub name: String,
+    pub head: Option<u64>,
+    pub latest_block: Check,
+    pub historical_state: Check,
+    pub logs: Check,
+    // `None` if the node has no WS endpoint
+    pub subscription: Option<Check>,
+    // Average time between `newHeads` events
+    pub block_interval: Option<Duration>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SelftestReport {
+    pub nodes: Vec<NodeReport>,
+    pub recommendations: Vec<String>,
+}
+
+impl SelftestReport {
+    // We need at least one node that knows the head of the chain
+    pub fn is_usable(&self) -> bool {
+        self.nodes.iter().any(|node| node.head.is_some())
+    }
+}
+
+// Call `method` and return its result, or the error it failed with
+async fn call(rpc: &Rpc, method: &str, params: Value) -> (Result<Value, String>, Duration) {
+    let request = json!({
+        "jsonrpc": "2.0",
+        "id": 1,
+        "method": method,
+        "params": params,
+    });
+
+    let time = Instant::now();
+    let response = rpc.send_request(request).await;
+    let latency = time.elapsed();
+
+    let result = match response {
+        Ok(response) => {
+            match serde_json::from_str::<Value>(&response) {
+                Ok(response) if response.get("error").is_some() => {
+                    Err(response["error"]["message"]
+                        .as_str()
+                        .map(str::to_string)
+                        .unwrap_or(response["error"].to_string()))
+                }
+                Ok(response) => Ok(response["result"].clone()),
+                Err(e) => Err(e.to_string()),
+            }
+        }
+        Err(e) => Err(e.to_string()),
+    };
+
+    (result, latency)
+}
+
+fn failed(detail: impl Into<String>) -> Check {
+    Check {
+        ok: false,
+        latency: Duration::ZERO,
+        detail: detail.into(),
+    }
+}
+
+// Subscribe to `newHeads` and count the heads we get in `duration`
+async fn check_subscription(rpc: &Rpc, ws_url: &str, duration: Duration) -> (Check, Vec<Instant>) {
+    let connector = rpc.ws_connector.clone().map(Connector::NativeTls);
+    let time = Instant::now();
+    let ws_stream = match connect_async_tls_with_config(ws_url, None, false, connector).await {
+        Ok((ws_stream, _)) => ws_stream,
+        Err(e) => return (failed(format!("could not connect: {}", e)), Vec::new()),
+    };
+    let (mut sink, mut stream) = ws_stream.split();
+
+    let subscribe =
+        json!({"jsonrpc": "2.0", "id": 1, "method": "eth_subscribe", "params": ["newHeads"]});
+    if let Err(e) = sink.send(Message::Text(subscribe.to_string())).await {
+        return (failed(format!("could not subscribe: {}", e)), Vec::new());
+    }
+
+    let mut latency = None;
+    let mut heads = Vec::new();
+    let deadline = tokio::time::sleep(duration);
+    tokio::pin!(deadline);
+    loop {
+        tokio::select! {
+            _ = &mut deadline => break,
+            message = stream.next() => {
+                let message: Value = match message {
+                    Some(Ok(Message::Text(text))) => serde_json::from_str(&text).unwrap_or_default(),
+                    Some(Ok(_)) => continue,
+                    _ => break,
+                };
+
+                if message["method"] == "eth_subscription" {
+                    heads.push(Instant::now());
+                } else if message["id"] == 1 {
+                    if let Some(error) = message.get("error") {
+                        return (failed(format!("subscription rejected: {}", error)), Vec::new());
+                    }
+                    latency = Some(time.elapsed());
+                }
+            }
+        }
+    }
+
+    let check = match latency {
+        Some(latency) => {
+            Check {
+                ok: !heads.is_empty(),
+                latency,
+                detail: format!("{} heads in {}s", heads.len(), duration.as_secs()),
+            }
+        }
+        None => failed("subscription was never confirmed"),
+    };
+
+    (check, heads)
+}
+
+async fn test_node(rpc: &Rpc, settings: &SelftestSettings) -> NodeReport {
+    let (result, latency) = call(rpc, "eth_blockNumber", json!([])).await;
+    let head = result
+        .as_ref()
+        .ok()
+        .and_then(Value::as_str)
+        .and_then(|head| hex_to_decimal(head).ok());
+    let latest_block = match (&result, head) {
+        (_, Some(head)) => {
+            Check {
+                ok: true,
+                latency,
+                detail: format!("head at {}", head),
+            }
+        }
+        (Err(e), None) => failed(e.clone()),
+        (Ok(result), None) => failed(format!("unexpected result {}", result)),
+    };
+
+    let (historical_state, logs) = match head {
+        Some(head) => {
+            // Pruned nodes can't serve state this old
+            let (result, latency) = call(
+                rpc,
+                "eth_getBalance",
+                json!(["0x0000000000000000000000000000000000000000", "0x1"]),
+            )
+            .await;
+            let historical_state = match result {
+                Ok(_) => {
+                    Check {
+                        ok: true,
+                        latency,
+                        detail: "archive".to_string(),
+                    }
+                }
+                Err(e) => failed(e),
+            };
+
+            let from = head.saturating_sub(LOGS_RANGE - 1);
+            let (result, latency) = call(
+                rpc,
+                "eth_getLogs",
+                json!([{
+                    "fromBlock": format!("0x{:x}", from),
+                    "toBlock": format!("0x{:x}", head),
+                    "address": "0x0000000000000000000000000000000000000000",
+                }]),
+            )
+            .await;
+            let logs = match result {
+                Ok(_) => {
+                    Check {
+                        ok: true,
+                        latency,
+                        detail: format!("{} block range", head - from + 1),
+                    }
+                }
+                Err(e) => failed(e),
+            };
+
+            (historical_state, logs)
+        }
+        None => (failed("no head"), failed("no head")),
+    };
+
+    let (subscription, block_interval) = match &rpc.ws_url {
+        Some(ws_url) => {
+            let (check, heads) = check_subscription(rpc, ws_url, settings.ws_duration).await;
+            let block_interval = match (heads.first(), heads.last()) {
+                (Some(first), Some(last)) if heads.len() > 1 => {
+                    Some(last.duration_since(*first) / (heads.len() as u32 - 1))
+                }
+                _ => None,
+            };
+            (Some(check), block_interval)
+        }
+        None => (None, None),
+    };
+
+    NodeReport {
+        name: rpc.name.clone(),
+        head,
+        latest_block,
+        historical_state,
+        logs,
+        subscription,
+        block_interval,
+    }
+}
+
+// Suggest config changes based on what the nodes can do.
+//
+// `expected_block_time` is the one from the config, in ms.
+fn recommend(nodes: &[NodeReport], expected_block_time: u64) -> Vec<String> {
+    let mut recommendations = Vec::new();
+    let responsive: Vec<&NodeReport> = nodes.iter().filter(|node| node.head.is_some()).collect();
+
+    for node in nodes.iter().filter(|node| node.head.is_none()) {
+        recommendations.push(format!(
+            "{} doesn't answer eth_blockNumber ({}), check its URL or remove it.",
+            node.name, node.latest_block.detail
+        ));
+    }
+
+    let best_head = responsive.iter().filter_map(|node| node.head).max();
+    if let Some(best_head) = best_head {
+        for node in &responsive {
+            let lag = best_head - node.head.unwrap_or(best_head);
+            if lag > MAX_LAG {
+                recommendations.push(format!(
+                    "{} is {} blocks behind the best head, check that it's synced.",
+                    node.name, lag
+                ));
+            }
+        }
+    }
+
+    let fastest = responsive
+        .iter()
+        .map(|node| node.latest_block.latency)
+        .min();
+    if let Some(fastest) = fastest {
+        for node in &responsive {
+            if node.latest_block.latency > (fastest * SLOW_FACTOR).max(Duration::from_millis(100)) {
+                recommendations.push(format!(
+                    "{} is much slower than the fastest node ({:?} vs {:?}), consider removing it.",
+                    node.name, node.latest_block.latency, fastest
+                ));
+            }
+        }
+    }
+
+    if !responsive.is_empty() && responsive.iter().all(|node| !node.historical_state.ok) {
+        recommendations.push(
+            "None of the nodes serve historical state, requests for old blocks will fail. Add an archive node."
+                .to_string(),
+        );
+    }
+
+    for node in responsive.iter().filter(|node| !node.logs.ok) {
+        recommendations.push(format!(
+            "{} can't serve eth_getLogs over {} blocks ({}), route large log queries elsewhere.",
+            node.name, LOGS_RANGE, node.logs.detail
+        ));
+    }
+
+    let with_ws = nodes
+        .iter()
+        .filter(|node| node.subscription.is_some())
+        .count();
+    if with_ws > 0 && with_ws < nodes.len() {
+        recommendations.push(
+            "Only some nodes have a ws_url, subscriptions are disabled unless all of them do."
+                .to_string(),
+        );
+    }
+    for node in nodes {
+        if let Some(subscription) = node.subscription.as_ref().filter(|check| !check.ok) {
+            recommendations.push(format!(
+                "{} subscription check failed ({}), check its ws_url.",
+                node.name, subscription.detail
+            ));
+        }
+    }
+
+    // The config value is padded by 10% when parsed
+    let intervals: Vec<Duration> = nodes
+        .iter()
+        .filter_map(|node| node.block_interval)
+        .collect();
+    if expected_block_time > 0 && !intervals.is_empty() {
+        let measured = intervals.iter().sum::<Duration>() / intervals.len() as u32;
+        let measured = measured.as_millis() as u64;
+        let configured = expected_block_time * 10 / 11;
+        if measured * 2 < configured || measured > configured * 2 {
+            recommendations.push(format!(
+                "expected_block_time is {}ms, but blocks arrived every {}ms. Set it to {}.",
+                configured, measured, measured
+            ));
+        }
+    }
+
+    recommendations
+}
+
+pub async fn run_selftest(settings: &Settings, selftest: SelftestSettings) -> SelftestReport {
+    log_info!(
+        "Testing {} nodes, listening for new heads for {}s...",
+        settings.rpc_list.len(),
+        selftest.ws_duration.as_secs()
+    );
+
+    let nodes = join_all(
+        settings
+            .rpc_list
+            .iter()
+            .map(|rpc| test_node(rpc, &selftest)),
+    )
+    .await;
+    let recommendations = recommend(&nodes, settings.expected_block_time);
+
+    SelftestReport {
+        nodes,
+        recommendations,
+    }
+}
+
+fn print_check(name: &str, check: &Check) {
+    if check.ok {
+        println!(
+            "  {:<18} ok    {:>10.2?}  {}",
+            name, check.latency, check.detail
+        );
+    } else {
+        println!("  {:<18} FAIL  {:>10}  {}", name, "", check.detail);
+    }
+}
+
+pub fn print_selftest_report(report: &SelftestReport) {
+    for node in &report.nodes {
+        println!("{}", node.name);
+        print_check("latest block", &node.latest_block);
+        print_check("historical state", &node.historical_state);
+        print_check("logs", &node.logs);
+        match &node.subscription {
+            Some(subscription) => print_check("subscription", subscription),
+            None => println!("  {:<18} skip  {:>10}  no ws_url", "subscription", ""),
+        }
+    }
+
+    if !report.recommendations.is_empty() {
+        println!("\nRecommendations:");
+        for recommendation in &report.recommendations {
+            println!("  - {}", recommendation);
+        }
+    }
+
+    if report.is_usable() {
+        println!("\nFleet is usable.");
+    } else {
+        println!("\nFleet is unusable, no node knows the head of the chain!");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::node::MockNode;
+
+    fn ok(latency: u64, detail: &str) -> Check {
+        Check {
+            ok: true,
+            latency: Duration::from_millis(latency),
+            detail: detail.to_string(),
+        }
+    }
+
+    fn node(name: &str, head: Option<u64>) -> NodeReport {
+        NodeReport {
+            name: name.to_string(),
+            head,
+            latest_block: match head {
+                Some(_) => ok(10, "head"),
+                None => failed("connection refused"),
+            },
+            historical_state: failed("missing trie node"),
+            logs: ok(10, "1000 block range"),
+            subscription: None,
+            block_interval: None,
+        }
+    }
+
+    #[test]
+    fn test_recommend() {
+        let mut nodes = vec![node("a", Some(100)), node("b", Some(90)), node("c", None)];
+        nodes[0].subscription = Some(ok(5, "3 heads in 30s"));
+        nodes[0].block_interval = Some(Duration::from_millis(2000));
+
+        let recommendations = recommend(&nodes, 13200);
+        let has = |needle: &str| recommendations.iter().any(|r| r.contains(needle));
+        assert!(has("c doesn't answer eth_blockNumber"));
+        assert!(has("b is 10 blocks behind"));
+        assert!(has("None of the nodes serve historical state"));
+        assert!(has("Only some nodes have a ws_url"));
+        assert!(has(
+            "expected_block_time is 12000ms, but blocks arrived every 2000ms"
+        ));
+        assert!(!has("a is"));
+
+        // Nothing to say about a healthy fleet
+        let mut healthy = node("a", Some(100));
+        healthy.historical_state = ok(10, "archive");
+        assert!(recommend(&[healthy], 13200).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_run_selftest() {
+        let archive = MockNode::spawn(1).await.unwrap();
+        archive.set_response("eth_getBalance", json!("0x0"));
+        archive.set_response("eth_getLogs", json!([]));
+        let pruned = MockNode::spawn(1).await.unwrap();
+        pruned.set_state_horizon(2);
+
+        let settings = Settings {
+            rpc_list: vec![
+                Rpc::new(archive.http_url(), Some(archive.ws_url()), 6, 0, 10.0),
+                Rpc::new(pruned.http_url(), None, 6, 0, 10.0),
+            ],
+            expected_block_time: 0,
+            ..Default::default()
+        };
+
+        let heads = archive.clone();
+        tokio::spawn(async move {
+            for _ in 0..3 {
+                tokio::time::sleep(Duration::from_millis(200)).await;
+                heads.advance();
+            }
+        });
+
+        let report = run_selftest(
+            &settings,
+            SelftestSettings {
+                ws_duration: Duration::from_secs(1),
+            },
+        )
+        .await;
+        assert!(report.is_usable());
+
+        let (archive, pruned) = (&report.nodes[0], &report.nodes[1]);
+        assert_eq!(archive.head, Some(1));
+        assert!(archive.historical_state.ok);
+        assert!(archive.logs.ok);
+        assert!(archive.subscription.as_ref().unwrap().ok);
+        assert!(archive.block_interval.is_some());
+        assert!(!pruned.historical_state.ok);
+        assert!(!pruned.logs.ok);
+        assert!(pruned.subscription.is_none());
+
+        // Nothing answering is unusable
+        let report = run_selftest(
+            &Settings {
+                rpc_list: vec![Rpc::new("http://127.0.0.1:1".to_string(), None, 6, 0, 10.0)],
+                ..Default::default()
+            },
+            SelftestSettings {
+                ws_duration: Duration::from_secs(1),
+            },
+        )
+        .await;
+        assert!(!report.is_usable());
+    }
+}
@@ -0,0 +1,219 @@
+// Typed client for the admin namespace.
+//
+// Signs every call with the admin JWT secret if one is set, the same way
+// blutgang expects them when `jwt = true` in `[admin]`.
+use crate::{
+    admin::accept::Claims,
+    client::{
+        error::ClientError,
+        http::into_result,
+    },
+};
+
+use std::{
+    sync::{
+        atomic::{
+            AtomicU64,
+            Ordering,
+        },
+        Arc,
+    },
+    time::{
+        Duration,
+        SystemTime,
+    },
+};
+
+use jsonwebtoken::{
+    encode,
+    EncodingKey,
+    Header,
+};
+use serde_json::{
+    json,
+    Value,
+};
+
+// How long signed calls stay valid
+const TOKEN_LIFETIME: Duration = Duration::from_secs(60);
+
+#[derive(Clone)]
+pub struct AdminClient {
+    url: String,
+    client: reqwest::Client,
+    key: Option<EncodingKey>,
+    next_id: Arc<AtomicU64>,
+}
+
+impl std::fmt::Debug for AdminClient {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "AdminClient {{ url: {:?}, jwt: HIDDEN }}", self.url)
+    }
+}
+
+impl AdminClient {
+    pub fn new(url: &str) -> Self {
+        Self {
+            url: url.to_string(),
+            client: reqwest::Client::new(),
+            key: None,
+            next_id: Arc::new(AtomicU64::new(1)),
+        }
+    }
+
+    // Sign calls with the admin `secret`
+    pub fn with_jwt(mut self, secret: &[u8]) -> Self {
+        self.key = Some(EncodingKey::from_secret(secret));
+        self
+    }
+
+    // Body of a call, wrapped in a token if we sign them
+    fn request(&self, method: &str, params: Value) -> Result<Value, ClientError> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let key = match &self.key {
+            Some(key) => key,
+            None => {
+                return Ok(json!({
+                    "jsonrpc": "2.0",
+                    "id": id,
+                    "method": method,
+                    "params": params,
+                }))
+            }
+        };
+
+        let exp = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            + TOKEN_LIFETIME;
+        let claims = Claims {
+            id: json!(id),
+            jsonrpc: json!("2.0"),
+            method: json!(method),
+            params,
+            exp: exp.as_secs() as usize,
+        };
+
+        Ok(json!({"token": encode(&Header::default(), &claims, key)?}))
+    }
+
+    pub async fn call(&self, method: &str, params: Value) -> Result<Value, ClientError> {
+        let response = self
+            .client
+            .post(&self.url)
+            .json(&self.request(method, params)?)
+            .send()
+            .await?;
+        if response.status() == reqwest::StatusCode::UNAUTHORIZED {
+            return Err(ClientError::Rpc {
+                code: 401,
+                message: response.text().await?,
+            });
+        }
+
+        into_result(response.json::<Value>().await?)
+    }
+
+    // Admin errors come back as a string result, so check we got the type we expect
+    async fn call_u64(&self, method: &str, params: Value) -> Result<u64, ClientError> {
+        let result = self.call(method, params).await?;
+        result
+            .as_u64()
+            .ok_or_else(|| ClientError::InvalidResponse(result.to_string()))
+    }
+
+    pub async fn rpc_list(&self) -> Result<Value, ClientError> {
+        self.call("blutgang_rpc_list", json!([])).await
+    }
+
+    pub async fn poverty_list(&self) -> Result<Value, ClientError> {
+        self.call("blutgang_poverty_list", json!([])).await
+    }
+
+    pub async fn quarantine_list(&self) -> Result<Value, ClientError> {
+        self.call("blutgang_quarantine_list", json!([])).await
+    }
+
+    pub async fn release_from_quarantine(&self, node: &str) -> Result<Value, ClientError> {
+        self.call("blutgang_release_from_quarantine", json!([node]))
+            .await
+    }
+
+    pub async fn drain_rpc(&self, name: &str) -> Result<Value, ClientError> {
+        self.call("blutgang_drainRpc", json!([name])).await
+    }
+
+    pub async fn enable_rpc(&self, name: &str) -> Result<Value, ClientError> {
+        self.call("blutgang_enableRpc", json!([name])).await
+    }
+
+    pub async fn latency(&self) -> Result<Value, ClientError> {
+        self.call("blutgang_latency", json!([])).await
+    }
+
+    pub async fn scores(&self) -> Result<Value, ClientError> {
+        self.call("blutgang_scores", json!([])).await
+    }
+
+    pub async fn memory(&self) -> Result<Value, ClientError> {
+        self.call("blutgang_memory", json!([])).await
+    }
+
+    pub async fn config(&self) -> Result<Value, ClientError> {
+        self.call("blutgang_config", json!([])).await
+    }
+
+    pub async fn ttl(&self) -> Result<u64, ClientError> {
+        self.call_u64("blutgang_ttl", json!([])).await
+    }
+
+    pub async fn set_ttl(&self, ttl: u64) -> Result<Value, ClientError> {
+        self.call("blutgang_set_ttl", json!([ttl])).await
+    }
+
+    pub async fn health_check_ttl(&self) -> Result<u64, ClientError> {
+        self.call_u64("blutgang_health_check_ttl", json!([])).await
+    }
+
+    pub async fn set_health_check_ttl(&self, ttl: u64) -> Result<Value, ClientError> {
+        self.call("blutgang_set_health_check_ttl", json!([ttl]))
+            .await
+    }
+
+    pub async fn flush_cache(&self) -> Result<Value, ClientError> {
+        self.call("blutgang_flush_cache", json!([])).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use jsonwebtoken::{
+        decode,
+        DecodingKey,
+        Validation,
+    };
+
+    #[test]
+    fn test_signed_request() {
+        let client = AdminClient::new("http://127.0.0.1:5715").with_jwt(b"some-key");
+        let request = client.request("blutgang_set_ttl", json!([500])).unwrap();
+
+        let claims = decode::<Claims>(
+            request["token"].as_str().unwrap(),
+            &DecodingKey::from_secret(b"some-key"),
+            &Validation::default(),
+        )
+        .unwrap()
+        .claims;
+        assert_eq!(claims.method, "blutgang_set_ttl");
+        assert_eq!(claims.params, json!([500]));
+
+        // Plain JSON-RPC without a secret
+        let request = AdminClient::new("http://127.0.0.1:5715")
+            .request("blutgang_ttl", json!([]))
+            .unwrap();
+        assert_eq!(request["method"], "blutgang_ttl");
+        assert!(request.get("token").is_none());
+    }
+}
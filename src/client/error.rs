@@ -0,0 +1,49 @@
+// Errors
+use std::error::Error;
+
+#[derive(Debug)]
+pub enum ClientError {
+    // We couldn't reach blutgang at all
+    Http(reqwest::Error),
+    WebSocket(String),
+    // Blutgang or the node answered with a JSON-RPC error
+    Rpc { code: i64, message: String },
+    // The response doesn't look like what the method returns
+    InvalidResponse(String),
+    Jwt(jsonwebtoken::errors::Error),
+    // The connection went away before we got an answer
+    Closed,
+}
+
+impl std::fmt::Display for ClientError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ClientError::Http(e) => write!(f, "HTTP error: {}", e),
+            ClientError::WebSocket(reason) => write!(f, "WebSocket error: {}", reason),
+            ClientError::Rpc { code, message } => write!(f, "RPC error {}: {}", code, message),
+            ClientError::InvalidResponse(reason) => write!(f, "Invalid response: {}", reason),
+            ClientError::Jwt(e) => write!(f, "Could not sign admin request: {}", e),
+            ClientError::Closed => write!(f, "Connection closed"),
+        }
+    }
+}
+
+impl From<reqwest::Error> for ClientError {
+    fn from(e: reqwest::Error) -> Self {
+        ClientError::Http(e)
+    }
+}
+
+impl From<tungstenite::Error> for ClientError {
+    fn from(e: tungstenite::Error) -> Self {
+        ClientError::WebSocket(e.to_string())
+    }
+}
+
+impl From<jsonwebtoken::errors::Error> for ClientError {
+    fn from(e: jsonwebtoken::errors::Error) -> Self {
+        ClientError::Jwt(e)
+    }
+}
+
+impl Error for ClientError {}
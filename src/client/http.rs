@@ -0,0 +1,224 @@
+// Typed client for blutgang's HTTP extensions.
+//
+// Sends regular JSON-RPC calls, optionally with routing hints or to a node
+// group, and wraps blutgang's own methods so services don't have to build
+// the JSON for them by hand.
+use crate::{
+    balancer::{
+        block_range::BLOCK_RANGE,
+        logs_paged::LOGS_PAGED,
+        routing_hints::RoutingHints,
+        selection::groups::GROUP_PATH,
+    },
+    client::error::ClientError,
+    rpc::types::hex_to_decimal,
+    transactions::tracker::NEXT_NONCE,
+};
+
+use std::sync::{
+    atomic::{
+        AtomicU64,
+        Ordering,
+    },
+    Arc,
+};
+
+use serde_json::{
+    json,
+    Value,
+};
+
+// `result` of a JSON-RPC `response`, or its error
+pub(crate) fn into_result(mut response: Value) -> Result<Value, ClientError> {
+    if let Some(error) = response.get("error") {
+        // Some of our errors are plain strings
+        let (code, message) = match error {
+            Value::String(message) => (0, message.clone()),
+            error => {
+                (
+                    error["code"].as_i64().unwrap_or_default(),
+                    error["message"]
+                        .as_str()
+                        .map(str::to_string)
+                        .unwrap_or_else(|| error.to_string()),
+                )
+            }
+        };
+        return Err(ClientError::Rpc { code, message });
+    }
+
+    match response.get_mut("result") {
+        Some(result) => Ok(result.take()),
+        None => Err(ClientError::InvalidResponse(response.to_string())),
+    }
+}
+
+// Parse a hex quantity out of a `result`
+pub(crate) fn quantity(result: &Value) -> Result<u64, ClientError> {
+    result
+        .as_str()
+        .and_then(|number| hex_to_decimal(number).ok())
+        .ok_or_else(|| ClientError::InvalidResponse(format!("expected a quantity, got {}", result)))
+}
+
+#[derive(Debug, Clone)]
+pub struct BlutgangClient {
+    url: String,
+    client: reqwest::Client,
+    hints: Option<RoutingHints>,
+    next_id: Arc<AtomicU64>,
+}
+
+impl BlutgangClient {
+    pub fn new(url: &str) -> Self {
+        Self {
+            url: url.trim_end_matches('/').to_string(),
+            client: reqwest::Client::new(),
+            hints: None,
+            next_id: Arc::new(AtomicU64::new(1)),
+        }
+    }
+
+    // Client that only talks to the nodes in `group`
+    pub fn group(&self, group: &str) -> Self {
+        Self {
+            url: format!("{}{}{}", self.url, GROUP_PATH, group),
+            ..self.clone()
+        }
+    }
+
+    // Client that sends `hints` with every call
+    pub fn with_hints(&self, hints: RoutingHints) -> Self {
+        Self {
+            hints: Some(hints),
+            ..self.clone()
+        }
+    }
+
+    pub async fn call(&self, method: &str, params: Value) -> Result<Value, ClientError> {
+        let mut request = json!({
+            "jsonrpc": "2.0",
+            "id": self.next_id.fetch_add(1, Ordering::Relaxed),
+            "method": method,
+            "params": params,
+        });
+        if let Some(hints) = &self.hints {
+            request["blutgang"] = hints.to_value();
+        }
+
+        let response = self
+            .client
+            .post(&self.url)
+            .json(&request)
+            .send()
+            .await?
+            .json::<Value>()
+            .await?;
+        into_result(response)
+    }
+
+    pub async fn block_number(&self) -> Result<u64, ClientError> {
+        quantity(&self.call("eth_blockNumber", json!([])).await?)
+    }
+
+    // Every block in `[start, end]`, see `blutgang_getBlockRange`
+    pub async fn block_range(
+        &self,
+        start: u64,
+        end: u64,
+        full_tx: bool,
+    ) -> Result<Vec<Value>, ClientError> {
+        match self.call(BLOCK_RANGE, json!([start, end, full_tx])).await? {
+            Value::Array(blocks) => Ok(blocks),
+            result => {
+                Err(ClientError::InvalidResponse(format!(
+                    "expected an array of blocks, got {}",
+                    result
+                )))
+            }
+        }
+    }
+
+    // One page of the logs matching `filter`, and the cursor to pass back for
+    // the next one if there's more. See `blutgang_getLogsPaged`.
+    pub async fn logs_page(
+        &self,
+        filter: Value,
+        page_size: Option<usize>,
+        cursor: Option<&str>,
+    ) -> Result<(Vec<Value>, Option<String>), ClientError> {
+        let page = self
+            .call(
+                LOGS_PAGED,
+                json!([filter, {"pageSize": page_size, "cursor": cursor}]),
+            )
+            .await?;
+        match (&page["logs"], &page["cursor"]) {
+            (Value::Array(logs), Value::String(cursor)) => Ok((logs.clone(), Some(cursor.clone()))),
+            (Value::Array(logs), Value::Null) => Ok((logs.clone(), None)),
+            _ => {
+                Err(ClientError::InvalidResponse(format!(
+                    "expected a page of logs, got {}",
+                    page
+                )))
+            }
+        }
+    }
+
+    // Nonce the next transaction from `address` should use, counting the
+    // ones blutgang has seen broadcast but that aren't included yet
+    pub async fn next_nonce(&self, address: &str) -> Result<u64, ClientError> {
+        quantity(&self.call(NEXT_NONCE, json!([address])).await?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::node::MockNode;
+
+    #[test]
+    fn test_into_result() {
+        assert_eq!(into_result(json!({"result": "0x1"})).unwrap(), "0x1");
+        assert!(matches!(
+            into_result(json!({"error": {"code": -32006, "message": "nope"}})),
+            Err(ClientError::Rpc { code: -32006, .. })
+        ));
+        assert!(matches!(
+            into_result(json!({"error": "Unknown subscription kind!"})),
+            Err(ClientError::Rpc { code: 0, .. })
+        ));
+        assert!(into_result(json!({"id": 1})).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_client_call() {
+        let node = MockNode::spawn(1).await.unwrap();
+        node.advance();
+        node.set_response(BLOCK_RANGE, json!([{"number": "0x1"}, {"number": "0x2"}]));
+
+        let client = BlutgangClient::new(&node.http_url());
+        assert_eq!(client.block_number().await.unwrap(), 2);
+        assert_eq!(client.block_range(1, 2, false).await.unwrap().len(), 2);
+
+        node.set_response(
+            LOGS_PAGED,
+            json!({"logs": [{"logIndex": "0x0"}], "cursor": "0x2:1"}),
+        );
+        let (logs, cursor) = client
+            .logs_page(json!({"fromBlock": "0x1", "toBlock": "0x2"}), Some(1), None)
+            .await
+            .unwrap();
+        assert_eq!(logs.len(), 1);
+        assert_eq!(cursor.as_deref(), Some("0x2:1"));
+        assert!(matches!(
+            client.call("eth_unknown", json!([])).await,
+            Err(ClientError::Rpc { code: -32601, .. })
+        ));
+
+        assert_eq!(
+            client.group("archive").url,
+            format!("{}/group/archive", node.http_url())
+        );
+    }
+}
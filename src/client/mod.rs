@@ -0,0 +1,4 @@
+pub mod admin;
+pub mod error;
+pub mod http;
+pub mod subscriptions;
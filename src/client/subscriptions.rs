@@ -0,0 +1,336 @@
+// Typed client for blutgang's WS subscriptions.
+//
+// One connection carries any number of subscriptions. A background task
+// reads every message, hands responses to the call waiting on them and
+// events to the subscription they belong to. Subscriptions are registered
+// before their id is handed back, so no event gets lost in between.
+use crate::{
+    client::{
+        error::ClientError,
+        http::into_result,
+    },
+    websocket::{
+        confirmed::CONFIRMED_LOGS,
+        reorgs::REORGS,
+        tx_status::TX_STATUS,
+    },
+};
+
+use std::{
+    collections::HashMap,
+    marker::PhantomData,
+    sync::{
+        atomic::{
+            AtomicU64,
+            Ordering,
+        },
+        Arc,
+        Mutex,
+    },
+};
+
+use futures::{
+    SinkExt,
+    StreamExt,
+};
+use serde::{
+    de::DeserializeOwned,
+    Deserialize,
+};
+use serde_json::{
+    json,
+    Value,
+};
+use tokio::sync::{
+    mpsc,
+    oneshot,
+};
+use tokio_tungstenite::connect_async;
+use tungstenite::Message;
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct BlockRef {
+    pub number: String,
+    pub hash: String,
+}
+
+// `reorgs` event
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ReorgEvent {
+    pub old_head: BlockRef,
+    pub new_head: BlockRef,
+    pub depth: u64,
+}
+
+// `txStatus` event
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TxStatusEvent {
+    pub transaction_hash: String,
+    // `pending`, `included`, `finalized`, `replaced` or `dropped`
+    pub status: String,
+    pub block_number: Option<String>,
+    pub block_hash: Option<String>,
+}
+
+// A call waiting on its response
+struct Pending {
+    response: oneshot::Sender<Value>,
+    // Where events go if this call creates a subscription
+    events: Option<mpsc::UnboundedSender<Value>>,
+}
+
+#[derive(Default)]
+struct Routes {
+    pending: HashMap<u64, Pending>,
+    subscriptions: HashMap<String, mpsc::UnboundedSender<Value>>,
+}
+
+impl Routes {
+    // Send `message` to whoever is waiting for it
+    fn route(&mut self, message: Value) {
+        if let Some(id) = message["id"].as_u64() {
+            if let Some(pending) = self.pending.remove(&id) {
+                if let (Some(events), Some(subscription)) =
+                    (pending.events, message["result"].as_str())
+                {
+                    self.subscriptions.insert(subscription.to_string(), events);
+                }
+                let _ = pending.response.send(message);
+            }
+            return;
+        }
+
+        // `eth_subscription` for subscriptions we proxy, `blutgang_subscription` for our own
+        let is_event = message["method"]
+            .as_str()
+            .is_some_and(|method| method.ends_with("_subscription"));
+        let subscription = message["params"]["subscription"].as_str();
+        if let (true, Some(subscription)) = (is_event, subscription) {
+            let delivered = self
+                .subscriptions
+                .get(subscription)
+                .is_some_and(|events| events.send(message["params"]["result"].clone()).is_ok());
+            if !delivered {
+                self.subscriptions.remove(subscription);
+            }
+        }
+    }
+}
+
+// Events of a single subscription
+#[derive(Debug)]
+pub struct Subscription<T> {
+    pub id: String,
+    unsubscribe: &'static str,
+    events: mpsc::UnboundedReceiver<Value>,
+    kind: PhantomData<T>,
+}
+
+impl<T: DeserializeOwned> Subscription<T> {
+    // Next event, or `None` once the connection is gone
+    pub async fn next(&mut self) -> Option<Result<T, ClientError>> {
+        let event = self.events.recv().await?;
+        Some(
+            serde_json::from_value(event.clone())
+                .map_err(|e| ClientError::InvalidResponse(format!("{}: {}", e, event))),
+        )
+    }
+}
+
+pub struct SubscriptionClient {
+    outgoing: mpsc::UnboundedSender<Message>,
+    routes: Arc<Mutex<Routes>>,
+    next_id: AtomicU64,
+}
+
+impl SubscriptionClient {
+    pub async fn connect(url: &str) -> Result<Self, ClientError> {
+        let (websocket, _) = connect_async(url).await?;
+        let (mut sink, mut stream) = websocket.split();
+        let (outgoing, mut outgoing_rx) = mpsc::unbounded_channel::<Message>();
+        let routes = Arc::new(Mutex::new(Routes::default()));
+
+        tokio::spawn(async move {
+            while let Some(message) = outgoing_rx.recv().await {
+                if sink.send(message).await.is_err() {
+                    return;
+                }
+            }
+        });
+
+        let routes_reader = routes.clone();
+        tokio::spawn(async move {
+            while let Some(Ok(message)) = stream.next().await {
+                let message: Value = match message {
+                    Message::Text(text) => {
+                        match serde_json::from_str(&text) {
+                            Ok(message) => message,
+                            Err(_) => continue,
+                        }
+                    }
+                    Message::Close(_) => break,
+                    _ => continue,
+                };
+                routes_reader
+                    .lock()
+                    .unwrap_or_else(|e| e.into_inner())
+                    .route(message);
+            }
+
+            // Dropping the senders ends every call and subscription
+            let mut routes = routes_reader.lock().unwrap_or_else(|e| e.into_inner());
+            *routes = Routes::default();
+        });
+
+        Ok(Self {
+            outgoing,
+            routes,
+            next_id: AtomicU64::new(1),
+        })
+    }
+
+    async fn request(
+        &self,
+        method: &str,
+        params: Value,
+        events: Option<mpsc::UnboundedSender<Value>>,
+    ) -> Result<Value, ClientError> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (response, response_rx) = oneshot::channel();
+        self.routes
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .pending
+            .insert(id, Pending { response, events });
+
+        let request = json!({"jsonrpc": "2.0", "id": id, "method": method, "params": params});
+        self.outgoing
+            .send(Message::Text(request.to_string()))
+            .map_err(|_| ClientError::Closed)?;
+
+        into_result(response_rx.await.map_err(|_| ClientError::Closed)?)
+    }
+
+    // Send a regular call over the connection
+    pub async fn call(&self, method: &str, params: Value) -> Result<Value, ClientError> {
+        self.request(method, params, None).await
+    }
+
+    // Subscribe with `method`, ended later with `unsubscribe`
+    pub async fn subscribe<T: DeserializeOwned>(
+        &self,
+        method: &str,
+        params: Value,
+        unsubscribe: &'static str,
+    ) -> Result<Subscription<T>, ClientError> {
+        let (events, events_rx) = mpsc::unbounded_channel();
+        let id = self.request(method, params, Some(events)).await?;
+        let id = id
+            .as_str()
+            .ok_or_else(|| ClientError::InvalidResponse(format!("subscription id {}", id)))?;
+
+        Ok(Subscription {
+            id: id.to_string(),
+            unsubscribe,
+            events: events_rx,
+            kind: PhantomData,
+        })
+    }
+
+    pub async fn new_heads(&self) -> Result<Subscription<Value>, ClientError> {
+        self.subscribe("eth_subscribe", json!(["newHeads"]), "eth_unsubscribe")
+            .await
+    }
+
+    // Logs matching `filter`, only sent once their block has `confirmations`,
+    // which is either a number of blocks or `"finalized"`
+    pub async fn confirmed_logs(
+        &self,
+        mut filter: Value,
+        confirmations: Value,
+    ) -> Result<Subscription<Value>, ClientError> {
+        filter["confirmations"] = confirmations;
+        self.subscribe(
+            "eth_subscribe",
+            json!([CONFIRMED_LOGS, filter]),
+            "eth_unsubscribe",
+        )
+        .await
+    }
+
+    pub async fn reorgs(&self) -> Result<Subscription<ReorgEvent>, ClientError> {
+        self.subscribe(
+            "blutgang_subscribe",
+            json!([REORGS]),
+            "blutgang_unsubscribe",
+        )
+        .await
+    }
+
+    pub async fn tx_status(
+        &self,
+        tx_hash: &str,
+    ) -> Result<Subscription<TxStatusEvent>, ClientError> {
+        self.subscribe(
+            "blutgang_subscribe",
+            json!([TX_STATUS, tx_hash]),
+            "blutgang_unsubscribe",
+        )
+        .await
+    }
+
+    pub async fn unsubscribe<T>(&self, subscription: Subscription<T>) -> Result<bool, ClientError> {
+        self.routes
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .subscriptions
+            .remove(&subscription.id);
+        let result = self
+            .call(subscription.unsubscribe, json!([subscription.id]))
+            .await?;
+        Ok(result.as_bool().unwrap_or_default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::node::MockNode;
+
+    #[test]
+    fn test_event_types() {
+        let reorg: ReorgEvent = serde_json::from_value(json!({
+            "oldHead": {"number": "0x2", "hash": "0xaa"},
+            "newHead": {"number": "0x2", "hash": "0xbb"},
+            "depth": 1,
+        }))
+        .unwrap();
+        assert_eq!(reorg.depth, 1);
+        assert_eq!(reorg.new_head.hash, "0xbb");
+
+        let status: TxStatusEvent = serde_json::from_value(json!({
+            "transactionHash": "0x01",
+            "status": "pending",
+        }))
+        .unwrap();
+        assert_eq!(status.status, "pending");
+        assert_eq!(status.block_number, None);
+    }
+
+    #[tokio::test]
+    async fn test_subscription_client() {
+        let node = MockNode::spawn(1).await.unwrap();
+        let client = SubscriptionClient::connect(&node.ws_url()).await.unwrap();
+
+        assert_eq!(client.call("eth_chainId", json!([])).await.unwrap(), "0x1");
+
+        let mut heads = client.new_heads().await.unwrap();
+        node.advance();
+        let head = heads.next().await.unwrap().unwrap();
+        assert_eq!(head["number"], "0x2");
+
+        assert!(client.unsubscribe(heads).await.unwrap());
+    }
+}
@@ -0,0 +1,139 @@
+// Send requests our own nodes can't answer to another blutgang instance.
+//
+// With `[blutgang.fallback]` set, requests that found no working node, or ran
+// out of retries, go to the instance at `url` before we give up on them. This
+// lets edge instances with a few local nodes lean on a central one with more.
+//
+// Every instance that forwards a request this way adds one to `HOPS_HEADER`,
+// and requests that already went through `max_hops` instances aren't sent on,
+// so instances that fall back to each other can't pass a request around
+// forever. Requests for tenants never fall back, the other instance would
+// serve them outside of the tenant's nodes and cache namespace.
+use crate::{
+    config::types::FallbackSettings,
+    log_wrn,
+};
+
+use reqwest::Client;
+use serde_json::Value;
+
+pub const HOPS_HEADER: &str = "x-blutgang-hops";
+
+// Instances a request went through before us, 0 if it came from a client
+pub fn request_hops(header: Option<&str>) -> u32 {
+    header
+        .and_then(|hops| hops.trim().parse().ok())
+        .unwrap_or(0)
+}
+
+#[derive(Debug, Default)]
+pub struct Fallback {
+    settings: Option<FallbackSettings>,
+    client: Client,
+}
+
+impl Fallback {
+    pub fn new(settings: Option<FallbackSettings>) -> Self {
+        Fallback {
+            settings,
+            client: Client::new(),
+        }
+    }
+
+    // Whether a request that went through `hops` instances can still be sent on
+    pub fn allows(&self, hops: u32) -> bool {
+        self.settings
+            .as_ref()
+            .is_some_and(|settings| hops < settings.max_hops)
+    }
+
+    // Where we send requests to, to tell its responses from our nodes'
+    pub fn url(&self) -> Option<&str> {
+        self.settings.as_ref().map(|settings| settings.url.as_str())
+    }
+
+    // Response to `tx` from the fallback instance. `None` if it isn't
+    // configured, can't be reached in time or couldn't answer either.
+    pub async fn fetch(&self, tx: &Value, hops: u32) -> Option<String> {
+        let settings = self.settings.as_ref().filter(|_| self.allows(hops))?;
+
+        let mut request = self
+            .client
+            .post(&settings.url)
+            .header("content-type", "application/json")
+            .header(HOPS_HEADER, (hops + 1).to_string())
+            .timeout(settings.timeout)
+            .body(tx.to_string());
+        if let Some(key) = &settings.key {
+            request = request.bearer_auth(key);
+        }
+
+        // Instances answer with an error status when they can't answer themselves
+        let response = match request.send().await {
+            Ok(response) if response.status().is_success() => response,
+            Ok(response) => {
+                log_wrn!("Fallback {} returned {}", settings.url, response.status());
+                return None;
+            }
+            Err(e) => {
+                log_wrn!("Could not reach fallback {}: {}", settings.url, e);
+                return None;
+            }
+        };
+
+        let rax = response.text().await.ok()?;
+        let result: Value = serde_json::from_str(&rax).ok()?;
+        (result.get("result").is_some() || result.get("error").is_some()).then_some(rax)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::node::MockNode;
+    use serde_json::json;
+    use std::time::Duration;
+
+    fn settings(url: &str) -> FallbackSettings {
+        FallbackSettings {
+            url: url.to_string(),
+            key: Some("hunter2".to_string()),
+            max_hops: 2,
+            timeout: Duration::from_millis(500),
+        }
+    }
+
+    #[test]
+    fn test_fallback_hops() {
+        assert_eq!(request_hops(None), 0);
+        assert_eq!(request_hops(Some(" 1 ")), 1);
+        assert_eq!(request_hops(Some("many")), 0);
+
+        assert!(!Fallback::default().allows(0));
+        let fallback = Fallback::new(Some(settings("http://127.0.0.1:1")));
+        assert!(fallback.allows(0));
+        assert!(fallback.allows(1));
+        assert!(!fallback.allows(2));
+    }
+
+    #[tokio::test]
+    async fn test_fallback_fetch() {
+        let tx = json!({"jsonrpc": "2.0", "id": 1, "method": "eth_chainId", "params": []});
+
+        let node = MockNode::spawn(1).await.unwrap();
+        let fallback = Fallback::new(Some(settings(&node.http_url())));
+        let rax = fallback.fetch(&tx, 0).await.unwrap();
+        assert_eq!(
+            serde_json::from_str::<Value>(&rax).unwrap()["result"],
+            "0x1"
+        );
+
+        // Requests that went through enough instances stay here
+        assert_eq!(fallback.fetch(&tx, 2).await, None);
+        assert_eq!(node.request_count(), 1);
+
+        // Nothing listens on the fallback
+        let unreachable = Fallback::new(Some(settings("http://127.0.0.1:1")));
+        assert_eq!(unreachable.fetch(&tx, 0).await, None);
+    }
+}
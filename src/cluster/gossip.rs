@@ -0,0 +1,349 @@
+// Share node health between the instances of a fleet.
+//
+// With `[blutgang.cluster.gossip]` set, every instance tells the others which
+// nodes its health check took out of rotation, over UDP every `interval`.
+// Instances that hear a node is down put it on hold for a few intervals, so
+// requests go to other nodes right away instead of each instance burning
+// its own timeouts finding out. Nodes on hold still get requests if nothing
+// else can take them, and the hold runs out once nobody reports the node.
+// Reports are sent even when every node is up, as heartbeats for electing
+// a leader, see `cluster::leader`.
+//
+// Datagram layout:
+//
+// tag (32 bytes) | report JSON
+//
+// The tag is a keyed blake3 hash of the report under a key derived from the
+// cluster secret, which gossip requires, and reports with a wrong tag are
+// dropped. Reports carry when they were sent and a sequence number that only
+// goes up, even across restarts, so ones that are old or that we've seen
+// before are dropped too and recorded reports can't be replayed.
+use crate::{
+    cluster::leader::Leadership,
+    config::types::{
+        ClusterSettings,
+        GossipSettings,
+    },
+    log_err,
+    log_info,
+    log_wrn,
+    Rpc,
+};
+
+use std::{
+    collections::HashMap,
+    sync::{
+        Arc,
+        RwLock,
+    },
+    time::{
+        Duration,
+        SystemTime,
+        UNIX_EPOCH,
+    },
+};
+
+use serde::{
+    Deserialize,
+    Serialize,
+};
+use tokio::net::UdpSocket;
+use url::Url;
+
+const KEY_CONTEXT: &str = "blutgang cluster gossip v1";
+const TAG_LEN: usize = 32;
+// Reports are a list of node names, they don't get near this
+const MAX_DATAGRAM: usize = 8192;
+// Intervals a report keeps a node on hold for, so a lost datagram or two
+// don't put it back in rotation
+const HOLD_INTERVALS: u32 = 3;
+// Reports sent longer ago than this are dropped, clocks have to be this close
+const MAX_AGE: Duration = Duration::from_secs(30);
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct Report {
+    // Instance that sent it
+    from: String,
+    // Goes up with every report the instance sends
+    seq: u64,
+    // When it was sent, in ms since the epoch
+    sent: u64,
+    // Nodes it took out of rotation
+    down: Vec<String>,
+}
+
+fn since_epoch() -> Duration {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+}
+
+// Last report we got from each instance
+#[derive(Debug, Default)]
+struct Freshness {
+    last: HashMap<String, u64>,
+}
+
+impl Freshness {
+    // Whether `report` is recent as of `now` and newer than the last one from
+    // its sender
+    fn check(&mut self, report: &Report, now: Duration) -> bool {
+        let now = now.as_millis() as u64;
+        if now.abs_diff(report.sent) > MAX_AGE.as_millis() as u64 {
+            return false;
+        }
+        match self.last.get(&report.from) {
+            Some(last) if *last >= report.seq => false,
+            _ => {
+                self.last.insert(report.from.clone(), report.seq);
+                true
+            }
+        }
+    }
+}
+
+fn gossip_key(secret: &str) -> [u8; 32] {
+    blake3::derive_key(KEY_CONTEXT, secret.as_bytes())
+}
+
+fn seal(key: &[u8; 32], body: &[u8]) -> Vec<u8> {
+    let mut datagram = Vec::with_capacity(TAG_LEN + body.len());
+    datagram.extend_from_slice(blake3::keyed_hash(key, body).as_bytes());
+    datagram.extend_from_slice(body);
+    datagram
+}
+
+// The report in `datagram`, if it was sealed with `key`
+fn open<'a>(key: &[u8; 32], datagram: &'a [u8]) -> Option<&'a [u8]> {
+    if datagram.len() < TAG_LEN {
+        return None;
+    }
+    let (tag, body) = datagram.split_at(TAG_LEN);
+    let tag: [u8; TAG_LEN] = tag.try_into().ok()?;
+    // `Hash` compares in constant time
+    (blake3::Hash::from(tag) == blake3::keyed_hash(key, body)).then_some(body)
+}
+
+// Where the other instances gossip, the hosts of their `peers` on our port
+fn gossip_addresses(settings: &ClusterSettings, gossip: &GossipSettings) -> Vec<String> {
+    settings
+        .peers
+        .iter()
+        .filter_map(|peer| {
+            match Url::parse(peer)
+                .ok()
+                .and_then(|url| url.host_str().map(str::to_string))
+            {
+                Some(host) => Some(format!("{}:{}", host, gossip.port)),
+                None => {
+                    log_wrn!("No host in peer {}, not gossiping with it", peer);
+                    None
+                }
+            }
+        })
+        .collect()
+}
+
+// Put the nodes in `report` on hold for `hold`
+fn apply_report(report: &Report, rpc_list: &Arc<RwLock<Vec<Rpc>>>, hold: Duration) {
+    let rpc_list = rpc_list.read().unwrap_or_else(|e| e.into_inner());
+    for rpc in rpc_list
+        .iter()
+        .filter(|rpc| report.down.contains(&rpc.name))
+    {
+        if rpc.status.cooldown.hold(hold) {
+            log_info!(
+                "{} reported {} down, deprioritizing it",
+                report.from,
+                rpc.name
+            );
+        }
+    }
+}
+
+// Send our view of node health to the other instances and act on theirs
+pub async fn gossip_health(
+    settings: ClusterSettings,
+    rpc_list: Arc<RwLock<Vec<Rpc>>>,
+    poverty_list: Arc<RwLock<Vec<Rpc>>>,
+    leadership: Arc<Leadership>,
+) {
+    let gossip = match &settings.gossip {
+        Some(gossip) => gossip.clone(),
+        None => return,
+    };
+    let socket = match UdpSocket::bind(("0.0.0.0", gossip.port)).await {
+        Ok(socket) => Arc::new(socket),
+        Err(e) => {
+            log_err!("Could not listen for gossip on port {}: {}", gossip.port, e);
+            return;
+        }
+    };
+    let key = match &settings.secret {
+        Some(secret) => gossip_key(secret),
+        None => {
+            log_err!("Cluster gossip needs a secret, not gossiping!");
+            return;
+        }
+    };
+    let peers = gossip_addresses(&settings, &gossip);
+
+    let sender = Arc::clone(&socket);
+    let from = settings.address.clone();
+    tokio::task::spawn(async move {
+        let mut interval = tokio::time::interval(gossip.interval);
+        // Starting from the time means we never go back to a number we've
+        // used before a restart
+        let mut seq = since_epoch().as_micros() as u64;
+        loop {
+            interval.tick().await;
+
+            let down: Vec<String> = poverty_list
+                .read()
+                .unwrap_or_else(|e| e.into_inner())
+                .iter()
+                .map(|rpc| rpc.name.clone())
+                .collect();
+            seq += 1;
+            let report = Report {
+                from: from.clone(),
+                seq,
+                sent: since_epoch().as_millis() as u64,
+                down,
+            };
+            let datagram = seal(&key, &serde_json::to_vec(&report).unwrap());
+            for peer in &peers {
+                if let Err(e) = sender.send_to(&datagram, peer).await {
+                    log_wrn!("Could not gossip with {}: {}", peer, e);
+                }
+            }
+        }
+    });
+
+    let hold = gossip.interval * HOLD_INTERVALS;
+    let mut freshness = Freshness::default();
+    let mut buf = vec![0u8; MAX_DATAGRAM];
+    loop {
+        let (len, source) = match socket.recv_from(&mut buf).await {
+            Ok(received) => received,
+            Err(e) => {
+                log_wrn!("Could not receive gossip: {}", e);
+                continue;
+            }
+        };
+
+        let report = match open(&key, &buf[..len]).map(serde_json::from_slice::<Report>) {
+            Some(Ok(report)) => report,
+            Some(Err(e)) => {
+                log_wrn!("Unparsable gossip from {}: {}", source, e);
+                continue;
+            }
+            None => {
+                log_wrn!("Dropped gossip from {} with a bad tag", source);
+                continue;
+            }
+        };
+        if !settings.peers.contains(&report.from) {
+            log_wrn!(
+                "Dropped gossip from {} claiming to be {}",
+                source,
+                report.from
+            );
+            continue;
+        }
+        if !freshness.check(&report, since_epoch()) {
+            log_wrn!("Dropped stale or replayed gossip from {}", source);
+            continue;
+        }
+        leadership.heartbeat(&report.from);
+        apply_report(&report, &rpc_list, hold);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_seal_open() {
+        let key = gossip_key("hunter2");
+        let datagram = seal(&key, b"{}");
+        assert_eq!(open(&key, &datagram), Some(&b"{}"[..]));
+
+        assert_eq!(open(&gossip_key("hunter3"), &datagram), None);
+        assert_eq!(open(&key, &datagram[..TAG_LEN - 1]), None);
+        let mut tampered = datagram.clone();
+        tampered[TAG_LEN] = b'[';
+        assert_eq!(open(&key, &tampered), None);
+    }
+
+    #[test]
+    fn test_freshness() {
+        let report = |from: &str, seq: u64, sent: u64| {
+            Report {
+                from: from.to_string(),
+                seq,
+                sent,
+                down: Vec::new(),
+            }
+        };
+        let now = Duration::from_secs(1000);
+        let mut freshness = Freshness::default();
+
+        assert!(freshness.check(&report("a", 5, 1_000_000), now));
+        // Replayed, or older than the last one
+        assert!(!freshness.check(&report("a", 5, 1_000_000), now));
+        assert!(!freshness.check(&report("a", 4, 1_000_000), now));
+        assert!(freshness.check(&report("a", 6, 1_000_500), now));
+        // Everyone counts on their own
+        assert!(freshness.check(&report("b", 1, 999_000), now));
+        // Sent too long ago, or too far ahead
+        assert!(!freshness.check(&report("a", 7, 900_000), now));
+        assert!(!freshness.check(&report("a", 8, 1_100_000), now));
+    }
+
+    #[test]
+    fn test_gossip_addresses() {
+        let settings = ClusterSettings {
+            address: "http://10.0.0.1:3000".to_string(),
+            peers: vec![
+                "http://10.0.0.2:3000".to_string(),
+                "https://blutgang-3.internal".to_string(),
+                "not a url".to_string(),
+            ],
+            secret: None,
+            timeout: Duration::from_secs(2),
+            gossip: None,
+        };
+        let gossip = GossipSettings {
+            port: 3100,
+            interval: Duration::from_secs(1),
+        };
+
+        assert_eq!(
+            gossip_addresses(&settings, &gossip),
+            vec!["10.0.0.2:3100", "blutgang-3.internal:3100"]
+        );
+    }
+
+    #[test]
+    fn test_apply_report() {
+        let up = Rpc::new("https://up.example.com".to_string(), None, 6, 0, 10.0);
+        let down = Rpc::new("https://down.example.com".to_string(), None, 6, 0, 10.0);
+        let rpc_list = Arc::new(RwLock::new(vec![up, down]));
+
+        let report: Report = serde_json::from_value(json!({
+            "from": "http://10.0.0.2:3000",
+            "seq": 1,
+            "sent": 0,
+            "down": ["https://down.example.com/", "https://unknown.example.com/"],
+        }))
+        .unwrap();
+        apply_report(&report, &rpc_list, Duration::from_secs(3));
+
+        let rpc_list = rpc_list.read().unwrap();
+        assert!(!rpc_list[0].status.cooldown.is_cooling_down());
+        assert!(rpc_list[1].status.cooldown.is_cooling_down());
+    }
+}
@@ -0,0 +1,181 @@
+// Pick one instance of a fleet to run the jobs that only need to run once.
+//
+// Every instance serves traffic, but pre-warming the cache and exporting
+// usage reports only need one of them. Instances hear from each other
+// through the gossip channel, which doubles as a heartbeat, and the leader
+// is the instance with the lowest `address` among us and the peers we heard
+// from within `MISSED_HEARTBEATS` intervals. Only heartbeats from addresses
+// in our `peers` count, so a signed report can't make up an instance that
+// would win the election without running anything. Everyone applies the same rule
+// to the same heartbeats, so they agree on the leader without a round of
+// voting, and a leader that goes away is replaced once its heartbeats stop.
+// A network partition briefly gives each side its own leader, which is fine
+// for jobs that are merely wasteful to run twice.
+//
+// Without `[blutgang.cluster.gossip]` we can't hear from anyone, so every
+// instance leads itself and runs everything like it would on its own.
+use crate::{
+    config::types::ClusterSettings,
+    log_info,
+};
+
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{
+            AtomicBool,
+            Ordering,
+        },
+        Mutex,
+    },
+    time::{
+        Duration,
+        Instant,
+    },
+};
+
+// Heartbeats a peer can miss before we stop counting it
+const MISSED_HEARTBEATS: u32 = 3;
+
+#[derive(Debug)]
+pub struct Leadership {
+    // Our address, if there's a fleet to lead
+    address: Option<String>,
+    // Addresses of the instances that can lead instead
+    peers: Vec<String>,
+    // How long a heartbeat keeps a peer in the running
+    timeout: Duration,
+    started: Instant,
+    heartbeats: Mutex<HashMap<String, Instant>>,
+    // To log when we take over or step down
+    leading: AtomicBool,
+}
+
+impl Default for Leadership {
+    fn default() -> Self {
+        Self::new(None)
+    }
+}
+
+impl Leadership {
+    pub fn new(settings: Option<&ClusterSettings>) -> Self {
+        let (address, peers, timeout) = match settings {
+            Some(ClusterSettings {
+                address,
+                peers,
+                gossip: Some(gossip),
+                ..
+            }) => {
+                (
+                    Some(address.clone()),
+                    peers.clone(),
+                    gossip.interval * MISSED_HEARTBEATS,
+                )
+            }
+            _ => (None, Vec::new(), Duration::ZERO),
+        };
+
+        Leadership {
+            address,
+            peers,
+            timeout,
+            started: Instant::now(),
+            heartbeats: Mutex::new(HashMap::new()),
+            leading: AtomicBool::new(true),
+        }
+    }
+
+    // `address` is alive
+    pub fn heartbeat(&self, address: &str) {
+        if !self.peers.iter().any(|peer| peer == address) {
+            return;
+        }
+        self.heartbeats
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(address.to_string(), Instant::now());
+    }
+
+    // Instance leading the fleet right now, `None` without one
+    pub fn leader(&self) -> Option<String> {
+        let address = self.address.as_ref()?;
+        let heartbeats = self.heartbeats.lock().unwrap_or_else(|e| e.into_inner());
+        heartbeats
+            .iter()
+            .filter(|(_, last)| last.elapsed() <= self.timeout)
+            .map(|(peer, _)| peer)
+            .chain(std::iter::once(address))
+            .min()
+            .cloned()
+    }
+
+    pub fn is_leader(&self) -> bool {
+        let leader = self.leader();
+        let leading = leader.is_none() || leader == self.address;
+
+        if self.leading.swap(leading, Ordering::Relaxed) != leading {
+            if leading {
+                log_info!("Leading the cluster");
+            } else if let Some(leader) = &leader {
+                log_info!("{} leads the cluster, stepping down", leader);
+            }
+        }
+        leading
+    }
+
+    // Give peers that are up a chance to be heard before we decide who leads
+    pub async fn settle(&self) {
+        if let Some(left) = self.timeout.checked_sub(self.started.elapsed()) {
+            tokio::time::sleep(left).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::types::GossipSettings;
+
+    fn settings(address: &str) -> ClusterSettings {
+        ClusterSettings {
+            address: address.to_string(),
+            peers: vec!["http://a".to_string(), "http://c".to_string()],
+            secret: None,
+            timeout: Duration::from_secs(2),
+            gossip: Some(GossipSettings {
+                port: 3100,
+                interval: Duration::from_millis(10),
+            }),
+        }
+    }
+
+    #[test]
+    fn test_leadership_standalone() {
+        let leadership = Leadership::default();
+        leadership.heartbeat("http://a");
+        assert_eq!(leadership.leader(), None);
+        assert!(leadership.is_leader());
+    }
+
+    #[test]
+    fn test_leadership() {
+        let b = Leadership::new(Some(&settings("http://b")));
+        assert!(b.is_leader());
+
+        b.heartbeat("http://c");
+        assert!(b.is_leader());
+
+        // Only instances we know of can lead
+        b.heartbeat("http://0");
+        assert!(b.is_leader());
+
+        b.heartbeat("http://a");
+        assert_eq!(b.leader().as_deref(), Some("http://a"));
+        assert!(!b.is_leader());
+
+        // `a` went quiet
+        std::thread::sleep(Duration::from_millis(40));
+        b.heartbeat("http://c");
+        assert!(b.is_leader());
+    }
+}
@@ -0,0 +1,5 @@
+pub mod fallback;
+pub mod gossip;
+pub mod leader;
+pub mod peers;
+pub mod ring;
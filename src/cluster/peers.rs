@@ -0,0 +1,167 @@
+// Share cached responses between the instances of a fleet.
+//
+// With `[blutgang.cluster]` set, every cache key belongs to one instance on a
+// consistent hash ring made of us and our `peers`. When we miss the cache on
+// a key another instance owns, we send the request to that instance before
+// going upstream. It answers from its cache, or fetches and caches the
+// response itself, so every response is cached once in the whole fleet and
+// adding instances adds cache capacity instead of copies.
+//
+// Requests between instances carry `PEER_HEADER`, set to the cluster secret
+// if there is one, and are never forwarded again. If the owner can't be
+// reached in time or doesn't have a result for us, we go upstream like we
+// would without a cluster. Requests for tenants never go to peers, since
+// they'd be answered from another tenant's cache namespace.
+use crate::{
+    balancer::cache_entry::CacheKey,
+    cluster::ring::HashRing,
+    config::types::ClusterSettings,
+    log_wrn,
+};
+
+use reqwest::Client;
+use serde_json::Value;
+
+pub const PEER_HEADER: &str = "x-blutgang-peer";
+
+#[derive(Debug, Default)]
+pub struct PeerCache {
+    settings: Option<ClusterSettings>,
+    ring: HashRing,
+    client: Client,
+}
+
+impl PeerCache {
+    pub fn new(settings: Option<ClusterSettings>) -> Self {
+        let ring = match &settings {
+            Some(settings) => {
+                HashRing::new(
+                    settings
+                        .peers
+                        .iter()
+                        .cloned()
+                        .chain(std::iter::once(settings.address.clone())),
+                )
+            }
+            None => HashRing::default(),
+        };
+
+        PeerCache {
+            settings,
+            ring,
+            client: Client::new(),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.settings.is_some()
+    }
+
+    fn header_value(&self) -> &str {
+        self.settings
+            .as_ref()
+            .and_then(|settings| settings.secret.as_deref())
+            .unwrap_or("true")
+    }
+
+    // Whether a request with `PEER_HEADER` set to `header` came from another
+    // instance, and should be answered by us instead of forwarded
+    pub fn is_peer(&self, header: Option<&str>) -> bool {
+        self.is_enabled() && header == Some(self.header_value())
+    }
+
+    // Instance that owns `key`, if it isn't us
+    pub fn owner(&self, key: &CacheKey) -> Option<&str> {
+        let settings = self.settings.as_ref()?;
+        self.ring
+            .owner(key.as_bytes())
+            .filter(|owner| *owner != settings.address)
+    }
+
+    // Response to `tx` from the instance that owns `key`. `None` if we own it
+    // or have to go upstream ourselves.
+    pub async fn fetch(&self, tx: &Value, key: &CacheKey) -> Option<String> {
+        let owner = self.owner(key)?;
+        let timeout = self.settings.as_ref()?.timeout;
+
+        let response = match self
+            .client
+            .post(owner)
+            .header("content-type", "application/json")
+            .header(PEER_HEADER, self.header_value())
+            .timeout(timeout)
+            .body(tx.to_string())
+            .send()
+            .await
+        {
+            Ok(response) if response.status().is_success() => response,
+            Ok(response) => {
+                log_wrn!("Peer {} returned {}", owner, response.status());
+                return None;
+            }
+            Err(e) => {
+                log_wrn!("Could not reach peer {}: {}", owner, e);
+                return None;
+            }
+        };
+
+        let rax = response.text().await.ok()?;
+        // Errors are retried upstream, our nodes might do better
+        let result: Value = serde_json::from_str(&rax).ok()?;
+        result.get("result").is_some().then_some(rax)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::time::Duration;
+
+    fn settings(address: &str, peers: &[&str]) -> ClusterSettings {
+        ClusterSettings {
+            address: address.to_string(),
+            peers: peers.iter().map(|peer| peer.to_string()).collect(),
+            secret: Some("hunter2".to_string()),
+            timeout: Duration::from_millis(200),
+            gossip: None,
+        }
+    }
+
+    #[test]
+    fn test_peer_cache_owner() {
+        let disabled = PeerCache::default();
+        let key = CacheKey::new(&json!({"method": "eth_chainId"}));
+        assert_eq!(disabled.owner(&key), None);
+        assert!(!disabled.is_peer(Some("true")));
+
+        let a = PeerCache::new(Some(settings("http://a", &["http://b"])));
+        let b = PeerCache::new(Some(settings("http://b", &["http://a"])));
+        assert!(a.is_peer(Some("hunter2")));
+        assert!(!a.is_peer(Some("true")));
+        assert!(!a.is_peer(None));
+
+        // Exactly one of the two owns every key, and they agree on which
+        for i in 0..100 {
+            let key = CacheKey::new(&json!({"method": "eth_getBlockByNumber", "params": [i]}));
+            match (a.owner(&key), b.owner(&key)) {
+                (Some("http://b"), None) | (None, Some("http://a")) => {}
+                owners => panic!("peers disagree on {}: {:?}", key, owners),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_peer_cache_unreachable() {
+        // Nothing listens on the peer, so we go upstream
+        let cache = PeerCache::new(Some(settings(
+            "http://127.0.0.1:1",
+            &["http://127.0.0.1:2"],
+        )));
+        let tx = (0..)
+            .map(|id| json!({"jsonrpc": "2.0", "id": id, "method": "eth_chainId"}))
+            .find(|tx| cache.owner(&CacheKey::new(tx)).is_some())
+            .unwrap();
+        assert_eq!(cache.fetch(&tx, &CacheKey::new(&tx)).await, None);
+    }
+}
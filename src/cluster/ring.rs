@@ -0,0 +1,112 @@
+// Consistent hashing of cache keys to the instances of a fleet.
+//
+// Every instance gets `VIRTUAL_NODES` points on a ring of u64s, and a key
+// belongs to the first instance at or after it, wrapping around. Each
+// instance ends up owning many small slices of the ring, so keys spread
+// evenly, and an instance joining or leaving only moves the keys in its own
+// slices. Instances listed in a different order build the same ring.
+
+// Points per instance, more of them spread keys more evenly
+const VIRTUAL_NODES: usize = 128;
+
+#[derive(Debug, Clone, Default)]
+pub struct HashRing {
+    members: Vec<String>,
+    // Sorted points and the index of the member they belong to
+    points: Vec<(u64, usize)>,
+}
+
+fn point(bytes: &[u8]) -> u64 {
+    u64::from_be_bytes(blake3::hash(bytes).as_bytes()[..8].try_into().unwrap())
+}
+
+impl HashRing {
+    pub fn new(members: impl IntoIterator<Item = String>) -> Self {
+        let mut members: Vec<String> = members.into_iter().collect();
+        members.sort();
+        members.dedup();
+
+        let mut points: Vec<(u64, usize)> = members
+            .iter()
+            .enumerate()
+            .flat_map(|(index, member)| {
+                (0..VIRTUAL_NODES).map(move |replica| {
+                    (point(format!("{}#{}", member, replica).as_bytes()), index)
+                })
+            })
+            .collect();
+        points.sort_unstable();
+
+        HashRing { members, points }
+    }
+
+    pub fn members(&self) -> &[String] {
+        &self.members
+    }
+
+    // Instance that owns `key`, a cache key digest
+    pub fn owner(&self, key: &[u8]) -> Option<&str> {
+        if self.points.is_empty() {
+            return None;
+        }
+
+        // Digests are already uniform, no need to hash them again
+        let mut prefix = [0u8; 8];
+        let len = key.len().min(8);
+        prefix[..len].copy_from_slice(&key[..len]);
+        let key = u64::from_be_bytes(prefix);
+
+        let position = self.points.partition_point(|(point, _)| *point < key);
+        let (_, member) = self.points[position % self.points.len()];
+        Some(&self.members[member])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn keys() -> Vec<[u8; 32]> {
+        (0..3000u32)
+            .map(|i| *blake3::hash(&i.to_le_bytes()).as_bytes())
+            .collect()
+    }
+
+    fn members(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn test_ring_owner() {
+        assert_eq!(HashRing::default().owner(&[1, 2, 3]), None);
+
+        let ring = HashRing::new(members(&["http://a", "http://b", "http://c"]));
+        let reversed = HashRing::new(members(&["http://c", "http://b", "http://a"]));
+
+        let mut counts: HashMap<&str, usize> = HashMap::new();
+        for key in keys() {
+            let owner = ring.owner(&key).unwrap();
+            assert_eq!(reversed.owner(&key), Some(owner));
+            *counts.entry(owner).or_default() += 1;
+        }
+
+        // Roughly a third each
+        assert_eq!(counts.len(), 3);
+        assert!(counts.values().all(|count| (600..1400).contains(count)));
+    }
+
+    #[test]
+    fn test_ring_membership_change() {
+        let ring = HashRing::new(members(&["http://a", "http://b", "http://c"]));
+        let shrunk = HashRing::new(members(&["http://a", "http://b"]));
+
+        // Only keys of the instance that left move
+        for key in keys() {
+            let owner = ring.owner(&key).unwrap();
+            if owner != "http://c" {
+                assert_eq!(shrunk.owner(&key), Some(owner));
+            }
+        }
+    }
+}
@@ -93,6 +93,21 @@ pub fn create_match() -> clap::Command {
             .num_args(1..)
             .default_value("2000")
             .help("How often to perform the health check"))
+        .arg(Arg::new("jsonrpc_mode")
+            .long("jsonrpc_mode")
+            .num_args(1..)
+            .default_value("off")
+            .help("JSON-RPC spec enforcement for incoming requests. Can be off/lenient/strict"))
+        .arg(Arg::new("record")
+            .long("record")
+            .num_args(1)
+            .conflicts_with("replay")
+            .help("Record all responses to a DB at the specified path"))
+        .arg(Arg::new("replay")
+            .long("replay")
+            .num_args(1)
+            .conflicts_with("record")
+            .help("Serve responses from a recording at the specified path"))
         .arg(Arg::new("admin")
             .long("admin")
             .num_args(0..)
@@ -121,4 +136,41 @@ pub fn create_match() -> clap::Command {
             .num_args(1..)
             .requires("jwt")
             .help("JWT token"))
+        .subcommand(Command::new("bench")
+            .about("Benchmark a running blutgang instance or RPC nodes directly")
+            .arg(Arg::new("target")
+                .long("target")
+                .short('t')
+                .num_args(1)
+                .default_value("http://127.0.0.1:3000")
+                .help("CSV list of URLs to send requests to"))
+            .arg(Arg::new("requests")
+                .long("requests")
+                .short('n')
+                .num_args(1)
+                .default_value("10000")
+                .help("Total amount of requests to send"))
+            .arg(Arg::new("concurrency")
+                .long("concurrency")
+                .num_args(1)
+                .default_value("64")
+                .help("Amount of requests in flight at the same time"))
+            .arg(Arg::new("mix")
+                .long("mix")
+                .num_args(1)
+                .default_value("eth_blockNumber:4,eth_getBlockByNumber:4,eth_chainId:1,eth_getBalance:1")
+                .help("CSV list of `method:weight` pairs to send")))
+        .subcommand(Command::new("selftest")
+            .about("Check what the configured nodes can do and exit with an error if none are usable")
+            .arg(Arg::new("config")
+                .long("config")
+                .short('c')
+                .num_args(1)
+                .default_value("config.toml")
+                .help("TOML config file for blutgang"))
+            .arg(Arg::new("ws_duration")
+                .long("ws_duration")
+                .num_args(1)
+                .default_value("30")
+                .help("Seconds to listen for new heads on each node")))
 }
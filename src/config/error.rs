@@ -7,6 +7,7 @@ use std::error::Error;
 pub enum ConfigError {
     RpcError(String),
     BadConfig,
+    InvalidConfig(String),
 }
 
 impl std::fmt::Display for ConfigError {
@@ -14,6 +15,7 @@ impl std::fmt::Display for ConfigError {
         match self {
             ConfigError::RpcError(e) => write!(f, "Error while calling RPC: {}", e),
             ConfigError::BadConfig => write!(f, "Invalid Config File!"),
+            ConfigError::InvalidConfig(reason) => write!(f, "Invalid config: {}", reason),
         }
     }
 }
@@ -31,3 +33,20 @@ impl<T> From<tokio::sync::mpsc::error::SendError<T>> for ConfigError {
 }
 
 impl Error for ConfigError {}
+
+// Turn a missing or unparsable config value into an `InvalidConfig` error
+pub trait OrInvalid<T> {
+    fn or_invalid(self, reason: &str) -> Result<T, ConfigError>;
+}
+
+impl<T> OrInvalid<T> for Option<T> {
+    fn or_invalid(self, reason: &str) -> Result<T, ConfigError> {
+        self.ok_or_else(|| ConfigError::InvalidConfig(reason.to_string()))
+    }
+}
+
+impl<T, E> OrInvalid<T> for Result<T, E> {
+    fn or_invalid(self, reason: &str) -> Result<T, ConfigError> {
+        self.map_err(|_| ConfigError::InvalidConfig(reason.to_string()))
+    }
+}
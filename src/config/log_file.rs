@@ -0,0 +1,257 @@
+// Log file with built-in rotation.
+//
+// Rotated files are renamed to `<log_file>.<timestamp>`, and optionally zstd
+// compressed to `<log_file>.<timestamp>.zst`. Compression and deleting old
+// files happen on their own thread so logging doesn't stall while they run.
+use crate::config::types::{
+    LogRotation,
+    RotationInterval,
+};
+
+use std::{
+    fs::{
+        self,
+        File,
+        OpenOptions,
+    },
+    io::{
+        self,
+        Write,
+    },
+    path::{
+        Path,
+        PathBuf,
+    },
+    thread,
+};
+
+use chrono::{
+    DateTime,
+    Utc,
+};
+
+const COMPRESSION_LEVEL: i32 = 3;
+
+fn open(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+// Which hour or day `now` falls into, if we rotate on an interval
+fn period(interval: RotationInterval, now: DateTime<Utc>) -> Option<i64> {
+    match interval {
+        RotationInterval::Never => None,
+        RotationInterval::Hourly => Some(now.timestamp() / 3600),
+        RotationInterval::Daily => Some(now.timestamp() / 86400),
+    }
+}
+
+#[derive(Debug)]
+pub struct LogFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    period: Option<i64>,
+    rotation: LogRotation,
+}
+
+impl LogFile {
+    pub fn open(path: &str, rotation: LogRotation) -> io::Result<Self> {
+        let path = PathBuf::from(path);
+        let file = open(&path)?;
+        let size = file.metadata()?.len();
+
+        Ok(LogFile {
+            path,
+            file,
+            size,
+            period: period(rotation.interval, Utc::now()),
+            rotation,
+        })
+    }
+
+    pub fn path(&self) -> String {
+        self.path.to_string_lossy().to_string()
+    }
+
+    // Start writing to a fresh file at the same path, for external rotation
+    pub fn reopen(&mut self) -> io::Result<()> {
+        self.file = open(&self.path)?;
+        self.size = self.file.metadata()?.len();
+        Ok(())
+    }
+
+    pub fn write_line(&mut self, line: &str) -> io::Result<()> {
+        let now = Utc::now();
+        if self.should_rotate(now, line.len() as u64 + 1) {
+            self.rotate(now)?;
+        }
+
+        writeln!(self.file, "{}", line)?;
+        self.size += line.len() as u64 + 1;
+        Ok(())
+    }
+
+    fn should_rotate(&self, now: DateTime<Utc>, incoming: u64) -> bool {
+        // Never leave an empty file behind
+        if self.size == 0 {
+            return false;
+        }
+
+        let too_big = self
+            .rotation
+            .max_size
+            .is_some_and(|max_size| self.size + incoming > max_size);
+        too_big || period(self.rotation.interval, now) != self.period
+    }
+
+    // Move the current file out of the way and start a new one. Returns the
+    // handle of the thread compressing and deleting old files.
+    fn rotate(&mut self, now: DateTime<Utc>) -> io::Result<thread::JoinHandle<()>> {
+        let rotated = PathBuf::from(format!(
+            "{}.{}",
+            self.path.to_string_lossy(),
+            now.format("%Y%m%d-%H%M%S%.3f")
+        ));
+        fs::rename(&self.path, &rotated)?;
+
+        self.file = open(&self.path)?;
+        self.size = 0;
+        self.period = period(self.rotation.interval, now);
+
+        let path = self.path.clone();
+        let rotation = self.rotation.clone();
+        Ok(thread::spawn(move || {
+            if rotation.compress {
+                if let Err(e) = compress(&rotated) {
+                    eprintln!("Could not compress {}: {}", rotated.display(), e);
+                }
+            }
+            if let Err(e) = prune(&path, rotation.keep) {
+                eprintln!("Could not delete old log files: {}", e);
+            }
+        }))
+    }
+}
+
+fn compress(path: &Path) -> io::Result<()> {
+    let compressed = PathBuf::from(format!("{}.zst", path.to_string_lossy()));
+    zstd::stream::copy_encode(
+        File::open(path)?,
+        File::create(&compressed)?,
+        COMPRESSION_LEVEL,
+    )?;
+    fs::remove_file(path)
+}
+
+// Delete all but the newest `keep` rotated files
+fn prune(path: &Path, keep: usize) -> io::Result<()> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let prefix = match path.file_name() {
+        Some(name) => format!("{}.", name.to_string_lossy()),
+        None => return Ok(()),
+    };
+
+    // Timestamps sort chronologically
+    let mut rotated: Vec<PathBuf> = fs::read_dir(dir)?
+        .filter_map(Result::ok)
+        .filter(|entry| entry.file_name().to_string_lossy().starts_with(&prefix))
+        .map(|entry| entry.path())
+        .collect();
+    rotated.sort();
+
+    let excess = rotated.len().saturating_sub(keep);
+    for old in &rotated[..excess] {
+        fs::remove_file(old)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use chrono::Duration;
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("blutgang-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn rotated(dir: &Path) -> Vec<String> {
+        let mut names: Vec<String> = fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+            .filter(|name| name != "blutgang.log")
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn test_rotate_on_size() {
+        let dir = test_dir("log-size");
+        let path = dir.join("blutgang.log");
+        let rotation = LogRotation {
+            max_size: Some(20),
+            keep: 2,
+            ..Default::default()
+        };
+        let mut log_file = LogFile::open(&path.to_string_lossy(), rotation).unwrap();
+
+        log_file.write_line("0123456789").unwrap();
+        assert!(rotated(&dir).is_empty());
+        // Doesn't fit anymore
+        log_file.write_line("0123456789").unwrap();
+        assert_eq!(rotated(&dir).len(), 1);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "0123456789\n");
+
+        // Only the newest 2 are kept
+        let now = Utc::now();
+        for i in 1..4 {
+            log_file
+                .rotate(now + Duration::seconds(i))
+                .unwrap()
+                .join()
+                .unwrap();
+        }
+        assert_eq!(rotated(&dir).len(), 2);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_rotate_on_interval() {
+        let dir = test_dir("log-interval");
+        let path = dir.join("blutgang.log");
+        let rotation = LogRotation {
+            interval: RotationInterval::Daily,
+            compress: true,
+            ..Default::default()
+        };
+        let mut log_file = LogFile::open(&path.to_string_lossy(), rotation).unwrap();
+        log_file.write_line("yesterday").unwrap();
+
+        let tomorrow = Utc::now() + Duration::days(1);
+        assert!(log_file.should_rotate(tomorrow, 1));
+        assert!(!log_file.should_rotate(Utc::now(), 1));
+
+        log_file.rotate(tomorrow).unwrap().join().unwrap();
+        let rotated = rotated(&dir);
+        assert_eq!(rotated.len(), 1);
+        assert!(rotated[0].ends_with(".zst"));
+
+        let compressed = fs::read(dir.join(&rotated[0])).unwrap();
+        assert_eq!(
+            zstd::decode_all(compressed.as_slice()).unwrap(),
+            b"yesterday\n"
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
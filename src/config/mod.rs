@@ -1,6 +1,8 @@
 pub mod cache_setup;
 pub mod cli_args;
 pub mod error;
+pub mod log_file;
 pub mod setup;
 pub mod system;
 pub mod types;
+pub mod validate;
@@ -8,7 +8,7 @@ use tokio::sync::mpsc;
 
 #[derive(Debug)]
 enum StartingLatencyResp {
-    Ok(Rpc),
+    Ok(Box<Rpc>),
     Error(ConfigError),
 }
 
@@ -41,7 +41,7 @@ async fn set_starting_latency(
 
     println!("{}: {}ns", rpc.name, rpc.status.latency);
 
-    tx.send(StartingLatencyResp::Ok(rpc)).await?;
+    tx.send(StartingLatencyResp::Ok(Box::new(rpc))).await?;
 
     Ok(())
 }
@@ -74,7 +74,7 @@ pub async fn sort_by_latency(
     // Collect results from tasks
     while let Some(rpc) = rx.recv().await {
         let rpc = match rpc {
-            StartingLatencyResp::Ok(rax) => rax,
+            StartingLatencyResp::Ok(rax) => *rax,
             StartingLatencyResp::Error(e) => {
                 log_err!("{}", e);
                 continue;
@@ -1,3 +1,21 @@
+use crate::config::{
+    log_file::LogFile,
+    types::LogRotation,
+};
+
+use std::{
+    io,
+    str::FromStr,
+    sync::{
+        atomic::{
+            AtomicBool,
+            AtomicU8,
+            Ordering,
+        },
+        Mutex,
+    },
+};
+
 // System consts
 pub const WS_HEALTH_CHECK_USER_ID: u32 = 1;
 pub const WS_SUB_MANAGER_ID: u32 = 2;
@@ -13,65 +31,233 @@ pub fn log_journald(level: u32, message: &str) {
     journal::print(level, message);
 }
 
+// How verbose our logs are. Changeable at runtime through the admin namespace.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LogLevel {
+    Error = 0,
+    Warn = 1,
+    Info = 2,
+}
+
+impl FromStr for LogLevel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "error" => Ok(LogLevel::Error),
+            "warn" => Ok(LogLevel::Warn),
+            "info" => Ok(LogLevel::Info),
+            _ => Err(format!("Unknown log level: {}", s)),
+        }
+    }
+}
+
+// Parts of blutgang with verbose debug logging that can be toggled separately
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DebugModule {
+    // Incoming HTTP requests
+    Http = 1,
+    // Requests we send to nodes
+    Rpc = 2,
+    // WS frames, both with users and nodes
+    Ws = 4,
+}
+
+pub const DEBUG_MODULES: [DebugModule; 3] = [DebugModule::Http, DebugModule::Rpc, DebugModule::Ws];
+
+impl DebugModule {
+    pub fn name(&self) -> &'static str {
+        match self {
+            DebugModule::Http => "http",
+            DebugModule::Rpc => "rpc",
+            DebugModule::Ws => "ws",
+        }
+    }
+}
+
+impl FromStr for DebugModule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        DEBUG_MODULES
+            .into_iter()
+            .find(|module| module.name() == s.to_lowercase())
+            .ok_or(format!("Unknown debug module: {}", s))
+    }
+}
+
+static LOG_LEVEL: AtomicU8 = AtomicU8::new(LogLevel::Info as u8);
+
+// Bitmask of `DebugModule`s. `debug-verbose` turns all of them on.
+static DEBUG: AtomicU8 = AtomicU8::new(if cfg!(feature = "debug-verbose") {
+    DebugModule::Http as u8 | DebugModule::Rpc as u8 | DebugModule::Ws as u8
+} else {
+    0
+});
+
+pub fn set_log_level(level: LogLevel) {
+    LOG_LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+pub fn log_level() -> LogLevel {
+    match LOG_LEVEL.load(Ordering::Relaxed) {
+        0 => LogLevel::Error,
+        1 => LogLevel::Warn,
+        _ => LogLevel::Info,
+    }
+}
+
+pub fn log_enabled(level: LogLevel) -> bool {
+    level as u8 <= LOG_LEVEL.load(Ordering::Relaxed)
+}
+
+pub fn set_debug(module: DebugModule, enabled: bool) {
+    if enabled {
+        DEBUG.fetch_or(module as u8, Ordering::Relaxed);
+    } else {
+        DEBUG.fetch_and(!(module as u8), Ordering::Relaxed);
+    }
+}
+
+pub fn debug_enabled(module: DebugModule) -> bool {
+    DEBUG.load(Ordering::Relaxed) & module as u8 != 0
+}
+
+// File we also write logs to, if set. Reopened on SIGUSR2 so tools like
+// logrotate can move it out of the way, or rotated by us.
+static LOG_FILE: Mutex<Option<LogFile>> = Mutex::new(None);
+static LOG_FILE_ENABLED: AtomicBool = AtomicBool::new(false);
+
+pub fn set_log_file(path: &str, rotation: LogRotation) -> io::Result<()> {
+    let log_file = LogFile::open(path, rotation)?;
+    *LOG_FILE.lock().unwrap_or_else(|e| e.into_inner()) = Some(log_file);
+    LOG_FILE_ENABLED.store(true, Ordering::Relaxed);
+    Ok(())
+}
+
+// Start writing to a fresh file at the same path. Returns the path, if
+// we're logging to a file at all.
+pub fn reopen_log_file() -> io::Result<Option<String>> {
+    let mut log_file = LOG_FILE.lock().unwrap_or_else(|e| e.into_inner());
+    match log_file.as_mut() {
+        Some(log_file) => {
+            log_file.reopen()?;
+            Ok(Some(log_file.path()))
+        }
+        None => Ok(None),
+    }
+}
+
+pub fn write_log_file(label: &str, message: &str) {
+    if !LOG_FILE_ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+
+    if let Some(log_file) = LOG_FILE.lock().unwrap_or_else(|e| e.into_inner()).as_mut() {
+        let line = format!("{} {}: {}", chrono::Utc::now().to_rfc3339(), label, message);
+        if let Err(e) = log_file.write_line(&line) {
+            eprintln!("Could not write to {}: {}", log_file.path(), e);
+        }
+    }
+}
+
 #[macro_export]
 macro_rules! log_info {
     ($fmt:expr, $($arg:tt)*) => {
-        let message = format!($fmt, $($arg)*);
-        #[cfg(feature = "journald")]
-        {
-            use crate::config::system::log_journald;
-            log_journald(6, &message);
+        if $crate::config::system::log_enabled($crate::config::system::LogLevel::Info) {
+            let message = format!($fmt, $($arg)*);
+            #[cfg(feature = "journald")]
+            {
+                use $crate::config::system::log_journald;
+                log_journald(6, &message);
+            }
+            $crate::config::system::write_log_file("Info", &message);
+            println!("\x1b[35mInfo:\x1b[0m {}", message)
         }
-        println!("\x1b[35mInfo:\x1b[0m {}", message)
     };
     ($fmt:expr) => {
-        #[cfg(feature = "journald")]
-        {
-            use crate::config::system::log_journald;
-            log_journald(6, $fmt);
+        if $crate::config::system::log_enabled($crate::config::system::LogLevel::Info) {
+            #[cfg(feature = "journald")]
+            {
+                use $crate::config::system::log_journald;
+                log_journald(6, $fmt);
+            }
+            $crate::config::system::write_log_file("Info", $fmt);
+            println!(concat!("\x1b[35mInfo:\x1b[0m ", $fmt))
         }
-        println!(concat!("\x1b[35mInfo:\x1b[0m ", $fmt))
     };
 }
 
 #[macro_export]
 macro_rules! log_wrn {
     ($fmt:expr, $($arg:tt)*) => {
-        let message = format!($fmt, $($arg)*);
-        #[cfg(feature = "journald")]
-        {
-            use crate::config::system::log_journald;
-            log_journald(4, &message);
+        if $crate::config::system::log_enabled($crate::config::system::LogLevel::Warn) {
+            let message = format!($fmt, $($arg)*);
+            #[cfg(feature = "journald")]
+            {
+                use $crate::config::system::log_journald;
+                log_journald(4, &message);
+            }
+            $crate::config::system::write_log_file("Wrn", &message);
+            println!("\x1b[93mWrn:\x1b[0m {}", message)
         }
-        println!("\x1b[93mWrn:\x1b[0m {}", message)
     };
     ($fmt:expr) => {
-        #[cfg(feature = "journald")]
-        {
-            use crate::config::system::log_journald;
-            log_journald(4, $fmt);
+        if $crate::config::system::log_enabled($crate::config::system::LogLevel::Warn) {
+            #[cfg(feature = "journald")]
+            {
+                use $crate::config::system::log_journald;
+                log_journald(4, $fmt);
+            }
+            $crate::config::system::write_log_file("Wrn", $fmt);
+            println!(concat!("\x1b[93mWrn:\x1b[0m ", $fmt))
         }
-        println!(concat!("\x1b[93mWrn:\x1b[0m ", $fmt))
     };
 }
 
 #[macro_export]
 macro_rules! log_err {
     ($fmt:expr, $($arg:tt)*) => {
-        let message = format!($fmt, $($arg)*);
-        #[cfg(feature = "journald")]
-        {
-            use crate::config::system::log_journald;
-            log_journald(3, &message);
+        if $crate::config::system::log_enabled($crate::config::system::LogLevel::Error) {
+            let message = format!($fmt, $($arg)*);
+            #[cfg(feature = "journald")]
+            {
+                use $crate::config::system::log_journald;
+                log_journald(3, &message);
+            }
+            $crate::config::system::write_log_file("Err", &message);
+            println!("\x1b[31mErr:\x1b[0m {}", message)
         }
-        println!("\x1b[31mErr:\x1b[0m {}", message)
     };
     ($fmt:expr) => {
-        #[cfg(feature = "journald")]
-        {
-            use crate::config::system::log_journald;
-            log_journald(3, $fmt);
+        if $crate::config::system::log_enabled($crate::config::system::LogLevel::Error) {
+            #[cfg(feature = "journald")]
+            {
+                use $crate::config::system::log_journald;
+                log_journald(3, $fmt);
+            }
+            $crate::config::system::write_log_file("Err", $fmt);
+            println!(concat!("\x1b[31mErr:\x1b[0m ", $fmt))
         }
-        println!(concat!("\x1b[31mErr:\x1b[0m ", $fmt))
+    };
+}
+
+// Debug logs for `$module`, only printed if it was toggled on
+#[macro_export]
+macro_rules! log_dbg {
+    ($module:expr, $fmt:expr, $($arg:tt)*) => {
+        if $crate::config::system::debug_enabled($module) {
+            let message = format!($fmt, $($arg)*);
+            #[cfg(feature = "journald")]
+            {
+                use $crate::config::system::log_journald;
+                log_journald(7, &message);
+            }
+            $crate::config::system::write_log_file("Dbg", &message);
+            println!("\x1b[36mDbg:\x1b[0m {}", message)
+        }
+    };
+    ($module:expr, $fmt:expr) => {
+        $crate::log_dbg!($module, "{}", $fmt)
     };
 }
@@ -1,28 +1,3703 @@
+#[cfg(feature = "chaos")]
+use crate::rpc::chaos::FaultInjection;
 use crate::{
-    config::setup::sort_by_latency,
+    balancer::{
+        mtls::{
+            AuthPolicy,
+            ListenerTlsSettings,
+        },
+        request_body::DEFAULT_MAX_SIZE,
+        routing_hints::ROUTING_HINTS,
+        selection::schedules::Cron,
+    },
+    config::{
+        error::{
+            ConfigError,
+            OrInvalid,
+        },
+        setup::sort_by_latency,
+    },
     log_info,
     log_wrn,
+    rpc::{
+        latency::MethodLatencies,
+        profile::Provider,
+        tls::{
+            TlsConfig,
+            TlsSettings,
+        },
+        types::sanitize_url,
+    },
     Rpc,
 };
-use clap::{
-    ArgMatches,
-    Command,
-};
+use clap::ArgMatches;
 use jsonwebtoken::DecodingKey;
 
-use sled::Config;
+use sled::Config;
+
+use std::{
+    collections::BTreeMap,
+    fmt,
+    fmt::Debug,
+    fs::{
+        self,
+    },
+    net::SocketAddr,
+    println,
+    str::FromStr,
+    time::Duration,
+};
+
+use toml::Value;
+
+// How strictly we check incoming requests against the JSON-RPC 2.0 spec.
+//
+// `Off` forwards requests as-is, `Lenient` fills in missing fields before
+// forwarding, and `Strict` rejects malformed requests without forwarding them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum JsonRpcMode {
+    #[default]
+    Off,
+    Lenient,
+    Strict,
+}
+
+impl FromStr for JsonRpcMode {
+    type Err = ConfigError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "off" => Ok(JsonRpcMode::Off),
+            "lenient" => Ok(JsonRpcMode::Lenient),
+            "strict" => Ok(JsonRpcMode::Strict),
+            _ => Err(ConfigError::BadConfig),
+        }
+    }
+}
+
+// Which cache entries get evicted first once we go over the memory budget.
+//
+// `Lru` evicts the entries that weren't read for the longest time, `Lfu`
+// the ones that were read the least.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum EvictionPolicy {
+    #[default]
+    Lru,
+    Lfu,
+}
+
+impl FromStr for EvictionPolicy {
+    type Err = ConfigError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "lru" => Ok(EvictionPolicy::Lru),
+            "lfu" => Ok(EvictionPolicy::Lfu),
+            _ => Err(ConfigError::BadConfig),
+        }
+    }
+}
+
+// How we pick the node a request goes to.
+//
+// `Latency` picks the fastest available node, `Rendezvous` hashes the request
+// so the same request always goes to the same available node.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SelectionMode {
+    #[default]
+    Latency,
+    Rendezvous,
+}
+
+impl FromStr for SelectionMode {
+    type Err = ConfigError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "latency" => Ok(SelectionMode::Latency),
+            "rendezvous" => Ok(SelectionMode::Rendezvous),
+            _ => Err(ConfigError::BadConfig),
+        }
+    }
+}
+
+// How often the log file is rotated regardless of its size
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RotationInterval {
+    #[default]
+    Never,
+    Hourly,
+    Daily,
+}
+
+impl FromStr for RotationInterval {
+    type Err = ConfigError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "never" => Ok(RotationInterval::Never),
+            "hourly" => Ok(RotationInterval::Hourly),
+            "daily" => Ok(RotationInterval::Daily),
+            _ => Err(ConfigError::BadConfig),
+        }
+    }
+}
+
+// Built-in rotation for `log_file`
+#[derive(Debug, Clone, PartialEq)]
+pub struct LogRotation {
+    // Rotate once the file would grow past this many bytes
+    pub max_size: Option<u64>,
+    pub interval: RotationInterval,
+    // Rotated files we keep around, older ones get deleted
+    pub keep: usize,
+    // zstd compress rotated files
+    pub compress: bool,
+}
+
+impl Default for LogRotation {
+    fn default() -> Self {
+        Self {
+            max_size: None,
+            interval: RotationInterval::default(),
+            keep: 7,
+            compress: false,
+        }
+    }
+}
+
+impl LogRotation {
+    // Parse the optional `[blutgang.log_rotation]` table
+    fn from_table(table: Option<&Value>) -> Result<Self, ConfigError> {
+        let table = match table {
+            Some(table) => {
+                table
+                    .as_table()
+                    .or_invalid("Could not parse log_rotation table!")?
+            }
+            None => return Ok(LogRotation::default()),
+        };
+
+        // 0 turns size based rotation off
+        let max_size = match table.get("max_size") {
+            Some(max_size) => {
+                let max_size = max_size
+                    .as_integer()
+                    .or_invalid("Could not parse log_rotation max_size as int!")?;
+                (max_size > 0).then_some(max_size as u64)
+            }
+            None => None,
+        };
+        let interval = match table.get("interval") {
+            Some(interval) => {
+                interval
+                    .as_str()
+                    .or_invalid("Could not parse log_rotation interval as str!")?
+                    .parse::<RotationInterval>()
+                    .or_invalid("log_rotation interval must be never, hourly or daily!")?
+            }
+            None => RotationInterval::default(),
+        };
+        let keep = match table.get("keep") {
+            Some(keep) => {
+                keep.as_integer()
+                    .or_invalid("Could not parse log_rotation keep as int!")?
+                    as usize
+            }
+            None => LogRotation::default().keep,
+        };
+        let compress = match table.get("compress") {
+            Some(compress) => {
+                compress
+                    .as_bool()
+                    .or_invalid("Could not parse log_rotation compress as bool!")?
+            }
+            None => false,
+        };
+
+        Ok(LogRotation {
+            max_size,
+            interval,
+            keep,
+            compress,
+        })
+    }
+}
+
+// Per node and method timeouts derived from latency histograms, used
+// instead of `ttl` once we have enough samples
+#[derive(Debug, Clone, PartialEq)]
+pub struct AdaptiveTimeouts {
+    // Timeout is this times the p99 latency
+    pub factor: f64,
+    // Bounds in ms
+    pub min: u64,
+    pub max: u64,
+    pub min_samples: u64,
+}
+
+impl Default for AdaptiveTimeouts {
+    fn default() -> Self {
+        Self {
+            factor: 3.0,
+            min: 500,
+            max: 60_000,
+            min_samples: 100,
+        }
+    }
+}
+
+impl AdaptiveTimeouts {
+    // Timeout for a call to `method` on a node with `latencies`.
+    // Falls back to `ttl` ms if we don't know enough about it yet.
+    pub fn timeout(&self, latencies: &MethodLatencies, method: &str, ttl: u128) -> Duration {
+        match latencies.quantile(method, 0.99, self.min_samples) {
+            Some(p99) => {
+                let timeout = (p99.as_secs_f64() * 1000.0 * self.factor) as u64;
+                Duration::from_millis(timeout.clamp(self.min, self.max))
+            }
+            None => Duration::from_millis(ttl.try_into().unwrap_or(u64::MAX)),
+        }
+    }
+
+    // Parse the optional `[blutgang.adaptive_timeouts]` table
+    fn from_table(table: Option<&Value>) -> Result<Option<Self>, ConfigError> {
+        let table = match table {
+            Some(table) => {
+                table
+                    .as_table()
+                    .or_invalid("Could not parse adaptive_timeouts table!")?
+            }
+            None => return Ok(None),
+        };
+        let defaults = AdaptiveTimeouts::default();
+
+        let int = |name: &str, default: u64| -> Result<u64, ConfigError> {
+            Ok(match table.get(name) {
+                Some(value) => {
+                    value.as_integer().ok_or_else(|| {
+                        ConfigError::InvalidConfig(format!(
+                            "Could not parse adaptive_timeouts {} as int!",
+                            name
+                        ))
+                    })? as u64
+                }
+                None => default,
+            })
+        };
+        let factor = match table.get("factor") {
+            Some(factor) => {
+                factor
+                    .as_float()
+                    .or(factor.as_integer().map(|factor| factor as f64))
+                    .or_invalid("Could not parse adaptive_timeouts factor as float!")?
+            }
+            None => defaults.factor,
+        };
+
+        let timeouts = AdaptiveTimeouts {
+            factor,
+            min: int("min", defaults.min)?,
+            max: int("max", defaults.max)?,
+            min_samples: int("min_samples", defaults.min_samples)?,
+        };
+        if timeouts.factor <= 0.0 {
+            return Err(ConfigError::InvalidConfig(
+                "adaptive_timeouts factor must be positive!".to_string(),
+            ));
+        }
+        if timeouts.min > timeouts.max {
+            return Err(ConfigError::InvalidConfig(
+                "adaptive_timeouts min can't be larger than max!".to_string(),
+            ));
+        }
+
+        Ok(Some(timeouts))
+    }
+}
+
+// Ask several nodes for `eth_estimateGas` instead of one
+#[derive(Debug, Clone, PartialEq)]
+pub struct EstimateGasSettings {
+    // How many nodes we ask
+    pub nodes: usize,
+    // Which of their estimates we answer with, 100 being the highest
+    pub percentile: f64,
+    // How long estimates are cached for
+    pub cache_ttl: Duration,
+}
+
+impl Default for EstimateGasSettings {
+    fn default() -> Self {
+        Self {
+            nodes: 2,
+            percentile: 100.0,
+            cache_ttl: Duration::from_millis(2000),
+        }
+    }
+}
+
+impl EstimateGasSettings {
+    // Parse the optional `[blutgang.estimate_gas]` table
+    fn from_table(table: Option<&Value>) -> Result<Option<Self>, ConfigError> {
+        let table = match table {
+            Some(table) => {
+                table
+                    .as_table()
+                    .or_invalid("Could not parse estimate_gas table!")?
+            }
+            None => return Ok(None),
+        };
+        let defaults = EstimateGasSettings::default();
+
+        let nodes = match table.get("nodes") {
+            Some(nodes) => {
+                nodes
+                    .as_integer()
+                    .or_invalid("Could not parse estimate_gas nodes as int!")?
+                    as usize
+            }
+            None => defaults.nodes,
+        };
+        let percentile = match table.get("percentile") {
+            Some(percentile) => {
+                percentile
+                    .as_float()
+                    .or(percentile.as_integer().map(|percentile| percentile as f64))
+                    .or_invalid("Could not parse estimate_gas percentile as float!")?
+            }
+            None => defaults.percentile,
+        };
+        let cache_ttl = match table.get("cache_ttl") {
+            Some(cache_ttl) => {
+                Duration::from_millis(
+                    cache_ttl
+                        .as_integer()
+                        .or_invalid("Could not parse estimate_gas cache_ttl as int!")?
+                        as u64,
+                )
+            }
+            None => defaults.cache_ttl,
+        };
+
+        if nodes == 0 {
+            return Err(ConfigError::InvalidConfig(
+                "estimate_gas nodes must be at least 1!".to_string(),
+            ));
+        }
+        if !(0.0..=100.0).contains(&percentile) {
+            return Err(ConfigError::InvalidConfig(
+                "estimate_gas percentile must be between 0 and 100!".to_string(),
+            ));
+        }
+
+        Ok(Some(EstimateGasSettings {
+            nodes,
+            percentile,
+            cache_ttl,
+        }))
+    }
+}
+
+// Pin `latest` to one block for every request in a session
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConsistentReadsSettings {
+    // How long a session keeps the same block
+    pub session_ttl: Duration,
+}
+
+impl Default for ConsistentReadsSettings {
+    fn default() -> Self {
+        Self {
+            session_ttl: Duration::from_secs(12),
+        }
+    }
+}
+
+impl ConsistentReadsSettings {
+    // Parse the optional `[blutgang.consistent_reads]` table
+    fn from_table(table: Option<&Value>) -> Result<Option<Self>, ConfigError> {
+        let table = match table {
+            Some(table) => {
+                table
+                    .as_table()
+                    .or_invalid("Could not parse consistent_reads table!")?
+            }
+            None => return Ok(None),
+        };
+
+        let session_ttl = match table.get("session_ttl") {
+            Some(session_ttl) => {
+                Duration::from_millis(
+                    session_ttl
+                        .as_integer()
+                        .or_invalid("Could not parse consistent_reads session_ttl as int!")?
+                        as u64,
+                )
+            }
+            None => ConsistentReadsSettings::default().session_ttl,
+        };
+
+        Ok(Some(ConsistentReadsSettings { session_ttl }))
+    }
+}
+
+// Send historical calls to the node with the least history that still has it
+#[derive(Debug, Clone, PartialEq)]
+pub struct HistoryRoutingSettings {
+    // How often we probe how far back each node has state
+    pub probe_interval: Duration,
+    // Blocks of headroom above a node's horizon, so it doesn't prune the
+    // block between our probe and the request
+    pub margin: u64,
+}
+
+impl Default for HistoryRoutingSettings {
+    fn default() -> Self {
+        Self {
+            probe_interval: Duration::from_secs(600),
+            margin: 128,
+        }
+    }
+}
+
+impl HistoryRoutingSettings {
+    // Parse the optional `[blutgang.history_routing]` table
+    fn from_table(table: Option<&Value>) -> Result<Option<Self>, ConfigError> {
+        let table = match table {
+            Some(table) => {
+                table
+                    .as_table()
+                    .or_invalid("Could not parse history_routing table!")?
+            }
+            None => return Ok(None),
+        };
+        let defaults = HistoryRoutingSettings::default();
+
+        let int = |key: &str| {
+            table
+                .get(key)
+                .map(|value| {
+                    value.as_integer().map(|value| value as u64).ok_or_else(|| {
+                        ConfigError::InvalidConfig(format!(
+                            "Could not parse history_routing {} as int!",
+                            key
+                        ))
+                    })
+                })
+                .transpose()
+        };
+        let probe_interval = int("probe_interval")?
+            .map(Duration::from_millis)
+            .unwrap_or(defaults.probe_interval);
+        let margin = int("margin")?.unwrap_or(defaults.margin);
+
+        if probe_interval.is_zero() {
+            return Err(ConfigError::InvalidConfig(
+                "history_routing probe_interval must be greater than 0!".to_string(),
+            ));
+        }
+
+        Ok(Some(HistoryRoutingSettings {
+            probe_interval,
+            margin,
+        }))
+    }
+}
+
+// Where deprecation warnings go in the response
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum WarningPlacement {
+    // `x-blutgang-warning` header
+    #[default]
+    Header,
+    // `blutgang_warning` member of the JSON-RPC response
+    Field,
+    Both,
+}
+
+impl FromStr for WarningPlacement {
+    type Err = ConfigError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "header" => Ok(WarningPlacement::Header),
+            "field" => Ok(WarningPlacement::Field),
+            "both" => Ok(WarningPlacement::Both),
+            _ => Err(ConfigError::BadConfig),
+        }
+    }
+}
+
+// Warn clients calling methods that are about to be blocked
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DeprecationSettings {
+    pub warn_in: WarningPlacement,
+    // Method, or method prefix ending in `*`, and the warning for it
+    pub methods: BTreeMap<String, String>,
+}
+
+impl DeprecationSettings {
+    // Parse the optional `[blutgang.deprecations]` table
+    fn from_table(table: Option<&Value>) -> Result<Option<Self>, ConfigError> {
+        let table = match table {
+            Some(table) => {
+                table
+                    .as_table()
+                    .or_invalid("Could not parse deprecations table!")?
+            }
+            None => return Ok(None),
+        };
+
+        let warn_in = match table.get("warn_in") {
+            Some(warn_in) => {
+                warn_in
+                    .as_str()
+                    .and_then(|warn_in| warn_in.parse().ok())
+                    .or_invalid("deprecations warn_in must be header, field or both!")?
+            }
+            None => WarningPlacement::default(),
+        };
+        let methods = match table.get("methods") {
+            Some(methods) => {
+                methods
+                    .as_table()
+                    .or_invalid("Could not parse deprecations methods table!")?
+                    .iter()
+                    .map(|(method, warning)| {
+                        let warning = warning.as_str().ok_or_else(|| {
+                            ConfigError::InvalidConfig(format!(
+                                "Could not parse the deprecation warning for {} as str!",
+                                method
+                            ))
+                        })?;
+                        // Has to fit in a header
+                        if !warning.chars().all(|c| c == ' ' || c.is_ascii_graphic()) {
+                            return Err(ConfigError::InvalidConfig(format!(
+                                "The deprecation warning for {} can only contain printable ASCII!",
+                                method
+                            )));
+                        }
+                        Ok((method.clone(), warning.to_string()))
+                    })
+                    .collect::<Result<_, _>>()?
+            }
+            None => BTreeMap::new(),
+        };
+
+        Ok(Some(DeprecationSettings { warn_in, methods }))
+    }
+
+    // Warning for `method`. Exact matches win over the longest matching prefix.
+    pub fn warning(&self, method: &str) -> Option<&str> {
+        if let Some(warning) = self.methods.get(method) {
+            return Some(warning);
+        }
+
+        self.methods
+            .iter()
+            .filter_map(|(pattern, warning)| {
+                pattern
+                    .strip_suffix('*')
+                    .filter(|prefix| method.starts_with(prefix))
+                    .map(|prefix| (prefix.len(), warning))
+            })
+            .max_by_key(|(len, _)| *len)
+            .map(|(_, warning)| warning.as_str())
+    }
+}
+
+// What an upstream error has to have for a rule to apply to it
+#[derive(Debug, Clone, PartialEq)]
+pub enum ErrorMatch {
+    Code(i64),
+    // Lowercase substring of the message
+    Message(String),
+}
+
+// Code, and message if set, upstream errors matching `matches` are sent with
+#[derive(Debug, Clone, PartialEq)]
+pub struct ErrorRule {
+    pub matches: ErrorMatch,
+    pub code: i64,
+    pub message: Option<String>,
+}
+
+// Map upstream errors to standard JSON-RPC codes
+#[derive(Debug, Clone, PartialEq)]
+pub struct ErrorMapSettings {
+    // Whether the errors of providers we know are mapped too
+    pub builtin_rules: bool,
+    // Checked before the built-in rules. Codes first, then the longest message.
+    pub rules: Vec<ErrorRule>,
+}
+
+impl Default for ErrorMapSettings {
+    fn default() -> Self {
+        Self {
+            builtin_rules: true,
+            rules: Vec::new(),
+        }
+    }
+}
+
+impl ErrorMapSettings {
+    // Parse the optional `[blutgang.error_map]` table
+    fn from_table(table: Option<&Value>) -> Result<Option<Self>, ConfigError> {
+        let table = match table {
+            Some(table) => {
+                table
+                    .as_table()
+                    .or_invalid("Could not parse error_map table!")?
+            }
+            None => return Ok(None),
+        };
+        let defaults = ErrorMapSettings::default();
+
+        let builtin_rules = match table.get("builtin_rules") {
+            Some(builtin_rules) => {
+                builtin_rules
+                    .as_bool()
+                    .or_invalid("Could not parse error_map builtin_rules as bool!")?
+            }
+            None => defaults.builtin_rules,
+        };
+        let mut rules: Vec<ErrorRule> = match table.get("rules") {
+            Some(rules) => {
+                rules
+                    .as_table()
+                    .or_invalid("Could not parse error_map rules table!")?
+                    .iter()
+                    .map(|(key, rule)| {
+                        let code = rule
+                            .get("code")
+                            .and_then(|code| code.as_integer())
+                            .ok_or_else(|| {
+                                ConfigError::InvalidConfig(format!(
+                                    "error_map rule {} needs an int code!",
+                                    key
+                                ))
+                            })?;
+                        let message = rule
+                            .get("message")
+                            .map(|message| {
+                                message.as_str().map(str::to_string).ok_or_else(|| {
+                                    ConfigError::InvalidConfig(format!(
+                                        "Could not parse the message of error_map rule {} as str!",
+                                        key
+                                    ))
+                                })
+                            })
+                            .transpose()?;
+                        let matches = match key.parse() {
+                            Ok(code) => ErrorMatch::Code(code),
+                            Err(_) => ErrorMatch::Message(key.to_lowercase()),
+                        };
+                        Ok(ErrorRule {
+                            matches,
+                            code,
+                            message,
+                        })
+                    })
+                    .collect::<Result<_, ConfigError>>()?
+            }
+            None => Vec::new(),
+        };
+        rules.sort_by_key(|rule| {
+            match &rule.matches {
+                ErrorMatch::Code(_) => 0,
+                ErrorMatch::Message(message) => usize::MAX - message.len(),
+            }
+        });
+
+        Ok(Some(ErrorMapSettings {
+            builtin_rules,
+            rules,
+        }))
+    }
+
+    // Configured rule for an error with `code` and lowercase `message`
+    pub fn rule(&self, code: Option<i64>, message: &str) -> Option<&ErrorRule> {
+        self.rules.iter().find(|rule| {
+            match &rule.matches {
+                ErrorMatch::Code(matches) => code == Some(*matches),
+                ErrorMatch::Message(matches) => message.contains(matches.as_str()),
+            }
+        })
+    }
+}
+
+// Refuse raw transactions a client keeps sending over and over
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReplayProtectionSettings {
+    // Times a client can send the same raw transaction within `window`
+    pub max_duplicates: u32,
+    pub window: Duration,
+}
+
+impl Default for ReplayProtectionSettings {
+    fn default() -> Self {
+        Self {
+            max_duplicates: 3,
+            window: Duration::from_secs(60),
+        }
+    }
+}
+
+impl ReplayProtectionSettings {
+    // Parse the optional `[blutgang.replay_protection]` table
+    fn from_table(table: Option<&Value>) -> Result<Option<Self>, ConfigError> {
+        let table = match table {
+            Some(table) => {
+                table
+                    .as_table()
+                    .or_invalid("Could not parse replay_protection table!")?
+            }
+            None => return Ok(None),
+        };
+        let defaults = ReplayProtectionSettings::default();
+
+        let int = |key: &str| {
+            table
+                .get(key)
+                .map(|value| {
+                    value.as_integer().map(|value| value as u64).ok_or_else(|| {
+                        ConfigError::InvalidConfig(format!(
+                            "Could not parse replay_protection {} as int!",
+                            key
+                        ))
+                    })
+                })
+                .transpose()
+        };
+        let max_duplicates = int("max_duplicates")?
+            .map(|max_duplicates| max_duplicates as u32)
+            .unwrap_or(defaults.max_duplicates);
+        let window = int("window")?
+            .map(Duration::from_millis)
+            .unwrap_or(defaults.window);
+
+        if max_duplicates == 0 {
+            return Err(ConfigError::InvalidConfig(
+                "replay_protection max_duplicates must be greater than 0!".to_string(),
+            ));
+        }
+
+        Ok(Some(ReplayProtectionSettings {
+            max_duplicates,
+            window,
+        }))
+    }
+}
+
+// Rank nodes by a score made of latency, error rate and lag
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScoringSettings {
+    // Time after which a past error rate or lag counts half as much
+    pub half_life: Duration,
+    // A node failing every request ranks like one `1 + error_penalty` times as slow
+    pub error_penalty: f64,
+    // Latency added for every block a node is behind
+    pub lag_penalty: Duration,
+}
+
+impl Default for ScoringSettings {
+    fn default() -> Self {
+        Self {
+            half_life: Duration::from_secs(300),
+            error_penalty: 10.0,
+            lag_penalty: Duration::from_millis(100),
+        }
+    }
+}
+
+impl ScoringSettings {
+    // Parse the optional `[blutgang.scoring]` table
+    fn from_table(table: Option<&Value>) -> Result<Option<Self>, ConfigError> {
+        let table = match table {
+            Some(table) => {
+                table
+                    .as_table()
+                    .or_invalid("Could not parse scoring table!")?
+            }
+            None => return Ok(None),
+        };
+        let defaults = ScoringSettings::default();
+
+        let millis = |key: &str| {
+            table
+                .get(key)
+                .map(|value| {
+                    value
+                        .as_integer()
+                        .map(|value| Duration::from_millis(value as u64))
+                        .ok_or_else(|| {
+                            ConfigError::InvalidConfig(format!(
+                                "Could not parse scoring {} as int!",
+                                key
+                            ))
+                        })
+                })
+                .transpose()
+        };
+        let error_penalty = match table.get("error_penalty") {
+            Some(error_penalty) => {
+                error_penalty
+                    .as_float()
+                    .or(error_penalty.as_integer().map(|penalty| penalty as f64))
+                    .or_invalid("Could not parse scoring error_penalty as float!")?
+            }
+            None => defaults.error_penalty,
+        };
+        if error_penalty < 0.0 {
+            return Err(ConfigError::InvalidConfig(
+                "scoring error_penalty can't be negative!".to_string(),
+            ));
+        }
+
+        Ok(Some(ScoringSettings {
+            half_life: millis("half_life")?.unwrap_or(defaults.half_life),
+            error_penalty,
+            lag_penalty: millis("lag_penalty")?.unwrap_or(defaults.lag_penalty),
+        }))
+    }
+}
+
+// What happens to subscription events over a user's rate limit
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum EventLimitPolicy {
+    // Hold back the latest event of every subscription and send it once the
+    // user has budget again, dropping the ones it replaced
+    #[default]
+    Coalesce,
+    // Drop events over the limit
+    Sample,
+    // Close the user's connection
+    Disconnect,
+}
+
+impl FromStr for EventLimitPolicy {
+    type Err = ConfigError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "coalesce" => Ok(EventLimitPolicy::Coalesce),
+            "sample" => Ok(EventLimitPolicy::Sample),
+            "disconnect" => Ok(EventLimitPolicy::Disconnect),
+            _ => Err(ConfigError::BadConfig),
+        }
+    }
+}
+
+// Limit how many subscription events every WS user gets
+#[derive(Debug, Clone, PartialEq)]
+pub struct EventRateLimitSettings {
+    pub events_per_second: f64,
+    // Events a user can get at once after being quiet
+    pub burst: f64,
+    pub policy: EventLimitPolicy,
+}
+
+impl Default for EventRateLimitSettings {
+    fn default() -> Self {
+        Self {
+            events_per_second: 100.0,
+            burst: 200.0,
+            policy: EventLimitPolicy::default(),
+        }
+    }
+}
+
+impl EventRateLimitSettings {
+    // Parse the optional `[blutgang.event_rate_limit]` table
+    fn from_table(table: Option<&Value>) -> Result<Option<Self>, ConfigError> {
+        let table = match table {
+            Some(table) => {
+                table
+                    .as_table()
+                    .or_invalid("Could not parse event_rate_limit table!")?
+            }
+            None => return Ok(None),
+        };
+        let defaults = EventRateLimitSettings::default();
+
+        let float = |key: &str| {
+            table
+                .get(key)
+                .map(|value| {
+                    let value = value
+                        .as_float()
+                        .or(value.as_integer().map(|value| value as f64))
+                        .ok_or_else(|| {
+                            ConfigError::InvalidConfig(format!(
+                                "Could not parse event_rate_limit {} as float!",
+                                key
+                            ))
+                        })?;
+                    if value <= 0.0 {
+                        return Err(ConfigError::InvalidConfig(format!(
+                            "event_rate_limit {} has to be positive!",
+                            key
+                        )));
+                    }
+                    Ok(value)
+                })
+                .transpose()
+        };
+        let policy = match table.get("policy") {
+            Some(policy) => {
+                policy
+                    .as_str()
+                    .and_then(|policy| policy.parse().ok())
+                    .or_invalid("event_rate_limit policy must be coalesce, sample or disconnect!")?
+            }
+            None => defaults.policy,
+        };
+
+        Ok(Some(EventRateLimitSettings {
+            events_per_second: float("events_per_second")?.unwrap_or(defaults.events_per_second),
+            burst: float("burst")?.unwrap_or(defaults.burst),
+            policy,
+        }))
+    }
+}
+
+// How we introduce ourselves to nodes. Some providers route or rate limit
+// based on the `User-Agent` or `Origin` of requests.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UpstreamIdentity {
+    pub user_agent: String,
+    pub origin: Option<String>,
+}
+
+impl Default for UpstreamIdentity {
+    fn default() -> Self {
+        Self {
+            user_agent: concat!("blutgang/", env!("CARGO_PKG_VERSION")).to_string(),
+            origin: None,
+        }
+    }
+}
+
+impl UpstreamIdentity {
+    // Parse `user_agent` and `origin` from the `blutgang` or a node table,
+    // keeping `defaults` for what isn't set
+    fn from_table(
+        table: &toml::map::Map<String, Value>,
+        defaults: &UpstreamIdentity,
+    ) -> Result<Self, ConfigError> {
+        let header = |key: &str| {
+            table
+                .get(key)
+                .map(|value| {
+                    let value = value.as_str().ok_or_else(|| {
+                        ConfigError::InvalidConfig(format!("Could not parse {} as str!", key))
+                    })?;
+                    if reqwest::header::HeaderValue::from_str(value).is_err() {
+                        return Err(ConfigError::InvalidConfig(format!(
+                            "{} is not a valid header value!",
+                            key
+                        )));
+                    }
+                    Ok(value.to_string())
+                })
+                .transpose()
+        };
+
+        Ok(UpstreamIdentity {
+            user_agent: header("user_agent")?.unwrap_or_else(|| defaults.user_agent.clone()),
+            origin: header("origin")?.or_else(|| defaults.origin.clone()),
+        })
+    }
+
+    // Headers that go with every request to a node
+    pub fn headers(&self) -> Vec<(&'static str, &str)> {
+        let mut headers = vec![("user-agent", self.user_agent.as_str())];
+        if let Some(origin) = &self.origin {
+            headers.push(("origin", origin.as_str()));
+        }
+        headers
+    }
+}
+
+// Back off from nodes that rate limit us
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateLimitSettings {
+    // Cooldown after the first limit if the node doesn't say how long to wait
+    pub base: Duration,
+    // Longest cooldown, no matter what the node asks for
+    pub max: Duration,
+}
+
+impl Default for RateLimitSettings {
+    fn default() -> Self {
+        Self {
+            base: Duration::from_secs(1),
+            max: Duration::from_secs(60),
+        }
+    }
+}
+
+impl RateLimitSettings {
+    // Parse the optional `[blutgang.rate_limits]` table
+    fn from_table(table: Option<&Value>) -> Result<Self, ConfigError> {
+        let defaults = RateLimitSettings::default();
+        let table = match table {
+            Some(table) => {
+                table
+                    .as_table()
+                    .or_invalid("Could not parse rate_limits table!")?
+            }
+            None => return Ok(defaults),
+        };
+
+        let millis = |key: &str| {
+            table
+                .get(key)
+                .map(|value| {
+                    let millis = value.as_integer().ok_or_else(|| {
+                        ConfigError::InvalidConfig(format!(
+                            "Could not parse rate_limits {} as int!",
+                            key
+                        ))
+                    })?;
+                    if millis <= 0 {
+                        return Err(ConfigError::InvalidConfig(format!(
+                            "rate_limits {} has to be positive!",
+                            key
+                        )));
+                    }
+                    Ok(Duration::from_millis(millis as u64))
+                })
+                .transpose()
+        };
+        let base = millis("base")?.unwrap_or(defaults.base);
+        let max = millis("max")?.unwrap_or(defaults.max);
+        if base > max {
+            return Err(ConfigError::InvalidConfig(
+                "rate_limits base can't be longer than max!".to_string(),
+            ));
+        }
+
+        Ok(RateLimitSettings { base, max })
+    }
+}
+
+// How the `net_peerCount` of our nodes is combined into ours
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PeerCountAggregate {
+    #[default]
+    Sum,
+    Max,
+}
+
+impl FromStr for PeerCountAggregate {
+    type Err = ConfigError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "sum" => Ok(PeerCountAggregate::Sum),
+            "max" => Ok(PeerCountAggregate::Max),
+            _ => Err(ConfigError::BadConfig),
+        }
+    }
+}
+
+// Answer `net_peerCount` for all our nodes at once
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PeerCountSettings {
+    pub aggregate: PeerCountAggregate,
+    // How long we answer with the same count before asking the nodes again
+    pub cache_ttl: Duration,
+}
+
+impl Default for PeerCountSettings {
+    fn default() -> Self {
+        Self {
+            aggregate: PeerCountAggregate::default(),
+            cache_ttl: Duration::from_secs(5),
+        }
+    }
+}
+
+impl PeerCountSettings {
+    // Parse the optional `[blutgang.peer_count]` table
+    fn from_table(table: Option<&Value>) -> Result<Self, ConfigError> {
+        let defaults = PeerCountSettings::default();
+        let table = match table {
+            Some(table) => {
+                table
+                    .as_table()
+                    .or_invalid("Could not parse peer_count table!")?
+            }
+            None => return Ok(defaults),
+        };
+
+        let aggregate = match table.get("aggregate") {
+            Some(aggregate) => {
+                aggregate
+                    .as_str()
+                    .and_then(|aggregate| aggregate.parse().ok())
+                    .or_invalid("peer_count aggregate must be sum or max!")?
+            }
+            None => defaults.aggregate,
+        };
+        let cache_ttl = match table.get("cache_ttl") {
+            Some(cache_ttl) => {
+                let cache_ttl = cache_ttl
+                    .as_integer()
+                    .or_invalid("Could not parse peer_count cache_ttl as int!")?;
+                if cache_ttl < 0 {
+                    return Err(ConfigError::InvalidConfig(
+                        "peer_count cache_ttl can't be negative!".to_string(),
+                    ));
+                }
+                Duration::from_millis(cache_ttl as u64)
+            }
+            None => defaults.cache_ttl,
+        };
+
+        Ok(PeerCountSettings {
+            aggregate,
+            cache_ttl,
+        })
+    }
+}
+
+// Close WS connections we haven't heard from in a while
+#[derive(Debug, Clone, PartialEq)]
+pub struct IdleSettings {
+    // Time without a frame from the client before we warn them
+    pub timeout: Duration,
+    // Time they have to answer the warning before we close the connection
+    pub warning: Duration,
+}
+
+impl Default for IdleSettings {
+    fn default() -> Self {
+        Self {
+            timeout: Duration::from_secs(300),
+            warning: Duration::from_secs(30),
+        }
+    }
+}
+
+impl IdleSettings {
+    // Parse the optional `[blutgang.idle_connections]` table
+    fn from_table(table: Option<&Value>) -> Result<Option<Self>, ConfigError> {
+        let table = match table {
+            Some(table) => {
+                table
+                    .as_table()
+                    .or_invalid("Could not parse idle_connections table!")?
+            }
+            None => return Ok(None),
+        };
+        let defaults = IdleSettings::default();
+
+        let millis = |key: &str| {
+            table
+                .get(key)
+                .map(|value| {
+                    let millis = value.as_integer().ok_or_else(|| {
+                        ConfigError::InvalidConfig(format!(
+                            "Could not parse idle_connections {} as int!",
+                            key
+                        ))
+                    })?;
+                    if millis <= 0 {
+                        return Err(ConfigError::InvalidConfig(format!(
+                            "idle_connections {} has to be positive!",
+                            key
+                        )));
+                    }
+                    Ok(Duration::from_millis(millis as u64))
+                })
+                .transpose()
+        };
+
+        Ok(Some(IdleSettings {
+            timeout: millis("timeout")?.unwrap_or(defaults.timeout),
+            warning: millis("warning")?.unwrap_or(defaults.warning),
+        }))
+    }
+}
+
+// Let WS clients resume their subscriptions on a new connection, even
+// after we restart
+#[derive(Debug, Clone, PartialEq)]
+pub struct WsSessionSettings {
+    // How long a session can be resumed after its connection goes away
+    pub ttl: Duration,
+}
+
+impl Default for WsSessionSettings {
+    fn default() -> Self {
+        Self {
+            ttl: Duration::from_secs(600),
+        }
+    }
+}
+
+impl WsSessionSettings {
+    // Parse the optional `[blutgang.ws_sessions]` table
+    fn from_table(table: Option<&Value>) -> Result<Option<Self>, ConfigError> {
+        let table = match table {
+            Some(table) => {
+                table
+                    .as_table()
+                    .or_invalid("Could not parse ws_sessions table!")?
+            }
+            None => return Ok(None),
+        };
+
+        let ttl = match table.get("ttl") {
+            Some(ttl) => {
+                let ttl = ttl
+                    .as_integer()
+                    .or_invalid("Could not parse ws_sessions ttl as int!")?;
+                if ttl <= 0 {
+                    return Err(ConfigError::InvalidConfig(
+                        "ws_sessions ttl has to be positive!".to_string(),
+                    ));
+                }
+                Duration::from_millis(ttl as u64)
+            }
+            None => WsSessionSettings::default().ttl,
+        };
+
+        Ok(Some(WsSessionSettings { ttl }))
+    }
+}
+
+// Prefer nodes in our own region, and fail over to others in order
+#[derive(Debug, Clone, PartialEq)]
+pub struct RegionSettings {
+    // Region we run in
+    pub local: String,
+    // Regions to fall back to when none of our own nodes are healthy, closest first
+    pub failover: Vec<String>,
+}
+
+impl RegionSettings {
+    // Parse the optional `[blutgang.regions]` table
+    fn from_table(table: Option<&Value>) -> Result<Option<Self>, ConfigError> {
+        let table = match table {
+            Some(table) => {
+                table
+                    .as_table()
+                    .or_invalid("Could not parse regions table!")?
+            }
+            None => return Ok(None),
+        };
+
+        let local = table
+            .get("local")
+            .or_invalid("Missing local from regions!")?
+            .as_str()
+            .or_invalid("Could not parse regions local as str!")?
+            .to_string();
+        let failover = match table.get("failover") {
+            Some(failover) => {
+                failover
+                    .as_array()
+                    .and_then(|failover| {
+                        failover
+                            .iter()
+                            .map(|region| region.as_str().map(str::to_string))
+                            .collect::<Option<Vec<String>>>()
+                    })
+                    .or_invalid("Regions failover must be a list of strings!")?
+            }
+            None => Vec::new(),
+        };
+
+        Ok(Some(RegionSettings { local, failover }))
+    }
+
+    // How far down the failover order `region` is, our own region being 0.
+    // Nodes in regions we don't list, or without one, come last.
+    pub fn rank(&self, region: Option<&str>) -> usize {
+        match region {
+            Some(region) if region == self.local => 0,
+            Some(region) => {
+                self.failover
+                    .iter()
+                    .position(|failover| failover == region)
+                    .map_or(self.failover.len() + 1, |position| position + 1)
+            }
+            None => self.failover.len() + 1,
+        }
+    }
+}
+
+// Methods only answered once enough nodes return the same result
+#[derive(Debug, Clone, PartialEq)]
+pub struct QuorumClass {
+    pub methods: Vec<String>,
+    // How many nodes we ask
+    pub nodes: usize,
+    // How many of them have to agree
+    pub agree: usize,
+}
+
+// Classes of methods that need a quorum, by name
+#[derive(Debug, Clone, PartialEq)]
+pub struct QuorumSettings {
+    pub classes: BTreeMap<String, QuorumClass>,
+}
+
+impl QuorumSettings {
+    // Parse the optional `[blutgang.quorum]` table, one subtable per class
+    fn from_table(table: Option<&Value>) -> Result<Option<Self>, ConfigError> {
+        let table = match table {
+            Some(table) => {
+                table
+                    .as_table()
+                    .or_invalid("Could not parse quorum table!")?
+            }
+            None => return Ok(None),
+        };
+
+        let mut classes = BTreeMap::new();
+        for (name, class) in table {
+            let class = class.as_table().ok_or_else(|| {
+                ConfigError::InvalidConfig(format!("Could not parse quorum class {}!", name))
+            })?;
+            let methods = class
+                .get("methods")
+                .and_then(|methods| methods.as_array())
+                .and_then(|methods| {
+                    methods
+                        .iter()
+                        .map(|method| method.as_str().map(str::to_string))
+                        .collect::<Option<Vec<String>>>()
+                })
+                .ok_or_else(|| {
+                    ConfigError::InvalidConfig(format!(
+                        "Quorum class {} needs a list of methods!",
+                        name
+                    ))
+                })?;
+            let count = |field: &str| {
+                class
+                    .get(field)
+                    .and_then(|count| count.as_integer())
+                    .and_then(|count| usize::try_from(count).ok())
+                    .filter(|count| *count > 0)
+                    .ok_or_else(|| {
+                        ConfigError::InvalidConfig(format!(
+                            "Quorum class {} needs a positive {}!",
+                            name, field
+                        ))
+                    })
+            };
+            let nodes = count("nodes")?;
+            let agree = count("agree")?;
+            if agree > nodes {
+                return Err(ConfigError::InvalidConfig(format!(
+                    "Quorum class {} can't need more nodes to agree than it asks!",
+                    name
+                )));
+            }
+
+            classes.insert(
+                name.clone(),
+                QuorumClass {
+                    methods,
+                    nodes,
+                    agree,
+                },
+            );
+        }
+
+        let mut methods: Vec<&String> = classes.values().flat_map(|class| &class.methods).collect();
+        methods.sort();
+        if let Some(method) = methods.windows(2).find(|pair| pair[0] == pair[1]) {
+            return Err(ConfigError::InvalidConfig(format!(
+                "{} is in more than one quorum class!",
+                method[0]
+            )));
+        }
+
+        Ok(Some(QuorumSettings { classes }))
+    }
+
+    // Class `method` is in, with its name
+    pub fn class(&self, method: &str) -> Option<(&str, &QuorumClass)> {
+        self.classes
+            .iter()
+            .find(|(_, class)| class.methods.iter().any(|listed| listed == method))
+            .map(|(name, class)| (name.as_str(), class))
+    }
+}
+
+// Headers we never copy from clients, they describe the connection to us
+const CONNECTION_HEADERS: [&str; 9] = [
+    "connection",
+    "content-length",
+    "content-type",
+    "host",
+    "keep-alive",
+    "te",
+    "transfer-encoding",
+    "upgrade",
+    "sec-websocket-key",
+];
+
+// Client headers and WS subprotocols we pass on
+#[derive(Debug, Clone, PartialEq)]
+pub struct PassthroughSettings {
+    // Headers copied from client requests onto upstream ones, lowercase
+    pub headers: Vec<String>,
+    // WS subprotocols clients can negotiate with us
+    pub subprotocols: Vec<String>,
+}
+
+impl PassthroughSettings {
+    // Parse the optional `[blutgang.passthrough]` table
+    fn from_table(table: Option<&Value>) -> Result<Option<Self>, ConfigError> {
+        let table = match table {
+            Some(table) => {
+                table
+                    .as_table()
+                    .or_invalid("Could not parse passthrough table!")?
+            }
+            None => return Ok(None),
+        };
+
+        let list = |field: &str| {
+            match table.get(field) {
+                Some(list) => {
+                    list.as_array()
+                        .and_then(|list| {
+                            list.iter()
+                                .map(|item| item.as_str().map(str::to_string))
+                                .collect::<Option<Vec<String>>>()
+                        })
+                        .ok_or_else(|| {
+                            ConfigError::InvalidConfig(format!(
+                                "passthrough {} must be a list of strings!",
+                                field
+                            ))
+                        })
+                }
+                None => Ok(Vec::new()),
+            }
+        };
+        let headers: Vec<String> = list("headers")?
+            .iter()
+            .map(|header| header.to_lowercase())
+            .collect();
+        if let Some(header) = headers
+            .iter()
+            .find(|header| CONNECTION_HEADERS.contains(&header.as_str()))
+        {
+            return Err(ConfigError::InvalidConfig(format!(
+                "{} can't be passed through, it's about the connection to us!",
+                header
+            )));
+        }
+
+        Ok(Some(PassthroughSettings {
+            headers,
+            subprotocols: list("subprotocols")?,
+        }))
+    }
+}
+
+// Cap request bodies, and stream big ones to nodes instead of buffering them
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RequestBodySettings {
+    // Bodies over this many bytes are rejected
+    pub max_size: usize,
+    // Bodies over this many bytes are streamed, if set
+    pub stream_above: Option<usize>,
+}
+
+impl RequestBodySettings {
+    // Parse the optional `[blutgang.request_body]` table
+    fn from_table(table: Option<&Value>) -> Result<Option<Self>, ConfigError> {
+        let table = match table {
+            Some(table) => {
+                table
+                    .as_table()
+                    .or_invalid("Could not parse request_body table!")?
+            }
+            None => return Ok(None),
+        };
+
+        let size = |field: &str| {
+            table
+                .get(field)
+                .map(|size| {
+                    size.as_integer()
+                        .and_then(|size| usize::try_from(size).ok())
+                        .filter(|size| *size > 0)
+                        .ok_or_else(|| {
+                            ConfigError::InvalidConfig(format!(
+                                "request_body {} has to be a positive int!",
+                                field
+                            ))
+                        })
+                })
+                .transpose()
+        };
+        let max_size = size("max_size")?.unwrap_or(DEFAULT_MAX_SIZE);
+        let stream_above = size("stream_above")?;
+        if stream_above.is_some_and(|stream_above| stream_above >= max_size) {
+            return Err(ConfigError::InvalidConfig(
+                "request_body stream_above has to be below max_size!".to_string(),
+            ));
+        }
+
+        Ok(Some(RequestBodySettings {
+            max_size,
+            stream_above,
+        }))
+    }
+}
+
+// Routing policy that applies while `when` matches
+#[derive(Debug, Clone, PartialEq)]
+pub struct Schedule {
+    pub when: Cron,
+    // Node group requests go to first
+    pub prefer: Option<String>,
+    // Overrides `selection`
+    pub selection: Option<SelectionMode>,
+    // Names of nodes taken out of rotation
+    pub drain: Vec<String>,
+}
+
+// Time-windowed routing policies, by name
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScheduleSettings {
+    pub schedules: BTreeMap<String, Schedule>,
+}
+
+impl ScheduleSettings {
+    // Parse the optional `[blutgang.schedules]` table, one subtable per schedule
+    fn from_table(table: Option<&Value>) -> Result<Option<Self>, ConfigError> {
+        let table = match table {
+            Some(table) => {
+                table
+                    .as_table()
+                    .or_invalid("Could not parse schedules table!")?
+            }
+            None => return Ok(None),
+        };
+
+        let mut schedules = BTreeMap::new();
+        for (name, schedule) in table {
+            let schedule = schedule.as_table().ok_or_else(|| {
+                ConfigError::InvalidConfig(format!("Could not parse schedule {}!", name))
+            })?;
+            let string = |field: &str| {
+                schedule
+                    .get(field)
+                    .map(|value| {
+                        value.as_str().ok_or_else(|| {
+                            ConfigError::InvalidConfig(format!(
+                                "Could not parse schedule {} {} as str!",
+                                name, field
+                            ))
+                        })
+                    })
+                    .transpose()
+            };
+
+            let when = string("when")?
+                .ok_or_else(|| {
+                    ConfigError::InvalidConfig(format!("Schedule {} needs a `when`!", name))
+                })?
+                .parse::<Cron>()
+                .map_err(|e| {
+                    ConfigError::InvalidConfig(format!("Invalid schedule {} when: {}", name, e))
+                })?;
+            let selection = string("selection")?
+                .map(|selection| {
+                    selection.parse::<SelectionMode>().map_err(|_| {
+                        ConfigError::InvalidConfig(format!(
+                            "Schedule {} selection must be latency or rendezvous!",
+                            name
+                        ))
+                    })
+                })
+                .transpose()?;
+            let drain = match schedule.get("drain") {
+                Some(drain) => {
+                    drain
+                        .as_array()
+                        .and_then(|drain| {
+                            drain
+                                .iter()
+                                .map(|node| node.as_str().map(str::to_string))
+                                .collect::<Option<Vec<String>>>()
+                        })
+                        .ok_or_else(|| {
+                            ConfigError::InvalidConfig(format!(
+                                "Schedule {} drain must be a list of node names!",
+                                name
+                            ))
+                        })?
+                }
+                None => Vec::new(),
+            };
+
+            let prefer = string("prefer")?.map(str::to_string);
+            if prefer.is_none() && selection.is_none() && drain.is_empty() {
+                return Err(ConfigError::InvalidConfig(format!(
+                    "Schedule {} needs at least one of prefer, selection or drain!",
+                    name
+                )));
+            }
+
+            schedules.insert(
+                name.clone(),
+                Schedule {
+                    when,
+                    prefer,
+                    selection,
+                    drain,
+                },
+            );
+        }
+
+        Ok(Some(ScheduleSettings { schedules }))
+    }
+}
+
+// Share cached responses between the instances of a blutgang fleet
+#[derive(Clone, PartialEq)]
+pub struct ClusterSettings {
+    // How the other instances reach us, the way it's listed in their `peers`
+    pub address: String,
+    // The other instances
+    pub peers: Vec<String>,
+    // Sent with requests between instances so they can tell us from clients
+    pub secret: Option<String>,
+    // How long to wait on a peer before going upstream ourselves
+    pub timeout: Duration,
+    // Share node health with the other instances if set
+    pub gossip: Option<GossipSettings>,
+}
+
+impl Debug for ClusterSettings {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ClusterSettings {{")?;
+        write!(f, " address: {:?}", self.address)?;
+        write!(f, ", peers: {:?}", self.peers)?;
+        if self.secret.is_some() {
+            write!(f, ", secret: HIDDEN")?;
+        }
+        write!(f, ", timeout: {:?}", self.timeout)?;
+        write!(f, ", gossip: {:?}", self.gossip)?;
+        write!(f, " }}")
+    }
+}
+
+impl ClusterSettings {
+    // Parse the optional `[blutgang.cluster]` table
+    fn from_table(table: Option<&Value>) -> Result<Option<Self>, ConfigError> {
+        let table = match table {
+            Some(table) => {
+                table
+                    .as_table()
+                    .or_invalid("Could not parse cluster table!")?
+            }
+            None => return Ok(None),
+        };
+
+        let address = table
+            .get("address")
+            .or_invalid("Missing address from cluster!")?
+            .as_str()
+            .or_invalid("Could not parse cluster address as str!")?
+            .trim_end_matches('/')
+            .to_string();
+        let peers = match table.get("peers") {
+            Some(peers) => {
+                peers
+                    .as_array()
+                    .and_then(|peers| {
+                        peers
+                            .iter()
+                            .map(|peer| {
+                                peer.as_str()
+                                    .map(|peer| peer.trim_end_matches('/').to_string())
+                            })
+                            .collect::<Option<Vec<String>>>()
+                    })
+                    .or_invalid("Cluster peers must be a list of strings!")?
+            }
+            None => Vec::new(),
+        };
+        let secret = table
+            .get("secret")
+            .map(|secret| {
+                secret
+                    .as_str()
+                    .map(str::to_string)
+                    .or_invalid("Could not parse cluster secret as str!")
+            })
+            .transpose()?;
+        let timeout = match table.get("timeout") {
+            Some(timeout) => {
+                let timeout = timeout
+                    .as_integer()
+                    .or_invalid("Could not parse cluster timeout as int!")?;
+                if timeout <= 0 {
+                    return Err(ConfigError::InvalidConfig(
+                        "cluster timeout has to be positive!".to_string(),
+                    ));
+                }
+                Duration::from_millis(timeout as u64)
+            }
+            None => Duration::from_millis(2000),
+        };
+
+        let gossip = GossipSettings::from_table(table.get("gossip"))?;
+        if gossip.is_some() && secret.is_none() {
+            return Err(ConfigError::InvalidConfig(
+                "cluster gossip needs a secret to sign reports with!".to_string(),
+            ));
+        }
+
+        Ok(Some(ClusterSettings {
+            address,
+            peers,
+            secret,
+            timeout,
+            gossip,
+        }))
+    }
+}
+
+// Tell the other instances about nodes we found down, and listen to them
+#[derive(Debug, Clone, PartialEq)]
+pub struct GossipSettings {
+    // UDP port every instance gossips on
+    pub port: u16,
+    // How often we tell the others what we see
+    pub interval: Duration,
+}
+
+impl GossipSettings {
+    // Parse the optional `[blutgang.cluster.gossip]` table
+    fn from_table(table: Option<&Value>) -> Result<Option<Self>, ConfigError> {
+        let table = match table {
+            Some(table) => {
+                table
+                    .as_table()
+                    .or_invalid("Could not parse cluster gossip table!")?
+            }
+            None => return Ok(None),
+        };
+
+        let port = table
+            .get("port")
+            .or_invalid("Missing port from cluster gossip!")?
+            .as_integer()
+            .and_then(|port| u16::try_from(port).ok())
+            .or_invalid("Could not parse cluster gossip port!")?;
+        let interval = match table.get("interval") {
+            Some(interval) => {
+                let interval = interval
+                    .as_integer()
+                    .or_invalid("Could not parse gossip interval as int!")?;
+                if interval <= 0 {
+                    return Err(ConfigError::InvalidConfig(
+                        "gossip interval has to be positive!".to_string(),
+                    ));
+                }
+                Duration::from_millis(interval as u64)
+            }
+            None => Duration::from_millis(1000),
+        };
+
+        Ok(Some(GossipSettings { port, interval }))
+    }
+}
+
+// REST paths in front of JSON-RPC
+#[derive(Debug, Clone, PartialEq)]
+pub struct RestSettings {
+    // Paths under this are REST calls
+    pub prefix: String,
+    // How long HTTP caches can keep answers that might still change, in
+    // seconds. Answers that can't change are kept for much longer.
+    pub max_age: u64,
+}
+
+impl Default for RestSettings {
+    fn default() -> Self {
+        Self {
+            prefix: "/eth/v1".to_string(),
+            max_age: 0,
+        }
+    }
+}
+
+impl RestSettings {
+    // Parse the optional `[blutgang.rest]` table
+    fn from_table(table: Option<&Value>) -> Result<Option<Self>, ConfigError> {
+        let table = match table {
+            Some(table) => table.as_table().or_invalid("Could not parse rest table!")?,
+            None => return Ok(None),
+        };
+        let defaults = RestSettings::default();
+
+        let prefix = match table.get("prefix") {
+            Some(prefix) => {
+                prefix
+                    .as_str()
+                    .filter(|prefix| prefix.starts_with('/') && prefix.len() > 1)
+                    .or_invalid("rest prefix must be a path like /eth/v1!")?
+                    .trim_end_matches('/')
+                    .to_string()
+            }
+            None => defaults.prefix,
+        };
+        let max_age = match table.get("max_age") {
+            Some(max_age) => {
+                max_age
+                    .as_integer()
+                    .and_then(|max_age| u64::try_from(max_age).ok())
+                    .or_invalid("rest max_age can't be negative!")?
+            }
+            None => defaults.max_age,
+        };
+
+        Ok(Some(RestSettings { prefix, max_age }))
+    }
+}
+
+// Another blutgang instance we send requests to when our own nodes can't answer
+#[derive(Clone, PartialEq)]
+pub struct FallbackSettings {
+    // Where the other instance accepts requests
+    pub url: String,
+    // Sent as a bearer token if the other instance wants a key
+    pub key: Option<String>,
+    // Requests that already went through this many instances aren't sent on
+    pub max_hops: u32,
+    // How long to wait on the other instance
+    pub timeout: Duration,
+}
+
+impl Debug for FallbackSettings {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "FallbackSettings {{")?;
+        write!(f, " url: {:?}", self.url)?;
+        if self.key.is_some() {
+            write!(f, ", key: HIDDEN")?;
+        }
+        write!(f, ", max_hops: {:?}", self.max_hops)?;
+        write!(f, ", timeout: {:?}", self.timeout)?;
+        write!(f, " }}")
+    }
+}
+
+impl FallbackSettings {
+    // Parse the optional `[blutgang.fallback]` table
+    fn from_table(table: Option<&Value>) -> Result<Option<Self>, ConfigError> {
+        let table = match table {
+            Some(table) => {
+                table
+                    .as_table()
+                    .or_invalid("Could not parse fallback table!")?
+            }
+            None => return Ok(None),
+        };
+
+        let url = table
+            .get("url")
+            .or_invalid("Missing url from fallback!")?
+            .as_str()
+            .or_invalid("Could not parse fallback url as str!")?
+            .to_string();
+        let key = table
+            .get("key")
+            .map(|key| {
+                key.as_str()
+                    .map(str::to_string)
+                    .or_invalid("Could not parse fallback key as str!")
+            })
+            .transpose()?;
+        let max_hops = match table.get("max_hops") {
+            Some(max_hops) => {
+                max_hops
+                    .as_integer()
+                    .and_then(|max_hops| u32::try_from(max_hops).ok())
+                    .filter(|max_hops| *max_hops > 0)
+                    .or_invalid("fallback max_hops has to be a positive int!")?
+            }
+            None => 2,
+        };
+        let timeout = match table.get("timeout") {
+            Some(timeout) => {
+                let timeout = timeout
+                    .as_integer()
+                    .or_invalid("Could not parse fallback timeout as int!")?;
+                if timeout <= 0 {
+                    return Err(ConfigError::InvalidConfig(
+                        "fallback timeout has to be positive!".to_string(),
+                    ));
+                }
+                Duration::from_millis(timeout as u64)
+            }
+            None => Duration::from_millis(5000),
+        };
+
+        Ok(Some(FallbackSettings {
+            url,
+            key,
+            max_hops,
+            timeout,
+        }))
+    }
+}
+
+// Index logs of finalized blocks locally and answer `eth_getLogs` from it
+#[derive(Debug, Clone, PartialEq)]
+pub struct LogIndexSettings {
+    // Blocks before the finalized one to index when we start without an index
+    pub backfill: u64,
+    // Blocks we ask a node for logs of at once
+    pub batch: u64,
+    // Only index logs of these contracts, lowercase. Everything if empty.
+    pub addresses: Vec<String>,
+    // Widest range and most logs we answer a query with, bigger ones go upstream
+    pub max_range: u64,
+    pub max_logs: usize,
+}
+
+impl LogIndexSettings {
+    // Parse the optional `[blutgang.log_index]` table
+    fn from_table(table: Option<&Value>) -> Result<Option<Self>, ConfigError> {
+        let table = match table {
+            Some(table) => {
+                table
+                    .as_table()
+                    .or_invalid("Could not parse log_index table!")?
+            }
+            None => return Ok(None),
+        };
+
+        let backfill = match table.get("backfill") {
+            Some(backfill) => {
+                backfill
+                    .as_integer()
+                    .and_then(|backfill| u64::try_from(backfill).ok())
+                    .or_invalid("log_index backfill has to be a positive int!")?
+            }
+            None => 0,
+        };
+        let batch = match table.get("batch") {
+            Some(batch) => {
+                batch
+                    .as_integer()
+                    .and_then(|batch| u64::try_from(batch).ok())
+                    .filter(|batch| *batch > 0)
+                    .or_invalid("log_index batch has to be a positive int!")?
+            }
+            None => 100,
+        };
+        let addresses = match table.get("addresses") {
+            Some(addresses) => {
+                addresses
+                    .as_array()
+                    .and_then(|addresses| {
+                        addresses
+                            .iter()
+                            .map(|address| address.as_str().map(str::to_lowercase))
+                            .collect::<Option<Vec<String>>>()
+                    })
+                    .or_invalid("log_index addresses must be a list of strings!")?
+            }
+            None => Vec::new(),
+        };
+        let max_range = match table.get("max_range") {
+            Some(max_range) => {
+                max_range
+                    .as_integer()
+                    .and_then(|max_range| u64::try_from(max_range).ok())
+                    .filter(|max_range| *max_range > 0)
+                    .or_invalid("log_index max_range has to be a positive int!")?
+            }
+            None => 10000,
+        };
+        let max_logs = match table.get("max_logs") {
+            Some(max_logs) => {
+                max_logs
+                    .as_integer()
+                    .and_then(|max_logs| usize::try_from(max_logs).ok())
+                    .filter(|max_logs| *max_logs > 0)
+                    .or_invalid("log_index max_logs has to be a positive int!")?
+            }
+            None => 10000,
+        };
+
+        Ok(Some(LogIndexSettings {
+            backfill,
+            batch,
+            addresses,
+            max_range,
+            max_logs,
+        }))
+    }
+}
+
+// Keep receipts of finalized transactions and answer `eth_getTransactionReceipt` from them
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReceiptStoreSettings {
+    // Bytes of receipts we keep before pruning the oldest blocks
+    pub max_size: u64,
+}
+
+impl ReceiptStoreSettings {
+    // Parse the optional `[blutgang.receipts]` table
+    fn from_table(table: Option<&Value>) -> Result<Option<Self>, ConfigError> {
+        let table = match table {
+            Some(table) => {
+                table
+                    .as_table()
+                    .or_invalid("Could not parse receipts table!")?
+            }
+            None => return Ok(None),
+        };
+
+        let max_size = match table.get("max_size") {
+            Some(max_size) => {
+                max_size
+                    .as_integer()
+                    .and_then(|max_size| u64::try_from(max_size).ok())
+                    .filter(|max_size| *max_size > 0)
+                    .or_invalid("receipts max_size has to be a positive int!")?
+            }
+            None => 1 << 30,
+        };
+
+        Ok(Some(ReceiptStoreSettings { max_size }))
+    }
+}
+
+// Start nodes added at runtime on a small share of traffic and ramp them up
+#[derive(Debug, Clone, PartialEq)]
+pub struct CanarySettings {
+    // Share of the requests a new node would get that it gets right away, 0 to 1
+    pub start_share: f64,
+    // Time a new node has to stay healthy to get all of its traffic
+    pub window: Duration,
+}
+
+impl Default for CanarySettings {
+    fn default() -> Self {
+        Self {
+            start_share: 0.05,
+            window: Duration::from_secs(600),
+        }
+    }
+}
+
+impl CanarySettings {
+    // Parse the optional `[blutgang.canary]` table
+    fn from_table(table: Option<&Value>) -> Result<Option<Self>, ConfigError> {
+        let table = match table {
+            Some(table) => {
+                table
+                    .as_table()
+                    .or_invalid("Could not parse canary table!")?
+            }
+            None => return Ok(None),
+        };
+        let defaults = CanarySettings::default();
+
+        let start_share = match table.get("start_percent") {
+            Some(start_percent) => {
+                start_percent
+                    .as_float()
+                    .or(start_percent.as_integer().map(|percent| percent as f64))
+                    .or_invalid("Could not parse canary start_percent as float!")?
+                    / 100.0
+            }
+            None => defaults.start_share,
+        };
+        if !(0.0..=1.0).contains(&start_share) {
+            return Err(ConfigError::InvalidConfig(
+                "canary start_percent has to be between 0 and 100!".to_string(),
+            ));
+        }
+        let window = match table.get("window") {
+            Some(window) => {
+                Duration::from_millis(
+                    window
+                        .as_integer()
+                        .or_invalid("Could not parse canary window as int!")?
+                        as u64,
+                )
+            }
+            None => defaults.window,
+        };
+
+        Ok(Some(CanarySettings {
+            start_share,
+            window,
+        }))
+    }
+}
+
+// Request caps of a node on a plan with a daily or monthly quota
+#[derive(Debug, Clone, PartialEq)]
+pub struct BudgetSettings {
+    // Requests per UTC day
+    pub daily: Option<u64>,
+    // Requests per UTC month
+    pub monthly: Option<u64>,
+    // Spread requests evenly over the period instead of using them up first come
+    pub pacing: bool,
+}
+
+impl BudgetSettings {
+    // Parse the optional `[rpc_name.budget]` table
+    fn from_table(table: Option<&Value>) -> Result<Option<Self>, ConfigError> {
+        let table = match table {
+            Some(table) => {
+                table
+                    .as_table()
+                    .or_invalid("Could not parse budget table!")?
+            }
+            None => return Ok(None),
+        };
+
+        let limit = |name: &str| {
+            table
+                .get(name)
+                .map(|limit| {
+                    limit
+                        .as_integer()
+                        .filter(|limit| *limit > 0)
+                        .map(|limit| limit as u64)
+                        .ok_or_else(|| {
+                            ConfigError::InvalidConfig(format!(
+                                "Budget {} has to be a positive int!",
+                                name
+                            ))
+                        })
+                })
+                .transpose()
+        };
+        let daily = limit("daily")?;
+        let monthly = limit("monthly")?;
+        if daily.is_none() && monthly.is_none() {
+            return Err(ConfigError::InvalidConfig(
+                "Budget tables need a daily or monthly limit!".to_string(),
+            ));
+        }
+
+        let pacing = match table.get("pacing") {
+            Some(pacing) => {
+                pacing
+                    .as_bool()
+                    .or_invalid("Could not parse budget pacing as bool!")?
+            }
+            None => true,
+        };
+
+        Ok(Some(BudgetSettings {
+            daily,
+            monthly,
+            pacing,
+        }))
+    }
+}
+
+// Re-fetch the most requested `latest` requests on every new block
+#[derive(Debug, Clone, PartialEq)]
+pub struct RevalidationSettings {
+    // How many requests get re-fetched per block
+    pub top_n: usize,
+    // Requests in flight at the same time
+    pub concurrency: usize,
+}
+
+impl Default for RevalidationSettings {
+    fn default() -> Self {
+        Self {
+            top_n: 100,
+            concurrency: 16,
+        }
+    }
+}
+
+impl RevalidationSettings {
+    // Parse the optional `[blutgang.revalidation]` table
+    fn from_table(table: Option<&Value>) -> Result<Option<Self>, ConfigError> {
+        let table = match table {
+            Some(table) => {
+                table
+                    .as_table()
+                    .or_invalid("Could not parse revalidation table!")?
+            }
+            None => return Ok(None),
+        };
+        let defaults = RevalidationSettings::default();
+
+        let int = |key: &str| {
+            table
+                .get(key)
+                .map(|value| {
+                    value
+                        .as_integer()
+                        .map(|value| value as usize)
+                        .ok_or_else(|| {
+                            ConfigError::InvalidConfig(format!(
+                                "Could not parse revalidation {} as int!",
+                                key
+                            ))
+                        })
+                })
+                .transpose()
+        };
+        let top_n = int("top_n")?.unwrap_or(defaults.top_n);
+        let concurrency = int("concurrency")?.unwrap_or(defaults.concurrency);
+
+        if concurrency == 0 {
+            return Err(ConfigError::InvalidConfig(
+                "revalidation concurrency must be greater than 0!".to_string(),
+            ));
+        }
+
+        Ok(Some(RevalidationSettings { top_n, concurrency }))
+    }
+}
+
+// Format of usage exports
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum UsageFormat {
+    #[default]
+    Csv,
+    Json,
+}
+
+impl FromStr for UsageFormat {
+    type Err = ConfigError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "csv" => Ok(UsageFormat::Csv),
+            "json" => Ok(UsageFormat::Json),
+            _ => Err(ConfigError::BadConfig),
+        }
+    }
+}
+
+// S3-compatible bucket we push usage exports to
+#[derive(Clone, PartialEq)]
+pub struct UsageS3Settings {
+    // e.g. `https://s3.us-east-1.amazonaws.com`, objects are addressed path-style
+    pub endpoint: String,
+    pub bucket: String,
+    pub region: String,
+    pub access_key: String,
+    pub secret_key: String,
+    // Prepended to `<day>.csv`/`<day>.json`
+    pub prefix: String,
+    pub format: UsageFormat,
+    pub interval: Duration,
+}
+
+impl Debug for UsageS3Settings {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "UsageS3Settings {{")?;
+        write!(f, " endpoint: {:?}", self.endpoint)?;
+        write!(f, ", bucket: {:?}", self.bucket)?;
+        write!(f, ", region: {:?}", self.region)?;
+        write!(f, ", access_key: {:?}", self.access_key)?;
+        write!(f, ", secret_key: HIDDEN")?;
+        write!(f, ", prefix: {:?}", self.prefix)?;
+        write!(f, ", format: {:?}", self.format)?;
+        write!(f, ", interval: {:?}", self.interval)?;
+        write!(f, " }}")
+    }
+}
+
+impl UsageS3Settings {
+    // Parse the optional `[blutgang.usage.s3]` table
+    fn from_table(table: Option<&Value>) -> Result<Option<Self>, ConfigError> {
+        let table = match table {
+            Some(table) => {
+                table
+                    .as_table()
+                    .or_invalid("Could not parse usage s3 table!")?
+            }
+            None => return Ok(None),
+        };
+
+        let string = |key: &str| {
+            table
+                .get(key)
+                .map(|value| {
+                    value.as_str().map(str::to_string).ok_or_else(|| {
+                        ConfigError::InvalidConfig(format!(
+                            "Could not parse usage s3 {} as str!",
+                            key
+                        ))
+                    })
+                })
+                .transpose()
+        };
+        let required = |key: &str| {
+            string(key)?
+                .ok_or_else(|| ConfigError::InvalidConfig(format!("usage s3 needs a {}!", key)))
+        };
+
+        let format = match string("format")? {
+            Some(format) => {
+                format
+                    .parse()
+                    .or_invalid("usage s3 format must be csv or json!")?
+            }
+            None => UsageFormat::default(),
+        };
+        let interval = match table.get("interval") {
+            Some(interval) => {
+                Duration::from_millis(
+                    interval
+                        .as_integer()
+                        .or_invalid("Could not parse usage s3 interval as int!")?
+                        as u64,
+                )
+            }
+            None => Duration::from_secs(3600),
+        };
+        if interval.is_zero() {
+            return Err(ConfigError::InvalidConfig(
+                "usage s3 interval must be greater than 0!".to_string(),
+            ));
+        }
+
+        Ok(Some(UsageS3Settings {
+            endpoint: required("endpoint")?.trim_end_matches('/').to_string(),
+            bucket: required("bucket")?,
+            region: string("region")?.unwrap_or_else(|| "us-east-1".to_string()),
+            access_key: required("access_key")?,
+            secret_key: required("secret_key")?,
+            prefix: string("prefix")?.unwrap_or_default(),
+            format,
+            interval,
+        }))
+    }
+}
+
+// Count calls per client and method for every day
+#[derive(Debug, Clone, PartialEq)]
+pub struct UsageSettings {
+    // Days of counts we keep around
+    pub retention_days: u64,
+    pub s3: Option<UsageS3Settings>,
+}
+
+impl Default for UsageSettings {
+    fn default() -> Self {
+        Self {
+            retention_days: 35,
+            s3: None,
+        }
+    }
+}
+
+impl UsageSettings {
+    // Parse the optional `[blutgang.usage]` table
+    fn from_table(table: Option<&Value>) -> Result<Option<Self>, ConfigError> {
+        let table = match table {
+            Some(table) => {
+                table
+                    .as_table()
+                    .or_invalid("Could not parse usage table!")?
+            }
+            None => return Ok(None),
+        };
+
+        let retention_days = match table.get("retention_days") {
+            Some(retention_days) => {
+                retention_days
+                    .as_integer()
+                    .or_invalid("Could not parse usage retention_days as int!")?
+                    as u64
+            }
+            None => UsageSettings::default().retention_days,
+        };
+        if retention_days == 0 {
+            return Err(ConfigError::InvalidConfig(
+                "usage retention_days must be greater than 0!".to_string(),
+            ));
+        }
+
+        Ok(Some(UsageSettings {
+            retention_days,
+            s3: UsageS3Settings::from_table(table.get("s3"))?,
+        }))
+    }
+}
+
+// Per-request routing hints clients can send with a `blutgang` field or header
+#[derive(Debug, Clone, PartialEq)]
+pub struct RoutingHintsSettings {
+    // Hints any client can send
+    pub allow: Vec<String>,
+    // Overrides `allow` for clients that authenticated with a certificate
+    pub identities: BTreeMap<String, Vec<String>>,
+    // Longest `timeout_ms` a client can ask for
+    pub max_timeout: Duration,
+    // Read `no-cache` and `no-store` from `Cache-Control` headers
+    pub cache_control: bool,
+}
+
+impl Default for RoutingHintsSettings {
+    fn default() -> Self {
+        Self {
+            allow: ROUTING_HINTS.iter().map(|hint| hint.to_string()).collect(),
+            identities: BTreeMap::new(),
+            max_timeout: Duration::from_secs(10),
+            cache_control: false,
+        }
+    }
+}
+
+impl RoutingHintsSettings {
+    // Parse the optional `[blutgang.routing_hints]` table
+    fn from_table(table: Option<&Value>) -> Result<Option<Self>, ConfigError> {
+        let table = match table {
+            Some(table) => {
+                table
+                    .as_table()
+                    .or_invalid("Could not parse routing_hints table!")?
+            }
+            None => return Ok(None),
+        };
+        let defaults = RoutingHintsSettings::default();
+
+        let hints = |name: &str, hints: &Value| {
+            let hints = hints
+                .as_array()
+                .and_then(|hints| {
+                    hints
+                        .iter()
+                        .map(|hint| hint.as_str().map(str::to_string))
+                        .collect::<Option<Vec<String>>>()
+                })
+                .ok_or_else(|| {
+                    ConfigError::InvalidConfig(format!(
+                        "routing_hints {} must be a list of strings!",
+                        name
+                    ))
+                })?;
+            if let Some(hint) = hints
+                .iter()
+                .find(|hint| !ROUTING_HINTS.contains(&hint.as_str()))
+            {
+                return Err(ConfigError::InvalidConfig(format!(
+                    "Unknown routing hint: {}",
+                    hint
+                )));
+            }
+            Ok(hints)
+        };
+
+        let allow = match table.get("allow") {
+            Some(allow) => hints("allow", allow)?,
+            None => defaults.allow,
+        };
+        let identities = match table.get("identities") {
+            Some(identities) => {
+                identities
+                    .as_table()
+                    .or_invalid("Could not parse routing_hints identities table!")?
+                    .iter()
+                    .map(|(identity, allowed)| Ok((identity.clone(), hints(identity, allowed)?)))
+                    .collect::<Result<_, ConfigError>>()?
+            }
+            None => BTreeMap::new(),
+        };
+        let max_timeout = match table.get("max_timeout_ms") {
+            Some(max_timeout) => {
+                Duration::from_millis(
+                    max_timeout
+                        .as_integer()
+                        .or_invalid("Could not parse routing_hints max_timeout_ms as int!")?
+                        as u64,
+                )
+            }
+            None => defaults.max_timeout,
+        };
+        let cache_control = match table.get("cache_control") {
+            Some(cache_control) => {
+                cache_control
+                    .as_bool()
+                    .or_invalid("Could not parse routing_hints cache_control as bool!")?
+            }
+            None => defaults.cache_control,
+        };
+
+        Ok(Some(RoutingHintsSettings {
+            allow,
+            identities,
+            max_timeout,
+            cache_control,
+        }))
+    }
+
+    // Whether the client `identity` can send `hint`
+    pub fn allows(&self, identity: Option<&str>, hint: &str) -> bool {
+        let allowed = identity
+            .and_then(|identity| self.identities.get(identity))
+            .unwrap_or(&self.allow);
+        allowed.iter().any(|allowed| allowed == hint)
+    }
+}
+
+// Alarm when nodes disagree on the hash at the same height
+#[derive(Debug, Clone, PartialEq)]
+pub struct ForkChoiceSettings {
+    // How long nodes can disagree before we raise the alarm
+    pub threshold: Duration,
+}
+
+impl Default for ForkChoiceSettings {
+    fn default() -> Self {
+        Self {
+            threshold: Duration::from_secs(30),
+        }
+    }
+}
+
+impl ForkChoiceSettings {
+    // Parse the optional `[blutgang.fork_choice]` table
+    fn from_table(table: Option<&Value>) -> Result<Option<Self>, ConfigError> {
+        let table = match table {
+            Some(table) => {
+                table
+                    .as_table()
+                    .or_invalid("Could not parse fork_choice table!")?
+            }
+            None => return Ok(None),
+        };
+
+        let threshold = match table.get("threshold") {
+            Some(threshold) => {
+                Duration::from_millis(
+                    threshold
+                        .as_integer()
+                        .or_invalid("Could not parse fork_choice threshold as int!")?
+                        as u64,
+                )
+            }
+            None => ForkChoiceSettings::default().threshold,
+        };
+
+        Ok(Some(ForkChoiceSettings { threshold }))
+    }
+}
+
+// Keep rebroadcasting transactions sent through us until they're included
+#[derive(Debug, Clone, PartialEq)]
+pub struct RebroadcastSettings {
+    // How often we rebroadcast
+    pub interval: Duration,
+    // How long after the first broadcast we give up
+    pub ttl: Duration,
+    // Client identities that opted in, everyone if not set
+    pub identities: Option<Vec<String>>,
+}
+
+impl Default for RebroadcastSettings {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(30),
+            ttl: Duration::from_secs(600),
+            identities: None,
+        }
+    }
+}
+
+impl RebroadcastSettings {
+    // Parse the optional `[blutgang.rebroadcast]` table
+    fn from_table(table: Option<&Value>) -> Result<Option<Self>, ConfigError> {
+        let table = match table {
+            Some(table) => {
+                table
+                    .as_table()
+                    .or_invalid("Could not parse rebroadcast table!")?
+            }
+            None => return Ok(None),
+        };
+        let defaults = RebroadcastSettings::default();
+
+        let millis = |key: &str, default: Duration| -> Result<Duration, ConfigError> {
+            Ok(match table.get(key) {
+                Some(millis) => {
+                    Duration::from_millis(millis.as_integer().ok_or_else(|| {
+                        ConfigError::InvalidConfig(format!(
+                            "Could not parse rebroadcast {} as int!",
+                            key
+                        ))
+                    })? as u64)
+                }
+                None => default,
+            })
+        };
+        let interval = millis("interval", defaults.interval)?;
+        let ttl = millis("ttl", defaults.ttl)?;
+
+        let identities = table
+            .get("identities")
+            .map(|identities| {
+                identities
+                    .as_array()
+                    .and_then(|identities| {
+                        identities
+                            .iter()
+                            .map(|identity| identity.as_str().map(str::to_string))
+                            .collect::<Option<Vec<String>>>()
+                    })
+                    .or_invalid("Rebroadcast identities must be a list of strings!")
+            })
+            .transpose()?;
+
+        if interval.is_zero() {
+            return Err(ConfigError::InvalidConfig(
+                "Rebroadcast interval must be greater than 0!".to_string(),
+            ));
+        }
+
+        Ok(Some(RebroadcastSettings {
+            interval,
+            ttl,
+            identities,
+        }))
+    }
+
+    // Whether transactions from the client `identity` get rebroadcast
+    pub fn wants(&self, identity: Option<&str>) -> bool {
+        match &self.identities {
+            Some(identities) => {
+                identity.is_some_and(|identity| identities.iter().any(|name| name == identity))
+            }
+            None => true,
+        }
+    }
+}
+
+// Ask other nodes when one says it doesn't have a block at the head yet
+#[derive(Debug, Clone, PartialEq)]
+pub struct HeadRetrySettings {
+    // How many blocks behind our head still count as the head
+    pub depth: u64,
+    // How long we wait before asking all nodes again
+    pub delay: Duration,
+    // Up to how much we add to `delay` at random
+    pub jitter: Duration,
+}
+
+impl Default for HeadRetrySettings {
+    fn default() -> Self {
+        Self {
+            depth: 3,
+            delay: Duration::from_millis(200),
+            jitter: Duration::from_millis(300),
+        }
+    }
+}
+
+impl HeadRetrySettings {
+    // Parse the optional `[blutgang.head_retry]` table
+    fn from_table(table: Option<&Value>) -> Result<Option<Self>, ConfigError> {
+        let table = match table {
+            Some(table) => {
+                table
+                    .as_table()
+                    .or_invalid("Could not parse head_retry table!")?
+            }
+            None => return Ok(None),
+        };
+        let defaults = HeadRetrySettings::default();
+
+        let int = |key: &str| {
+            table
+                .get(key)
+                .map(|value| {
+                    value
+                        .as_integer()
+                        .filter(|value| *value >= 0)
+                        .map(|value| value as u64)
+                        .ok_or_else(|| {
+                            ConfigError::InvalidConfig(format!(
+                                "head_retry {} has to be a positive int!",
+                                key
+                            ))
+                        })
+                })
+                .transpose()
+        };
+        let depth = int("depth")?.unwrap_or(defaults.depth);
+        let delay = int("delay")?.map_or(defaults.delay, Duration::from_millis);
+        let jitter = int("jitter")?.map_or(defaults.jitter, Duration::from_millis);
+
+        if depth == 0 {
+            return Err(ConfigError::InvalidConfig(
+                "head_retry depth must be greater than 0!".to_string(),
+            ));
+        }
+
+        Ok(Some(HeadRetrySettings {
+            depth,
+            delay,
+            jitter,
+        }))
+    }
+}
+
+// Record responses to disk, or serve them from an earlier recording
+// without contacting any upstream nodes.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum RecordingMode {
+    #[default]
+    Off,
+    Record(String),
+    Replay(String),
+}
+
+impl RecordingMode {
+    fn from_paths(
+        record_path: Option<String>,
+        replay_path: Option<String>,
+    ) -> Result<Self, ConfigError> {
+        match (record_path, replay_path) {
+            (Some(_), Some(_)) => {
+                Err(ConfigError::InvalidConfig(
+                    "Can't record and replay at the same time!".to_string(),
+                ))
+            }
+            (Some(path), None) => Ok(RecordingMode::Record(path)),
+            (None, Some(path)) => Ok(RecordingMode::Replay(path)),
+            (None, None) => Ok(RecordingMode::Off),
+        }
+    }
+}
+
+// Where and how often to send health event webhooks
+#[derive(Debug, Clone, PartialEq)]
+pub struct WebhookSettings {
+    pub urls: Vec<String>,
+    pub rate_limit: Duration,
+    pub retries: u32,
+}
+
+impl Default for WebhookSettings {
+    fn default() -> Self {
+        Self {
+            urls: Vec::new(),
+            rate_limit: Duration::from_secs(60),
+            retries: 3,
+        }
+    }
+}
+
+impl WebhookSettings {
+    // Parse the optional `[webhooks]` table
+    fn from_table(table: Option<&Value>) -> Result<Self, ConfigError> {
+        let table = match table {
+            Some(table) => {
+                table
+                    .as_table()
+                    .or_invalid("Could not parse webhooks table!")?
+            }
+            None => return Ok(WebhookSettings::default()),
+        };
+
+        let urls = match table.get("urls") {
+            Some(urls) => {
+                urls.as_array()
+                    .or_invalid("Could not parse webhook urls as array!")?
+                    .iter()
+                    .map(|url| {
+                        url.as_str()
+                            .map(str::to_string)
+                            .or_invalid("Could not parse webhook url as str!")
+                    })
+                    .collect::<Result<_, _>>()?
+            }
+            None => Vec::new(),
+        };
+        let rate_limit = match table.get("rate_limit_ms") {
+            Some(rate_limit) => {
+                Duration::from_millis(
+                    rate_limit
+                        .as_integer()
+                        .or_invalid("Could not parse webhook rate_limit_ms as int!")?
+                        as u64,
+                )
+            }
+            None => WebhookSettings::default().rate_limit,
+        };
+        let retries = match table.get("retries") {
+            Some(retries) => {
+                retries
+                    .as_integer()
+                    .or_invalid("Could not parse webhook retries as int!")? as u32
+            }
+            None => WebhookSettings::default().retries,
+        };
+
+        Ok(WebhookSettings {
+            urls,
+            rate_limit,
+            retries,
+        })
+    }
+}
+
+// An `eth_call` to make and cache on startup
+#[derive(Debug, Clone, PartialEq)]
+pub struct PrewarmCall {
+    pub to: String,
+    pub data: String,
+    pub block: String,
+}
+
+// What to fetch and cache before we start serving requests
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct PrewarmSettings {
+    pub blocks: u64,
+    pub chain_id: bool,
+    pub calls: Vec<PrewarmCall>,
+}
+
+impl PrewarmSettings {
+    pub fn is_enabled(&self) -> bool {
+        self.blocks > 0 || self.chain_id || !self.calls.is_empty()
+    }
+
+    // Parse the optional `[prewarm]` table
+    fn from_table(table: Option<&Value>) -> Result<Self, ConfigError> {
+        let table = match table {
+            Some(table) => {
+                table
+                    .as_table()
+                    .or_invalid("Could not parse prewarm table!")?
+            }
+            None => return Ok(PrewarmSettings::default()),
+        };
+
+        let blocks = match table.get("blocks") {
+            Some(blocks) => {
+                blocks
+                    .as_integer()
+                    .or_invalid("Could not parse prewarm blocks as int!")? as u64
+            }
+            None => 0,
+        };
+        let chain_id = match table.get("chain_id") {
+            Some(chain_id) => {
+                chain_id
+                    .as_bool()
+                    .or_invalid("Could not parse prewarm chain_id as bool!")?
+            }
+            None => false,
+        };
+        let calls = match table.get("calls") {
+            Some(calls) => {
+                calls
+                    .as_array()
+                    .or_invalid("Could not parse prewarm calls as array!")?
+                    .iter()
+                    .map(|call| {
+                        let field = |name: &str| {
+                            call.get(name)
+                                .map(|value| {
+                                    value
+                                        .as_str()
+                                        .ok_or_else(|| {
+                                            ConfigError::InvalidConfig(format!(
+                                                "Could not parse prewarm call {} as str!",
+                                                name
+                                            ))
+                                        })
+                                        .map(str::to_string)
+                                })
+                                .transpose()
+                        };
+
+                        Ok(PrewarmCall {
+                            to: field("to")?.or_invalid("Missing to from a prewarm call!")?,
+                            data: field("data")?.or_invalid("Missing data from a prewarm call!")?,
+                            block: field("block")?.unwrap_or("latest".to_string()),
+                        })
+                    })
+                    .collect::<Result<_, ConfigError>>()?
+            }
+            None => Vec::new(),
+        };
+
+        Ok(PrewarmSettings {
+            blocks,
+            chain_id,
+            calls,
+        })
+    }
+}
+
+// Thresholds for flagging nodes whose responses diverge from their peers
+#[derive(Debug, Clone, PartialEq)]
+pub struct AnomalySettings {
+    pub enabled: bool,
+    // How many blocks a node's head can be away from the median head
+    pub max_block_deviation: u64,
+    // How many times higher or lower than the median a gas price can be
+    pub max_gas_price_ratio: f64,
+    // Consecutive anomalies before a node is quarantined
+    pub strikes: u32,
+}
+
+impl Default for AnomalySettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            max_block_deviation: 32,
+            max_gas_price_ratio: 5.0,
+            strikes: 3,
+        }
+    }
+}
+
+impl AnomalySettings {
+    // Parse the optional `[anomaly]` table, detection is enabled if it's present
+    fn from_table(table: Option<&Value>) -> Result<Self, ConfigError> {
+        let table = match table {
+            Some(table) => {
+                table
+                    .as_table()
+                    .or_invalid("Could not parse anomaly table!")?
+            }
+            None => return Ok(AnomalySettings::default()),
+        };
+
+        let max_block_deviation = match table.get("max_block_deviation") {
+            Some(max_block_deviation) => {
+                max_block_deviation
+                    .as_integer()
+                    .or_invalid("Could not parse anomaly max_block_deviation as int!")?
+                    as u64
+            }
+            None => AnomalySettings::default().max_block_deviation,
+        };
+        let max_gas_price_ratio = match table.get("max_gas_price_ratio") {
+            Some(max_gas_price_ratio) => {
+                max_gas_price_ratio
+                    .as_float()
+                    .or_invalid("Could not parse anomaly max_gas_price_ratio as float!")?
+            }
+            None => AnomalySettings::default().max_gas_price_ratio,
+        };
+        let strikes = match table.get("strikes") {
+            Some(strikes) => {
+                strikes
+                    .as_integer()
+                    .or_invalid("Could not parse anomaly strikes as int!")? as u32
+            }
+            None => AnomalySettings::default().strikes,
+        };
+
+        Ok(AnomalySettings {
+            enabled: true,
+            max_block_deviation,
+            max_gas_price_ratio,
+            strikes: strikes.max(1),
+        })
+    }
+}
+
+// Optional HTTP/3 listener, only used with the `http3` feature
+#[derive(Debug, Clone, PartialEq)]
+pub struct Http3Settings {
+    pub address: SocketAddr,
+    pub cert: String,
+    pub key: String,
+    // Client certificate checks, same as `[blutgang.tls]`
+    pub client_ca: Option<String>,
+    pub require_client_cert: bool,
+    pub identities: BTreeMap<String, AuthPolicy>,
+}
+
+impl Http3Settings {
+    // Parse the optional `[blutgang.http3]` table. The certificate and key
+    // default to the ones in `[blutgang.tls]`, client certificates are
+    // always checked like there.
+    fn from_table(
+        table: Option<&Value>,
+        tls: Option<&ListenerTlsSettings>,
+    ) -> Result<Option<Self>, ConfigError> {
+        let table = match table {
+            Some(table) => {
+                table
+                    .as_table()
+                    .or_invalid("Could not parse http3 table!")?
+            }
+            None => return Ok(None),
+        };
+
+        let field = |key: &str| {
+            table
+                .get(key)
+                .map(|value| {
+                    value
+                        .as_str()
+                        .ok_or_else(|| {
+                            ConfigError::InvalidConfig(format!(
+                                "Could not parse http3 {} as str!",
+                                key
+                            ))
+                        })
+                        .map(str::to_string)
+                })
+                .transpose()
+        };
+
+        let address = field("address")?
+            .or_invalid("Missing address from http3!")?
+            .replace("localhost", "127.0.0.1")
+            .parse::<SocketAddr>()
+            .or_invalid("Could not parse http3 address to SocketAddr!")?;
+        let cert = field("cert")?
+            .or_else(|| tls.map(|tls| tls.cert.clone()))
+            .or_invalid("Missing cert from http3!")?;
+        let key = field("key")?
+            .or_else(|| tls.map(|tls| tls.key.clone()))
+            .or_invalid("Missing key from http3!")?;
+
+        Ok(Some(Http3Settings {
+            address,
+            cert,
+            key,
+            client_ca: tls.and_then(|tls| tls.client_ca.clone()),
+            require_client_cert: tls.is_some_and(|tls| tls.require_client_cert),
+            identities: tls.map(|tls| tls.identities.clone()).unwrap_or_default(),
+        }))
+    }
+}
+
+// Which browser origins can call us, and how often
+#[derive(Debug, Clone, PartialEq)]
+pub struct CorsSettings {
+    // `*` allows any origin
+    pub origins: Vec<String>,
+    pub headers: Vec<String>,
+    // How long browsers can cache preflight responses, in seconds
+    pub max_age: u64,
+    // Requests per second for each origin, 0 for no limit
+    pub rate_limit: u32,
+    // Overrides `rate_limit` for specific origins
+    pub rate_limits: BTreeMap<String, u32>,
+}
+
+impl Default for CorsSettings {
+    fn default() -> Self {
+        Self {
+            origins: vec!["*".to_string()],
+            headers: vec!["content-type".to_string()],
+            max_age: 86400,
+            rate_limit: 0,
+            rate_limits: BTreeMap::new(),
+        }
+    }
+}
+
+impl CorsSettings {
+    // Parse the optional `[cors]` table, any origin is allowed if it's not set
+    fn from_table(table: Option<&Value>) -> Result<Self, ConfigError> {
+        let table = match table {
+            Some(table) => table.as_table().or_invalid("Could not parse cors table!")?,
+            None => return Ok(CorsSettings::default()),
+        };
+
+        let list = |key: &str| {
+            table
+                .get(key)
+                .map(|list| {
+                    list.as_array()
+                        .and_then(|list| {
+                            list.iter()
+                                .map(|item| item.as_str().map(str::to_string))
+                                .collect::<Option<Vec<String>>>()
+                        })
+                        .ok_or_else(|| {
+                            ConfigError::InvalidConfig(format!(
+                                "cors {} must be a list of strings!",
+                                key
+                            ))
+                        })
+                })
+                .transpose()
+        };
+        let limit = |limit: &Value| {
+            limit
+                .as_integer()
+                .map(|limit| limit as u32)
+                .or_invalid("Could not parse cors rate limit as int!")
+        };
+
+        let max_age = match table.get("max_age") {
+            Some(max_age) => {
+                max_age
+                    .as_integer()
+                    .or_invalid("Could not parse cors max_age as int!")? as u64
+            }
+            None => CorsSettings::default().max_age,
+        };
+        let rate_limits = match table.get("rate_limits") {
+            Some(rate_limits) => {
+                rate_limits
+                    .as_table()
+                    .or_invalid("Could not parse cors rate_limits table!")?
+                    .iter()
+                    .map(|(origin, rate_limit)| Ok((origin.clone(), limit(rate_limit)?)))
+                    .collect::<Result<_, ConfigError>>()?
+            }
+            None => BTreeMap::new(),
+        };
+
+        Ok(CorsSettings {
+            origins: list("origins")?.unwrap_or_else(|| CorsSettings::default().origins),
+            headers: list("headers")?.unwrap_or_else(|| CorsSettings::default().headers),
+            max_age,
+            rate_limit: table.get("rate_limit").map(limit).transpose()?.unwrap_or(0),
+            rate_limits,
+        })
+    }
+}
+
+// A customer served on its own host names
+#[derive(Clone, PartialEq)]
+pub struct Tenant {
+    // Host names the tenant is reached on, lowercase. `*` catches hosts no
+    // other tenant lists.
+    pub hosts: Vec<String>,
+    // Only forward to nodes in this group, any node if not set
+    pub group: Option<String>,
+    // Keys clients send as `Authorization: Bearer <key>`, anyone if empty
+    pub keys: Vec<String>,
+    // Requests per second, 0 for no limit
+    pub rate_limit: u32,
+    // Cache entries are only shared between tenants with the same namespace
+    pub namespace: String,
+}
+
+impl Debug for Tenant {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Tenant {{")?;
+        write!(f, " hosts: {:?}", self.hosts)?;
+        write!(f, ", group: {:?}", self.group)?;
+        if !self.keys.is_empty() {
+            write!(f, ", keys: HIDDEN")?;
+        }
+        write!(f, ", rate_limit: {:?}", self.rate_limit)?;
+        write!(f, ", namespace: {:?}", self.namespace)?;
+        write!(f, " }}")
+    }
+}
+
+// Customers of a multi-tenant deployment, by name
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TenantSettings {
+    pub tenants: BTreeMap<String, Tenant>,
+}
+
+impl TenantSettings {
+    // Parse the optional `[tenants]` table, one subtable per tenant
+    fn from_table(table: Option<&Value>) -> Result<Self, ConfigError> {
+        let table = match table {
+            Some(table) => {
+                table
+                    .as_table()
+                    .or_invalid("Could not parse tenants table!")?
+            }
+            None => return Ok(TenantSettings::default()),
+        };
+
+        let mut tenants = BTreeMap::new();
+        for (name, tenant) in table {
+            let tenant = tenant.as_table().ok_or_else(|| {
+                ConfigError::InvalidConfig(format!("Could not parse tenant {}!", name))
+            })?;
+            let list = |field: &str| -> Result<Vec<String>, ConfigError> {
+                Ok(match tenant.get(field) {
+                    Some(list) => {
+                        list.as_array()
+                            .and_then(|list| {
+                                list.iter()
+                                    .map(|item| item.as_str().map(str::to_string))
+                                    .collect::<Option<Vec<String>>>()
+                            })
+                            .ok_or_else(|| {
+                                ConfigError::InvalidConfig(format!(
+                                    "Tenant {} {} must be a list of strings!",
+                                    name, field
+                                ))
+                            })?
+                    }
+                    None => Vec::new(),
+                })
+            };
+            let string = |field: &str| {
+                tenant
+                    .get(field)
+                    .map(|value| {
+                        value
+                            .as_str()
+                            .ok_or_else(|| {
+                                ConfigError::InvalidConfig(format!(
+                                    "Could not parse tenant {} {} as str!",
+                                    name, field
+                                ))
+                            })
+                            .map(str::to_string)
+                    })
+                    .transpose()
+            };
+
+            let hosts: Vec<String> = list("hosts")?
+                .iter()
+                .map(|host| host.to_lowercase())
+                .collect();
+            if hosts.is_empty() {
+                return Err(ConfigError::InvalidConfig(format!(
+                    "Tenant {} needs a list of hosts!",
+                    name
+                )));
+            }
+            let rate_limit = match tenant.get("rate_limit") {
+                Some(rate_limit) => {
+                    rate_limit
+                        .as_integer()
+                        .and_then(|rate_limit| u32::try_from(rate_limit).ok())
+                        .ok_or_else(|| {
+                            ConfigError::InvalidConfig(format!(
+                                "Tenant {} rate_limit has to be a positive int!",
+                                name
+                            ))
+                        })?
+                }
+                None => 0,
+            };
+
+            let namespace = string("namespace")?.unwrap_or_else(|| name.clone());
+            if namespace.contains('\0') {
+                return Err(ConfigError::InvalidConfig(format!(
+                    "Tenant {} namespace can't contain a NUL!",
+                    name
+                )));
+            }
+
+            tenants.insert(
+                name.clone(),
+                Tenant {
+                    hosts,
+                    group: string("group")?,
+                    keys: list("keys")?,
+                    rate_limit,
+                    namespace,
+                },
+            );
+        }
+
+        let mut hosts: Vec<&String> = tenants.values().flat_map(|tenant| &tenant.hosts).collect();
+        hosts.sort();
+        if let Some(host) = hosts.windows(2).find(|pair| pair[0] == pair[1]) {
+            return Err(ConfigError::InvalidConfig(format!(
+                "{} is a host of more than one tenant!",
+                host[0]
+            )));
+        }
+
+        Ok(TenantSettings { tenants })
+    }
+}
 
-use std::{
-    fmt,
-    fmt::Debug,
-    fs::{
-        self,
+// Beacon nodes we balance consensus layer requests between
+#[derive(Clone, PartialEq)]
+pub struct BeaconSettings {
+    // Requests under this path go to beacon nodes, without it
+    pub prefix: String,
+    pub nodes: Vec<String>,
+    pub health_check_interval: Duration,
+    // Slots a node can be behind the best head before we stop using it
+    pub max_slot_lag: u64,
+    pub timeout: Duration,
+    // Answers over this many bytes are dropped
+    pub max_response_size: usize,
+}
+
+impl Default for BeaconSettings {
+    fn default() -> Self {
+        Self {
+            prefix: "/beacon".to_string(),
+            nodes: Vec::new(),
+            health_check_interval: Duration::from_secs(5),
+            max_slot_lag: 2,
+            timeout: Duration::from_secs(10),
+            max_response_size: 256 * 1024 * 1024,
+        }
+    }
+}
+
+// Node urls can hold keys
+impl Debug for BeaconSettings {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let nodes: Vec<String> = self
+            .nodes
+            .iter()
+            .map(|url| sanitize_url(url).unwrap_or_else(|_| "HIDDEN".to_string()))
+            .collect();
+        write!(f, "BeaconSettings {{")?;
+        write!(f, " prefix: {:?}", self.prefix)?;
+        write!(f, ", nodes: {:?}", nodes)?;
+        write!(
+            f,
+            ", health_check_interval: {:?}",
+            self.health_check_interval
+        )?;
+        write!(f, ", max_slot_lag: {:?}", self.max_slot_lag)?;
+        write!(f, ", timeout: {:?}", self.timeout)?;
+        write!(f, ", max_response_size: {:?}", self.max_response_size)?;
+        write!(f, " }}")
+    }
+}
+
+impl BeaconSettings {
+    // Parse the optional `[beacon]` table
+    fn from_table(table: Option<&Value>) -> Result<Option<Self>, ConfigError> {
+        let table = match table {
+            Some(table) => {
+                table
+                    .as_table()
+                    .or_invalid("Could not parse beacon table!")?
+            }
+            None => return Ok(None),
+        };
+        let defaults = BeaconSettings::default();
+
+        let nodes: Vec<String> = table
+            .get("nodes")
+            .and_then(|nodes| nodes.as_array())
+            .and_then(|nodes| {
+                nodes
+                    .iter()
+                    .map(|node| node.as_str().map(str::to_string))
+                    .collect::<Option<Vec<String>>>()
+            })
+            .or_invalid("beacon nodes must be a list of urls!")?;
+        if nodes.is_empty() {
+            return Err(ConfigError::InvalidConfig(
+                "beacon needs at least one node!".to_string(),
+            ));
+        }
+
+        let prefix = match table.get("prefix") {
+            Some(prefix) => {
+                let prefix = prefix
+                    .as_str()
+                    .or_invalid("Could not parse beacon prefix as str!")?
+                    .trim_end_matches('/')
+                    .to_string();
+                if !prefix.starts_with('/') {
+                    return Err(ConfigError::InvalidConfig(
+                        "beacon prefix must start with /!".to_string(),
+                    ));
+                }
+                prefix
+            }
+            None => defaults.prefix,
+        };
+        let millis = |key: &str, default: Duration| -> Result<Duration, ConfigError> {
+            Ok(match table.get(key) {
+                Some(millis) => {
+                    let millis = millis.as_integer().ok_or_else(|| {
+                        ConfigError::InvalidConfig(format!(
+                            "Could not parse beacon {} as int!",
+                            key
+                        ))
+                    })?;
+                    if millis <= 0 {
+                        return Err(ConfigError::InvalidConfig(format!(
+                            "beacon {} must be positive!",
+                            key
+                        )));
+                    }
+                    Duration::from_millis(millis as u64)
+                }
+                None => default,
+            })
+        };
+        let max_slot_lag = match table.get("max_slot_lag") {
+            Some(max_slot_lag) => {
+                let max_slot_lag = max_slot_lag
+                    .as_integer()
+                    .or_invalid("Could not parse beacon max_slot_lag as int!")?;
+                if max_slot_lag < 0 {
+                    return Err(ConfigError::InvalidConfig(
+                        "beacon max_slot_lag can't be negative!".to_string(),
+                    ));
+                }
+                max_slot_lag as u64
+            }
+            None => defaults.max_slot_lag,
+        };
+        let max_response_size = match table.get("max_response_size") {
+            Some(max_response_size) => {
+                max_response_size
+                    .as_integer()
+                    .and_then(|size| usize::try_from(size).ok())
+                    .filter(|size| *size > 0)
+                    .or_invalid("beacon max_response_size has to be a positive int!")?
+            }
+            None => defaults.max_response_size,
+        };
+
+        Ok(Some(BeaconSettings {
+            prefix,
+            nodes,
+            health_check_interval: millis("health_check_interval", defaults.health_check_interval)?,
+            max_slot_lag,
+            timeout: millis("timeout", defaults.timeout)?,
+            max_response_size,
+        }))
+    }
+}
+
+// Where the firehose sends mirrored requests
+#[derive(Debug, Clone, PartialEq)]
+pub enum FirehoseSink {
+    // NDJSON file, appended to
+    File(String),
+    // NATS server as `host:port` and the subject to publish to
+    Nats { address: String, subject: String },
+    // Kafka REST proxy URL and the topic to produce to
+    Kafka { url: String, topic: String },
+}
+
+impl FirehoseSink {
+    pub fn name(&self) -> &'static str {
+        match self {
+            FirehoseSink::File(_) => "file",
+            FirehoseSink::Nats { .. } => "nats",
+            FirehoseSink::Kafka { .. } => "kafka",
+        }
+    }
+}
+
+// Mirror requests and responses to a sink for offline analysis
+#[derive(Debug, Clone, PartialEq)]
+pub struct FirehoseSettings {
+    pub sink: Option<FirehoseSink>,
+    // Share of requests to mirror, from 0 to 1
+    pub sample_rate: f64,
+    // Records we hold before dropping new ones
+    pub buffer_size: usize,
+}
+
+impl Default for FirehoseSettings {
+    fn default() -> Self {
+        Self {
+            sink: None,
+            sample_rate: 1.0,
+            buffer_size: 10000,
+        }
+    }
+}
+
+impl FirehoseSettings {
+    // Parse the optional `[firehose]` table
+    fn from_table(table: Option<&Value>) -> Result<Self, ConfigError> {
+        let table = match table {
+            Some(table) => {
+                table
+                    .as_table()
+                    .or_invalid("Could not parse firehose table!")?
+            }
+            None => return Ok(FirehoseSettings::default()),
+        };
+
+        let field = |name: &str| {
+            table
+                .get(name)
+                .map(|value| {
+                    value
+                        .as_str()
+                        .ok_or_else(|| {
+                            ConfigError::InvalidConfig(format!(
+                                "Could not parse firehose {} as str!",
+                                name
+                            ))
+                        })
+                        .map(str::to_string)
+                })
+                .transpose()
+        };
+
+        let target = || field("target")?.or_invalid("Missing target from the firehose!");
+        let topic = || field("topic")?.or_invalid("Missing topic from the firehose!");
+        let sink = match field("sink")?.as_deref() {
+            Some("file") => FirehoseSink::File(target()?),
+            Some("nats") => {
+                FirehoseSink::Nats {
+                    address: target()?.trim_start_matches("nats://").to_string(),
+                    subject: topic()?,
+                }
+            }
+            Some("kafka") => {
+                FirehoseSink::Kafka {
+                    url: target()?.trim_end_matches('/').to_string(),
+                    topic: topic()?,
+                }
+            }
+            Some(_) => {
+                return Err(ConfigError::InvalidConfig(
+                    "Firehose sink must be file, nats or kafka!".to_string(),
+                ))
+            }
+            None => {
+                return Err(ConfigError::InvalidConfig(
+                    "Missing sink from the firehose!".to_string(),
+                ))
+            }
+        };
+
+        let sample_rate = match table.get("sample_rate") {
+            Some(sample_rate) => {
+                let sample_rate = sample_rate
+                    .as_float()
+                    .or_invalid("Could not parse firehose sample_rate as float!")?;
+                if !(0.0..=1.0).contains(&sample_rate) {
+                    return Err(ConfigError::InvalidConfig(
+                        "Firehose sample_rate must be between 0 and 1!".to_string(),
+                    ));
+                }
+                sample_rate
+            }
+            None => FirehoseSettings::default().sample_rate,
+        };
+        let buffer_size = match table.get("buffer_size") {
+            Some(buffer_size) => {
+                buffer_size
+                    .as_integer()
+                    .or_invalid("Could not parse firehose buffer_size as int!")?
+                    as usize
+            }
+            None => FirehoseSettings::default().buffer_size,
+        };
+
+        Ok(FirehoseSettings {
+            sink: Some(sink),
+            sample_rate,
+            buffer_size,
+        })
+    }
+}
+
+// What to do with wallet methods like `eth_accounts`, `eth_sign` and
+// `eth_sendTransaction`. Forwarding them to whatever node is next in line is
+// never right, so they're rejected unless a node or signer holding the keys
+// is configured.
+#[derive(Debug, Clone, Default)]
+pub enum WalletPolicy {
+    #[default]
+    Reject,
+    // Node with the accounts unlocked, not part of the regular rotation
+    Node(Rpc),
+    // External signer. `eth_sendTransaction` gets signed by it and broadcast
+    // through the regular rotation.
+    Signer {
+        rpc: Rpc,
+        kind: SignerKind,
     },
-    net::SocketAddr,
-    println,
-};
+}
 
-use toml::Value;
+// API spoken by the external signer
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SignerKind {
+    #[default]
+    Web3Signer,
+    Clef,
+}
+
+impl FromStr for SignerKind {
+    type Err = ConfigError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "web3signer" => Ok(SignerKind::Web3Signer),
+            "clef" => Ok(SignerKind::Clef),
+            _ => Err(ConfigError::BadConfig),
+        }
+    }
+}
+
+impl WalletPolicy {
+    // Parse the optional `[wallet]` table, its node introduces itself as
+    // `identity` unless the table says otherwise
+    fn from_table(table: Option<&Value>, identity: &UpstreamIdentity) -> Result<Self, ConfigError> {
+        let table = match table {
+            Some(table) => {
+                table
+                    .as_table()
+                    .or_invalid("Could not parse wallet table!")?
+            }
+            None => return Ok(WalletPolicy::default()),
+        };
+
+        let policy = match table.get("policy") {
+            Some(policy) => {
+                policy
+                    .as_str()
+                    .or_invalid("Could not parse wallet policy as str!")?
+            }
+            None => "reject",
+        };
+        let rpc = || -> Result<Rpc, ConfigError> {
+            let url = table
+                .get("url")
+                .or_invalid("Missing wallet url!")?
+                .as_str()
+                .or_invalid("Could not parse wallet url as str!")?;
+            Ok(Rpc::new(url.to_string(), None, 0, 0, 1.0)
+                .with_identity(UpstreamIdentity::from_table(table, identity)?))
+        };
+
+        match policy.to_lowercase().as_str() {
+            "reject" => Ok(WalletPolicy::Reject),
+            "node" => Ok(WalletPolicy::Node(rpc()?)),
+            "signer" => {
+                let kind = match table.get("signer") {
+                    Some(kind) => {
+                        kind.as_str()
+                            .or_invalid("Could not parse wallet signer as str!")?
+                            .parse::<SignerKind>()
+                            .or_invalid("Wallet signer must be web3signer or clef!")?
+                    }
+                    None => SignerKind::default(),
+                };
+                Ok(WalletPolicy::Signer { rpc: rpc()?, kind })
+            }
+            _ => {
+                Err(ConfigError::InvalidConfig(
+                    "Wallet policy must be reject, node or signer!".to_string(),
+                ))
+            }
+        }
+    }
+}
 
 #[derive(Clone)]
 pub struct AdminSettings {
@@ -31,6 +3706,7 @@ pub struct AdminSettings {
     pub readonly: bool,
     pub jwt: bool,
     pub key: DecodingKey,
+    pub jsonrpc_mode: JsonRpcMode,
 }
 
 impl Default for AdminSettings {
@@ -41,6 +3717,7 @@ impl Default for AdminSettings {
             readonly: false,
             jwt: false,
             key: DecodingKey::from_secret(b""),
+            jsonrpc_mode: JsonRpcMode::default(),
         }
     }
 }
@@ -52,6 +3729,7 @@ impl Debug for AdminSettings {
         write!(f, ", address: {:?}", self.address)?;
         write!(f, ", readonly: {:?}", self.readonly)?;
         write!(f, ", jwt: HIDDEN",)?;
+        write!(f, ", jsonrpc_mode: {:?}", self.jsonrpc_mode)?;
         write!(f, " }}")
     }
 }
@@ -62,12 +3740,74 @@ pub struct Settings {
     pub is_ws: bool,
     pub do_clear: bool,
     pub address: SocketAddr,
+    pub tls: Option<ListenerTlsSettings>,
+    pub http3: Option<Http3Settings>,
     pub health_check: bool,
     pub ttl: u128,
+    pub adaptive_timeouts: Option<AdaptiveTimeouts>,
     pub expected_block_time: u64,
     pub supress_rpc_check: bool,
     pub max_retries: u32,
     pub health_check_ttl: u64,
+    pub subscription_stall_multiplier: u64,
+    pub jsonrpc_mode: JsonRpcMode,
+    pub recording: RecordingMode,
+    pub plugins: Vec<String>,
+    pub verify_proofs: bool,
+    pub validate_responses: bool,
+    pub http_get: bool,
+    pub rest: Option<RestSettings>,
+    pub beacon_url: Option<String>,
+    // Root of the beacon block the light client bootstraps its sync committee from
+    pub light_client_checkpoint: Option<[u8; 32]>,
+    pub memory_budget: Option<usize>,
+    pub eviction_policy: EvictionPolicy,
+    pub hot_cache_size: usize,
+    pub ens_cache_ttl: Option<u64>,
+    pub estimate_gas: Option<EstimateGasSettings>,
+    pub nonce_tracking: bool,
+    pub rebroadcast: Option<RebroadcastSettings>,
+    pub head_retry: Option<HeadRetrySettings>,
+    pub fork_choice: Option<ForkChoiceSettings>,
+    pub consistent_reads: Option<ConsistentReadsSettings>,
+    pub routing_hints: Option<RoutingHintsSettings>,
+    pub history_routing: Option<HistoryRoutingSettings>,
+    pub deprecations: Option<DeprecationSettings>,
+    pub usage: Option<UsageSettings>,
+    pub replay_protection: Option<ReplayProtectionSettings>,
+    pub revalidation: Option<RevalidationSettings>,
+    pub scoring: Option<ScoringSettings>,
+    pub canary: Option<CanarySettings>,
+    pub event_rate_limit: Option<EventRateLimitSettings>,
+    pub coalesce_new_heads: bool,
+    pub dispatch_workers: usize,
+    pub idle_connections: Option<IdleSettings>,
+    pub ws_sessions: Option<WsSessionSettings>,
+    pub regions: Option<RegionSettings>,
+    pub cluster: Option<ClusterSettings>,
+    pub log_index: Option<LogIndexSettings>,
+    pub receipts: Option<ReceiptStoreSettings>,
+    pub quorum: Option<QuorumSettings>,
+    pub selection: SelectionMode,
+    pub passthrough: Option<PassthroughSettings>,
+    pub request_body: Option<RequestBodySettings>,
+    pub schedules: Option<ScheduleSettings>,
+    pub fallback: Option<FallbackSettings>,
+    pub error_map: Option<ErrorMapSettings>,
+    pub rate_limits: RateLimitSettings,
+    pub peer_count: PeerCountSettings,
+    pub upstream_identity: UpstreamIdentity,
+    pub log_file: Option<String>,
+    pub log_rotation: LogRotation,
+    pub config_path: Option<String>,
+    pub prewarm: PrewarmSettings,
+    pub firehose: FirehoseSettings,
+    pub anomaly: AnomalySettings,
+    pub cors: CorsSettings,
+    pub tenants: TenantSettings,
+    pub beacon: Option<BeaconSettings>,
+    pub wallet: WalletPolicy,
+    pub webhooks: WebhookSettings,
     pub sled_config: Config,
     pub admin: AdminSettings,
 }
@@ -79,12 +3819,73 @@ impl Default for Settings {
             is_ws: true,
             do_clear: false,
             address: "127.0.0.1:3000".parse::<SocketAddr>().unwrap(),
+            tls: None,
+            http3: None,
             health_check: false,
             ttl: 1000,
+            adaptive_timeouts: None,
             expected_block_time: 12500,
             supress_rpc_check: true,
             max_retries: 32,
             health_check_ttl: 1000,
+            subscription_stall_multiplier: 3,
+            jsonrpc_mode: JsonRpcMode::default(),
+            recording: RecordingMode::default(),
+            plugins: Vec::new(),
+            verify_proofs: false,
+            validate_responses: true,
+            http_get: false,
+            rest: None,
+            beacon_url: None,
+            light_client_checkpoint: None,
+            memory_budget: None,
+            eviction_policy: EvictionPolicy::default(),
+            hot_cache_size: 16 * 1024 * 1024,
+            ens_cache_ttl: None,
+            estimate_gas: None,
+            nonce_tracking: false,
+            rebroadcast: None,
+            head_retry: None,
+            fork_choice: None,
+            consistent_reads: None,
+            routing_hints: None,
+            history_routing: None,
+            deprecations: None,
+            usage: None,
+            replay_protection: None,
+            revalidation: None,
+            scoring: None,
+            canary: None,
+            event_rate_limit: None,
+            coalesce_new_heads: false,
+            dispatch_workers: 1,
+            idle_connections: None,
+            ws_sessions: None,
+            regions: None,
+            cluster: None,
+            log_index: None,
+            receipts: None,
+            quorum: None,
+            selection: SelectionMode::default(),
+            passthrough: None,
+            request_body: None,
+            schedules: None,
+            fallback: None,
+            error_map: None,
+            rate_limits: RateLimitSettings::default(),
+            peer_count: PeerCountSettings::default(),
+            upstream_identity: UpstreamIdentity::default(),
+            log_file: None,
+            log_rotation: LogRotation::default(),
+            config_path: None,
+            prewarm: PrewarmSettings::default(),
+            firehose: FirehoseSettings::default(),
+            anomaly: AnomalySettings::default(),
+            cors: CorsSettings::default(),
+            tenants: TenantSettings::default(),
+            beacon: None,
+            wallet: WalletPolicy::default(),
+            webhooks: WebhookSettings::default(),
             sled_config: sled::Config::default(),
             admin: AdminSettings::default(),
         }
@@ -92,9 +3893,7 @@ impl Default for Settings {
 }
 
 impl Settings {
-    pub async fn new(matches: Command) -> Settings {
-        let matches = matches.get_matches();
-
+    pub async fn new(matches: ArgMatches) -> Settings {
         // Try to open the file at the path specified in the args
         let path = matches.get_one::<String>("config").unwrap();
         let file: Option<String> = match fs::read_to_string(path) {
@@ -104,7 +3903,10 @@ impl Settings {
 
         if let Some(file) = file {
             log_info!("Using config file at {}", path);
-            return Settings::create_from_file(file).await;
+            let mut settings = Settings::create_from_file(file).await;
+            // So we can reload it later
+            settings.config_path = Some(path.to_string());
+            return settings;
         }
 
         log_info!("Using command line arguments for settings...");
@@ -112,7 +3914,30 @@ impl Settings {
     }
 
     async fn create_from_file(conf_file: String) -> Settings {
-        let parsed_toml = conf_file.parse::<Value>().expect("Error parsing TOML");
+        let (mut settings, sort_ma_length) = Settings::parse_toml(&conf_file)
+            .unwrap_or_else(|e| panic!("\x1b[31mErr:\x1b[0m {}", e));
+
+        if let Some(ma_length) = sort_ma_length {
+            println!("Sorting RPCs by latency...");
+            settings.rpc_list = match sort_by_latency(settings.rpc_list, ma_length).await {
+                Ok(rax) => rax,
+                Err(e) => {
+                    panic!("{:?}", e);
+                }
+            };
+        }
+
+        settings
+    }
+
+    // Parse a config file without touching the network.
+    //
+    // Also returns the moving average length to sort RPCs by latency with,
+    // if they should be sorted on startup.
+    pub fn parse_toml(conf_file: &str) -> Result<(Settings, Option<f64>), ConfigError> {
+        let parsed_toml = conf_file
+            .parse::<Value>()
+            .or_invalid("Error parsing TOML")?;
 
         // `is_ws` flag is used to turn off WS specific things when a WS endpoint isnt present.
         let mut is_ws = true;
@@ -122,24 +3947,24 @@ impl Settings {
         // Parse the `blutgang` table
         let blutgang_table = parsed_toml
             .get("blutgang")
-            .expect("\x1b[31mErr:\x1b[0m Missing blutgang table!")
+            .or_invalid("Missing blutgang table!")?
             .as_table()
-            .expect("\x1b[31mErr:\x1b[0m Could not parse blutgang table!");
+            .or_invalid("Could not parse blutgang table!")?;
         let do_clear = blutgang_table
             .get("do_clear")
-            .expect("\x1b[31mErr:\x1b[0m Missing do_clear toggle!")
+            .or_invalid("Missing do_clear toggle!")?
             .as_bool()
-            .expect("\x1b[31mErr:\x1b[0m Could not parse do_clear as bool!");
+            .or_invalid("Could not parse do_clear as bool!")?;
         let address = blutgang_table
             .get("address")
-            .expect("\x1b[31mErr:\x1b[0m Missing address!")
+            .or_invalid("Missing address!")?
             .as_str()
-            .expect("\x1b[31mErr:\x1b[0m Could not parse address as str!");
+            .or_invalid("Could not parse address as str!")?;
         let sort_on_startup = blutgang_table
             .get("sort_on_startup")
-            .expect("\x1b[31mErr:\x1b[0m Missing sort_on_startup toggle!")
+            .or_invalid("Missing sort_on_startup toggle!")?
             .as_bool()
-            .expect("\x1b[31mErr:\x1b[0m Could not parse sort_on_startup as bool!");
+            .or_invalid("Could not parse sort_on_startup as bool!")?;
 
         // Build the SocketAddr
         let port = 3000;
@@ -153,31 +3978,34 @@ impl Settings {
         };
         let address = address
             .parse::<SocketAddr>()
-            .expect("\x1b[31mErr:\x1b[0m Could not address to SocketAddr!");
+            .or_invalid("Could not address to SocketAddr!")?;
 
         let ma_length = blutgang_table
             .get("ma_length")
-            .expect("\x1b[31mErr:\x1b[0m Missing ma_length!")
+            .or_invalid("Missing ma_length!")?
             .as_integer()
-            .expect("\x1b[31mErr:\x1b[0m Could not parse ma_length as int!")
-            as f64;
+            .or_invalid("Could not parse ma_length as int!")? as f64;
 
         let health_check = blutgang_table
             .get("health_check")
-            .expect("\x1b[31mErr:\x1b[0m Missing health_check toggle!")
+            .or_invalid("Missing health_check toggle!")?
             .as_bool()
-            .expect("\x1b[31mErr:\x1b[0m Could not parse health_check as bool!");
+            .or_invalid("Could not parse health_check as bool!")?;
         let ttl = blutgang_table
             .get("ttl")
-            .expect("\x1b[31mErr:\x1b[0m Missing ttl!")
+            .or_invalid("Missing ttl!")?
             .as_integer()
-            .expect("\x1b[31mErr:\x1b[0m Could not parse ttl as int!") as u128;
+            .or_invalid("Could not parse ttl as int!")? as u128;
+
+        // Everything times out after `ttl` if not set
+        let adaptive_timeouts =
+            AdaptiveTimeouts::from_table(blutgang_table.get("adaptive_timeouts"))?;
 
         let mut expected_block_time = blutgang_table
             .get("expected_block_time")
-            .expect("\x1b[31mErr:\x1b[0m Missing ttl!")
+            .or_invalid("Missing ttl!")?
             .as_integer()
-            .expect("\x1b[31mErr:\x1b[0m Could not parse ttl as int!")
+            .or_invalid("Could not parse ttl as int!")?
             as u64;
         if expected_block_time == 0 {
             log_wrn!("Expected_block_time is 0, turning off WS and health checks!");
@@ -189,67 +4017,417 @@ impl Settings {
 
         let max_retries = blutgang_table
             .get("max_retries")
-            .expect("\x1b[31mErr:\x1b[0m Missing max_retries!")
+            .or_invalid("Missing max_retries!")?
             .as_integer()
-            .expect("\x1b[31mErr:\x1b[0m Could not parse max_retries as int!")
-            as u32;
+            .or_invalid("Could not parse max_retries as int!")? as u32;
 
         let health_check_ttl = if health_check {
             blutgang_table
                 .get("health_check_ttl")
-                .expect("\x1b[31mErr:\x1b[0m Missing health_check_ttl!")
+                .or_invalid("Missing health_check_ttl!")?
                 .as_integer()
-                .expect("\x1b[31mErr:\x1b[0m Could not parse health_check_ttl as int!")
-                as u64
+                .or_invalid("Could not parse health_check_ttl as int!")? as u64
         } else {
             u64::MAX
         };
 
         let supress_rpc_check = blutgang_table
             .get("supress_rpc_check")
-            .expect("\x1b[31mErr:\x1b[0m Missing supress_rpc_check!")
+            .or_invalid("Missing supress_rpc_check!")?
             .as_bool()
-            .expect("\x1b[31mErr:\x1b[0m Could not parse supress_rpc_check as bool!");
+            .or_invalid("Could not parse supress_rpc_check as bool!")?;
+
+        // Optional, 0 disables the stalled subscription watchdog
+        let subscription_stall_multiplier =
+            match blutgang_table.get("subscription_stall_multiplier") {
+                Some(multiplier) => {
+                    multiplier
+                        .as_integer()
+                        .or_invalid("Could not parse subscription_stall_multiplier as int!")?
+                        as u64
+                }
+                None => Settings::default().subscription_stall_multiplier,
+            };
+
+        // Optional, defaults to `off` so older configs keep working
+        let jsonrpc_mode = match blutgang_table.get("jsonrpc_mode") {
+            Some(jsonrpc_mode) => {
+                jsonrpc_mode
+                    .as_str()
+                    .or_invalid("Could not parse jsonrpc_mode as str!")?
+                    .parse::<JsonRpcMode>()
+                    .or_invalid("jsonrpc_mode must be off, lenient or strict!")?
+            }
+            None => JsonRpcMode::default(),
+        };
+
+        // Record/replay paths are optional
+        let record_path = blutgang_table
+            .get("record_path")
+            .map(|path| {
+                path.as_str()
+                    .map(str::to_string)
+                    .or_invalid("Could not parse record_path as str!")
+            })
+            .transpose()?;
+        let replay_path = blutgang_table
+            .get("replay_path")
+            .map(|path| {
+                path.as_str()
+                    .map(str::to_string)
+                    .or_invalid("Could not parse replay_path as str!")
+            })
+            .transpose()?;
+        let recording = RecordingMode::from_paths(record_path, replay_path)?;
+
+        // Optional list of WASM plugins, run in the order they are listed
+        let plugins = match blutgang_table.get("plugins") {
+            Some(plugins) => {
+                plugins
+                    .as_array()
+                    .or_invalid("Could not parse plugins as array!")?
+                    .iter()
+                    .map(|path| {
+                        path.as_str()
+                            .map(str::to_string)
+                            .or_invalid("Could not parse plugin path as str!")
+                    })
+                    .collect::<Result<_, _>>()?
+            }
+            None => Vec::new(),
+        };
+
+        // Optional, cross-check state queries with `eth_getProof` from another node
+        let verify_proofs = match blutgang_table.get("verify_proofs") {
+            Some(verify_proofs) => {
+                verify_proofs
+                    .as_bool()
+                    .or_invalid("Could not parse verify_proofs as bool!")?
+            }
+            None => false,
+        };
+
+        // Check the shape of responses to well-known methods before using them
+        let validate_responses = match blutgang_table.get("validate_responses") {
+            Some(validate_responses) => {
+                validate_responses
+                    .as_bool()
+                    .or_invalid("Could not parse validate_responses as bool!")?
+            }
+            None => true,
+        };
+
+        // Only POSTed JSON-RPC is answered if not set
+        let http_get = match blutgang_table.get("http_get") {
+            Some(http_get) => {
+                http_get
+                    .as_bool()
+                    .or_invalid("Could not parse http_get as bool!")?
+            }
+            None => false,
+        };
+
+        // No REST paths if not set
+        let rest = RestSettings::from_table(blutgang_table.get("rest"))?;
+
+        // Optional beacon node used to check that heads are canonical
+        let beacon_url = blutgang_table
+            .get("beacon_url")
+            .map(|url| {
+                url.as_str()
+                    .map(str::to_string)
+                    .or_invalid("Could not parse beacon_url as str!")
+            })
+            .transpose()?;
+        // Heads are only as trustworthy as the sync committee we start from
+        let light_client_checkpoint = blutgang_table
+            .get("light_client_checkpoint")
+            .map(|root| {
+                let root = root
+                    .as_str()
+                    .or_invalid("Could not parse light_client_checkpoint as str!")?;
+                hex::decode(root.strip_prefix("0x").unwrap_or(root))
+                    .ok()
+                    .and_then(|root| <[u8; 32]>::try_from(root).ok())
+                    .or_invalid("light_client_checkpoint must be a 32 byte block root!")
+            })
+            .transpose()?;
+        if beacon_url.is_some() && light_client_checkpoint.is_none() {
+            return Err(ConfigError::InvalidConfig(
+                "beacon_url needs a light_client_checkpoint to verify the sync committee!"
+                    .to_string(),
+            ));
+        }
+
+        // Optional cap on how many bytes cached responses and subscription
+        // buffers can take up. 0 means no cap.
+        let memory_budget = match blutgang_table.get("memory_budget") {
+            Some(memory_budget) => {
+                let memory_budget = memory_budget
+                    .as_integer()
+                    .or_invalid("Could not parse memory_budget as int!")?;
+                (memory_budget > 0).then_some(memory_budget as usize)
+            }
+            None => None,
+        };
+        let eviction_policy = match blutgang_table.get("eviction_policy") {
+            Some(eviction_policy) => {
+                eviction_policy
+                    .as_str()
+                    .or_invalid("Could not parse eviction_policy as str!")?
+                    .parse::<EvictionPolicy>()
+                    .or_invalid("eviction_policy must be lru or lfu!")?
+            }
+            None => EvictionPolicy::default(),
+        };
+
+        // Bytes of the most read cache entries kept in memory in front of
+        // the DB. 0 disables the in-memory tier.
+        let hot_cache_size = match blutgang_table.get("hot_cache_size") {
+            Some(hot_cache_size) => {
+                hot_cache_size
+                    .as_integer()
+                    .filter(|hot_cache_size| *hot_cache_size >= 0)
+                    .or_invalid("Could not parse hot_cache_size as a positive int!")?
+                    as usize
+            }
+            None => Settings::default().hot_cache_size,
+        };
+
+        // How long ENS lookups are cached for in ms. 0 disables the ENS cache.
+        let ens_cache_ttl = match blutgang_table.get("ens_cache_ttl") {
+            Some(ens_cache_ttl) => {
+                let ens_cache_ttl = ens_cache_ttl
+                    .as_integer()
+                    .or_invalid("Could not parse ens_cache_ttl as int!")?;
+                (ens_cache_ttl > 0).then_some(ens_cache_ttl as u64)
+            }
+            None => None,
+        };
+
+        // Estimates come from a single node if not set
+        let estimate_gas = EstimateGasSettings::from_table(blutgang_table.get("estimate_gas"))?;
+
+        // Optional, answer `blutgang_getNextNonce` with nonces we've seen broadcast
+        let nonce_tracking = match blutgang_table.get("nonce_tracking") {
+            Some(nonce_tracking) => {
+                nonce_tracking
+                    .as_bool()
+                    .or_invalid("Could not parse nonce_tracking as bool!")?
+            }
+            None => false,
+        };
+
+        // Optional, send slow WS users only the latest of the `newHeads` events
+        // queued for them
+        let coalesce_new_heads = match blutgang_table.get("coalesce_new_heads") {
+            Some(coalesce_new_heads) => {
+                coalesce_new_heads
+                    .as_bool()
+                    .or_invalid("Could not parse coalesce_new_heads as bool!")?
+            }
+            None => false,
+        };
+
+        // Optional, tasks delivering WS subscription events. One per core if
+        // not set or 0.
+        let dispatch_workers = match blutgang_table.get("dispatch_workers") {
+            Some(dispatch_workers) => {
+                dispatch_workers
+                    .as_integer()
+                    .and_then(|dispatch_workers| usize::try_from(dispatch_workers).ok())
+                    .or_invalid("dispatch_workers has to be a non-negative int!")?
+            }
+            None => 0,
+        };
+        let dispatch_workers = match dispatch_workers {
+            0 => std::thread::available_parallelism().map_or(1, |cores| cores.get()),
+            dispatch_workers => dispatch_workers,
+        };
+
+        // Every request sees whatever `latest` is when it's served if not set
+        let consistent_reads =
+            ConsistentReadsSettings::from_table(blutgang_table.get("consistent_reads"))?;
+
+        // Clients can't pick nodes or skip the cache if not set
+        let routing_hints = RoutingHintsSettings::from_table(blutgang_table.get("routing_hints"))?;
+
+        // Historical calls go through the regular rotation if not set
+        let history_routing =
+            HistoryRoutingSettings::from_table(blutgang_table.get("history_routing"))?;
+
+        // Deprecated methods are served without a warning if not set
+        let deprecations = DeprecationSettings::from_table(blutgang_table.get("deprecations"))?;
+
+        // Upstream errors are passed on as they are if not set
+        let error_map = ErrorMapSettings::from_table(blutgang_table.get("error_map"))?;
+
+        // Nodes that rate limit us get a cooldown starting at a second,
+        // doubling up to a minute, if not set
+        let rate_limits = RateLimitSettings::from_table(blutgang_table.get("rate_limits"))?;
+
+        // `net_peerCount` is the sum over our nodes, asked every 5 seconds at
+        // most, if not set
+        let peer_count = PeerCountSettings::from_table(blutgang_table.get("peer_count"))?;
+
+        // Nodes see us as `blutgang/<version>` with no origin if not set. Nodes
+        // can set their own `user_agent` and `origin` on top of these.
+        let upstream_identity =
+            UpstreamIdentity::from_table(blutgang_table, &UpstreamIdentity::default())?;
+
+        // Calls aren't counted per client if not set
+        let usage = UsageSettings::from_table(blutgang_table.get("usage"))?;
+
+        // Nodes on different forks are only caught by the regular health check if not set
+        let fork_choice = ForkChoiceSettings::from_table(blutgang_table.get("fork_choice"))?;
+
+        // Clients can send the same raw transaction as often as they like if not set
+        let replay_protection =
+            ReplayProtectionSettings::from_table(blutgang_table.get("replay_protection"))?;
+
+        // Hot `latest` requests are only fetched when clients ask for them if not set
+        let revalidation = RevalidationSettings::from_table(blutgang_table.get("revalidation"))?;
+
+        // Nodes are ranked by latency alone if not set
+        let scoring = ScoringSettings::from_table(blutgang_table.get("scoring"))?;
+
+        // Nodes added at runtime get all their traffic right away if not set
+        let canary = CanarySettings::from_table(blutgang_table.get("canary"))?;
+
+        // Users get every subscription event if not set
+        let event_rate_limit =
+            EventRateLimitSettings::from_table(blutgang_table.get("event_rate_limit"))?;
+
+        // WS connections stay open until the client closes them if not set
+        let idle_connections = IdleSettings::from_table(blutgang_table.get("idle_connections"))?;
+
+        // WS subscriptions end with their connection if not set
+        let ws_sessions = WsSessionSettings::from_table(blutgang_table.get("ws_sessions"))?;
+
+        // Nodes are picked regardless of where they run if not set
+        let regions = RegionSettings::from_table(blutgang_table.get("regions"))?;
+
+        // Every instance caches for itself if not set
+        let cluster = ClusterSettings::from_table(blutgang_table.get("cluster"))?;
+
+        // `eth_getLogs` always goes upstream if not set
+        let log_index = LogIndexSettings::from_table(blutgang_table.get("log_index"))?;
+
+        // `eth_getTransactionReceipt` always goes upstream or to the cache if not set
+        let receipts = ReceiptStoreSettings::from_table(blutgang_table.get("receipts"))?;
+
+        // A single node's answer is trusted if not set
+        let quorum = QuorumSettings::from_table(blutgang_table.get("quorum"))?;
+
+        // Optional, defaults to `latency` so older configs keep working
+        let selection = match blutgang_table.get("selection") {
+            Some(selection) => {
+                selection
+                    .as_str()
+                    .or_invalid("Could not parse selection as str!")?
+                    .parse::<SelectionMode>()
+                    .or_invalid("selection must be latency or rendezvous!")?
+            }
+            None => SelectionMode::default(),
+        };
+
+        // Nodes only see our own headers if not set
+        let passthrough = PassthroughSettings::from_table(blutgang_table.get("passthrough"))?;
+
+        // Request bodies are read whole, however big, if not set
+        let request_body = RequestBodySettings::from_table(blutgang_table.get("request_body"))?;
+
+        // Routing is the same at all times if not set
+        let schedules = ScheduleSettings::from_table(blutgang_table.get("schedules"))?;
+
+        // Requests fail when our own nodes can't answer them if not set
+        let fallback = FallbackSettings::from_table(blutgang_table.get("fallback"))?;
+
+        // Transactions are only broadcast once if not set
+        let rebroadcast = RebroadcastSettings::from_table(blutgang_table.get("rebroadcast"))?;
+
+        // "Not found" answers for blocks at the head go back to the client if not set
+        let head_retry = HeadRetrySettings::from_table(blutgang_table.get("head_retry"))?;
+
+        // Logs only go to stdout if not set
+        let log_file = blutgang_table
+            .get("log_file")
+            .map(|path| {
+                path.as_str()
+                    .map(str::to_string)
+                    .or_invalid("Could not parse log_file as str!")
+            })
+            .transpose()?;
+        // Never rotated if not set
+        let log_rotation = LogRotation::from_table(blutgang_table.get("log_rotation"))?;
+
+        // Downstream connections are plain HTTP if not set
+        let tls = ListenerTlsSettings::from_table(blutgang_table.get("tls"))?;
+        let http3 = Http3Settings::from_table(blutgang_table.get("http3"), tls.as_ref())?;
+
+        // Webhooks for health events are optional
+        let webhooks = WebhookSettings::from_table(parsed_toml.get("webhooks"))?;
+
+        // Nothing gets fetched on startup if not set
+        let prewarm = PrewarmSettings::from_table(parsed_toml.get("prewarm"))?;
+
+        // Requests aren't mirrored anywhere if not set
+        let firehose = FirehoseSettings::from_table(parsed_toml.get("firehose"))?;
+
+        // Nodes are never quarantined if not set
+        let anomaly = AnomalySettings::from_table(parsed_toml.get("anomaly"))?;
+
+        // Any origin is allowed without limits if not set
+        let cors = CorsSettings::from_table(parsed_toml.get("cors"))?;
+
+        // Every host is served the same way if not set
+        let tenants = TenantSettings::from_table(parsed_toml.get("tenants"))?;
+
+        // Requests only go to our execution nodes if not set
+        let beacon = BeaconSettings::from_table(parsed_toml.get("beacon"))?;
+
+        // Where wallet methods go, rejected if not set
+        let wallet = WalletPolicy::from_table(parsed_toml.get("wallet"), &upstream_identity)?;
 
         // Parse `sled` table
         let sled_table = parsed_toml
             .get("sled")
-            .expect("\x1b[31mErr:\x1b[0m Missing sled table!")
+            .or_invalid("Missing sled table!")?
             .as_table()
-            .expect("\x1b[31mErr:\x1b[0m Could not parse sled_table as table!");
+            .or_invalid("Could not parse sled_table as table!")?;
         let db_path = sled_table
             .get("db_path")
-            .expect("\x1b[31mErr:\x1b[0m Missing db_path!")
+            .or_invalid("Missing db_path!")?
             .as_str()
-            .expect("\x1b[31mErr:\x1b[0m Could not parse db_path as str!");
+            .or_invalid("Could not parse db_path as str!")?;
         let cache_capacity = sled_table
             .get("cache_capacity")
-            .expect("\x1b[31mErr:\x1b[0m Missing cache_capacity!")
+            .or_invalid("Missing cache_capacity!")?
             .as_integer()
-            .expect("\x1b[31mErr:\x1b[0m Could not parse cache_capacity as int!")
+            .or_invalid("Could not parse cache_capacity as int!")?
             as usize;
         let compression = sled_table
             .get("compression")
-            .expect("\x1b[31mErr:\x1b[0m Missing compression toggle!")
+            .or_invalid("Missing compression toggle!")?
             .as_bool()
-            .expect("\x1b[31mErr:\x1b[0m Could not parse compression as bool!");
+            .or_invalid("Could not parse compression as bool!")?;
         let print_profile = sled_table
             .get("print_profile")
-            .expect("\x1b[31mErr:\x1b[0m Missing print profile toggle!")
+            .or_invalid("Missing print profile toggle!")?
             .as_bool()
-            .expect("\x1b[31mErr:\x1b[0m Could not parse print_profile as bool!");
+            .or_invalid("Could not parse print_profile as bool!")?;
         let flush_every_ms = sled_table
             .get("flush_every_ms")
-            .expect("\x1b[31mErr:\x1b[0m Missing flush_every_ms!")
+            .or_invalid("Missing flush_every_ms!")?
             .as_integer()
-            .expect("\x1b[31mErr:\x1b[0m Could not parse flush_every_ms as int!");
+            .or_invalid("Could not parse flush_every_ms as int!")?;
 
         // Parse sled mode
         let sled_mode_str = sled_table
             .get("mode")
-            .expect("\x1b[31mErr:\x1b[0m Missing sled_mode!")
+            .or_invalid("Missing sled_mode!")?
             .as_str()
-            .expect("\x1b[31mErr:\x1b[0m Could not parse sled_mode as str!");
+            .or_invalid("Could not parse sled_mode as str!")?;
         let mut sled_mode = sled::Mode::HighThroughput;
 
         if sled_mode_str == "LowSpace" {
@@ -259,7 +4437,11 @@ impl Settings {
         // Create sled config
         let sled_config = Config::new()
             .path(db_path)
-            .cache_capacity(cache_capacity.try_into().unwrap())
+            .cache_capacity(
+                cache_capacity
+                    .try_into()
+                    .or_invalid("cache_capacity has to be a positive int!")?,
+            )
             .mode(sled_mode)
             .flush_every_ms(Some(flush_every_ms as u64))
             .print_profile_on_drop(print_profile)
@@ -271,34 +4453,45 @@ impl Settings {
 
         let mut rpc_list: Vec<Rpc> = Vec::new();
         for table_name in table_names {
-            if table_name != "blutgang" && table_name != "sled" && table_name != "admin" {
-                let rpc_table = parsed_toml.get(table_name).unwrap().as_table().unwrap();
+            if table_name != "blutgang"
+                && table_name != "sled"
+                && table_name != "admin"
+                && table_name != "webhooks"
+                && table_name != "wallet"
+                && table_name != "prewarm"
+                && table_name != "firehose"
+                && table_name != "anomaly"
+                && table_name != "cors"
+                && table_name != "tenants"
+                && table_name != "beacon"
+            {
+                let rpc_table = parsed_toml[table_name].as_table().ok_or_else(|| {
+                    ConfigError::InvalidConfig(format!("Could not parse {} table!", table_name))
+                })?;
 
                 let max_consecutive = rpc_table
                     .get("max_consecutive")
-                    .expect("\x1b[31mErr:\x1b[0m Missing max_consecutive from an RPC!")
+                    .or_invalid("Missing max_consecutive from an RPC!")?
                     .as_integer()
-                    .expect("\x1b[31mErr:\x1b[0m Could not parse max_consecutive as int!")
+                    .or_invalid("Could not parse max_consecutive as int!")?
                     as u32;
 
                 let mut delta = rpc_table
                     .get("max_per_second")
-                    .expect("\x1b[31mErr:\x1b[0m Missing max_per_second from an RPC!")
+                    .or_invalid("Missing max_per_second from an RPC!")?
                     .as_integer()
-                    .expect("\x1b[31mErr:\x1b[0m Could not parse max_per_second as int!")
+                    .or_invalid("Could not parse max_per_second as int!")?
                     as u64;
 
                 // If the delta time isnt 0, we need to get how many microsecond need to pass
                 // before we can send a new request
-                if delta != 0 {
-                    delta = 1_000_000 / delta;
-                }
+                delta = 1_000_000u64.checked_div(delta).unwrap_or(0);
 
                 let url = rpc_table
                     .get("url")
-                    .expect("\x1b[31mErr:\x1b[0m Missing URL from RPC!")
+                    .or_invalid("Missing URL from RPC!")?
                     .as_str()
-                    .expect("\x1b[31mErr:\x1b[0m Could not parse URL from a RPC as str!")
+                    .or_invalid("Could not parse URL from a RPC as str!")?
                     .to_string();
 
                 // ws_url is an Option<>
@@ -309,7 +4502,7 @@ impl Settings {
                         Some(
                             ws_url
                                 .as_str()
-                                .expect("\x1b[31mErr:\x1b[0m Could not parse ws_url as str!")
+                                .or_invalid("Could not parse ws_url as str!")?
                                 .to_string(),
                         )
                     }
@@ -319,7 +4512,93 @@ impl Settings {
                     }
                 };
 
-                let rpc = Rpc::new(url, ws_url, max_consecutive, delta.into(), ma_length);
+                let rpc = Rpc::new(url, ws_url, max_consecutive, delta.into(), ma_length)
+                    .with_identity(UpstreamIdentity::from_table(rpc_table, &upstream_identity)?);
+
+                // Optional `[rpc_name.tls]` table for nodes behind an internal PKI
+                let rpc = match TlsSettings::from_table(rpc_table.get("tls"))? {
+                    Some(tls) => {
+                        rpc.with_tls(TlsConfig::load(&tls).map_err(|e| {
+                            ConfigError::InvalidConfig(format!(
+                                "Invalid TLS settings for {}: {}",
+                                table_name, e
+                            ))
+                        })?)
+                    }
+                    None => rpc,
+                };
+
+                // Calls against the pending block go to nodes with `pending_state` set
+                let pending_state = match rpc_table.get("pending_state") {
+                    Some(pending_state) => {
+                        pending_state
+                            .as_bool()
+                            .or_invalid("Could not parse pending_state as bool!")?
+                    }
+                    None => false,
+                };
+                let rpc = rpc.with_pending_state(pending_state);
+
+                // Nodes can be targeted as a group through `/group/<name>`
+                let groups = match rpc_table.get("groups") {
+                    Some(groups) => {
+                        groups
+                            .as_array()
+                            .and_then(|groups| {
+                                groups
+                                    .iter()
+                                    .map(|group| group.as_str().map(str::to_string))
+                                    .collect::<Option<Vec<String>>>()
+                            })
+                            .or_invalid("Node groups must be a list of strings!")?
+                    }
+                    None => Vec::new(),
+                };
+                let rpc = rpc.with_groups(groups);
+
+                // Known quirks of what the node runs, e.g. `profile = "alchemy"`
+                let profile = rpc_table.get("profile").map(|profile| {
+                    profile
+                        .as_str()
+                        .and_then(|profile| profile.parse::<Provider>().ok())
+                        .or_invalid("Node profile must be alchemy, infura, erigon, geth, nethermind or besu!")
+                })
+                .transpose()?;
+                let rpc = rpc.with_profile(profile);
+
+                // Where the node runs, see `[blutgang.regions]`
+                let region = rpc_table
+                    .get("region")
+                    .map(|region| {
+                        region
+                            .as_str()
+                            .map(str::to_string)
+                            .or_invalid("Could not parse node region as str!")
+                    })
+                    .transpose()?;
+                let rpc = rpc.with_region(region);
+
+                // Where the node serves GraphQL, requests to `/graphql` only go
+                // to nodes that have this
+                let graphql_url = rpc_table
+                    .get("graphql_url")
+                    .map(|graphql_url| {
+                        graphql_url
+                            .as_str()
+                            .map(str::to_string)
+                            .or_invalid("Could not parse graphql_url as str!")
+                    })
+                    .transpose()?;
+                let rpc = rpc.with_graphql_url(graphql_url);
+
+                // Optional `[rpc_name.budget]` table for nodes with request caps
+                let rpc =
+                    rpc.with_budget(BudgetSettings::from_table(rpc_table.get("budget"))?.as_ref());
+
+                // Optional `[rpc_name.chaos]` table for fault injection
+                #[cfg(feature = "chaos")]
+                let rpc = rpc.with_chaos(FaultInjection::from_table(rpc_table.get("chaos")));
+
                 rpc_list.push(rpc);
             }
         }
@@ -332,49 +4611,63 @@ impl Settings {
         // Admin namespace things
         let admin_table = parsed_toml
             .get("admin")
-            .expect("\x1b[31mErr:\x1b[0m Missing admin table!")
+            .or_invalid("Missing admin table!")?
             .as_table()
-            .expect("\x1b[31mErr:\x1b[0m Could not parse admin table!");
+            .or_invalid("Could not parse admin table!")?;
         let enabled = admin_table
             .get("enabled")
-            .expect("\x1b[31mErr:\x1b[0m Missing admin enabled toggle!")
+            .or_invalid("Missing admin enabled toggle!")?
             .as_bool()
-            .expect("\x1b[31mErr:\x1b[0m Could not parse admin enabled as bool!");
+            .or_invalid("Could not parse admin enabled as bool!")?;
         let admin = if enabled {
             let address = admin_table
                 .get("address")
-                .expect("\x1b[31mErr:\x1b[0m Missing address!")
+                .or_invalid("Missing address!")?
                 .as_str()
-                .expect("\x1b[31mErr:\x1b[0m Could not parse admin address!");
+                .or_invalid("Could not parse admin address!")?;
             let address = address.replace("localhost", "127.0.0.1");
             let readonly = admin_table
                 .get("readonly")
-                .expect("\x1b[31mErr:\x1b[0m Missing readonly toggle!")
+                .or_invalid("Missing readonly toggle!")?
                 .as_bool()
-                .expect("\x1b[31mErr:\x1b[0m Could not parse readonly as bool!");
+                .or_invalid("Could not parse readonly as bool!")?;
             let jwt = admin_table
                 .get("jwt")
-                .expect("\x1b[31mErr:\x1b[0m Missing JWT token toggle!")
+                .or_invalid("Missing JWT token toggle!")?
                 .as_bool()
-                .expect("\x1b[31mErr:\x1b[0m Could not parse JWT as bool!");
+                .or_invalid("Could not parse JWT as bool!")?;
 
             let key = if jwt {
                 admin_table
                     .get("key")
-                    .expect("\x1b[31mErr:\x1b[0m Missing key key!")
+                    .or_invalid("Missing key key!")?
                     .as_str()
-                    .expect("\x1b[31mErr:\x1b[0m Could not parse key as str!")
+                    .or_invalid("Could not parse key as str!")?
                     .to_string()
             } else {
                 String::new()
             };
 
+            let jsonrpc_mode = match admin_table.get("jsonrpc_mode") {
+                Some(jsonrpc_mode) => {
+                    jsonrpc_mode
+                        .as_str()
+                        .or_invalid("Could not parse admin jsonrpc_mode as str!")?
+                        .parse::<JsonRpcMode>()
+                        .or_invalid("Admin jsonrpc_mode must be off, lenient or strict!")?
+                }
+                None => JsonRpcMode::default(),
+            };
+
             AdminSettings {
                 enabled,
-                address: address.parse::<SocketAddr>().unwrap(),
+                address: address
+                    .parse::<SocketAddr>()
+                    .or_invalid("Could not parse admin address to SocketAddr!")?,
                 readonly,
                 jwt,
                 key: DecodingKey::from_secret(key.as_bytes()),
+                jsonrpc_mode,
             }
         } else {
             AdminSettings {
@@ -383,33 +4676,90 @@ impl Settings {
                 readonly: false,
                 jwt: false,
                 key: DecodingKey::from_secret(b""),
+                jsonrpc_mode: JsonRpcMode::default(),
             }
         };
 
-        if sort_on_startup {
-            println!("Sorting RPCs by latency...");
-            rpc_list = match sort_by_latency(rpc_list, ma_length).await {
-                Ok(rax) => rax,
-                Err(e) => {
-                    panic!("{:?}", e);
-                }
-            };
-        }
-
-        Settings {
+        let settings = Settings {
             rpc_list,
             is_ws,
             do_clear,
             address,
+            tls,
+            http3,
             health_check,
             ttl,
+            adaptive_timeouts,
             expected_block_time,
             max_retries,
             health_check_ttl,
             supress_rpc_check,
+            subscription_stall_multiplier,
+            jsonrpc_mode,
+            recording,
+            plugins,
+            verify_proofs,
+            validate_responses,
+            http_get,
+            rest,
+            beacon_url,
+            light_client_checkpoint,
+            memory_budget,
+            eviction_policy,
+            hot_cache_size,
+            ens_cache_ttl,
+            estimate_gas,
+            nonce_tracking,
+            rebroadcast,
+            head_retry,
+            fork_choice,
+            consistent_reads,
+            routing_hints,
+            history_routing,
+            deprecations,
+            usage,
+            replay_protection,
+            revalidation,
+            scoring,
+            canary,
+            event_rate_limit,
+            coalesce_new_heads,
+            dispatch_workers,
+            idle_connections,
+            ws_sessions,
+            regions,
+            cluster,
+            log_index,
+            receipts,
+            quorum,
+            selection,
+            passthrough,
+            request_body,
+            schedules,
+            fallback,
+            error_map,
+            rate_limits,
+            peer_count,
+            upstream_identity,
+            log_file,
+            log_rotation,
+            config_path: None,
+            prewarm,
+            firehose,
+            anomaly,
+            cors,
+            tenants,
+            beacon,
+            wallet,
+            webhooks,
             sled_config,
             admin,
-        }
+        };
+
+        Ok((
+            settings.for_recording(),
+            sort_on_startup.then_some(ma_length),
+        ))
     }
 
     fn create_from_matches(matches: ArgMatches) -> Settings {
@@ -429,9 +4779,7 @@ impl Settings {
             .expect("Invalid max_per_second")
             .to_owned();
 
-        if delta != 0 {
-            delta = 1_000_000 / delta;
-        }
+        delta = 1_000_000u64.checked_div(delta).unwrap_or(0);
 
         // Turn the rpc_list into a csv vec
         let rpc_list: Vec<&str> = rpc_list.split(',').collect();
@@ -516,6 +4864,18 @@ impl Settings {
             .get_one::<bool>("supress_rpc_check")
             .expect("Invalid supress_rpc_check");
 
+        let jsonrpc_mode = matches
+            .get_one::<String>("jsonrpc_mode")
+            .expect("Invalid jsonrpc_mode")
+            .parse::<JsonRpcMode>()
+            .expect("Invalid jsonrpc_mode");
+
+        let recording = RecordingMode::from_paths(
+            matches.get_one::<String>("record").cloned(),
+            matches.get_one::<String>("replay").cloned(),
+        )
+        .unwrap_or_else(|e| panic!("\x1b[31mErr:\x1b[0m {}", e));
+
         // Admin thing setup
         let enabled = matches.get_occurrences::<String>("admin").is_some();
         let admin = if enabled {
@@ -532,6 +4892,7 @@ impl Settings {
                 readonly,
                 jwt,
                 key: DecodingKey::from_secret(key.as_bytes()),
+                jsonrpc_mode,
             }
         } else {
             AdminSettings {
@@ -540,6 +4901,7 @@ impl Settings {
                 readonly: false,
                 jwt: false,
                 key: DecodingKey::from_secret(b""),
+                jsonrpc_mode: JsonRpcMode::default(),
             }
         };
 
@@ -548,14 +4910,85 @@ impl Settings {
             is_ws: false,
             do_clear: clear,
             address,
+            tls: None,
+            http3: None,
             health_check,
             ttl,
+            adaptive_timeouts: None,
             supress_rpc_check,
             expected_block_time,
             max_retries,
             health_check_ttl,
+            subscription_stall_multiplier: Settings::default().subscription_stall_multiplier,
+            jsonrpc_mode,
+            recording,
+            plugins: Vec::new(),
+            verify_proofs: false,
+            validate_responses: true,
+            http_get: false,
+            rest: None,
+            beacon_url: None,
+            light_client_checkpoint: None,
+            memory_budget: None,
+            eviction_policy: EvictionPolicy::default(),
+            hot_cache_size: Settings::default().hot_cache_size,
+            ens_cache_ttl: None,
+            estimate_gas: None,
+            nonce_tracking: false,
+            rebroadcast: None,
+            head_retry: None,
+            fork_choice: None,
+            consistent_reads: None,
+            routing_hints: None,
+            history_routing: None,
+            deprecations: None,
+            usage: None,
+            replay_protection: None,
+            revalidation: None,
+            scoring: None,
+            canary: None,
+            event_rate_limit: None,
+            coalesce_new_heads: false,
+            dispatch_workers: 1,
+            idle_connections: None,
+            ws_sessions: None,
+            regions: None,
+            cluster: None,
+            log_index: None,
+            receipts: None,
+            quorum: None,
+            selection: SelectionMode::default(),
+            passthrough: None,
+            request_body: None,
+            schedules: None,
+            fallback: None,
+            error_map: None,
+            rate_limits: RateLimitSettings::default(),
+            peer_count: PeerCountSettings::default(),
+            upstream_identity: UpstreamIdentity::default(),
+            log_file: None,
+            log_rotation: LogRotation::default(),
+            config_path: None,
+            prewarm: PrewarmSettings::default(),
+            firehose: FirehoseSettings::default(),
+            anomaly: AnomalySettings::default(),
+            cors: CorsSettings::default(),
+            tenants: TenantSettings::default(),
+            beacon: None,
+            wallet: WalletPolicy::default(),
+            webhooks: WebhookSettings::default(),
             sled_config,
             admin,
         }
+        .for_recording()
+    }
+
+    // There are no nodes to check or subscribe to when replaying
+    fn for_recording(mut self) -> Self {
+        if matches!(self.recording, RecordingMode::Replay(_)) {
+            self.health_check = false;
+            self.is_ws = false;
+        }
+        self
     }
 }
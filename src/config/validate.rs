@@ -0,0 +1,1692 @@
+// Dry runs and reloads for config changes.
+//
+// Parses a proposed config and reports what would change compared to the one
+// we're running. Settings we read on every request or health check take
+// effect as soon as they're changed, the rest need a restart and are reported
+// as such.
+use crate::{
+    config::{
+        error::ConfigError,
+        types::{
+            Settings,
+            WalletPolicy,
+        },
+    },
+    Rpc,
+};
+
+use std::{
+    collections::BTreeMap,
+    sync::{
+        Arc,
+        RwLock,
+    },
+};
+
+use serde_json::{
+    json,
+    Value,
+};
+
+// Settings we pick up without a restart
+const LIVE_SETTINGS: [&str; 25] = [
+    "ttl",
+    "adaptive_timeouts",
+    "max_retries",
+    "health_check_ttl",
+    "supress_rpc_check",
+    "jsonrpc_mode",
+    "verify_proofs",
+    "validate_responses",
+    "http_get",
+    "rest",
+    "wallet",
+    "scoring",
+    "canary",
+    "coalesce_new_heads",
+    "idle_connections",
+    "error_map",
+    "rate_limits",
+    "regions",
+    "quorum",
+    "head_retry",
+    "selection",
+    "passthrough",
+    "request_body",
+    "schedules",
+    "peer_count",
+];
+
+// Parse a proposed config file, returning why it's invalid if it is
+pub fn validate_config(proposed: &str) -> Result<Settings, ConfigError> {
+    Settings::parse_toml(proposed).map(|(settings, _)| settings)
+}
+
+fn wallet_name(wallet: &WalletPolicy) -> String {
+    match wallet {
+        WalletPolicy::Reject => "reject".to_string(),
+        WalletPolicy::Node(rpc) => format!("node {}", rpc.name),
+        WalletPolicy::Signer { rpc, kind } => format!("{:?} signer {}", kind, rpc.name),
+    }
+}
+
+// Values we compare, keyed by their name in the config
+fn comparable(settings: &Settings) -> BTreeMap<&'static str, Value> {
+    BTreeMap::from([
+        ("address", json!(settings.address)),
+        ("do_clear", json!(settings.do_clear)),
+        ("tls.enabled", json!(settings.tls.is_some())),
+        (
+            "http3.address",
+            json!(settings.http3.as_ref().map(|http3| http3.address)),
+        ),
+        (
+            "tls.identities",
+            json!(settings
+                .tls
+                .as_ref()
+                .map(|tls| tls.identities.keys().collect::<Vec<_>>())),
+        ),
+        ("health_check", json!(settings.health_check)),
+        ("ttl", json!(settings.ttl)),
+        (
+            "adaptive_timeouts",
+            json!(settings.adaptive_timeouts.as_ref().map(|timeouts| {
+                json!({
+                    "factor": timeouts.factor,
+                    "min": timeouts.min,
+                    "max": timeouts.max,
+                    "min_samples": timeouts.min_samples,
+                })
+            })),
+        ),
+        ("expected_block_time", json!(settings.expected_block_time)),
+        ("max_retries", json!(settings.max_retries)),
+        ("health_check_ttl", json!(settings.health_check_ttl)),
+        ("supress_rpc_check", json!(settings.supress_rpc_check)),
+        (
+            "subscription_stall_multiplier",
+            json!(settings.subscription_stall_multiplier),
+        ),
+        (
+            "jsonrpc_mode",
+            json!(format!("{:?}", settings.jsonrpc_mode).to_lowercase()),
+        ),
+        ("recording", json!(format!("{:?}", settings.recording))),
+        (
+            "log_rotation",
+            json!(format!("{:?}", settings.log_rotation)),
+        ),
+        ("prewarm", json!(format!("{:?}", settings.prewarm))),
+        ("plugins", json!(settings.plugins)),
+        ("verify_proofs", json!(settings.verify_proofs)),
+        ("validate_responses", json!(settings.validate_responses)),
+        ("http_get", json!(settings.http_get)),
+        (
+            "rest",
+            json!(settings.rest.as_ref().map(|rest| format!("{:?}", rest))),
+        ),
+        ("beacon_url", json!(settings.beacon_url)),
+        (
+            "light_client_checkpoint",
+            json!(settings.light_client_checkpoint.map(hex::encode)),
+        ),
+        ("memory_budget", json!(settings.memory_budget)),
+        (
+            "eviction_policy",
+            json!(format!("{:?}", settings.eviction_policy).to_lowercase()),
+        ),
+        ("hot_cache_size", json!(settings.hot_cache_size)),
+        ("ens_cache_ttl", json!(settings.ens_cache_ttl)),
+        (
+            "estimate_gas",
+            json!(settings
+                .estimate_gas
+                .as_ref()
+                .map(|estimate_gas| format!("{:?}", estimate_gas))),
+        ),
+        ("nonce_tracking", json!(settings.nonce_tracking)),
+        ("coalesce_new_heads", json!(settings.coalesce_new_heads)),
+        ("dispatch_workers", json!(settings.dispatch_workers)),
+        (
+            "consistent_reads",
+            json!(settings
+                .consistent_reads
+                .as_ref()
+                .map(|consistent_reads| consistent_reads.session_ttl.as_millis() as u64)),
+        ),
+        (
+            "deprecations",
+            json!(settings
+                .deprecations
+                .as_ref()
+                .map(|deprecations| format!("{:?}", deprecations))),
+        ),
+        (
+            "usage",
+            json!(settings.usage.as_ref().map(|usage| format!("{:?}", usage))),
+        ),
+        (
+            "history_routing",
+            json!(settings
+                .history_routing
+                .as_ref()
+                .map(|history_routing| format!("{:?}", history_routing))),
+        ),
+        (
+            "replay_protection",
+            json!(settings
+                .replay_protection
+                .as_ref()
+                .map(|replay_protection| format!("{:?}", replay_protection))),
+        ),
+        (
+            "revalidation",
+            json!(settings
+                .revalidation
+                .as_ref()
+                .map(|revalidation| format!("{:?}", revalidation))),
+        ),
+        (
+            "scoring",
+            json!(settings
+                .scoring
+                .as_ref()
+                .map(|scoring| format!("{:?}", scoring))),
+        ),
+        (
+            "canary",
+            json!(settings
+                .canary
+                .as_ref()
+                .map(|canary| format!("{:?}", canary))),
+        ),
+        (
+            "event_rate_limit",
+            json!(settings
+                .event_rate_limit
+                .as_ref()
+                .map(|event_rate_limit| format!("{:?}", event_rate_limit))),
+        ),
+        ("rate_limits", json!(format!("{:?}", settings.rate_limits))),
+        ("peer_count", json!(format!("{:?}", settings.peer_count))),
+        (
+            "upstream_identity",
+            json!(format!("{:?}", settings.upstream_identity)),
+        ),
+        (
+            "error_map",
+            json!(settings
+                .error_map
+                .as_ref()
+                .map(|error_map| format!("{:?}", error_map))),
+        ),
+        (
+            "idle_connections",
+            json!(settings
+                .idle_connections
+                .as_ref()
+                .map(|idle_connections| format!("{:?}", idle_connections))),
+        ),
+        (
+            "regions",
+            json!(settings
+                .regions
+                .as_ref()
+                .map(|regions| format!("{:?}", regions))),
+        ),
+        (
+            "cluster",
+            json!(settings
+                .cluster
+                .as_ref()
+                .map(|cluster| format!("{:?}", cluster))),
+        ),
+        (
+            "log_index",
+            json!(settings
+                .log_index
+                .as_ref()
+                .map(|log_index| format!("{:?}", log_index))),
+        ),
+        (
+            "receipts",
+            json!(settings.receipts.as_ref().map(|receipts| receipts.max_size)),
+        ),
+        (
+            "quorum",
+            json!(settings
+                .quorum
+                .as_ref()
+                .map(|quorum| format!("{:?}", quorum))),
+        ),
+        (
+            "selection",
+            json!(format!("{:?}", settings.selection).to_lowercase()),
+        ),
+        (
+            "passthrough",
+            json!(settings
+                .passthrough
+                .as_ref()
+                .map(|passthrough| format!("{:?}", passthrough))),
+        ),
+        (
+            "request_body",
+            json!(settings
+                .request_body
+                .map(|request_body| format!("{:?}", request_body))),
+        ),
+        (
+            "schedules",
+            json!(settings
+                .schedules
+                .as_ref()
+                .map(|schedules| format!("{:?}", schedules))),
+        ),
+        (
+            "fallback",
+            json!(settings
+                .fallback
+                .as_ref()
+                .map(|fallback| format!("{:?}", fallback))),
+        ),
+        (
+            "ws_sessions",
+            json!(settings
+                .ws_sessions
+                .as_ref()
+                .map(|ws_sessions| ws_sessions.ttl.as_millis() as u64)),
+        ),
+        (
+            "routing_hints",
+            json!(settings
+                .routing_hints
+                .as_ref()
+                .map(|routing_hints| format!("{:?}", routing_hints))),
+        ),
+        (
+            "fork_choice",
+            json!(settings
+                .fork_choice
+                .as_ref()
+                .map(|fork_choice| fork_choice.threshold.as_millis() as u64)),
+        ),
+        (
+            "head_retry",
+            json!(settings
+                .head_retry
+                .as_ref()
+                .map(|head_retry| format!("{:?}", head_retry))),
+        ),
+        (
+            "rebroadcast",
+            json!(settings
+                .rebroadcast
+                .as_ref()
+                .map(|rebroadcast| format!("{:?}", rebroadcast))),
+        ),
+        ("log_file", json!(settings.log_file)),
+        ("wallet", json!(wallet_name(&settings.wallet))),
+        ("webhooks", json!(settings.webhooks.urls.len())),
+        (
+            "firehose.sink",
+            json!(settings.firehose.sink.as_ref().map(|sink| sink.name())),
+        ),
+        ("firehose.sample_rate", json!(settings.firehose.sample_rate)),
+        ("anomaly.enabled", json!(settings.anomaly.enabled)),
+        ("cors.origins", json!(settings.cors.origins)),
+        ("cors.rate_limit", json!(settings.cors.rate_limit)),
+        ("tenants", json!(format!("{:?}", settings.tenants))),
+        ("beacon", json!(format!("{:?}", settings.beacon))),
+        ("admin.enabled", json!(settings.admin.enabled)),
+        ("admin.address", json!(settings.admin.address)),
+        ("admin.readonly", json!(settings.admin.readonly)),
+        ("admin.jwt", json!(settings.admin.jwt)),
+    ])
+}
+
+// Report how `proposed` differs from `current`.
+//
+// `nodes` are the nodes we're currently using, which might differ from the
+// ones in `current` if they were changed through the admin namespace.
+pub fn diff_config(current: &Settings, nodes: &[Rpc], proposed: &Settings) -> Value {
+    let current_values = comparable(current);
+    let proposed_values = comparable(proposed);
+
+    let mut changed = serde_json::Map::new();
+    let mut requires_restart = Vec::new();
+    for (name, from) in &current_values {
+        let to = &proposed_values[name];
+        if from == to {
+            continue;
+        }
+
+        changed.insert(name.to_string(), json!({"from": from, "to": to}));
+        if !LIVE_SETTINGS.contains(name) {
+            requires_restart.push(*name);
+        }
+    }
+
+    // Nodes are identified by their sanitized URL so we don't leak keys
+    let current_nodes: Vec<&String> = nodes.iter().map(|rpc| &rpc.name).collect();
+    let proposed_nodes: Vec<&String> = proposed.rpc_list.iter().map(|rpc| &rpc.name).collect();
+    let added: Vec<&&String> = proposed_nodes
+        .iter()
+        .filter(|name| !current_nodes.contains(name))
+        .collect();
+    let removed: Vec<&&String> = current_nodes
+        .iter()
+        .filter(|name| !proposed_nodes.contains(name))
+        .collect();
+
+    json!({
+        "changed": changed,
+        "nodesAdded": added,
+        "nodesRemoved": removed,
+        "requiresRestart": requires_restart,
+    })
+}
+
+// Apply `proposed` to the config we're running.
+//
+// Live settings are copied over and nodes are added or removed to match
+// `proposed`. Everything else is left as is and listed in `requiresRestart`.
+pub fn apply_config(
+    config: &Arc<RwLock<Settings>>,
+    rpc_list: &Arc<RwLock<Vec<Rpc>>>,
+    poverty_list: &Arc<RwLock<Vec<Rpc>>>,
+    proposed: &Settings,
+) -> Value {
+    let mut rpc_list = rpc_list.write().unwrap_or_else(|e| e.into_inner());
+    let mut poverty_list = poverty_list.write().unwrap_or_else(|e| e.into_inner());
+    let mut config = config.write().unwrap_or_else(|e| e.into_inner());
+
+    // Nodes in the poverty list are still ours, just unhealthy
+    let nodes: Vec<Rpc> = rpc_list
+        .iter()
+        .chain(poverty_list.iter())
+        .cloned()
+        .collect();
+    let diff = diff_config(&config, &nodes, proposed);
+
+    config.ttl = proposed.ttl;
+    config.adaptive_timeouts = proposed.adaptive_timeouts.clone();
+    config.max_retries = proposed.max_retries;
+    config.health_check_ttl = proposed.health_check_ttl;
+    config.supress_rpc_check = proposed.supress_rpc_check;
+    config.jsonrpc_mode = proposed.jsonrpc_mode;
+    config.verify_proofs = proposed.verify_proofs;
+    config.validate_responses = proposed.validate_responses;
+    config.http_get = proposed.http_get;
+    config.rest = proposed.rest.clone();
+    config.wallet = proposed.wallet.clone();
+    config.scoring = proposed.scoring.clone();
+    config.canary = proposed.canary.clone();
+    config.coalesce_new_heads = proposed.coalesce_new_heads;
+    config.idle_connections = proposed.idle_connections.clone();
+    config.error_map = proposed.error_map.clone();
+    config.rate_limits = proposed.rate_limits;
+    config.peer_count = proposed.peer_count;
+    config.regions = proposed.regions.clone();
+    config.quorum = proposed.quorum.clone();
+    config.head_retry = proposed.head_retry.clone();
+    config.selection = proposed.selection;
+    config.passthrough = proposed.passthrough.clone();
+    config.request_body = proposed.request_body;
+    config.schedules = proposed.schedules.clone();
+
+    let keep = |rpc: &Rpc| proposed.rpc_list.iter().any(|new| new.name == rpc.name);
+    rpc_list.retain(keep);
+    poverty_list.retain(keep);
+    for rpc in &proposed.rpc_list {
+        if !nodes.iter().any(|current| current.name == rpc.name) {
+            rpc_list.push(rpc.clone().with_canary(config.canary.as_ref()));
+        }
+    }
+
+    diff
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::types::{
+            ErrorMatch,
+            EventLimitPolicy,
+            PeerCountAggregate,
+            PeerCountSettings,
+            RateLimitSettings,
+            RestSettings,
+            SelectionMode,
+            UpstreamIdentity,
+            UsageFormat,
+            WarningPlacement,
+        },
+        rpc::{
+            latency::MethodLatencies,
+            profile::Provider,
+        },
+    };
+    use std::time::Duration;
+
+    const CONFIG: &str = r#"
+        [blutgang]
+        do_clear = false
+        address = "127.0.0.1:3000"
+        ma_length = 100
+        sort_on_startup = false
+        health_check = false
+        ttl = 30
+        max_retries = 32
+        expected_block_time = 13000
+        supress_rpc_check = false
+
+        [admin]
+        enabled = false
+
+        [sled]
+        db_path = "./blutgang-cache"
+        mode = "HighThroughput"
+        cache_capacity = 1000000000
+        compression = false
+        print_profile = false
+        flush_every_ms = 24000
+
+        [node]
+        url = "https://node.example.com"
+        max_consecutive = 150
+        max_per_second = 200
+    "#;
+
+    #[test]
+    fn test_validate_config() {
+        let settings = validate_config(CONFIG).unwrap();
+        assert_eq!(settings.ttl, 30);
+        assert_eq!(settings.rpc_list.len(), 1);
+
+        let missing_ttl = CONFIG.replace("ttl = 30", "");
+        match validate_config(&missing_ttl) {
+            Err(ConfigError::InvalidConfig(reason)) => assert_eq!(reason, "Missing ttl!"),
+            other => panic!("expected an invalid config, got {:?}", other.map(|_| ())),
+        }
+
+        assert!(validate_config("not toml").is_err());
+        assert!(
+            validate_config(&CONFIG.replace("max_per_second = 200", "max_per_second = \"x\""))
+                .is_err()
+        );
+        assert!(validate_config(&format!("stray = 1\n{}", CONFIG)).is_err());
+    }
+
+    #[test]
+    fn test_recording() {
+        let current = validate_config(CONFIG).unwrap();
+        let proposed = validate_config(&CONFIG.replace(
+            "supress_rpc_check = false",
+            "supress_rpc_check = false\n        replay_path = \"./recording.ndjson\"",
+        ))
+        .unwrap();
+
+        let diff = diff_config(&current, &current.rpc_list, &proposed);
+        assert!(diff["changed"].get("recording").is_some());
+        assert_eq!(diff["requiresRestart"], json!(["recording"]));
+    }
+
+    #[test]
+    fn test_diff_config() {
+        let current = validate_config(CONFIG).unwrap();
+        let proposed = validate_config(
+            &CONFIG
+                .replace("ttl = 30", "ttl = 60")
+                .replace("127.0.0.1:3000", "127.0.0.1:3001")
+                .replace("node.example.com", "other.example.com"),
+        )
+        .unwrap();
+
+        let diff = diff_config(&current, &current.rpc_list, &proposed);
+        assert_eq!(diff["changed"]["ttl"], json!({"from": 30, "to": 60}));
+        assert_eq!(diff["changed"]["address"]["to"], "127.0.0.1:3001");
+        assert!(diff["changed"].get("max_retries").is_none());
+        assert_eq!(diff["requiresRestart"], json!(["address"]));
+        assert_eq!(diff["nodesAdded"], json!(["https://other.example.com/"]));
+        assert_eq!(diff["nodesRemoved"], json!(["https://node.example.com/"]));
+
+        // Nothing changes against itself
+        let diff = diff_config(&current, &current.rpc_list, &current);
+        assert_eq!(diff["changed"], json!({}));
+        assert_eq!(diff["nodesAdded"], json!([]));
+    }
+
+    #[test]
+    fn test_apply_config() {
+        let current = validate_config(CONFIG).unwrap();
+        let config = Arc::new(RwLock::new(current.clone()));
+        let rpc_list = Arc::new(RwLock::new(current.rpc_list.clone()));
+        let poverty_list = Arc::new(RwLock::new(Vec::new()));
+
+        let proposed = validate_config(&format!(
+            "{}{}",
+            CONFIG
+                .replace("ttl = 30", "ttl = 60")
+                .replace("127.0.0.1:3000", "127.0.0.1:3001"),
+            r#"
+            [other]
+            url = "https://other.example.com"
+            max_consecutive = 150
+            max_per_second = 200
+            "#
+        ))
+        .unwrap();
+
+        let diff = apply_config(&config, &rpc_list, &poverty_list, &proposed);
+        assert_eq!(diff["requiresRestart"], json!(["address"]));
+        assert_eq!(diff["nodesAdded"], json!(["https://other.example.com/"]));
+
+        let config = config.read().unwrap();
+        assert_eq!(config.ttl, 60);
+        // Needs a restart, so it's left alone
+        assert_eq!(config.address, current.address);
+        assert_eq!(rpc_list.read().unwrap().len(), 2);
+
+        // Nodes missing from the new config are dropped from both lists
+        let mut proposed = proposed.clone();
+        proposed.rpc_list.remove(0);
+        let config = Arc::new(RwLock::new(config.clone()));
+        poverty_list
+            .write()
+            .unwrap()
+            .push(rpc_list.write().unwrap().remove(0));
+        let diff = apply_config(&config, &rpc_list, &poverty_list, &proposed);
+        assert_eq!(diff["nodesRemoved"], json!(["https://node.example.com/"]));
+        assert!(poverty_list.read().unwrap().is_empty());
+        assert_eq!(rpc_list.read().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_adaptive_timeouts() {
+        let settings = validate_config(&CONFIG.replace(
+            "[admin]",
+            "[blutgang.adaptive_timeouts]\nfactor = 2\nmin = 100\nmax = 1000\nmin_samples = 2\n\n[admin]",
+        ))
+        .unwrap();
+        let timeouts = settings.adaptive_timeouts.unwrap();
+        assert_eq!(timeouts.factor, 2.0);
+
+        // `ttl` until we have enough samples
+        let latencies = MethodLatencies::default();
+        latencies.record("eth_call", Duration::from_millis(200));
+        assert_eq!(
+            timeouts.timeout(&latencies, "eth_call", 30),
+            Duration::from_millis(30)
+        );
+
+        latencies.record("eth_call", Duration::from_millis(200));
+        assert_eq!(
+            timeouts.timeout(&latencies, "eth_call", 30),
+            Duration::from_millis(400)
+        );
+
+        // Kept within bounds
+        latencies.record("debug_traceTransaction", Duration::from_secs(5));
+        latencies.record("debug_traceTransaction", Duration::from_secs(5));
+        assert_eq!(
+            timeouts.timeout(&latencies, "debug_traceTransaction", 30),
+            Duration::from_millis(1000)
+        );
+
+        let invalid = CONFIG.replace(
+            "[admin]",
+            "[blutgang.adaptive_timeouts]\nmin = 1000\nmax = 100\n\n[admin]",
+        );
+        assert!(validate_config(&invalid).is_err());
+    }
+
+    #[test]
+    fn test_estimate_gas() {
+        let settings = validate_config(&CONFIG.replace(
+            "[admin]",
+            "[blutgang.estimate_gas]\nnodes = 3\npercentile = 50\n\n[admin]",
+        ))
+        .unwrap();
+        let estimate_gas = settings.estimate_gas.unwrap();
+        assert_eq!(estimate_gas.nodes, 3);
+        assert_eq!(estimate_gas.percentile, 50.0);
+        assert_eq!(estimate_gas.cache_ttl, Duration::from_millis(2000));
+
+        let invalid = CONFIG.replace(
+            "[admin]",
+            "[blutgang.estimate_gas]\npercentile = 150\n\n[admin]",
+        );
+        assert!(validate_config(&invalid).is_err());
+    }
+
+    #[test]
+    fn test_consistent_reads() {
+        assert!(validate_config(CONFIG).unwrap().consistent_reads.is_none());
+
+        let settings =
+            validate_config(&CONFIG.replace("[admin]", "[blutgang.consistent_reads]\n\n[admin]"))
+                .unwrap();
+        assert_eq!(
+            settings.consistent_reads.unwrap().session_ttl,
+            Duration::from_secs(12)
+        );
+    }
+
+    #[test]
+    fn test_history_routing() {
+        assert!(validate_config(CONFIG).unwrap().history_routing.is_none());
+
+        let settings = validate_config(&CONFIG.replace(
+            "[admin]",
+            "[blutgang.history_routing]\nmargin = 64\n\n[admin]",
+        ))
+        .unwrap()
+        .history_routing
+        .unwrap();
+        assert_eq!(settings.margin, 64);
+        assert_eq!(settings.probe_interval, Duration::from_secs(600));
+    }
+
+    #[test]
+    fn test_deprecations() {
+        assert!(validate_config(CONFIG).unwrap().deprecations.is_none());
+
+        let settings = validate_config(&CONFIG.replace(
+            "[admin]",
+            "[blutgang.deprecations]\nwarn_in = \"both\"\n\n[blutgang.deprecations.methods]\neth_sign = \"Blocked from December\"\n\"debug_*\" = \"Moving to the archive endpoint\"\n\"debug_trace*\" = \"Use trace_*\"\n\n[admin]",
+        ))
+        .unwrap()
+        .deprecations
+        .unwrap();
+        assert_eq!(settings.warn_in, WarningPlacement::Both);
+        assert_eq!(settings.warning("eth_sign"), Some("Blocked from December"));
+        assert_eq!(
+            settings.warning("debug_getRawBlock"),
+            Some("Moving to the archive endpoint")
+        );
+        assert_eq!(settings.warning("debug_traceCall"), Some("Use trace_*"));
+        assert_eq!(settings.warning("eth_call"), None);
+    }
+
+    #[test]
+    fn test_usage() {
+        assert!(validate_config(CONFIG).unwrap().usage.is_none());
+
+        let settings = validate_config(&CONFIG.replace(
+            "[admin]",
+            "[blutgang.usage]\nretention_days = 7\n\n[blutgang.usage.s3]\nendpoint = \"https://s3.example.com/\"\nbucket = \"usage\"\naccess_key = \"AKID\"\nsecret_key = \"wJalrXUtnFEMI\"\nformat = \"json\"\n\n[admin]",
+        ))
+        .unwrap()
+        .usage
+        .unwrap();
+        assert_eq!(settings.retention_days, 7);
+        let s3 = settings.s3.unwrap();
+        assert_eq!(s3.endpoint, "https://s3.example.com");
+        assert_eq!(s3.region, "us-east-1");
+        assert_eq!(s3.format, UsageFormat::Json);
+        assert_eq!(s3.interval, Duration::from_secs(3600));
+        assert!(!format!("{:?}", s3).contains("wJalrXUtnFEMI"));
+    }
+
+    #[test]
+    fn test_replay_protection() {
+        assert!(validate_config(CONFIG).unwrap().replay_protection.is_none());
+
+        let settings = validate_config(&CONFIG.replace(
+            "[admin]",
+            "[blutgang.replay_protection]\nmax_duplicates = 5\n\n[admin]",
+        ))
+        .unwrap()
+        .replay_protection
+        .unwrap();
+        assert_eq!(settings.max_duplicates, 5);
+        assert_eq!(settings.window, Duration::from_secs(60));
+    }
+
+    #[test]
+    fn test_validate_responses() {
+        assert!(validate_config(CONFIG).unwrap().validate_responses);
+
+        let current = validate_config(CONFIG).unwrap();
+        let proposed = validate_config(&CONFIG.replace(
+            "supress_rpc_check = false",
+            "supress_rpc_check = false\n        validate_responses = false",
+        ))
+        .unwrap();
+        assert!(!proposed.validate_responses);
+
+        // Picked up without a restart
+        let diff = diff_config(&current, &current.rpc_list, &proposed);
+        assert_eq!(diff["changed"]["validate_responses"]["to"], false);
+        assert_eq!(diff["requiresRestart"], json!([]));
+    }
+
+    #[test]
+    fn test_http_get() {
+        let current = validate_config(CONFIG).unwrap();
+        assert!(!current.http_get);
+
+        let proposed = validate_config(&CONFIG.replace(
+            "supress_rpc_check = false",
+            "supress_rpc_check = false\n        http_get = true",
+        ))
+        .unwrap();
+        assert!(proposed.http_get);
+
+        let diff = diff_config(&current, &current.rpc_list, &proposed);
+        assert_eq!(diff["changed"]["http_get"]["to"], true);
+        assert_eq!(diff["requiresRestart"], json!([]));
+    }
+
+    #[test]
+    fn test_rest() {
+        let current = validate_config(CONFIG).unwrap();
+        assert!(current.rest.is_none());
+
+        let proposed = validate_config(&CONFIG.replace(
+            "[admin]",
+            "[blutgang.rest]\nprefix = \"/api/\"\nmax_age = 2\n\n[admin]",
+        ))
+        .unwrap();
+        assert_eq!(
+            proposed.rest,
+            Some(RestSettings {
+                prefix: "/api".to_string(),
+                max_age: 2,
+            })
+        );
+
+        let diff = diff_config(&current, &current.rpc_list, &proposed);
+        assert!(diff["changed"]["rest"]["to"].is_string());
+        assert_eq!(diff["requiresRestart"], json!([]));
+
+        assert_eq!(
+            validate_config(&CONFIG.replace("[admin]", "[blutgang.rest]\n\n[admin]"))
+                .unwrap()
+                .rest,
+            Some(RestSettings::default())
+        );
+        assert!(validate_config(
+            &CONFIG.replace("[admin]", "[blutgang.rest]\nprefix = \"api\"\n\n[admin]",)
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_revalidation() {
+        assert!(validate_config(CONFIG).unwrap().revalidation.is_none());
+
+        let settings = validate_config(
+            &CONFIG.replace("[admin]", "[blutgang.revalidation]\ntop_n = 500\n\n[admin]"),
+        )
+        .unwrap()
+        .revalidation
+        .unwrap();
+        assert_eq!(settings.top_n, 500);
+        assert_eq!(settings.concurrency, 16);
+    }
+
+    #[test]
+    fn test_scoring() {
+        let current = validate_config(CONFIG).unwrap();
+        assert!(current.scoring.is_none());
+
+        let proposed = validate_config(&CONFIG.replace(
+            "[admin]",
+            "[blutgang.scoring]\nerror_penalty = 4\nlag_penalty = 250\n\n[admin]",
+        ))
+        .unwrap();
+        let scoring = proposed.scoring.clone().unwrap();
+        assert_eq!(scoring.error_penalty, 4.0);
+        assert_eq!(scoring.lag_penalty, Duration::from_millis(250));
+        assert_eq!(scoring.half_life, Duration::from_secs(300));
+
+        // Picked up by the next health check
+        let diff = diff_config(&current, &current.rpc_list, &proposed);
+        assert!(diff["changed"]["scoring"]["to"].is_string());
+        assert_eq!(diff["requiresRestart"], json!([]));
+    }
+
+    #[test]
+    fn test_canary() {
+        let current = validate_config(CONFIG).unwrap();
+        assert!(current.canary.is_none());
+
+        let proposed = validate_config(&CONFIG.replace(
+            "[admin]",
+            "[blutgang.canary]\nstart_percent = 10\n\n[admin]",
+        ))
+        .unwrap();
+        let canary = proposed.canary.clone().unwrap();
+        assert_eq!(canary.start_share, 0.1);
+        assert_eq!(canary.window, Duration::from_secs(600));
+
+        let diff = diff_config(&current, &current.rpc_list, &proposed);
+        assert!(diff["changed"]["canary"]["to"].is_string());
+        assert_eq!(diff["requiresRestart"], json!([]));
+
+        assert!(validate_config(&CONFIG.replace(
+            "[admin]",
+            "[blutgang.canary]\nstart_percent = 150\n\n[admin]",
+        ))
+        .is_err());
+        // Only nodes added by the reload start as canaries
+        let config = Arc::new(RwLock::new(current.clone()));
+        let rpc_list = Arc::new(RwLock::new(current.rpc_list.clone()));
+        let poverty_list = Arc::new(RwLock::new(Vec::new()));
+        let proposed = validate_config(&format!(
+            "{}{}",
+            CONFIG.replace(
+                "[admin]",
+                "[blutgang.canary]\nstart_percent = 10\n\n[admin]"
+            ),
+            r#"
+            [other]
+            url = "https://other.example.com"
+            max_consecutive = 150
+            max_per_second = 200
+            "#
+        ))
+        .unwrap();
+        apply_config(&config, &rpc_list, &poverty_list, &proposed);
+        let rpc_list = rpc_list.read().unwrap();
+        assert_eq!(rpc_list[0].canary_share(), 1.0);
+        assert!(rpc_list[1].canary_share() < 0.2);
+    }
+
+    #[test]
+    fn test_coalesce_new_heads() {
+        let current = validate_config(CONFIG).unwrap();
+        assert!(!current.coalesce_new_heads);
+
+        let proposed = validate_config(&CONFIG.replace(
+            "supress_rpc_check = false",
+            "supress_rpc_check = false\n        coalesce_new_heads = true",
+        ))
+        .unwrap();
+        assert!(proposed.coalesce_new_heads);
+
+        // New connections pick it up
+        let diff = diff_config(&current, &current.rpc_list, &proposed);
+        assert_eq!(diff["changed"]["coalesce_new_heads"]["to"], true);
+        assert_eq!(diff["requiresRestart"], json!([]));
+    }
+
+    #[test]
+    fn test_dispatch_workers() {
+        let current = validate_config(CONFIG).unwrap();
+        assert!(current.dispatch_workers >= 1);
+
+        let proposed = validate_config(&CONFIG.replace(
+            "supress_rpc_check = false",
+            "supress_rpc_check = false\n        dispatch_workers = 64",
+        ))
+        .unwrap();
+        assert_eq!(proposed.dispatch_workers, 64);
+
+        // Workers are spawned on startup
+        let diff = diff_config(&current, &current.rpc_list, &proposed);
+        assert_eq!(diff["requiresRestart"], json!(["dispatch_workers"]));
+
+        assert!(validate_config(&CONFIG.replace(
+            "supress_rpc_check = false",
+            "supress_rpc_check = false\n        dispatch_workers = -1",
+        ))
+        .is_err());
+    }
+
+    #[test]
+    fn test_rate_limits() {
+        let current = validate_config(CONFIG).unwrap();
+        assert_eq!(current.rate_limits, RateLimitSettings::default());
+
+        let proposed = validate_config(
+            &CONFIG.replace("[admin]", "[blutgang.rate_limits]\nbase = 500\n\n[admin]"),
+        )
+        .unwrap();
+        assert_eq!(proposed.rate_limits.base, Duration::from_millis(500));
+        assert_eq!(proposed.rate_limits.max, Duration::from_secs(60));
+
+        let diff = diff_config(&current, &current.rpc_list, &proposed);
+        assert!(diff["changed"]["rate_limits"]["to"].is_string());
+        assert_eq!(diff["requiresRestart"], json!([]));
+
+        assert!(validate_config(&CONFIG.replace(
+            "[admin]",
+            "[blutgang.rate_limits]\nbase = 5000\nmax = 1000\n\n[admin]",
+        ))
+        .is_err());
+    }
+
+    #[test]
+    fn test_peer_count() {
+        let current = validate_config(CONFIG).unwrap();
+        assert_eq!(current.peer_count, PeerCountSettings::default());
+
+        let proposed = validate_config(&CONFIG.replace(
+            "[admin]",
+            "[blutgang.peer_count]\naggregate = \"max\"\ncache_ttl = 0\n\n[admin]",
+        ))
+        .unwrap();
+        assert_eq!(proposed.peer_count.aggregate, PeerCountAggregate::Max);
+        assert_eq!(proposed.peer_count.cache_ttl, Duration::ZERO);
+
+        let diff = diff_config(&current, &current.rpc_list, &proposed);
+        assert!(diff["changed"]["peer_count"]["to"].is_string());
+        assert_eq!(diff["requiresRestart"], json!([]));
+
+        assert!(validate_config(&CONFIG.replace(
+            "[admin]",
+            "[blutgang.peer_count]\naggregate = \"mean\"\n\n[admin]",
+        ))
+        .is_err());
+    }
+
+    #[test]
+    fn test_node_profile() {
+        assert!(validate_config(CONFIG).unwrap().rpc_list[0]
+            .profile
+            .is_none());
+
+        let settings = validate_config(&CONFIG.replace(
+            "max_per_second = 200",
+            "max_per_second = 200\n        profile = \"Geth\"",
+        ))
+        .unwrap();
+        assert_eq!(settings.rpc_list[0].profile, Some(Provider::Geth));
+
+        assert!(validate_config(&CONFIG.replace(
+            "max_per_second = 200",
+            "max_per_second = 200\n        profile = \"parity\""
+        ))
+        .is_err());
+    }
+
+    #[test]
+    fn test_regions() {
+        let current = validate_config(CONFIG).unwrap();
+        assert!(current.regions.is_none());
+        assert!(current.rpc_list[0].region.is_none());
+
+        let proposed = validate_config(
+            &CONFIG
+                .replace(
+                    "max_per_second = 200",
+                    "max_per_second = 200\n        region = \"eu-west\"",
+                )
+                .replace(
+                    "[admin]",
+                    "[blutgang.regions]\nlocal = \"eu-west\"\nfailover = [\"eu-central\", \"us-east\"]\n\n[admin]",
+                ),
+        )
+        .unwrap();
+        assert_eq!(proposed.rpc_list[0].region.as_deref(), Some("eu-west"));
+        let regions = proposed.regions.clone().unwrap();
+        assert_eq!(regions.rank(Some("eu-west")), 0);
+        assert_eq!(regions.rank(Some("us-east")), 2);
+        assert_eq!(regions.rank(Some("ap-south")), 3);
+        assert_eq!(regions.rank(None), 3);
+
+        let diff = diff_config(&current, &current.rpc_list, &proposed);
+        assert!(diff["changed"]["regions"]["to"].is_string());
+        assert_eq!(diff["requiresRestart"], json!([]));
+
+        assert!(validate_config(&CONFIG.replace(
+            "[admin]",
+            "[blutgang.regions]\nfailover = [\"us-east\"]\n\n[admin]",
+        ))
+        .is_err());
+    }
+
+    #[test]
+    fn test_error_map() {
+        let current = validate_config(CONFIG).unwrap();
+        assert!(current.error_map.is_none());
+
+        let proposed = validate_config(&CONFIG.replace(
+            "[admin]",
+            "[blutgang.error_map]\n\n[blutgang.error_map.rules]\n\"Capacity\" = { code = -32005 }\n\"capacity exceeded\" = { code = -32005, message = \"Limit exceeded\" }\n\"-32099\" = { code = -32603 }\n\n[admin]",
+        ))
+        .unwrap();
+        let error_map = proposed.error_map.clone().unwrap();
+        assert!(error_map.builtin_rules);
+        // Codes first, then the most specific message
+        assert_eq!(error_map.rules[0].matches, ErrorMatch::Code(-32099));
+        assert_eq!(
+            error_map.rules[1].matches,
+            ErrorMatch::Message("capacity exceeded".to_string())
+        );
+        assert_eq!(
+            error_map.rules[2].matches,
+            ErrorMatch::Message("capacity".to_string())
+        );
+        assert_eq!(
+            error_map
+                .rule(Some(-32000), "capacity exceeded")
+                .unwrap()
+                .message,
+            Some("Limit exceeded".to_string())
+        );
+
+        let diff = diff_config(&current, &current.rpc_list, &proposed);
+        assert!(diff["changed"]["error_map"]["to"].is_string());
+        assert_eq!(diff["requiresRestart"], json!([]));
+
+        assert!(validate_config(&CONFIG.replace(
+            "[admin]",
+            "[blutgang.error_map.rules]\n\"oops\" = { message = \"Oops\" }\n\n[admin]",
+        ))
+        .is_err());
+    }
+
+    #[test]
+    fn test_idle_connections() {
+        let current = validate_config(CONFIG).unwrap();
+        assert!(current.idle_connections.is_none());
+
+        let proposed = validate_config(&CONFIG.replace(
+            "[admin]",
+            "[blutgang.idle_connections]\ntimeout = 60000\n\n[admin]",
+        ))
+        .unwrap();
+        let idle = proposed.idle_connections.clone().unwrap();
+        assert_eq!(idle.timeout, Duration::from_secs(60));
+        assert_eq!(idle.warning, Duration::from_secs(30));
+
+        // New connections pick it up
+        let diff = diff_config(&current, &current.rpc_list, &proposed);
+        assert!(diff["changed"]["idle_connections"]["to"].is_string());
+        assert_eq!(diff["requiresRestart"], json!([]));
+
+        assert!(validate_config(&CONFIG.replace(
+            "[admin]",
+            "[blutgang.idle_connections]\nwarning = 0\n\n[admin]",
+        ))
+        .is_err());
+    }
+
+    #[test]
+    fn test_cluster() {
+        let current = validate_config(CONFIG).unwrap();
+        assert!(current.cluster.is_none());
+
+        let proposed = validate_config(&CONFIG.replace(
+            "[admin]",
+            "[blutgang.cluster]\naddress = \"http://10.0.0.1:3000/\"\npeers = [\"http://10.0.0.2:3000\"]\nsecret = \"hunter2\"\n\n[admin]",
+        ))
+        .unwrap();
+        let cluster = proposed.cluster.as_ref().unwrap();
+        assert_eq!(cluster.address, "http://10.0.0.1:3000");
+        assert_eq!(cluster.peers, vec!["http://10.0.0.2:3000".to_string()]);
+        assert_eq!(cluster.timeout, Duration::from_secs(2));
+        assert!(cluster.gossip.is_none());
+        assert!(!format!("{:?}", cluster).contains("hunter2"));
+
+        // The ring is built on startup
+        let diff = diff_config(&current, &current.rpc_list, &proposed);
+        assert_eq!(diff["requiresRestart"], json!(["cluster"]));
+
+        assert!(validate_config(&CONFIG.replace(
+            "[admin]",
+            "[blutgang.cluster]\npeers = [\"http://10.0.0.2:3000\"]\n\n[admin]",
+        ))
+        .is_err());
+
+        let gossip = validate_config(&CONFIG.replace(
+            "[admin]",
+            "[blutgang.cluster]\naddress = \"http://10.0.0.1:3000\"\nsecret = \"hunter2\"\n\n[blutgang.cluster.gossip]\nport = 3100\ninterval = 500\n\n[admin]",
+        ))
+        .unwrap()
+        .cluster
+        .unwrap()
+        .gossip
+        .unwrap();
+        assert_eq!(gossip.port, 3100);
+        assert_eq!(gossip.interval, Duration::from_millis(500));
+        assert!(validate_config(&CONFIG.replace(
+            "[admin]",
+            "[blutgang.cluster]\naddress = \"http://10.0.0.1:3000\"\nsecret = \"hunter2\"\n\n[blutgang.cluster.gossip]\nport = 70000\n\n[admin]",
+        ))
+        .is_err());
+        // Reports have to be signed
+        assert!(validate_config(&CONFIG.replace(
+            "[admin]",
+            "[blutgang.cluster]\naddress = \"http://10.0.0.1:3000\"\n\n[blutgang.cluster.gossip]\nport = 3100\n\n[admin]",
+        ))
+        .is_err());
+    }
+
+    #[test]
+    fn test_log_index() {
+        let current = validate_config(CONFIG).unwrap();
+        assert!(current.log_index.is_none());
+
+        let proposed = validate_config(&CONFIG.replace(
+            "[admin]",
+            "[blutgang.log_index]\nbackfill = 10000\naddresses = [\"0xA0b86991c6218b36c1d19D4a2e9Eb0cE3606eB48\"]\n\n[admin]",
+        ))
+        .unwrap();
+        let log_index = proposed.log_index.as_ref().unwrap();
+        assert_eq!(log_index.backfill, 10000);
+        assert_eq!(log_index.batch, 100);
+        assert_eq!(
+            log_index.addresses,
+            vec!["0xa0b86991c6218b36c1d19d4a2e9eb0ce3606eb48".to_string()]
+        );
+
+        // Indexing starts on startup
+        let diff = diff_config(&current, &current.rpc_list, &proposed);
+        assert_eq!(diff["requiresRestart"], json!(["log_index"]));
+
+        assert!(validate_config(
+            &CONFIG.replace("[admin]", "[blutgang.log_index]\nbatch = 0\n\n[admin]")
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_receipts() {
+        let current = validate_config(CONFIG).unwrap();
+        assert!(current.receipts.is_none());
+
+        let proposed = validate_config(&CONFIG.replace(
+            "[admin]",
+            "[blutgang.receipts]\nmax_size = 1048576\n\n[admin]",
+        ))
+        .unwrap();
+        assert_eq!(proposed.receipts.as_ref().unwrap().max_size, 1 << 20);
+
+        // The store is opened on startup
+        let diff = diff_config(&current, &current.rpc_list, &proposed);
+        assert_eq!(diff["requiresRestart"], json!(["receipts"]));
+
+        let default =
+            validate_config(&CONFIG.replace("[admin]", "[blutgang.receipts]\n\n[admin]")).unwrap();
+        assert_eq!(default.receipts.unwrap().max_size, 1 << 30);
+        assert!(validate_config(
+            &CONFIG.replace("[admin]", "[blutgang.receipts]\nmax_size = 0\n\n[admin]")
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_quorum() {
+        let current = validate_config(CONFIG).unwrap();
+        assert!(current.quorum.is_none());
+
+        let proposed = validate_config(&CONFIG.replace(
+            "[admin]",
+            "[blutgang.quorum.deposits]\nmethods = [\"eth_getTransactionReceipt\", \"eth_getLogs\"]\nnodes = 3\nagree = 2\n\n[admin]",
+        ))
+        .unwrap();
+        let quorum = proposed.quorum.as_ref().unwrap();
+        let (name, class) = quorum.class("eth_getLogs").unwrap();
+        assert_eq!(name, "deposits");
+        assert_eq!((class.nodes, class.agree), (3, 2));
+        assert!(quorum.class("eth_call").is_none());
+
+        // Applies to the next request
+        let diff = diff_config(&current, &current.rpc_list, &proposed);
+        assert!(diff["changed"]["quorum"]["to"].is_string());
+        assert_eq!(diff["requiresRestart"], json!([]));
+
+        assert!(validate_config(&CONFIG.replace(
+            "[admin]",
+            "[blutgang.quorum.deposits]\nmethods = [\"eth_getLogs\"]\nnodes = 2\nagree = 3\n\n[admin]",
+        ))
+        .is_err());
+        assert!(validate_config(&CONFIG.replace(
+            "[admin]",
+            "[blutgang.quorum.a]\nmethods = [\"eth_getLogs\"]\nnodes = 2\nagree = 2\n\n[blutgang.quorum.b]\nmethods = [\"eth_getLogs\"]\nnodes = 3\nagree = 2\n\n[admin]",
+        ))
+        .is_err());
+    }
+
+    #[test]
+    fn test_head_retry() {
+        let current = validate_config(CONFIG).unwrap();
+        assert!(current.head_retry.is_none());
+
+        let proposed = validate_config(
+            &CONFIG.replace("[admin]", "[blutgang.head_retry]\ndepth = 5\n\n[admin]"),
+        )
+        .unwrap();
+        let head_retry = proposed.head_retry.as_ref().unwrap();
+        assert_eq!(head_retry.depth, 5);
+        assert_eq!(head_retry.delay, Duration::from_millis(200));
+
+        // Applies to the next request
+        let diff = diff_config(&current, &current.rpc_list, &proposed);
+        assert!(diff["changed"]["head_retry"]["to"].is_string());
+        assert_eq!(diff["requiresRestart"], json!([]));
+
+        assert!(validate_config(
+            &CONFIG.replace("[admin]", "[blutgang.head_retry]\ndepth = 0\n\n[admin]")
+        )
+        .is_err());
+        assert!(validate_config(
+            &CONFIG.replace("[admin]", "[blutgang.head_retry]\njitter = -1\n\n[admin]")
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_selection() {
+        let current = validate_config(CONFIG).unwrap();
+        assert_eq!(current.selection, SelectionMode::Latency);
+
+        let proposed = validate_config(&CONFIG.replace(
+            "supress_rpc_check = false",
+            "supress_rpc_check = false\nselection = \"Rendezvous\"",
+        ))
+        .unwrap();
+        assert_eq!(proposed.selection, SelectionMode::Rendezvous);
+
+        // Applies to the next request
+        let diff = diff_config(&current, &current.rpc_list, &proposed);
+        assert_eq!(diff["changed"]["selection"]["to"], "rendezvous");
+        assert_eq!(diff["requiresRestart"], json!([]));
+
+        assert!(validate_config(&CONFIG.replace(
+            "supress_rpc_check = false",
+            "supress_rpc_check = false\nselection = \"random\"",
+        ))
+        .is_err());
+    }
+
+    #[test]
+    fn test_passthrough() {
+        let current = validate_config(CONFIG).unwrap();
+        assert!(current.passthrough.is_none());
+
+        let proposed = validate_config(&CONFIG.replace(
+            "[admin]",
+            "[blutgang.passthrough]\nheaders = [\"User-Agent\", \"X-Trace-Id\"]\nsubprotocols = [\"jsonrpc\"]\n\n[admin]",
+        ))
+        .unwrap();
+        let passthrough = proposed.passthrough.as_ref().unwrap();
+        assert_eq!(passthrough.headers, vec!["user-agent", "x-trace-id"]);
+        assert_eq!(passthrough.subprotocols, vec!["jsonrpc"]);
+
+        // Applies to the next request
+        let diff = diff_config(&current, &current.rpc_list, &proposed);
+        assert!(diff["changed"]["passthrough"]["to"].is_string());
+        assert_eq!(diff["requiresRestart"], json!([]));
+
+        assert!(validate_config(&CONFIG.replace(
+            "[admin]",
+            "[blutgang.passthrough]\nheaders = [\"Host\"]\n\n[admin]",
+        ))
+        .is_err());
+        assert!(validate_config(&CONFIG.replace(
+            "[admin]",
+            "[blutgang.passthrough]\nheaders = \"user-agent\"\n\n[admin]",
+        ))
+        .is_err());
+    }
+
+    #[test]
+    fn test_request_body() {
+        let current = validate_config(CONFIG).unwrap();
+        assert!(current.request_body.is_none());
+
+        let proposed = validate_config(&CONFIG.replace(
+            "[admin]",
+            "[blutgang.request_body]\nstream_above = 1048576\n\n[admin]",
+        ))
+        .unwrap();
+        let request_body = proposed.request_body.unwrap();
+        assert_eq!(request_body.max_size, 16 * 1024 * 1024);
+        assert_eq!(request_body.stream_above, Some(1048576));
+
+        // Applies to the next request
+        let diff = diff_config(&current, &current.rpc_list, &proposed);
+        assert!(diff["changed"]["request_body"]["to"].is_string());
+        assert_eq!(diff["requiresRestart"], json!([]));
+
+        assert!(validate_config(&CONFIG.replace(
+            "[admin]",
+            "[blutgang.request_body]\nmax_size = 1000\nstream_above = 1000\n\n[admin]",
+        ))
+        .is_err());
+        assert!(validate_config(&CONFIG.replace(
+            "[admin]",
+            "[blutgang.request_body]\nmax_size = 0\n\n[admin]",
+        ))
+        .is_err());
+    }
+
+    #[test]
+    fn test_tenants() {
+        let current = validate_config(CONFIG).unwrap();
+        assert!(current.tenants.tenants.is_empty());
+
+        let proposed = validate_config(&CONFIG.replace(
+            "[admin]",
+            "[tenants.acme]\nhosts = [\"RPC.acme.com\"]\nkeys = [\"acme-key\"]\nrate_limit = 10\n\n[tenants.public]\nhosts = [\"*\"]\nnamespace = \"shared\"\n\n[admin]",
+        ))
+        .unwrap();
+        let acme = &proposed.tenants.tenants["acme"];
+        assert_eq!(acme.hosts, vec!["rpc.acme.com"]);
+        assert_eq!(acme.keys, vec!["acme-key"]);
+        assert_eq!(acme.rate_limit, 10);
+        // Tenants get their own cache unless told otherwise
+        assert_eq!(acme.namespace, "acme");
+        assert_eq!(proposed.tenants.tenants["public"].namespace, "shared");
+
+        // Keys stay out of the diff, and tenants need a restart
+        let diff = diff_config(&current, &current.rpc_list, &proposed);
+        assert!(!diff.to_string().contains("acme-key"));
+        assert!(diff["requiresRestart"]
+            .as_array()
+            .unwrap()
+            .contains(&json!("tenants")));
+
+        assert!(validate_config(&CONFIG.replace(
+            "[admin]",
+            "[tenants.acme]\nhosts = [\"rpc.acme.com\"]\n\n[tenants.globex]\nhosts = [\"rpc.acme.com\"]\n\n[admin]",
+        ))
+        .is_err());
+        assert!(validate_config(&CONFIG.replace(
+            "[admin]",
+            "[tenants.acme]\nkeys = [\"acme-key\"]\n\n[admin]",
+        ))
+        .is_err());
+    }
+
+    #[test]
+    fn test_beacon() {
+        let current = validate_config(CONFIG).unwrap();
+        assert!(current.beacon.is_none());
+
+        let proposed = validate_config(&CONFIG.replace(
+            "[admin]",
+            "[beacon]\nprefix = \"/consensus/\"\nnodes = [\"http://lighthouse:5052\", \"https://beacon.example.com/secret-key\"]\nmax_slot_lag = 4\ntimeout = 3000\n\n[admin]",
+        ))
+        .unwrap();
+        let beacon = proposed.beacon.as_ref().unwrap();
+        assert_eq!(beacon.prefix, "/consensus");
+        assert_eq!(beacon.nodes.len(), 2);
+        assert_eq!(beacon.max_slot_lag, 4);
+        assert_eq!(beacon.timeout, Duration::from_secs(3));
+        assert_eq!(beacon.health_check_interval, Duration::from_secs(5));
+        // The beacon table isn't mistaken for a node
+        assert_eq!(proposed.rpc_list.len(), current.rpc_list.len());
+
+        let diff = diff_config(&current, &current.rpc_list, &proposed);
+        assert!(!diff.to_string().contains("secret-key"));
+        assert!(diff["requiresRestart"]
+            .as_array()
+            .unwrap()
+            .contains(&json!("beacon")));
+
+        assert!(
+            validate_config(&CONFIG.replace("[admin]", "[beacon]\nnodes = []\n\n[admin]")).is_err()
+        );
+        assert!(validate_config(&CONFIG.replace(
+            "[admin]",
+            "[beacon]\nprefix = \"beacon\"\nnodes = [\"http://lighthouse:5052\"]\n\n[admin]",
+        ))
+        .is_err());
+    }
+
+    #[test]
+    fn test_schedules() {
+        let current = validate_config(CONFIG).unwrap();
+        assert!(current.schedules.is_none());
+
+        let proposed = validate_config(&CONFIG.replace(
+            "[admin]",
+            "[blutgang.schedules.off_peak]\nwhen = \"* 0-7,20-23 * * *\"\nprefer = \"cheap\"\n\n[blutgang.schedules.backups]\nwhen = \"0-29 3 * * *\"\nselection = \"rendezvous\"\ndrain = [\"https://archive.example.com/\"]\n\n[admin]",
+        ))
+        .unwrap();
+        let schedules = &proposed.schedules.as_ref().unwrap().schedules;
+        assert_eq!(schedules["off_peak"].prefer.as_deref(), Some("cheap"));
+        assert_eq!(
+            schedules["backups"].selection,
+            Some(SelectionMode::Rendezvous)
+        );
+        assert_eq!(
+            schedules["backups"].drain,
+            vec!["https://archive.example.com/"]
+        );
+
+        // Picked up by the scheduler on its next tick
+        let diff = diff_config(&current, &current.rpc_list, &proposed);
+        assert!(diff["changed"]["schedules"]["to"].is_string());
+        assert_eq!(diff["requiresRestart"], json!([]));
+
+        assert!(validate_config(&CONFIG.replace(
+            "[admin]",
+            "[blutgang.schedules.broken]\nwhen = \"* 25 * * *\"\nprefer = \"cheap\"\n\n[admin]",
+        ))
+        .is_err());
+        assert!(validate_config(&CONFIG.replace(
+            "[admin]",
+            "[blutgang.schedules.idle]\nwhen = \"* * * * *\"\n\n[admin]",
+        ))
+        .is_err());
+    }
+
+    #[test]
+    fn test_fallback() {
+        let current = validate_config(CONFIG).unwrap();
+        assert!(current.fallback.is_none());
+
+        let proposed = validate_config(&CONFIG.replace(
+            "[admin]",
+            "[blutgang.fallback]\nurl = \"https://central.example.com\"\nkey = \"hunter2\"\n\n[admin]",
+        ))
+        .unwrap();
+        let fallback = proposed.fallback.as_ref().unwrap();
+        assert_eq!(fallback.url, "https://central.example.com");
+        assert_eq!(fallback.max_hops, 2);
+        assert_eq!(fallback.timeout, Duration::from_secs(5));
+        assert!(!format!("{:?}", fallback).contains("hunter2"));
+
+        // The client is made on startup
+        let diff = diff_config(&current, &current.rpc_list, &proposed);
+        assert_eq!(diff["requiresRestart"], json!(["fallback"]));
+
+        assert!(validate_config(
+            &CONFIG.replace("[admin]", "[blutgang.fallback]\nmax_hops = 1\n\n[admin]",)
+        )
+        .is_err());
+        assert!(validate_config(&CONFIG.replace(
+            "[admin]",
+            "[blutgang.fallback]\nurl = \"https://central.example.com\"\nmax_hops = 0\n\n[admin]",
+        ))
+        .is_err());
+    }
+
+    #[test]
+    fn test_ws_sessions() {
+        let current = validate_config(CONFIG).unwrap();
+        assert!(current.ws_sessions.is_none());
+
+        let proposed = validate_config(
+            &CONFIG.replace("[admin]", "[blutgang.ws_sessions]\nttl = 60000\n\n[admin]"),
+        )
+        .unwrap();
+        assert_eq!(
+            proposed.ws_sessions.as_ref().unwrap().ttl,
+            Duration::from_secs(60)
+        );
+
+        // Sessions are restored on startup
+        let diff = diff_config(&current, &current.rpc_list, &proposed);
+        assert_eq!(diff["requiresRestart"], json!(["ws_sessions"]));
+
+        assert!(validate_config(
+            &CONFIG.replace("[admin]", "[blutgang.ws_sessions]\nttl = -1\n\n[admin]",)
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_event_rate_limit() {
+        let current = validate_config(CONFIG).unwrap();
+        assert!(current.event_rate_limit.is_none());
+
+        let proposed = validate_config(&CONFIG.replace(
+            "[admin]",
+            "[blutgang.event_rate_limit]\nevents_per_second = 20\npolicy = \"disconnect\"\n\n[admin]",
+        ))
+        .unwrap();
+        let settings = proposed.event_rate_limit.clone().unwrap();
+        assert_eq!(settings.events_per_second, 20.0);
+        assert_eq!(settings.burst, 200.0);
+        assert_eq!(settings.policy, EventLimitPolicy::Disconnect);
+
+        // Users are set up with their limit when they connect
+        let diff = diff_config(&current, &current.rpc_list, &proposed);
+        assert_eq!(diff["requiresRestart"], json!(["event_rate_limit"]));
+
+        assert!(validate_config(&CONFIG.replace(
+            "[admin]",
+            "[blutgang.event_rate_limit]\npolicy = \"throttle\"\n\n[admin]",
+        ))
+        .is_err());
+    }
+
+    #[test]
+    fn test_hot_cache_size() {
+        assert_eq!(
+            validate_config(CONFIG).unwrap().hot_cache_size,
+            16 * 1024 * 1024
+        );
+
+        let proposed = validate_config(&CONFIG.replace(
+            "supress_rpc_check = false",
+            "supress_rpc_check = false\n        hot_cache_size = 0",
+        ))
+        .unwrap();
+        assert_eq!(proposed.hot_cache_size, 0);
+
+        let current = validate_config(CONFIG).unwrap();
+        let diff = diff_config(&current, &current.rpc_list, &proposed);
+        assert_eq!(diff["changed"]["hot_cache_size"]["to"], 0);
+        assert_eq!(diff["requiresRestart"], json!(["hot_cache_size"]));
+    }
+
+    #[test]
+    fn test_routing_hints() {
+        assert!(validate_config(CONFIG).unwrap().routing_hints.is_none());
+
+        let settings = validate_config(&CONFIG.replace(
+            "[admin]",
+            "[blutgang.routing_hints]\nallow = [\"no_cache\", \"no_store\"]\ncache_control = true\n\n[blutgang.routing_hints.identities]\n\"indexer.internal\" = [\"node\"]\n\n[admin]",
+        ))
+        .unwrap()
+        .routing_hints
+        .unwrap();
+        assert!(settings.allows(None, "no_cache"));
+        assert!(!settings.allows(None, "node"));
+        assert!(settings.allows(Some("indexer.internal"), "node"));
+        assert!(!settings.allows(Some("indexer.internal"), "no_cache"));
+        assert!(settings.allows(None, "no_store"));
+        assert!(settings.cache_control);
+    }
+
+    #[test]
+    fn test_fork_choice() {
+        assert!(validate_config(CONFIG).unwrap().fork_choice.is_none());
+
+        let settings = validate_config(&CONFIG.replace(
+            "[admin]",
+            "[blutgang.fork_choice]\nthreshold = 5000\n\n[admin]",
+        ))
+        .unwrap();
+        assert_eq!(
+            settings.fork_choice.unwrap().threshold,
+            Duration::from_secs(5)
+        );
+    }
+
+    #[test]
+    fn test_rebroadcast() {
+        assert!(validate_config(CONFIG).unwrap().rebroadcast.is_none());
+
+        let settings = validate_config(&CONFIG.replace(
+            "[admin]",
+            "[blutgang.rebroadcast]\nttl = 60000\nidentities = [\"wallet\"]\n\n[admin]",
+        ))
+        .unwrap();
+        let rebroadcast = settings.rebroadcast.unwrap();
+        assert_eq!(rebroadcast.interval, Duration::from_secs(30));
+        assert_eq!(rebroadcast.ttl, Duration::from_secs(60));
+        assert!(rebroadcast.wants(Some("wallet")));
+        assert!(!rebroadcast.wants(Some("indexer")));
+        assert!(!rebroadcast.wants(None));
+
+        let invalid = CONFIG.replace("[admin]", "[blutgang.rebroadcast]\ninterval = 0\n\n[admin]");
+        assert!(validate_config(&invalid).is_err());
+    }
+
+    #[test]
+    fn test_upstream_identity() {
+        let current = validate_config(CONFIG).unwrap();
+        assert_eq!(current.upstream_identity, UpstreamIdentity::default());
+        assert_eq!(
+            current.rpc_list[0].identity.user_agent,
+            concat!("blutgang/", env!("CARGO_PKG_VERSION"))
+        );
+
+        let proposed = validate_config(
+            &CONFIG
+                .replace(
+                    "supress_rpc_check = false",
+                    "supress_rpc_check = false\nuser_agent = \"acme-rpc/1.0\"\norigin = \"https://acme.com\"",
+                )
+                .replace(
+                    "max_per_second = 200",
+                    "max_per_second = 200\nuser_agent = \"acme-archive/1.0\"",
+                ),
+        )
+        .unwrap();
+        assert_eq!(proposed.upstream_identity.user_agent, "acme-rpc/1.0");
+
+        // Nodes override what they set and keep the rest
+        let identity = &proposed.rpc_list[0].identity;
+        assert_eq!(identity.user_agent, "acme-archive/1.0");
+        assert_eq!(identity.origin.as_deref(), Some("https://acme.com"));
+        assert_eq!(
+            identity.headers(),
+            vec![
+                ("user-agent", "acme-archive/1.0"),
+                ("origin", "https://acme.com")
+            ]
+        );
+
+        // Nodes we already use keep how they introduced themselves
+        let diff = diff_config(&current, &current.rpc_list, &proposed);
+        assert_eq!(diff["requiresRestart"], json!(["upstream_identity"]));
+
+        assert!(validate_config(&CONFIG.replace(
+            "max_per_second = 200",
+            "max_per_second = 200\nuser_agent = \"bad\\nagent\"",
+        ))
+        .is_err());
+    }
+
+    #[test]
+    fn test_budget() {
+        let current = validate_config(CONFIG).unwrap();
+        assert!(!current.rpc_list[0].status.budget.is_enabled());
+
+        let budget =
+            |table: &str| validate_config(&format!("{}\n[node.budget]\n{}", CONFIG, table));
+        let proposed = budget("daily = 1000\npacing = false").unwrap();
+        assert_eq!(
+            proposed.rpc_list[0].status.budget.remaining(),
+            vec![(crate::rpc::budget::BudgetPeriod::Day, 1000)]
+        );
+
+        assert!(budget("pacing = true").is_err());
+        assert!(budget("monthly = 0").is_err());
+        assert!(budget("daily = \"lots\"").is_err());
+    }
+}
@@ -0,0 +1,920 @@
+// Wires up all of blutgang's subsystems and serves requests.
+//
+// This is what the `blutgang` binary runs. Services embedding blutgang can call
+// `run` with their own `Settings` and middleware instead of going through the CLI.
+#[cfg(unix)]
+use crate::admin::signals::{
+    listen_for_signals,
+    SignalTargets,
+};
+use crate::{
+    accept,
+    admin::listener::listen_for_admin_requests,
+    balancer::{
+        accept_http::{
+            accept_request,
+            ConnectionParams,
+            RequestChannels,
+        },
+        block_hashes::BlockHashes,
+        cache_entry::CacheKey,
+        consistent_reads::ConsistentReads,
+        cors::Cors,
+        ens::EnsCache,
+        estimate_gas::GasEstimator,
+        graphql::Graphql,
+        hot_cache::{
+            watch_cache,
+            HotCache,
+        },
+        memory::{
+            enforce_memory_budget,
+            MemoryBudget,
+        },
+        mtls::ListenerTls,
+        peer_count::PeerCounts,
+        prewarm::prewarm_cache,
+        processing::CacheArgs,
+        recording::Recorder,
+        revalidate::{
+            revalidate_on_new_blocks,
+            Revalidator,
+        },
+        selection::schedules::{
+            run_schedules,
+            Scheduler,
+        },
+        tenants::Tenants,
+    },
+    beacon::{
+        health::{
+            beacon_health_check,
+            follow_head,
+        },
+        types::BeaconPool,
+    },
+    cluster::{
+        fallback::Fallback,
+        gossip::gossip_health,
+        leader::Leadership,
+        peers::PeerCache,
+    },
+    config::{
+        cache_setup::setup_data,
+        system::set_log_file,
+        types::Settings,
+    },
+    health::{
+        anomaly::AnomalyDetector,
+        check::{
+            dropped_listener,
+            health_check,
+        },
+        head_cache::manage_cache,
+        horizon::{
+            track_state_horizons,
+            StateHorizons,
+        },
+        light_client::light_client_sync,
+        maintenance::Maintenance,
+        safe_block::{
+            subscribe_to_new_heads,
+            NamedBlocknumbers,
+        },
+        watchdog::subscription_watchdog,
+    },
+    log_err,
+    log_info,
+    log_wrn,
+    middleware::types::MiddlewareStack,
+    notify::{
+        firehose::Firehose,
+        usage::{
+            push_usage,
+            UsageTracker,
+        },
+        webhook::Notifier,
+    },
+    rpc::types::Rpc,
+    store::{
+        logs::{
+            index_logs,
+            LogIndex,
+        },
+        receipts::ReceiptStore,
+    },
+    transactions::{
+        rebroadcast::rebroadcast_transactions,
+        replay::ReplayGuard,
+        tracker::TxTracker,
+    },
+    verify::state::TrackedHeaders,
+    websocket::{
+        client::ws_conn_manager,
+        long_poll::LongPoll,
+        rate_limit::release_withheld_events,
+        sessions::{
+            restore_subscriptions,
+            Sessions,
+        },
+        subscription_manager::{
+            confirmed_logs_releaser,
+            subscription_dispatcher,
+        },
+        tx_status::tx_status_watcher,
+        types::{
+            IncomingResponse,
+            SubscriptionData,
+            WsChannelErr,
+            WsconnMessage,
+        },
+    },
+};
+
+use std::{
+    collections::BTreeMap,
+    sync::{
+        Arc,
+        RwLock,
+    },
+    time::Duration,
+};
+
+use tokio::{
+    net::TcpListener,
+    sync::{
+        broadcast,
+        mpsc,
+        watch,
+    },
+};
+
+use hyper::{
+    server::conn::http1,
+    service::service_fn,
+};
+use hyper_util_blutgang::rt::TokioIo;
+use openssl::ssl::NameType;
+
+#[cfg(feature = "http3")]
+use crate::balancer::http3::listen_http3;
+#[cfg(feature = "wasm-plugins")]
+use crate::middleware::wasm::load_plugins;
+
+// Start blutgang with `config` and run until the listener fails.
+//
+// `middleware` runs on every request, response and subscription event. Pass
+// `MiddlewareStack::default()` to run without any.
+pub async fn run(
+    config: Arc<RwLock<Settings>>,
+    middleware: MiddlewareStack,
+) -> Result<(), Box<dyn std::error::Error>> {
+    // Also write logs to a file if configured
+    let (log_file, log_rotation) = {
+        let config_guard = config.read().unwrap();
+        (
+            config_guard.log_file.clone(),
+            config_guard.log_rotation.clone(),
+        )
+    };
+    if let Some(log_file) = &log_file {
+        set_log_file(log_file, log_rotation)?;
+        log_info!("Writing logs to {}", log_file);
+    }
+
+    // Copy the configuration values we need
+    let (addr, do_clear, do_health_check, admin_enabled, is_ws, expected_block_time) = {
+        let config_guard = config.read().unwrap();
+        (
+            config_guard.address,
+            config_guard.do_clear,
+            config_guard.health_check,
+            config_guard.admin.enabled,
+            config_guard.is_ws,
+            config_guard.expected_block_time,
+        )
+    };
+
+    // Make the list a rwlock
+    let rpc_list_rwlock = Arc::new(RwLock::new(config.read().unwrap().rpc_list.clone()));
+
+    // Create/Open sled DB
+    let cache = Arc::new(
+        config
+            .read()
+            .unwrap()
+            .sled_config
+            .open()
+            .expect("Can't open/create database!"),
+    );
+
+    // Open the recording DB if we're recording or replaying responses
+    let recorder = Recorder::open(&config.read().unwrap().recording)
+        .expect("Can't open/create recording database!")
+        .map(Arc::new);
+    if let Some(recorder) = &recorder {
+        if recorder.is_replay() {
+            log_wrn!("Replay mode enabled, responses will only be served from the recording!");
+        } else {
+            log_info!("Recording all responses to disk.");
+        }
+    }
+
+    // Middleware layers run on every request, response and subscription event
+    #[cfg(feature = "wasm-plugins")]
+    let middleware = load_plugins(middleware, &config.read().unwrap().plugins)?;
+    #[cfg(not(feature = "wasm-plugins"))]
+    if !config.read().unwrap().plugins.is_empty() {
+        log_wrn!("WASM plugins configured, but blutgang was built without the `wasm-plugins` feature! Ignoring them.");
+    }
+    let middleware = Arc::new(middleware);
+
+    // Webhook notifications for health events, disabled if no URLs are set
+    let notifier = Notifier::new(config.read().unwrap().webhooks.clone());
+
+    // Mirror requests to the firehose if configured
+    let firehose = Firehose::new(config.read().unwrap().firehose.clone());
+
+    // Quarantines nodes serving data that diverges from their peers
+    let anomaly = Arc::new(AnomalyDetector::new(
+        config.read().unwrap().anomaly.clone(),
+        notifier.clone(),
+    ));
+
+    // Nodes taken out of rotation through the admin namespace
+    let maintenance = Arc::new(Maintenance::default());
+
+    // Routing policies that apply at certain times, re-read on every tick
+    let scheduler = Arc::new(Scheduler::default());
+
+    // Which instance of the fleet runs the jobs that only need to run once
+    let leadership = Arc::new(Leadership::new(config.read().unwrap().cluster.as_ref()));
+
+    // Per-client call counts for billing, pushed to S3 if configured
+    let usage = Arc::new(UsageTracker::new(config.read().unwrap().usage.clone()));
+    tokio::task::spawn(push_usage(usage.clone(), leadership.clone()));
+
+    // Allowed browser origins and their rate limits
+    let cors = Arc::new(Cors::new(config.read().unwrap().cors.clone()));
+    let tenants = Arc::new(Tenants::new(config.read().unwrap().tenants.clone()));
+
+    // Other instances of the fleet and the cache keys they own
+    let peer_cache = Arc::new(PeerCache::new(config.read().unwrap().cluster.clone()));
+
+    // Instance we send requests to when our own nodes can't answer them
+    let fallback = Arc::new(Fallback::new(config.read().unwrap().fallback.clone()));
+
+    // Cached `net_peerCount` of all our nodes
+    let peer_counts = Arc::new(PeerCounts::new());
+
+    // Cached answers to `/graphql` for the current head
+    let graphql = Arc::new(Graphql::new());
+
+    // Subscriptions of clients that poll for their events
+    let long_poll = Arc::new(LongPoll::new());
+
+    // Beacon nodes behind the `[beacon]` prefix, checked and followed in the
+    // background
+    let beacon = Arc::new(BeaconPool::new(config.read().unwrap().beacon.clone()));
+    if beacon.is_enabled() {
+        let retry = beacon
+            .settings
+            .as_ref()
+            .map_or(Duration::from_secs(5), |settings| {
+                settings.health_check_interval
+            });
+        tokio::task::spawn(beacon_health_check(Arc::clone(&beacon)));
+        for node in &beacon.nodes {
+            tokio::task::spawn(follow_head(beacon.client.clone(), Arc::clone(node), retry));
+        }
+    }
+
+    // Cache for storing querries near the tip
+    let head_cache = Arc::new(RwLock::new(BTreeMap::<u64, Vec<CacheKey>>::new()));
+
+    // Size accounting for cached responses and subscription buffers
+    let memory = {
+        let config_guard = config.read().unwrap();
+        Arc::new(MemoryBudget::new(
+            config_guard.memory_budget,
+            config_guard.eviction_policy,
+        ))
+    };
+
+    // Longer lived cache for ENS lookups at the tip, disabled if no TTL is set
+    let ens = Arc::new(EnsCache::new(
+        config
+            .read()
+            .unwrap()
+            .ens_cache_ttl
+            .map(Duration::from_millis),
+    ));
+
+    // Most read cache entries, kept in memory in front of the DB
+    let hot = Arc::new(HotCache::new(config.read().unwrap().hot_cache_size));
+    watch_cache(hot.clone(), &cache);
+
+    // Ask several nodes for gas estimates, disabled if not configured
+    let gas_estimator = Arc::new(GasEstimator::new(
+        config.read().unwrap().estimate_gas.clone(),
+    ));
+
+    // Pin `latest` for sessions if enabled
+    let consistent_reads = Arc::new(ConsistentReads::new(
+        config.read().unwrap().consistent_reads.clone(),
+    ));
+
+    // Block numbers of the block hashes clients pin requests to
+    let block_hashes = Arc::new(BlockHashes::default());
+    // State roots of the heads we see, proofs from other nodes are checked against them
+    let headers = Arc::new(TrackedHeaders::default());
+
+    // Track nonces of transactions we broadcast, and rebroadcast them, if enabled
+    let tx_tracker = Arc::new(TxTracker::new(
+        config.read().unwrap().nonce_tracking,
+        config.read().unwrap().rebroadcast.clone(),
+    ));
+    if tx_tracker.rebroadcast_settings().is_some() {
+        tokio::task::spawn(rebroadcast_transactions(
+            tx_tracker.clone(),
+            rpc_list_rwlock.clone(),
+            config.read().unwrap().ttl,
+        ));
+    }
+
+    // Refuse raw transactions clients keep resending, if enabled
+    let replay = Arc::new(ReplayGuard::new(
+        config.read().unwrap().replay_protection.clone(),
+    ));
+
+    // Re-fetch the hottest `latest` requests on new blocks, if enabled
+    let revalidator = Arc::new(Revalidator::new(
+        config.read().unwrap().revalidation.clone(),
+    ));
+
+    // Clear database if specified
+    if do_clear {
+        cache.clear().unwrap();
+        log_wrn!("All data cleared from the database.");
+    }
+    // Insert data about blutgang and our settings into the DB
+    //
+    // Print any relevant warnings about a misconfigured DB. Check docs for more
+    setup_data(Arc::clone(&cache));
+
+    // Count what we cached before the restart against the memory budget.
+    // Reading the whole DB takes a while, so we don't wait for it.
+    let memory_seed = Arc::clone(&memory);
+    let cache_seed = Arc::clone(&cache);
+    tokio::task::spawn_blocking(move || {
+        let seeded = memory_seed.seed(&cache_seed);
+        log_info!("Counted {} cached responses from before", seeded);
+    });
+
+    // We create a TcpListener and bind it to 127.0.0.1:3000
+    let listener = TcpListener::bind(addr).await?;
+    log_info!("Bound to: {}", addr);
+
+    // Terminate TLS ourselves if configured
+    let listener_tls = match &config.read().unwrap().tls {
+        Some(settings) => Some(Arc::new(ListenerTls::load(settings)?)),
+        None => None,
+    };
+
+    let (blocknum_tx, blocknum_rx) = watch::channel(0);
+    let (finalized_tx, finalized_rx) = watch::channel(0);
+
+    let finalized_rx_arc = Arc::new(finalized_rx.clone());
+    let rpc_poverty_list = Arc::new(RwLock::new(Vec::<Rpc>::new()));
+    tokio::task::spawn(run_schedules(
+        scheduler.clone(),
+        config.clone(),
+        rpc_list_rwlock.clone(),
+        rpc_poverty_list.clone(),
+        maintenance.clone(),
+    ));
+
+    // Nodes other instances found down get deprioritized here too
+    let cluster = config.read().unwrap().cluster.clone();
+    if let Some(cluster) = cluster.filter(|cluster| cluster.gossip.is_some()) {
+        tokio::task::spawn(gossip_health(
+            cluster,
+            Arc::clone(&rpc_list_rwlock),
+            Arc::clone(&rpc_poverty_list),
+            leadership.clone(),
+        ));
+    }
+
+    // Spawn a thread for the admin namespace if enabled
+    if admin_enabled {
+        let rpc_list_admin = Arc::clone(&rpc_list_rwlock);
+        let poverty_list_admin = Arc::clone(&rpc_poverty_list);
+        let cache_admin = Arc::clone(&cache);
+        let config_admin = Arc::clone(&config);
+        let memory_admin = Arc::clone(&memory);
+        let ens_admin = Arc::clone(&ens);
+        let head_cache_admin = Arc::clone(&head_cache);
+        let anomaly_admin = Arc::clone(&anomaly);
+        let usage_admin = Arc::clone(&usage);
+        let hot_admin = Arc::clone(&hot);
+        let maintenance_admin = Arc::clone(&maintenance);
+        let beacon_admin = Arc::clone(&beacon);
+        tokio::task::spawn(async move {
+            log_info!("Admin namespace enabled, accepting admin methods at admin port");
+            let _ = listen_for_admin_requests(
+                rpc_list_admin,
+                poverty_list_admin,
+                cache_admin,
+                config_admin,
+                memory_admin,
+                ens_admin,
+                head_cache_admin,
+                anomaly_admin,
+                usage_admin,
+                hot_admin,
+                maintenance_admin,
+                beacon_admin,
+            )
+            .await;
+        });
+    }
+
+    // Confirmed logs get released on new heads
+    let blocknum_rx_confirmed = blocknum_rx.clone();
+    // So are the hottest `latest` requests
+    let blocknum_rx_revalidate = blocknum_rx.clone();
+
+    // Spawn a thread for the head cache
+    let head_cache_clone = Arc::clone(&head_cache);
+    let cache_clone = Arc::clone(&cache);
+    let finalized_rxclone = Arc::clone(&finalized_rx_arc);
+    let memory_clone = Arc::clone(&memory);
+    tokio::task::spawn(async move {
+        let _ = manage_cache(
+            &head_cache_clone,
+            blocknum_rx,
+            finalized_rxclone,
+            &cache_clone,
+            &memory_clone,
+        )
+        .await;
+    });
+
+    // Spawn a thread for the health check
+    //
+    // Also handle the finalized block tracking in this thread
+    let named_blocknumbers = Arc::new(RwLock::new(NamedBlocknumbers::default()));
+
+    // Probe how far back each node has state, if we route historical calls by it
+    let horizons = Arc::new(StateHorizons::new(
+        config.read().unwrap().history_routing.clone(),
+    ));
+    if horizons.is_enabled() {
+        tokio::task::spawn(track_state_horizons(
+            horizons.clone(),
+            rpc_list_rwlock.clone(),
+            named_blocknumbers.clone(),
+            config.read().unwrap().ttl,
+        ));
+    }
+
+    if do_health_check {
+        let poverty_list_health = Arc::clone(&rpc_poverty_list);
+        let config_health = Arc::clone(&config);
+
+        let rpc_list_health = Arc::clone(&rpc_list_rwlock);
+        let named_blocknumbers_health = Arc::clone(&named_blocknumbers);
+        let notifier_health = notifier.clone();
+        let anomaly_health = Arc::clone(&anomaly);
+
+        tokio::task::spawn(async move {
+            let _ = health_check(
+                rpc_list_health,
+                poverty_list_health,
+                finalized_tx,
+                &named_blocknumbers_health,
+                &config_health,
+                notifier_health,
+                anomaly_health,
+            )
+            .await;
+        });
+
+        // Downgrade nodes serving non-canonical heads. They get back in
+        // through the health check once they serve the canonical chain again.
+        let (beacon_url, checkpoint) = {
+            let config_guard = config.read().unwrap();
+            (
+                config_guard.beacon_url.clone(),
+                config_guard.light_client_checkpoint,
+            )
+        };
+        if let (Some(beacon_url), Some(checkpoint)) = (beacon_url, checkpoint) {
+            tokio::task::spawn(light_client_sync(
+                Arc::clone(&rpc_list_rwlock),
+                Arc::clone(&rpc_poverty_list),
+                beacon_url,
+                checkpoint,
+                notifier.clone(),
+                Duration::from_millis(expected_block_time.max(1000)),
+            ));
+        }
+    }
+
+    // WebSocket connection + health check setup. Only runs when every node has a WS endpoint.
+    let (incoming_tx, incoming_rx) = mpsc::unbounded_channel::<WsconnMessage>();
+    let (outgoing_tx, outgoing_rx) = broadcast::channel::<IncomingResponse>(2048);
+    let sub_data = Arc::new(
+        SubscriptionData::new()
+            .with_event_rate_limit(config.read().unwrap().event_rate_limit.clone())
+            .with_dispatch_workers(config.read().unwrap().dispatch_workers),
+    );
+    // Resumable WS sessions, kept in the same DB as the cache
+    let sessions = Arc::new(
+        Sessions::open(&cache, config.read().unwrap().ws_sessions.as_ref())
+            .expect("Can't open WS sessions!"),
+    );
+
+    // Local log index, kept in the same DB as the cache
+    let log_index = Arc::new(
+        LogIndex::open(&cache, config.read().unwrap().log_index.as_ref())
+            .expect("Can't open the log index!"),
+    );
+    // Receipts of finalized transactions, also kept in the same DB
+    let receipts = Arc::new(
+        ReceiptStore::open(&cache, config.read().unwrap().receipts.as_ref())
+            .expect("Can't open the receipt store!"),
+    );
+    if log_index.is_enabled() {
+        let ttl = config.read().unwrap().ttl;
+        tokio::task::spawn(index_logs(
+            Arc::clone(&log_index),
+            Arc::clone(&rpc_list_rwlock),
+            finalized_rx.clone(),
+            ttl,
+        ));
+    }
+
+    // Evict cache entries if we go over the memory budget
+    if let Some(memory_budget) = config.read().unwrap().memory_budget {
+        log_info!("Memory budget set to {} bytes", memory_budget);
+        tokio::task::spawn(enforce_memory_budget(
+            Arc::clone(&memory),
+            Arc::clone(&cache),
+            Arc::clone(&hot),
+            Arc::clone(&sub_data),
+        ));
+    }
+
+    // SIGHUP reloads the config, SIGUSR1 logs a state report and SIGUSR2
+    // reopens the log file
+    #[cfg(unix)]
+    {
+        let targets = SignalTargets {
+            config: Arc::clone(&config),
+            rpc_list: Arc::clone(&rpc_list_rwlock),
+            poverty_list: Arc::clone(&rpc_poverty_list),
+            cache: Arc::clone(&cache),
+            memory: Arc::clone(&memory),
+            hot: Arc::clone(&hot),
+            sub_data: Arc::clone(&sub_data),
+            anomaly: Arc::clone(&anomaly),
+        };
+        tokio::task::spawn(async move {
+            if let Err(e) = listen_for_signals(targets).await {
+                log_err!("Could not listen for signals: {}", e);
+            }
+        });
+    }
+
+    if is_ws {
+        let (ws_error_tx, ws_error_rx) = mpsc::unbounded_channel::<WsChannelErr>();
+
+        // Events users went over their budget for, if we're coalescing them
+        tokio::task::spawn(release_withheld_events(Arc::clone(&sub_data)));
+
+        // Drained nodes hand their subscriptions over to the rest
+        maintenance.attach_ws(
+            incoming_tx.clone(),
+            outgoing_rx.resubscribe(),
+            Arc::clone(&sub_data),
+        );
+
+        let rpc_list_ws = Arc::clone(&rpc_list_rwlock);
+        // TODO: make this more ergonomic
+        let ws_handle = Arc::new(RwLock::new(Vec::<
+            Option<mpsc::UnboundedSender<serde_json::Value>>,
+        >::new()));
+        let outgoing_rx_ws = outgoing_rx.resubscribe();
+        let incoming_tx_ws = incoming_tx.clone();
+        let ws_error_tx_ws = ws_error_tx.clone();
+
+        let sub_dispatcher = Arc::clone(&sub_data);
+        let middleware_dispatcher = Arc::clone(&middleware);
+
+        tokio::task::spawn(async move {
+            tokio::task::spawn(async move {
+                let _ = subscription_dispatcher(
+                    outgoing_rx_ws,
+                    incoming_tx_ws,
+                    sub_dispatcher,
+                    middleware_dispatcher,
+                )
+                .await;
+            });
+
+            let _ = ws_conn_manager(
+                rpc_list_ws,
+                ws_handle,
+                incoming_rx,
+                outgoing_tx,
+                ws_error_tx_ws,
+            )
+            .await;
+        });
+
+        // Subscribe to what sessions from before we started need again
+        if sessions.is_enabled() {
+            tokio::task::spawn(restore_subscriptions(
+                incoming_tx.clone(),
+                outgoing_rx.resubscribe(),
+                Arc::clone(&sub_data),
+                Arc::clone(&sessions),
+            ));
+        }
+
+        // Resubscribe elsewhere if our newHeads subscription silently stalls
+        let stall_multiplier = config.read().unwrap().subscription_stall_multiplier;
+        if stall_multiplier > 0 {
+            let watchdog_rpc = Arc::clone(&rpc_list_rwlock);
+            let watchdog_inc = incoming_tx.clone();
+            let watchdog_rx = outgoing_rx.resubscribe();
+            let watchdog_sub_data = Arc::clone(&sub_data);
+            let watchdog_notifier = notifier.clone();
+
+            tokio::task::spawn(async move {
+                subscription_watchdog(
+                    watchdog_rpc,
+                    watchdog_inc,
+                    watchdog_rx,
+                    watchdog_sub_data,
+                    watchdog_notifier,
+                    expected_block_time,
+                    stall_multiplier,
+                )
+                .await;
+            });
+        }
+
+        if do_health_check {
+            let dropped_rpc = Arc::clone(&rpc_list_rwlock);
+            let dropped_povrty = Arc::clone(&rpc_poverty_list);
+            let dropped_inc = incoming_tx.clone();
+            let dropped_rx = outgoing_rx.resubscribe();
+            let dropped_sub_data = Arc::clone(&sub_data);
+            let dropped_notifier = notifier.clone();
+
+            tokio::task::spawn(async move {
+                dropped_listener(
+                    dropped_rpc,
+                    dropped_povrty,
+                    ws_error_rx,
+                    dropped_inc,
+                    dropped_rx,
+                    dropped_sub_data,
+                    dropped_notifier,
+                )
+                .await
+            });
+
+            let cache_args = CacheArgs {
+                finalized_rx: finalized_rx.clone(),
+                named_numbers: named_blocknumbers.clone(),
+                cache: cache.clone(),
+                head_cache: head_cache.clone(),
+                memory: memory.clone(),
+                ens: ens.clone(),
+                hot: hot.clone(),
+            };
+
+            tokio::task::spawn(revalidate_on_new_blocks(
+                revalidator.clone(),
+                rpc_list_rwlock.clone(),
+                cache_args.clone(),
+                blocknum_rx_revalidate,
+                config.read().unwrap().ttl,
+            ));
+
+            tokio::task::spawn(tx_status_watcher(
+                rpc_list_rwlock.clone(),
+                sub_data.clone(),
+                blocknum_rx_confirmed.clone(),
+                finalized_rx.clone(),
+                config.read().unwrap().ttl,
+            ));
+        }
+
+        // Confirmed logs subscriptions are there whenever WS is, and need new
+        // heads to be released even if we don't health check
+        tokio::task::spawn(confirmed_logs_releaser(
+            sub_data.clone(),
+            blocknum_rx_confirmed,
+            finalized_rx.clone(),
+        ));
+
+        let heads_inc = incoming_tx.clone();
+        let heads_rx = outgoing_rx.resubscribe();
+        let heads_sub_data = sub_data.clone();
+        let heads_headers = headers.clone();
+        let cache_args = CacheArgs {
+            finalized_rx: finalized_rx.clone(),
+            named_numbers: named_blocknumbers.clone(),
+            cache: cache.clone(),
+            head_cache: head_cache.clone(),
+            memory: memory.clone(),
+            ens: ens.clone(),
+            hot: hot.clone(),
+        };
+
+        tokio::task::spawn(async move {
+            subscribe_to_new_heads(
+                heads_inc,
+                heads_rx,
+                blocknum_tx,
+                heads_sub_data,
+                cache_args,
+                heads_headers,
+                expected_block_time,
+            )
+            .await;
+        });
+    }
+
+    // Fill the cache before we accept connections so they don't all go upstream.
+    // There are no nodes to fetch from when replaying.
+    let (prewarm, ttl) = {
+        let config_guard = config.read().unwrap();
+        (config_guard.prewarm.clone(), config_guard.ttl)
+    };
+    if prewarm.is_enabled()
+        && !recorder
+            .as_ref()
+            .is_some_and(|recorder| recorder.is_replay())
+    {
+        // One instance of a fleet warming up is enough, once we know which
+        leadership.settle().await;
+        if leadership.is_leader() {
+            let cache_args = CacheArgs {
+                finalized_rx: finalized_rx.clone(),
+                named_numbers: named_blocknumbers.clone(),
+                cache: cache.clone(),
+                head_cache: head_cache.clone(),
+                memory: memory.clone(),
+                ens: ens.clone(),
+                hot: hot.clone(),
+            };
+            prewarm_cache(&prewarm, &rpc_list_rwlock, &cache_args, ttl).await;
+        }
+    }
+
+    // Serve HTTP/3 next to the regular listener if configured
+    let http3 = config.read().unwrap().http3.clone();
+    #[cfg(feature = "http3")]
+    if let Some(http3) = http3 {
+        let connection_params = ConnectionParams::new(
+            &rpc_list_rwlock,
+            RequestChannels::new(
+                finalized_rx_arc.clone(),
+                incoming_tx.clone(),
+                outgoing_rx.resubscribe(),
+            ),
+            &named_blocknumbers,
+            &head_cache,
+            &sub_data,
+            &sessions,
+            &cache,
+            &config,
+            &recorder,
+            &middleware,
+            &notifier,
+            &memory,
+            &ens,
+            &hot,
+            &gas_estimator,
+            &tx_tracker,
+            &consistent_reads,
+            &block_hashes,
+            &horizons,
+            &firehose,
+            &anomaly,
+            &usage,
+            &replay,
+            &revalidator,
+            &cors,
+            &tenants,
+            &scheduler,
+            &peer_cache,
+            &fallback,
+            &peer_counts,
+            &graphql,
+            &beacon,
+            &long_poll,
+            &log_index,
+            &receipts,
+            &headers,
+        );
+
+        tokio::task::spawn(async move {
+            if let Err(e) = listen_http3(http3, connection_params).await {
+                log_err!("HTTP/3 listener failed: {}", e);
+            }
+        });
+    }
+    #[cfg(not(feature = "http3"))]
+    if http3.is_some() {
+        log_wrn!(
+            "HTTP/3 configured, but blutgang was built without the `http3` feature! Ignoring it."
+        );
+    }
+
+    // We start a loop to continuously accept incoming connections
+    loop {
+        let (stream, socketaddr) = listener.accept().await?;
+        log_info!("Connection from: {}", socketaddr);
+
+        let channels = RequestChannels::new(
+            finalized_rx_arc.clone(),
+            incoming_tx.clone(),
+            outgoing_rx.resubscribe(),
+        );
+
+        let mut connection_params = ConnectionParams::new(
+            &rpc_list_rwlock,
+            channels,
+            &named_blocknumbers,
+            &head_cache,
+            &sub_data,
+            &sessions,
+            &cache,
+            &config,
+            &recorder,
+            &middleware,
+            &notifier,
+            &memory,
+            &ens,
+            &hot,
+            &gas_estimator,
+            &tx_tracker,
+            &consistent_reads,
+            &block_hashes,
+            &horizons,
+            &firehose,
+            &anomaly,
+            &usage,
+            &replay,
+            &revalidator,
+            &cors,
+            &tenants,
+            &scheduler,
+            &peer_cache,
+            &fallback,
+            &peer_counts,
+            &graphql,
+            &beacon,
+            &long_poll,
+            &log_index,
+            &receipts,
+            &headers,
+        );
+
+        // Spawn a tokio task to serve multiple connections concurrently
+        let listener_tls = listener_tls.clone();
+        tokio::task::spawn(async move {
+            // Use an adapter to access something implementing `tokio::io` traits as if they implement
+            // `hyper::rt` IO traits.
+            match listener_tls {
+                Some(listener_tls) => {
+                    let (stream, identity) = match listener_tls.accept(stream).await {
+                        Ok(accepted) => accepted,
+                        Err(e) => {
+                            log_wrn!("Rejected connection from {}: {}", socketaddr, e);
+                            return;
+                        }
+                    };
+                    if let Some(identity) = &identity {
+                        log_info!("{} authenticated as {}", socketaddr, identity.name);
+                    }
+                    connection_params.identity = identity;
+                    connection_params.server_name = stream
+                        .ssl()
+                        .servername(NameType::HOST_NAME)
+                        .map(str::to_string);
+                    accept!(TokioIo::new(stream), connection_params.clone());
+                }
+                None => {
+                    accept!(TokioIo::new(stream), connection_params.clone());
+                }
+            }
+        });
+    }
+}
@@ -0,0 +1,586 @@
+// Detect nodes returning data that diverges from their peers.
+//
+// A node can be reachable and following a head while still serving garbage:
+// a head far from everyone else's, gas prices way off the median, or empty
+// results for data its peers have. The regular health check doesn't catch
+// these, and a node reporting a bogus high head even pushes healthy nodes
+// into the poverty list.
+//
+// Each anomaly is a strike against the node, and a good answer of the same
+// kind clears them. After `strikes` in a row of the same kind the node is
+// quarantined, unless that would leave us without enough nodes: the quarantine
+// never takes the last active node, or more than half of all of them. Unlike
+// the poverty list, quarantined nodes never come back on their own, they have
+// to be released through the admin namespace after someone looked at them.
+use crate::{
+    config::types::AnomalySettings,
+    log_wrn,
+    notify::webhook::{
+        HealthEvent,
+        Notifier,
+    },
+    rpc::types::hex_to_decimal,
+    Rpc,
+};
+
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{
+            AtomicU64,
+            Ordering,
+        },
+        Arc,
+        Mutex,
+        RwLock,
+    },
+    time::Duration,
+};
+
+use serde_json::{
+    json,
+    Value,
+};
+use tokio::time::timeout;
+
+// Gas prices are checked every this many health checks
+const GAS_PRICE_CHECK_INTERVAL: u64 = 10;
+
+// We need a few peers for the median to mean anything
+const MIN_PEERS: usize = 3;
+
+// Methods where an empty result is checked against a peer
+const CROSS_CHECKED_METHODS: [&str; 4] = [
+    "eth_getBlockByNumber",
+    "eth_getBlockByHash",
+    "eth_getTransactionReceipt",
+    "eth_getLogs",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum AnomalyKind {
+    BlockNumber,
+    GasPrice,
+    EmptyResult,
+    MalformedResponse,
+}
+
+#[derive(Debug, Clone)]
+pub struct Quarantined {
+    pub rpc: Rpc,
+    pub reason: String,
+    pub since: String,
+}
+
+#[derive(Debug)]
+pub struct AnomalyDetector {
+    settings: AnomalySettings,
+    strikes: Mutex<HashMap<(AnomalyKind, String), u32>>,
+    quarantine: RwLock<Vec<Quarantined>>,
+    checks: AtomicU64,
+    notifier: Notifier,
+}
+
+impl Default for AnomalyDetector {
+    fn default() -> Self {
+        AnomalyDetector::new(AnomalySettings::default(), Notifier::disabled())
+    }
+}
+
+fn median(values: &[u128]) -> u128 {
+    let mut values = values.to_vec();
+    values.sort_unstable();
+    values[values.len() / 2]
+}
+
+// Null, or an empty list for `eth_getLogs`
+fn is_empty_result(response: &Value) -> bool {
+    match &response["result"] {
+        Value::Null => response.get("error").is_none(),
+        Value::Array(logs) => logs.is_empty(),
+        _ => false,
+    }
+}
+
+impl AnomalyDetector {
+    pub fn new(settings: AnomalySettings, notifier: Notifier) -> Self {
+        AnomalyDetector {
+            settings,
+            strikes: Mutex::new(HashMap::new()),
+            quarantine: RwLock::new(Vec::new()),
+            checks: AtomicU64::new(0),
+            notifier,
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.settings.enabled
+    }
+
+    // Nodes whose head is too far from the median. Unresponsive nodes
+    // report `0` and are left to the poverty list.
+    pub fn divergent_heads(&self, heads: &[(String, u64)]) -> Vec<(String, String)> {
+        let responsive: Vec<&(String, u64)> = heads.iter().filter(|(_, head)| *head > 0).collect();
+        if responsive.len() < MIN_PEERS {
+            return Vec::new();
+        }
+
+        let median = median(
+            &responsive
+                .iter()
+                .map(|(_, head)| *head as u128)
+                .collect::<Vec<_>>(),
+        ) as u64;
+
+        responsive
+            .into_iter()
+            .filter(|(_, head)| head.abs_diff(median) > self.settings.max_block_deviation)
+            .map(|(name, head)| {
+                (
+                    name.clone(),
+                    format!("reported head {} while the median head is {}", head, median),
+                )
+            })
+            .collect()
+    }
+
+    // Nodes whose gas price is too many times off the median
+    pub fn divergent_gas_prices(&self, prices: &[(String, u128)]) -> Vec<(String, String)> {
+        if prices.len() < MIN_PEERS {
+            return Vec::new();
+        }
+
+        let median = median(&prices.iter().map(|(_, price)| *price).collect::<Vec<_>>());
+        let ratio = self.settings.max_gas_price_ratio;
+
+        prices
+            .iter()
+            .filter(|(_, price)| {
+                let (price, median) = (*price as f64, median as f64);
+                price > median * ratio || price * ratio < median
+            })
+            .map(|(name, price)| {
+                (
+                    name.clone(),
+                    format!(
+                        "reported a gas price of {} while the median is {}",
+                        price, median
+                    ),
+                )
+            })
+            .collect()
+    }
+
+    // Add a strike against `name`. Returns true if it should be quarantined.
+    fn flag(&self, kind: AnomalyKind, name: &str, reason: &str) -> bool {
+        let mut strikes = self.strikes.lock().unwrap_or_else(|e| e.into_inner());
+        let count = strikes.entry((kind, name.to_string())).or_insert(0);
+        *count += 1;
+
+        log_wrn!(
+            "Anomaly on {} ({}/{}): {}",
+            name,
+            count,
+            self.settings.strikes,
+            reason
+        );
+
+        *count >= self.settings.strikes
+    }
+
+    // Only strikes in a row count
+    fn clear(&self, kind: AnomalyKind, name: &str) {
+        self.strikes
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(&(kind, name.to_string()));
+    }
+
+    // Move `name` from the rpc list to the quarantine, if we can spare it
+    pub fn quarantine(&self, rpc_list: &Arc<RwLock<Vec<Rpc>>>, name: &str, reason: String) {
+        let mut quarantine = self.quarantine.write().unwrap();
+        let rpc = {
+            let mut rpc_list = rpc_list.write().unwrap();
+            let position = match rpc_list.iter().position(|rpc| rpc.name == name) {
+                Some(position) => position,
+                None => return,
+            };
+
+            // A majority serving bad data is more likely a problem on our end,
+            // and no nodes at all is worse than a bad one
+            let total = rpc_list.len() + quarantine.len();
+            if rpc_list.len() <= 1 || (quarantine.len() + 1) * 2 > total {
+                log_wrn!(
+                    "Not quarantining {}, it's one of our last healthy nodes: {}",
+                    name,
+                    reason
+                );
+                return;
+            }
+            rpc_list.remove(position)
+        };
+
+        log_wrn!(
+            "Quarantined {}: {}. Release it with blutgang_release_from_quarantine.",
+            name,
+            reason
+        );
+        self.notifier.notify(HealthEvent::NodeUnhealthy {
+            node: name.to_string(),
+            reason: format!("quarantined, {}", reason),
+        });
+
+        quarantine.push(Quarantined {
+            rpc,
+            reason,
+            since: chrono::Utc::now().to_rfc3339(),
+        });
+    }
+
+    pub fn quarantined(&self) -> Vec<Quarantined> {
+        self.quarantine.read().unwrap().clone()
+    }
+
+    // Take the node at `index` out of the quarantine and clear its strikes
+    pub fn release(&self, index: usize) -> Option<Rpc> {
+        let mut quarantine = self.quarantine.write().unwrap();
+        if index >= quarantine.len() {
+            return None;
+        }
+
+        let mut rpc = quarantine.remove(index).rpc;
+        rpc.status.is_erroring = false;
+        self.strikes
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .retain(|(_, name), _| *name != rpc.name);
+
+        Some(rpc)
+    }
+
+    // Strike or clear every node in `names`, quarantining the ones that ran out
+    fn judge(
+        &self,
+        kind: AnomalyKind,
+        rpc_list: &Arc<RwLock<Vec<Rpc>>>,
+        names: Vec<String>,
+        anomalies: Vec<(String, String)>,
+    ) {
+        for name in names {
+            match anomalies.iter().find(|(anomalous, _)| *anomalous == name) {
+                Some((_, reason)) => {
+                    if self.flag(kind, &name, reason) {
+                        self.quarantine(rpc_list, &name, reason.clone());
+                    }
+                }
+                None => self.clear(kind, &name),
+            }
+        }
+    }
+
+    // Check the heads reported in the health check
+    pub fn check_heads(&self, rpc_list: &Arc<RwLock<Vec<Rpc>>>, heads: &[(String, u64)]) {
+        if !self.is_enabled() {
+            return;
+        }
+
+        let anomalies = self.divergent_heads(heads);
+        let names = heads.iter().map(|(name, _)| name.clone()).collect();
+        self.judge(AnomalyKind::BlockNumber, rpc_list, names, anomalies);
+    }
+
+    // Compare the gas prices of every node, every few health checks
+    pub async fn check_gas_prices(&self, rpc_list: &Arc<RwLock<Vec<Rpc>>>, ttl: u128) {
+        if !self.is_enabled()
+            || self.checks.fetch_add(1, Ordering::Relaxed) % GAS_PRICE_CHECK_INTERVAL != 0
+        {
+            return;
+        }
+
+        let rpcs = rpc_list.read().unwrap().clone();
+        let request = json!({"jsonrpc": "2.0", "id": 1, "method": "eth_gasPrice", "params": []});
+        let responses = futures::future::join_all(rpcs.iter().map(|rpc| {
+            timeout(
+                Duration::from_millis(ttl.try_into().unwrap_or(u64::MAX)),
+                rpc.send_request(request.clone()),
+            )
+        }))
+        .await;
+
+        let prices: Vec<(String, u128)> = rpcs
+            .iter()
+            .zip(responses)
+            .filter_map(|(rpc, response)| {
+                let response: Value = serde_json::from_str(&response.ok()?.ok()?).ok()?;
+                let price = hex_to_decimal(response["result"].as_str()?).ok()?;
+                Some((rpc.name.clone(), price as u128))
+            })
+            .collect();
+
+        let anomalies = self.divergent_gas_prices(&prices);
+        let names = prices.into_iter().map(|(name, _)| name).collect();
+        self.judge(AnomalyKind::GasPrice, rpc_list, names, anomalies);
+    }
+
+    // Strike `name` for a structurally invalid response. There's no such thing
+    // as a legitimate one, so these strikes aren't cleared by good responses.
+    pub fn malformed_response(&self, rpc_list: &Arc<RwLock<Vec<Rpc>>>, name: &str, reason: &str) {
+        if self.is_enabled() && self.flag(AnomalyKind::MalformedResponse, name, reason) {
+            self.quarantine(rpc_list, name, format!("malformed responses, {}", reason));
+        }
+    }
+
+    // Whether empty results for `tx` get checked against a peer
+    pub fn is_cross_checked(&self, tx: &Value) -> bool {
+        self.is_enabled()
+            && tx["method"]
+                .as_str()
+                .is_some_and(|method| CROSS_CHECKED_METHODS.contains(&method))
+    }
+
+    // If the node at `position` answered `tx` with an empty result, ask
+    // another node in the background. It's an anomaly if the peer has data.
+    pub fn cross_check(
+        self: &Arc<Self>,
+        mut tx: Value,
+        response: &str,
+        position: usize,
+        rpc_list: &Arc<RwLock<Vec<Rpc>>>,
+    ) {
+        if !self.is_cross_checked(&tx) {
+            return;
+        }
+        match serde_json::from_str::<Value>(response) {
+            Ok(response) if is_empty_result(&response) => {}
+            // Any good answer clears its strikes, so lagging a block now and
+            // then doesn't add up to a quarantine
+            Ok(response) if response.get("error").is_none() => {
+                let name = rpc_list
+                    .read()
+                    .unwrap()
+                    .get(position)
+                    .map(|rpc| rpc.name.clone());
+                if let Some(name) = name {
+                    self.clear(AnomalyKind::EmptyResult, &name);
+                }
+                return;
+            }
+            _ => return,
+        }
+
+        let (name, peer) = {
+            let rpc_list = rpc_list.read().unwrap();
+            if rpc_list.len() < 2 || position >= rpc_list.len() {
+                return;
+            }
+            (
+                rpc_list[position].name.clone(),
+                rpc_list[(position + 1) % rpc_list.len()].clone(),
+            )
+        };
+
+        tx["id"] = 1.into();
+        let detector = self.clone();
+        let rpc_list = rpc_list.clone();
+        tokio::task::spawn(async move {
+            let peer_response = match peer.send_request(tx.clone()).await {
+                Ok(response) => response,
+                Err(_) => return,
+            };
+            let peer_response: Value = match serde_json::from_str(&peer_response) {
+                Ok(response) => response,
+                Err(_) => return,
+            };
+
+            let anomalies =
+                if peer_response.get("error").is_none() && !is_empty_result(&peer_response) {
+                    vec![(
+                        name.clone(),
+                        format!(
+                            "returned nothing for a {} that {} has",
+                            tx["method"], peer.name
+                        ),
+                    )]
+                } else {
+                    Vec::new()
+                };
+            detector.judge(AnomalyKind::EmptyResult, &rpc_list, vec![name], anomalies);
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::node::MockNode;
+
+    fn detector(strikes: u32) -> AnomalyDetector {
+        AnomalyDetector::new(
+            AnomalySettings {
+                enabled: true,
+                strikes,
+                ..Default::default()
+            },
+            Notifier::disabled(),
+        )
+    }
+
+    fn rpc(name: &str) -> Rpc {
+        let mut rpc = Rpc::new(format!("http://{}", name), None, 5, 0, 1.0);
+        rpc.name = name.to_string();
+        rpc
+    }
+
+    #[test]
+    fn test_divergent_heads() {
+        let detector = detector(1);
+        let heads = vec![
+            ("a".to_string(), 100),
+            ("b".to_string(), 101),
+            ("c".to_string(), 100),
+            ("d".to_string(), 10_000),
+            ("e".to_string(), 0),
+        ];
+
+        let anomalies = detector.divergent_heads(&heads);
+        assert_eq!(anomalies.len(), 1);
+        assert_eq!(anomalies[0].0, "d");
+
+        // Not enough peers to tell who's right
+        assert!(detector.divergent_heads(&heads[2..]).is_empty());
+    }
+
+    #[test]
+    fn test_divergent_gas_prices() {
+        let detector = detector(1);
+        let prices = vec![
+            ("a".to_string(), 20),
+            ("b".to_string(), 25),
+            ("c".to_string(), 22),
+            ("d".to_string(), 1000),
+            ("e".to_string(), 1),
+        ];
+
+        let anomalies: Vec<String> = detector
+            .divergent_gas_prices(&prices)
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect();
+        assert_eq!(anomalies, vec!["d", "e"]);
+    }
+
+    #[test]
+    fn test_quarantine_after_strikes() {
+        let detector = detector(2);
+        let rpc_list = Arc::new(RwLock::new(vec![rpc("a"), rpc("b"), rpc("c"), rpc("d")]));
+        let heads = vec![
+            ("a".to_string(), 100),
+            ("b".to_string(), 100),
+            ("c".to_string(), 100),
+            ("d".to_string(), 10_000),
+        ];
+        let healthy = vec![
+            ("a".to_string(), 100),
+            ("b".to_string(), 100),
+            ("c".to_string(), 100),
+            ("d".to_string(), 100),
+        ];
+
+        // A healthy check in between resets the strikes
+        detector.check_heads(&rpc_list, &heads);
+        detector.check_heads(&rpc_list, &healthy);
+        detector.check_heads(&rpc_list, &heads);
+        assert_eq!(rpc_list.read().unwrap().len(), 4);
+
+        detector.check_heads(&rpc_list, &heads);
+        assert_eq!(rpc_list.read().unwrap().len(), 3);
+        assert_eq!(detector.quarantined()[0].rpc.name, "d");
+
+        // Only comes back when released
+        detector.check_heads(&rpc_list, &healthy[..3]);
+        assert_eq!(detector.quarantined().len(), 1);
+        assert_eq!(detector.release(0).unwrap().name, "d");
+        assert!(detector.quarantined().is_empty());
+        assert!(detector.release(0).is_none());
+    }
+
+    #[test]
+    fn test_keep_last_healthy_nodes() {
+        let detector = detector(1);
+        let rpc_list = Arc::new(RwLock::new(vec![rpc("a"), rpc("b"), rpc("c")]));
+
+        detector.malformed_response(&rpc_list, "a", "receipt has no logs");
+        assert_eq!(rpc_list.read().unwrap().len(), 2);
+
+        // Quarantining another would take out most of our nodes
+        detector.malformed_response(&rpc_list, "b", "receipt has no logs");
+        assert_eq!(rpc_list.read().unwrap().len(), 2);
+        assert_eq!(detector.quarantined().len(), 1);
+
+        // And never the last one
+        let rpc_list = Arc::new(RwLock::new(vec![rpc("a")]));
+        let detector = self::detector(1);
+        detector.malformed_response(&rpc_list, "a", "receipt has no logs");
+        assert_eq!(rpc_list.read().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_good_answer_clears_empty_results() {
+        let detector = Arc::new(detector(2));
+        let rpc_list = Arc::new(RwLock::new(vec![rpc("a"), rpc("b"), rpc("c")]));
+        let tx = json!({"jsonrpc": "2.0", "id": 1, "method": "eth_getTransactionReceipt", "params": ["0x01"]});
+
+        assert!(!detector.flag(AnomalyKind::EmptyResult, "a", "lagging"));
+        detector.cross_check(tx, r#"{"result":{"status":"0x1"}}"#, 0, &rpc_list);
+        assert!(!detector.flag(AnomalyKind::EmptyResult, "a", "lagging"));
+    }
+
+    #[test]
+    fn test_malformed_response() {
+        let detector = detector(2);
+        let rpc_list = Arc::new(RwLock::new(vec![rpc("a"), rpc("b")]));
+
+        detector.malformed_response(&rpc_list, "a", "receipt has no logs");
+        assert_eq!(rpc_list.read().unwrap().len(), 2);
+        detector.malformed_response(&rpc_list, "a", "receipt has no logs");
+        assert_eq!(rpc_list.read().unwrap().len(), 1);
+        assert_eq!(detector.quarantined()[0].rpc.name, "a");
+
+        // Only counted when anomaly detection is enabled
+        let rpc_list = Arc::new(RwLock::new(vec![rpc("a")]));
+        let detector = AnomalyDetector::default();
+        for _ in 0..10 {
+            detector.malformed_response(&rpc_list, "a", "receipt has no logs");
+        }
+        assert_eq!(rpc_list.read().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_cross_check_empty_result() {
+        let empty = MockNode::spawn(1).await.unwrap();
+        let full = MockNode::spawn(1).await.unwrap();
+        empty.set_response("eth_getTransactionReceipt", Value::Null);
+        full.set_response("eth_getTransactionReceipt", json!({"status": "0x1"}));
+
+        let rpc_list = Arc::new(RwLock::new(vec![
+            Rpc::new(empty.http_url(), None, 5, 0, 1.0),
+            Rpc::new(full.http_url(), None, 5, 0, 1.0),
+        ]));
+        let detector = Arc::new(detector(1));
+        let tx = json!({"jsonrpc": "2.0", "id": Value::Null, "method": "eth_getTransactionReceipt", "params": ["0x01"]});
+
+        // Results with data aren't checked
+        detector.cross_check(tx.clone(), r#"{"result":{"status":"0x1"}}"#, 0, &rpc_list);
+        detector.cross_check(tx, r#"{"result":null}"#, 0, &rpc_list);
+
+        for _ in 0..100 {
+            if !detector.quarantined().is_empty() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(full.request_count(), 1);
+        assert_eq!(detector.quarantined().len(), 1);
+        assert_eq!(rpc_list.read().unwrap().len(), 1);
+    }
+}
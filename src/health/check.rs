@@ -1,6 +1,12 @@
 use crate::{
+    config::types::{
+        ScoringSettings,
+        Settings,
+    },
     health::{
+        anomaly::AnomalyDetector,
         error::HealthError,
+        fork_choice::ForkChoiceMonitor,
         safe_block::{
             get_safe_block,
             NamedBlocknumbers,
@@ -8,17 +14,20 @@ use crate::{
     },
     log_info,
     log_wrn,
+    notify::webhook::{
+        HealthEvent,
+        Notifier,
+    },
     websocket::{
         subscription_manager::move_subscriptions,
         types::{
+            IncomingResponse,
+            SubscriptionData,
             WsChannelErr,
             WsconnMessage,
         },
     },
-    IncomingResponse,
     Rpc,
-    Settings,
-    SubscriptionData,
 };
 use tokio::sync::broadcast;
 
@@ -50,14 +59,31 @@ pub async fn health_check(
     finalized_tx: tokio::sync::watch::Sender<u64>,
     named_numbers_rwlock: &Arc<RwLock<NamedBlocknumbers>>,
     config: &Arc<RwLock<Settings>>,
+    notifier: Notifier,
+    anomaly: Arc<AnomalyDetector>,
 ) -> Result<(), HealthError> {
+    let mut fork_choice =
+        ForkChoiceMonitor::new(config.read().unwrap().fork_choice.clone(), notifier.clone());
+
     loop {
         let health_check_ttl = config.read().unwrap().health_check_ttl;
         let ttl = config.read().unwrap().ttl;
         let supress_rpc_check = config.read().unwrap().supress_rpc_check;
+        let scoring = config.read().unwrap().scoring.clone();
 
         sleep(Duration::from_millis(health_check_ttl)).await;
-        check(&rpc_list, &poverty_list, &ttl, supress_rpc_check).await?;
+        check(
+            &rpc_list,
+            &poverty_list,
+            &ttl,
+            supress_rpc_check,
+            &notifier,
+            &anomaly,
+            &mut fork_choice,
+            scoring.as_ref(),
+        )
+        .await?;
+        anomaly.check_gas_prices(&rpc_list, ttl).await;
         get_safe_block(
             &rpc_list,
             &finalized_tx,
@@ -69,11 +95,16 @@ pub async fn health_check(
 }
 
 // Track the head of each RPC and process them accordingly
+#[allow(clippy::too_many_arguments)]
 async fn check(
     rpc_list: &Arc<RwLock<Vec<Rpc>>>,
     poverty_list: &Arc<RwLock<Vec<Rpc>>>,
     ttl: &u128,
     supress_rpc_check: bool,
+    notifier: &Notifier,
+    anomaly: &AnomalyDetector,
+    fork_choice: &mut ForkChoiceMonitor,
+    scoring: Option<&ScoringSettings>,
 ) -> Result<(), HealthError> {
     if !supress_rpc_check {
         print!("\x1b[35mInfo:\x1b[0m Checking RPC health... ");
@@ -81,10 +112,26 @@ async fn check(
     // Head blocks reported by each RPC, we also use it to mark delinquents
     //
     // If a head is marked at `0` that means that the rpc is delinquent
-    let heads = head_check(rpc_list, *ttl).await?;
+    let mut heads = head_check(rpc_list, *ttl).await?;
+
+    // Rank nodes by how they've been doing lately
+    if let Some(scoring) = scoring {
+        score_nodes(rpc_list, &heads, scoring);
+    }
+
+    // Quarantine nodes with heads far from their peers before they can
+    // push everyone else into the poverty list
+    if anomaly.is_enabled() {
+        heads = quarantine_divergent(rpc_list, heads, anomaly);
+    }
 
     // Remove RPCs that are falling behind
-    let agreed_head = make_poverty(rpc_list, poverty_list, heads)?;
+    let agreed_head = make_poverty(rpc_list, poverty_list, heads, notifier)?;
+
+    // Nodes at the agreed head should also agree on its hash
+    fork_choice
+        .check(rpc_list, poverty_list, agreed_head, *ttl)
+        .await;
 
     // Check if any rpc nodes made it out
     // Its ok if we call them twice because some might have been accidentally put here
@@ -92,7 +139,7 @@ async fn check(
     // Do a head check over the current poverty list to see if any nodes are back to normal
     let poverty_heads = head_check(poverty_list, *ttl).await?;
 
-    escape_poverty(rpc_list, poverty_list, poverty_heads, agreed_head)?;
+    escape_poverty(rpc_list, poverty_list, poverty_heads, agreed_head, notifier)?;
 
     if !supress_rpc_check {
         println!("OK!");
@@ -164,11 +211,78 @@ async fn head_check(
     Ok(heads)
 }
 
+// Let the anomaly detector judge `heads` and drop the nodes it quarantined.
+// Quarantined nodes are removed from `rpc_list` so we remap the indices.
+fn quarantine_divergent(
+    rpc_list: &Arc<RwLock<Vec<Rpc>>>,
+    heads: Vec<HeadResult>,
+    anomaly: &AnomalyDetector,
+) -> Vec<HeadResult> {
+    let named_heads: Vec<(String, u64)> = {
+        let rpc_list = rpc_list.read().unwrap();
+        heads
+            .iter()
+            .map(|head| {
+                (
+                    rpc_list[head.rpc_list_index].name.clone(),
+                    head.reported_head,
+                )
+            })
+            .collect()
+    };
+
+    anomaly.check_heads(rpc_list, &named_heads);
+
+    let rpc_list = rpc_list.read().unwrap();
+    named_heads
+        .into_iter()
+        .filter_map(|(name, reported_head)| {
+            rpc_list
+                .iter()
+                .position(|rpc| rpc.name == name)
+                .map(|rpc_list_index| {
+                    HeadResult {
+                        rpc_list_index,
+                        reported_head,
+                    }
+                })
+        })
+        .collect()
+}
+
+// Recompute the score of every node in `rpc_list` from its latency and
+// how far behind the highest of `heads` it is
+fn score_nodes(rpc_list: &Arc<RwLock<Vec<Rpc>>>, heads: &[HeadResult], scoring: &ScoringSettings) {
+    let highest_head = heads
+        .iter()
+        .map(|head| head.reported_head)
+        .max()
+        .unwrap_or(0);
+
+    let rpc_list_guard = rpc_list.read().unwrap();
+    for head in heads {
+        let rpc = match rpc_list_guard.get(head.rpc_list_index) {
+            Some(rpc) => rpc,
+            None => continue,
+        };
+
+        // Not answering the head check counts as a failed request
+        let lag = if head.reported_head == 0 {
+            rpc.status.score.record(true);
+            None
+        } else {
+            Some(highest_head - head.reported_head)
+        };
+        rpc.status.score.update(rpc.status.latency, lag, scoring);
+    }
+}
+
 // Add unresponsive/erroring RPCs to the poverty list
 fn make_poverty(
     rpc_list: &Arc<RwLock<Vec<Rpc>>>,
     poverty_list: &Arc<RwLock<Vec<Rpc>>>,
     heads: Vec<HeadResult>,
+    notifier: &Notifier,
 ) -> Result<u64, HealthError> {
     // Get the highest head reported by the RPCs
     let mut highest_head = 0;
@@ -178,6 +292,20 @@ fn make_poverty(
         }
     }
 
+    // If most nodes don't agree with the highest head, the one(s) reporting it
+    // might be on a different fork than everyone else
+    let agreeing = heads
+        .iter()
+        .filter(|head| head.reported_head == highest_head)
+        .count();
+    if agreeing * 2 < heads.len() {
+        notifier.notify(HealthEvent::ConsensusDivergence {
+            highest_head,
+            agreeing,
+            total: heads.len(),
+        });
+    }
+
     // Mark all RPCs that dont report the highest head as erroring
     let mut rpc_list_guard = rpc_list.write().unwrap();
     let mut poverty_list_guard = poverty_list.write().unwrap();
@@ -190,6 +318,13 @@ fn make_poverty(
                 "{} is falling behind! Removing froma active RPC pool.",
                 rpc_list_guard[head.rpc_list_index].name
             );
+            notifier.notify(HealthEvent::NodeUnhealthy {
+                node: rpc_list_guard[head.rpc_list_index].name.clone(),
+                reason: format!(
+                    "reported head {} while the highest head is {}",
+                    head.reported_head, highest_head
+                ),
+            });
 
             // Add the RPC to the poverty list
             poverty_list_guard.push(rpc_list_guard[head.rpc_list_index].clone());
@@ -208,19 +343,33 @@ fn escape_poverty(
     poverty_list: &Arc<RwLock<Vec<Rpc>>>,
     poverty_heads: Vec<HeadResult>,
     agreed_head: u64,
+    notifier: &Notifier,
 ) -> Result<(), HealthError> {
     // Check if any nodes made it 🗣️🔥🔥🔥
     let mut poverty_list_guard = poverty_list.write().unwrap();
     let mut rpc_list_guard = rpc_list.write().unwrap();
 
     for head_result in poverty_heads {
-        if head_result.reported_head >= agreed_head {
+        // Nodes on a minority fork stay here until they're back on the majority
+        // one, and so do nodes on a non-canonical chain until they're off it
+        let status = &poverty_list_guard[head_result.rpc_list_index].status;
+        if head_result.reported_head >= agreed_head
+            && !status.minority_fork
+            && !status.non_canonical
+        {
             let mut rpc = poverty_list_guard[head_result.rpc_list_index].clone();
             rpc.status.is_erroring = false;
+            // New nodes have to prove themselves again
+            if let Some(canary) = &mut rpc.status.canary {
+                canary.restart();
+            }
             log_info!(
                 "{} is following the head again! Added to active RPC pool.",
                 rpc.name
             );
+            notifier.notify(HealthEvent::NodeRecovered {
+                node: rpc.name.clone(),
+            });
 
             // Move the RPC from the poverty list to the rpc list
             rpc_list_guard.push(rpc);
@@ -246,6 +395,7 @@ pub async fn send_dropped_to_poverty(
     rx: broadcast::Receiver<IncomingResponse>,
     sub_data: &Arc<SubscriptionData>,
     ws_conn_index: usize,
+    notifier: &Notifier,
 ) -> Result<(), HealthError> {
     {
         let mut rpc_list_guard = rpc_list.write().unwrap();
@@ -253,6 +403,11 @@ pub async fn send_dropped_to_poverty(
 
         // Check if the RPC is in the rpc_list
         if let Some(rpc) = rpc_list_guard.get(ws_conn_index) {
+            notifier.notify(HealthEvent::NodeUnhealthy {
+                node: rpc.name.clone(),
+                reason: "WebSocket connection dropped".to_string(),
+            });
+
             // Add the RPC to the poverty list
             poverty_list_guard.push(rpc.clone());
 
@@ -275,6 +430,7 @@ pub async fn dropped_listener(
     incoming_tx: mpsc::UnboundedSender<WsconnMessage>,
     rx: broadcast::Receiver<IncomingResponse>,
     sub_data: Arc<SubscriptionData>,
+    notifier: Notifier,
 ) -> Result<(), HealthError> {
     loop {
         let ws_err = ws_err_rx.recv().await;
@@ -288,6 +444,7 @@ pub async fn dropped_listener(
                     rx.resubscribe(),
                     &sub_data,
                     index,
+                    &notifier,
                 )
                 .await
                 .unwrap_or(());
@@ -341,7 +498,7 @@ mod tests {
         let heads = dummy_head_check();
 
         // Call the make_poverty function
-        let result = make_poverty(&rpc_list, &poverty_list, heads);
+        let result = make_poverty(&rpc_list, &poverty_list, heads, &Notifier::disabled());
         assert!(result.is_ok());
 
         // Check the state of RPCs after the test
@@ -355,6 +512,45 @@ mod tests {
         assert_eq!(poverty_list_guard.len(), 2);
     }
 
+    #[test]
+    fn test_poverty_notifies() {
+        let rpc_list = Arc::new(RwLock::new(vec![
+            Rpc::default(),
+            Rpc::default(),
+            Rpc::default(),
+        ]));
+        let poverty_list = Arc::new(RwLock::new(vec![]));
+        let (tx, mut rx) = mpsc::unbounded_channel();
+
+        make_poverty(
+            &rpc_list,
+            &poverty_list,
+            dummy_head_check(),
+            &Notifier::from_sender(tx),
+        )
+        .unwrap();
+
+        // Only 1/3 nodes agree on the head, so we should get a divergence
+        // warning as well as 2 unhealthy nodes
+        assert!(matches!(
+            rx.try_recv().unwrap(),
+            HealthEvent::ConsensusDivergence {
+                agreeing: 1,
+                total: 3,
+                ..
+            }
+        ));
+        assert!(matches!(
+            rx.try_recv().unwrap(),
+            HealthEvent::NodeUnhealthy { .. }
+        ));
+        assert!(matches!(
+            rx.try_recv().unwrap(),
+            HealthEvent::NodeUnhealthy { .. }
+        ));
+        assert!(rx.try_recv().is_err());
+    }
+
     #[test]
     fn test_escape() {
         // Create a mock RPC list and poverty list
@@ -381,7 +577,13 @@ mod tests {
         ];
 
         // Call the escape_poverty function
-        let result = escape_poverty(&rpc_list, &poverty_list, heads, 18193012);
+        let result = escape_poverty(
+            &rpc_list,
+            &poverty_list,
+            heads,
+            18193012,
+            &Notifier::disabled(),
+        );
         assert!(result.is_ok());
 
         // Check the state of RPCs after the test
@@ -393,4 +595,53 @@ mod tests {
         // The poverty list should have 1 RPC
         assert_eq!(poverty_list_guard.len(), 1);
     }
+
+    #[test]
+    fn test_minority_fork_stays_in_poverty() {
+        let mut rpc = Rpc::default();
+        rpc.status.is_erroring = true;
+        rpc.status.minority_fork = true;
+
+        let rpc_list = Arc::new(RwLock::new(vec![]));
+        let poverty_list = Arc::new(RwLock::new(vec![rpc]));
+        let heads = vec![HeadResult {
+            rpc_list_index: 0,
+            reported_head: 100,
+        }];
+
+        escape_poverty(&rpc_list, &poverty_list, heads, 100, &Notifier::disabled()).unwrap();
+        assert!(rpc_list.read().unwrap().is_empty());
+        assert_eq!(poverty_list.read().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_score_nodes() {
+        let mut fast = Rpc::default();
+        fast.status.latency = 1_000_000.0;
+        let mut behind = Rpc::default();
+        behind.status.latency = 1_000_000.0;
+        let rpc_list = Arc::new(RwLock::new(vec![fast, behind, Rpc::default()]));
+        let heads = vec![
+            HeadResult {
+                rpc_list_index: 0,
+                reported_head: 100,
+            },
+            HeadResult {
+                rpc_list_index: 1,
+                reported_head: 98,
+            },
+            HeadResult {
+                rpc_list_index: 2,
+                reported_head: 0,
+            },
+        ];
+
+        score_nodes(&rpc_list, &heads, &ScoringSettings::default());
+
+        let rpc_list = rpc_list.read().unwrap();
+        assert_eq!(rpc_list[0].rank_latency(), 1_000_000.0);
+        assert_eq!(rpc_list[1].rank_latency(), 201_000_000.0);
+        // Didn't answer, so all its requests failed
+        assert_eq!(rpc_list[2].status.score.report()["errorRate"], 1.0);
+    }
 }
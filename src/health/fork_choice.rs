@@ -0,0 +1,268 @@
+// Alarm when nodes follow different forks.
+//
+// Nodes at the same height can still disagree on which block is at that
+// height, and the regular health check only looks at block numbers. Every
+// health check we ask the active nodes for the hash of the agreed head. If
+// they disagree for longer than `threshold` we raise the alarm, and nodes
+// that aren't on the majority fork are moved to the poverty list until they
+// agree with everyone else again.
+use crate::{
+    config::types::ForkChoiceSettings,
+    log_err,
+    log_info,
+    notify::webhook::{
+        HealthEvent,
+        Notifier,
+    },
+    Rpc,
+};
+
+use std::{
+    cmp::Reverse,
+    collections::BTreeMap,
+    sync::{
+        Arc,
+        RwLock,
+    },
+    time::{
+        Duration,
+        Instant,
+    },
+};
+
+use futures::future::join_all;
+use serde_json::json;
+use tokio::time::timeout;
+
+// Block hash and the nodes that have it, biggest fork first
+type Forks = Vec<(String, Vec<String>)>;
+
+fn group_forks(hashes: Vec<(String, String)>) -> Forks {
+    let mut forks: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for (name, hash) in hashes {
+        forks.entry(hash).or_default().push(name);
+    }
+
+    let mut forks: Forks = forks.into_iter().collect();
+    forks.sort_by_key(|(_, nodes)| Reverse(nodes.len()));
+    forks
+}
+
+// The fork more nodes follow than any other, if there is one
+fn majority_fork(forks: &Forks) -> Option<&(String, Vec<String>)> {
+    match forks.as_slice() {
+        [first, second, ..] if first.1.len() == second.1.len() => None,
+        [first, ..] => Some(first),
+        [] => None,
+    }
+}
+
+#[derive(Debug)]
+pub struct ForkChoiceMonitor {
+    settings: Option<ForkChoiceSettings>,
+    notifier: Notifier,
+    // When the nodes started disagreeing
+    diverged_since: Option<Instant>,
+    alarmed: bool,
+}
+
+impl ForkChoiceMonitor {
+    pub fn new(settings: Option<ForkChoiceSettings>, notifier: Notifier) -> Self {
+        ForkChoiceMonitor {
+            settings,
+            notifier,
+            diverged_since: None,
+            alarmed: false,
+        }
+    }
+
+    // Hash every node reports for `height`. Sidelined nodes are asked too so
+    // we notice when they're back on the majority fork.
+    async fn hashes(
+        rpc_list: &Arc<RwLock<Vec<Rpc>>>,
+        poverty_list: &Arc<RwLock<Vec<Rpc>>>,
+        height: u64,
+        ttl: u128,
+    ) -> Vec<(String, String)> {
+        let nodes: Vec<Rpc> = {
+            let rpc_list = rpc_list.read().unwrap_or_else(|e| e.into_inner());
+            let poverty_list = poverty_list.read().unwrap_or_else(|e| e.into_inner());
+            rpc_list
+                .iter()
+                .chain(poverty_list.iter().filter(|rpc| rpc.status.minority_fork))
+                .cloned()
+                .collect()
+        };
+
+        let request_timeout = Duration::from_millis(ttl.try_into().unwrap_or(u64::MAX));
+        let params = json!([format!("0x{:x}", height), false]);
+        join_all(nodes.iter().map(|rpc| {
+            let params = params.clone();
+            async move {
+                let block = timeout(request_timeout, rpc.call("eth_getBlockByNumber", params))
+                    .await
+                    .ok()?
+                    .ok()?;
+                Some((rpc.name.clone(), block["hash"].as_str()?.to_lowercase()))
+            }
+        }))
+        .await
+        .into_iter()
+        .flatten()
+        .collect()
+    }
+
+    // Compare the hash of `height` across nodes
+    pub async fn check(
+        &mut self,
+        rpc_list: &Arc<RwLock<Vec<Rpc>>>,
+        poverty_list: &Arc<RwLock<Vec<Rpc>>>,
+        height: u64,
+        ttl: u128,
+    ) {
+        let threshold = match &self.settings {
+            Some(settings) if height > 0 => settings.threshold,
+            _ => return,
+        };
+
+        let forks = group_forks(Self::hashes(rpc_list, poverty_list, height, ttl).await);
+        if forks.len() < 2 {
+            if self.alarmed {
+                log_info!("Nodes agree on the head hash again.");
+            }
+            self.diverged_since = None;
+            self.alarmed = false;
+            Self::mark(rpc_list, poverty_list, &Vec::new(), None);
+            return;
+        }
+
+        let since = *self.diverged_since.get_or_insert_with(Instant::now);
+        if since.elapsed() < threshold {
+            return;
+        }
+
+        if !self.alarmed {
+            self.alarmed = true;
+            let event = HealthEvent::ForkChoiceDivergence {
+                height,
+                forks: forks.clone(),
+            };
+            log_err!("{}", event.message());
+            self.notifier.notify(event);
+        }
+
+        Self::mark(rpc_list, poverty_list, &forks, majority_fork(&forks));
+    }
+
+    // Flag nodes on diverging forks and sideline the ones not on `majority`
+    fn mark(
+        rpc_list: &Arc<RwLock<Vec<Rpc>>>,
+        poverty_list: &Arc<RwLock<Vec<Rpc>>>,
+        forks: &Forks,
+        majority: Option<&(String, Vec<String>)>,
+    ) {
+        let diverged = |name: &str| {
+            forks
+                .iter()
+                .any(|(_, nodes)| nodes.iter().any(|node| node == name))
+        };
+        let minority = |name: &str| {
+            majority.is_some_and(|(_, nodes)| nodes.iter().all(|node| node != name))
+                && diverged(name)
+        };
+
+        let mut rpc_list = rpc_list.write().unwrap_or_else(|e| e.into_inner());
+        let mut poverty_list = poverty_list.write().unwrap_or_else(|e| e.into_inner());
+
+        for rpc in poverty_list.iter_mut() {
+            rpc.status.fork_divergence = diverged(&rpc.name);
+            rpc.status.minority_fork = minority(&rpc.name);
+        }
+
+        for rpc in rpc_list.iter_mut() {
+            rpc.status.fork_divergence = diverged(&rpc.name);
+            rpc.status.minority_fork = minority(&rpc.name);
+            if rpc.status.minority_fork {
+                log_err!(
+                    "{} is not on the majority fork! Removing from active RPC pool.",
+                    rpc.name
+                );
+                rpc.status.is_erroring = true;
+                poverty_list.push(rpc.clone());
+            }
+        }
+        rpc_list.retain(|rpc| !rpc.status.minority_fork);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::node::MockNode;
+
+    fn hashes(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs
+            .iter()
+            .map(|(name, hash)| (name.to_string(), hash.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_majority_fork() {
+        let forks = group_forks(hashes(&[("a", "0x1"), ("b", "0x2"), ("c", "0x2")]));
+        assert_eq!(
+            forks[0],
+            ("0x2".to_string(), vec!["b".to_string(), "c".to_string()])
+        );
+        assert_eq!(majority_fork(&forks).unwrap().0, "0x2");
+
+        // No majority on a tie
+        let forks = group_forks(hashes(&[("a", "0x1"), ("b", "0x2")]));
+        assert!(majority_fork(&forks).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_fork_choice_check() {
+        let mut nodes = Vec::new();
+        for hash in ["0xaa", "0xaa", "0xbb"] {
+            let node = MockNode::spawn(1).await.unwrap();
+            node.set_response("eth_getBlockByNumber", json!({"hash": hash}));
+            nodes.push(node);
+        }
+        let rpc_list = Arc::new(RwLock::new(
+            nodes
+                .iter()
+                .map(|node| Rpc::new(node.http_url(), None, 6, 0, 10.0))
+                .collect::<Vec<_>>(),
+        ));
+        let poverty_list = Arc::new(RwLock::new(Vec::new()));
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let mut monitor = ForkChoiceMonitor::new(
+            Some(ForkChoiceSettings {
+                threshold: Duration::ZERO,
+            }),
+            Notifier::from_sender(tx),
+        );
+
+        // The odd one out gets sidelined
+        monitor.check(&rpc_list, &poverty_list, 10, 1000).await;
+        assert_eq!(rpc_list.read().unwrap().len(), 2);
+        assert!(rpc_list.read().unwrap()[0].status.fork_divergence);
+        let sidelined = poverty_list.read().unwrap()[0].clone();
+        assert!(sidelined.status.minority_fork && sidelined.status.is_erroring);
+        assert!(matches!(
+            rx.try_recv().unwrap(),
+            HealthEvent::ForkChoiceDivergence { height: 10, .. }
+        ));
+
+        // Only alarmed once
+        monitor.check(&rpc_list, &poverty_list, 10, 1000).await;
+        assert!(rx.try_recv().is_err());
+
+        // Back on the majority fork
+        nodes[2].set_response("eth_getBlockByNumber", json!({"hash": "0xaa"}));
+        monitor.check(&rpc_list, &poverty_list, 10, 1000).await;
+        assert!(!rpc_list.read().unwrap()[0].status.fork_divergence);
+        assert!(!poverty_list.read().unwrap()[0].status.minority_fork);
+    }
+}
@@ -1,4 +1,8 @@
 use crate::{
+    balancer::{
+        cache_entry::CacheKey,
+        memory::MemoryBudget,
+    },
     log_info,
     log_wrn,
 };
@@ -11,7 +15,6 @@ use std::{
     },
 };
 
-use sled::Batch;
 use tokio_stream::{
     wrappers::WatchStream,
     StreamExt,
@@ -19,10 +22,11 @@ use tokio_stream::{
 
 // Check if we need to do a reorg or if a new block has finalized.
 pub async fn manage_cache(
-    head_cache: &Arc<RwLock<BTreeMap<u64, Vec<String>>>>,
+    head_cache: &Arc<RwLock<BTreeMap<u64, Vec<CacheKey>>>>,
     blocknum_rx: tokio::sync::watch::Receiver<u64>,
     finalized_rx: Arc<tokio::sync::watch::Receiver<u64>>,
     cache: &Arc<sled::Db>,
+    memory: &MemoryBudget,
 ) -> Result<(), sled::Error> {
     let mut block_number = 0;
     let mut last_finalized = 0;
@@ -38,7 +42,7 @@ pub async fn manage_cache(
         // remove everything from the last block to the `new_block`
         if new_block <= block_number {
             log_wrn!("Reorg detected!\nRemoving stale entries from the cache.");
-            handle_reorg(head_cache, block_number, new_block, cache)?;
+            handle_reorg(head_cache, block_number, new_block, cache, memory)?;
         }
 
         // Check if finalized_stream has changed
@@ -58,29 +62,27 @@ pub async fn manage_cache(
 // If a reorg happens, we need to remove all querries in the reorg range
 // from the sled database.
 fn handle_reorg(
-    head_cache: &Arc<RwLock<BTreeMap<u64, Vec<String>>>>,
+    head_cache: &Arc<RwLock<BTreeMap<u64, Vec<CacheKey>>>>,
     block_number: u64,
     new_block: u64,
     cache: &Arc<sled::Db>,
+    memory: &MemoryBudget,
 ) -> Result<(), sled::Error> {
-    // sled batch
-    let mut batch = Batch::default();
-
     // Go over the head cache and get all the keys from block_number to new_block
     let mut head_cache_guard = head_cache.write().unwrap();
     for i in block_number..new_block + 1 {
         if let Some(keys) = head_cache_guard.get(&i) {
             for key in keys {
-                batch.remove(key.as_bytes());
+                // Removed one by one so we know how much each one freed
+                if let Some(entry) = cache.remove(key.as_bytes())? {
+                    memory.release(key.as_bytes(), entry.len());
+                }
             }
             // Remove the entry from the head_cache
             head_cache_guard.remove(&i);
         }
     }
 
-    // Apply the batch to the cache
-    cache.apply_batch(batch)?;
-
     Ok(())
 }
 
@@ -89,7 +91,7 @@ fn handle_reorg(
 // Once a new block finalizes, we can be sure that certain TXs wont
 // reorg, so theyre safe to be permanantly in the cache.
 fn remove_stale(
-    head_cache: &Arc<RwLock<BTreeMap<u64, Vec<String>>>>,
+    head_cache: &Arc<RwLock<BTreeMap<u64, Vec<CacheKey>>>>,
     block_number: u64,
 ) -> Result<(), sled::Error> {
     // Get the lowest block_number from the BTreeMap
@@ -144,20 +146,26 @@ mod tests {
         let head_cache = Arc::new(RwLock::new(BTreeMap::new()));
         let cache = Arc::new(Config::new().temporary(true).open().unwrap());
 
-        let _ = cache.insert("key1", "value1");
-        let _ = cache.insert("key2", "value2");
-        let _ = cache.insert("key3", "value3");
+        let keys: Vec<CacheKey> = ["key1", "key2", "key3"]
+            .iter()
+            .map(|key| CacheKey::from_request(key.as_bytes()))
+            .collect();
+        let memory = MemoryBudget::default();
+        for key in &keys {
+            let _ = cache.insert(key.as_bytes(), "value");
+            memory.record_insert(key.as_bytes(), 5);
+        }
 
         // Add some data to the head_cache
         {
             let mut head_cache_guard = head_cache.write().unwrap();
-            head_cache_guard.insert(1, vec!["key1".to_string()]);
-            head_cache_guard.insert(2, vec!["key2".to_string()]);
-            head_cache_guard.insert(3, vec!["key3".to_string()]);
+            head_cache_guard.insert(1, vec![keys[0]]);
+            head_cache_guard.insert(2, vec![keys[1]]);
+            head_cache_guard.insert(3, vec![keys[2]]);
         }
 
         // Call handle_reorg
-        let result = handle_reorg(&head_cache, 2, 3, &cache);
+        let result = handle_reorg(&head_cache, 2, 3, &cache, &memory);
 
         // Verify the result and check if the data is removed from the cache
         assert!(result.is_ok());
@@ -167,12 +175,13 @@ mod tests {
         assert!(!head_cache_guard.contains_key(&3));
 
         // Check if the data is removed from the cache
-        let key1 = cache.get("key1").unwrap();
+        let key1 = cache.get(keys[0].as_bytes()).unwrap();
         assert!(key1.is_some());
-        let key2 = cache.get("key2").unwrap();
+        let key2 = cache.get(keys[1].as_bytes()).unwrap();
         assert!(key2.is_none());
-        let key3 = cache.get("key3").unwrap();
+        let key3 = cache.get(keys[2].as_bytes()).unwrap();
         assert!(key3.is_none());
+        assert_eq!(memory.used_bytes(), 5);
     }
 
     #[test]
@@ -183,8 +192,8 @@ mod tests {
         // Add some data to the head_cache
         {
             let mut head_cache_guard = head_cache.write().unwrap();
-            head_cache_guard.insert(1, vec!["key1".to_string()]);
-            head_cache_guard.insert(2, vec!["key2".to_string()]);
+            head_cache_guard.insert(1, vec![CacheKey::from_request(b"key1")]);
+            head_cache_guard.insert(2, vec![CacheKey::from_request(b"key2")]);
         }
 
         // Call remove_stale
@@ -0,0 +1,261 @@
+// Route historical calls by how far back each node has state.
+//
+// Archive nodes are expensive, and most historical calls only go a few
+// thousand blocks back, which a pruned node can answer just as well. Every
+// `probe_interval` we binary search the oldest block each node still has
+// state for (its horizon) with `eth_getBalance` calls. Calls for blocks that
+// some of our nodes don't cover go to the node with the most recent horizon
+// that still covers them, so archive capacity is kept for calls nobody
+// else can answer.
+use crate::{
+    config::types::HistoryRoutingSettings,
+    health::safe_block::NamedBlocknumbers,
+    log_info,
+    Rpc,
+};
+
+use std::{
+    collections::HashMap,
+    sync::{
+        Arc,
+        RwLock,
+    },
+    time::Duration,
+};
+
+use futures::future::join_all;
+use serde_json::json;
+use tokio::time::{
+    interval,
+    timeout,
+    MissedTickBehavior,
+};
+
+// Account whose balance we ask for. Any account works, we only care
+// whether the node has the state to answer.
+const PROBE_ADDRESS: &str = "0x0000000000000000000000000000000000000000";
+
+#[derive(Debug, Default)]
+pub struct StateHorizons {
+    settings: Option<HistoryRoutingSettings>,
+    // Oldest block each node has state for, by node name
+    horizons: RwLock<HashMap<String, u64>>,
+}
+
+impl StateHorizons {
+    pub fn new(settings: Option<HistoryRoutingSettings>) -> Self {
+        Self {
+            settings,
+            horizons: RwLock::new(HashMap::new()),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.settings.is_some()
+    }
+
+    pub fn get(&self, node: &str) -> Option<u64> {
+        let horizons = self.horizons.read().unwrap_or_else(|e| e.into_inner());
+        horizons.get(node).copied()
+    }
+
+    pub fn insert(&self, node: &str, horizon: u64) {
+        let mut horizons = self.horizons.write().unwrap_or_else(|e| e.into_inner());
+        if horizons.insert(node.to_string(), horizon) != Some(horizon) {
+            log_info!("{} has state from block {}", node, horizon);
+        }
+    }
+
+    // Node in `list` with the most recent horizon that still covers `block`.
+    //
+    // Returns `None` if every node we know the horizon of covers it, so calls
+    // near the tip keep going through the regular rotation, or if none do.
+    pub fn pick(
+        &self,
+        list: &[Rpc],
+        block: u64,
+        group: Option<&str>,
+    ) -> Option<(Rpc, Option<usize>)> {
+        let margin = self.settings.as_ref()?.margin;
+        let horizons = self.horizons.read().unwrap_or_else(|e| e.into_inner());
+
+        let known = list
+            .iter()
+            .enumerate()
+            .filter(|(_, rpc)| group.map_or(true, |group| rpc.in_group(group)))
+            .filter_map(|(index, rpc)| horizons.get(&rpc.name).map(|horizon| (index, *horizon)))
+            .collect::<Vec<(usize, u64)>>();
+        let covering = known
+            .iter()
+            .filter(|(_, horizon)| *horizon == 0 || horizon.saturating_add(margin) <= block)
+            .copied()
+            .collect::<Vec<(usize, u64)>>();
+        if covering.len() == known.len() {
+            return None;
+        }
+
+        let (index, _) = covering.into_iter().min_by(|a, b| {
+            b.1.cmp(&a.1).then(
+                list[a.0]
+                    .status
+                    .latency
+                    .total_cmp(&list[b.0].status.latency),
+            )
+        })?;
+        Some((list[index].clone(), Some(index)))
+    }
+}
+
+// Whether `rpc` has state at `block`. `None` if it didn't answer at all.
+async fn has_state(rpc: &Rpc, block: u64, ttl: Duration) -> Option<bool> {
+    let balance = timeout(
+        ttl,
+        rpc.call(
+            "eth_getBalance",
+            json!([PROBE_ADDRESS, format!("0x{:x}", block)]),
+        ),
+    )
+    .await
+    .ok()?;
+    Some(balance.is_ok_and(|balance| balance.is_string()))
+}
+
+// Oldest block `rpc` has state for, out of `[0, latest]`.
+//
+// Hash-scheme geth keeps genesis state even when pruned, so we start
+// from block 1 and count a node that has it as an archive node.
+pub async fn probe_horizon(rpc: &Rpc, latest: u64, ttl: Duration) -> Option<u64> {
+    if has_state(rpc, 1, ttl).await? {
+        return Some(0);
+    }
+    if !has_state(rpc, latest, ttl).await? {
+        return None;
+    }
+
+    // `low` never has state, `high` always does
+    let (mut low, mut high) = (1, latest);
+    while high - low > 1 {
+        let middle = low + (high - low) / 2;
+        if has_state(rpc, middle, ttl).await? {
+            high = middle;
+        } else {
+            low = middle;
+        }
+    }
+
+    Some(high)
+}
+
+// Probe the horizon of every node once
+pub async fn probe_horizons(
+    horizons: &StateHorizons,
+    rpc_list: &Arc<RwLock<Vec<Rpc>>>,
+    latest: u64,
+    ttl: Duration,
+) {
+    let nodes: Vec<Rpc> = {
+        let rpc_list = rpc_list.read().unwrap_or_else(|e| e.into_inner());
+        rpc_list.clone()
+    };
+
+    let probed = join_all(
+        nodes
+            .iter()
+            .map(|rpc| async move { (rpc, probe_horizon(rpc, latest, ttl).await) }),
+    )
+    .await;
+    for (rpc, horizon) in probed {
+        if let Some(horizon) = horizon {
+            horizons.insert(&rpc.name, horizon);
+        }
+    }
+}
+
+pub async fn track_state_horizons(
+    horizons: Arc<StateHorizons>,
+    rpc_list: Arc<RwLock<Vec<Rpc>>>,
+    named_numbers: Arc<RwLock<NamedBlocknumbers>>,
+    ttl: u128,
+) {
+    let every = match &horizons.settings {
+        Some(settings) => settings.probe_interval,
+        None => return,
+    };
+    let ttl = Duration::from_millis(ttl.try_into().unwrap_or(u64::MAX));
+
+    let mut ticker = interval(every);
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        ticker.tick().await;
+
+        // Nothing to search through until the health check found the head
+        let latest = named_numbers
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .latest;
+        if latest == 0 {
+            continue;
+        }
+        probe_horizons(&horizons, &rpc_list, latest, ttl).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::node::MockNode;
+
+    fn rpc(name: &str, latency: f64) -> Rpc {
+        let mut rpc = Rpc::default();
+        rpc.name = name.to_string();
+        rpc.status.latency = latency;
+        rpc
+    }
+
+    #[tokio::test]
+    async fn test_probe_horizon() {
+        let archive = MockNode::spawn(1).await.unwrap();
+        let pruned = MockNode::spawn(1).await.unwrap();
+        pruned.set_state_horizon(9_000);
+        let ttl = Duration::from_secs(1);
+
+        let archive = Rpc::new(archive.http_url(), None, 6, 0, 10.0);
+        let pruned = Rpc::new(pruned.http_url(), None, 6, 0, 10.0);
+        assert_eq!(probe_horizon(&archive, 10_000, ttl).await, Some(0));
+        assert_eq!(probe_horizon(&pruned, 10_000, ttl).await, Some(9_000));
+        assert_eq!(probe_horizon(&pruned, 8_000, ttl).await, None);
+    }
+
+    #[test]
+    fn test_pick() {
+        let horizons = StateHorizons::new(Some(HistoryRoutingSettings {
+            probe_interval: Duration::from_secs(600),
+            margin: 100,
+        }));
+        let list = vec![
+            rpc("archive", 1.0),
+            rpc("full", 5.0),
+            rpc("pruned", 2.0).with_groups(vec!["fast".to_string()]),
+            rpc("unknown", 1.0),
+        ];
+
+        // We don't know anything yet
+        assert!(horizons.pick(&list, 10, None).is_none());
+
+        horizons.insert("archive", 0);
+        horizons.insert("full", 5_000);
+        horizons.insert("pruned", 9_000);
+
+        // Everyone has it
+        assert!(horizons.pick(&list, 9_500, None).is_none());
+        // Margin's too thin for the pruned node
+        assert_eq!(horizons.pick(&list, 9_050, None).unwrap().1, Some(1));
+        assert_eq!(horizons.pick(&list, 6_000, None).unwrap().1, Some(1));
+        assert_eq!(horizons.pick(&list, 10, None).unwrap().1, Some(0));
+
+        // Nobody in the group has it
+        assert!(horizons.pick(&list, 10, Some("fast")).is_none());
+
+        assert!(StateHorizons::default().pick(&list, 10, None).is_none());
+    }
+}
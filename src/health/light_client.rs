@@ -0,0 +1,915 @@
+// Check that the heads our nodes serve are canonical according to the
+// beacon chain, using the light client API of a beacon node.
+//
+// We bootstrap the sync committee from `light_client_checkpoint`, the root of
+// a beacon block we trust, and follow it from period to period with light
+// client updates. Every block we fetch the latest optimistic update, check
+// that a supermajority of the sync committee signed its header with their
+// aggregate BLS signature, and that the execution header is part of it. Nodes
+// that return a different block hash at that height are on a non-canonical
+// chain, so we move them to the poverty list. They stay there until they
+// serve the canonical block again.
+//
+// The beacon node can't make us accept a head the sync committee didn't sign,
+// but it can keep us from learning about new ones.
+use crate::{
+    health::error::HealthError,
+    log_info,
+    log_wrn,
+    notify::webhook::{
+        HealthEvent,
+        Notifier,
+    },
+    Rpc,
+};
+
+use std::{
+    sync::{
+        Arc,
+        RwLock,
+    },
+    time::Duration,
+};
+
+use blst::{
+    min_pk::{
+        PublicKey,
+        Signature,
+    },
+    BLST_ERROR,
+};
+use reqwest::Client;
+use serde_json::{
+    json,
+    Value,
+};
+use sha2::{
+    Digest,
+    Sha256,
+};
+use tokio::time::sleep;
+
+const BOOTSTRAP_PATH: &str = "/eth/v1/beacon/light_client/bootstrap/";
+const UPDATES_PATH: &str = "/eth/v1/beacon/light_client/updates";
+const OPTIMISTIC_UPDATE_PATH: &str = "/eth/v1/beacon/light_client/optimistic_update";
+const GENESIS_PATH: &str = "/eth/v1/beacon/genesis";
+const FORK_SCHEDULE_PATH: &str = "/eth/v1/config/fork_schedule";
+
+// Number of sync committee members
+const SYNC_COMMITTEE_SIZE: usize = 512;
+const SLOTS_PER_EPOCH: u64 = 32;
+// A sync committee serves for 256 epochs
+const SLOTS_PER_PERIOD: u64 = SLOTS_PER_EPOCH * 256;
+
+const DOMAIN_SYNC_COMMITTEE: [u8; 4] = [7, 0, 0, 0];
+const BLS_DST: &[u8] = b"BLS_SIG_BLS12381G2_XMD:SHA-256_SSWU_RO_POP_";
+
+// Where the sync committees are in the beacon state, and the execution
+// payload in the block body, counted at the depth of their branch. The same
+// for branches from before and after Electra made the state deeper.
+const CURRENT_SYNC_COMMITTEE_INDEX: u64 = 22;
+const NEXT_SYNC_COMMITTEE_INDEX: u64 = 23;
+const EXECUTION_PAYLOAD_INDEX: u64 = 9;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CanonicalHead {
+    pub number: u64,
+    pub hash: String,
+}
+
+fn hash(left: &[u8], right: &[u8]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(left);
+    hasher.update(right);
+    hasher.finalize().into()
+}
+
+// SSZ root of `chunks`, padded with empty chunks to a power of two
+fn merkleize(mut chunks: Vec<[u8; 32]>) -> [u8; 32] {
+    chunks.resize(chunks.len().next_power_of_two(), [0; 32]);
+    while chunks.len() > 1 {
+        chunks = chunks
+            .chunks(2)
+            .map(|pair| hash(&pair[0], &pair[1]))
+            .collect();
+    }
+    chunks[0]
+}
+
+// Split `bytes` into zero padded chunks
+fn pack(bytes: &[u8]) -> Vec<[u8; 32]> {
+    bytes
+        .chunks(32)
+        .map(|bytes| {
+            let mut chunk = [0; 32];
+            chunk[..bytes.len()].copy_from_slice(bytes);
+            chunk
+        })
+        .collect()
+}
+
+fn uint_chunk(value: u64) -> [u8; 32] {
+    let mut chunk = [0; 32];
+    chunk[..8].copy_from_slice(&value.to_le_bytes());
+    chunk
+}
+
+// Root we get by hashing `leaf` at `index` up the tree with `branch`
+fn branch_root(leaf: [u8; 32], branch: &[[u8; 32]], index: u64) -> [u8; 32] {
+    branch
+        .iter()
+        .enumerate()
+        .fold(leaf, |node, (depth, sibling)| {
+            match (index >> depth) & 1 {
+                1 => hash(sibling, &node),
+                _ => hash(&node, sibling),
+            }
+        })
+}
+
+fn hex_bytes(value: &Value) -> Option<Vec<u8>> {
+    let value = value.as_str()?;
+    hex::decode(value.strip_prefix("0x").unwrap_or(value)).ok()
+}
+
+fn root(value: &Value) -> Option<[u8; 32]> {
+    hex_bytes(value)?.try_into().ok()
+}
+
+fn branch(value: &Value) -> Option<Vec<[u8; 32]>> {
+    value.as_array()?.iter().map(root).collect()
+}
+
+// Beacon APIs send integers as decimal strings
+fn number(value: &Value) -> Option<u64> {
+    match value {
+        Value::String(number) => number.parse().ok(),
+        number => number.as_u64(),
+    }
+}
+
+fn invalid(reason: &str) -> HealthError {
+    HealthError::InvalidResponse(reason.to_string())
+}
+
+// Root of a `BeaconBlockHeader`
+fn beacon_header_root(header: &Value) -> Option<[u8; 32]> {
+    Some(merkleize(vec![
+        uint_chunk(number(&header["slot"])?),
+        uint_chunk(number(&header["proposer_index"])?),
+        root(&header["parent_root"])?,
+        root(&header["state_root"])?,
+        root(&header["body_root"])?,
+    ]))
+}
+
+// Root of an `ExecutionPayloadHeader` from Capella on
+fn execution_header_root(header: &Value) -> Option<[u8; 32]> {
+    let fee_recipient = hex_bytes(&header["fee_recipient"]).filter(|bytes| bytes.len() == 20)?;
+    let logs_bloom = hex_bytes(&header["logs_bloom"]).filter(|bytes| bytes.len() == 256)?;
+    let extra_data = hex_bytes(&header["extra_data"]).filter(|bytes| bytes.len() <= 32)?;
+    let base_fee: u128 = header["base_fee_per_gas"].as_str()?.parse().ok()?;
+    let mut base_fee_chunk = [0; 32];
+    base_fee_chunk[..16].copy_from_slice(&base_fee.to_le_bytes());
+
+    let mut fields = vec![
+        root(&header["parent_hash"])?,
+        pack(&fee_recipient)[0],
+        root(&header["state_root"])?,
+        root(&header["receipts_root"])?,
+        merkleize(pack(&logs_bloom)),
+        root(&header["prev_randao"])?,
+        uint_chunk(number(&header["block_number"])?),
+        uint_chunk(number(&header["gas_limit"])?),
+        uint_chunk(number(&header["gas_used"])?),
+        uint_chunk(number(&header["timestamp"])?),
+        hash(
+            &merkleize(pack(&extra_data)),
+            &uint_chunk(extra_data.len() as u64),
+        ),
+        base_fee_chunk,
+        root(&header["block_hash"])?,
+        root(&header["transactions_root"])?,
+        root(&header["withdrawals_root"])?,
+    ];
+    // Deneb added blob gas
+    if !header["blob_gas_used"].is_null() {
+        fields.push(uint_chunk(number(&header["blob_gas_used"])?));
+        fields.push(uint_chunk(number(&header["excess_blob_gas"])?));
+    }
+
+    Some(merkleize(fields))
+}
+
+// Public keys of a sync committee and its root
+fn parse_committee(committee: &Value) -> Option<(Vec<PublicKey>, [u8; 32])> {
+    let pubkeys: Vec<Vec<u8>> = committee["pubkeys"]
+        .as_array()?
+        .iter()
+        .map(|pubkey| hex_bytes(pubkey).filter(|bytes| bytes.len() == 48))
+        .collect::<Option<_>>()?;
+    let aggregate = hex_bytes(&committee["aggregate_pubkey"]).filter(|bytes| bytes.len() == 48)?;
+    if pubkeys.len() != SYNC_COMMITTEE_SIZE {
+        return None;
+    }
+
+    let pubkeys_root = merkleize(
+        pubkeys
+            .iter()
+            .map(|pubkey| merkleize(pack(pubkey)))
+            .collect(),
+    );
+    let committee_root = hash(&pubkeys_root, &merkleize(pack(&aggregate)));
+    let pubkeys = pubkeys
+        .iter()
+        .map(|pubkey| PublicKey::key_validate(pubkey).ok())
+        .collect::<Option<_>>()?;
+
+    Some((pubkeys, committee_root))
+}
+
+fn period(slot: u64) -> u64 {
+    slot / SLOTS_PER_PERIOD
+}
+
+// The sync committee we follow, and what we need to check its signatures
+#[derive(Debug)]
+pub struct SyncState {
+    genesis_validators_root: [u8; 32],
+    // Fork versions and the epoch they start at
+    forks: Vec<(u64, [u8; 4])>,
+    period: u64,
+    committee: Vec<PublicKey>,
+}
+
+impl SyncState {
+    // Start from the sync committee in `bootstrap`, which has to be for the
+    // block with root `checkpoint`
+    pub fn from_bootstrap(
+        bootstrap: &Value,
+        checkpoint: [u8; 32],
+        genesis_validators_root: [u8; 32],
+        forks: Vec<(u64, [u8; 4])>,
+    ) -> Result<Self, HealthError> {
+        let header = &bootstrap["data"]["header"]["beacon"];
+        if beacon_header_root(header) != Some(checkpoint) {
+            return Err(invalid("bootstrap is not for the checkpoint"));
+        }
+
+        let (committee, committee_root) =
+            parse_committee(&bootstrap["data"]["current_sync_committee"])
+                .ok_or(invalid("bootstrap has no valid sync committee"))?;
+        let committee_branch = branch(&bootstrap["data"]["current_sync_committee_branch"])
+            .ok_or(invalid("bootstrap has no sync committee branch"))?;
+        if Some(branch_root(
+            committee_root,
+            &committee_branch,
+            CURRENT_SYNC_COMMITTEE_INDEX,
+        )) != root(&header["state_root"])
+        {
+            return Err(invalid(
+                "sync committee is not part of the checkpoint state",
+            ));
+        }
+
+        Ok(SyncState {
+            genesis_validators_root,
+            forks,
+            period: period(number(&header["slot"]).unwrap_or_default()),
+            committee,
+        })
+    }
+
+    fn fork_version(&self, slot: u64) -> [u8; 4] {
+        let epoch = slot.max(1).saturating_sub(1) / SLOTS_PER_EPOCH;
+        self.forks
+            .iter()
+            .filter(|(start, _)| *start <= epoch)
+            .max_by_key(|(start, _)| *start)
+            .map_or([0; 4], |(_, version)| *version)
+    }
+
+    // Check that a supermajority of our committee signed `update`'s attested
+    // header. Returns the header.
+    fn verify_signature<'a>(&self, update: &'a Value) -> Result<&'a Value, HealthError> {
+        let header = &update["attested_header"]["beacon"];
+        let signature_slot =
+            number(&update["signature_slot"]).ok_or(invalid("update has no signature slot"))?;
+        let attested_slot = number(&header["slot"]).ok_or(invalid("update has no header"))?;
+        if period(signature_slot) != self.period || attested_slot >= signature_slot {
+            return Err(invalid("update is not signed by the committee we follow"));
+        }
+
+        let bits = hex_bytes(&update["sync_aggregate"]["sync_committee_bits"])
+            .filter(|bits| bits.len() * 8 == SYNC_COMMITTEE_SIZE)
+            .ok_or(invalid("update has no sync committee bits"))?;
+        let signers: Vec<&PublicKey> = self
+            .committee
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| (bits[i / 8] >> (i % 8)) & 1 == 1)
+            .map(|(_, pubkey)| pubkey)
+            .collect();
+        if signers.len() * 3 < SYNC_COMMITTEE_SIZE * 2 {
+            return Err(invalid("update lacks sync committee supermajority"));
+        }
+
+        let signature = hex_bytes(&update["sync_aggregate"]["sync_committee_signature"])
+            .and_then(|signature| Signature::from_bytes(&signature).ok())
+            .ok_or(invalid("update has no sync committee signature"))?;
+        let header_root = beacon_header_root(header).ok_or(invalid("update has no header"))?;
+
+        let mut fork_version = [0; 32];
+        fork_version[..4].copy_from_slice(&self.fork_version(signature_slot));
+        let fork_data_root = hash(&fork_version, &self.genesis_validators_root);
+        let mut domain = [0; 32];
+        domain[..4].copy_from_slice(&DOMAIN_SYNC_COMMITTEE);
+        domain[4..].copy_from_slice(&fork_data_root[..28]);
+        let signing_root = hash(&header_root, &domain);
+
+        match signature.fast_aggregate_verify(true, &signing_root, BLS_DST, &signers) {
+            BLST_ERROR::BLST_SUCCESS => Ok(header),
+            _ => Err(invalid("sync committee signature is invalid")),
+        }
+    }
+
+    // Move on to the next committee with the update for our period
+    pub fn apply_update(&mut self, update: &Value) -> Result<(), HealthError> {
+        let header = self.verify_signature(update)?;
+        if number(&header["slot"]).map(period) != Some(self.period) {
+            return Err(invalid("update is for another period"));
+        }
+
+        let (committee, committee_root) = parse_committee(&update["next_sync_committee"])
+            .ok_or(invalid("update has no valid next sync committee"))?;
+        let committee_branch = branch(&update["next_sync_committee_branch"])
+            .ok_or(invalid("update has no next sync committee branch"))?;
+        if Some(branch_root(
+            committee_root,
+            &committee_branch,
+            NEXT_SYNC_COMMITTEE_INDEX,
+        )) != root(&header["state_root"])
+        {
+            return Err(invalid(
+                "next sync committee is not part of the attested state",
+            ));
+        }
+
+        self.period += 1;
+        self.committee = committee;
+        Ok(())
+    }
+
+    // Period the committee that signed `update` serves in
+    fn signature_period(update: &Value) -> Option<u64> {
+        number(&update["signature_slot"]).map(period)
+    }
+
+    // The execution head of an optimistic update our committee signed
+    pub fn verify_head(&self, update: &Value) -> Result<CanonicalHead, HealthError> {
+        let header = self.verify_signature(update)?;
+
+        let execution = &update["attested_header"]["execution"];
+        let execution_root =
+            execution_header_root(execution).ok_or(invalid("update has no execution header"))?;
+        let execution_branch = branch(&update["attested_header"]["execution_branch"])
+            .ok_or(invalid("update has no execution branch"))?;
+        if Some(branch_root(
+            execution_root,
+            &execution_branch,
+            EXECUTION_PAYLOAD_INDEX,
+        )) != root(&header["body_root"])
+        {
+            return Err(invalid(
+                "execution header is not part of the attested block",
+            ));
+        }
+
+        let number =
+            number(&execution["block_number"]).ok_or(invalid("update has no block number"))?;
+        let hash = execution["block_hash"]
+            .as_str()
+            .ok_or(invalid("update has no block hash"))?
+            .to_lowercase();
+
+        Ok(CanonicalHead { number, hash })
+    }
+}
+
+async fn get_json(client: &Client, beacon_url: &str, path: &str) -> Result<Value, HealthError> {
+    client
+        .get(format!("{}{}", beacon_url.trim_end_matches('/'), path))
+        .send()
+        .await
+        .map_err(|e| HealthError::InvalidResponse(e.to_string()))?
+        .json()
+        .await
+        .map_err(|e| HealthError::InvalidResponse(e.to_string()))
+}
+
+// Bootstrap from `checkpoint` with the beacon node's genesis and fork schedule
+async fn bootstrap(
+    client: &Client,
+    beacon_url: &str,
+    checkpoint: [u8; 32],
+) -> Result<SyncState, HealthError> {
+    let genesis = get_json(client, beacon_url, GENESIS_PATH).await?;
+    let genesis_validators_root = root(&genesis["data"]["genesis_validators_root"])
+        .ok_or(invalid("no genesis validators root"))?;
+
+    let schedule = get_json(client, beacon_url, FORK_SCHEDULE_PATH).await?;
+    let forks = schedule["data"]
+        .as_array()
+        .ok_or(invalid("no fork schedule"))?
+        .iter()
+        .map(|fork| {
+            let version = hex_bytes(&fork["current_version"])?.try_into().ok()?;
+            Some((number(&fork["epoch"])?, version))
+        })
+        .collect::<Option<_>>()
+        .ok_or(invalid("fork schedule is invalid"))?;
+
+    let path = format!("{}0x{}", BOOTSTRAP_PATH, hex::encode(checkpoint));
+    let bootstrap = get_json(client, beacon_url, &path).await?;
+
+    SyncState::from_bootstrap(&bootstrap, checkpoint, genesis_validators_root, forks)
+}
+
+// Follow the sync committee up to the one that signed `update`
+async fn sync_to(
+    client: &Client,
+    beacon_url: &str,
+    state: &mut SyncState,
+    update: &Value,
+) -> Result<(), HealthError> {
+    let target =
+        SyncState::signature_period(update).ok_or(invalid("update has no signature slot"))?;
+
+    while state.period < target {
+        let path = format!("{}?start_period={}&count=1", UPDATES_PATH, state.period);
+        let updates = get_json(client, beacon_url, &path).await?;
+        state.apply_update(&updates[0]["data"])?;
+        log_info!("Following the sync committee of period {}", state.period);
+    }
+
+    Ok(())
+}
+
+// Returns `Some(true)` if `block` is the canonical block, or `None` if the
+// node doesn't have it yet
+fn is_canonical(head: &CanonicalHead, block: &Value) -> Option<bool> {
+    let hash = block["result"]["hash"].as_str()?;
+    Some(hash.to_lowercase() == head.hash)
+}
+
+// Move the nodes in `non_canonical` from `rpc_list` to `poverty_list`, where
+// they stay until they serve the canonical block again
+fn downgrade_non_canonical(
+    rpc_list: &Arc<RwLock<Vec<Rpc>>>,
+    poverty_list: &Arc<RwLock<Vec<Rpc>>>,
+    non_canonical: &[Rpc],
+    head: &CanonicalHead,
+    notifier: &Notifier,
+) {
+    let mut rpc_list_guard = rpc_list.write().unwrap();
+    let mut poverty_list_guard = poverty_list.write().unwrap();
+
+    for rpc in rpc_list_guard.iter_mut() {
+        if !non_canonical.iter().any(|node| node.is_same(rpc)) {
+            continue;
+        }
+        rpc.status.is_erroring = true;
+        rpc.status.non_canonical = true;
+
+        log_wrn!(
+            "{} is serving a non-canonical block at height {}! Removing from active RPC pool.",
+            rpc.name,
+            head.number
+        );
+        notifier.notify(HealthEvent::NodeUnhealthy {
+            node: rpc.name.clone(),
+            reason: format!(
+                "block {} does not match canonical hash {}",
+                head.number, head.hash
+            ),
+        });
+
+        poverty_list_guard.push(rpc.clone());
+    }
+
+    rpc_list_guard.retain(|rpc| !rpc.status.is_erroring);
+}
+
+// Let the health check take back nodes we downgraded once they serve the
+// canonical block
+fn restore_canonical(poverty_list: &Arc<RwLock<Vec<Rpc>>>, canonical: &[Rpc]) {
+    let mut poverty_list_guard = poverty_list.write().unwrap();
+    for rpc in poverty_list_guard.iter_mut() {
+        if rpc.status.non_canonical && canonical.iter().any(|node| node.is_same(rpc)) {
+            log_info!("{} is serving the canonical chain again.", rpc.name);
+            rpc.status.non_canonical = false;
+        }
+    }
+}
+
+// Fetch the canonical head and downgrade every node that disagrees with it
+async fn light_client_check(
+    client: &Client,
+    beacon_url: &str,
+    state: &mut SyncState,
+    rpc_list: &Arc<RwLock<Vec<Rpc>>>,
+    poverty_list: &Arc<RwLock<Vec<Rpc>>>,
+    notifier: &Notifier,
+) -> Result<(), HealthError> {
+    let update = get_json(client, beacon_url, OPTIMISTIC_UPDATE_PATH).await?;
+    sync_to(client, beacon_url, state, &update["data"]).await?;
+    let head = state.verify_head(&update["data"])?;
+
+    // Nodes we downgraded get checked too, so they can come back
+    let rpcs: Vec<Rpc> = {
+        let poverty_list = poverty_list.read().unwrap();
+        rpc_list
+            .read()
+            .unwrap()
+            .iter()
+            .chain(poverty_list.iter().filter(|rpc| rpc.status.non_canonical))
+            .cloned()
+            .collect()
+    };
+    let request = json!({
+        "jsonrpc": "2.0",
+        "id": 1,
+        "method": "eth_getBlockByNumber",
+        "params": [format!("0x{:x}", head.number), false],
+    });
+
+    let blocks =
+        futures::future::join_all(rpcs.iter().map(|rpc| rpc.send_request(request.clone()))).await;
+
+    // Nodes that errored or don't have the block yet are left to the health check
+    let mut canonical = Vec::new();
+    let mut non_canonical = Vec::new();
+    for (rpc, block) in rpcs.into_iter().zip(blocks) {
+        let block: Option<Value> = block
+            .ok()
+            .and_then(|block| serde_json::from_str(&block).ok());
+        match block.and_then(|block| is_canonical(&head, &block)) {
+            Some(true) => canonical.push(rpc),
+            Some(false) => non_canonical.push(rpc),
+            None => {}
+        }
+    }
+
+    if !non_canonical.is_empty() {
+        downgrade_non_canonical(rpc_list, poverty_list, &non_canonical, &head, notifier);
+    }
+    restore_canonical(poverty_list, &canonical);
+
+    Ok(())
+}
+
+// Check our nodes against the beacon chain every `interval`
+pub async fn light_client_sync(
+    rpc_list: Arc<RwLock<Vec<Rpc>>>,
+    poverty_list: Arc<RwLock<Vec<Rpc>>>,
+    beacon_url: String,
+    checkpoint: [u8; 32],
+    notifier: Notifier,
+    interval: Duration,
+) {
+    log_info!("Verifying heads against the beacon chain light client");
+    let client = Client::new();
+    let mut state = None;
+
+    loop {
+        sleep(interval).await;
+
+        let state = match &mut state {
+            Some(state) => state,
+            None => {
+                match bootstrap(&client, &beacon_url, checkpoint).await {
+                    Ok(bootstrapped) => state.insert(bootstrapped),
+                    Err(e) => {
+                        log_wrn!("Could not bootstrap the light client: {}", e);
+                        continue;
+                    }
+                }
+            }
+        };
+
+        if let Err(e) = light_client_check(
+            &client,
+            &beacon_url,
+            state,
+            &rpc_list,
+            &poverty_list,
+            &notifier,
+        )
+        .await
+        {
+            log_wrn!("Light client check failed: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use blst::min_pk::{
+        AggregateSignature,
+        SecretKey,
+    };
+
+    const GENESIS_VALIDATORS_ROOT: [u8; 32] = [9; 32];
+    const FORK_VERSION: [u8; 4] = [4, 0, 0, 0];
+
+    fn hex_value(bytes: &[u8]) -> Value {
+        json!(format!("0x{}", hex::encode(bytes)))
+    }
+
+    fn keys(seed: u8) -> Vec<SecretKey> {
+        (0..SYNC_COMMITTEE_SIZE)
+            .map(|i| {
+                let mut ikm = [seed; 32];
+                ikm[..2].copy_from_slice(&(i as u16).to_le_bytes());
+                SecretKey::key_gen(&ikm, &[]).unwrap()
+            })
+            .collect()
+    }
+
+    fn committee(keys: &[SecretKey]) -> Value {
+        let pubkeys: Vec<Value> = keys
+            .iter()
+            .map(|key| hex_value(&key.sk_to_pk().compress()))
+            .collect();
+        json!({"pubkeys": pubkeys, "aggregate_pubkey": hex_value(&keys[0].sk_to_pk().compress())})
+    }
+
+    fn header(slot: u64, state_root: [u8; 32], body_root: [u8; 32]) -> Value {
+        json!({
+            "slot": slot.to_string(),
+            "proposer_index": "7",
+            "parent_root": hex_value(&[1; 32]),
+            "state_root": hex_value(&state_root),
+            "body_root": hex_value(&body_root),
+        })
+    }
+
+    fn execution(number: u64) -> Value {
+        json!({
+            "parent_hash": hex_value(&[1; 32]),
+            "fee_recipient": hex_value(&[2; 20]),
+            "state_root": hex_value(&[3; 32]),
+            "receipts_root": hex_value(&[4; 32]),
+            "logs_bloom": hex_value(&[0; 256]),
+            "prev_randao": hex_value(&[5; 32]),
+            "block_number": number.to_string(),
+            "gas_limit": "30000000",
+            "gas_used": "21000",
+            "timestamp": "1700000000",
+            "extra_data": "0x626c7574",
+            "base_fee_per_gas": "7",
+            "block_hash": hex_value(&[0xab; 32]),
+            "transactions_root": hex_value(&[6; 32]),
+            "withdrawals_root": hex_value(&[7; 32]),
+            "blob_gas_used": "0",
+            "excess_blob_gas": "0",
+        })
+    }
+
+    fn state(keys: &[SecretKey]) -> SyncState {
+        let (committee, _) = parse_committee(&committee(keys)).unwrap();
+        SyncState {
+            genesis_validators_root: GENESIS_VALIDATORS_ROOT,
+            forks: vec![(0, [0; 4]), (10, FORK_VERSION)],
+            period: 1,
+            committee,
+        }
+    }
+
+    // An update at `slot` with `signers` of `keys` signing its header
+    fn update(keys: &[SecretKey], signers: usize, slot: u64, state_root: [u8; 32]) -> Value {
+        let execution = execution(20000000);
+        let execution_branch = [[8; 32]; 4];
+        let body_root = branch_root(
+            execution_header_root(&execution).unwrap(),
+            &execution_branch,
+            EXECUTION_PAYLOAD_INDEX,
+        );
+        let header = header(slot, state_root, body_root);
+
+        let state = state(keys);
+        let mut fork_version = [0; 32];
+        fork_version[..4].copy_from_slice(&state.fork_version(slot + 1));
+        let mut domain = [0; 32];
+        domain[..4].copy_from_slice(&DOMAIN_SYNC_COMMITTEE);
+        domain[4..].copy_from_slice(&hash(&fork_version, &GENESIS_VALIDATORS_ROOT)[..28]);
+        let signing_root = hash(&beacon_header_root(&header).unwrap(), &domain);
+
+        let signatures: Vec<Signature> = keys[..signers]
+            .iter()
+            .map(|key| key.sign(&signing_root, BLS_DST, &[]))
+            .collect();
+        let signatures: Vec<&Signature> = signatures.iter().collect();
+        let signature = AggregateSignature::aggregate(&signatures, true)
+            .unwrap()
+            .to_signature();
+
+        let mut bits = [0u8; SYNC_COMMITTEE_SIZE / 8];
+        for i in 0..signers {
+            bits[i / 8] |= 1 << (i % 8);
+        }
+
+        json!({
+            "attested_header": {
+                "beacon": header,
+                "execution": execution,
+                "execution_branch": execution_branch.iter().map(|node| hex_value(node)).collect::<Vec<_>>(),
+            },
+            "sync_aggregate": {
+                "sync_committee_bits": hex_value(&bits),
+                "sync_committee_signature": hex_value(&signature.compress()),
+            },
+            "signature_slot": (slot + 1).to_string(),
+        })
+    }
+
+    #[test]
+    fn test_branch_root() {
+        let leaves: Vec<[u8; 32]> = (0..8).map(|i| [i; 32]).collect();
+        let root = merkleize(leaves.clone());
+
+        // Siblings of leaf 5 from the bottom up
+        let branch = [
+            leaves[4],
+            hash(&leaves[6], &leaves[7]),
+            merkleize(leaves[..4].to_vec()),
+        ];
+        assert_eq!(branch_root(leaves[5], &branch, 5), root);
+        assert_ne!(branch_root(leaves[5], &branch, 4), root);
+    }
+
+    #[test]
+    fn test_from_bootstrap() {
+        let keys = keys(1);
+        let (_, committee_root) = parse_committee(&committee(&keys)).unwrap();
+        let committee_branch = [[3; 32]; 5];
+        let state_root = branch_root(
+            committee_root,
+            &committee_branch,
+            CURRENT_SYNC_COMMITTEE_INDEX,
+        );
+        let header = header(SLOTS_PER_PERIOD * 2 + 5, state_root, [0; 32]);
+        let checkpoint = beacon_header_root(&header).unwrap();
+        let bootstrap = json!({"data": {
+            "header": {"beacon": header},
+            "current_sync_committee": committee(&keys),
+            "current_sync_committee_branch": committee_branch.iter().map(|node| hex_value(node)).collect::<Vec<_>>(),
+        }});
+
+        let state =
+            SyncState::from_bootstrap(&bootstrap, checkpoint, GENESIS_VALIDATORS_ROOT, vec![])
+                .unwrap();
+        assert_eq!(state.period, 2);
+        assert_eq!(state.committee.len(), SYNC_COMMITTEE_SIZE);
+
+        // Only for the block we trust
+        assert!(
+            SyncState::from_bootstrap(&bootstrap, [0; 32], GENESIS_VALIDATORS_ROOT, vec![])
+                .is_err()
+        );
+
+        // With a committee that's part of its state
+        let mut forged = bootstrap.clone();
+        forged["data"]["current_sync_committee"] = committee(&self::keys(2));
+        assert!(
+            SyncState::from_bootstrap(&forged, checkpoint, GENESIS_VALIDATORS_ROOT, vec![])
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_verify_head() {
+        let keys = keys(1);
+        let state = state(&keys);
+        let slot = SLOTS_PER_PERIOD + 400;
+
+        assert_eq!(
+            state
+                .verify_head(&update(&keys, 400, slot, [0; 32]))
+                .unwrap(),
+            CanonicalHead {
+                number: 20000000,
+                hash: format!("0x{}", "ab".repeat(32)),
+            }
+        );
+
+        // Two thirds of the committee have to sign
+        assert!(state
+            .verify_head(&update(&keys, 300, slot, [0; 32]))
+            .is_err());
+
+        // Bits that claim signers who didn't sign
+        let mut forged = update(&keys, 400, slot, [0; 32]);
+        forged["sync_aggregate"]["sync_committee_bits"] = hex_value(&[0xff; 64]);
+        assert!(state.verify_head(&forged).is_err());
+
+        // Signed by a committee we don't follow
+        assert!(state
+            .verify_head(&update(&self::keys(2), 512, slot, [0; 32]))
+            .is_err());
+
+        // An execution header that isn't part of the signed block
+        let mut forged = update(&keys, 400, slot, [0; 32]);
+        forged["attested_header"]["execution"]["block_hash"] = hex_value(&[0xcd; 32]);
+        assert!(state.verify_head(&forged).is_err());
+
+        // Or from another period
+        assert!(state
+            .verify_head(&update(&keys, 400, SLOTS_PER_PERIOD * 2 + 1, [0; 32]))
+            .is_err());
+    }
+
+    #[test]
+    fn test_apply_update() {
+        let keys = keys(1);
+        let next_keys = self::keys(2);
+        let mut state = state(&keys);
+
+        let (_, next_root) = parse_committee(&committee(&next_keys)).unwrap();
+        let next_branch = [[4; 32]; 6];
+        let state_root = branch_root(next_root, &next_branch, NEXT_SYNC_COMMITTEE_INDEX);
+        let mut next = update(&keys, 512, SLOTS_PER_PERIOD + 100, state_root);
+        next["next_sync_committee"] = committee(&next_keys);
+        next["next_sync_committee_branch"] = json!(next_branch
+            .iter()
+            .map(|node| hex_value(node))
+            .collect::<Vec<_>>());
+
+        // A committee that isn't part of the attested state is refused
+        let mut forged = next.clone();
+        forged["next_sync_committee"] = committee(&self::keys(3));
+        assert!(state.apply_update(&forged).is_err());
+        assert_eq!(state.period, 1);
+
+        state.apply_update(&next).unwrap();
+        assert_eq!(state.period, 2);
+        assert!(state
+            .verify_head(&update(&next_keys, 512, SLOTS_PER_PERIOD * 2 + 1, [0; 32]))
+            .is_ok());
+    }
+
+    #[test]
+    fn test_is_canonical() {
+        let head = CanonicalHead {
+            number: 1,
+            hash: "0xabcd".to_string(),
+        };
+
+        assert_eq!(
+            is_canonical(&head, &json!({"result": {"hash": "0xABCD"}})),
+            Some(true)
+        );
+        assert_eq!(
+            is_canonical(&head, &json!({"result": {"hash": "0x1234"}})),
+            Some(false)
+        );
+        assert_eq!(is_canonical(&head, &json!({"result": null})), None);
+    }
+
+    #[test]
+    fn test_downgrade_non_canonical() {
+        // Two nodes on the same host have the same name
+        let rpc1 = Rpc::new("http://a.com/key1".to_string(), None, 10, 0, 1.0);
+        let rpc2 = Rpc::new("http://a.com/key2".to_string(), None, 10, 0, 1.0);
+        let rpc_list = Arc::new(RwLock::new(vec![rpc1.clone(), rpc2.clone()]));
+        let poverty_list = Arc::new(RwLock::new(vec![]));
+        let head = CanonicalHead {
+            number: 1,
+            hash: "0xabcd".to_string(),
+        };
+
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        downgrade_non_canonical(
+            &rpc_list,
+            &poverty_list,
+            std::slice::from_ref(&rpc2),
+            &head,
+            &Notifier::from_sender(tx),
+        );
+
+        assert_eq!(rpc_list.read().unwrap().len(), 1);
+        assert!(rpc_list.read().unwrap()[0].is_same(&rpc1));
+        assert!(poverty_list.read().unwrap()[0].is_same(&rpc2));
+        assert!(poverty_list.read().unwrap()[0].status.non_canonical);
+        assert!(matches!(
+            rx.try_recv(),
+            Ok(HealthEvent::NodeUnhealthy { node, .. }) if node == "http://a.com/"
+        ));
+
+        restore_canonical(&poverty_list, &[rpc1]);
+        assert!(poverty_list.read().unwrap()[0].status.non_canonical);
+        restore_canonical(&poverty_list, &[rpc2]);
+        assert!(!poverty_list.read().unwrap()[0].status.non_canonical);
+    }
+}
@@ -0,0 +1,260 @@
+// Take nodes out of rotation for maintenance.
+//
+// Draining a node through the admin namespace moves it out of the rpc or
+// poverty list into its own list, moves its subscriptions to other nodes and
+// waits for the requests it's still serving. The health check never sees
+// drained nodes, so they stay out until they're enabled again no matter how
+// healthy they look.
+use crate::{
+    log_info,
+    log_wrn,
+    websocket::{
+        subscription_manager::move_subscriptions,
+        types::{
+            IncomingResponse,
+            SubscriptionData,
+            WsconnMessage,
+        },
+    },
+    Rpc,
+};
+
+use std::{
+    sync::{
+        atomic::Ordering,
+        Arc,
+        Mutex,
+        RwLock,
+    },
+    time::{
+        Duration,
+        Instant,
+    },
+};
+
+use tokio::{
+    sync::{
+        broadcast,
+        mpsc,
+    },
+    time::{
+        sleep,
+        timeout,
+    },
+};
+
+// How often we check if a drained node is done with its requests
+const IN_FLIGHT_POLL: Duration = Duration::from_millis(10);
+
+#[derive(Debug, Clone)]
+pub struct Drained {
+    pub rpc: Rpc,
+    pub since: String,
+}
+
+// What we need to move subscriptions, only there when we're using WS
+struct WsHandles {
+    incoming_tx: mpsc::UnboundedSender<WsconnMessage>,
+    rx: broadcast::Receiver<IncomingResponse>,
+    sub_data: Arc<SubscriptionData>,
+}
+
+#[derive(Default)]
+pub struct Maintenance {
+    drained: RwLock<Vec<Drained>>,
+    ws: Mutex<Option<WsHandles>>,
+}
+
+impl Maintenance {
+    // Move subscriptions of drained nodes and reconnect through these
+    pub fn attach_ws(
+        &self,
+        incoming_tx: mpsc::UnboundedSender<WsconnMessage>,
+        rx: broadcast::Receiver<IncomingResponse>,
+        sub_data: Arc<SubscriptionData>,
+    ) {
+        *self.ws.lock().unwrap_or_else(|e| e.into_inner()) = Some(WsHandles {
+            incoming_tx,
+            rx,
+            sub_data,
+        });
+    }
+
+    fn ws(
+        &self,
+    ) -> Option<(
+        mpsc::UnboundedSender<WsconnMessage>,
+        broadcast::Receiver<IncomingResponse>,
+        Arc<SubscriptionData>,
+    )> {
+        self.ws
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .as_ref()
+            .map(|ws| {
+                (
+                    ws.incoming_tx.clone(),
+                    ws.rx.resubscribe(),
+                    Arc::clone(&ws.sub_data),
+                )
+            })
+    }
+
+    pub fn drained(&self) -> Vec<Drained> {
+        self.drained.read().unwrap().clone()
+    }
+
+    // Take `name` out of rotation and wait up to `wait` for the requests it's
+    // serving. Returns how many were still in flight when we stopped waiting,
+    // or `None` if we don't have a node called `name`.
+    pub async fn drain(
+        &self,
+        rpc_list: &Arc<RwLock<Vec<Rpc>>>,
+        poverty_list: &Arc<RwLock<Vec<Rpc>>>,
+        name: &str,
+        wait: Duration,
+    ) -> Option<u64> {
+        let (rpc, ws_conn_index) = {
+            let mut rpc_list = rpc_list.write().unwrap();
+            match rpc_list.iter().position(|rpc| rpc.name == name) {
+                Some(position) => (rpc_list.remove(position), Some(position)),
+                None => {
+                    let mut poverty_list = poverty_list.write().unwrap();
+                    let position = poverty_list.iter().position(|rpc| rpc.name == name)?;
+                    (poverty_list.remove(position), None)
+                }
+            }
+        };
+
+        log_info!(
+            "Draining {} for maintenance. Bring it back with blutgang_enableRpc.",
+            name
+        );
+        self.drained.write().unwrap().push(Drained {
+            rpc: rpc.clone(),
+            since: chrono::Utc::now().to_rfc3339(),
+        });
+
+        // Only nodes in the rpc list have WS connections we subscribed through
+        if let (Some(index), Some((incoming_tx, rx, sub_data))) = (ws_conn_index, self.ws()) {
+            match timeout(wait, move_subscriptions(&incoming_tx, rx, &sub_data, index)).await {
+                Ok(Ok(_)) => {}
+                Ok(Err(err)) => log_wrn!("Could not move subscriptions from {}: {}", name, err),
+                Err(_) => log_wrn!("Timed out moving subscriptions from {}", name),
+            }
+            incoming_tx.send(WsconnMessage::Reconnect()).unwrap_or(());
+        }
+
+        let start = Instant::now();
+        loop {
+            let in_flight = rpc.status.in_flight.load(Ordering::Relaxed);
+            if in_flight == 0 || start.elapsed() >= wait {
+                return Some(in_flight);
+            }
+            sleep(IN_FLIGHT_POLL).await;
+        }
+    }
+
+    // Put drained `name` back in the rpc list. Returns false if it isn't drained.
+    pub fn enable(&self, rpc_list: &Arc<RwLock<Vec<Rpc>>>, name: &str) -> bool {
+        let mut rpc = {
+            let mut drained = self.drained.write().unwrap();
+            match drained.iter().position(|drained| drained.rpc.name == name) {
+                Some(position) => drained.remove(position).rpc,
+                None => return false,
+            }
+        };
+
+        log_info!("Enabled {} after maintenance", name);
+        rpc.status.is_erroring = false;
+        rpc_list.write().unwrap().push(rpc);
+
+        if let Some((incoming_tx, _, _)) = self.ws() {
+            incoming_tx.send(WsconnMessage::Reconnect()).unwrap_or(());
+        }
+
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rpc(name: &str) -> Rpc {
+        let mut rpc = Rpc::default();
+        rpc.name = name.to_string();
+        rpc
+    }
+
+    #[tokio::test]
+    async fn test_drain_and_enable() {
+        let rpc_list = Arc::new(RwLock::new(vec![rpc("a"), rpc("b")]));
+        let poverty_list = Arc::new(RwLock::new(vec![rpc("c")]));
+        let maintenance = Maintenance::default();
+
+        assert_eq!(
+            maintenance
+                .drain(&rpc_list, &poverty_list, "a", Duration::ZERO)
+                .await,
+            Some(0)
+        );
+        // Unhealthy nodes can be drained too
+        assert_eq!(
+            maintenance
+                .drain(&rpc_list, &poverty_list, "c", Duration::ZERO)
+                .await,
+            Some(0)
+        );
+        assert_eq!(
+            maintenance
+                .drain(&rpc_list, &poverty_list, "d", Duration::ZERO)
+                .await,
+            None
+        );
+        assert_eq!(rpc_list.read().unwrap().len(), 1);
+        assert!(poverty_list.read().unwrap().is_empty());
+        assert_eq!(maintenance.drained().len(), 2);
+
+        assert!(maintenance.enable(&rpc_list, "c"));
+        assert!(!maintenance.enable(&rpc_list, "c"));
+        let names: Vec<String> = rpc_list
+            .read()
+            .unwrap()
+            .iter()
+            .map(|rpc| rpc.name.clone())
+            .collect();
+        assert_eq!(names, vec!["b", "c"]);
+    }
+
+    #[tokio::test]
+    async fn test_drain_waits_for_in_flight() {
+        let busy = rpc("a");
+        busy.status.in_flight.fetch_add(1, Ordering::Relaxed);
+        let rpc_list = Arc::new(RwLock::new(vec![busy.clone(), rpc("b")]));
+        let poverty_list = Arc::new(RwLock::new(Vec::new()));
+        let maintenance = Maintenance::default();
+
+        // Gives up once `wait` is over
+        assert_eq!(
+            maintenance
+                .drain(&rpc_list, &poverty_list, "a", Duration::ZERO)
+                .await,
+            Some(1)
+        );
+
+        tokio::spawn(async move {
+            sleep(Duration::from_millis(50)).await;
+            busy.status.in_flight.fetch_sub(1, Ordering::Relaxed);
+        });
+        let start = Instant::now();
+        assert!(maintenance.enable(&rpc_list, "a"));
+        assert_eq!(
+            maintenance
+                .drain(&rpc_list, &poverty_list, "a", Duration::from_secs(5))
+                .await,
+            Some(0)
+        );
+        assert!(start.elapsed() >= Duration::from_millis(50));
+    }
+}
@@ -1,4 +1,10 @@
+pub mod anomaly;
 pub mod check;
 pub mod error;
+pub mod fork_choice;
 pub mod head_cache;
+pub mod horizon;
+pub mod light_client;
+pub mod maintenance;
 pub mod safe_block;
+pub mod watchdog;
@@ -11,8 +11,13 @@ use crate::{
             Rpc,
         },
     },
+    verify::state::TrackedHeaders,
     websocket::{
         client::execute_ws_call,
+        reorgs::{
+            Head,
+            ReorgDetector,
+        },
         subscription_manager::move_subscriptions,
         types::{
             IncomingResponse,
@@ -179,13 +184,15 @@ async fn send_newheads_sub_message(
     };
 }
 
-// Subscribe to eth_subscribe("newHeads") and write to NamedBlocknumbers
+// Subscribe to eth_subscribe("newHeads") and write to NamedBlocknumbers,
+// and the state roots proofs are verified against to `headers`
 pub async fn subscribe_to_new_heads(
     incoming_tx: mpsc::UnboundedSender<WsconnMessage>,
     outgoing_rx: broadcast::Receiver<IncomingResponse>,
     blocknum_tx: watch::Sender<u64>,
     sub_data: Arc<SubscriptionData>,
     cache_args: CacheArgs,
+    headers: Arc<TrackedHeaders>,
     expected_block_time: u64,
 ) {
     // We basically have to create a new system-only user for subscribing to newHeads
@@ -208,10 +215,11 @@ pub async fn subscribe_to_new_heads(
     // New message == new head received. We can then update and process
     // everything associated with a new head block.
     let mut subscription_id: String = "".to_string();
+    let mut reorg_detector = ReorgDetector::default();
     loop {
         match timeout(Duration::from_millis(expected_block_time), rx.recv()).await {
             Ok(Some(msg)) => {
-                if let RequestResult::Subscription(sub) = msg {
+                if let Some(sub) = msg.event() {
                     let mut nn_rwlock = cache_args.named_numbers.write().unwrap();
                     let a = hex_to_decimal(sub["params"]["result"]["number"].as_str().unwrap())
                         .unwrap();
@@ -219,6 +227,20 @@ pub async fn subscribe_to_new_heads(
                     log_info!("New chain head: {}", a);
                     let _ = blocknum_tx.send(a);
                     nn_rwlock.latest = a;
+                    headers.observe(&sub["params"]["result"]);
+
+                    // Let reorg subscribers know if this head replaced blocks we've seen
+                    if let Some((head, parent_hash)) = Head::from_new_head(&sub["params"]["result"])
+                    {
+                        if let Some(reorg) = reorg_detector.observe(head, &parent_hash) {
+                            log_wrn!(
+                                "Reorg of depth {} at block {}",
+                                reorg.depth,
+                                reorg.new_head.number
+                            );
+                            sub_data.dispatch_reorg(&reorg);
+                        }
+                    }
                 }
             }
             Ok(None) => {
@@ -0,0 +1,152 @@
+// Watchdog for upstream `newHeads` subscriptions that silently stop
+// producing events while the socket stays open.
+use crate::{
+    log_err,
+    log_wrn,
+    notify::webhook::{
+        HealthEvent,
+        Notifier,
+    },
+    rpc::types::Rpc,
+    websocket::{
+        subscription_manager::move_subscriptions,
+        types::{
+            IncomingResponse,
+            NodeSubInfo,
+            SubscriptionData,
+            WsconnMessage,
+        },
+    },
+};
+
+use std::{
+    sync::{
+        Arc,
+        RwLock,
+    },
+    time::{
+        Duration,
+        Instant,
+    },
+};
+
+use tokio::{
+    sync::{
+        broadcast,
+        mpsc,
+    },
+    time::sleep,
+};
+
+// Params our `newHeads` subscription is registered under
+const NEW_HEADS_PARAMS: &str = r#"["newHeads"]"#;
+
+// Return the `newHeads` subscription if it hasn't produced an event in `stall_after`
+fn stalled_subscription(
+    sub_data: &SubscriptionData,
+    now: Instant,
+    stall_after: Duration,
+) -> Option<NodeSubInfo> {
+    let node_sub_info = sub_data.get_node_sub_info_by_params(NEW_HEADS_PARAMS)?;
+    let last_event = sub_data.last_event(&node_sub_info.subscription_id)?;
+
+    if now.saturating_duration_since(last_event) > stall_after {
+        return Some(node_sub_info);
+    }
+
+    None
+}
+
+// Check the `newHeads` subscription every block and move all subscriptions
+// off its node if it produced no events for `expected_block_time * stall_multiplier`.
+pub async fn subscription_watchdog(
+    rpc_list: Arc<RwLock<Vec<Rpc>>>,
+    incoming_tx: mpsc::UnboundedSender<WsconnMessage>,
+    outgoing_rx: broadcast::Receiver<IncomingResponse>,
+    sub_data: Arc<SubscriptionData>,
+    notifier: Notifier,
+    expected_block_time: u64,
+    stall_multiplier: u64,
+) {
+    let stall_after = Duration::from_millis(expected_block_time * stall_multiplier);
+
+    loop {
+        sleep(Duration::from_millis(expected_block_time)).await;
+
+        let stalled = match stalled_subscription(&sub_data, Instant::now(), stall_after) {
+            Some(stalled) => stalled,
+            None => continue,
+        };
+
+        let node = match rpc_list.read().unwrap().get(stalled.node_id) {
+            Some(rpc) => rpc.name.clone(),
+            None => format!("node {}", stalled.node_id),
+        };
+
+        log_wrn!(
+            "newHeads subscription on {} produced no events for {:?}! Resubscribing on another node.",
+            node,
+            stall_after
+        );
+        notifier.notify(HealthEvent::SubscriptionStalled {
+            node,
+            subscription_id: stalled.subscription_id.clone(),
+        });
+
+        // Give the resubscription a full window before checking again
+        sub_data.touch_subscription(&stalled.subscription_id);
+
+        if let Err(e) = move_subscriptions(
+            &incoming_tx,
+            outgoing_rx.resubscribe(),
+            &sub_data,
+            stalled.node_id,
+        )
+        .await
+        {
+            log_err!("Failed to move stalled subscriptions: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_stalled_subscription() {
+        let sub_data = SubscriptionData::new();
+        let stall_after = Duration::from_secs(36);
+
+        // Nothing to check before we subscribe
+        assert!(stalled_subscription(&sub_data, Instant::now(), stall_after).is_none());
+
+        sub_data.register_subscription(
+            json!({"method": "eth_subscribe", "params": ["newHeads"]}),
+            "0xabc".to_string(),
+            1,
+        );
+
+        let now = Instant::now();
+        assert!(stalled_subscription(&sub_data, now, stall_after).is_none());
+
+        let stalled =
+            stalled_subscription(&sub_data, now + Duration::from_secs(40), stall_after).unwrap();
+        assert_eq!(stalled.node_id, 1);
+        assert_eq!(stalled.subscription_id, "0xabc");
+    }
+
+    #[test]
+    fn test_other_subscriptions_ignored() {
+        let sub_data = SubscriptionData::new();
+        sub_data.register_subscription(
+            json!({"method": "eth_subscribe", "params": ["logs"]}),
+            "0xabc".to_string(),
+            0,
+        );
+
+        let later = Instant::now() + Duration::from_secs(600);
+        assert!(stalled_subscription(&sub_data, later, Duration::from_secs(36)).is_none());
+    }
+}
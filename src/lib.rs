@@ -0,0 +1,68 @@
+//! Blutgang's routing engine as a library.
+//!
+//! The `blutgang` binary is a thin wrapper around this crate. Embed it to run
+//! blutgang's load balancing, caching and subscription handling inside your own
+//! service, and hook into it with [`Middleware`].
+//!
+//! ```no_run
+//! use blutgang_core::{
+//!     engine,
+//!     MiddlewareStack,
+//!     Settings,
+//! };
+//! use std::sync::{
+//!     Arc,
+//!     RwLock,
+//! };
+//!
+//! # async fn start(settings: Settings) -> Result<(), Box<dyn std::error::Error>> {
+//! engine::run(Arc::new(RwLock::new(settings)), MiddlewareStack::default()).await
+//! # }
+//! ```
+//!
+//! Lower level building blocks live in their respective modules:
+//! - [`balancer`]: accepting requests, selecting nodes and caching responses
+//! - [`websocket`]: the WS server, upstream WS connections and subscriptions
+//! - [`health`]: node health checks and head/finalized block tracking
+//! - [`rpc`]: upstream node handles
+//! - [`transactions`]: decoding and tracking transactions we broadcast
+//! - [`beacon`]: load balancing for consensus layer beacon nodes
+//! - [`cluster`]: cooperation between the instances of a fleet
+//! - [`store`]: local indexes of finalized chain data
+//! - [`config`]: settings and CLI parsing
+//! - `client`: typed async client for blutgang's extensions, behind the `client` feature
+
+pub mod admin;
+pub mod balancer;
+pub mod beacon;
+pub mod bench;
+#[cfg(feature = "client")]
+pub mod client;
+pub mod cluster;
+pub mod config;
+pub mod engine;
+pub mod health;
+pub mod middleware;
+#[cfg(any(test, feature = "mock-node"))]
+pub mod mock;
+pub mod notify;
+pub mod rpc;
+pub mod store;
+pub mod transactions;
+pub mod verify;
+pub mod websocket;
+
+pub use crate::{
+    balancer::accept_http::{
+        accept_request,
+        ConnectionParams,
+        RequestChannels,
+    },
+    config::types::Settings,
+    middleware::types::{
+        Middleware,
+        MiddlewareStack,
+        RequestAction,
+    },
+    rpc::types::Rpc,
+};
@@ -0,0 +1,25 @@
+// Errors
+use std::error::Error;
+
+#[derive(Debug)]
+pub enum PluginError {
+    Load(String, String),
+    Call(String),
+    InvalidOutput(String),
+}
+
+impl std::fmt::Display for PluginError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            PluginError::Load(path, reason) => {
+                write!(f, "Could not load plugin {}: {}", path, reason)
+            }
+            PluginError::Call(reason) => write!(f, "Plugin call failed: {}", reason),
+            PluginError::InvalidOutput(reason) => {
+                write!(f, "Plugin returned invalid output: {}", reason)
+            }
+        }
+    }
+}
+
+impl Error for PluginError {}
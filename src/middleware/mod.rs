@@ -0,0 +1,5 @@
+#[cfg(feature = "wasm-plugins")]
+pub mod error;
+pub mod types;
+#[cfg(feature = "wasm-plugins")]
+pub mod wasm;
@@ -0,0 +1,173 @@
+use std::{
+    fmt::Debug,
+    sync::Arc,
+};
+
+use serde_json::Value;
+
+// What should happen to a request after a middleware has seen it
+#[derive(Debug, Clone, PartialEq)]
+pub enum RequestAction {
+    // Keep processing the (possibly modified) request
+    Continue,
+    // Stop here and send this response back to the user
+    Respond(Value),
+}
+
+// Hooks that run around the routing core.
+//
+// All hooks have no-op defaults, so implementors only need to override
+// the ones they care about. Hooks run on the hot path, keep them fast!
+pub trait Middleware: Send + Sync + Debug {
+    // Called for every incoming call before it is cached or forwarded
+    fn on_request(&self, _tx: &mut Value) -> RequestAction {
+        RequestAction::Continue
+    }
+
+    // Called with the response before it is sent back to the user
+    fn on_response(&self, _tx: &Value, _response: &mut Value) {}
+
+    // Called for every subscription event before it is fanned out to users
+    fn on_subscription_event(&self, _event: &mut Value) {}
+}
+
+// Ordered list of middleware. Hooks run in the order they were added.
+#[derive(Debug, Clone, Default)]
+pub struct MiddlewareStack {
+    layers: Vec<Arc<dyn Middleware>>,
+}
+
+impl MiddlewareStack {
+    pub fn with(mut self, middleware: Arc<dyn Middleware>) -> Self {
+        self.layers.push(middleware);
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.layers.is_empty()
+    }
+
+    // Run `on_request` hooks until one of them responds. Responses always
+    // carry the id of the request they answer.
+    pub fn on_request(&self, tx: &mut Value) -> RequestAction {
+        for layer in &self.layers {
+            if let RequestAction::Respond(mut response) = layer.on_request(tx) {
+                if let (Some(response), Some(id)) = (response.as_object_mut(), tx.get("id")) {
+                    response.insert("id".to_string(), id.clone());
+                }
+                return RequestAction::Respond(response);
+            }
+        }
+
+        RequestAction::Continue
+    }
+
+    // Run `on_response` hooks over a serialized response
+    pub fn on_response(&self, tx: &Value, response: String) -> String {
+        if self.is_empty() {
+            return response;
+        }
+
+        let mut response_value: Value = match serde_json::from_str(&response) {
+            Ok(rax) => rax,
+            Err(_) => return response,
+        };
+
+        for layer in &self.layers {
+            layer.on_response(tx, &mut response_value);
+        }
+
+        response_value.to_string()
+    }
+
+    pub fn on_subscription_event(&self, event: &mut Value) {
+        for layer in &self.layers {
+            layer.on_subscription_event(event);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[derive(Debug)]
+    struct DenyMethod(&'static str);
+
+    impl Middleware for DenyMethod {
+        fn on_request(&self, tx: &mut Value) -> RequestAction {
+            if tx["method"] == self.0 {
+                return RequestAction::Respond(json!({"error": "denied"}));
+            }
+            RequestAction::Continue
+        }
+    }
+
+    #[derive(Debug)]
+    struct Tag;
+
+    impl Middleware for Tag {
+        fn on_request(&self, tx: &mut Value) -> RequestAction {
+            tx["tagged"] = true.into();
+            RequestAction::Continue
+        }
+
+        fn on_response(&self, _tx: &Value, response: &mut Value) {
+            response["tagged"] = true.into();
+        }
+
+        fn on_subscription_event(&self, event: &mut Value) {
+            event["tagged"] = true.into();
+        }
+    }
+
+    #[test]
+    fn test_empty_stack() {
+        let stack = MiddlewareStack::default();
+        let mut tx = json!({"method": "eth_chainId"});
+
+        assert_eq!(stack.on_request(&mut tx), RequestAction::Continue);
+        assert_eq!(stack.on_response(&tx, "not json".to_string()), "not json");
+    }
+
+    #[test]
+    fn test_request_short_circuit() {
+        let stack = MiddlewareStack::default()
+            .with(Arc::new(DenyMethod("eth_sendRawTransaction")))
+            .with(Arc::new(Tag));
+
+        let mut tx = json!({"method": "eth_sendRawTransaction"});
+        assert_eq!(
+            stack.on_request(&mut tx),
+            RequestAction::Respond(json!({"error": "denied"}))
+        );
+        // Later layers should not have run
+        assert_eq!(tx["tagged"], Value::Null);
+
+        let mut tx = json!({"method": "eth_chainId"});
+        assert_eq!(stack.on_request(&mut tx), RequestAction::Continue);
+        assert_eq!(tx["tagged"], true);
+
+        // Responses answer the request they were made for
+        let mut tx = json!({"id": 7, "method": "eth_sendRawTransaction"});
+        assert_eq!(
+            stack.on_request(&mut tx),
+            RequestAction::Respond(json!({"id": 7, "error": "denied"}))
+        );
+    }
+
+    #[test]
+    fn test_response_and_subscription_hooks() {
+        let stack = MiddlewareStack::default().with(Arc::new(Tag));
+        let tx = json!({"method": "eth_chainId"});
+
+        let response = stack.on_response(&tx, r#"{"result":"0x1"}"#.to_string());
+        let response: Value = serde_json::from_str(&response).unwrap();
+        assert_eq!(response["tagged"], true);
+
+        let mut event = json!({"method": "eth_subscription"});
+        stack.on_subscription_event(&mut event);
+        assert_eq!(event["tagged"], true);
+    }
+}
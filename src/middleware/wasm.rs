@@ -0,0 +1,312 @@
+// Request/response filters loaded from operator provided WASM modules.
+//
+// A plugin must export its `memory` and an `alloc(len: i32) -> i32` function,
+// plus one or both of:
+// - `on_request(ptr: i32, len: i32) -> i64`
+// - `on_response(ptr: i32, len: i32) -> i64`
+//
+// Both receive a JSON document written to the memory returned by `alloc`, and
+// return the location of their JSON output packed as `(ptr << 32) | len`.
+// Returning 0 leaves everything unchanged.
+//
+// `on_request` receives the request and can return `{"request": ...}` to
+// rewrite it, or `{"respond": ...}` to answer it without forwarding.
+// `on_response` receives `{"request": ..., "response": ...}` and returns the
+// response that should be sent instead.
+//
+// Every call runs in a fresh instance with a fuel budget, so plugins can't keep
+// state between calls or hang blutgang. Plugins are often policy, so a failing
+// one is logged and the request gets an error instead of slipping through.
+use crate::{
+    log_err,
+    log_info,
+    middleware::{
+        error::PluginError,
+        types::{
+            Middleware,
+            MiddlewareStack,
+            RequestAction,
+        },
+    },
+};
+
+use std::{
+    fmt::Debug,
+    fs,
+    sync::Arc,
+};
+
+use serde_json::{
+    json,
+    Value,
+};
+use wasmtime::{
+    Config,
+    Engine,
+    InstancePre,
+    Linker,
+    Module,
+    Store,
+};
+
+// Max amount of fuel a single plugin call can burn through
+const PLUGIN_FUEL: u64 = 10_000_000;
+
+// Error we answer with when a plugin fails
+fn plugin_error(id: &Value) -> Value {
+    json!({
+        "jsonrpc": "2.0",
+        "id": id,
+        "error": {"code": -32603, "message": "Request rejected by plugin"},
+    })
+}
+
+pub struct WasmPlugin {
+    path: String,
+    engine: Engine,
+    instance_pre: InstancePre<()>,
+    has_on_request: bool,
+    has_on_response: bool,
+}
+
+impl Debug for WasmPlugin {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "WasmPlugin {{ path: {:?} }}", self.path)
+    }
+}
+
+impl WasmPlugin {
+    pub fn load(path: &str) -> Result<Self, PluginError> {
+        let bytes =
+            fs::read(path).map_err(|e| PluginError::Load(path.to_string(), e.to_string()))?;
+        Self::from_bytes(path, &bytes)
+    }
+
+    // Compile a plugin from WASM bytes or WAT text
+    fn from_bytes(path: &str, bytes: &[u8]) -> Result<Self, PluginError> {
+        let load_err = |e: wasmtime::Error| PluginError::Load(path.to_string(), e.to_string());
+
+        let mut config = Config::new();
+        config.consume_fuel(true);
+        let engine = Engine::new(&config).map_err(load_err)?;
+        let module = Module::new(&engine, bytes).map_err(load_err)?;
+
+        if module.get_export("memory").is_none() || module.get_export("alloc").is_none() {
+            return Err(PluginError::Load(
+                path.to_string(),
+                "plugins must export `memory` and `alloc`".to_string(),
+            ));
+        }
+
+        let has_on_request = module.get_export("on_request").is_some();
+        let has_on_response = module.get_export("on_response").is_some();
+
+        // Plugins don't get any imports, they can only transform JSON
+        let instance_pre = Linker::new(&engine)
+            .instantiate_pre(&module)
+            .map_err(load_err)?;
+
+        Ok(WasmPlugin {
+            path: path.to_string(),
+            engine,
+            instance_pre,
+            has_on_request,
+            has_on_response,
+        })
+    }
+
+    // Run `export` with `input` and return its output, if any
+    fn call(&self, export: &str, input: &[u8]) -> Result<Option<Value>, PluginError> {
+        let call_err = |e: wasmtime::Error| PluginError::Call(e.to_string());
+
+        let mut store = Store::new(&self.engine, ());
+        store.set_fuel(PLUGIN_FUEL).map_err(call_err)?;
+
+        let instance = self
+            .instance_pre
+            .instantiate(&mut store)
+            .map_err(call_err)?;
+        let memory = instance
+            .get_memory(&mut store, "memory")
+            .ok_or(PluginError::Call("`memory` is not a memory".to_string()))?;
+        let alloc = instance
+            .get_typed_func::<i32, i32>(&mut store, "alloc")
+            .map_err(call_err)?;
+        let func = instance
+            .get_typed_func::<(i32, i32), i64>(&mut store, export)
+            .map_err(call_err)?;
+
+        let ptr = alloc
+            .call(&mut store, input.len() as i32)
+            .map_err(call_err)?;
+        memory
+            .write(&mut store, ptr as usize, input)
+            .map_err(|e| PluginError::Call(e.to_string()))?;
+
+        let packed = func
+            .call(&mut store, (ptr, input.len() as i32))
+            .map_err(call_err)? as u64;
+        if packed == 0 {
+            return Ok(None);
+        }
+
+        let (ptr, len) = ((packed >> 32) as usize, (packed & 0xffff_ffff) as usize);
+        let mut output = vec![0; len];
+        memory
+            .read(&store, ptr, &mut output)
+            .map_err(|e| PluginError::InvalidOutput(e.to_string()))?;
+
+        serde_json::from_slice(&output)
+            .map(Some)
+            .map_err(|e| PluginError::InvalidOutput(e.to_string()))
+    }
+}
+
+impl Middleware for WasmPlugin {
+    fn on_request(&self, tx: &mut Value) -> RequestAction {
+        if !self.has_on_request {
+            return RequestAction::Continue;
+        }
+
+        let mut output = match self.call("on_request", tx.to_string().as_bytes()) {
+            Ok(Some(output)) => output,
+            Ok(None) => return RequestAction::Continue,
+            Err(e) => {
+                log_err!("WASM plugin {} failed on request: {}", self.path, e);
+                return RequestAction::Respond(plugin_error(&tx["id"]));
+            }
+        };
+
+        if let Some(response) = output.get_mut("respond") {
+            return RequestAction::Respond(response.take());
+        }
+        if let Some(request) = output.get_mut("request") {
+            *tx = request.take();
+        }
+
+        RequestAction::Continue
+    }
+
+    fn on_response(&self, tx: &Value, response: &mut Value) {
+        if !self.has_on_response {
+            return;
+        }
+
+        let input = json!({"request": tx, "response": response});
+        match self.call("on_response", input.to_string().as_bytes()) {
+            Ok(Some(output)) => *response = output,
+            Ok(None) => {}
+            Err(e) => {
+                log_err!("WASM plugin {} failed on response: {}", self.path, e);
+                *response = plugin_error(&tx["id"]);
+            }
+        }
+    }
+}
+
+// Load all plugins in `paths` and append them to `stack` in order
+pub fn load_plugins(
+    mut stack: MiddlewareStack,
+    paths: &[String],
+) -> Result<MiddlewareStack, PluginError> {
+    for path in paths {
+        stack = stack.with(Arc::new(WasmPlugin::load(path)?));
+        log_info!("Loaded WASM plugin: {}", path);
+    }
+
+    Ok(stack)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Plugin whose `export` always returns `output`
+    fn plugin_returning(export: &str, output: &str) -> WasmPlugin {
+        let wat = format!(
+            r#"(module
+                (memory (export "memory") 1)
+                (data (i32.const 16) "{}")
+                (func (export "alloc") (param i32) (result i32) i32.const 1024)
+                (func (export "{}") (param i32 i32) (result i64)
+                    i64.const {}))"#,
+            output.replace('"', "\\\""),
+            export,
+            (16u64 << 32) | output.len() as u64,
+        );
+
+        WasmPlugin::from_bytes("test.wat", wat.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn test_plugin_responds() {
+        let plugin = plugin_returning(
+            "on_request",
+            r#"{"respond":{"jsonrpc":"2.0","id":1,"result":"blocked"}}"#,
+        );
+        let mut tx = json!({"jsonrpc": "2.0", "id": 1, "method": "eth_sendRawTransaction"});
+
+        assert_eq!(
+            plugin.on_request(&mut tx),
+            RequestAction::Respond(json!({"jsonrpc": "2.0", "id": 1, "result": "blocked"}))
+        );
+    }
+
+    #[test]
+    fn test_plugin_rewrites_request() {
+        let plugin = plugin_returning(
+            "on_request",
+            r#"{"request":{"jsonrpc":"2.0","id":1,"method":"eth_chainId"}}"#,
+        );
+        let mut tx = json!({"jsonrpc": "2.0", "id": 1, "method": "net_version"});
+
+        assert_eq!(plugin.on_request(&mut tx), RequestAction::Continue);
+        assert_eq!(tx["method"], "eth_chainId");
+    }
+
+    #[test]
+    fn test_plugin_rewrites_response() {
+        let plugin = plugin_returning("on_response", r#"{"jsonrpc":"2.0","id":1,"result":"0x2"}"#);
+        let tx = json!({"jsonrpc": "2.0", "id": 1, "method": "eth_chainId"});
+        let mut response = json!({"jsonrpc": "2.0", "id": 1, "result": "0x1"});
+
+        plugin.on_response(&tx, &mut response);
+        assert_eq!(response["result"], "0x2");
+    }
+
+    #[test]
+    fn test_plugin_out_of_fuel() {
+        let wat = r#"(module
+            (memory (export "memory") 1)
+            (func (export "alloc") (param i32) (result i32) i32.const 0)
+            (func (export "on_request") (param i32 i32) (result i64)
+                (loop (br 0))
+                i64.const 0))"#;
+        let plugin = WasmPlugin::from_bytes("loop.wat", wat.as_bytes()).unwrap();
+        let mut tx = json!({"jsonrpc": "2.0", "id": 1, "method": "eth_chainId"});
+
+        assert!(plugin.call("on_request", b"{}").is_err());
+        assert_eq!(
+            plugin.on_request(&mut tx),
+            RequestAction::Respond(plugin_error(&json!(1)))
+        );
+        assert_eq!(tx["method"], "eth_chainId");
+    }
+
+    #[test]
+    fn test_plugin_bad_output() {
+        let plugin = plugin_returning("on_response", "not json");
+        let tx = json!({"jsonrpc": "2.0", "id": 1, "method": "eth_chainId"});
+        let mut response = json!({"jsonrpc": "2.0", "id": 1, "result": "0x1"});
+
+        plugin.on_response(&tx, &mut response);
+        assert_eq!(response["error"]["code"], -32603);
+        assert_eq!(response["id"], 1);
+    }
+
+    #[test]
+    fn test_plugin_missing_exports() {
+        let wat = r#"(module (memory (export "memory") 1))"#;
+        assert!(WasmPlugin::from_bytes("empty.wat", wat.as_bytes()).is_err());
+    }
+}
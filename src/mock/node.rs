@@ -0,0 +1,353 @@
+// Minimal in-process Ethereum JSON-RPC node for hermetic tests.
+//
+// Serves HTTP and WS on the same port, keeps a fake chain head that only
+// moves when `advance` is called, and emits `newHeads` to WS subscribers.
+use crate::balancer::format::incoming_to_value;
+
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    net::SocketAddr,
+    sync::{
+        atomic::{
+            AtomicU64,
+            AtomicUsize,
+            Ordering,
+        },
+        Arc,
+        RwLock,
+    },
+};
+
+use futures::{
+    sink::SinkExt,
+    stream::StreamExt,
+};
+use http_body_util::Full;
+use hyper::{
+    body::Bytes,
+    server::conn::http1,
+    service::service_fn,
+    Request,
+    Response,
+};
+use hyper_tungstenite::{
+    is_upgrade_request,
+    upgrade,
+    HyperWebsocket,
+};
+use hyper_util_blutgang::rt::TokioIo;
+use serde_json::{
+    json,
+    Value,
+};
+use tokio::{
+    net::TcpListener,
+    sync::broadcast,
+};
+use tungstenite::Message;
+
+#[derive(Debug)]
+struct MockState {
+    block_number: AtomicU64,
+    chain_id: u64,
+    // Oldest block we have state for
+    state_horizon: AtomicU64,
+    requests: AtomicUsize,
+    responses: RwLock<HashMap<String, Value>>,
+    heads_tx: broadcast::Sender<u64>,
+}
+
+// Handle to a running mock node. The node lives as long as the runtime.
+#[derive(Debug, Clone)]
+pub struct MockNode {
+    addr: SocketAddr,
+    state: Arc<MockState>,
+}
+
+impl MockNode {
+    // Spawn a mock node on a random local port
+    pub async fn spawn(chain_id: u64) -> std::io::Result<MockNode> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+
+        let (heads_tx, _) = broadcast::channel(64);
+        let state = Arc::new(MockState {
+            block_number: AtomicU64::new(1),
+            chain_id,
+            state_horizon: AtomicU64::new(0),
+            requests: AtomicUsize::new(0),
+            responses: RwLock::new(HashMap::new()),
+            heads_tx,
+        });
+
+        let state_listener = Arc::clone(&state);
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let io = TokioIo::new(stream);
+                let state = Arc::clone(&state_listener);
+
+                tokio::spawn(async move {
+                    let _ = http1::Builder::new()
+                        .serve_connection(
+                            io,
+                            service_fn(|req| handle_request(req, Arc::clone(&state))),
+                        )
+                        .with_upgrades()
+                        .await;
+                });
+            }
+        });
+
+        Ok(MockNode { addr, state })
+    }
+
+    pub fn http_url(&self) -> String {
+        format!("http://{}", self.addr)
+    }
+
+    pub fn ws_url(&self) -> String {
+        format!("ws://{}", self.addr)
+    }
+
+    pub fn block_number(&self) -> u64 {
+        self.state.block_number.load(Ordering::SeqCst)
+    }
+
+    // Amount of JSON-RPC calls the node has answered
+    pub fn request_count(&self) -> usize {
+        self.state.requests.load(Ordering::SeqCst)
+    }
+
+    // Always answer `method` with `result`, overriding the built-in answer
+    pub fn set_response(&self, method: &str, result: Value) {
+        self.state
+            .responses
+            .write()
+            .unwrap()
+            .insert(method.to_string(), result);
+    }
+
+    // Act like a pruned node without state before `block`, apart from
+    // genesis, which hash-scheme geth keeps around
+    pub fn set_state_horizon(&self, block: u64) {
+        self.state.state_horizon.store(block, Ordering::SeqCst);
+    }
+
+    // Mine a new block and emit it to `newHeads` subscribers
+    pub fn advance(&self) -> u64 {
+        let number = self.state.block_number.fetch_add(1, Ordering::SeqCst) + 1;
+        let _ = self.state.heads_tx.send(number);
+        number
+    }
+}
+
+fn block_header(number: u64) -> Value {
+    json!({
+        "number": format!("0x{:x}", number),
+        "hash": format!("0x{:064x}", number),
+        "parentHash": format!("0x{:064x}", number.saturating_sub(1)),
+    })
+}
+
+// Answer a single JSON-RPC call
+fn answer(state: &MockState, tx: &Value) -> Value {
+    state.requests.fetch_add(1, Ordering::SeqCst);
+
+    let method = tx["method"].as_str().unwrap_or_default();
+    let head = state.block_number.load(Ordering::SeqCst);
+
+    if let Some(result) = state.responses.read().unwrap().get(method) {
+        return json!({"jsonrpc": "2.0", "id": tx["id"], "result": result});
+    }
+
+    let result = match method {
+        "eth_blockNumber" => json!(format!("0x{:x}", head)),
+        "eth_chainId" => json!(format!("0x{:x}", state.chain_id)),
+        "net_version" => json!(state.chain_id.to_string()),
+        "web3_clientVersion" => json!("blutgang-mock-node"),
+        "eth_getBlockByNumber" => {
+            let number = match tx["params"][0].as_str() {
+                Some(number) if number.starts_with("0x") => {
+                    u64::from_str_radix(&number[2..], 16).unwrap_or(head)
+                }
+                _ => head,
+            };
+            block_header(number)
+        }
+        "eth_getBalance" => {
+            let number = tx["params"][1]
+                .as_str()
+                .and_then(|number| number.strip_prefix("0x"))
+                .and_then(|number| u64::from_str_radix(number, 16).ok())
+                .unwrap_or(head);
+            if number != 0 && number < state.state_horizon.load(Ordering::SeqCst) {
+                return json!({
+                    "jsonrpc": "2.0",
+                    "id": tx["id"],
+                    "error": {"code": -32000, "message": "missing trie node"},
+                });
+            }
+            json!("0x0")
+        }
+        _ => {
+            return json!({
+                "jsonrpc": "2.0",
+                "id": tx["id"],
+                "error": {"code": -32601, "message": "Method not found"},
+            })
+        }
+    };
+
+    json!({"jsonrpc": "2.0", "id": tx["id"], "result": result})
+}
+
+async fn handle_request(
+    mut req: Request<hyper::body::Incoming>,
+    state: Arc<MockState>,
+) -> Result<Response<Full<Bytes>>, Infallible> {
+    if is_upgrade_request(&req) {
+        let (response, websocket) = match upgrade(&mut req, None) {
+            Ok(rax) => rax,
+            Err(_) => {
+                return Ok(Response::builder()
+                    .status(400)
+                    .body(Full::new(Bytes::new()))
+                    .unwrap())
+            }
+        };
+
+        tokio::spawn(serve_ws(websocket, state));
+        return Ok(response);
+    }
+
+    let tx = match incoming_to_value(req).await {
+        Ok(tx) => tx,
+        Err(_) => Value::Null,
+    };
+
+    Ok(Response::builder()
+        .status(200)
+        .header("Content-Type", "application/json")
+        .body(Full::new(Bytes::from(answer(&state, &tx).to_string())))
+        .unwrap())
+}
+
+async fn serve_ws(websocket: HyperWebsocket, state: Arc<MockState>) {
+    let websocket = match websocket.await {
+        Ok(websocket) => websocket,
+        Err(_) => return,
+    };
+    let (mut sink, mut stream) = websocket.split();
+    let mut heads_rx = state.heads_tx.subscribe();
+    let mut subscriptions: Vec<String> = Vec::new();
+
+    loop {
+        tokio::select! {
+            message = stream.next() => {
+                let tx: Value = match message {
+                    Some(Ok(Message::Text(text))) => match serde_json::from_str(&text) {
+                        Ok(tx) => tx,
+                        Err(_) => continue,
+                    },
+                    Some(Ok(_)) => continue,
+                    _ => return,
+                };
+
+                let response = match tx["method"].as_str() {
+                    Some("eth_subscribe") if tx["params"][0] == "newHeads" => {
+                        state.requests.fetch_add(1, Ordering::SeqCst);
+                        let id = format!("0x{:x}", rand::random::<u64>());
+                        subscriptions.push(id.clone());
+                        json!({"jsonrpc": "2.0", "id": tx["id"], "result": id})
+                    }
+                    Some("eth_unsubscribe") => {
+                        state.requests.fetch_add(1, Ordering::SeqCst);
+                        let id = tx["params"][0].as_str().unwrap_or_default();
+                        let existed = subscriptions.iter().any(|sub| sub == id);
+                        subscriptions.retain(|sub| sub != id);
+                        json!({"jsonrpc": "2.0", "id": tx["id"], "result": existed})
+                    }
+                    _ => answer(&state, &tx),
+                };
+
+                if sink.send(Message::Text(response.to_string())).await.is_err() {
+                    return;
+                }
+            }
+            head = heads_rx.recv() => {
+                let number = match head {
+                    Ok(number) => number,
+                    Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(_) => return,
+                };
+
+                for id in &subscriptions {
+                    let notification = json!({
+                        "jsonrpc": "2.0",
+                        "method": "eth_subscription",
+                        "params": {"subscription": id, "result": block_header(number)},
+                    });
+
+                    if sink.send(Message::Text(notification.to_string())).await.is_err() {
+                        return;
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Rpc;
+    use tokio_tungstenite::connect_async;
+
+    #[tokio::test]
+    async fn test_mock_node_http() {
+        let node = MockNode::spawn(1).await.unwrap();
+        let rpc = Rpc::new(node.http_url(), Some(node.ws_url()), 10, 0, 1.0);
+
+        assert_eq!(rpc.block_number().await.unwrap(), node.block_number());
+        node.advance();
+        assert_eq!(rpc.block_number().await.unwrap(), 2);
+        assert_eq!(node.request_count(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_mock_node_custom_response() {
+        let node = MockNode::spawn(1).await.unwrap();
+        let rpc = Rpc::new(node.http_url(), None, 10, 0, 1.0);
+        node.set_response("eth_gasPrice", json!("0x2a"));
+
+        let response = rpc
+            .send_request(json!({"jsonrpc": "2.0", "id": 1, "method": "eth_gasPrice"}))
+            .await
+            .unwrap();
+        let response: Value = serde_json::from_str(&response).unwrap();
+        assert_eq!(response["result"], "0x2a");
+    }
+
+    #[tokio::test]
+    async fn test_mock_node_new_heads() {
+        let node = MockNode::spawn(1).await.unwrap();
+        let (ws, _) = connect_async(node.ws_url()).await.unwrap();
+        let (mut sink, mut stream) = ws.split();
+
+        let sub =
+            json!({"jsonrpc": "2.0", "id": 1, "method": "eth_subscribe", "params": ["newHeads"]});
+        sink.send(Message::Text(sub.to_string())).await.unwrap();
+
+        let response = stream.next().await.unwrap().unwrap().into_text().unwrap();
+        let response: Value = serde_json::from_str(&response).unwrap();
+        let sub_id = response["result"].as_str().unwrap().to_string();
+
+        node.advance();
+
+        let head = stream.next().await.unwrap().unwrap().into_text().unwrap();
+        let head: Value = serde_json::from_str(&head).unwrap();
+        assert_eq!(head["params"]["subscription"], sub_id);
+        assert_eq!(head["params"]["result"]["number"], "0x2");
+    }
+}
@@ -0,0 +1,516 @@
+// Mirror requests and their responses to a sink for offline analysis.
+//
+// Every request (or a sampled share of them) is queued on a bounded channel
+// and written out as NDJSON by a dedicated thread. If the sink can't keep up,
+// new records are dropped instead of slowing down the requests being served.
+//
+// Kafka is reached through a REST proxy, NATS through its plain TCP protocol.
+use crate::{
+    config::types::{
+        FirehoseSettings,
+        FirehoseSink,
+    },
+    log_info,
+    log_wrn,
+};
+
+use std::{
+    fs::{
+        File,
+        OpenOptions,
+    },
+    io::{
+        self,
+        BufRead,
+        BufReader,
+        Write,
+    },
+    net::TcpStream,
+    sync::{
+        atomic::{
+            AtomicU64,
+            Ordering,
+        },
+        mpsc::{
+            sync_channel,
+            Receiver,
+            RecvTimeoutError,
+            SyncSender,
+            TrySendError,
+        },
+        Arc,
+    },
+    thread,
+    time::{
+        Duration,
+        Instant,
+    },
+};
+
+use serde_json::{
+    json,
+    Value,
+};
+
+// Most records we write at once
+const MAX_BATCH: usize = 512;
+
+// How long we wait for records before checking on the sink
+const IDLE_INTERVAL: Duration = Duration::from_secs(1);
+
+// How long we wait for PINGs from NATS when checking on the connection
+const PING_CHECK_TIMEOUT: Duration = Duration::from_millis(1);
+
+// How long we wait before reconnecting to a sink that failed
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone)]
+pub struct FirehoseRecord {
+    pub request: Value,
+    pub response: String,
+    // Node that answered, `None` if it came from the cache
+    pub node: Option<String>,
+    pub latency: Duration,
+}
+
+impl FirehoseRecord {
+    fn to_ndjson(&self) -> String {
+        // Responses are stored as JSON if they are JSON
+        let response = serde_json::from_str::<Value>(&self.response)
+            .unwrap_or_else(|_| Value::String(self.response.clone()));
+
+        json!({
+            "timestamp": chrono::Utc::now().to_rfc3339(),
+            "method": self.request["method"],
+            "request": self.request,
+            "response": response,
+            "node": self.node,
+            "cached": self.node.is_none(),
+            "latency_ms": self.latency.as_secs_f64() * 1000.0,
+        })
+        .to_string()
+    }
+}
+
+// Cheap to clone handle for mirroring requests
+#[derive(Debug, Clone)]
+pub struct Firehose {
+    tx: Option<SyncSender<FirehoseRecord>>,
+    sample_rate: f64,
+    dropped: Arc<AtomicU64>,
+}
+
+impl Firehose {
+    // Firehose that mirrors nothing
+    pub fn disabled() -> Self {
+        Firehose {
+            tx: None,
+            sample_rate: 0.0,
+            dropped: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    // Spawn the sink thread. Returns a disabled firehose if no sink is set.
+    pub fn new(settings: FirehoseSettings) -> Self {
+        let sink = match settings.sink {
+            Some(sink) => sink,
+            None => return Firehose::disabled(),
+        };
+
+        log_info!(
+            "Mirroring {}% of requests to the {} firehose",
+            settings.sample_rate * 100.0,
+            sink.name()
+        );
+
+        let (tx, rx) = sync_channel(settings.buffer_size);
+        thread::spawn(move || firehose_worker(rx, SinkWriter::new(sink)));
+
+        Firehose {
+            tx: Some(tx),
+            sample_rate: settings.sample_rate,
+            dropped: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    // Decide whether to mirror the next request
+    pub fn sample(&self) -> bool {
+        self.tx.is_some() && (self.sample_rate >= 1.0 || rand::random::<f64>() < self.sample_rate)
+    }
+
+    // Queue `record` without ever blocking. Dropped if the buffer is full.
+    pub fn send(&self, record: FirehoseRecord) {
+        if let Some(tx) = &self.tx {
+            if let Err(TrySendError::Full(_)) = tx.try_send(record) {
+                // Only warn once every so often so we don't flood the logs
+                let dropped = self.dropped.fetch_add(1, Ordering::Relaxed);
+                if dropped % 10000 == 0 {
+                    log_wrn!(
+                        "Firehose buffer is full, {} records dropped so far",
+                        dropped + 1
+                    );
+                }
+            }
+        }
+    }
+
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+enum Connection {
+    File(File),
+    // Reads go through the same buffer as the handshake, so nothing the
+    // server sent right after its INFO gets lost
+    Nats(BufReader<TcpStream>),
+    Kafka(reqwest::blocking::Client),
+}
+
+struct SinkWriter {
+    sink: FirehoseSink,
+    connection: Option<Connection>,
+    last_failure: Option<Instant>,
+}
+
+impl SinkWriter {
+    fn new(sink: FirehoseSink) -> Self {
+        SinkWriter {
+            sink,
+            connection: None,
+            last_failure: None,
+        }
+    }
+
+    fn connect(&self) -> io::Result<Connection> {
+        match &self.sink {
+            FirehoseSink::File(path) => {
+                Ok(Connection::File(
+                    OpenOptions::new().create(true).append(true).open(path)?,
+                ))
+            }
+            FirehoseSink::Nats { address, .. } => {
+                let stream = TcpStream::connect(address)?;
+                stream.set_read_timeout(Some(IDLE_INTERVAL))?;
+                let mut stream = BufReader::new(stream);
+
+                // The server greets us with its INFO before anything else
+                let mut info = String::new();
+                stream.read_line(&mut info)?;
+                if !info.starts_with("INFO") {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "NATS server did not send INFO",
+                    ));
+                }
+                stream
+                    .get_mut()
+                    .write_all(b"CONNECT {\"verbose\":false,\"pedantic\":false}\r\n")?;
+
+                // Only wait briefly when checking for PINGs
+                stream
+                    .get_ref()
+                    .set_read_timeout(Some(PING_CHECK_TIMEOUT))?;
+                stream.get_ref().set_write_timeout(Some(RECONNECT_DELAY))?;
+
+                Ok(Connection::Nats(stream))
+            }
+            FirehoseSink::Kafka { .. } => Ok(Connection::Kafka(reqwest::blocking::Client::new())),
+        }
+    }
+
+    // Get the connection to the sink, reconnecting if we can
+    fn connection(&mut self) -> Option<&mut Connection> {
+        if self.connection.is_none() {
+            if self
+                .last_failure
+                .is_some_and(|failure| failure.elapsed() < RECONNECT_DELAY)
+            {
+                return None;
+            }
+
+            match self.connect() {
+                Ok(connection) => self.connection = Some(connection),
+                Err(e) => {
+                    log_wrn!(
+                        "Could not connect to the {} firehose: {}",
+                        self.sink.name(),
+                        e
+                    );
+                    self.last_failure = Some(Instant::now());
+                    return None;
+                }
+            }
+        }
+
+        self.connection.as_mut()
+    }
+
+    fn write(&mut self, lines: &[String]) {
+        let sink = self.sink.clone();
+        let result = match self.connection() {
+            Some(Connection::File(file)) => {
+                let mut buf = lines.join("\n");
+                buf.push('\n');
+                file.write_all(buf.as_bytes())
+            }
+            Some(Connection::Nats(stream)) => {
+                let subject = match &sink {
+                    FirehoseSink::Nats { subject, .. } => subject,
+                    _ => unreachable!(),
+                };
+                let mut buf = Vec::new();
+                for line in lines {
+                    buf.extend_from_slice(
+                        format!("PUB {} {}\r\n{}\r\n", subject, line.len(), line).as_bytes(),
+                    );
+                }
+
+                stream.get_mut().write_all(&buf)
+            }
+            Some(Connection::Kafka(client)) => {
+                let (url, topic) = match &sink {
+                    FirehoseSink::Kafka { url, topic } => (url, topic),
+                    _ => unreachable!(),
+                };
+                let records: Vec<Value> = lines
+                    .iter()
+                    .map(|line| json!({"value": serde_json::from_str::<Value>(line).unwrap()}))
+                    .collect();
+
+                client
+                    .post(format!("{}/topics/{}", url, topic))
+                    .header("Content-Type", "application/vnd.kafka.json.v2+json")
+                    .body(json!({"records": records}).to_string())
+                    .send()
+                    .and_then(|response| response.error_for_status())
+                    .map(|_| ())
+                    .map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+            }
+            None => return,
+        };
+
+        if let Err(e) = result {
+            log_wrn!(
+                "Could not write {} records to the {} firehose: {}",
+                lines.len(),
+                sink.name(),
+                e
+            );
+            self.connection = None;
+            self.last_failure = Some(Instant::now());
+        }
+    }
+
+    // Keep the connection alive while there's nothing to send
+    fn idle(&mut self) {
+        let stream = match &mut self.connection {
+            Some(Connection::Nats(stream)) => stream,
+            _ => return,
+        };
+
+        // NATS drops clients that don't answer its PINGs
+        let buffered = stream
+            .fill_buf()
+            .map(|buf| (buf.len(), buf.windows(4).any(|w| w == b"PING")));
+        let result = match buffered {
+            Ok((0, _)) => Err(io::Error::from(io::ErrorKind::UnexpectedEof)),
+            Ok((len, ping)) => {
+                stream.consume(len);
+                if ping {
+                    stream.get_mut().write_all(b"PONG\r\n")
+                } else {
+                    Ok(())
+                }
+            }
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+            {
+                Ok(())
+            }
+            other => other.map(|_| ()),
+        };
+
+        if let Err(e) = result {
+            log_wrn!("Lost connection to the nats firehose: {}", e);
+            self.connection = None;
+        }
+    }
+}
+
+fn firehose_worker(rx: Receiver<FirehoseRecord>, mut writer: SinkWriter) {
+    loop {
+        let first = match rx.recv_timeout(IDLE_INTERVAL) {
+            Ok(record) => record,
+            Err(RecvTimeoutError::Timeout) => {
+                writer.idle();
+                continue;
+            }
+            Err(RecvTimeoutError::Disconnected) => return,
+        };
+
+        let mut lines = vec![first.to_ndjson()];
+        while lines.len() < MAX_BATCH {
+            match rx.try_recv() {
+                Ok(record) => lines.push(record.to_ndjson()),
+                Err(_) => break,
+            }
+        }
+
+        writer.idle();
+        writer.write(&lines);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    fn record(method: &str) -> FirehoseRecord {
+        FirehoseRecord {
+            request: json!({"jsonrpc": "2.0", "id": 1, "method": method, "params": []}),
+            response: r#"{"jsonrpc":"2.0","id":1,"result":"0x1"}"#.to_string(),
+            node: Some("node1".to_string()),
+            latency: Duration::from_millis(5),
+        }
+    }
+
+    #[test]
+    fn test_record_to_ndjson() {
+        let line: Value = serde_json::from_str(&record("eth_chainId").to_ndjson()).unwrap();
+        assert_eq!(line["method"], "eth_chainId");
+        assert_eq!(line["response"]["result"], "0x1");
+        assert_eq!(line["node"], "node1");
+        assert_eq!(line["cached"], false);
+        assert_eq!(line["latency_ms"], 5.0);
+    }
+
+    #[test]
+    fn test_full_buffer_drops() {
+        // Nothing reads from the channel
+        let (tx, _rx) = sync_channel(1);
+        let firehose = Firehose {
+            tx: Some(tx),
+            sample_rate: 1.0,
+            dropped: Arc::new(AtomicU64::new(0)),
+        };
+
+        firehose.send(record("eth_chainId"));
+        firehose.send(record("eth_chainId"));
+        firehose.send(record("eth_chainId"));
+        assert_eq!(firehose.dropped(), 2);
+
+        assert!(!Firehose::disabled().sample());
+    }
+
+    #[test]
+    fn test_file_sink() {
+        let path = std::env::temp_dir().join(format!("blutgang-firehose-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let firehose = Firehose::new(FirehoseSettings {
+            sink: Some(FirehoseSink::File(path.to_string_lossy().to_string())),
+            ..Default::default()
+        });
+        assert!(firehose.sample());
+        firehose.send(record("eth_chainId"));
+        firehose.send(record("eth_blockNumber"));
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let mut lines = Vec::new();
+        while lines.len() < 2 && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+            lines = std::fs::read_to_string(&path)
+                .unwrap_or_default()
+                .lines()
+                .map(str::to_string)
+                .collect();
+        }
+        let _ = std::fs::remove_file(&path);
+
+        assert_eq!(lines.len(), 2);
+        let second: Value = serde_json::from_str(&lines[1]).unwrap();
+        assert_eq!(second["method"], "eth_blockNumber");
+    }
+
+    #[test]
+    fn test_nats_sink() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+
+        // Bare bones NATS server that hands back the first PUB it gets
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            stream.write_all(b"INFO {}\r\n").unwrap();
+
+            let mut reader = BufReader::new(stream);
+            let mut line = String::new();
+            loop {
+                line.clear();
+                reader.read_line(&mut line).unwrap();
+                if line.starts_with("PUB") {
+                    let mut payload = String::new();
+                    reader.read_line(&mut payload).unwrap();
+                    return (line, payload);
+                }
+            }
+        });
+
+        let firehose = Firehose::new(FirehoseSettings {
+            sink: Some(FirehoseSink::Nats {
+                address,
+                subject: "blutgang".to_string(),
+            }),
+            ..Default::default()
+        });
+        firehose.send(record("eth_chainId"));
+
+        let (header, payload) = server.join().unwrap();
+        let payload = payload.trim_end();
+        assert_eq!(header, format!("PUB blutgang {}\r\n", payload.len()));
+        let payload: Value = serde_json::from_str(payload).unwrap();
+        assert_eq!(payload["method"], "eth_chainId");
+    }
+
+    #[test]
+    fn test_nats_ping_after_info() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+
+        // The PING arrives in the same packet as the INFO
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            stream.write_all(b"INFO {}\r\nPING\r\n").unwrap();
+            stream
+                .set_read_timeout(Some(Duration::from_secs(5)))
+                .unwrap();
+
+            let mut reader = BufReader::new(stream);
+            let mut line = String::new();
+            loop {
+                line.clear();
+                if reader.read_line(&mut line).unwrap_or(0) == 0 {
+                    return false;
+                }
+                if line == "PONG\r\n" {
+                    return true;
+                }
+            }
+        });
+
+        let firehose = Firehose::new(FirehoseSettings {
+            sink: Some(FirehoseSink::Nats {
+                address,
+                subject: "blutgang".to_string(),
+            }),
+            ..Default::default()
+        });
+        firehose.send(record("eth_chainId"));
+
+        assert!(server.join().unwrap());
+    }
+}
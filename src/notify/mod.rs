@@ -0,0 +1,4 @@
+pub mod firehose;
+pub mod s3;
+pub mod usage;
+pub mod webhook;
@@ -0,0 +1,303 @@
+// Per-client, per-method daily call counts for usage-based billing.
+//
+// Every call that gets past the JSON-RPC and policy checks is counted under
+// the day it came in, the client's certificate identity (or `anonymous`) and
+// its method. Counts are kept in memory for `retention_days` and can be
+// exported as CSV or JSON from `GET /usage` on the admin address. If an S3
+// bucket is configured, today's and yesterday's counts are uploaded to it
+// every `interval`, so yesterday's file ends up complete after midnight.
+use crate::{
+    cluster::leader::Leadership,
+    config::types::{
+        UsageFormat,
+        UsageSettings,
+    },
+    log_err,
+    log_info,
+    notify::s3::put_object,
+};
+
+use std::{
+    collections::{
+        BTreeMap,
+        HashMap,
+    },
+    sync::{
+        Arc,
+        Mutex,
+    },
+    time::Duration,
+};
+
+use chrono::{
+    NaiveDate,
+    Utc,
+};
+use serde_json::{
+    json,
+    Value,
+};
+use tokio::time::{
+    interval,
+    MissedTickBehavior,
+};
+
+// Key calls without a certificate identity are counted under
+pub const ANONYMOUS: &str = "anonymous";
+
+// Methods past this many per client and day are counted under `OTHER`, so
+// clients can't make us track an unbounded number of made up methods
+const MAX_METHODS: usize = 1024;
+const OTHER: &str = "other";
+
+// Day, client and method, and how often it was called
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UsageRow {
+    pub day: String,
+    pub key: String,
+    pub method: String,
+    pub count: u64,
+}
+
+type DayCounts = HashMap<String, HashMap<String, u64>>;
+
+#[derive(Debug, Default)]
+pub struct UsageTracker {
+    settings: Option<UsageSettings>,
+    // Day -> client -> method -> count
+    counts: Mutex<BTreeMap<NaiveDate, DayCounts>>,
+}
+
+impl UsageTracker {
+    pub fn new(settings: Option<UsageSettings>) -> Self {
+        Self {
+            settings,
+            counts: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.settings.is_some()
+    }
+
+    pub fn record(&self, identity: Option<&str>, method: &str) {
+        if self.is_enabled() {
+            self.record_on(Utc::now().date_naive(), identity, method);
+        }
+    }
+
+    fn record_on(&self, day: NaiveDate, identity: Option<&str>, method: &str) {
+        let retention_days = match &self.settings {
+            Some(settings) => settings.retention_days,
+            None => return,
+        };
+        let mut counts = self.counts.lock().unwrap_or_else(|e| e.into_inner());
+
+        // New day, drop the ones we don't keep anymore
+        if !counts.contains_key(&day) {
+            let oldest = day - chrono::Duration::days(retention_days as i64 - 1);
+            counts.retain(|kept, _| *kept >= oldest);
+        }
+
+        let key = identity.unwrap_or(ANONYMOUS);
+        let keys = counts.entry(day).or_default();
+        if !keys.contains_key(key) {
+            keys.insert(key.to_string(), HashMap::new());
+        }
+        let methods = keys.get_mut(key).unwrap();
+
+        let full = methods.len() >= MAX_METHODS;
+        match methods.get_mut(method) {
+            Some(count) => *count += 1,
+            None if full => *methods.entry(OTHER.to_string()).or_default() += 1,
+            None => {
+                methods.insert(method.to_string(), 1);
+            }
+        }
+    }
+
+    // Counts for `day`, or every day we have, sorted
+    pub fn rows(&self, day: Option<NaiveDate>) -> Vec<UsageRow> {
+        let counts = self.counts.lock().unwrap_or_else(|e| e.into_inner());
+
+        let mut rows: Vec<UsageRow> = counts
+            .iter()
+            .filter(|(counted, _)| day.map_or(true, |day| **counted == day))
+            .flat_map(|(day, keys)| {
+                keys.iter().flat_map(move |(key, methods)| {
+                    methods.iter().map(move |(method, count)| {
+                        UsageRow {
+                            day: day.to_string(),
+                            key: key.clone(),
+                            method: method.clone(),
+                            count: *count,
+                        }
+                    })
+                })
+            })
+            .collect();
+        rows.sort_by(|a, b| (&a.day, &a.key, &a.method).cmp(&(&b.day, &b.key, &b.method)));
+        rows
+    }
+}
+
+// Quote CSV fields that need it
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+pub fn to_csv(rows: &[UsageRow]) -> String {
+    let mut csv = String::from("day,key,method,count\n");
+    for row in rows {
+        csv.push_str(&format!(
+            "{},{},{},{}\n",
+            row.day,
+            csv_field(&row.key),
+            csv_field(&row.method),
+            row.count
+        ));
+    }
+    csv
+}
+
+pub fn to_json(rows: &[UsageRow]) -> Value {
+    rows.iter()
+        .map(|row| {
+            json!({
+                "day": row.day,
+                "key": row.key,
+                "method": row.method,
+                "count": row.count,
+            })
+        })
+        .collect()
+}
+
+// Export body and content type for `format`
+pub fn export(rows: &[UsageRow], format: UsageFormat) -> (String, &'static str) {
+    match format {
+        UsageFormat::Csv => (to_csv(rows), "text/csv"),
+        UsageFormat::Json => (to_json(rows).to_string(), "application/json"),
+    }
+}
+
+// Upload today's and yesterday's counts every `interval`. In a cluster only
+// the leader uploads, the others would overwrite the same objects.
+pub async fn push_usage(usage: Arc<UsageTracker>, leadership: Arc<Leadership>) {
+    let s3 = match usage
+        .settings
+        .as_ref()
+        .and_then(|settings| settings.s3.clone())
+    {
+        Some(s3) => s3,
+        None => return,
+    };
+    let client = reqwest::Client::builder()
+        .timeout(Duration::from_secs(60))
+        .build()
+        .unwrap_or_default();
+
+    let mut ticker = interval(s3.interval);
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    // Nothing to upload right after starting
+    ticker.tick().await;
+    loop {
+        ticker.tick().await;
+        if !leadership.is_leader() {
+            continue;
+        }
+
+        let today = Utc::now().date_naive();
+        for day in [today - chrono::Duration::days(1), today] {
+            let rows = usage.rows(Some(day));
+            if rows.is_empty() {
+                continue;
+            }
+
+            let (body, content_type) = export(&rows, s3.format);
+            let extension = match s3.format {
+                UsageFormat::Csv => "csv",
+                UsageFormat::Json => "json",
+            };
+            let key = format!("{}{}.{}", s3.prefix, day, extension);
+            match put_object(&client, &s3, &key, body.into_bytes(), content_type).await {
+                Ok(()) => log_info!("Uploaded usage for {} to {}", day, key),
+                Err(e) => log_err!("Could not upload usage for {}: {}", day, e),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn day(day: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(2026, 10, day).unwrap()
+    }
+
+    #[test]
+    fn test_record() {
+        let usage = UsageTracker::new(Some(UsageSettings {
+            retention_days: 2,
+            s3: None,
+        }));
+        usage.record_on(day(15), Some("indexer"), "eth_call");
+        usage.record_on(day(16), Some("indexer"), "eth_call");
+        usage.record_on(day(16), Some("indexer"), "eth_call");
+        usage.record_on(day(16), None, "eth_getLogs");
+
+        let rows = usage.rows(Some(day(16)));
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[0].key, ANONYMOUS);
+        assert_eq!(rows[1].method, "eth_call");
+        assert_eq!(rows[1].count, 2);
+        assert_eq!(usage.rows(None).len(), 3);
+
+        // Day 15 is past retention now
+        usage.record_on(day(17), None, "eth_chainId");
+        assert!(usage.rows(Some(day(15))).is_empty());
+        assert_eq!(usage.rows(None).len(), 3);
+
+        // Disabled trackers don't count anything
+        let usage = UsageTracker::default();
+        usage.record(None, "eth_call");
+        assert!(usage.rows(None).is_empty());
+    }
+
+    #[test]
+    fn test_max_methods() {
+        let usage = UsageTracker::new(Some(UsageSettings::default()));
+        for method in 0..MAX_METHODS + 10 {
+            usage.record_on(day(16), None, &method.to_string());
+        }
+
+        let rows = usage.rows(None);
+        assert_eq!(rows.len(), MAX_METHODS + 1);
+        assert_eq!(
+            rows.iter().find(|row| row.method == OTHER).unwrap().count,
+            10
+        );
+    }
+
+    #[test]
+    fn test_export() {
+        let rows = vec![UsageRow {
+            day: "2026-10-16".to_string(),
+            key: "indexer, eu".to_string(),
+            method: "eth_call".to_string(),
+            count: 3,
+        }];
+
+        assert_eq!(
+            to_csv(&rows),
+            "day,key,method,count\n2026-10-16,\"indexer, eu\",eth_call,3\n"
+        );
+        assert_eq!(to_json(&rows)[0]["count"], 3);
+        assert_eq!(export(&rows, UsageFormat::Json).1, "application/json");
+    }
+}
@@ -0,0 +1,374 @@
+// Webhook notifications for node health events.
+//
+// Events are queued on a channel so they can be raised from anywhere, including
+// sync code holding locks. A background task rate limits them and POSTs them to
+// every configured URL, retrying with backoff if the endpoint is unavailable.
+use crate::{
+    config::types::WebhookSettings,
+    log_err,
+    log_info,
+    log_wrn,
+};
+
+use std::{
+    collections::HashMap,
+    time::{
+        Duration,
+        Instant,
+    },
+};
+
+use reqwest::Client;
+use serde_json::{
+    json,
+    Value,
+};
+use tokio::{
+    sync::mpsc,
+    time::sleep,
+};
+
+// Base delay between retries, doubled after each failed attempt
+const RETRY_BACKOFF_MS: u64 = 500;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum HealthEvent {
+    NodeUnhealthy {
+        node: String,
+        reason: String,
+    },
+    NodeRecovered {
+        node: String,
+    },
+    ConsensusDivergence {
+        highest_head: u64,
+        agreeing: usize,
+        total: usize,
+    },
+    ForkChoiceDivergence {
+        height: u64,
+        // Block hash and the nodes that have it
+        forks: Vec<(String, Vec<String>)>,
+    },
+    CacheCorruption {
+        reason: String,
+    },
+    SubscriptionStalled {
+        node: String,
+        subscription_id: String,
+    },
+    UnverifiableResponse {
+        node: String,
+        method: String,
+    },
+}
+
+impl HealthEvent {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            HealthEvent::NodeUnhealthy { .. } => "node_unhealthy",
+            HealthEvent::NodeRecovered { .. } => "node_recovered",
+            HealthEvent::ConsensusDivergence { .. } => "consensus_divergence",
+            HealthEvent::ForkChoiceDivergence { .. } => "fork_choice_divergence",
+            HealthEvent::CacheCorruption { .. } => "cache_corruption",
+            HealthEvent::SubscriptionStalled { .. } => "subscription_stalled",
+            HealthEvent::UnverifiableResponse { .. } => "unverifiable_response",
+        }
+    }
+
+    // Events with the same key share a rate limit
+    fn rate_limit_key(&self) -> String {
+        match self {
+            HealthEvent::NodeUnhealthy { node, .. }
+            | HealthEvent::NodeRecovered { node }
+            | HealthEvent::SubscriptionStalled { node, .. }
+            | HealthEvent::UnverifiableResponse { node, .. } => format!("{}:{}", self.kind(), node),
+            _ => self.kind().to_string(),
+        }
+    }
+
+    pub fn message(&self) -> String {
+        match self {
+            HealthEvent::NodeUnhealthy { node, reason } => {
+                format!("Blutgang: {} marked as unhealthy ({})", node, reason)
+            }
+            HealthEvent::NodeRecovered { node } => {
+                format!(
+                    "Blutgang: {} recovered and is back in the active pool",
+                    node
+                )
+            }
+            HealthEvent::ConsensusDivergence {
+                highest_head,
+                agreeing,
+                total,
+            } => {
+                format!(
+                    "Blutgang: consensus divergence, only {}/{} nodes agree on head {}",
+                    agreeing, total, highest_head
+                )
+            }
+            HealthEvent::ForkChoiceDivergence { height, forks } => {
+                let forks: Vec<String> = forks
+                    .iter()
+                    .map(|(hash, nodes)| format!("{} ({})", hash, nodes.join(", ")))
+                    .collect();
+                format!(
+                    "Blutgang: nodes disagree on the hash of block {}: {}",
+                    height,
+                    forks.join(" vs ")
+                )
+            }
+            HealthEvent::CacheCorruption { reason } => {
+                format!("Blutgang: cache corruption detected ({})", reason)
+            }
+            HealthEvent::SubscriptionStalled {
+                node,
+                subscription_id,
+            } => {
+                format!(
+                    "Blutgang: newHeads subscription {} on {} stopped producing events",
+                    subscription_id, node
+                )
+            }
+            HealthEvent::UnverifiableResponse { node, method } => {
+                format!(
+                    "Blutgang: {} returned a {} response that does not match its state proof",
+                    node, method
+                )
+            }
+        }
+    }
+
+    // `text` is picked up by Slack, `content` by Discord, and the rest is
+    // there for anything that wants structured data.
+    pub fn payload(&self) -> Value {
+        let message = self.message();
+        let details = match self {
+            HealthEvent::NodeUnhealthy { node, reason } => json!({"node": node, "reason": reason}),
+            HealthEvent::NodeRecovered { node } => json!({"node": node}),
+            HealthEvent::ConsensusDivergence {
+                highest_head,
+                agreeing,
+                total,
+            } => json!({"highest_head": highest_head, "agreeing": agreeing, "total": total}),
+            HealthEvent::ForkChoiceDivergence { height, forks } => {
+                let forks: Vec<Value> = forks
+                    .iter()
+                    .map(|(hash, nodes)| json!({"hash": hash, "nodes": nodes}))
+                    .collect();
+                json!({"height": height, "forks": forks})
+            }
+            HealthEvent::CacheCorruption { reason } => json!({"reason": reason}),
+            HealthEvent::SubscriptionStalled {
+                node,
+                subscription_id,
+            } => json!({"node": node, "subscription_id": subscription_id}),
+            HealthEvent::UnverifiableResponse { node, method } => {
+                json!({"node": node, "method": method})
+            }
+        };
+
+        json!({
+            "text": message,
+            "content": message,
+            "event": self.kind(),
+            "details": details,
+            "timestamp": chrono::Utc::now().to_rfc3339(),
+        })
+    }
+}
+
+// Cheap to clone handle for raising health events
+#[derive(Debug, Clone)]
+pub struct Notifier {
+    tx: Option<mpsc::UnboundedSender<HealthEvent>>,
+}
+
+impl Notifier {
+    // Notifier that drops every event
+    pub fn disabled() -> Self {
+        Notifier { tx: None }
+    }
+
+    // Spawn the webhook task. Returns a disabled notifier if no URLs are set.
+    pub fn new(settings: WebhookSettings) -> Self {
+        if settings.urls.is_empty() {
+            return Notifier::disabled();
+        }
+
+        log_info!(
+            "Sending health events to {} webhook(s)",
+            settings.urls.len()
+        );
+
+        let (tx, rx) = mpsc::unbounded_channel();
+        tokio::task::spawn(webhook_worker(rx, settings));
+
+        Notifier { tx: Some(tx) }
+    }
+
+    // Notifier that forwards events to `tx` instead of webhooks
+    #[cfg(test)]
+    pub fn from_sender(tx: mpsc::UnboundedSender<HealthEvent>) -> Self {
+        Notifier { tx: Some(tx) }
+    }
+
+    pub fn notify(&self, event: HealthEvent) {
+        if let Some(tx) = &self.tx {
+            let _ = tx.send(event);
+        }
+    }
+}
+
+// Returns true if an event with `key` hasn't been sent in the last `rate_limit`
+fn should_send(
+    last_sent: &mut HashMap<String, Instant>,
+    key: String,
+    now: Instant,
+    rate_limit: Duration,
+) -> bool {
+    match last_sent.get(&key) {
+        Some(sent) if now.duration_since(*sent) < rate_limit => false,
+        _ => {
+            last_sent.insert(key, now);
+            true
+        }
+    }
+}
+
+async fn webhook_worker(mut rx: mpsc::UnboundedReceiver<HealthEvent>, settings: WebhookSettings) {
+    let client = Client::new();
+    let mut last_sent = HashMap::new();
+
+    while let Some(event) = rx.recv().await {
+        if !should_send(
+            &mut last_sent,
+            event.rate_limit_key(),
+            Instant::now(),
+            settings.rate_limit,
+        ) {
+            continue;
+        }
+
+        let payload = event.payload();
+        for url in &settings.urls {
+            tokio::task::spawn(post_with_retry(
+                client.clone(),
+                url.clone(),
+                payload.clone(),
+                settings.retries,
+            ));
+        }
+    }
+}
+
+async fn post_with_retry(client: Client, url: String, payload: Value, retries: u32) {
+    for attempt in 0..=retries {
+        match client.post(&url).json(&payload).send().await {
+            Ok(response) if response.status().is_success() => return,
+            Ok(response) => {
+                log_wrn!("Webhook {} returned {}", url, response.status());
+            }
+            Err(e) => {
+                log_wrn!("Could not reach webhook {}: {}", url, e);
+            }
+        }
+
+        if attempt < retries {
+            sleep(Duration::from_millis(RETRY_BACKOFF_MS << attempt)).await;
+        }
+    }
+
+    log_err!("Giving up on webhook {} after {} retries", url, retries);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_should_send_rate_limits() {
+        let mut last_sent = HashMap::new();
+        let rate_limit = Duration::from_secs(60);
+        let now = Instant::now();
+
+        assert!(should_send(
+            &mut last_sent,
+            "a".to_string(),
+            now,
+            rate_limit
+        ));
+        assert!(!should_send(
+            &mut last_sent,
+            "a".to_string(),
+            now + Duration::from_secs(1),
+            rate_limit
+        ));
+        assert!(should_send(
+            &mut last_sent,
+            "b".to_string(),
+            now,
+            rate_limit
+        ));
+        assert!(should_send(
+            &mut last_sent,
+            "a".to_string(),
+            now + rate_limit,
+            rate_limit
+        ));
+    }
+
+    #[test]
+    fn test_rate_limit_key_per_node() {
+        let a = HealthEvent::NodeUnhealthy {
+            node: "a".to_string(),
+            reason: "behind".to_string(),
+        };
+        let b = HealthEvent::NodeUnhealthy {
+            node: "b".to_string(),
+            reason: "behind".to_string(),
+        };
+
+        assert_ne!(a.rate_limit_key(), b.rate_limit_key());
+    }
+
+    #[test]
+    fn test_payload() {
+        let event = HealthEvent::NodeRecovered {
+            node: "node1".to_string(),
+        };
+        let payload = event.payload();
+
+        assert_eq!(payload["event"], "node_recovered");
+        assert_eq!(payload["details"]["node"], "node1");
+        assert_eq!(payload["text"], payload["content"]);
+
+        let event = HealthEvent::ForkChoiceDivergence {
+            height: 10,
+            forks: vec![
+                (
+                    "0xa".to_string(),
+                    vec!["node1".to_string(), "node2".to_string()],
+                ),
+                ("0xb".to_string(), vec!["node3".to_string()]),
+            ],
+        };
+        assert_eq!(
+            event.message(),
+            "Blutgang: nodes disagree on the hash of block 10: 0xa (node1, node2) vs 0xb (node3)"
+        );
+        assert_eq!(event.payload()["details"]["forks"][1]["nodes"][0], "node3");
+    }
+
+    #[tokio::test]
+    async fn test_disabled_notifier() {
+        let notifier = Notifier::new(WebhookSettings::default());
+        assert!(notifier.tx.is_none());
+
+        // Should be a no-op
+        notifier.notify(HealthEvent::CacheCorruption {
+            reason: "test".to_string(),
+        });
+    }
+}
@@ -0,0 +1,272 @@
+// Daily and monthly request budgets for nodes with capped plans.
+//
+// Every request sent to a node counts against its budgets, which start over
+// at midnight UTC and on the first of the month. A node that used up a budget
+// is unavailable until the next period, so requests fail over to other nodes.
+// With pacing on, a node also sits out while it's ahead of an even spread of
+// its budget over the period, so the cap doesn't run out in the morning.
+// Counts are kept in memory and start over on restart.
+use crate::config::types::BudgetSettings;
+
+use std::sync::Mutex;
+
+use chrono::{
+    DateTime,
+    Datelike,
+    Duration,
+    TimeZone,
+    Utc,
+};
+
+// Share of a budget a node can use ahead of the even spread, for bursts
+const BURST_SHARE: f64 = 0.02;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BudgetPeriod {
+    Day,
+    Month,
+}
+
+impl BudgetPeriod {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            BudgetPeriod::Day => "day",
+            BudgetPeriod::Month => "month",
+        }
+    }
+
+    // Start and end of the period `now` is in
+    fn bounds(&self, now: DateTime<Utc>) -> (DateTime<Utc>, DateTime<Utc>) {
+        match self {
+            BudgetPeriod::Day => {
+                let start = Utc
+                    .with_ymd_and_hms(now.year(), now.month(), now.day(), 0, 0, 0)
+                    .unwrap();
+                (start, start + Duration::days(1))
+            }
+            BudgetPeriod::Month => {
+                let start = Utc
+                    .with_ymd_and_hms(now.year(), now.month(), 1, 0, 0, 0)
+                    .unwrap();
+                let (year, month) = match now.month() {
+                    12 => (now.year() + 1, 1),
+                    month => (now.year(), month + 1),
+                };
+                (
+                    start,
+                    Utc.with_ymd_and_hms(year, month, 1, 0, 0, 0).unwrap(),
+                )
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Counter {
+    period: BudgetPeriod,
+    limit: u64,
+    used: u64,
+    // Start of the period `used` counts in
+    start: DateTime<Utc>,
+}
+
+impl Counter {
+    fn new(period: BudgetPeriod, limit: u64, now: DateTime<Utc>) -> Self {
+        Counter {
+            period,
+            limit,
+            used: 0,
+            start: period.bounds(now).0,
+        }
+    }
+
+    // Start over if `now` is in a new period
+    fn roll(&mut self, now: DateTime<Utc>) {
+        let (start, _) = self.period.bounds(now);
+        if start != self.start {
+            self.start = start;
+            self.used = 0;
+        }
+    }
+
+    // Requests the node may have used by `now` if spread evenly
+    fn paced(&self, now: DateTime<Utc>) -> u64 {
+        let (start, end) = self.period.bounds(now);
+        let elapsed = (now - start).num_milliseconds() as f64;
+        let length = (end - start).num_milliseconds() as f64;
+        let limit = self.limit as f64;
+
+        ((limit * elapsed / length + limit * BURST_SHARE).ceil() as u64).clamp(1, self.limit)
+    }
+}
+
+// Shared between clones of a node, so requests sent by any of them count
+#[derive(Debug, Default)]
+pub struct RequestBudget {
+    counters: Mutex<Vec<Counter>>,
+    pacing: bool,
+}
+
+impl RequestBudget {
+    pub fn new(settings: &BudgetSettings) -> Self {
+        Self::new_at(settings, Utc::now())
+    }
+
+    fn new_at(settings: &BudgetSettings, now: DateTime<Utc>) -> Self {
+        let counters = [
+            (BudgetPeriod::Day, settings.daily),
+            (BudgetPeriod::Month, settings.monthly),
+        ]
+        .into_iter()
+        .filter_map(|(period, limit)| Some(Counter::new(period, limit?, now)))
+        .collect();
+
+        RequestBudget {
+            counters: Mutex::new(counters),
+            pacing: settings.pacing,
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self
+            .counters
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .is_empty()
+    }
+
+    // Count a request sent to the node
+    pub fn record(&self) {
+        self.record_at(Utc::now());
+    }
+
+    fn record_at(&self, now: DateTime<Utc>) {
+        let mut counters = self.counters.lock().unwrap_or_else(|e| e.into_inner());
+        for counter in counters.iter_mut() {
+            counter.roll(now);
+            counter.used = counter.used.saturating_add(1);
+        }
+    }
+
+    // Whether the node can take another request without going over its
+    // budgets, or ahead of their pace
+    pub fn has_budget(&self) -> bool {
+        self.has_budget_at(Utc::now())
+    }
+
+    fn has_budget_at(&self, now: DateTime<Utc>) -> bool {
+        let mut counters = self.counters.lock().unwrap_or_else(|e| e.into_inner());
+        counters.iter_mut().all(|counter| {
+            counter.roll(now);
+            let allowed = match self.pacing {
+                true => counter.paced(now),
+                false => counter.limit,
+            };
+            counter.used < allowed
+        })
+    }
+
+    // Requests left in each budget this period
+    pub fn remaining(&self) -> Vec<(BudgetPeriod, u64)> {
+        self.remaining_at(Utc::now())
+    }
+
+    fn remaining_at(&self, now: DateTime<Utc>) -> Vec<(BudgetPeriod, u64)> {
+        let mut counters = self.counters.lock().unwrap_or_else(|e| e.into_inner());
+        counters
+            .iter_mut()
+            .map(|counter| {
+                counter.roll(now);
+                (counter.period, counter.limit.saturating_sub(counter.used))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(day: u32, hour: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2024, 4, day, hour, 0, 0).unwrap()
+    }
+
+    #[test]
+    fn test_bounds() {
+        let now = Utc.with_ymd_and_hms(2024, 12, 31, 13, 5, 0).unwrap();
+        assert_eq!(
+            BudgetPeriod::Day.bounds(now),
+            (
+                Utc.with_ymd_and_hms(2024, 12, 31, 0, 0, 0).unwrap(),
+                Utc.with_ymd_and_hms(2025, 1, 1, 0, 0, 0).unwrap()
+            )
+        );
+        assert_eq!(
+            BudgetPeriod::Month.bounds(now),
+            (
+                Utc.with_ymd_and_hms(2024, 12, 1, 0, 0, 0).unwrap(),
+                Utc.with_ymd_and_hms(2025, 1, 1, 0, 0, 0).unwrap()
+            )
+        );
+    }
+
+    #[test]
+    fn test_exhausted() {
+        let budget = RequestBudget::new_at(
+            &BudgetSettings {
+                daily: Some(3),
+                monthly: None,
+                pacing: false,
+            },
+            at(2, 0),
+        );
+        assert!(budget.is_enabled());
+
+        for _ in 0..3 {
+            assert!(budget.has_budget_at(at(2, 1)));
+            budget.record_at(at(2, 1));
+        }
+        assert!(!budget.has_budget_at(at(2, 1)));
+        assert_eq!(budget.remaining_at(at(2, 23)), vec![(BudgetPeriod::Day, 0)]);
+
+        // Next day starts over
+        assert!(budget.has_budget_at(at(3, 0)));
+        assert_eq!(budget.remaining_at(at(3, 0)), vec![(BudgetPeriod::Day, 3)]);
+    }
+
+    #[test]
+    fn test_pacing() {
+        let budget = RequestBudget::new_at(
+            &BudgetSettings {
+                daily: Some(2400),
+                monthly: Some(1_000_000),
+                pacing: true,
+            },
+            at(2, 0),
+        );
+
+        // An hour in we may use an hour's worth plus the burst
+        for _ in 0..147 {
+            budget.record_at(at(2, 1));
+        }
+        assert!(budget.has_budget_at(at(2, 1)));
+        budget.record_at(at(2, 1));
+        assert!(!budget.has_budget_at(at(2, 1)));
+
+        // Catches up as the day goes on
+        assert!(budget.has_budget_at(at(2, 2)));
+        assert_eq!(
+            budget.remaining_at(at(2, 2)),
+            vec![(BudgetPeriod::Day, 2252), (BudgetPeriod::Month, 999_852)]
+        );
+    }
+
+    #[test]
+    fn test_no_budget() {
+        let budget = RequestBudget::default();
+        budget.record();
+        assert!(!budget.is_enabled());
+        assert!(budget.has_budget());
+        assert!(budget.remaining().is_empty());
+    }
+}
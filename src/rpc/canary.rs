@@ -0,0 +1,85 @@
+// Ramp up traffic to nodes added while we're running.
+//
+// A node added through a config reload or the admin namespace is usually
+// fresh: cold caches, maybe still catching up, maybe misconfigured. With a
+// `[blutgang.canary]` table it starts out getting `start_share` of the
+// requests it's picked for, the rest go to the best fully ramped node, and
+// its share grows linearly to all of them over `window`. If it falls into
+// the poverty list before that, the ramp starts over once it's back.
+use crate::config::types::CanarySettings;
+
+use std::time::{
+    Duration,
+    Instant,
+};
+
+#[derive(Debug, Clone)]
+pub struct Canary {
+    since: Instant,
+    start_share: f64,
+    window: Duration,
+}
+
+impl Canary {
+    pub fn new(settings: &CanarySettings) -> Self {
+        Canary {
+            since: Instant::now(),
+            start_share: settings.start_share,
+            window: settings.window,
+        }
+    }
+
+    fn share_after(&self, elapsed: Duration) -> f64 {
+        if elapsed >= self.window {
+            return 1.0;
+        }
+
+        let progress = elapsed.as_secs_f64() / self.window.as_secs_f64();
+        self.start_share + (1.0 - self.start_share) * progress
+    }
+
+    // Share of the requests it's picked for the node gets now, 0 to 1
+    pub fn share(&self) -> f64 {
+        self.share_after(self.since.elapsed())
+    }
+
+    // Whether the node gets a request, for a `roll` uniform between 0 and 1
+    pub fn admits(&self, roll: f64) -> bool {
+        roll < self.share()
+    }
+
+    // Start the ramp over if the node was unhealthy before it finished
+    pub fn restart(&mut self) {
+        if self.share() < 1.0 {
+            self.since = Instant::now();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_canary_share() {
+        let canary = Canary::new(&CanarySettings {
+            start_share: 0.1,
+            window: Duration::from_secs(100),
+        });
+
+        assert_eq!(canary.share_after(Duration::ZERO), 0.1);
+        assert!((canary.share_after(Duration::from_secs(50)) - 0.55).abs() < 1e-9);
+        assert_eq!(canary.share_after(Duration::from_secs(100)), 1.0);
+        assert_eq!(canary.share_after(Duration::from_secs(1000)), 1.0);
+
+        assert!(canary.admits(0.05));
+        assert!(!canary.admits(0.5));
+
+        // No window means no ramp
+        let canary = Canary::new(&CanarySettings {
+            start_share: 0.1,
+            window: Duration::ZERO,
+        });
+        assert_eq!(canary.share(), 1.0);
+    }
+}
@@ -0,0 +1,158 @@
+// Fault injection for exercising retries and failover in CI/staging.
+//
+// Only compiled with the `chaos` feature. Never enable this in production!
+use crate::rpc::error::RpcError;
+
+use std::time::Duration;
+
+use rand::Rng;
+use toml::Value;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Fault {
+    None,
+    Delay(Duration),
+    Drop,
+    Error,
+    Corrupt,
+}
+
+// Fraction of requests (0.0-1.0) that get each fault injected.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FaultInjection {
+    pub delay: f64,
+    pub delay_ms: u64,
+    pub drop: f64,
+    pub error: f64,
+    pub corrupt: f64,
+}
+
+impl FaultInjection {
+    // Parse the optional `[rpc_name.chaos]` table
+    pub fn from_table(table: Option<&Value>) -> Self {
+        let table = match table.and_then(|table| table.as_table()) {
+            Some(table) => table,
+            None => return FaultInjection::default(),
+        };
+
+        let rate = |key: &str| {
+            table
+                .get(key)
+                .and_then(|rate| rate.as_float())
+                .unwrap_or(0.0)
+                .clamp(0.0, 1.0)
+        };
+
+        FaultInjection {
+            delay: rate("delay"),
+            delay_ms: table
+                .get("delay_ms")
+                .and_then(|delay_ms| delay_ms.as_integer())
+                .unwrap_or(0) as u64,
+            drop: rate("drop"),
+            error: rate("error"),
+            corrupt: rate("corrupt"),
+        }
+    }
+
+    // Pick a fault for a roll in the range of [0, 1)
+    fn pick_fault(&self, roll: f64) -> Fault {
+        let mut threshold = self.drop;
+        if roll < threshold {
+            return Fault::Drop;
+        }
+        threshold += self.error;
+        if roll < threshold {
+            return Fault::Error;
+        }
+        threshold += self.corrupt;
+        if roll < threshold {
+            return Fault::Corrupt;
+        }
+        threshold += self.delay;
+        if roll < threshold {
+            return Fault::Delay(Duration::from_millis(self.delay_ms));
+        }
+
+        Fault::None
+    }
+
+    pub fn roll(&self) -> Fault {
+        self.pick_fault(rand::thread_rng().gen::<f64>())
+    }
+
+    // Apply faults that happen before the request is sent
+    pub async fn before_request(&self, fault: Fault) -> Result<(), RpcError> {
+        match fault {
+            Fault::Delay(delay) => tokio::time::sleep(delay).await,
+            // Never respond so the request times out
+            Fault::Drop => std::future::pending::<()>().await,
+            Fault::Error => {
+                return Err(RpcError::InvalidResponse(
+                    "chaos: injected upstream error".to_string(),
+                ))
+            }
+            Fault::None | Fault::Corrupt => {}
+        }
+
+        Ok(())
+    }
+}
+
+// Mangle a response so it no longer parses as JSON
+pub fn corrupt(response: String) -> String {
+    let half = response.len() / 2;
+    let mut end = half;
+    while !response.is_char_boundary(end) {
+        end -= 1;
+    }
+
+    response[..end].to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pick_fault() {
+        let chaos = FaultInjection {
+            delay: 0.1,
+            delay_ms: 50,
+            drop: 0.1,
+            error: 0.1,
+            corrupt: 0.1,
+        };
+
+        assert_eq!(chaos.pick_fault(0.05), Fault::Drop);
+        assert_eq!(chaos.pick_fault(0.15), Fault::Error);
+        assert_eq!(chaos.pick_fault(0.25), Fault::Corrupt);
+        assert_eq!(
+            chaos.pick_fault(0.35),
+            Fault::Delay(Duration::from_millis(50))
+        );
+        assert_eq!(chaos.pick_fault(0.5), Fault::None);
+    }
+
+    #[test]
+    fn test_default_never_faults() {
+        assert_eq!(FaultInjection::default().pick_fault(0.0), Fault::None);
+    }
+
+    #[test]
+    fn test_from_table() {
+        let table: Value = "drop = 0.5\nerror = 2.0\ndelay_ms = 10".parse().unwrap();
+        let chaos = FaultInjection::from_table(Some(&table));
+
+        assert_eq!(chaos.drop, 0.5);
+        assert_eq!(chaos.error, 1.0);
+        assert_eq!(chaos.delay_ms, 10);
+        assert_eq!(chaos.corrupt, 0.0);
+    }
+
+    #[test]
+    fn test_corrupt() {
+        let response = r#"{"jsonrpc":"2.0","id":1,"result":"0x1"}"#.to_string();
+        assert!(serde_json::from_str::<serde_json::Value>(&corrupt(response)).is_err());
+    }
+}
@@ -0,0 +1,184 @@
+// Back off from nodes that rate limit us.
+//
+// A node that answers with HTTP 429, a rate limit error or a `Retry-After`
+// header gets no requests until its cooldown is over, and we go to other
+// nodes meanwhile instead of hammering it. The cooldown is what the node
+// asked for if it told us, capped at `max`. Otherwise it starts at `base`
+// and doubles every time the node limits us again until a request goes
+// through. Nodes other instances found down are put on hold the same way,
+// see `cluster::gossip`.
+use crate::{
+    config::types::RateLimitSettings,
+    rpc::profile::Provider,
+};
+
+use std::{
+    sync::Mutex,
+    time::{
+        Duration,
+        Instant,
+    },
+};
+
+use reqwest::header::HeaderMap;
+
+#[derive(Debug, Default)]
+struct State {
+    until: Option<Instant>,
+    // Rate limits in a row, for the backoff
+    strikes: u32,
+}
+
+// Shared between clones of a node, so limits seen by any of them count
+#[derive(Debug, Default)]
+pub struct Cooldown {
+    state: Mutex<State>,
+}
+
+impl Cooldown {
+    // Start cooling down after being rate limited. Returns for how long.
+    pub fn start(&self, retry_after: Option<Duration>, settings: &RateLimitSettings) -> Duration {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        let cooldown = match retry_after {
+            Some(retry_after) => retry_after,
+            None => {
+                settings
+                    .base
+                    .saturating_mul(2u32.saturating_pow(state.strikes))
+            }
+        }
+        .min(settings.max);
+
+        state.strikes = state.strikes.saturating_add(1);
+        state.until = Some(Instant::now() + cooldown);
+        cooldown
+    }
+
+    // A request went through, so the next limit starts the backoff over
+    pub fn reset(&self) {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        if state.strikes > 0 {
+            *state = State::default();
+        }
+    }
+
+    // Time left until the node takes requests again
+    pub fn remaining(&self) -> Duration {
+        self.state
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .until
+            .map_or(Duration::ZERO, |until| {
+                until.saturating_duration_since(Instant::now())
+            })
+    }
+
+    // Keep off the node for at least `duration`, without it counting towards
+    // the backoff. Returns whether it wasn't cooling down already.
+    pub fn hold(&self, duration: Duration) -> bool {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        let now = Instant::now();
+        let was_cooling_down = state.until.is_some_and(|until| until > now);
+        state.until = state.until.max(Some(now + duration));
+        !was_cooling_down
+    }
+
+    pub fn is_cooling_down(&self) -> bool {
+        !self.remaining().is_zero()
+    }
+}
+
+// How long the node asked us to wait, from the headers its profile uses or
+// `Retry-After`. Both delay seconds and HTTP dates are understood.
+pub fn retry_after(headers: &HeaderMap, profile: Option<Provider>) -> Option<Duration> {
+    let names = match profile {
+        Some(profile) if !profile.rate_limit_headers().is_empty() => profile.rate_limit_headers(),
+        _ => &["retry-after"],
+    };
+
+    names.iter().find_map(|name| {
+        let value = headers.get(*name)?.to_str().ok()?.trim();
+        if let Ok(seconds) = value.parse::<f64>() {
+            return (seconds.is_finite() && seconds >= 0.0)
+                .then(|| Duration::from_secs_f64(seconds));
+        }
+
+        let date = chrono::DateTime::parse_from_rfc2822(value).ok()?;
+        (date.with_timezone(&chrono::Utc) - chrono::Utc::now())
+            .to_std()
+            .ok()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::header::HeaderValue;
+
+    fn settings() -> RateLimitSettings {
+        RateLimitSettings {
+            base: Duration::from_millis(100),
+            max: Duration::from_millis(350),
+        }
+    }
+
+    #[test]
+    fn test_cooldown_backoff() {
+        let cooldown = Cooldown::default();
+        assert!(!cooldown.is_cooling_down());
+
+        assert_eq!(
+            cooldown.start(None, &settings()),
+            Duration::from_millis(100)
+        );
+        assert!(cooldown.is_cooling_down());
+        assert_eq!(
+            cooldown.start(None, &settings()),
+            Duration::from_millis(200)
+        );
+        assert_eq!(
+            cooldown.start(None, &settings()),
+            Duration::from_millis(350)
+        );
+
+        // What the node asks for wins, up to `max`
+        assert_eq!(
+            cooldown.start(Some(Duration::from_millis(10)), &settings()),
+            Duration::from_millis(10)
+        );
+        assert_eq!(
+            cooldown.start(Some(Duration::from_secs(10)), &settings()),
+            Duration::from_millis(350)
+        );
+
+        cooldown.reset();
+        assert!(cooldown.hold(Duration::from_millis(50)));
+        assert!(!cooldown.hold(Duration::from_millis(10)));
+        assert!(cooldown.remaining() > Duration::from_millis(10));
+        assert_eq!(
+            cooldown.start(None, &settings()),
+            Duration::from_millis(100)
+        );
+    }
+
+    #[test]
+    fn test_retry_after() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(retry_after(&headers, None), None);
+
+        headers.insert("retry-after", HeaderValue::from_static("2"));
+        assert_eq!(retry_after(&headers, None), Some(Duration::from_secs(2)));
+        assert_eq!(
+            retry_after(&headers, Some(Provider::Alchemy)),
+            Some(Duration::from_secs(2))
+        );
+
+        let date = (chrono::Utc::now() + chrono::Duration::seconds(30)).to_rfc2822();
+        headers.insert("retry-after", HeaderValue::from_str(&date).unwrap());
+        let wait = retry_after(&headers, None).unwrap();
+        assert!(wait > Duration::from_secs(25) && wait <= Duration::from_secs(30));
+
+        headers.insert("retry-after", HeaderValue::from_static("soon"));
+        assert_eq!(retry_after(&headers, None), None);
+    }
+}
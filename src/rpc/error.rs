@@ -1,5 +1,8 @@
 // Errors
-use std::error::Error;
+use std::{
+    error::Error,
+    time::Duration,
+};
 
 #[derive(Debug)]
 #[allow(dead_code)]
@@ -8,6 +11,8 @@ pub enum RpcError {
     //InvalidHexFormat,
     OutOfBounds,
     InvalidResponse(String),
+    // The node rate limited us, and how long it asked us to wait
+    RateLimited(Option<Duration>),
 }
 
 impl std::fmt::Display for RpcError {
@@ -21,6 +26,7 @@ impl std::fmt::Display for RpcError {
                 )
             }
             RpcError::InvalidResponse(reason) => write!(f, "Invalid RPC response: {}", reason),
+            RpcError::RateLimited(_) => write!(f, "RPC rate limited us"),
         }
     }
 }
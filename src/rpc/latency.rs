@@ -0,0 +1,211 @@
+// Per-method latency histograms for a node.
+//
+// Buckets are log-linear like HDR histograms: exact below 32µs, and 16
+// buckets per power of two above that, so any recorded value is off by at
+// most ~6%. Memory stays small no matter how many samples we record.
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::Duration,
+};
+
+// Values below this are recorded exactly
+const LINEAR: u64 = 32;
+const SUB_BUCKETS: u64 = LINEAR / 2;
+// Distinct methods we track per node, the rest are lumped into `other`
+const MAX_METHODS: usize = 256;
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LatencyHistogram {
+    counts: Vec<u64>,
+    count: u64,
+    // In µs
+    sum: u64,
+    max: u64,
+}
+
+fn bucket(micros: u64) -> usize {
+    if micros < LINEAR {
+        return micros as usize;
+    }
+
+    let shift = (63 - micros.leading_zeros() as u64) + 1 - LINEAR.trailing_zeros() as u64;
+    (shift * SUB_BUCKETS + (micros >> shift)) as usize
+}
+
+// Highest value that lands in `bucket`
+fn highest_in(bucket: usize) -> u64 {
+    let bucket = bucket as u64;
+    if bucket < LINEAR {
+        return bucket;
+    }
+
+    let shift = bucket / SUB_BUCKETS - 1;
+    let top = bucket - shift * SUB_BUCKETS;
+    ((top + 1) << shift) - 1
+}
+
+impl LatencyHistogram {
+    pub fn record(&mut self, latency: Duration) {
+        let micros = latency.as_micros().min(u64::MAX as u128) as u64;
+        let bucket = bucket(micros);
+        if self.counts.len() <= bucket {
+            self.counts.resize(bucket + 1, 0);
+        }
+
+        self.counts[bucket] += 1;
+        self.count += 1;
+        self.sum = self.sum.saturating_add(micros);
+        self.max = self.max.max(micros);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub fn sum(&self) -> Duration {
+        Duration::from_micros(self.sum)
+    }
+
+    pub fn max(&self) -> Duration {
+        Duration::from_micros(self.max)
+    }
+
+    // Latency `quantile` of the samples were at or below, e.g. 0.99 for p99
+    pub fn quantile(&self, quantile: f64) -> Duration {
+        if self.count == 0 {
+            return Duration::ZERO;
+        }
+
+        let rank = ((quantile.clamp(0.0, 1.0) * self.count as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (bucket, count) in self.counts.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return Duration::from_micros(highest_in(bucket).min(self.max));
+            }
+        }
+
+        self.max()
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct MethodLatencies {
+    histograms: Mutex<HashMap<String, LatencyHistogram>>,
+}
+
+impl MethodLatencies {
+    pub fn record(&self, method: &str, latency: Duration) {
+        let mut histograms = self.histograms.lock().unwrap_or_else(|e| e.into_inner());
+
+        // Methods come from users, so don't let them grow this forever
+        let method = if histograms.contains_key(method) || histograms.len() < MAX_METHODS {
+            method
+        } else {
+            "other"
+        };
+        histograms
+            .entry(method.to_string())
+            .or_default()
+            .record(latency);
+    }
+
+    pub fn get(&self, method: &str) -> Option<LatencyHistogram> {
+        self.histograms
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(method)
+            .cloned()
+    }
+
+    // `quantile` of `method`'s latency, if we have at least `min_samples` of it
+    pub fn quantile(&self, method: &str, quantile: f64, min_samples: u64) -> Option<Duration> {
+        self.histograms
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(method)
+            .filter(|histogram| histogram.count() >= min_samples.max(1))
+            .map(|histogram| histogram.quantile(quantile))
+    }
+
+    // All histograms, sorted by method
+    pub fn snapshot(&self) -> Vec<(String, LatencyHistogram)> {
+        let mut snapshot: Vec<(String, LatencyHistogram)> = self
+            .histograms
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .iter()
+            .map(|(method, histogram)| (method.clone(), histogram.clone()))
+            .collect();
+        snapshot.sort_by(|a, b| a.0.cmp(&b.0));
+        snapshot
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_buckets() {
+        // Every value lands in a bucket that contains it
+        for micros in (0..100_000).chain([u32::MAX as u64, u64::MAX / 2]) {
+            let bucket = bucket(micros);
+            assert!(highest_in(bucket) >= micros, "{}", micros);
+            if bucket > 0 {
+                assert!(highest_in(bucket - 1) < micros, "{}", micros);
+            }
+        }
+        assert_eq!(bucket(31), 31);
+        assert_eq!(bucket(32), 32);
+    }
+
+    #[test]
+    fn test_quantiles() {
+        let mut histogram = LatencyHistogram::default();
+        assert_eq!(histogram.quantile(0.5), Duration::ZERO);
+
+        for ms in 1..=100 {
+            histogram.record(Duration::from_millis(ms));
+        }
+        assert_eq!(histogram.count(), 100);
+        assert_eq!(histogram.max(), Duration::from_millis(100));
+
+        // Within bucket precision
+        let p50 = histogram.quantile(0.5).as_micros() as f64;
+        assert!((p50 - 50_000.0).abs() / 50_000.0 < 0.07, "{}", p50);
+        let p99 = histogram.quantile(0.99).as_micros() as f64;
+        assert!((p99 - 99_000.0).abs() / 99_000.0 < 0.07, "{}", p99);
+        assert_eq!(histogram.quantile(1.0), Duration::from_millis(100));
+    }
+
+    #[test]
+    fn test_method_latencies() {
+        let latencies = MethodLatencies::default();
+        latencies.record("eth_call", Duration::from_millis(5));
+        latencies.record("eth_call", Duration::from_millis(7));
+        latencies.record("debug_traceTransaction", Duration::from_secs(2));
+
+        assert_eq!(latencies.get("eth_call").unwrap().count(), 2);
+        assert!(latencies.get("eth_chainId").is_none());
+        assert_eq!(
+            latencies.quantile("eth_call", 1.0, 2),
+            Some(Duration::from_millis(7))
+        );
+        assert_eq!(latencies.quantile("eth_call", 1.0, 3), None);
+
+        let methods: Vec<String> = latencies
+            .snapshot()
+            .into_iter()
+            .map(|(method, _)| method)
+            .collect();
+        assert_eq!(methods, vec!["debug_traceTransaction", "eth_call"]);
+
+        for i in 0..MAX_METHODS {
+            latencies.record(&format!("method_{}", i), Duration::from_millis(1));
+        }
+        assert_eq!(latencies.snapshot().len(), MAX_METHODS + 1);
+        assert!(latencies.get("other").is_some());
+    }
+}
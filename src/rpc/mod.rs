@@ -1,2 +1,11 @@
+pub mod budget;
+pub mod canary;
+#[cfg(feature = "chaos")]
+pub mod chaos;
+pub mod cooldown;
 pub mod error;
+pub mod latency;
+pub mod profile;
+pub mod score;
+pub mod tls;
 pub mod types;
@@ -0,0 +1,215 @@
+// Known quirks of node software and hosted providers.
+//
+// Setting `profile` on a node tells us what it can't do, so we can route
+// around it instead of finding out from its errors:
+//
+// [alchemy]
+// url = "https://eth-mainnet.g.alchemy.com/v2/..."
+// profile = "alchemy"
+//
+// Profiles cover methods a node doesn't serve, the widest `eth_getLogs`
+// block range it accepts, error codes it uses in place of the standard ones,
+// and the headers it sends with rate limited responses.
+use crate::config::error::ConfigError;
+
+use std::{
+    fmt,
+    str::FromStr,
+};
+
+use serde_json::Value;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Provider {
+    Alchemy,
+    Infura,
+    Erigon,
+    Geth,
+    Nethermind,
+    Besu,
+}
+
+// Signing happens in wallets, hosted nodes don't keep accounts
+const HOSTED_UNSUPPORTED: [&str; 5] = [
+    "eth_accounts",
+    "eth_sign",
+    "eth_signTransaction",
+    "eth_sendTransaction",
+    "personal_*",
+];
+// Geth doesn't implement the Parity trace namespace
+const GETH_UNSUPPORTED: [&str; 2] = ["trace_*", "parity_*"];
+
+impl Provider {
+    // Methods the node doesn't serve, or prefixes of them ending in `*`
+    pub fn unsupported(&self) -> &'static [&'static str] {
+        match self {
+            Provider::Alchemy | Provider::Infura => &HOSTED_UNSUPPORTED,
+            Provider::Geth => &GETH_UNSUPPORTED,
+            Provider::Erigon | Provider::Nethermind | Provider::Besu => &[],
+        }
+    }
+
+    pub fn supports(&self, method: &str) -> bool {
+        !self.unsupported().iter().any(|pattern| {
+            match pattern.strip_suffix('*') {
+                Some(prefix) => method.starts_with(prefix),
+                None => method == *pattern,
+            }
+        })
+    }
+
+    // Widest block range an `eth_getLogs` call can span
+    pub fn max_log_range(&self) -> Option<u64> {
+        match self {
+            Provider::Alchemy => Some(2_000),
+            Provider::Infura => Some(10_000),
+            // `--rpc-max-logs-range` default
+            Provider::Besu => Some(5_000),
+            Provider::Erigon | Provider::Geth | Provider::Nethermind => None,
+        }
+    }
+
+    // Codes the node answers with, and the standard code for them
+    pub fn error_codes(&self) -> &'static [(i64, i64)] {
+        match self {
+            // Rate limits
+            Provider::Alchemy => &[(429, -32005)],
+            // Method not enabled, for namespaces that are turned off
+            Provider::Besu => &[(-32604, -32601)],
+            Provider::Infura | Provider::Erigon | Provider::Geth | Provider::Nethermind => &[],
+        }
+    }
+
+    // Headers that tell us how long to back off when we're rate limited
+    pub fn rate_limit_headers(&self) -> &'static [&'static str] {
+        match self {
+            Provider::Alchemy | Provider::Infura => &["retry-after"],
+            Provider::Erigon | Provider::Geth | Provider::Nethermind | Provider::Besu => &[],
+        }
+    }
+
+    // Whether the node can answer `tx`
+    pub fn can_serve(&self, tx: &Value) -> bool {
+        let method = tx["method"].as_str().unwrap_or_default();
+        if !self.supports(method) {
+            return false;
+        }
+
+        match (method, self.max_log_range()) {
+            ("eth_getLogs", Some(max_log_range)) => {
+                log_range(&tx["params"][0]).map_or(true, |range| range <= max_log_range)
+            }
+            _ => true,
+        }
+    }
+
+    // `rax` with the node's own error codes replaced by the standard ones
+    pub fn standardize_errors(&self, rax: String) -> String {
+        let codes = self.error_codes();
+        if codes.is_empty() || !rax.contains("\"error\"") {
+            return rax;
+        }
+
+        let mut response: Value = match serde_json::from_str(&rax) {
+            Ok(response) => response,
+            Err(_) => return rax,
+        };
+        let standard = response["error"]["code"]
+            .as_i64()
+            .and_then(|code| codes.iter().find(|(own, _)| *own == code))
+            .map(|(_, standard)| *standard);
+
+        match standard {
+            Some(standard) => {
+                response["error"]["code"] = standard.into();
+                response.to_string()
+            }
+            None => rax,
+        }
+    }
+}
+
+// Blocks a log filter spans, if both ends are numbers
+pub fn log_range(filter: &Value) -> Option<u64> {
+    let block = |value: &Value| {
+        value
+            .as_str()
+            .and_then(|value| value.strip_prefix("0x"))
+            .and_then(|value| u64::from_str_radix(value, 16).ok())
+    };
+    let from = block(&filter["fromBlock"])?;
+    let to = block(&filter["toBlock"])?;
+
+    Some(to.saturating_sub(from) + 1)
+}
+
+impl FromStr for Provider {
+    type Err = ConfigError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "alchemy" => Ok(Provider::Alchemy),
+            "infura" => Ok(Provider::Infura),
+            "erigon" => Ok(Provider::Erigon),
+            "geth" => Ok(Provider::Geth),
+            "nethermind" => Ok(Provider::Nethermind),
+            "besu" => Ok(Provider::Besu),
+            _ => Err(ConfigError::BadConfig),
+        }
+    }
+}
+
+impl fmt::Display for Provider {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Provider::Alchemy => "alchemy",
+            Provider::Infura => "infura",
+            Provider::Erigon => "erigon",
+            Provider::Geth => "geth",
+            Provider::Nethermind => "nethermind",
+            Provider::Besu => "besu",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn get_logs(from: &str, to: &str) -> Value {
+        json!({"method": "eth_getLogs", "params": [{"fromBlock": from, "toBlock": to}]})
+    }
+
+    #[test]
+    fn test_provider_can_serve() {
+        assert_eq!("Alchemy".parse::<Provider>().unwrap(), Provider::Alchemy);
+        assert!("quicknode".parse::<Provider>().is_err());
+
+        assert!(!Provider::Infura.supports("eth_sendTransaction"));
+        assert!(!Provider::Infura.supports("personal_sign"));
+        assert!(Provider::Infura.supports("eth_sendRawTransaction"));
+        assert!(!Provider::Geth.supports("trace_block"));
+        assert!(Provider::Erigon.supports("trace_block"));
+
+        assert!(Provider::Alchemy.can_serve(&get_logs("0x1", "0x7d0")));
+        assert!(!Provider::Alchemy.can_serve(&get_logs("0x1", "0x7d1")));
+        assert!(Provider::Geth.can_serve(&get_logs("0x1", "0x100000")));
+        // We can't tell how wide ranges to a tag are
+        assert!(Provider::Alchemy.can_serve(&get_logs("0x1", "latest")));
+    }
+
+    #[test]
+    fn test_provider_standardize_errors() {
+        let rax = json!({"jsonrpc": "2.0", "id": 1, "error": {"code": -32604, "message": "Method not enabled"}})
+            .to_string();
+        let standard: Value =
+            serde_json::from_str(&Provider::Besu.standardize_errors(rax.clone())).unwrap();
+        assert_eq!(standard["error"]["code"], -32601);
+        assert_eq!(standard["error"]["message"], "Method not enabled");
+
+        assert_eq!(Provider::Geth.standardize_errors(rax.clone()), rax);
+    }
+}
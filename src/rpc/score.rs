@@ -0,0 +1,179 @@
+// Scores nodes are ranked by.
+//
+// By default nodes are ranked by their moving average latency alone, so a
+// node that's fast but times out every other request, or one that's a few
+// blocks behind, still gets picked first. With a `[blutgang.scoring]` table
+// the health check recomputes a score for every node from its latency, how
+// many of its requests failed and how far behind the highest head it is, and
+// nodes are ranked by that instead.
+//
+// Error rate and lag are decayed exponentially with `half_life`, so a node
+// that had an incident an hour ago ranks like it never had one.
+use crate::config::types::ScoringSettings;
+
+use std::{
+    sync::{
+        atomic::{
+            AtomicU64,
+            Ordering,
+        },
+        Mutex,
+    },
+    time::Instant,
+};
+
+use serde_json::{
+    json,
+    Value,
+};
+
+#[derive(Debug, Default, Clone, Copy)]
+struct Decayed {
+    // Share of requests that failed, 0 to 1
+    error_rate: f64,
+    // Blocks behind the highest head
+    lag: f64,
+    // Effective latency in ns, see `update`
+    score: Option<f64>,
+    updated: Option<Instant>,
+    // Counters as of the last update
+    requests: u64,
+    errors: u64,
+}
+
+// Shared between clones of a node, so requests sent through any of them count
+#[derive(Debug, Default)]
+pub struct NodeScore {
+    requests: AtomicU64,
+    errors: AtomicU64,
+    decayed: Mutex<Decayed>,
+}
+
+impl NodeScore {
+    // Count a request, and whether it failed (timed out or came back malformed)
+    pub fn record(&self, failed: bool) {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+        if failed {
+            self.errors.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    // Recompute the score from `latency` (ns) and `lag` behind the highest
+    // head, if we know it. Lower is better.
+    //
+    // The score is the latency, scaled up by the error rate and with a fixed
+    // penalty per block of lag added, so it can be compared to raw latencies.
+    pub fn update(&self, latency: f64, lag: Option<u64>, settings: &ScoringSettings) -> f64 {
+        let mut decayed = self.decayed.lock().unwrap_or_else(|e| e.into_inner());
+        let now = Instant::now();
+
+        // How much of the old value is replaced by what we saw since the last update
+        let weight = match decayed.updated {
+            Some(updated) if !settings.half_life.is_zero() => {
+                let half_lives =
+                    now.duration_since(updated).as_secs_f64() / settings.half_life.as_secs_f64();
+                1.0 - 0.5_f64.powf(half_lives)
+            }
+            _ => 1.0,
+        };
+
+        let requests = self.requests.load(Ordering::Relaxed);
+        let errors = self.errors.load(Ordering::Relaxed);
+        let new_requests = requests.saturating_sub(decayed.requests);
+        let new_errors = errors.saturating_sub(decayed.errors);
+        // Nodes that get no traffic because of their score still recover
+        let error_rate = if new_requests > 0 {
+            (new_errors as f64 / new_requests as f64).min(1.0)
+        } else {
+            0.0
+        };
+        decayed.error_rate += weight * (error_rate - decayed.error_rate);
+        if let Some(lag) = lag {
+            decayed.lag += weight * (lag as f64 - decayed.lag);
+        }
+
+        let score = latency * (1.0 + settings.error_penalty * decayed.error_rate)
+            + settings.lag_penalty.as_nanos() as f64 * decayed.lag;
+        decayed.score = Some(score);
+        decayed.updated = Some(now);
+        decayed.requests = requests;
+        decayed.errors = errors;
+
+        score
+    }
+
+    // `None` until the first update, or if scoring is off
+    pub fn score(&self) -> Option<f64> {
+        self.decayed.lock().unwrap_or_else(|e| e.into_inner()).score
+    }
+
+    pub fn report(&self) -> Value {
+        let decayed = *self.decayed.lock().unwrap_or_else(|e| e.into_inner());
+        json!({
+            // In ms, like the latency report
+            "score": decayed.score.map(|score| score / 1_000_000.0),
+            "errorRate": decayed.error_rate,
+            "lag": decayed.lag,
+            "requests": self.requests.load(Ordering::Relaxed),
+            "errors": self.errors.load(Ordering::Relaxed),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn settings(half_life: Duration) -> ScoringSettings {
+        ScoringSettings {
+            half_life,
+            error_penalty: 10.0,
+            lag_penalty: Duration::from_millis(100),
+        }
+    }
+
+    #[test]
+    fn test_node_score() {
+        let score = NodeScore::default();
+        assert_eq!(score.score(), None);
+
+        score.record(false);
+        assert_eq!(
+            score.update(1_000_000.0, Some(0), &settings(Duration::ZERO)),
+            1_000_000.0
+        );
+
+        // Half the requests failed and it's 2 blocks behind
+        score.record(true);
+        score.record(false);
+        let penalized = score.update(1_000_000.0, Some(2), &settings(Duration::ZERO));
+        assert_eq!(penalized, 1_000_000.0 * 6.0 + 200_000_000.0);
+        assert_eq!(score.report()["score"], 206.0);
+        assert_eq!(score.report()["errors"], 1);
+
+        // Unknown lag keeps the last one
+        assert_eq!(
+            score.update(1_000_000.0, None, &settings(Duration::ZERO)),
+            201_000_000.0
+        );
+    }
+
+    #[test]
+    fn test_node_score_decay() {
+        let score = NodeScore::default();
+        let settings = settings(Duration::from_millis(20));
+
+        score.record(true);
+        score.update(1_000_000.0, Some(10), &settings);
+        assert_eq!(score.report()["errorRate"], 1.0);
+
+        // Clean since, so the incident fades
+        std::thread::sleep(Duration::from_millis(40));
+        score.record(false);
+        score.update(1_000_000.0, Some(0), &settings);
+        let report = score.report();
+        assert!(report["errorRate"].as_f64().unwrap() < 0.3);
+        assert!(report["lag"].as_f64().unwrap() < 3.0);
+    }
+}
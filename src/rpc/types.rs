@@ -1,5 +1,48 @@
-use crate::rpc::error::RpcError;
-use reqwest::Client;
+#[cfg(feature = "chaos")]
+use crate::rpc::chaos::{
+    corrupt,
+    Fault,
+    FaultInjection,
+};
+use crate::{
+    balancer::error_map::is_rate_limit,
+    config::{
+        system::DebugModule,
+        types::{
+            BudgetSettings,
+            CanarySettings,
+            UpstreamIdentity,
+        },
+    },
+    log_dbg,
+    rpc::{
+        budget::RequestBudget,
+        canary::Canary,
+        cooldown::{
+            retry_after,
+            Cooldown,
+        },
+        error::RpcError,
+        latency::MethodLatencies,
+        profile::Provider,
+        score::NodeScore,
+        tls::TlsConfig,
+    },
+};
+
+use std::sync::{
+    atomic::{
+        AtomicU64,
+        Ordering,
+    },
+    Arc,
+};
+
+use reqwest::{
+    header::CONTENT_TYPE,
+    Body,
+    Client,
+};
 use url::Url;
 
 use serde_json::{
@@ -19,12 +62,57 @@ pub struct Status {
     pub latency: f64,
     pub latency_data: Vec<f64>,
     ma_length: f64,
+
+    // Long running smoothed latency and its mean deviation. Used to tell
+    // consistently fast nodes apart from ones that are only fast sometimes.
+    pub smoothed_latency: f64,
+    pub latency_deviation: f64,
+
+    // Latency distribution of every method we forwarded. Shared between
+    // clones, so it keeps working on the copies we send requests with.
+    pub methods: Arc<MethodLatencies>,
+    // Transactions we rebroadcast to this node, shared between clones too
+    pub rebroadcasts: Arc<AtomicU64>,
+    // Failed requests and the score we rank by, shared between clones too
+    pub score: Arc<NodeScore>,
+    // Requests sent and not answered yet, shared between clones too
+    pub in_flight: Arc<AtomicU64>,
+    // Set while a node added at runtime is ramping up to its full share
+    pub canary: Option<Canary>,
+    // Set while we back off after the node rate limited us, shared between clones too
+    pub cooldown: Arc<Cooldown>,
+    // Requests left for the day and month on capped plans, shared between clones too
+    pub budget: Arc<RequestBudget>,
+
+    // Set while this node disagrees with its peers on the head hash, and if
+    // it's not on the majority fork, so it stays out of the active pool
+    pub fork_divergence: bool,
+    pub minority_fork: bool,
+    // Set while the node serves a block the beacon chain says isn't
+    // canonical, so it stays out of the active pool too
+    pub non_canonical: bool,
     // ???
     // pub throughput: f64,
 }
 
 unsafe impl Sync for Status {}
 
+// Counts a request as in flight until it's dropped, even if it's cancelled
+pub struct InFlight<'a>(&'a AtomicU64);
+
+impl<'a> InFlight<'a> {
+    pub fn new(counter: &'a AtomicU64) -> Self {
+        counter.fetch_add(1, Ordering::Relaxed);
+        InFlight(counter)
+    }
+}
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Rpc {
     pub name: String,           // sanitized name for appearing in logs
@@ -38,14 +126,30 @@ pub struct Rpc {
     // For max_per_second
     pub last_used: u128,      // last time we sent a querry to this node
     pub min_time_delta: u128, // microseconds
+    // Custom TLS for the WS connection, HTTP uses `client`
+    pub ws_connector: Option<native_tls::TlsConnector>,
+    // Requests for the pending block go here instead of the regular rotation
+    pub pending_state: bool,
+    // Named groups this node can be targeted through, e.g. `/group/archive`
+    pub groups: Vec<String>,
+    // Known quirks of the node, if we were told what it runs
+    pub profile: Option<Provider>,
+    // Where the node runs, for preferring nodes close to us
+    pub region: Option<String>,
+    // `User-Agent` and `Origin` we send the node over HTTP and WS
+    pub identity: UpstreamIdentity,
+    // Where the node serves GraphQL, if it does
+    pub graphql_url: Option<String>,
+    #[cfg(feature = "chaos")]
+    pub chaos: FaultInjection, // faults to inject into responses
 }
 
 // Sanitizes URLs so secrets don't get outputed.
 //
 // For example, if we have a URL: https://eth-mainnet.g.alchemy.com/v2/api-key
 // as input, we output: https://eth-mainnet.g.alchemy.com/
-fn sanitize_url(url: &str) -> Result<String, url::ParseError> {
-    let parsed_url = Url::parse(&url)?;
+pub fn sanitize_url(url: &str) -> Result<String, url::ParseError> {
+    let parsed_url = Url::parse(url)?;
 
     // Build a new URL with the scheme, host, and port (if any), but without the path or query
     let sanitized = Url::parse(&format!(
@@ -75,6 +179,15 @@ impl Default for Rpc {
             consecutive: 0,
             last_used: 0,
             min_time_delta: 0,
+            ws_connector: None,
+            pending_state: false,
+            groups: Vec::new(),
+            profile: None,
+            region: None,
+            identity: UpstreamIdentity::default(),
+            graphql_url: None,
+            #[cfg(feature = "chaos")]
+            chaos: FaultInjection::default(),
         }
     }
 }
@@ -101,9 +214,94 @@ impl Rpc {
             consecutive: 0,
             last_used: 0,
             min_time_delta,
+            ws_connector: None,
+            pending_state: false,
+            groups: Vec::new(),
+            profile: None,
+            region: None,
+            identity: UpstreamIdentity::default(),
+            graphql_url: None,
+            #[cfg(feature = "chaos")]
+            chaos: FaultInjection::default(),
         }
     }
 
+    // Whether `other` is a clone of this node. Names aren't unique, nodes
+    // on the same host have the same one.
+    pub fn is_same(&self, other: &Rpc) -> bool {
+        Arc::ptr_eq(&self.status.score, &other.status.score)
+    }
+
+    // Connect with custom CAs or client certificates
+    pub fn with_tls(mut self, tls: TlsConfig) -> Self {
+        self.client = tls.client;
+        self.ws_connector = Some(tls.connector);
+        self
+    }
+
+    pub fn with_pending_state(mut self, pending_state: bool) -> Self {
+        self.pending_state = pending_state;
+        self
+    }
+
+    pub fn with_groups(mut self, groups: Vec<String>) -> Self {
+        self.groups = groups;
+        self
+    }
+
+    // Ramp up traffic to this node if canaries are configured
+    pub fn with_budget(mut self, budget: Option<&BudgetSettings>) -> Self {
+        self.status.budget = Arc::new(budget.map(RequestBudget::new).unwrap_or_default());
+        self
+    }
+
+    pub fn with_canary(mut self, canary: Option<&CanarySettings>) -> Self {
+        self.status.canary = canary.map(Canary::new);
+        self
+    }
+
+    pub fn with_profile(mut self, profile: Option<Provider>) -> Self {
+        self.profile = profile;
+        self
+    }
+
+    pub fn with_region(mut self, region: Option<String>) -> Self {
+        self.region = region;
+        self
+    }
+
+    pub fn with_identity(mut self, identity: UpstreamIdentity) -> Self {
+        self.identity = identity;
+        self
+    }
+
+    pub fn with_graphql_url(mut self, graphql_url: Option<String>) -> Self {
+        self.graphql_url = graphql_url;
+        self
+    }
+
+    // Whether the node can answer `tx` as far as its profile goes
+    pub fn can_serve(&self, tx: &Value) -> bool {
+        self.profile.map_or(true, |profile| profile.can_serve(tx))
+    }
+
+    // Whether we can send `tx` to the node right now
+    pub fn is_available(&self, tx: &Value) -> bool {
+        self.can_serve(tx)
+            && !self.status.cooldown.is_cooling_down()
+            && self.status.budget.has_budget()
+    }
+
+    pub fn in_group(&self, group: &str) -> bool {
+        self.groups.iter().any(|name| name == group)
+    }
+
+    #[cfg(feature = "chaos")]
+    pub fn with_chaos(mut self, chaos: FaultInjection) -> Self {
+        self.chaos = chaos;
+        self
+    }
+
     // Explicitly get the url of the Rpc, potentially dangerous as it can expose basic auth
     #[cfg(test)]
     pub fn get_url(&self) -> String {
@@ -112,10 +310,55 @@ impl Rpc {
 
     // Generic fn to send rpc
     pub async fn send_request(&self, tx: Value) -> Result<String, crate::rpc::types::RpcError> {
-        #[cfg(feature = "debug-verbose")]
-        println!("Sending request: {}", tx.clone());
+        self.send_request_with_headers(tx, &[]).await
+    }
+
+    // Send rpc along with headers we pass through from the client
+    pub async fn send_request_with_headers(
+        &self,
+        tx: Value,
+        headers: &[(String, String)],
+    ) -> Result<String, crate::rpc::types::RpcError> {
+        log_dbg!(DebugModule::Rpc, "Sending request: {}", tx);
+        self.send_body(Body::from(tx.to_string()), headers).await
+    }
 
-        let response = match self.client.post(&self.url).json(&tx).send().await {
+    // Send a JSON request body as is, it might still be streaming in
+    pub async fn send_body(
+        &self,
+        body: Body,
+        headers: &[(String, String)],
+    ) -> Result<String, crate::rpc::types::RpcError> {
+        let _in_flight = InFlight::new(&self.status.in_flight);
+        self.status.budget.record();
+
+        #[cfg(feature = "chaos")]
+        let fault = self.chaos.roll();
+        #[cfg(feature = "chaos")]
+        self.chaos.before_request(fault).await?;
+
+        // Headers passed through from the client win over our own
+        let request = self
+            .identity
+            .headers()
+            .into_iter()
+            .filter(|(name, _)| {
+                !headers
+                    .iter()
+                    .any(|(header, _)| header.eq_ignore_ascii_case(name))
+            })
+            .fold(self.client.post(&self.url), |request, (name, value)| {
+                request.header(name, value)
+            });
+        let request = headers.iter().fold(request, |request, (name, value)| {
+            request.header(name, value)
+        });
+        let response = match request
+            .header(CONTENT_TYPE, "application/json")
+            .body(body)
+            .send()
+            .await
+        {
             Ok(response) => response,
             Err(err) => {
                 return Err(crate::rpc::types::RpcError::InvalidResponse(
@@ -124,15 +367,88 @@ impl Rpc {
             }
         };
 
-        #[cfg(feature = "debug-verbose")]
+        let wait = retry_after(response.headers(), self.profile);
+        if response.status() == reqwest::StatusCode::TOO_MANY_REQUESTS {
+            return Err(RpcError::RateLimited(wait));
+        }
+
+        let rx = response.text().await.unwrap();
+        log_dbg!(DebugModule::Rpc, "Response: {}", rx);
+
+        // Some nodes rate limit with a regular JSON-RPC error
+        if rx.contains("\"error\"")
+            && serde_json::from_str::<Value>(&rx)
+                .is_ok_and(|response| is_rate_limit(&response["error"]))
         {
-            let a = response.text().await.unwrap();
-            println!("response: {}", a);
-            return Ok(a);
+            return Err(RpcError::RateLimited(wait));
+        }
+
+        #[cfg(feature = "chaos")]
+        if fault == Fault::Corrupt {
+            return Ok(corrupt(rx));
         }
 
-        #[cfg(not(feature = "debug-verbose"))]
-        Ok(response.text().await.unwrap())
+        Ok(rx)
+    }
+
+    // Send a GraphQL request to the node's GraphQL endpoint. Returns the
+    // status the node answered with and its response, unless the node
+    // couldn't answer at all.
+    pub async fn send_graphql(&self, body: Vec<u8>) -> Result<(u16, String), RpcError> {
+        let url = self
+            .graphql_url
+            .as_ref()
+            .ok_or_else(|| RpcError::InvalidResponse("node doesn't serve GraphQL".to_string()))?;
+        let _in_flight = InFlight::new(&self.status.in_flight);
+        self.status.budget.record();
+
+        let request = self
+            .identity
+            .headers()
+            .into_iter()
+            .fold(self.client.post(url), |request, (name, value)| {
+                request.header(name, value)
+            });
+        let response = request
+            .header(CONTENT_TYPE, "application/json")
+            .body(body)
+            .send()
+            .await
+            .map_err(|err| RpcError::InvalidResponse(err.to_string()))?;
+
+        let status = response.status();
+        if status == reqwest::StatusCode::TOO_MANY_REQUESTS {
+            return Err(RpcError::RateLimited(retry_after(
+                response.headers(),
+                self.profile,
+            )));
+        }
+        if status.is_server_error() {
+            return Err(RpcError::InvalidResponse(status.to_string()));
+        }
+
+        let rx = response
+            .text()
+            .await
+            .map_err(|err| RpcError::InvalidResponse(err.to_string()))?;
+        Ok((status.as_u16(), rx))
+    }
+
+    // Call `method` and return its result, or the error the node answered with
+    pub async fn call(&self, method: &str, params: Value) -> Result<Value, RpcError> {
+        let request = json!({
+            "method": method,
+            "params": params,
+            "id": 1,
+            "jsonrpc": "2.0",
+        });
+
+        let mut response: Value = serde_json::from_str(&self.send_request(request).await?)
+            .map_err(|e| RpcError::InvalidResponse(e.to_string()))?;
+        match response.get("error") {
+            Some(error) => Err(RpcError::InvalidResponse(error.to_string())),
+            None => Ok(response["result"].take()),
+        }
     }
 
     // Request blocknumber and return its value
@@ -192,6 +508,31 @@ impl Rpc {
         self.status.latency_data.push(latest);
         self.status.latency =
             self.status.latency_data.iter().sum::<f64>() / self.status.latency_data.len() as f64;
+
+        // Same smoothing TCP uses for its RTT estimates
+        if self.status.smoothed_latency == 0.0 {
+            self.status.smoothed_latency = latest;
+            self.status.latency_deviation = latest / 2.0;
+        } else {
+            self.status.latency_deviation = 0.75 * self.status.latency_deviation
+                + 0.25 * (self.status.smoothed_latency - latest).abs();
+            self.status.smoothed_latency = 0.875 * self.status.smoothed_latency + 0.125 * latest;
+        }
+    }
+
+    // What we rank this node by, its score if scoring is on. Lower is better.
+    pub fn rank_latency(&self) -> f64 {
+        self.status.score.score().unwrap_or(self.status.latency)
+    }
+
+    // Share of the requests it's picked for this node gets, see `Canary`
+    pub fn canary_share(&self) -> f64 {
+        self.status.canary.as_ref().map_or(1.0, Canary::share)
+    }
+
+    // Latency we can expect from this node most of the time. Lower is better.
+    pub fn latency_score(&self) -> f64 {
+        self.status.smoothed_latency + 4.0 * self.status.latency_deviation
     }
 }
 
@@ -224,3 +565,71 @@ pub fn hex_to_decimal(hex_string: &str) -> Result<u64, std::num::ParseIntError>
 
     u64::from_str_radix(hex_string, 16)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::{
+        io::{
+            Read,
+            Write,
+        },
+        net::TcpListener,
+        sync::mpsc,
+        thread,
+    };
+
+    // Node that answers once and hands us the request it got
+    fn recording_node() -> (String, mpsc::Receiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let (tx, rx) = mpsc::channel();
+
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut buf = [0u8; 4096];
+            let read = stream.read(&mut buf).unwrap();
+            let _ = tx.send(String::from_utf8_lossy(&buf[..read]).to_lowercase());
+
+            let body = r#"{"jsonrpc":"2.0","id":1,"result":"0x1"}"#;
+            let _ = write!(
+                stream,
+                "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            );
+        });
+
+        (format!("http://{}", address), rx)
+    }
+
+    #[tokio::test]
+    async fn test_upstream_identity_headers() {
+        let (url, rx) = recording_node();
+        let rpc = Rpc::new(url, None, 6, 0, 10.0).with_identity(UpstreamIdentity {
+            user_agent: "acme-rpc/1.0".to_string(),
+            origin: Some("https://acme.com".to_string()),
+        });
+        rpc.send_request(json!({"jsonrpc": "2.0", "id": 1, "method": "eth_chainId"}))
+            .await
+            .unwrap();
+        let request = rx.recv().unwrap();
+        assert!(request.contains("user-agent: acme-rpc/1.0\r\n"));
+        assert!(request.contains("origin: https://acme.com\r\n"));
+
+        // Headers passed through from the client replace ours
+        let (url, rx) = recording_node();
+        let rpc = Rpc::new(url, None, 6, 0, 10.0);
+        rpc.send_request_with_headers(
+            json!({"jsonrpc": "2.0", "id": 1, "method": "eth_chainId"}),
+            &[("User-Agent".to_string(), "wallet/2.0".to_string())],
+        )
+        .await
+        .unwrap();
+        let request = rx.recv().unwrap();
+        assert!(request.contains("user-agent: wallet/2.0\r\n"));
+        assert_eq!(request.matches("user-agent").count(), 1);
+        assert!(!request.contains("origin"));
+    }
+}
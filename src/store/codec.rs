@@ -0,0 +1,465 @@
+// Compact encoding of the JSON the local stores keep.
+//
+// RPC JSON is mostly hex: addresses, hashes, topics and quantities spelled
+// out as `0x` strings, twice the size of the bytes they stand for, under the
+// same handful of keys in every value. Values are packed into a binary
+// layout where hex strings are kept as raw bytes and canonical quantities as
+// varints, then compressed with a zstd dictionary trained on the first
+// `TRAIN_SAMPLES` values the store sees. The dictionary holds the keys and
+// the structure every value shares, so even small values compress well. It
+// is kept in sled and never retrained, so every value stays readable.
+//
+// Value layout:
+//
+// PACKED | packed value
+// PACKED_ZSTD | packed length (varint) | zstd frame of the packed value
+//
+// Anything else is plain JSON, the way values were stored before.
+//
+// Packed values are a tag followed by its payload:
+//
+// NULL, FALSE, TRUE           -
+// NUMBER, STRING              length (varint) | UTF-8
+// BYTES                       length (varint) | bytes of a lowercase hex string
+// QUANTITY                    varint of a canonical `0x` quantity
+// ARRAY                       count (varint) | values
+// OBJECT                      count (varint) | (key length | key | value)...
+use crate::{
+    log_info,
+    log_wrn,
+};
+
+use std::{
+    fmt,
+    io::{
+        Read,
+        Write,
+    },
+    sync::{
+        Mutex,
+        RwLock,
+    },
+};
+
+use serde_json::{
+    Map,
+    Number,
+    Value,
+};
+use sled::{
+    Db,
+    Tree,
+};
+use zstd::dict::{
+    DecoderDictionary,
+    EncoderDictionary,
+};
+
+const DICT_TREE: &str = "store_dictionaries";
+
+const PACKED: u8 = 1;
+const PACKED_ZSTD: u8 = 2;
+
+const NULL: u8 = 0;
+const FALSE: u8 = 1;
+const TRUE: u8 = 2;
+const NUMBER: u8 = 3;
+const STRING: u8 = 4;
+const BYTES: u8 = 5;
+const QUANTITY: u8 = 6;
+const ARRAY: u8 = 7;
+const OBJECT: u8 = 8;
+
+// Values we train the dictionary on
+const TRAIN_SAMPLES: usize = 2000;
+const DICT_SIZE: usize = 64 * 1024;
+const COMPRESSION_LEVEL: i32 = 3;
+// Nothing we store packs to more than this, anything claiming to is corrupt
+const MAX_PACKED_LEN: usize = 64 * 1024 * 1024;
+
+fn write_varint(mut value: u64, out: &mut Vec<u8>) {
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn read_varint(input: &mut &[u8]) -> Option<u64> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        let (&byte, rest) = input.split_first()?;
+        *input = rest;
+        value |= u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+fn read_bytes<'a>(input: &mut &'a [u8]) -> Option<&'a [u8]> {
+    let len = read_varint(input)? as usize;
+    if input.len() < len {
+        return None;
+    }
+    let (bytes, rest) = input.split_at(len);
+    *input = rest;
+    Some(bytes)
+}
+
+fn write_bytes(tag: u8, bytes: &[u8], out: &mut Vec<u8>) {
+    out.push(tag);
+    write_varint(bytes.len() as u64, out);
+    out.extend_from_slice(bytes);
+}
+
+// `0x` quantity that reads back the same from its value
+fn canonical_quantity(hex: &str) -> Option<u64> {
+    let digits = hex.strip_prefix("0x")?;
+    if digits.is_empty()
+        || digits.len() > 16
+        || (digits.len() > 1 && digits.starts_with('0'))
+        || !digits
+            .bytes()
+            .all(|c| matches!(c, b'0'..=b'9' | b'a'..=b'f'))
+    {
+        return None;
+    }
+    u64::from_str_radix(digits, 16).ok()
+}
+
+// Bytes of a lowercase `0x` hex string
+fn lowercase_hex(hex: &str) -> Option<Vec<u8>> {
+    let digits = hex.strip_prefix("0x")?;
+    if digits.bytes().any(|c| c.is_ascii_uppercase()) {
+        return None;
+    }
+    hex::decode(digits).ok()
+}
+
+fn pack(value: &Value, out: &mut Vec<u8>) {
+    match value {
+        Value::Null => out.push(NULL),
+        Value::Bool(false) => out.push(FALSE),
+        Value::Bool(true) => out.push(TRUE),
+        Value::Number(number) => write_bytes(NUMBER, number.to_string().as_bytes(), out),
+        Value::String(string) => {
+            if let Some(quantity) = canonical_quantity(string) {
+                out.push(QUANTITY);
+                write_varint(quantity, out);
+            } else if let Some(bytes) = lowercase_hex(string) {
+                write_bytes(BYTES, &bytes, out);
+            } else {
+                write_bytes(STRING, string.as_bytes(), out);
+            }
+        }
+        Value::Array(values) => {
+            out.push(ARRAY);
+            write_varint(values.len() as u64, out);
+            for value in values {
+                pack(value, out);
+            }
+        }
+        Value::Object(map) => {
+            out.push(OBJECT);
+            write_varint(map.len() as u64, out);
+            for (key, value) in map {
+                write_varint(key.len() as u64, out);
+                out.extend_from_slice(key.as_bytes());
+                pack(value, out);
+            }
+        }
+    }
+}
+
+fn unpack(input: &mut &[u8]) -> Option<Value> {
+    let (&tag, rest) = input.split_first()?;
+    *input = rest;
+
+    let value = match tag {
+        NULL => Value::Null,
+        FALSE => Value::Bool(false),
+        TRUE => Value::Bool(true),
+        NUMBER => {
+            let number = std::str::from_utf8(read_bytes(input)?).ok()?;
+            Value::Number(number.parse::<Number>().ok()?)
+        }
+        STRING => Value::String(std::str::from_utf8(read_bytes(input)?).ok()?.to_string()),
+        BYTES => Value::String(format!("0x{}", hex::encode(read_bytes(input)?))),
+        QUANTITY => Value::String(format!("0x{:x}", read_varint(input)?)),
+        ARRAY => {
+            let count = read_varint(input)? as usize;
+            let mut values = Vec::with_capacity(count.min(input.len()));
+            for _ in 0..count {
+                values.push(unpack(input)?);
+            }
+            Value::Array(values)
+        }
+        OBJECT => {
+            let count = read_varint(input)?;
+            let mut map = Map::new();
+            for _ in 0..count {
+                let key = std::str::from_utf8(read_bytes(input)?).ok()?.to_string();
+                map.insert(key, unpack(input)?);
+            }
+            Value::Object(map)
+        }
+        _ => return None,
+    };
+    Some(value)
+}
+
+struct Dictionary {
+    encoder: EncoderDictionary<'static>,
+    decoder: DecoderDictionary<'static>,
+}
+
+impl fmt::Debug for Dictionary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Dictionary {{ .. }}")
+    }
+}
+
+impl Dictionary {
+    fn new(dictionary: &[u8]) -> Self {
+        Dictionary {
+            encoder: EncoderDictionary::copy(dictionary, COMPRESSION_LEVEL),
+            decoder: DecoderDictionary::copy(dictionary),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Codec {
+    name: String,
+    tree: Tree,
+    dictionary: RwLock<Option<Dictionary>>,
+    // Packed values we train the dictionary on once we have enough
+    samples: Mutex<Vec<Vec<u8>>>,
+}
+
+impl Codec {
+    // Codec of the store called `name`, with its dictionary if it has one
+    pub fn open(cache: &Db, name: &str) -> Result<Self, sled::Error> {
+        let tree = cache.open_tree(DICT_TREE)?;
+        let dictionary = tree
+            .get(name)?
+            .map(|dictionary| Dictionary::new(&dictionary));
+
+        Ok(Codec {
+            name: name.to_string(),
+            tree,
+            dictionary: RwLock::new(dictionary),
+            samples: Mutex::new(Vec::new()),
+        })
+    }
+
+    pub fn has_dictionary(&self) -> bool {
+        self.dictionary
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .is_some()
+    }
+
+    pub fn encode(&self, value: &Value) -> Vec<u8> {
+        let mut packed = Vec::new();
+        pack(value, &mut packed);
+
+        let compressed = match self
+            .dictionary
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .as_ref()
+        {
+            Some(dictionary) => compress(&packed, dictionary),
+            None => None,
+        };
+        if let Some(compressed) = compressed {
+            return compressed;
+        }
+        // Training takes the lock we just let go of
+        if !self.has_dictionary() {
+            self.sample(&packed);
+        }
+
+        let mut encoded = Vec::with_capacity(1 + packed.len());
+        encoded.push(PACKED);
+        encoded.extend_from_slice(&packed);
+        encoded
+    }
+
+    pub fn decode(&self, encoded: &[u8]) -> Option<Value> {
+        match encoded.first() {
+            Some(&PACKED) => {
+                let mut packed = &encoded[1..];
+                unpack(&mut packed)
+            }
+            Some(&PACKED_ZSTD) => {
+                let dictionary = self.dictionary.read().unwrap_or_else(|e| e.into_inner());
+                let packed = decompress(&encoded[1..], dictionary.as_ref()?)?;
+                unpack(&mut packed.as_slice())
+            }
+            _ => serde_json::from_slice(encoded).ok(),
+        }
+    }
+
+    // Keep `packed` to train the dictionary on, and train it once we can
+    fn sample(&self, packed: &[u8]) {
+        let mut samples = self.samples.lock().unwrap_or_else(|e| e.into_inner());
+        samples.push(packed.to_vec());
+        if samples.len() < TRAIN_SAMPLES {
+            return;
+        }
+
+        let trained = zstd::dict::from_samples(&samples, DICT_SIZE);
+        samples.clear();
+        let dictionary = match trained {
+            Ok(dictionary) => dictionary,
+            Err(e) => {
+                log_wrn!("Could not train a dictionary for {}: {}", self.name, e);
+                return;
+            }
+        };
+        // Only use it once it's stored, or we couldn't read what we wrote
+        if let Err(e) = self.tree.insert(self.name.as_str(), dictionary.as_slice()) {
+            log_wrn!("Could not store the dictionary for {}: {}", self.name, e);
+            return;
+        }
+        log_info!(
+            "Trained a {} byte dictionary for {}",
+            dictionary.len(),
+            self.name
+        );
+        *self.dictionary.write().unwrap_or_else(|e| e.into_inner()) =
+            Some(Dictionary::new(&dictionary));
+    }
+}
+
+fn compress(packed: &[u8], dictionary: &Dictionary) -> Option<Vec<u8>> {
+    let mut compressed = vec![PACKED_ZSTD];
+    write_varint(packed.len() as u64, &mut compressed);
+    let mut encoder =
+        zstd::Encoder::with_prepared_dictionary(compressed, &dictionary.encoder).ok()?;
+    encoder.write_all(packed).ok()?;
+    encoder.finish().ok()
+}
+
+fn decompress(mut compressed: &[u8], dictionary: &Dictionary) -> Option<Vec<u8>> {
+    let len = read_varint(&mut compressed)? as usize;
+    if len > MAX_PACKED_LEN {
+        return None;
+    }
+    let mut packed = Vec::with_capacity(len);
+    zstd::Decoder::with_prepared_dictionary(compressed, &dictionary.decoder)
+        .ok()?
+        .read_to_end(&mut packed)
+        .ok()?;
+    (packed.len() == len).then_some(packed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn codec() -> Codec {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        Codec::open(&db, "test").unwrap()
+    }
+
+    fn receipt(i: u64) -> Value {
+        let hash =
+            |salt: u64| format!("0x{}", blake3::hash(&(i * 7 + salt).to_le_bytes()).to_hex());
+        json!({
+            "blockHash": hash(1),
+            "blockNumber": format!("0x{:x}", 19_000_000 + i / 100),
+            "contractAddress": null,
+            "cumulativeGasUsed": format!("0x{:x}", 21_000 * (i % 100 + 1)),
+            "effectiveGasPrice": "0x5f5e100",
+            "from": &hash(2)[..42],
+            "gasUsed": "0x5208",
+            "logs": [{
+                "address": "0xa0b86991c6218b36c1d19d4a2e9eb0ce3606eb48",
+                "topics": [
+                    "0xddf252ad1be2c89b69c2b068fc378daa952ba7f163c4a11628f55a4df523b3ef",
+                    format!("0x000000000000000000000000{}", &hash(2)[2..42]),
+                ],
+                "data": "0x00000000000000000000000000000000000000000000000000000000000f4240",
+                "logIndex": format!("0x{:x}", i % 100),
+                "removed": false,
+            }],
+            "logsBloom": format!("0x{}", "0".repeat(512)),
+            "status": "0x1",
+            "to": "0xa0b86991c6218b36c1d19d4a2e9eb0ce3606eb48",
+            "transactionIndex": format!("0x{:x}", i % 100),
+            "type": "0x2",
+        })
+    }
+
+    #[test]
+    fn test_pack_roundtrip() {
+        let value = json!({
+            "quantity": "0x0",
+            "leading": "0x00ff",
+            "empty": "0x",
+            "upper": "0xABCD",
+            "odd": "0x123",
+            "long": "0x1234567890abcdef12",
+            "text": "blutgang",
+            "numbers": [0, -1, 1.5, u64::MAX],
+            "nested": {"null": null, "flags": [true, false]},
+        });
+        let mut packed = Vec::new();
+        pack(&value, &mut packed);
+        assert_eq!(unpack(&mut packed.as_slice()), Some(value));
+
+        assert_eq!(unpack(&mut [ARRAY, 5, NULL].as_slice()), None);
+        assert_eq!(unpack(&mut [42].as_slice()), None);
+    }
+
+    #[test]
+    fn test_codec() {
+        let codec = codec();
+        assert!(!codec.has_dictionary());
+
+        // Values from before packing are plain JSON
+        let plain = serde_json::to_vec(&receipt(0)).unwrap();
+        assert_eq!(codec.decode(&plain), Some(receipt(0)));
+
+        let packed: Vec<Vec<u8>> = (0..TRAIN_SAMPLES as u64)
+            .map(|i| codec.encode(&receipt(i)))
+            .collect();
+        assert!(codec.has_dictionary());
+        assert_eq!(packed[0][0], PACKED);
+        assert_eq!(codec.decode(&packed[0]), Some(receipt(0)));
+
+        let raw: usize = (0..1000)
+            .map(|i| serde_json::to_vec(&receipt(i)).unwrap().len())
+            .sum();
+        let compressed: Vec<Vec<u8>> = (0..1000).map(|i| codec.encode(&receipt(i))).collect();
+        assert_eq!(compressed[0][0], PACKED_ZSTD);
+        for (i, encoded) in compressed.iter().enumerate() {
+            assert_eq!(codec.decode(encoded), Some(receipt(i as u64)));
+        }
+        // Hashes are random, so this is mostly the hex and the keys going away
+        let size: usize = compressed.iter().map(Vec::len).sum();
+        assert!(size * 4 < raw, "{} of {}", size, raw);
+    }
+
+    #[test]
+    fn test_codec_reopen() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let codec = Codec::open(&db, "receipts").unwrap();
+        for i in 0..TRAIN_SAMPLES as u64 {
+            codec.encode(&receipt(i));
+        }
+        let encoded = codec.encode(&receipt(1));
+        drop(codec);
+
+        let codec = Codec::open(&db, "receipts").unwrap();
+        assert!(codec.has_dictionary());
+        assert_eq!(codec.decode(&encoded), Some(receipt(1)));
+        assert!(!Codec::open(&db, "logs").unwrap().has_dictionary());
+    }
+}
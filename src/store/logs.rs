@@ -0,0 +1,659 @@
+// Local index of the logs of finalized blocks.
+//
+// With `[blutgang.log_index]` set, we fetch the logs of every block up to
+// the finalized one from our nodes, `batch` blocks at a time, starting
+// `backfill` blocks back the first time, and keep them in sled next to the
+// cache. `eth_getLogs` calls whose whole range we indexed are answered from
+// here instead of going upstream, where they're the heaviest thing clients
+// ask for. Finalized blocks don't reorg, so nothing indexed ever changes.
+//
+// Logs are stored under their position, and indexed by address and by their
+// first topic:
+//
+// logs:      block (8 bytes) | log index (4 bytes)          -> log JSON
+// address:   address (20 bytes) | block | log index         -> ()
+// topic:     topic0 (32 bytes) | block | log index          -> ()
+//
+// Logs are stored with `store::codec`, without the block number and log
+// index their key already has.
+//
+// Queries scan the address or topic index for the range, or the logs
+// themselves if they filter on neither, then match the rest of the filter.
+// Ranges wider than `max_range`, and scans that turn up more than `max_logs`
+// logs, are left to our nodes so one query can't tie us up. With `addresses`
+// set we only index those contracts, and only answer queries for them.
+use crate::{
+    balancer::{
+        block_range::parse_number,
+        selection::select::{
+            pick,
+            route_available,
+        },
+    },
+    config::types::LogIndexSettings,
+    log_info,
+    log_wrn,
+    rpc::types::Rpc,
+    store::codec::Codec,
+};
+
+use std::{
+    collections::BTreeSet,
+    fmt,
+    sync::{
+        Arc,
+        RwLock,
+    },
+    time::Duration,
+};
+
+use serde_json::{
+    json,
+    Value,
+};
+use sled::{
+    Batch,
+    Db,
+    Tree,
+};
+use tokio::{
+    sync::watch,
+    time::timeout,
+};
+
+const LOGS_TREE: &str = "log_index";
+const ADDRESS_TREE: &str = "log_index_address";
+const TOPIC_TREE: &str = "log_index_topic";
+const META_TREE: &str = "log_index_meta";
+// First and last block we indexed
+const RANGE_KEY: &[u8] = b"range";
+
+const ADDRESS_LEN: usize = 20;
+const TOPIC_LEN: usize = 32;
+const POSITION_LEN: usize = 12;
+
+// Errors
+#[derive(Debug)]
+pub enum LogIndexError {
+    Db(sled::Error),
+    NoRpcAvailable,
+    TimedOut,
+    InvalidResponse(String),
+}
+
+impl fmt::Display for LogIndexError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LogIndexError::Db(e) => write!(f, "Database error: {}", e),
+            LogIndexError::NoRpcAvailable => write!(f, "No RPC available"),
+            LogIndexError::TimedOut => write!(f, "Timed out"),
+            LogIndexError::InvalidResponse(msg) => write!(f, "Invalid response: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for LogIndexError {}
+
+impl From<sled::Error> for LogIndexError {
+    fn from(e: sled::Error) -> Self {
+        LogIndexError::Db(e)
+    }
+}
+
+// Key of a log, sorts in chain order
+fn position(block: u64, index: u32) -> [u8; POSITION_LEN] {
+    let mut position = [0u8; POSITION_LEN];
+    position[..8].copy_from_slice(&block.to_be_bytes());
+    position[8..].copy_from_slice(&index.to_be_bytes());
+    position
+}
+
+// Drop the fields `position` has from `log`, if they read back the same
+fn strip_position(log: &Value, block: u64, index: u32) -> Value {
+    let mut log = log.clone();
+    if let Some(fields) = log.as_object_mut() {
+        for (field, number) in [("blockNumber", block), ("logIndex", u64::from(index))] {
+            if fields.get(field).and_then(Value::as_str) == Some(&format!("0x{:x}", number)) {
+                fields.remove(field);
+            }
+        }
+    }
+    log
+}
+
+fn restore_position(log: &mut Value, position: &[u8; POSITION_LEN]) {
+    let block = u64::from_be_bytes(position[..8].try_into().unwrap());
+    let index = u32::from_be_bytes(position[8..].try_into().unwrap());
+    if let Some(fields) = log.as_object_mut() {
+        fields
+            .entry("blockNumber")
+            .or_insert_with(|| format!("0x{:x}", block).into());
+        fields
+            .entry("logIndex")
+            .or_insert_with(|| format!("0x{:x}", index).into());
+    }
+}
+
+// `0x` prefixed hex of exactly `len` bytes
+fn decode(hex: &str, len: usize) -> Option<Vec<u8>> {
+    hex::decode(hex.strip_prefix("0x")?)
+        .ok()
+        .filter(|bytes| bytes.len() == len)
+}
+
+// Lowercase values of a filter field that takes a value or a list of them.
+// Empty if anything matches, `None` if it's malformed.
+fn filter_values(value: &Value) -> Option<Vec<String>> {
+    match value {
+        Value::Null => Some(Vec::new()),
+        Value::String(value) => Some(vec![value.to_lowercase()]),
+        Value::Array(values) => {
+            // A `null` in the list matches anything as well
+            if values.iter().any(Value::is_null) {
+                return Some(Vec::new());
+            }
+            values
+                .iter()
+                .map(|value| value.as_str().map(str::to_lowercase))
+                .collect()
+        }
+        _ => None,
+    }
+}
+
+fn matches(values: &[String], value: &Value) -> bool {
+    values.is_empty()
+        || value
+            .as_str()
+            .is_some_and(|value| values.contains(&value.to_lowercase()))
+}
+
+#[derive(Debug)]
+struct Trees {
+    logs: Tree,
+    by_address: Tree,
+    by_topic: Tree,
+    meta: Tree,
+    codec: Codec,
+}
+
+#[derive(Debug, Default)]
+pub struct LogIndex {
+    settings: Option<LogIndexSettings>,
+    trees: Option<Trees>,
+}
+
+impl LogIndex {
+    // Index kept in `cache`, disabled if `settings` aren't set
+    pub fn open(cache: &Db, settings: Option<&LogIndexSettings>) -> Result<Self, sled::Error> {
+        let settings = match settings {
+            Some(settings) => settings,
+            None => return Ok(LogIndex::default()),
+        };
+
+        Ok(LogIndex {
+            settings: Some(settings.clone()),
+            trees: Some(Trees {
+                logs: cache.open_tree(LOGS_TREE)?,
+                by_address: cache.open_tree(ADDRESS_TREE)?,
+                by_topic: cache.open_tree(TOPIC_TREE)?,
+                meta: cache.open_tree(META_TREE)?,
+                codec: Codec::open(cache, LOGS_TREE)?,
+            }),
+        })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.trees.is_some()
+    }
+
+    // First and last block we have the logs of
+    pub fn range(&self) -> Option<(u64, u64)> {
+        let range = self.trees.as_ref()?.meta.get(RANGE_KEY).ok()??;
+        let from = u64::from_be_bytes(range.get(..8)?.try_into().ok()?);
+        let to = u64::from_be_bytes(range.get(8..16)?.try_into().ok()?);
+        Some((from, to))
+    }
+
+    // Add the `logs` of blocks `from` to `to`, which have to follow the
+    // blocks we already indexed
+    pub fn insert(&self, from: u64, to: u64, logs: &[Value]) -> Result<(), LogIndexError> {
+        let trees = match &self.trees {
+            Some(trees) => trees,
+            None => return Ok(()),
+        };
+
+        let mut by_position = Batch::default();
+        let mut by_address = Batch::default();
+        let mut by_topic = Batch::default();
+        for log in logs {
+            let block = parse_number(&log["blockNumber"]).ok_or(LogIndexError::InvalidResponse(
+                "log without a blockNumber".to_string(),
+            ))?;
+            let index = parse_number(&log["logIndex"])
+                .and_then(|index| u32::try_from(index).ok())
+                .ok_or(LogIndexError::InvalidResponse(
+                    "log without a logIndex".to_string(),
+                ))?;
+            let position = position(block, index);
+
+            by_position.insert(
+                &position,
+                trees.codec.encode(&strip_position(log, block, index)),
+            );
+            if let Some(address) = log["address"]
+                .as_str()
+                .and_then(|address| decode(address, ADDRESS_LEN))
+            {
+                by_address.insert([address.as_slice(), &position].concat(), &[]);
+            }
+            if let Some(topic) = log["topics"][0]
+                .as_str()
+                .and_then(|topic| decode(topic, TOPIC_LEN))
+            {
+                by_topic.insert([topic.as_slice(), &position].concat(), &[]);
+            }
+        }
+
+        // The range goes last, so logs we stored before crashing halfway
+        // are stored again next time instead of being served incomplete
+        trees.logs.apply_batch(by_position)?;
+        trees.by_address.apply_batch(by_address)?;
+        trees.by_topic.apply_batch(by_topic)?;
+        let start = self.range().map_or(from, |(start, _)| start);
+        trees
+            .meta
+            .insert(RANGE_KEY, [start.to_be_bytes(), to.to_be_bytes()].concat())?;
+
+        Ok(())
+    }
+
+    // Positions of the logs in `index` under `keys` in blocks `from` to `to`.
+    // `None` once there are more than `max` of them.
+    fn scan(
+        index: &Tree,
+        keys: &[Vec<u8>],
+        from: u64,
+        to: u64,
+        max: usize,
+    ) -> Option<BTreeSet<[u8; POSITION_LEN]>> {
+        let mut positions = BTreeSet::new();
+        for key in keys {
+            let start = [key.as_slice(), &position(from, 0)].concat();
+            let end = [key.as_slice(), &position(to, u32::MAX)].concat();
+            for (entry, _) in index.range(start..=end).flatten() {
+                if let Ok(position) = entry[key.len()..].try_into() {
+                    positions.insert(position);
+                }
+                if positions.len() > max {
+                    return None;
+                }
+            }
+        }
+        Some(positions)
+    }
+
+    // Logs matching `filter`, the params of `eth_getLogs`. `None` if we
+    // don't have all of them, or there are too many, and it has to go
+    // upstream. Reads from sled, so call it off the async runtime.
+    pub fn query(&self, filter: &Value) -> Option<Vec<Value>> {
+        let trees = self.trees.as_ref()?;
+        let settings = self.settings.as_ref()?;
+        if !filter.is_object() || !filter["blockHash"].is_null() {
+            return None;
+        }
+
+        let from = parse_number(&filter["fromBlock"])?;
+        let to = parse_number(&filter["toBlock"])?;
+        let (start, end) = self.range()?;
+        if from > to || from < start || to > end || to - from >= settings.max_range {
+            return None;
+        }
+
+        let addresses = filter_values(&filter["address"])?;
+        if !settings.addresses.is_empty()
+            && (addresses.is_empty()
+                || addresses
+                    .iter()
+                    .any(|address| !settings.addresses.contains(address)))
+        {
+            return None;
+        }
+        let topics = match &filter["topics"] {
+            Value::Null => Vec::new(),
+            Value::Array(topics) => {
+                topics
+                    .iter()
+                    .map(filter_values)
+                    .collect::<Option<Vec<Vec<String>>>>()?
+            }
+            _ => return None,
+        };
+        let topic0 = topics.first().cloned().unwrap_or_default();
+
+        let max = settings.max_logs;
+        let positions = if !addresses.is_empty() {
+            let keys = addresses
+                .iter()
+                .map(|address| decode(address, ADDRESS_LEN))
+                .collect::<Option<Vec<Vec<u8>>>>()?;
+            Self::scan(&trees.by_address, &keys, from, to, max)?
+        } else if !topic0.is_empty() {
+            let keys = topic0
+                .iter()
+                .map(|topic| decode(topic, TOPIC_LEN))
+                .collect::<Option<Vec<Vec<u8>>>>()?;
+            Self::scan(&trees.by_topic, &keys, from, to, max)?
+        } else {
+            Self::scan(&trees.logs, &[Vec::new()], from, to, max)?
+        };
+
+        let logs = positions
+            .iter()
+            .filter_map(|position| {
+                let log = trees.logs.get(position).ok()??;
+                let mut log = trees.codec.decode(&log)?;
+                restore_position(&mut log, position);
+                Some(log)
+            })
+            .filter(|log| {
+                matches(&addresses, &log["address"])
+                    && topics
+                        .iter()
+                        .enumerate()
+                        .all(|(i, topic)| matches(topic, &log["topics"][i]))
+            })
+            .collect();
+        Some(logs)
+    }
+}
+
+// Logs of blocks `from` to `to` from one of our nodes
+async fn fetch_logs(
+    settings: &LogIndexSettings,
+    from: u64,
+    to: u64,
+    rpc_list: &Arc<RwLock<Vec<Rpc>>>,
+    ttl: u128,
+) -> Result<Vec<Value>, LogIndexError> {
+    let mut filter = json!({
+        "fromBlock": format!("0x{:x}", from),
+        "toBlock": format!("0x{:x}", to),
+    });
+    if !settings.addresses.is_empty() {
+        filter["address"] = json!(settings.addresses);
+    }
+    let request = json!({
+        "jsonrpc": "2.0",
+        "id": 1,
+        "method": "eth_getLogs",
+        "params": [filter],
+    });
+
+    let rpc = {
+        let mut rpc_list = rpc_list.write().unwrap();
+        let picked = pick(&mut rpc_list);
+        if picked.1.is_none() {
+            return Err(LogIndexError::NoRpcAvailable);
+        }
+        route_available(&rpc_list, picked, &request).0
+    };
+
+    let rx = match timeout(
+        Duration::from_millis(ttl.try_into().unwrap_or(u64::MAX)),
+        rpc.send_request(request),
+    )
+    .await
+    {
+        Ok(Ok(rx)) => rx,
+        Ok(Err(e)) => return Err(LogIndexError::InvalidResponse(e.to_string())),
+        Err(_) => return Err(LogIndexError::TimedOut),
+    };
+
+    let mut response: Value =
+        serde_json::from_str(&rx).map_err(|e| LogIndexError::InvalidResponse(e.to_string()))?;
+    if let Some(error) = response.get("error") {
+        return Err(LogIndexError::InvalidResponse(error.to_string()));
+    }
+    match response["result"].take() {
+        Value::Array(logs) => Ok(logs),
+        _ => {
+            Err(LogIndexError::InvalidResponse(
+                "result is not a list".to_string(),
+            ))
+        }
+    }
+}
+
+// Index the logs of every block up to the finalized one as it moves
+pub async fn index_logs(
+    index: Arc<LogIndex>,
+    rpc_list: Arc<RwLock<Vec<Rpc>>>,
+    mut finalized_rx: watch::Receiver<u64>,
+    ttl: u128,
+) {
+    let settings = match &index.settings {
+        Some(settings) => settings.clone(),
+        None => return,
+    };
+    if let Some((from, to)) = index.range() {
+        log_info!("Log index covers blocks {} to {}", from, to);
+    }
+
+    loop {
+        let finalized = *finalized_rx.borrow_and_update();
+        // Not known until the first health check
+        if finalized > 0 {
+            let mut next = match index.range() {
+                Some((_, to)) => to + 1,
+                None => finalized.saturating_sub(settings.backfill),
+            };
+            // Ask for no more than every node can answer
+            let batch = rpc_list
+                .read()
+                .unwrap()
+                .iter()
+                .filter_map(|rpc| rpc.profile.and_then(|profile| profile.max_log_range()))
+                .min()
+                .map_or(settings.batch, |max| settings.batch.min(max.max(1)));
+
+            while next <= finalized {
+                let to = finalized.min(next.saturating_add(batch - 1));
+                let indexed = match fetch_logs(&settings, next, to, &rpc_list, ttl).await {
+                    Ok(logs) => index.insert(next, to, &logs),
+                    Err(e) => Err(e),
+                };
+                if let Err(e) = indexed {
+                    // Try again once the finalized block moves
+                    log_wrn!("Could not index logs of blocks {} to {}: {}", next, to, e);
+                    break;
+                }
+                next = to + 1;
+            }
+        }
+
+        if finalized_rx.changed().await.is_err() {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TOKEN: &str = "0xa0b86991c6218b36c1d19d4a2e9eb0ce3606eb48";
+    const OTHER: &str = "0xdac17f958d2ee523a2206206994597c13d831ec7";
+    const TRANSFER: &str = "0xddf252ad1be2c89b69c2b068fc378daa952ba7f163c4a11628f55a4df523b3ef";
+    const APPROVAL: &str = "0x8c5be1e5ebec7d5bd14f71427d1e84f3dd0314c0f7b2291e5b200ac8c7c3b925";
+    const ALICE: &str = "0x000000000000000000000000000000000000000000000000000000000000a11c";
+
+    fn log(block: u64, index: u64, address: &str, topics: &[&str]) -> Value {
+        json!({
+            "address": address,
+            "blockNumber": format!("0x{:x}", block),
+            "logIndex": format!("0x{:x}", index),
+            "topics": topics,
+            "data": "0x",
+        })
+    }
+
+    fn index(settings: LogIndexSettings) -> LogIndex {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        LogIndex::open(&db, Some(&settings)).unwrap()
+    }
+
+    fn settings(addresses: &[&str]) -> LogIndexSettings {
+        LogIndexSettings {
+            backfill: 0,
+            batch: 100,
+            addresses: addresses
+                .iter()
+                .map(|address| address.to_string())
+                .collect(),
+            max_range: 10,
+            max_logs: 4,
+        }
+    }
+
+    fn blocks(logs: &[Value]) -> Vec<(u64, u64)> {
+        logs.iter()
+            .map(|log| {
+                (
+                    parse_number(&log["blockNumber"]).unwrap(),
+                    parse_number(&log["logIndex"]).unwrap(),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_log_index_query() {
+        let index = index(settings(&[]));
+        assert_eq!(
+            index.query(&json!({"fromBlock": "0x1", "toBlock": "0x1"})),
+            None
+        );
+
+        index
+            .insert(
+                10,
+                11,
+                &[
+                    log(10, 0, TOKEN, &[TRANSFER, ALICE]),
+                    log(10, 1, OTHER, &[TRANSFER]),
+                    log(11, 0, TOKEN, &[APPROVAL, ALICE]),
+                ],
+            )
+            .unwrap();
+        index
+            .insert(
+                12,
+                12,
+                &[log(
+                    12,
+                    3,
+                    "0xA0b86991c6218b36c1d19D4a2e9Eb0cE3606eB48",
+                    &[TRANSFER],
+                )],
+            )
+            .unwrap();
+        assert_eq!(index.range(), Some((10, 12)));
+
+        // By address, in chain order
+        let logs = index
+            .query(&json!({"fromBlock": "0xa", "toBlock": "0xc", "address": TOKEN}))
+            .unwrap();
+        assert_eq!(blocks(&logs), vec![(10, 0), (11, 0), (12, 3)]);
+
+        // By topic0, with the other topics matched on the logs
+        let logs = index
+            .query(&json!({"fromBlock": 10, "toBlock": 12, "topics": [TRANSFER, ALICE]}))
+            .unwrap();
+        assert_eq!(blocks(&logs), vec![(10, 0)]);
+        let logs = index
+            .query(&json!({"fromBlock": 10, "toBlock": 12, "topics": [[TRANSFER, APPROVAL], null]}))
+            .unwrap();
+        assert_eq!(blocks(&logs), vec![(10, 0), (10, 1), (11, 0), (12, 3)]);
+
+        // Everything in a range
+        let logs = index
+            .query(&json!({"fromBlock": "0xb", "toBlock": "0xb"}))
+            .unwrap();
+        assert_eq!(blocks(&logs), vec![(11, 0)]);
+        let logs = index
+            .query(&json!({"fromBlock": 10, "toBlock": 12, "address": [OTHER, TOKEN], "topics": [TRANSFER]}))
+            .unwrap();
+        assert_eq!(blocks(&logs), vec![(10, 0), (10, 1), (12, 3)]);
+
+        // Not indexed, or not by number
+        assert_eq!(
+            index.query(&json!({"fromBlock": 9, "toBlock": 12, "address": TOKEN})),
+            None
+        );
+        assert_eq!(index.query(&json!({"fromBlock": 10, "toBlock": 13})), None);
+        assert_eq!(
+            index.query(&json!({"fromBlock": 10, "toBlock": "latest"})),
+            None
+        );
+        assert_eq!(index.query(&json!({"blockHash": TRANSFER})), None);
+    }
+
+    #[test]
+    fn test_log_index_addresses() {
+        let index = index(settings(&[TOKEN]));
+        index
+            .insert(1, 1, &[log(1, 0, TOKEN, &[TRANSFER])])
+            .unwrap();
+
+        let logs = index
+            .query(&json!({"fromBlock": 1, "toBlock": 1, "address": TOKEN}))
+            .unwrap();
+        assert_eq!(blocks(&logs), vec![(1, 0)]);
+
+        // We only have logs of the addresses we index
+        assert_eq!(
+            index.query(&json!({"fromBlock": 1, "toBlock": 1, "address": [TOKEN, OTHER]})),
+            None
+        );
+        assert_eq!(
+            index.query(&json!({"fromBlock": 1, "toBlock": 1, "topics": [TRANSFER]})),
+            None
+        );
+    }
+
+    #[test]
+    fn test_log_index_limits() {
+        let index = index(settings(&[]));
+        index
+            .insert(
+                1,
+                20,
+                &(0..5)
+                    .map(|i| log(1, i, TOKEN, &[TRANSFER]))
+                    .collect::<Vec<Value>>(),
+            )
+            .unwrap();
+
+        // Too many logs, or too many blocks
+        assert_eq!(
+            index.query(&json!({"fromBlock": 1, "toBlock": 1, "address": TOKEN})),
+            None
+        );
+        assert_eq!(index.query(&json!({"fromBlock": 1, "toBlock": 1})), None);
+        assert_eq!(index.query(&json!({"fromBlock": 2, "toBlock": 12})), None);
+        assert_eq!(
+            index.query(&json!({"fromBlock": 2, "toBlock": 11})),
+            Some(Vec::new())
+        );
+    }
+
+    #[test]
+    fn test_log_index_disabled() {
+        let index = LogIndex::default();
+        assert!(!index.is_enabled());
+        index.insert(1, 1, &[log(1, 0, TOKEN, &[])]).unwrap();
+        assert_eq!(index.range(), None);
+        assert_eq!(index.query(&json!({"fromBlock": 1, "toBlock": 1})), None);
+    }
+}
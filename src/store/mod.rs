@@ -0,0 +1,3 @@
+pub mod codec;
+pub mod logs;
+pub mod receipts;
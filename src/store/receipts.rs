@@ -0,0 +1,275 @@
+// Local store of the receipts of finalized transactions.
+//
+// With `[blutgang.receipts]` set, every receipt a node sends us for a
+// transaction in a finalized block is kept in sled next to the cache, and
+// `eth_getTransactionReceipt` for it is answered from here from then on.
+// Unlike cached responses, receipts aren't evicted by the memory budget or
+// wiped with the cache, and they never change once finalized.
+//
+// Once the receipts we keep go over `max_size` bytes, we drop those of the
+// oldest blocks until we're `PRUNE_TO` of the way back under it, since old
+// transactions are the least likely to be looked up again.
+//
+// receipts:  tx hash (32 bytes)                 -> receipt
+// blocks:    block (8 bytes) | tx hash          -> ()
+//
+// Receipts are stored with `store::codec`, without the transaction hash
+// their key already has.
+use crate::{
+    balancer::block_range::parse_number,
+    config::types::ReceiptStoreSettings,
+    log_info,
+    log_wrn,
+    store::codec::Codec,
+};
+
+use std::sync::{
+    atomic::{
+        AtomicU64,
+        Ordering,
+    },
+    Mutex,
+};
+
+use serde_json::Value;
+use sled::{
+    Db,
+    Tree,
+};
+
+const RECEIPTS_TREE: &str = "receipts";
+const BLOCKS_TREE: &str = "receipts_blocks";
+
+const HASH_LEN: usize = 32;
+// Share of `max_size` we prune down to, so we don't prune on every insert
+const PRUNE_TO: f64 = 0.9;
+
+// `0x` prefixed transaction hash
+fn decode_hash(hash: &str) -> Option<Vec<u8>> {
+    hex::decode(hash.strip_prefix("0x")?)
+        .ok()
+        .filter(|bytes| bytes.len() == HASH_LEN)
+}
+
+#[derive(Debug)]
+struct Trees {
+    receipts: Tree,
+    blocks: Tree,
+    codec: Codec,
+}
+
+#[derive(Debug, Default)]
+pub struct ReceiptStore {
+    max_size: u64,
+    trees: Option<Trees>,
+    // Bytes of receipts we keep
+    size: AtomicU64,
+    // Held while pruning, so concurrent inserts don't all prune at once
+    pruning: Mutex<()>,
+}
+
+impl ReceiptStore {
+    // Receipts kept in `cache`, disabled if `settings` aren't set
+    pub fn open(cache: &Db, settings: Option<&ReceiptStoreSettings>) -> Result<Self, sled::Error> {
+        let settings = match settings {
+            Some(settings) => settings,
+            None => return Ok(ReceiptStore::default()),
+        };
+
+        let receipts = cache.open_tree(RECEIPTS_TREE)?;
+        let size = receipts
+            .iter()
+            .flatten()
+            .map(|(_, receipt)| receipt.len() as u64)
+            .sum();
+        if size > 0 {
+            log_info!("Receipt store holds {} bytes of receipts", size);
+        }
+
+        Ok(ReceiptStore {
+            max_size: settings.max_size,
+            trees: Some(Trees {
+                receipts,
+                blocks: cache.open_tree(BLOCKS_TREE)?,
+                codec: Codec::open(cache, RECEIPTS_TREE)?,
+            }),
+            size: AtomicU64::new(size),
+            pruning: Mutex::new(()),
+        })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.trees.is_some()
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size.load(Ordering::Relaxed)
+    }
+
+    // Receipt of the transaction with hash `hash`, if we have it
+    pub fn get(&self, hash: &Value) -> Option<Value> {
+        let trees = self.trees.as_ref()?;
+        let hash = decode_hash(hash.as_str()?)?;
+        let receipt = trees.receipts.get(&hash).ok()??;
+        let mut receipt = trees.codec.decode(&receipt)?;
+        if let Some(fields) = receipt.as_object_mut() {
+            fields
+                .entry("transactionHash")
+                .or_insert_with(|| format!("0x{}", hex::encode(&hash)).into());
+        }
+        Some(receipt)
+    }
+
+    // Keep the receipt in `response`, a response to `eth_getTransactionReceipt`,
+    // if its block is at or below `finalized`
+    pub fn insert_response(&self, response: &str, finalized: u64) {
+        let trees = match &self.trees {
+            Some(trees) => trees,
+            None => return,
+        };
+        let response: Value = match serde_json::from_str(response) {
+            Ok(response) => response,
+            Err(_) => return,
+        };
+        let receipt = &response["result"];
+        let (block, hash) = match (
+            parse_number(&receipt["blockNumber"]),
+            receipt["transactionHash"].as_str().and_then(decode_hash),
+        ) {
+            (Some(block), Some(hash)) if block <= finalized => (block, hash),
+            // Pending, not finalized yet, or not a receipt
+            _ => return,
+        };
+
+        let mut receipt = receipt.clone();
+        if let Some(fields) = receipt.as_object_mut() {
+            if fields.get("transactionHash").and_then(Value::as_str)
+                == Some(&format!("0x{}", hex::encode(&hash)))
+            {
+                fields.remove("transactionHash");
+            }
+        }
+        let receipt = trees.codec.encode(&receipt);
+        let len = receipt.len() as u64;
+        match trees.receipts.insert(&hash, receipt) {
+            // Already had it
+            Ok(Some(_)) => return,
+            Ok(None) => {}
+            Err(e) => {
+                log_wrn!("Could not store receipt: {}", e);
+                return;
+            }
+        }
+        let _ = trees
+            .blocks
+            .insert([block.to_be_bytes().as_slice(), &hash].concat(), &[]);
+
+        if self.size.fetch_add(len, Ordering::Relaxed) + len > self.max_size {
+            self.prune(trees);
+        }
+    }
+
+    // Drop receipts of the oldest blocks until we're back under the cap
+    fn prune(&self, trees: &Trees) {
+        let _guard = match self.pruning.try_lock() {
+            Ok(guard) => guard,
+            Err(_) => return,
+        };
+
+        let target = (self.max_size as f64 * PRUNE_TO) as u64;
+        let mut pruned = 0;
+        for (key, _) in trees.blocks.iter().flatten() {
+            if self.size() <= target {
+                break;
+            }
+            let _ = trees.blocks.remove(&key);
+            if let Ok(Some(receipt)) = trees.receipts.remove(&key[8..]) {
+                self.size.fetch_sub(receipt.len() as u64, Ordering::Relaxed);
+                pruned += 1;
+            }
+        }
+        log_info!("Pruned {} receipts of old blocks", pruned);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn hash(i: u64) -> String {
+        format!("0x{:064x}", i)
+    }
+
+    fn response(block: u64, i: u64) -> String {
+        json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "result": {
+                "blockNumber": format!("0x{:x}", block),
+                "transactionHash": hash(i),
+                "status": "0x1",
+            },
+        })
+        .to_string()
+    }
+
+    fn store(max_size: u64) -> ReceiptStore {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        ReceiptStore::open(&db, Some(&ReceiptStoreSettings { max_size })).unwrap()
+    }
+
+    #[test]
+    fn test_receipt_store() {
+        let store = store(1 << 20);
+        store.insert_response(&response(10, 1), 10);
+        assert_eq!(store.get(&json!(hash(1))).unwrap()["status"], "0x1");
+
+        // Not finalized yet, pending, or not found
+        store.insert_response(&response(11, 2), 10);
+        assert_eq!(store.get(&json!(hash(2))), None);
+        store.insert_response(
+            &json!({"jsonrpc": "2.0", "id": 1, "result": {"transactionHash": hash(3), "blockNumber": null}})
+                .to_string(),
+            10,
+        );
+        store.insert_response(
+            &json!({"jsonrpc": "2.0", "id": 1, "result": null}).to_string(),
+            10,
+        );
+        assert_eq!(store.get(&json!(hash(3))), None);
+
+        // Inserting twice doesn't count twice
+        let size = store.size();
+        store.insert_response(&response(10, 1), 10);
+        assert_eq!(store.size(), size);
+
+        assert_eq!(store.get(&json!("0x01")), None);
+        assert_eq!(ReceiptStore::default().get(&json!(hash(1))), None);
+    }
+
+    #[test]
+    fn test_receipt_store_pruning() {
+        // Every receipt takes the same space
+        let len = {
+            let store = store(1 << 20);
+            store.insert_response(&response(1, 1), 100);
+            store.size()
+        };
+        let store = store(len * 10);
+
+        // Newer blocks first, pruning goes by block, not by insertion
+        for i in (1..=10).rev() {
+            store.insert_response(&response(i, i), 100);
+        }
+        assert_eq!(store.size(), len * 10);
+        assert!(store.get(&json!(hash(1))).is_some());
+
+        store.insert_response(&response(11, 11), 100);
+        assert!(store.size() <= len * 9);
+        assert_eq!(store.get(&json!(hash(1))), None);
+        assert_eq!(store.get(&json!(hash(2))), None);
+        assert!(store.get(&json!(hash(3))).is_some());
+        assert!(store.get(&json!(hash(11))).is_some());
+    }
+}
@@ -0,0 +1,287 @@
+// Decode raw transactions and recover who signed them.
+//
+// We only need the hash, sender and nonce of transactions broadcast through
+// us, so the rest of the fields are left alone. Typed transactions (EIP-2718)
+// sign everything but the signature, legacy ones add the chain id to what they
+// sign if they're EIP-155.
+use crate::{
+    transactions::error::TxError,
+    verify::proof::keccak256,
+};
+
+use openssl::{
+    bn::{
+        BigNum,
+        BigNumContext,
+    },
+    ec::{
+        EcGroup,
+        EcPoint,
+        PointConversionForm,
+    },
+    nid::Nid,
+};
+use rlp::{
+    Rlp,
+    RlpStream,
+};
+
+// Blob transactions are broadcast with their blobs wrapped around them
+const BLOB_TX_TYPE: u8 = 0x03;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DecodedTransaction {
+    pub hash: [u8; 32],
+    pub from: [u8; 20],
+    pub nonce: u64,
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    format!("0x{}", hex::encode(bytes))
+}
+
+// Decode an `eth_sendRawTransaction` param
+pub fn decode_transaction(raw: &str) -> Result<DecodedTransaction, TxError> {
+    let bytes = hex::decode(raw.trim_start_matches("0x"))?;
+    match bytes.first() {
+        None => Err(TxError::InvalidTransaction("empty transaction".to_string())),
+        Some(0xc0..=0xff) => decode_legacy(&bytes),
+        Some(0x80..=0xbf) => {
+            Err(TxError::InvalidTransaction(format!(
+                "unknown transaction type {:#x}",
+                bytes[0]
+            )))
+        }
+        Some(&tx_type) => decode_typed(tx_type, &bytes[1..]),
+    }
+}
+
+fn decode_legacy(bytes: &[u8]) -> Result<DecodedTransaction, TxError> {
+    let rlp = Rlp::new(bytes);
+    if rlp.item_count()? != 9 {
+        return Err(TxError::InvalidTransaction(
+            "legacy transactions have 9 fields".to_string(),
+        ));
+    }
+
+    let v: u64 = rlp.val_at(6)?;
+    let mut stream = RlpStream::new();
+    let parity = match v {
+        27 | 28 => {
+            stream.begin_list(6);
+            for i in 0..6 {
+                stream.append_raw(rlp.at(i)?.as_raw(), 1);
+            }
+            v - 27
+        }
+        35.. => {
+            stream.begin_list(9);
+            for i in 0..6 {
+                stream.append_raw(rlp.at(i)?.as_raw(), 1);
+            }
+            stream.append(&((v - 35) / 2));
+            stream.append_empty_data();
+            stream.append_empty_data();
+            (v - 35) % 2
+        }
+        _ => return Err(TxError::InvalidSignature(format!("invalid v {}", v))),
+    };
+
+    Ok(DecodedTransaction {
+        hash: keccak256(bytes),
+        from: recover(
+            &keccak256(&stream.out()),
+            rlp.at(7)?.data()?,
+            rlp.at(8)?.data()?,
+            parity as u8,
+        )?,
+        nonce: rlp.val_at(0)?,
+    })
+}
+
+fn decode_typed(tx_type: u8, payload: &[u8]) -> Result<DecodedTransaction, TxError> {
+    let outer = Rlp::new(payload);
+    let rlp = if tx_type == BLOB_TX_TYPE && outer.at(0)?.is_list() {
+        outer.at(0)?
+    } else {
+        outer
+    };
+
+    // Every typed transaction so far ends with `y_parity, r, s`
+    let fields = rlp.item_count()?;
+    if fields < 5 {
+        return Err(TxError::InvalidTransaction(format!(
+            "type {:#x} transaction has only {} fields",
+            tx_type, fields
+        )));
+    }
+
+    let mut stream = RlpStream::new_list(fields - 3);
+    for i in 0..fields - 3 {
+        stream.append_raw(rlp.at(i)?.as_raw(), 1);
+    }
+    let mut unsigned = vec![tx_type];
+    unsigned.extend_from_slice(&stream.out());
+
+    let mut signed = vec![tx_type];
+    signed.extend_from_slice(rlp.as_raw());
+
+    Ok(DecodedTransaction {
+        hash: keccak256(&signed),
+        from: recover(
+            &keccak256(&unsigned),
+            rlp.at(fields - 2)?.data()?,
+            rlp.at(fields - 1)?.data()?,
+            rlp.val_at(fields - 3)?,
+        )?,
+        nonce: rlp.val_at(1)?,
+    })
+}
+
+// Recover the address that signed `hash` with secp256k1
+fn recover(hash: &[u8; 32], r: &[u8], s: &[u8], parity: u8) -> Result<[u8; 20], TxError> {
+    if parity > 1 || r.len() > 32 || s.len() > 32 {
+        return Err(TxError::InvalidSignature("malformed signature".to_string()));
+    }
+
+    let group = EcGroup::from_curve_name(Nid::SECP256K1)?;
+    let mut ctx = BigNumContext::new()?;
+    let mut order = BigNum::new()?;
+    group.order(&mut order, &mut ctx)?;
+
+    let r_num = BigNum::from_slice(r)?;
+    let s_num = BigNum::from_slice(s)?;
+    let zero = BigNum::new()?;
+    if r_num <= zero || r_num >= order || s_num <= zero || s_num >= order {
+        return Err(TxError::InvalidSignature("r or s out of range".to_string()));
+    }
+
+    // The point R whose x coordinate is r
+    let mut compressed = vec![0x02 + parity];
+    compressed.extend(std::iter::repeat(0).take(32 - r.len()));
+    compressed.extend_from_slice(r);
+    let point_r = EcPoint::from_bytes(&group, &compressed, &mut ctx)?;
+
+    // Q = r^-1 * (s * R - e * G)
+    let e = BigNum::from_slice(hash)?;
+    let mut r_inv = BigNum::new()?;
+    r_inv.mod_inverse(&r_num, &order, &mut ctx)?;
+    let mut neg_e = BigNum::new()?;
+    neg_e.mod_sub(&zero, &e, &order, &mut ctx)?;
+    let mut u1 = BigNum::new()?;
+    u1.mod_mul(&neg_e, &r_inv, &order, &mut ctx)?;
+    let mut u2 = BigNum::new()?;
+    u2.mod_mul(&s_num, &r_inv, &order, &mut ctx)?;
+
+    let mut public_key = EcPoint::new(&group)?;
+    public_key.mul_full(&group, &u1, &point_r, &u2, &mut ctx)?;
+    if public_key.is_infinity(&group) {
+        return Err(TxError::InvalidSignature(
+            "recovered the point at infinity".to_string(),
+        ));
+    }
+
+    let public_key = public_key.to_bytes(&group, PointConversionForm::UNCOMPRESSED, &mut ctx)?;
+    let mut address = [0u8; 20];
+    address.copy_from_slice(&keccak256(&public_key[1..])[12..]);
+    Ok(address)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openssl::{
+        ec::EcKey,
+        ecdsa::EcdsaSig,
+    };
+
+    fn public_key(group: &EcGroup, private_key: &[u8]) -> EcPoint {
+        let ctx = BigNumContext::new().unwrap();
+        let mut public_key = EcPoint::new(group).unwrap();
+        public_key
+            .mul_generator(group, &BigNum::from_slice(private_key).unwrap(), &ctx)
+            .unwrap();
+        public_key
+    }
+
+    // Address of `private_key` on secp256k1
+    fn address_of(private_key: &[u8]) -> [u8; 20] {
+        let group = EcGroup::from_curve_name(Nid::SECP256K1).unwrap();
+        let mut ctx = BigNumContext::new().unwrap();
+        let public_key = public_key(&group, private_key)
+            .to_bytes(&group, PointConversionForm::UNCOMPRESSED, &mut ctx)
+            .unwrap();
+
+        let mut address = [0u8; 20];
+        address.copy_from_slice(&keccak256(&public_key[1..])[12..]);
+        address
+    }
+
+    #[test]
+    fn test_decode_legacy() {
+        // The example from EIP-155, signed with 0x4646...46
+        let raw = "0xf86c098504a817c800825208943535353535353535353535353535353535353535880de0b6b3a76400008025a028ef61340bd939bc2195fe537567866003e1a15d3c71ff63e1590620aa636276a067cbe9d8997f761aecb703304b3800ccf555c9f3dc64214b297fb1966a3b6d83";
+        let decoded = decode_transaction(raw).unwrap();
+
+        assert_eq!(decoded.nonce, 9);
+        assert_eq!(decoded.from, address_of(&[0x46; 32]));
+        assert_eq!(
+            to_hex(&decoded.from),
+            "0x9d8a62f656a8d1615c1294fd71e9cfb3e4855a4f"
+        );
+        assert_eq!(decoded.hash, keccak256(&hex::decode(&raw[2..]).unwrap()));
+    }
+
+    #[test]
+    fn test_decode_typed() {
+        let private_key = [0x11; 32];
+        let group = EcGroup::from_curve_name(Nid::SECP256K1).unwrap();
+        let key = EcKey::from_private_components(
+            &group,
+            &BigNum::from_slice(&private_key).unwrap(),
+            &public_key(&group, &private_key),
+        )
+        .unwrap();
+
+        // EIP-1559: chain id, nonce, tip, fee cap, gas, to, value, data, access list
+        let mut stream = RlpStream::new_list(9);
+        stream.append(&1u64);
+        stream.append(&42u64);
+        stream.append(&1_000_000_000u64);
+        stream.append(&30_000_000_000u64);
+        stream.append(&21_000u64);
+        stream.append(&vec![0x35u8; 20]);
+        stream.append(&1u64);
+        stream.append_empty_data();
+        stream.begin_list(0);
+        let mut unsigned = vec![0x02];
+        unsigned.extend_from_slice(&stream.out());
+
+        let signature = EcdsaSig::sign(&keccak256(&unsigned), &key).unwrap();
+        let (r, s) = (signature.r().to_vec(), signature.s().to_vec());
+
+        // OpenSSL doesn't tell us the parity, whichever one recovers our key is it
+        let from = address_of(&private_key);
+        let decoded = (0..2u8)
+            .map(|parity| {
+                let mut stream = RlpStream::new_list(12);
+                let unsigned_rlp = Rlp::new(&unsigned[1..]);
+                for i in 0..9 {
+                    stream.append_raw(unsigned_rlp.at(i).unwrap().as_raw(), 1);
+                }
+                stream.append(&parity);
+                stream.append(&r);
+                stream.append(&s);
+                let mut raw = vec![0x02];
+                raw.extend_from_slice(&stream.out());
+                decode_transaction(&to_hex(&raw)).unwrap()
+            })
+            .find(|decoded| decoded.from == from)
+            .unwrap();
+        assert_eq!(decoded.nonce, 42);
+
+        assert!(decode_transaction("0x").is_err());
+        assert!(decode_transaction("0x02c0").is_err());
+        assert!(decode_transaction("nope").is_err());
+    }
+}
@@ -0,0 +1,42 @@
+// Errors
+use std::error::Error;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum TxError {
+    // Not a hex string
+    InvalidHex(String),
+    // Not a transaction we know how to decode
+    InvalidTransaction(String),
+    // We couldn't recover a sender from the signature
+    InvalidSignature(String),
+}
+
+impl std::fmt::Display for TxError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            TxError::InvalidHex(reason) => write!(f, "Invalid hex: {}", reason),
+            TxError::InvalidTransaction(reason) => write!(f, "Invalid transaction: {}", reason),
+            TxError::InvalidSignature(reason) => write!(f, "Invalid signature: {}", reason),
+        }
+    }
+}
+
+impl From<hex::FromHexError> for TxError {
+    fn from(e: hex::FromHexError) -> Self {
+        TxError::InvalidHex(e.to_string())
+    }
+}
+
+impl From<rlp::DecoderError> for TxError {
+    fn from(e: rlp::DecoderError) -> Self {
+        TxError::InvalidTransaction(format!("bad RLP: {}", e))
+    }
+}
+
+impl From<openssl::error::ErrorStack> for TxError {
+    fn from(e: openssl::error::ErrorStack) -> Self {
+        TxError::InvalidSignature(e.to_string())
+    }
+}
+
+impl Error for TxError {}
@@ -0,0 +1,5 @@
+pub mod decode;
+pub mod error;
+pub mod rebroadcast;
+pub mod replay;
+pub mod tracker;
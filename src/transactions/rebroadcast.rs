@@ -0,0 +1,187 @@
+// Rebroadcast transactions until they're included.
+//
+// Nodes evict transactions from their mempools when they fill up or restart,
+// and a transaction nobody gossips anymore never makes it into a block. Every
+// `interval` we check whether the transactions we track have a receipt yet,
+// and send the ones that don't to every node again until their `ttl` is up.
+use crate::{
+    log_wrn,
+    transactions::tracker::TxTracker,
+    Rpc,
+};
+
+use std::{
+    sync::{
+        atomic::Ordering,
+        Arc,
+        RwLock,
+    },
+    time::Duration,
+};
+
+use futures::future::join_all;
+use serde_json::json;
+use tokio::time::{
+    interval,
+    timeout,
+    MissedTickBehavior,
+};
+
+// Rebroadcast `raw` unless it was already included. Returns true once it's included.
+async fn rebroadcast_tx(nodes: &[Rpc], hash: &str, raw: &str, ttl: Duration) -> bool {
+    let receipt = match nodes.first() {
+        Some(rpc) => timeout(ttl, rpc.call("eth_getTransactionReceipt", json!([hash]))).await,
+        None => return false,
+    };
+    if let Ok(Ok(receipt)) = receipt {
+        if !receipt.is_null() {
+            return true;
+        }
+    }
+
+    // Nodes that still have it answer with "already known", which is fine
+    join_all(nodes.iter().map(|rpc| {
+        async move {
+            rpc.status.rebroadcasts.fetch_add(1, Ordering::Relaxed);
+            let _ = timeout(ttl, rpc.call("eth_sendRawTransaction", json!([raw]))).await;
+        }
+    }))
+    .await;
+
+    false
+}
+
+// Rebroadcast every tracked transaction that's due
+pub async fn rebroadcast_once(tracker: &TxTracker, rpc_list: &Arc<RwLock<Vec<Rpc>>>, ttl: u128) {
+    let due = tracker.due_for_rebroadcast();
+    if due.is_empty() {
+        return;
+    }
+
+    let nodes: Vec<Rpc> = {
+        let rpc_list = rpc_list.read().unwrap_or_else(|e| e.into_inner());
+        rpc_list.clone()
+    };
+    if nodes.is_empty() {
+        log_wrn!("\x1b[93mWrn:\x1b[0m No RPC available to rebroadcast transactions to!");
+        return;
+    }
+
+    let ttl = Duration::from_millis(ttl.try_into().unwrap_or(u64::MAX));
+    let included = join_all(due.iter().map(|(hash, raw)| {
+        let nodes = &nodes;
+        async move { rebroadcast_tx(nodes, hash, raw, ttl).await.then_some(hash) }
+    }))
+    .await;
+
+    for hash in included.into_iter().flatten() {
+        tracker.confirm(hash);
+    }
+}
+
+pub async fn rebroadcast_transactions(
+    tracker: Arc<TxTracker>,
+    rpc_list: Arc<RwLock<Vec<Rpc>>>,
+    ttl: u128,
+) {
+    let every = match tracker.rebroadcast_settings() {
+        Some(settings) => settings.interval,
+        None => return,
+    };
+
+    // Check twice per interval so transactions aren't late by up to a whole one
+    let mut ticker = interval(every / 2);
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        ticker.tick().await;
+        rebroadcast_once(&tracker, &rpc_list, ttl).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::types::RebroadcastSettings,
+        mock::node::MockNode,
+    };
+    use serde_json::Value;
+
+    // The example from EIP-155
+    const RAW: &str = "0xf86c098504a817c800825208943535353535353535353535353535353535353535880de0b6b3a76400008025a028ef61340bd939bc2195fe537567866003e1a15d3c71ff63e1590620aa636276a067cbe9d8997f761aecb703304b3800ccf555c9f3dc64214b297fb1966a3b6d83";
+
+    fn settings(identities: Option<Vec<String>>) -> RebroadcastSettings {
+        RebroadcastSettings {
+            interval: Duration::ZERO,
+            ttl: Duration::from_secs(60),
+            identities,
+        }
+    }
+
+    #[test]
+    fn test_track_identities() {
+        let tracker = TxTracker::new(false, Some(settings(Some(vec!["wallet".to_string()]))));
+        tracker.track(RAW, None).unwrap();
+        tracker.track(RAW, Some("indexer")).unwrap();
+        assert_eq!(tracker.pending_count(), 0);
+
+        tracker.track(RAW, Some("wallet")).unwrap();
+        assert_eq!(tracker.pending_count(), 1);
+        assert_eq!(tracker.due_for_rebroadcast()[0].1, RAW);
+
+        // Nothing is kept when rebroadcasting is off
+        let tracker = TxTracker::new(true, None);
+        tracker.track(RAW, None).unwrap();
+        assert_eq!(tracker.pending_count(), 0);
+        assert!(tracker.due_for_rebroadcast().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_rebroadcast_once() {
+        let first = MockNode::spawn(1).await.unwrap();
+        let second = MockNode::spawn(1).await.unwrap();
+        for node in [&first, &second] {
+            node.set_response("eth_getTransactionReceipt", Value::Null);
+            node.set_response("eth_sendRawTransaction", json!("0x88df"));
+        }
+        let rpc_list = Arc::new(RwLock::new(vec![
+            Rpc::new(first.http_url(), None, 6, 0, 10.0),
+            Rpc::new(second.http_url(), None, 6, 0, 10.0),
+        ]));
+
+        let tracker = TxTracker::new(false, Some(settings(None)));
+        tracker.track(RAW, None).unwrap();
+
+        // Not included yet, so every node gets it again
+        rebroadcast_once(&tracker, &rpc_list, 1000).await;
+        assert_eq!(tracker.pending_count(), 1);
+        assert_eq!(first.request_count(), 2);
+        assert_eq!(second.request_count(), 1);
+        for rpc in rpc_list.read().unwrap().iter() {
+            assert_eq!(rpc.status.rebroadcasts.load(Ordering::Relaxed), 1);
+        }
+
+        // Included, so we're done with it
+        first.set_response(
+            "eth_getTransactionReceipt",
+            json!({"blockNumber": "0x1", "blockHash": "0x2"}),
+        );
+        rebroadcast_once(&tracker, &rpc_list, 1000).await;
+        assert_eq!(tracker.pending_count(), 0);
+        assert_eq!(second.request_count(), 1);
+    }
+
+    #[test]
+    fn test_rebroadcast_ttl() {
+        let tracker = TxTracker::new(
+            false,
+            Some(RebroadcastSettings {
+                ttl: Duration::ZERO,
+                ..settings(None)
+            }),
+        );
+        tracker.track(RAW, None).unwrap();
+        assert!(tracker.due_for_rebroadcast().is_empty());
+        assert_eq!(tracker.pending_count(), 0);
+    }
+}
@@ -0,0 +1,149 @@
+// Replay protection for `eth_sendRawTransaction`.
+//
+// Clients with buggy retry loops can send the exact same signed transaction
+// thousands of times, and every one of those costs compute units on our
+// nodes for an answer that can't change. We remember how often each client
+// sent each raw transaction within `window`, and refuse it once it goes over
+// `max_duplicates`. Clients that really mean it can send the
+// `x-blutgang-allow-replay: true` header to skip the check.
+use crate::{
+    config::types::ReplayProtectionSettings,
+    notify::usage::ANONYMOUS,
+};
+
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::Instant,
+};
+
+use serde_json::{
+    json,
+    Value,
+};
+
+pub const ALLOW_REPLAY_HEADER: &str = "x-blutgang-allow-replay";
+
+// Forget expired entries once we remember this many. Every distinct raw
+// string is a new entry, so if most of them haven't expired yet we forget
+// the oldest ones until a quarter of the room is free again.
+const MAX_ENTRIES: usize = 65_536;
+
+// Client and hash of the raw transaction
+type SendKey = (String, [u8; 32]);
+
+#[derive(Debug, Default)]
+pub struct ReplayGuard {
+    settings: Option<ReplayProtectionSettings>,
+    // Times sent and when it was first sent
+    seen: Mutex<HashMap<SendKey, (u32, Instant)>>,
+}
+
+impl ReplayGuard {
+    pub fn new(settings: Option<ReplayProtectionSettings>) -> Self {
+        Self {
+            settings,
+            seen: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.settings.is_some()
+    }
+
+    // Error response if `identity` already sent `raw` too often, counts it otherwise
+    pub fn check(&self, tx: &Value, identity: Option<&str>) -> Option<Value> {
+        let settings = self.settings.as_ref()?;
+        let raw = tx["params"][0].as_str()?;
+        let key = (
+            identity.unwrap_or(ANONYMOUS).to_string(),
+            *blake3::hash(raw.to_lowercase().as_bytes()).as_bytes(),
+        );
+
+        let mut seen = self.seen.lock().unwrap_or_else(|e| e.into_inner());
+        if seen.len() >= MAX_ENTRIES {
+            seen.retain(|_, (_, first)| first.elapsed() < settings.window);
+
+            let keep = MAX_ENTRIES / 4 * 3;
+            if seen.len() > keep {
+                let mut firsts: Vec<Instant> = seen.values().map(|(_, first)| *first).collect();
+                let (_, cutoff, _) = firsts.select_nth_unstable(seen.len() - keep);
+                let cutoff = *cutoff;
+                seen.retain(|_, (_, first)| *first >= cutoff);
+            }
+        }
+
+        let (count, first) = seen.entry(key).or_insert((0, Instant::now()));
+        if first.elapsed() >= settings.window {
+            *count = 0;
+            *first = Instant::now();
+        }
+        if *count >= settings.max_duplicates {
+            return Some(json!({
+                "jsonrpc": "2.0",
+                "id": tx["id"],
+                "error": {
+                    "code": -32005,
+                    "message": format!(
+                        "Transaction already sent {} times in the last {}s, send {}: true to send it anyway",
+                        count,
+                        settings.window.as_secs(),
+                        ALLOW_REPLAY_HEADER
+                    ),
+                },
+            }));
+        }
+
+        *count += 1;
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn send(raw: &str) -> Value {
+        json!({"jsonrpc": "2.0", "id": 1, "method": "eth_sendRawTransaction", "params": [raw]})
+    }
+
+    #[test]
+    fn test_replay_guard() {
+        let guard = ReplayGuard::new(Some(ReplayProtectionSettings {
+            max_duplicates: 2,
+            window: Duration::from_millis(100),
+        }));
+
+        assert!(guard.check(&send("0x01"), None).is_none());
+        assert!(guard.check(&send("0x01"), None).is_none());
+        let rax = guard.check(&send("0x01"), None).unwrap();
+        assert_eq!(rax["error"]["code"], -32005);
+
+        // Case doesn't make it a different transaction, another client does
+        assert!(guard.check(&send("0X01"), None).is_some());
+        assert!(guard.check(&send("0x01"), Some("indexer")).is_none());
+        assert!(guard.check(&send("0x02"), None).is_none());
+
+        // Allowed again once the window passed
+        std::thread::sleep(Duration::from_millis(110));
+        assert!(guard.check(&send("0x01"), None).is_none());
+
+        assert!(ReplayGuard::default().check(&send("0x01"), None).is_none());
+    }
+
+    #[test]
+    fn test_replay_guard_is_bounded() {
+        let guard = ReplayGuard::new(Some(ReplayProtectionSettings {
+            max_duplicates: 1,
+            window: Duration::from_secs(60),
+        }));
+
+        for n in 0..MAX_ENTRIES + 1 {
+            assert!(guard.check(&send(&format!("0x{:x}", n)), None).is_none());
+        }
+        let seen = guard.seen.lock().unwrap();
+        assert!(seen.len() <= MAX_ENTRIES);
+        assert!(seen.len() > MAX_ENTRIES / 2);
+    }
+}
@@ -0,0 +1,325 @@
+// Transactions broadcast through blutgang.
+//
+// Nodes can disagree on what's in the mempool, so asking a random one for the
+// `pending` transaction count right after a broadcast can hand out a nonce
+// that's already taken. `blutgang_getNextNonce` answers with the highest of
+// what the nodes report and what we've seen broadcast or handed out
+// ourselves, and reserves it so concurrent callers get different nonces.
+//
+// With `[blutgang.rebroadcast]` set we also hold on to the raw transactions
+// until they're included, so they can be sent again if nodes evict them.
+use crate::{
+    config::types::RebroadcastSettings,
+    rpc::types::hex_to_decimal,
+    transactions::decode::{
+        decode_transaction,
+        to_hex,
+        DecodedTransaction,
+    },
+    Rpc,
+};
+
+use std::{
+    collections::HashMap,
+    sync::{
+        Arc,
+        RwLock,
+    },
+    time::{
+        Duration,
+        Instant,
+    },
+};
+
+use futures::future::join_all;
+use serde_json::{
+    json,
+    Value,
+};
+use tokio::time::timeout;
+
+pub const NEXT_NONCE: &str = "blutgang_getNextNonce";
+
+// Nodes should have caught up with our broadcasts by now, and if they haven't
+// the transaction was probably dropped and its nonce is free again
+const NONCE_TTL: Duration = Duration::from_secs(600);
+
+fn nonce_error(id: &Value, code: i64, message: &str) -> Value {
+    json!({"jsonrpc": "2.0", "id": id, "error": {"code": code, "message": message}})
+}
+
+fn parse_address(address: &Value) -> Option<String> {
+    let address = address.as_str()?.strip_prefix("0x")?;
+    (address.len() == 40 && address.bytes().all(|b| b.is_ascii_hexdigit()))
+        .then(|| format!("0x{}", address.to_lowercase()))
+}
+
+// A transaction waiting to be included
+#[derive(Debug, Clone)]
+struct PendingTx {
+    raw: String,
+    sent: Instant,
+    last_sent: Instant,
+}
+
+#[derive(Debug, Default)]
+pub struct TxTracker {
+    nonce_tracking: bool,
+    rebroadcast: Option<RebroadcastSettings>,
+    // Next nonce of each sender according to our broadcasts
+    nonces: RwLock<HashMap<String, (u64, Instant)>>,
+    // Transactions to rebroadcast, by hash
+    pending: RwLock<HashMap<String, PendingTx>>,
+}
+
+impl TxTracker {
+    pub fn new(nonce_tracking: bool, rebroadcast: Option<RebroadcastSettings>) -> Self {
+        TxTracker {
+            nonce_tracking,
+            rebroadcast,
+            nonces: RwLock::new(HashMap::new()),
+            pending: RwLock::new(HashMap::new()),
+        }
+    }
+
+    // Whether we need to look at broadcast transactions at all
+    pub fn is_enabled(&self) -> bool {
+        self.nonce_tracking || self.rebroadcast.is_some()
+    }
+
+    pub fn nonce_tracking(&self) -> bool {
+        self.nonce_tracking
+    }
+
+    pub fn rebroadcast_settings(&self) -> Option<&RebroadcastSettings> {
+        self.rebroadcast.as_ref()
+    }
+
+    // Remember a raw transaction a node accepted from the client `identity`
+    pub fn track(&self, raw: &str, identity: Option<&str>) -> Option<DecodedTransaction> {
+        let decoded = decode_transaction(raw).ok()?;
+
+        if self.nonce_tracking {
+            let mut nonces = self.nonces.write().unwrap_or_else(|e| e.into_inner());
+            nonces.retain(|_, (_, updated)| updated.elapsed() < NONCE_TTL);
+            let next = nonces
+                .entry(to_hex(&decoded.from))
+                .or_insert((0, Instant::now()));
+            *next = (next.0.max(decoded.nonce + 1), Instant::now());
+        }
+
+        if self
+            .rebroadcast
+            .as_ref()
+            .is_some_and(|rebroadcast| rebroadcast.wants(identity))
+        {
+            let mut pending = self.pending.write().unwrap_or_else(|e| e.into_inner());
+            pending.entry(to_hex(&decoded.hash)).or_insert_with(|| {
+                PendingTx {
+                    raw: raw.to_string(),
+                    sent: Instant::now(),
+                    last_sent: Instant::now(),
+                }
+            });
+        }
+
+        Some(decoded)
+    }
+
+    // Transactions due for a rebroadcast as `(hash, raw)`. Forgets the ones
+    // past their TTL, and counts the returned ones as sent.
+    pub fn due_for_rebroadcast(&self) -> Vec<(String, String)> {
+        let rebroadcast = match &self.rebroadcast {
+            Some(rebroadcast) => rebroadcast,
+            None => return Vec::new(),
+        };
+
+        let mut pending = self.pending.write().unwrap_or_else(|e| e.into_inner());
+        pending.retain(|_, tx| tx.sent.elapsed() < rebroadcast.ttl);
+        pending
+            .iter_mut()
+            .filter(|(_, tx)| tx.last_sent.elapsed() >= rebroadcast.interval)
+            .map(|(hash, tx)| {
+                tx.last_sent = Instant::now();
+                (hash.clone(), tx.raw.clone())
+            })
+            .collect()
+    }
+
+    // Stop rebroadcasting `hash`, it made it into a block
+    pub fn confirm(&self, hash: &str) {
+        let mut pending = self.pending.write().unwrap_or_else(|e| e.into_inner());
+        pending.remove(hash);
+    }
+
+    pub fn pending_count(&self) -> usize {
+        let pending = self.pending.read().unwrap_or_else(|e| e.into_inner());
+        pending.len()
+    }
+
+    // Next nonce of `address`, at least `upstream`, and count it as taken
+    fn reserve_nonce(&self, address: &str, upstream: Option<u64>) -> Option<u64> {
+        let mut nonces = self.nonces.write().unwrap_or_else(|e| e.into_inner());
+        nonces.retain(|_, (_, updated)| updated.elapsed() < NONCE_TTL);
+        let tracked = nonces.get(address).map(|(nonce, _)| *nonce);
+
+        let nonce = upstream.max(tracked)?;
+        nonces.insert(address.to_string(), (nonce + 1, Instant::now()));
+        Some(nonce)
+    }
+
+    // Answer `blutgang_getNextNonce(address)`, asking only nodes in `group`
+    // if it's set
+    pub async fn next_nonce(
+        &self,
+        tx: &Value,
+        rpc_list: &Arc<RwLock<Vec<Rpc>>>,
+        group: Option<&str>,
+        ttl: u128,
+    ) -> Value {
+        let address = match parse_address(&tx["params"][0]) {
+            Some(address) => address,
+            None => {
+                return nonce_error(&tx["id"], -32602, "Expected an address as the first param")
+            }
+        };
+
+        // Ask every node that holds pending state, or everyone if none does
+        let nodes: Vec<Rpc> = {
+            let rpc_list = rpc_list.read().unwrap_or_else(|e| e.into_inner());
+            let members = rpc_list
+                .iter()
+                .filter(|rpc| group.map_or(true, |group| rpc.in_group(group)))
+                .collect::<Vec<&Rpc>>();
+            let pending_state = members.iter().any(|rpc| rpc.pending_state);
+            members
+                .into_iter()
+                .filter(|rpc| rpc.pending_state || !pending_state)
+                .cloned()
+                .collect()
+        };
+
+        let request = json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "method": "eth_getTransactionCount",
+            "params": [address, "pending"],
+        });
+        let request_timeout = Duration::from_millis(ttl.try_into().unwrap_or(u64::MAX));
+        let responses = join_all(
+            nodes
+                .iter()
+                .map(|rpc| timeout(request_timeout, rpc.send_request(request.clone()))),
+        )
+        .await;
+
+        let upstream = responses
+            .into_iter()
+            .filter_map(|response| {
+                let response: Value = serde_json::from_str(&response.ok()?.ok()?).ok()?;
+                hex_to_decimal(response["result"].as_str()?).ok()
+            })
+            .max();
+
+        match self.reserve_nonce(&address, upstream) {
+            Some(nonce) => {
+                json!({"jsonrpc": "2.0", "id": tx["id"], "result": format!("0x{:x}", nonce)})
+            }
+            None => nonce_error(&tx["id"], -32603, "No RPC available"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::node::MockNode;
+
+    // The example from EIP-155, nonce 9
+    const RAW: &str = "0xf86c098504a817c800825208943535353535353535353535353535353535353535880de0b6b3a76400008025a028ef61340bd939bc2195fe537567866003e1a15d3c71ff63e1590620aa636276a067cbe9d8997f761aecb703304b3800ccf555c9f3dc64214b297fb1966a3b6d83";
+    const SENDER: &str = "0x9d8a62f656a8d1615c1294fd71e9cfb3e4855a4f";
+
+    fn next_nonce_tx(address: &str) -> Value {
+        json!({"jsonrpc": "2.0", "id": 3, "method": NEXT_NONCE, "params": [address]})
+    }
+
+    #[tokio::test]
+    async fn test_next_nonce() {
+        let behind = MockNode::spawn(1).await.unwrap();
+        behind.set_response("eth_getTransactionCount", json!("0x5"));
+        let ahead = MockNode::spawn(1).await.unwrap();
+        ahead.set_response("eth_getTransactionCount", json!("0x7"));
+        let rpc_list = Arc::new(RwLock::new(vec![
+            Rpc::new(behind.http_url(), None, 6, 0, 10.0),
+            Rpc::new(ahead.http_url(), None, 6, 0, 10.0),
+        ]));
+
+        // Highest count any node reports
+        let tracker = TxTracker::new(true, None);
+        let response = tracker
+            .next_nonce(&next_nonce_tx(SENDER), &rpc_list, None, 1000)
+            .await;
+        assert_eq!(response["result"], "0x7");
+        assert_eq!(response["id"], 3);
+
+        // Handed out nonces are taken
+        let response = tracker
+            .next_nonce(&next_nonce_tx(SENDER), &rpc_list, None, 1000)
+            .await;
+        assert_eq!(response["result"], "0x8");
+
+        // Unless we broadcast something later ourselves
+        assert_eq!(tracker.track(RAW, None).unwrap().nonce, 9);
+        let response = tracker
+            .next_nonce(
+                &next_nonce_tx(&SENDER.to_uppercase().replace("0X", "0x")),
+                &rpc_list,
+                None,
+                1000,
+            )
+            .await;
+        assert_eq!(response["result"], "0xa");
+
+        let response = tracker
+            .next_nonce(&next_nonce_tx("0x1234"), &rpc_list, None, 1000)
+            .await;
+        assert_eq!(response["error"]["code"], -32602);
+    }
+
+    #[tokio::test]
+    async fn test_next_nonce_pending_state() {
+        let general = MockNode::spawn(1).await.unwrap();
+        general.set_response("eth_getTransactionCount", json!("0x9"));
+        let mempool = MockNode::spawn(1).await.unwrap();
+        mempool.set_response("eth_getTransactionCount", json!("0x4"));
+        let rpc_list = Arc::new(RwLock::new(vec![
+            Rpc::new(general.http_url(), None, 6, 0, 10.0),
+            Rpc::new(mempool.http_url(), None, 6, 0, 10.0).with_pending_state(true),
+        ]));
+
+        let response = TxTracker::new(true, None)
+            .next_nonce(&next_nonce_tx(SENDER), &rpc_list, None, 1000)
+            .await;
+        assert_eq!(response["result"], "0x4");
+        assert_eq!(general.request_count(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_next_nonce_group() {
+        let shared = MockNode::spawn(1).await.unwrap();
+        shared.set_response("eth_getTransactionCount", json!("0x9"));
+        let tenant = MockNode::spawn(1).await.unwrap();
+        tenant.set_response("eth_getTransactionCount", json!("0x4"));
+        let rpc_list = Arc::new(RwLock::new(vec![
+            Rpc::new(shared.http_url(), None, 6, 0, 10.0).with_pending_state(true),
+            Rpc::new(tenant.http_url(), None, 6, 0, 10.0).with_groups(vec!["acme".to_string()]),
+        ]));
+
+        // Pending state nodes outside the group don't count either
+        let response = TxTracker::new(true, None)
+            .next_nonce(&next_nonce_tx(SENDER), &rpc_list, Some("acme"), 1000)
+            .await;
+        assert_eq!(response["result"], "0x4");
+        assert_eq!(shared.request_count(), 0);
+    }
+}
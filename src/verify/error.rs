@@ -0,0 +1,46 @@
+// Errors
+use crate::rpc::error::RpcError;
+
+use std::error::Error;
+
+#[derive(Debug)]
+pub enum VerifyError {
+    // We couldn't get a proof to check against, not the node's fault
+    ProofUnavailable(String),
+    // The proof doesn't match the state root
+    InvalidProof(String),
+    // The response doesn't match what the proof says
+    Mismatch(String),
+}
+
+impl std::fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            VerifyError::ProofUnavailable(reason) => write!(f, "Proof unavailable: {}", reason),
+            VerifyError::InvalidProof(reason) => write!(f, "Invalid proof: {}", reason),
+            VerifyError::Mismatch(reason) => {
+                write!(f, "Response does not match proof: {}", reason)
+            }
+        }
+    }
+}
+
+impl From<rlp::DecoderError> for VerifyError {
+    fn from(e: rlp::DecoderError) -> Self {
+        VerifyError::InvalidProof(format!("bad RLP: {}", e))
+    }
+}
+
+impl From<RpcError> for VerifyError {
+    fn from(e: RpcError) -> Self {
+        VerifyError::ProofUnavailable(e.to_string())
+    }
+}
+
+impl From<serde_json::Error> for VerifyError {
+    fn from(e: serde_json::Error) -> Self {
+        VerifyError::ProofUnavailable(format!("bad proof response: {}", e))
+    }
+}
+
+impl Error for VerifyError {}
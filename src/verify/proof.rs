@@ -0,0 +1,357 @@
+// Merkle Patricia Trie proof verification, as returned by `eth_getProof`.
+use crate::verify::error::VerifyError;
+
+use rlp::Rlp;
+use tiny_keccak::{
+    Hasher,
+    Keccak,
+};
+
+// Root of an empty trie, keccak256(rlp(""))
+pub const EMPTY_ROOT: [u8; 32] = [
+    0x56, 0xe8, 0x1f, 0x17, 0x1b, 0xcc, 0x55, 0xa6, 0xff, 0x83, 0x45, 0xe6, 0x92, 0xc0, 0xf8, 0x6e,
+    0x5b, 0x48, 0xe0, 0x1b, 0x99, 0x6c, 0xad, 0xc0, 0x01, 0x62, 0x2f, 0xb5, 0xe3, 0x63, 0xb4, 0x21,
+];
+
+pub fn keccak256(data: &[u8]) -> [u8; 32] {
+    let mut hasher = Keccak::v256();
+    let mut output = [0u8; 32];
+    hasher.update(data);
+    hasher.finalize(&mut output);
+    output
+}
+
+// Account as stored in the state trie
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Account {
+    pub nonce: Vec<u8>,
+    pub balance: Vec<u8>,
+    pub storage_root: [u8; 32],
+    pub code_hash: [u8; 32],
+}
+
+impl Account {
+    fn decode(bytes: &[u8]) -> Result<Self, VerifyError> {
+        let rlp = Rlp::new(bytes);
+        if rlp.item_count()? != 4 {
+            return Err(VerifyError::InvalidProof(
+                "account must have 4 fields".to_string(),
+            ));
+        }
+
+        Ok(Account {
+            nonce: rlp.at(0)?.data()?.to_vec(),
+            balance: rlp.at(1)?.data()?.to_vec(),
+            storage_root: to_hash(rlp.at(2)?.data()?)?,
+            code_hash: to_hash(rlp.at(3)?.data()?)?,
+        })
+    }
+}
+
+// Next node we expect while walking the proof
+enum NodeRef {
+    Hash([u8; 32]),
+    Inline(Vec<u8>),
+}
+
+fn to_hash(bytes: &[u8]) -> Result<[u8; 32], VerifyError> {
+    bytes
+        .try_into()
+        .map_err(|_| VerifyError::InvalidProof("expected a 32 byte hash".to_string()))
+}
+
+fn to_nibbles(bytes: &[u8]) -> Vec<u8> {
+    bytes.iter().flat_map(|b| [b >> 4, b & 0x0f]).collect()
+}
+
+// Decode a hex-prefix encoded path. Returns the nibbles and if it's a leaf.
+fn decode_path(encoded: &[u8]) -> Result<(Vec<u8>, bool), VerifyError> {
+    let nibbles = to_nibbles(encoded);
+    let flag = *nibbles
+        .first()
+        .ok_or(VerifyError::InvalidProof("empty path".to_string()))?;
+
+    let is_leaf = flag & 2 != 0;
+    let is_odd = flag & 1 != 0;
+    let skip = if is_odd { 1 } else { 2 };
+
+    Ok((nibbles[skip..].to_vec(), is_leaf))
+}
+
+// Figure out what a child reference in a node points to. Children smaller
+// than 32 bytes are embedded directly instead of being referenced by hash.
+fn child_ref(child: Rlp) -> Result<Option<NodeRef>, VerifyError> {
+    if child.is_list() {
+        return Ok(Some(NodeRef::Inline(child.as_raw().to_vec())));
+    }
+
+    let data = child.data()?;
+    if data.is_empty() {
+        return Ok(None);
+    }
+
+    Ok(Some(NodeRef::Hash(to_hash(data)?)))
+}
+
+// Walk `proof` from `root` following `key`.
+//
+// Returns the value stored at `key`, or `None` if the proof shows that
+// `key` is not in the trie.
+pub fn verify_proof(
+    root: [u8; 32],
+    key: &[u8],
+    proof: &[Vec<u8>],
+) -> Result<Option<Vec<u8>>, VerifyError> {
+    if proof.is_empty() {
+        if root == EMPTY_ROOT {
+            return Ok(None);
+        }
+        return Err(VerifyError::InvalidProof("empty proof".to_string()));
+    }
+
+    let path = to_nibbles(&keccak256(key));
+    let mut position = 0;
+    let mut proof = proof.iter();
+    let mut next = NodeRef::Hash(root);
+
+    loop {
+        let node = match next {
+            NodeRef::Hash(hash) => {
+                let node = proof
+                    .next()
+                    .ok_or(VerifyError::InvalidProof("proof ended early".to_string()))?;
+                if keccak256(node) != hash {
+                    return Err(VerifyError::InvalidProof(
+                        "node hash does not match".to_string(),
+                    ));
+                }
+                node.clone()
+            }
+            NodeRef::Inline(node) => node,
+        };
+
+        let rlp = Rlp::new(&node);
+        match rlp.item_count()? {
+            // Branch node
+            17 => {
+                if position == path.len() {
+                    let value = rlp.at(16)?.data()?;
+                    return Ok((!value.is_empty()).then(|| value.to_vec()));
+                }
+
+                next = match child_ref(rlp.at(path[position] as usize)?)? {
+                    Some(child) => child,
+                    None => return Ok(None),
+                };
+                position += 1;
+            }
+            // Leaf or extension node
+            2 => {
+                let (node_path, is_leaf) = decode_path(rlp.at(0)?.data()?)?;
+                let remaining = &path[position..];
+
+                if is_leaf {
+                    if remaining == node_path.as_slice() {
+                        return Ok(Some(rlp.at(1)?.data()?.to_vec()));
+                    }
+                    return Ok(None);
+                }
+
+                if !remaining.starts_with(&node_path) {
+                    return Ok(None);
+                }
+
+                next = match child_ref(rlp.at(1)?)? {
+                    Some(child) => child,
+                    None => return Err(VerifyError::InvalidProof("empty extension".to_string())),
+                };
+                position += node_path.len();
+            }
+            _ => return Err(VerifyError::InvalidProof("unknown node type".to_string())),
+        }
+    }
+}
+
+// Verify an account proof against a state root.
+//
+// Returns `None` if the account does not exist.
+pub fn verify_account(
+    state_root: [u8; 32],
+    address: &[u8],
+    proof: &[Vec<u8>],
+) -> Result<Option<Account>, VerifyError> {
+    match verify_proof(state_root, address, proof)? {
+        Some(account) => Ok(Some(Account::decode(&account)?)),
+        None => Ok(None),
+    }
+}
+
+// Verify a storage proof against an account's storage root.
+//
+// Returns the value of the slot with leading zeros stripped.
+pub fn verify_storage(
+    storage_root: [u8; 32],
+    slot: &[u8; 32],
+    proof: &[Vec<u8>],
+) -> Result<Vec<u8>, VerifyError> {
+    match verify_proof(storage_root, slot, proof)? {
+        Some(value) => Ok(Rlp::new(&value).data()?.to_vec()),
+        None => Ok(Vec::new()),
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use rlp::RlpStream;
+
+    // Hex-prefix encode a leaf path
+    fn encode_leaf_path(nibbles: &[u8]) -> Vec<u8> {
+        let mut encoded = Vec::new();
+        let mut nibbles = nibbles.to_vec();
+        if nibbles.len() % 2 == 1 {
+            nibbles.insert(0, 3);
+        } else {
+            nibbles.insert(0, 0);
+            nibbles.insert(0, 2);
+        }
+        for pair in nibbles.chunks(2) {
+            encoded.push(pair[0] << 4 | pair[1]);
+        }
+        encoded
+    }
+
+    pub fn encode_account(account: &Account) -> Vec<u8> {
+        let mut stream = RlpStream::new_list(4);
+        stream.append(&account.nonce);
+        stream.append(&account.balance);
+        stream.append(&account.storage_root.to_vec());
+        stream.append(&account.code_hash.to_vec());
+        stream.out().to_vec()
+    }
+
+    fn leaf(path: &[u8], value: &[u8]) -> Vec<u8> {
+        let mut stream = RlpStream::new_list(2);
+        stream.append(&encode_leaf_path(path));
+        stream.append(&value.to_vec());
+        stream.out().to_vec()
+    }
+
+    // Build a trie with the given keys and values, where every key starts
+    // with a different nibble. Returns the root and a proof for each key.
+    pub fn build_trie(entries: &[(&[u8], Vec<u8>)]) -> ([u8; 32], Vec<Vec<Vec<u8>>>) {
+        if entries.len() == 1 {
+            let node = leaf(&to_nibbles(&keccak256(entries[0].0)), &entries[0].1);
+            return (keccak256(&node), vec![vec![node]]);
+        }
+
+        let mut children: Vec<Option<Vec<u8>>> = vec![None; 16];
+        for (key, value) in entries {
+            let path = to_nibbles(&keccak256(key));
+            children[path[0] as usize] = Some(leaf(&path[1..], value));
+        }
+
+        let mut stream = RlpStream::new_list(17);
+        for child in &children {
+            match child {
+                Some(child) => stream.append(&keccak256(child).to_vec()),
+                None => stream.append_empty_data(),
+            };
+        }
+        stream.append_empty_data();
+        let branch = stream.out().to_vec();
+        let root = keccak256(&branch);
+
+        let proofs = entries
+            .iter()
+            .map(|(key, _)| {
+                let nibble = to_nibbles(&keccak256(key))[0] as usize;
+                vec![branch.clone(), children[nibble].clone().unwrap()]
+            })
+            .collect();
+
+        (root, proofs)
+    }
+
+    fn test_account() -> Account {
+        Account {
+            nonce: vec![5],
+            balance: vec![0x0d, 0xe0, 0xb6, 0xb3],
+            storage_root: EMPTY_ROOT,
+            code_hash: keccak256(&[]),
+        }
+    }
+
+    #[test]
+    fn test_single_leaf_proof() {
+        let address = [0x11u8; 20];
+        let account = test_account();
+        let (root, proofs) = build_trie(&[(&address, encode_account(&account))]);
+
+        assert_eq!(
+            verify_account(root, &address, &proofs[0]).unwrap(),
+            Some(account)
+        );
+    }
+
+    #[test]
+    fn test_branch_proof() {
+        // Pick two addresses whose hashed keys start with different nibbles
+        let a = [0x11u8; 20];
+        let b = (0u8..=255)
+            .map(|i| [i; 20])
+            .find(|b| keccak256(b)[0] >> 4 != keccak256(&a)[0] >> 4)
+            .unwrap();
+
+        let mut other = test_account();
+        other.nonce = vec![9];
+        let (root, proofs) = build_trie(&[
+            (&a, encode_account(&test_account())),
+            (&b, encode_account(&other)),
+        ]);
+
+        assert_eq!(
+            verify_account(root, &a, &proofs[0]).unwrap(),
+            Some(test_account())
+        );
+        assert_eq!(verify_account(root, &b, &proofs[1]).unwrap(), Some(other));
+
+        // A's proof shows that an address in B's branch slot with a different path is absent
+        let c = (0u8..=255)
+            .map(|i| [i; 20])
+            .find(|c| c != &b && keccak256(c)[0] >> 4 == keccak256(&b)[0] >> 4)
+            .unwrap();
+        assert_eq!(verify_account(root, &c, &proofs[1]).unwrap(), None);
+    }
+
+    #[test]
+    fn test_tampered_proof() {
+        let address = [0x11u8; 20];
+        let (root, mut proofs) = build_trie(&[(&address, encode_account(&test_account()))]);
+
+        let mut tampered = test_account();
+        tampered.balance = vec![0xff];
+        proofs[0][0] = leaf(
+            &to_nibbles(&keccak256(&address)),
+            &encode_account(&tampered),
+        );
+
+        assert!(verify_account(root, &address, &proofs[0]).is_err());
+    }
+
+    #[test]
+    fn test_empty_trie() {
+        assert_eq!(verify_account(EMPTY_ROOT, &[0x11; 20], &[]).unwrap(), None);
+        assert!(verify_account([1; 32], &[0x11; 20], &[]).is_err());
+    }
+
+    #[test]
+    fn test_storage_proof() {
+        let slot = [0u8; 32];
+        let mut value = RlpStream::new();
+        value.append(&vec![0x2au8]);
+        let (root, proofs) = build_trie(&[(&slot, value.out().to_vec())]);
+
+        assert_eq!(verify_storage(root, &slot, &proofs[0]).unwrap(), vec![0x2a]);
+    }
+}
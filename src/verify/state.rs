@@ -0,0 +1,449 @@
+// Cross-check state queries against `eth_getProof` from another node.
+//
+// We fetch an account proof for the requested block from a second node,
+// verify it against the state root of that block as we saw it on our own
+// `newHeads` subscription, and make sure the response we got agrees with it.
+// Blocks older than the heads we still track can't be verified.
+use crate::{
+    rpc::types::{
+        hex_to_decimal,
+        Rpc,
+    },
+    verify::{
+        error::VerifyError,
+        proof::{
+            keccak256,
+            verify_account,
+            verify_storage,
+            Account,
+            EMPTY_ROOT,
+        },
+    },
+};
+
+use std::{
+    collections::BTreeMap,
+    sync::RwLock,
+};
+
+use serde_json::{
+    json,
+    Value,
+};
+
+// Number of recent heads we keep the state root of
+const MAX_HEADERS: usize = 256;
+
+// Methods whose responses can be checked against an account proof
+pub fn is_verifiable(method: &str) -> bool {
+    matches!(
+        method,
+        "eth_getBalance" | "eth_getTransactionCount" | "eth_getCode" | "eth_getStorageAt"
+    )
+}
+
+// Decode a hex string, allowing odd lengths like `0x0`
+fn decode_hex(value: &str) -> Option<Vec<u8>> {
+    let value = value.strip_prefix("0x").unwrap_or(value);
+    if value.len() % 2 == 1 {
+        return hex::decode(format!("0{}", value)).ok();
+    }
+    hex::decode(value).ok()
+}
+
+fn strip_zeros(bytes: &[u8]) -> &[u8] {
+    let start = bytes.iter().position(|b| *b != 0).unwrap_or(bytes.len());
+    &bytes[start..]
+}
+
+fn to_slot(value: &str) -> Option<[u8; 32]> {
+    let bytes = decode_hex(value)?;
+    if bytes.len() > 32 {
+        return None;
+    }
+
+    let mut slot = [0u8; 32];
+    slot[32 - bytes.len()..].copy_from_slice(&bytes);
+    Some(slot)
+}
+
+fn to_root(value: &Value) -> Option<[u8; 32]> {
+    decode_hex(value.as_str()?)?.try_into().ok()
+}
+
+fn to_proof(value: &Value) -> Option<Vec<Vec<u8>>> {
+    value
+        .as_array()?
+        .iter()
+        .map(|node| decode_hex(node.as_str()?))
+        .collect()
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct TrackedHeader {
+    hash: String,
+    state_root: [u8; 32],
+}
+
+// State roots of the heads our head tracker saw, by block number
+#[derive(Debug, Default)]
+pub struct TrackedHeaders {
+    headers: RwLock<BTreeMap<u64, TrackedHeader>>,
+}
+
+impl TrackedHeaders {
+    // Track the header of a `newHeads` event. A reorged block replaces the
+    // one we had at its height.
+    pub fn observe(&self, header: &Value) {
+        let number = match header["number"]
+            .as_str()
+            .and_then(|number| hex_to_decimal(number).ok())
+        {
+            Some(number) => number,
+            None => return,
+        };
+        let (hash, state_root) = match (header["hash"].as_str(), to_root(&header["stateRoot"])) {
+            (Some(hash), Some(state_root)) => (hash.to_lowercase(), state_root),
+            _ => return,
+        };
+
+        let mut headers = self.headers.write().unwrap_or_else(|e| e.into_inner());
+        headers.insert(number, TrackedHeader { hash, state_root });
+        while headers.len() > MAX_HEADERS {
+            headers.pop_first();
+        }
+    }
+
+    fn by_number(&self, number: u64) -> Option<[u8; 32]> {
+        let headers = self.headers.read().unwrap_or_else(|e| e.into_inner());
+        headers.get(&number).map(|header| header.state_root)
+    }
+
+    fn by_hash(&self, hash: &str) -> Option<[u8; 32]> {
+        let hash = hash.to_lowercase();
+        let headers = self.headers.read().unwrap_or_else(|e| e.into_inner());
+        headers
+            .values()
+            .find(|header| header.hash == hash)
+            .map(|header| header.state_root)
+    }
+}
+
+fn block_position(tx: &Value) -> Option<usize> {
+    match tx["method"].as_str()? {
+        "eth_getStorageAt" => Some(2),
+        _ => Some(1),
+    }
+}
+
+// Hex block number the request is pinned to, if any. Tags and block hashes
+// are skipped, call `replace_block_tags` first to pin the former.
+fn block_param(tx: &Value) -> Option<&str> {
+    let block = tx["params"][block_position(tx)?].as_str()?;
+    // Block hashes are also hex, but 32 bytes long
+    if !block.starts_with("0x") || block.len() > 18 {
+        return None;
+    }
+
+    Some(block)
+}
+
+// Block hash of an EIP-1898 `{"blockHash": ..}` block parameter
+fn block_hash_param(tx: &Value) -> Option<&str> {
+    tx["params"][block_position(tx)?]["blockHash"].as_str()
+}
+
+fn compare_quantity(name: &str, expected: &[u8], got: &Value) -> Result<(), VerifyError> {
+    let got = got
+        .as_str()
+        .and_then(decode_hex)
+        .ok_or(VerifyError::Mismatch(format!(
+            "{} is not a hex value",
+            name
+        )))?;
+
+    if strip_zeros(&got) != strip_zeros(expected) {
+        return Err(VerifyError::Mismatch(format!(
+            "{} is 0x{}, proof says 0x{}",
+            name,
+            hex::encode(strip_zeros(&got)),
+            hex::encode(strip_zeros(expected))
+        )));
+    }
+
+    Ok(())
+}
+
+// Check `result` of `tx` against a verified `eth_getProof` response
+fn check_result(
+    tx: &Value,
+    result: &Value,
+    state_root: [u8; 32],
+    proof: &Value,
+) -> Result<(), VerifyError> {
+    let address = tx["params"][0]
+        .as_str()
+        .and_then(decode_hex)
+        .ok_or(VerifyError::Mismatch("invalid address".to_string()))?;
+    let account_proof = to_proof(&proof["accountProof"]).ok_or(VerifyError::ProofUnavailable(
+        "no account proof".to_string(),
+    ))?;
+
+    // Accounts that don't exist have no code and empty storage
+    let account = verify_account(state_root, &address, &account_proof)?.unwrap_or(Account {
+        storage_root: EMPTY_ROOT,
+        code_hash: keccak256(&[]),
+        ..Default::default()
+    });
+
+    match tx["method"].as_str() {
+        Some("eth_getBalance") => compare_quantity("balance", &account.balance, result),
+        Some("eth_getTransactionCount") => compare_quantity("nonce", &account.nonce, result),
+        Some("eth_getCode") => {
+            let code = result
+                .as_str()
+                .and_then(decode_hex)
+                .ok_or(VerifyError::Mismatch("code is not a hex value".to_string()))?;
+            if keccak256(&code) != account.code_hash {
+                return Err(VerifyError::Mismatch(
+                    "code does not match code hash".to_string(),
+                ));
+            }
+            Ok(())
+        }
+        Some("eth_getStorageAt") => {
+            let slot = tx["params"][1]
+                .as_str()
+                .and_then(to_slot)
+                .ok_or(VerifyError::Mismatch("invalid storage slot".to_string()))?;
+            let storage_proof = to_proof(&proof["storageProof"][0]["proof"]).ok_or(
+                VerifyError::ProofUnavailable("no storage proof".to_string()),
+            )?;
+
+            let value = verify_storage(account.storage_root, &slot, &storage_proof)?;
+            compare_quantity("storage value", &value, result)
+        }
+        _ => Ok(()),
+    }
+}
+
+// Verify `response` to `tx` with a proof from `proof_rpc`, against the state
+// root `headers` has for the block.
+//
+// Returns `Ok(false)` if the request can't be verified, like when it isn't
+// pinned to a block number or the response is an error.
+pub async fn verify_state_response(
+    tx: &Value,
+    response: &str,
+    proof_rpc: &Rpc,
+    headers: &TrackedHeaders,
+) -> Result<bool, VerifyError> {
+    // Requests pinned to a block hash get the proof of that block
+    let (state_root, proof_block) = match (block_param(tx), block_hash_param(tx)) {
+        (Some(number), _) => {
+            let state_root = hex_to_decimal(number)
+                .ok()
+                .and_then(|number| headers.by_number(number));
+            (state_root, json!(number))
+        }
+        (None, Some(hash)) => (headers.by_hash(hash), json!({"blockHash": hash})),
+        (None, None) => return Ok(false),
+    };
+    let state_root = state_root.ok_or(VerifyError::ProofUnavailable(
+        "not one of the heads we track".to_string(),
+    ))?;
+
+    let response: Value = match serde_json::from_str(response) {
+        Ok(response) => response,
+        Err(_) => return Ok(false),
+    };
+    let result = match response.get("result") {
+        Some(result) => result,
+        None => return Ok(false),
+    };
+
+    let slots = match tx["method"].as_str() {
+        Some("eth_getStorageAt") => json!([tx["params"][1]]),
+        _ => json!([]),
+    };
+
+    let proof_request = json!({
+        "jsonrpc": "2.0",
+        "id": 1,
+        "method": "eth_getProof",
+        "params": [tx["params"][0], slots, proof_block],
+    });
+
+    let proof: Value = serde_json::from_str(&proof_rpc.send_request(proof_request).await?)?;
+
+    check_result(tx, result, state_root, &proof["result"])?;
+
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::verify::proof::tests::{
+        build_trie,
+        encode_account,
+    };
+    use rlp::RlpStream;
+
+    const ADDRESS: &str = "0x1111111111111111111111111111111111111111";
+
+    fn encode_proof(proof: &[Vec<u8>]) -> Value {
+        json!(proof
+            .iter()
+            .map(|node| format!("0x{}", hex::encode(node)))
+            .collect::<Vec<_>>())
+    }
+
+    // State with a single account holding `code` and storage slot 0 set to 42
+    fn state(code: &[u8]) -> ([u8; 32], Value) {
+        let slot = [0u8; 32];
+        let mut value = RlpStream::new();
+        value.append(&vec![0x2au8]);
+        let (storage_root, storage_proofs) = build_trie(&[(&slot, value.out().to_vec())]);
+
+        let account = Account {
+            nonce: vec![5],
+            balance: vec![0x0d, 0xe0],
+            storage_root,
+            code_hash: keccak256(code),
+        };
+        let address = decode_hex(ADDRESS).unwrap();
+        let (state_root, proofs) = build_trie(&[(&address, encode_account(&account))]);
+
+        let proof = json!({
+            "accountProof": encode_proof(&proofs[0]),
+            "storageProof": [{"key": "0x0", "proof": encode_proof(&storage_proofs[0])}],
+        });
+
+        (state_root, proof)
+    }
+
+    fn request(method: &str, params: Value) -> Value {
+        json!({"jsonrpc": "2.0", "id": 1, "method": method, "params": params})
+    }
+
+    #[test]
+    fn test_block_param() {
+        assert_eq!(
+            block_param(&request("eth_getBalance", json!([ADDRESS, "0x10"]))),
+            Some("0x10")
+        );
+        assert_eq!(
+            block_param(&request(
+                "eth_getStorageAt",
+                json!([ADDRESS, "0x0", "0x10"])
+            )),
+            Some("0x10")
+        );
+        assert_eq!(
+            block_param(&request("eth_getBalance", json!([ADDRESS, "pending"]))),
+            None
+        );
+        assert_eq!(
+            block_param(&request(
+                "eth_getBalance",
+                json!([ADDRESS, format!("0x{}", "ab".repeat(32))])
+            )),
+            None
+        );
+
+        let pinned = request(
+            "eth_getStorageAt",
+            json!([ADDRESS, "0x0", {"blockHash": "0xab"}]),
+        );
+        assert_eq!(block_param(&pinned), None);
+        assert_eq!(block_hash_param(&pinned), Some("0xab"));
+    }
+
+    #[test]
+    fn test_check_matching_result() {
+        let code = [0x60, 0x80];
+        let (root, proof) = state(&code);
+
+        let balance = request("eth_getBalance", json!([ADDRESS, "0x10"]));
+        assert!(check_result(&balance, &json!("0xde0"), root, &proof).is_ok());
+
+        let nonce = request("eth_getTransactionCount", json!([ADDRESS, "0x10"]));
+        assert!(check_result(&nonce, &json!("0x5"), root, &proof).is_ok());
+
+        let get_code = request("eth_getCode", json!([ADDRESS, "0x10"]));
+        assert!(check_result(&get_code, &json!("0x6080"), root, &proof).is_ok());
+
+        let storage = request("eth_getStorageAt", json!([ADDRESS, "0x0", "0x10"]));
+        let value = format!("0x{}2a", "00".repeat(31));
+        assert!(check_result(&storage, &json!(value), root, &proof).is_ok());
+    }
+
+    #[test]
+    fn test_check_mismatched_result() {
+        let (root, proof) = state(&[0x60, 0x80]);
+
+        let balance = request("eth_getBalance", json!([ADDRESS, "0x10"]));
+        assert!(matches!(
+            check_result(&balance, &json!("0x1"), root, &proof),
+            Err(VerifyError::Mismatch(_))
+        ));
+
+        let get_code = request("eth_getCode", json!([ADDRESS, "0x10"]));
+        assert!(matches!(
+            check_result(&get_code, &json!("0x"), root, &proof),
+            Err(VerifyError::Mismatch(_))
+        ));
+
+        let storage = request("eth_getStorageAt", json!([ADDRESS, "0x0", "0x10"]));
+        assert!(matches!(
+            check_result(&storage, &json!("0x0"), root, &proof),
+            Err(VerifyError::Mismatch(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_tracked_headers() {
+        let headers = TrackedHeaders::default();
+        let header = |number: u64, hash: &str, root: u8| {
+            json!({
+                "number": format!("0x{:x}", number),
+                "hash": hash,
+                "stateRoot": format!("0x{}", hex::encode([root; 32])),
+            })
+        };
+        headers.observe(&header(16, "0xAB", 1));
+        assert_eq!(headers.by_number(16), Some([1; 32]));
+        assert_eq!(headers.by_hash("0xab"), Some([1; 32]));
+
+        // A reorged block replaces the one at its height
+        headers.observe(&header(16, "0xcd", 2));
+        assert_eq!(headers.by_number(16), Some([2; 32]));
+        assert_eq!(headers.by_hash("0xab"), None);
+
+        // Only the most recent heads are kept
+        for number in 17..17 + MAX_HEADERS as u64 {
+            headers.observe(&header(number, "0xef", 3));
+        }
+        assert_eq!(headers.by_number(16), None);
+
+        // Blocks we didn't see can't be verified, whatever the proof node says
+        let balance = request("eth_getBalance", json!([ADDRESS, "0x10"]));
+        let proof_rpc = Rpc::new("http://127.0.0.1:1".to_string(), None, 1, 0, 1.0);
+        assert!(matches!(
+            verify_state_response(&balance, r#"{"result":"0x1"}"#, &proof_rpc, &headers).await,
+            Err(VerifyError::ProofUnavailable(_))
+        ));
+    }
+
+    #[test]
+    fn test_check_wrong_state_root() {
+        let (_, proof) = state(&[]);
+        let balance = request("eth_getBalance", json!([ADDRESS, "0x10"]));
+
+        assert!(matches!(
+            check_result(&balance, &json!("0xde0"), [7; 32], &proof),
+            Err(VerifyError::InvalidProof(_))
+        ));
+    }
+}
@@ -1,19 +1,41 @@
 use crate::{
     balancer::{
-        format::replace_block_tags,
+        cache_entry::CacheKey,
+        ens::ens_key,
+        format::{
+            is_pending_request,
+            replace_block_tags,
+        },
+        hot_cache::get_tiered,
         processing::{
             cache_querry,
             update_rpc_latency,
             CacheArgs,
         },
-        selection::select::pick,
+        routing_hints::RoutingHints,
+        selection::select::{
+            pick,
+            pick_pending,
+        },
     },
+    config::system::DebugModule,
+    log_dbg,
     log_err,
     log_info,
     log_wrn,
     rpc::types::Rpc,
     websocket::{
+        confirmed::{
+            self,
+            ConfirmedLogs,
+        },
         error::WsError,
+        filter::EventFilter,
+        reorgs::REORGS,
+        tx_status::{
+            is_tx_hash,
+            TX_STATUS,
+        },
         types::{
             IncomingResponse,
             SubscriptionData,
@@ -35,7 +57,10 @@ use futures_util::{
     SinkExt,
     StreamExt,
 };
-use serde_json::Value;
+use serde_json::{
+    json,
+    Value,
+};
 use simd_json::{
     from_slice,
     from_str,
@@ -46,16 +71,15 @@ use tokio::sync::{
     mpsc,
 };
 use tokio_tungstenite::{
-    connect_async,
-    tungstenite::protocol::Message,
+    connect_async_tls_with_config,
+    tungstenite::{
+        client::IntoClientRequest,
+        http::HeaderValue,
+        protocol::Message,
+    },
+    Connector,
 };
 
-#[cfg(not(feature = "xxhash"))]
-use blake3::hash;
-
-#[cfg(feature = "xxhash")]
-use xxhash_rust::xxh3::xxh3_64;
-
 pub async fn ws_conn_manager(
     rpc_list: Arc<RwLock<Vec<Rpc>>>,
     ws_handles: Arc<RwLock<Vec<Option<mpsc::UnboundedSender<Value>>>>>,
@@ -98,7 +122,13 @@ async fn handle_incoming_message(
     let rpc_position = if let Some(index) = specified_index {
         index
     } else {
-        match pick(&mut rpc_list.write().unwrap()).1 {
+        let mut rpc_list = rpc_list.write().unwrap();
+        let picked = if is_pending_request(&incoming) {
+            pick_pending(&mut rpc_list)
+        } else {
+            pick(&mut rpc_list)
+        };
+        match picked.1 {
             Some(position) => position,
             None => {
                 log_err!("No RPC position available");
@@ -154,7 +184,20 @@ pub async fn ws_conn(
     ws_error_tx: mpsc::UnboundedSender<WsChannelErr>,
     index: usize,
 ) {
-    let (ws_stream, _) = connect_async(&rpc.ws_url.unwrap())
+    // Introduce ourselves the same way we do over HTTP
+    let mut request = rpc
+        .ws_url
+        .unwrap()
+        .into_client_request()
+        .expect("Invalid WS url");
+    for (name, value) in rpc.identity.headers() {
+        if let Ok(value) = HeaderValue::from_str(value) {
+            request.headers_mut().insert(name, value);
+        }
+    }
+
+    let connector = rpc.ws_connector.map(Connector::NativeTls);
+    let (ws_stream, _) = connect_async_tls_with_config(request, None, false, connector)
         .await
         .expect("Failed to connect to WS");
     let (mut ws_sender, mut ws_receiver) = ws_stream.split();
@@ -163,8 +206,7 @@ pub async fn ws_conn(
     let sender_error_tx = ws_error_tx.clone();
     tokio::spawn(async move {
         while let Some(incoming) = incoming_rx.recv().await {
-            #[cfg(feature = "debug-verbose")]
-            println!("ws_conn[{}], send: {:?}", index, incoming);
+            log_dbg!(DebugModule::Ws, "ws_conn[{}], send: {:?}", index, incoming);
 
             if ws_sender
                 .send(Message::Text(incoming.to_string()))
@@ -183,8 +225,7 @@ pub async fn ws_conn(
             match message {
                 Ok(message) => {
                     let time = Instant::now();
-                    #[cfg(feature = "debug-verbose")]
-                    println!("ws_conn[{}], recv: {:?}", index, message);
+                    log_dbg!(DebugModule::Ws, "ws_conn[{}], recv: {:?}", index, message);
 
                     let mut ws_message = match message.into_text() {
                         Ok(rax) => rax,
@@ -230,30 +271,65 @@ pub async fn execute_ws_call(
     sub_data: &Arc<SubscriptionData>,
     cache_args: &CacheArgs,
 ) -> Result<String, WsError> {
-    #[cfg(feature = "debug-verbose")]
-    println!(
+    log_dbg!(
+        DebugModule::Ws,
         "Received incoming WS call from user_id {}: {:?}",
-        user_id, call
+        user_id,
+        call
     );
 
     let id = call["id"].take();
-    let tx_hash = {
-        #[cfg(not(feature = "xxhash"))]
-        {
-            hash(call.to_string().as_bytes())
-        }
-        #[cfg(feature = "xxhash")]
-        {
-            xxh3_64(call.to_string().as_bytes())
-        }
-    };
 
-    if let Ok(Some(mut rax)) = cache_args.cache.get(tx_hash.as_bytes()) {
+    // Blutgang's own subscriptions never go upstream
+    if call["method"] == "blutgang_subscribe" {
+        let subscription_id = match call["params"][0].as_str() {
+            Some(REORGS) => sub_data.subscribe_reorgs(user_id),
+            Some(TX_STATUS) => {
+                let Some(tx_hash) = call["params"][1].as_str().filter(|hash| is_tx_hash(hash))
+                else {
+                    return Ok(call_error(id, -32602, "Expected a transaction hash"));
+                };
+                match sub_data.subscribe_tx_status(user_id, tx_hash) {
+                    Some(subscription_id) => subscription_id,
+                    None => {
+                        return Ok(call_error(
+                            id,
+                            -32005,
+                            "Too many transaction status subscriptions",
+                        ));
+                    }
+                }
+            }
+            _ => return Ok(call_error(id, -32602, "Unknown subscription kind")),
+        };
+
+        return Ok(json!({"jsonrpc": "2.0", "id": id, "result": subscription_id}).to_string());
+    }
+    if call["method"] == "blutgang_unsubscribe" {
+        let unsubscribed = call["params"][0].as_str().is_some_and(|subscription_id| {
+            sub_data.unsubscribe_reorgs(user_id, subscription_id)
+                || sub_data.unsubscribe_tx_status(user_id, subscription_id)
+        });
+        return Ok(json!({"jsonrpc": "2.0", "id": id, "result": unsubscribed}).to_string());
+    }
+
+    // Routing hints are HTTP only, don't send them upstream
+    RoutingHints::strip(&mut call);
+    let tx_hash = CacheKey::new(&call);
+
+    if let Ok(Some(mut rax)) = get_tiered(&cache_args.cache, &cache_args.hot, &tx_hash) {
+        cache_args.memory.record_hit(tx_hash.as_bytes());
         let mut cached: Value = from_slice(&mut rax).unwrap();
         cached["id"] = id;
         return Ok(cached.to_string());
     }
 
+    // ENS lookups at the tip get their own cache, keyed before we pin `latest`
+    let ens_key = ens_key(&call, None).filter(|_| cache_args.ens.is_enabled());
+    if let Some(result) = ens_key.as_ref().and_then(|key| cache_args.ens.get(key)) {
+        return Ok(json!({"jsonrpc": "2.0", "id": id, "result": result}).to_string());
+    }
+
     // Remove and unsubscribe user is "eth_unsubscribe"
     if call["method"] == "eth_unsubscribe" {
         // subscription_id is ["params"][0]
@@ -266,6 +342,18 @@ pub async fn execute_ws_call(
                 ));
             }
         };
+        // Confirmed logs subscriptions only exist on our side
+        if let Some(upstream_id) = sub_data.remove_confirmed(user_id, &subscription_id) {
+            // Other confirmed subscriptions of the user can share the upstream one
+            if !sub_data.has_confirmed(user_id, &upstream_id) {
+                sub_data.unsubscribe_user(user_id, upstream_id);
+            }
+            return Ok(format!(
+                "{{\"jsonrpc\":\"2.0\",\"id\":{},\"result\":true}}",
+                id
+            ));
+        }
+
         // we have to get the id of the subsctiption and what node is subscribed and send the message
         let index = match sub_data.get_node_from_id(&subscription_id) {
             Some(rax) => Some(rax),
@@ -287,12 +375,33 @@ pub async fn execute_ws_call(
     }
 
     let is_subscription = call["method"] == "eth_subscribe";
+    let mut filter = None;
+    let mut confirmation = None;
     if is_subscription {
+        // Confirmed logs are regular `logs` upstream
+        confirmation = match confirmed::from_subscription(&mut call) {
+            Ok(confirmation) => confirmation,
+            Err(e) => return Ok(call_error(id, -32602, &e.to_string())),
+        };
+
+        // Take out our own filter so the upstream subscription can be shared
+        filter = match EventFilter::from_subscription(&mut call) {
+            Ok(filter) => filter,
+            Err(e) => return Ok(call_error(id, -32602, &e.to_string())),
+        };
+
         // Check if we're already subscribed to this
         // if so return the subscription id and add this user to the dispatch
         // if not continue
         if let Ok(rax) = sub_data.subscribe_user(user_id, call.clone()) {
             println!("has subscription already");
+            sub_data.set_filter(user_id, &rax, filter);
+            let rax = match confirmation {
+                Some(confirmation) => {
+                    sub_data.add_confirmed(user_id, &rax, ConfirmedLogs::new(confirmation))
+                }
+                None => rax,
+            };
             return Ok(format!(
                 "{{\"jsonrpc\":\"2.0\",\"id\":{},\"result\":\"{}\"}}",
                 id, rax
@@ -308,10 +417,11 @@ pub async fn execute_ws_call(
     let mut response = listen_for_response(user_id, broadcast_rx).await?;
 
     if is_subscription {
-        #[cfg(feature = "debug-verbose")]
-        println!("is subscription!");
-        #[cfg(feature = "debug-verbose")]
-        println!("response content: {:?}", response.content);
+        log_dbg!(
+            DebugModule::Ws,
+            "Subscription response: {:?}",
+            response.content
+        );
         // add the subscription id and add this user to the dispatch
         let sub_id = match response.content["result"].as_str() {
             Some(sub_id) => sub_id.to_string(),
@@ -326,14 +436,31 @@ pub async fn execute_ws_call(
         println!("\x1b[35mInfo:\x1b[0m sub_id: {}", sub_id);
         sub_data.register_subscription(call.clone(), sub_id.clone(), response.node_id);
         sub_data.subscribe_user(user_id, call)?;
+        sub_data.set_filter(user_id, &sub_id, filter);
+
+        // Give the user our own id if we're holding back events for them
+        if let Some(confirmation) = confirmation {
+            let client_id =
+                sub_data.add_confirmed(user_id, &sub_id, ConfirmedLogs::new(confirmation));
+            response.content["result"] = client_id.into();
+        }
     } else {
-        cache_querry(&mut response.content.to_string(), call, tx_hash, cache_args);
+        let mut rax = response.content.to_string();
+        if let Some(key) = ens_key {
+            cache_args.ens.insert(key, &rax);
+        }
+        cache_querry(&mut rax, call, tx_hash, None, cache_args);
     }
 
     response.content["id"] = id;
     Ok(response.content.to_string())
 }
 
+// JSON-RPC error response for a call we answer ourselves
+fn call_error(id: Value, code: i64, message: &str) -> String {
+    json!({"jsonrpc": "2.0", "id": id, "error": {"code": code, "message": message}}).to_string()
+}
+
 async fn listen_for_response(
     user_id: u32,
     mut broadcast_rx: broadcast::Receiver<IncomingResponse>,
@@ -349,7 +476,7 @@ async fn listen_for_response(
 #[cfg(test)]
 mod tests {
     use super::*;
-    use serde_json::json;
+    use crate::websocket::tx_status::MAX_WATCHED_PER_USER;
     use std::time::Duration;
 
     // Helper function to create a mock Rpc object
@@ -364,7 +491,7 @@ mod tests {
     }
 
     async fn create_mock_rpc_list() -> Arc<RwLock<Vec<Rpc>>> {
-        let rpc_list = Arc::new(RwLock::new(vec![
+        Arc::new(RwLock::new(vec![
             Rpc::new(
                 "http://test1".to_string(),
                 Some("ws://test1".to_string()),
@@ -379,11 +506,11 @@ mod tests {
                 0,
                 0.0,
             ),
-        ]));
-        rpc_list
+        ]))
     }
 
     // Helper function to setup the environment for ws_conn_manager tests
+    #[allow(clippy::type_complexity)]
     fn setup_ws_conn_manager_test() -> (
         Arc<RwLock<Vec<Rpc>>>,
         mpsc::UnboundedSender<WsconnMessage>,
@@ -522,6 +649,108 @@ mod tests {
         );
     }
 
+    #[tokio::test]
+    async fn test_execute_ws_bad_filter() {
+        let (incoming_tx, _incoming_rx) = mpsc::unbounded_channel();
+        let (_broadcast_tx, broadcast_rx) = broadcast::channel(10);
+        let sub_data = Arc::new(SubscriptionData::new());
+
+        let call = json!({
+            "jsonrpc": "2.0",
+            "id": 3,
+            "method": "eth_subscribe",
+            "params": ["logs", {"blutgang": {"addresses": 5}}]
+        });
+
+        let result = execute_ws_call(
+            call,
+            1,
+            &incoming_tx,
+            broadcast_rx,
+            &sub_data,
+            &CacheArgs::default(),
+        )
+        .await
+        .unwrap();
+        let result: Value = serde_json::from_str(&result).unwrap();
+        assert_eq!(result["id"], 3);
+        assert_eq!(result["error"]["code"], -32602);
+        assert!(result["error"]["message"].is_string());
+    }
+
+    #[tokio::test]
+    async fn test_execute_ws_unknown_blutgang_subscription() {
+        let (incoming_tx, _incoming_rx) = mpsc::unbounded_channel();
+        let (_broadcast_tx, broadcast_rx) = broadcast::channel(10);
+        let sub_data = Arc::new(SubscriptionData::new());
+
+        let call = json!({
+            "jsonrpc": "2.0",
+            "id": 4,
+            "method": "blutgang_subscribe",
+            "params": ["weather"]
+        });
+
+        let result = execute_ws_call(
+            call,
+            1,
+            &incoming_tx,
+            broadcast_rx,
+            &sub_data,
+            &CacheArgs::default(),
+        )
+        .await
+        .unwrap();
+        let result: Value = serde_json::from_str(&result).unwrap();
+        assert_eq!(result["id"], 4);
+        assert_eq!(result["error"]["code"], -32602);
+        assert_eq!(result["error"]["message"], "Unknown subscription kind");
+    }
+
+    #[tokio::test]
+    async fn test_execute_ws_tx_status_limit() {
+        let (incoming_tx, _incoming_rx) = mpsc::unbounded_channel();
+        let (broadcast_tx, _broadcast_rx) = broadcast::channel(10);
+        let sub_data = Arc::new(SubscriptionData::new());
+
+        let subscribe = |tx_hash: &str| {
+            json!({
+                "jsonrpc": "2.0",
+                "id": 5,
+                "method": "blutgang_subscribe",
+                "params": ["txStatus", tx_hash]
+            })
+        };
+        let execute = |call: Value| {
+            let (incoming_tx, broadcast_rx, sub_data) =
+                (&incoming_tx, broadcast_tx.subscribe(), &sub_data);
+            async move {
+                let result = execute_ws_call(
+                    call,
+                    1,
+                    incoming_tx,
+                    broadcast_rx,
+                    sub_data,
+                    &CacheArgs::default(),
+                )
+                .await
+                .unwrap();
+                serde_json::from_str::<Value>(&result).unwrap()
+            }
+        };
+
+        let result = execute(subscribe("0x1234")).await;
+        assert_eq!(result["error"]["code"], -32602);
+
+        for n in 0..MAX_WATCHED_PER_USER {
+            let result = execute(subscribe(&format!("0x{:064x}", n))).await;
+            assert!(result["result"].is_string());
+        }
+        let result = execute(subscribe(&format!("0x{:064x}", MAX_WATCHED_PER_USER))).await;
+        assert_eq!(result["id"], 5);
+        assert_eq!(result["error"]["code"], -32005);
+    }
+
     #[tokio::test]
     async fn test_listen_for_response() {
         let (broadcast_tx, broadcast_rx) = broadcast::channel(10);
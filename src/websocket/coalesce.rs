@@ -0,0 +1,139 @@
+// Skip `newHeads` events a slow user hasn't caught up with.
+//
+// Events for a user are queued until we get around to sending them, so a
+// user on a slow link can have several heads queued at once. Most consumers
+// only care about the latest one, so with `coalesce_new_heads` we send that
+// one instead and tell them how many we skipped in `params.skipped`.
+use crate::websocket::{
+    reorgs::Head,
+    types::RequestResult,
+};
+
+use std::collections::VecDeque;
+
+use serde_json::Value;
+use tokio::sync::mpsc;
+
+fn is_new_head(event: &Value, subscription: &Value) -> bool {
+    event["params"]["subscription"] == *subscription
+        && Head::from_new_head(&event["params"]["result"]).is_some()
+}
+
+// Latest of the heads queued for the subscription of the event in `msg`, or
+// `msg` if it isn't a head. Everything else taken off `rx` on the way goes to
+// `backlog`, in order, and should be sent before anything else we receive.
+pub fn latest_head(
+    msg: RequestResult,
+    rx: &mut mpsc::UnboundedReceiver<RequestResult>,
+    backlog: &mut VecDeque<RequestResult>,
+) -> RequestResult {
+    let subscription = match msg.event() {
+        Some(event) if is_new_head(event, &event["params"]["subscription"]) => {
+            event["params"]["subscription"].clone()
+        }
+        _ => return msg,
+    };
+
+    while let Ok(msg) = rx.try_recv() {
+        backlog.push_back(msg);
+    }
+
+    let mut latest = msg;
+    let mut skipped: u64 = 0;
+    let mut rest = VecDeque::with_capacity(backlog.len());
+    for msg in backlog.drain(..) {
+        if msg
+            .event()
+            .is_some_and(|event| is_new_head(event, &subscription))
+        {
+            latest = msg;
+            skipped += 1;
+        } else {
+            rest.push_back(msg);
+        }
+    }
+    *backlog = rest;
+
+    // Shared events lose their frame, this one is only for us
+    if skipped > 0 {
+        let mut latest = Value::from(latest);
+        latest["params"]["skipped"] = skipped.into();
+        return RequestResult::Subscription(latest);
+    }
+    latest
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::websocket::types::SharedEvent;
+    use serde_json::json;
+    use std::sync::Arc;
+
+    fn head(subscription: &str, number: u64) -> Value {
+        json!({
+            "jsonrpc": "2.0",
+            "method": "eth_subscription",
+            "params": {
+                "subscription": subscription,
+                "result": {
+                    "number": format!("0x{:x}", number),
+                    "hash": format!("0x{:x}", number),
+                    "parentHash": format!("0x{:x}", number - 1),
+                },
+            },
+        })
+    }
+
+    #[test]
+    fn test_latest_head() {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let mut backlog = VecDeque::new();
+
+        // Nothing queued, nothing skipped
+        let latest = latest_head(
+            RequestResult::Subscription(head("0x1", 1)),
+            &mut rx,
+            &mut backlog,
+        );
+        assert_eq!(Value::from(latest), head("0x1", 1));
+
+        let log = json!({"params": {"subscription": "0x2", "result": {"address": "0x0"}}});
+        tx.send(RequestResult::Subscription(head("0x1", 2)))
+            .unwrap();
+        tx.send(RequestResult::Subscription(log.clone())).unwrap();
+        tx.send(RequestResult::Subscription(head("0x3", 2)))
+            .unwrap();
+        tx.send(RequestResult::Shared(SharedEvent::new(head("0x1", 3))))
+            .unwrap();
+
+        let latest = Value::from(latest_head(
+            RequestResult::Subscription(head("0x1", 1)),
+            &mut rx,
+            &mut backlog,
+        ));
+        assert_eq!(latest["params"]["result"]["number"], "0x3");
+        assert_eq!(latest["params"]["skipped"], 2);
+
+        // Events of other subscriptions are kept in order
+        let rest: Vec<Value> = backlog
+            .drain(..)
+            .map(|msg| {
+                match msg {
+                    RequestResult::Subscription(event) => event,
+                    _ => panic!("Expected a subscription event"),
+                }
+            })
+            .collect();
+        assert_eq!(rest, vec![log.clone(), head("0x3", 2)]);
+
+        // Events that aren't heads are passed through
+        tx.send(RequestResult::Subscription(log.clone())).unwrap();
+        let shared = SharedEvent::new(log.clone());
+        match latest_head(RequestResult::Shared(shared.clone()), &mut rx, &mut backlog) {
+            RequestResult::Shared(latest) => assert!(Arc::ptr_eq(&latest, &shared)),
+            _ => panic!("Expected the shared event"),
+        }
+        assert!(backlog.is_empty());
+    }
+}
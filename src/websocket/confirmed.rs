@@ -0,0 +1,275 @@
+// `blutgang_confirmedLogs` subscriptions.
+//
+// Clients subscribe with:
+//
+// ["blutgang_confirmedLogs", {"address": ..., "topics": ..., "confirmations": 12}]
+//
+// or `"confirmations": "finalized"`. We subscribe to regular `logs` upstream
+// and buffer the events, only sending them once their block has enough
+// confirmations. Logs retracted by a reorg before that are dropped from the
+// buffer, so the client never sees them.
+use crate::websocket::error::WsError;
+
+use std::collections::BTreeMap;
+
+use rand::random;
+use serde_json::{
+    json,
+    Value,
+};
+
+pub const CONFIRMED_LOGS: &str = "blutgang_confirmedLogs";
+
+// Confirmations used if the client doesn't specify any
+const DEFAULT_CONFIRMATIONS: u64 = 12;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Confirmation {
+    Blocks(u64),
+    Finalized,
+}
+
+impl Confirmation {
+    // Highest block whose logs can be released
+    fn confirmed_up_to(&self, head: u64, finalized: u64) -> Option<u64> {
+        match self {
+            Confirmation::Blocks(confirmations) => head.checked_sub(*confirmations),
+            Confirmation::Finalized if finalized == 0 => None,
+            Confirmation::Finalized => Some(finalized),
+        }
+    }
+}
+
+// Turn a `blutgang_confirmedLogs` subscription into a `logs` one.
+//
+// Returns `None` if `call` is not a confirmed logs subscription.
+pub fn from_subscription(call: &mut Value) -> Result<Option<Confirmation>, WsError> {
+    if call["params"][0] != CONFIRMED_LOGS {
+        return Ok(None);
+    }
+    call["params"][0] = "logs".into();
+
+    let confirmations = match call["params"].get_mut(1).and_then(Value::as_object_mut) {
+        Some(criteria) => criteria.remove("confirmations"),
+        None => {
+            // `logs` needs a filter object, even if it's empty
+            if let Some(params) = call["params"].as_array_mut() {
+                params.truncate(1);
+                params.push(json!({}));
+            }
+            None
+        }
+    };
+
+    match confirmations {
+        None => Ok(Some(Confirmation::Blocks(DEFAULT_CONFIRMATIONS))),
+        Some(Value::String(tag)) if tag == "finalized" => Ok(Some(Confirmation::Finalized)),
+        Some(confirmations) => {
+            match confirmations.as_u64() {
+                Some(confirmations) => Ok(Some(Confirmation::Blocks(confirmations))),
+                None => {
+                    Err(WsError::InvalidData(
+                        "confirmations must be a number or \"finalized\"".to_string(),
+                    ))
+                }
+            }
+        }
+    }
+}
+
+fn block_number(log: &Value) -> Option<u64> {
+    u64::from_str_radix(log["blockNumber"].as_str()?.trim_start_matches("0x"), 16).ok()
+}
+
+// Logs from one upstream subscription waiting for confirmations
+#[derive(Debug, Clone)]
+pub struct ConfirmedLogs {
+    // Id the client knows this subscription by
+    pub subscription_id: String,
+    confirmation: Confirmation,
+    pending: BTreeMap<u64, Vec<Value>>,
+    // Serialized size of the logs in `pending`
+    pending_bytes: usize,
+}
+
+impl ConfirmedLogs {
+    pub fn new(confirmation: Confirmation) -> Self {
+        ConfirmedLogs {
+            subscription_id: format!("0x{:032x}", random::<u128>()),
+            confirmation,
+            pending: BTreeMap::new(),
+            pending_bytes: 0,
+        }
+    }
+
+    // Bytes held back for this subscription
+    pub fn buffered_bytes(&self) -> usize {
+        self.pending_bytes
+    }
+
+    // Buffer the log in an `eth_subscription` event, or drop it from the
+    // buffer if it was removed by a reorg
+    pub fn push(&mut self, event: &Value) {
+        let log = &event["params"]["result"];
+        let number = match block_number(log) {
+            Some(number) => number,
+            None => return,
+        };
+
+        if log["removed"] == true {
+            if let Some(logs) = self.pending.get_mut(&number) {
+                let pending_bytes = &mut self.pending_bytes;
+                logs.retain(|pending| {
+                    let keep = pending["blockHash"] != log["blockHash"]
+                        || pending["logIndex"] != log["logIndex"];
+                    if !keep {
+                        *pending_bytes -= pending.to_string().len();
+                    }
+                    keep
+                });
+            }
+            return;
+        }
+
+        self.pending_bytes += log.to_string().len();
+        self.pending.entry(number).or_default().push(log.clone());
+    }
+
+    // Take all logs that are confirmed at `head`/`finalized` and wrap
+    // them in events for the client
+    pub fn release(&mut self, head: u64, finalized: u64) -> Vec<Value> {
+        let confirmed_up_to = match self.confirmation.confirmed_up_to(head, finalized) {
+            Some(confirmed_up_to) => confirmed_up_to,
+            None => return Vec::new(),
+        };
+
+        let still_pending = self.pending.split_off(&(confirmed_up_to + 1));
+        let confirmed = std::mem::replace(&mut self.pending, still_pending);
+        self.pending_bytes = self
+            .pending
+            .values()
+            .flatten()
+            .map(|log| log.to_string().len())
+            .sum();
+
+        confirmed
+            .into_values()
+            .flatten()
+            .map(|log| {
+                json!({
+                    "jsonrpc": "2.0",
+                    "method": "eth_subscription",
+                    "params": {"subscription": self.subscription_id, "result": log},
+                })
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(number: u64, hash: &str, index: &str, removed: bool) -> Value {
+        json!({
+            "method": "eth_subscription",
+            "params": {
+                "subscription": "0xupstream",
+                "result": {
+                    "blockNumber": format!("0x{:x}", number),
+                    "blockHash": hash,
+                    "logIndex": index,
+                    "removed": removed,
+                },
+            }
+        })
+    }
+
+    #[test]
+    fn test_from_subscription() {
+        let mut call = json!({
+            "method": "eth_subscribe",
+            "params": [CONFIRMED_LOGS, {"address": "0x01", "confirmations": 6}],
+        });
+        assert_eq!(
+            from_subscription(&mut call).unwrap(),
+            Some(Confirmation::Blocks(6))
+        );
+        assert_eq!(call["params"], json!(["logs", {"address": "0x01"}]));
+
+        let mut call = json!({"method": "eth_subscribe", "params": [CONFIRMED_LOGS]});
+        assert_eq!(
+            from_subscription(&mut call).unwrap(),
+            Some(Confirmation::Blocks(DEFAULT_CONFIRMATIONS))
+        );
+        assert_eq!(call["params"], json!(["logs", {}]));
+
+        let mut call = json!({
+            "method": "eth_subscribe",
+            "params": [CONFIRMED_LOGS, {"confirmations": "finalized"}],
+        });
+        assert_eq!(
+            from_subscription(&mut call).unwrap(),
+            Some(Confirmation::Finalized)
+        );
+
+        let mut call = json!({
+            "method": "eth_subscribe",
+            "params": [CONFIRMED_LOGS, {"confirmations": "soon"}],
+        });
+        assert!(from_subscription(&mut call).is_err());
+
+        let mut call = json!({"method": "eth_subscribe", "params": ["logs", {}]});
+        assert_eq!(from_subscription(&mut call).unwrap(), None);
+    }
+
+    #[test]
+    fn test_release_after_confirmations() {
+        let mut logs = ConfirmedLogs::new(Confirmation::Blocks(2));
+        logs.push(&event(10, "0xa", "0x0", false));
+        logs.push(&event(11, "0xb", "0x0", false));
+
+        assert!(logs.release(11, 0).is_empty());
+
+        let released = logs.release(12, 0);
+        assert_eq!(released.len(), 1);
+        assert_eq!(released[0]["params"]["subscription"], logs.subscription_id);
+        assert_eq!(released[0]["params"]["result"]["blockHash"], "0xa");
+
+        // Already released logs aren't sent again
+        let released = logs.release(13, 0);
+        assert_eq!(released.len(), 1);
+        assert_eq!(released[0]["params"]["result"]["blockHash"], "0xb");
+    }
+
+    #[test]
+    fn test_reorged_logs_retracted() {
+        let mut logs = ConfirmedLogs::new(Confirmation::Blocks(2));
+        logs.push(&event(10, "0xa", "0x0", false));
+        logs.push(&event(10, "0xa", "0x0", true));
+        logs.push(&event(10, "0xc", "0x0", false));
+
+        let size = logs.buffered_bytes();
+        assert_eq!(
+            size,
+            event(10, "0xc", "0x0", false)["params"]["result"]
+                .to_string()
+                .len()
+        );
+
+        let released = logs.release(12, 0);
+        assert_eq!(released.len(), 1);
+        assert_eq!(released[0]["params"]["result"]["blockHash"], "0xc");
+        assert_eq!(logs.buffered_bytes(), 0);
+    }
+
+    #[test]
+    fn test_release_on_finalized() {
+        let mut logs = ConfirmedLogs::new(Confirmation::Finalized);
+        logs.push(&event(10, "0xa", "0x0", false));
+
+        assert!(logs.release(100, 0).is_empty());
+        assert!(logs.release(100, 9).is_empty());
+        assert_eq!(logs.release(100, 10).len(), 1);
+    }
+}
@@ -0,0 +1,197 @@
+// Blutgang specific filters for `logs` subscriptions.
+//
+// Clients can add a `blutgang` object to the filter of a `logs` subscription:
+//
+// ["logs", {"topics": [...], "blutgang": {"addresses": [...], "topics": [...], "excludeRemoved": true}}]
+//
+// We strip it before subscribing upstream and apply it to every event before
+// it gets sent to the client. This lets clients filter on more addresses than
+// providers allow, and several clients can share one upstream subscription
+// while filtering it differently.
+use crate::websocket::error::WsError;
+
+use std::collections::HashSet;
+
+use serde_json::Value;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct EventFilter {
+    // Only let through logs emitted by one of these addresses
+    addresses: Option<HashSet<String>>,
+    // Topic filter with the same semantics as `eth_getLogs`,
+    // `None` matches anything in that position
+    topics: Vec<Option<HashSet<String>>>,
+    // Drop logs retracted because of a reorg
+    exclude_removed: bool,
+}
+
+fn parse_set(value: &Value) -> Result<HashSet<String>, WsError> {
+    let parse = |value: &Value| {
+        value
+            .as_str()
+            .map(|value| value.to_lowercase())
+            .ok_or(WsError::InvalidData(
+                "blutgang filter values must be strings".to_string(),
+            ))
+    };
+
+    match value {
+        Value::Array(values) => values.iter().map(parse).collect(),
+        value => Ok(HashSet::from([parse(value)?])),
+    }
+}
+
+impl EventFilter {
+    // Remove the `blutgang` filter from a `logs` subscription request and parse it.
+    //
+    // Returns `None` if the request doesn't have one.
+    pub fn from_subscription(call: &mut Value) -> Result<Option<Self>, WsError> {
+        if call["params"][0] != "logs" {
+            return Ok(None);
+        }
+
+        let raw = match call["params"].get_mut(1).and_then(Value::as_object_mut) {
+            Some(criteria) => {
+                match criteria.remove("blutgang") {
+                    Some(raw) => raw,
+                    None => return Ok(None),
+                }
+            }
+            None => return Ok(None),
+        };
+
+        let addresses = match raw.get("addresses") {
+            Some(addresses) => Some(parse_set(addresses)?),
+            None => None,
+        };
+
+        let topics = match raw.get("topics") {
+            Some(Value::Array(topics)) => {
+                topics
+                    .iter()
+                    .map(|topic| {
+                        match topic {
+                            Value::Null => Ok(None),
+                            topic => parse_set(topic).map(Some),
+                        }
+                    })
+                    .collect::<Result<_, _>>()?
+            }
+            Some(_) => {
+                return Err(WsError::InvalidData(
+                    "blutgang topics must be an array".to_string(),
+                ))
+            }
+            None => Vec::new(),
+        };
+
+        let exclude_removed = raw
+            .get("excludeRemoved")
+            .and_then(|exclude| exclude.as_bool())
+            .unwrap_or(false);
+
+        Ok(Some(EventFilter {
+            addresses,
+            topics,
+            exclude_removed,
+        }))
+    }
+
+    // Check if a log passes the filter
+    pub fn matches_log(&self, log: &Value) -> bool {
+        if self.exclude_removed && log["removed"] == true {
+            return false;
+        }
+
+        if let Some(addresses) = &self.addresses {
+            match log["address"].as_str() {
+                Some(address) if addresses.contains(&address.to_lowercase()) => {}
+                _ => return false,
+            }
+        }
+
+        self.topics.iter().enumerate().all(|(i, allowed)| {
+            let allowed = match allowed {
+                Some(allowed) => allowed,
+                None => return true,
+            };
+
+            log["topics"][i]
+                .as_str()
+                .is_some_and(|topic| allowed.contains(&topic.to_lowercase()))
+        })
+    }
+
+    // Check if an `eth_subscription` event passes the filter
+    pub fn matches(&self, event: &Value) -> bool {
+        self.matches_log(&event["params"]["result"])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn event(address: &str, topics: Value, removed: bool) -> Value {
+        json!({
+            "jsonrpc": "2.0",
+            "method": "eth_subscription",
+            "params": {
+                "subscription": "0x1",
+                "result": {"address": address, "topics": topics, "removed": removed},
+            }
+        })
+    }
+
+    #[test]
+    fn test_filter_stripped_from_subscription() {
+        let mut call = json!({
+            "method": "eth_subscribe",
+            "params": ["logs", {"topics": ["0xaa"], "blutgang": {"addresses": ["0xAB"]}}],
+        });
+
+        let filter = EventFilter::from_subscription(&mut call).unwrap().unwrap();
+        assert_eq!(call["params"][1], json!({"topics": ["0xaa"]}));
+        assert_eq!(filter.addresses, Some(HashSet::from(["0xab".to_string()])));
+
+        // Nothing to do for other subscriptions or logs without a filter
+        let mut call = json!({"method": "eth_subscribe", "params": ["newHeads"]});
+        assert_eq!(EventFilter::from_subscription(&mut call).unwrap(), None);
+        let mut call = json!({"method": "eth_subscribe", "params": ["logs"]});
+        assert_eq!(EventFilter::from_subscription(&mut call).unwrap(), None);
+    }
+
+    #[test]
+    fn test_invalid_filter() {
+        let mut call = json!({
+            "method": "eth_subscribe",
+            "params": ["logs", {"blutgang": {"topics": "0xaa"}}],
+        });
+
+        assert!(EventFilter::from_subscription(&mut call).is_err());
+    }
+
+    #[test]
+    fn test_filter_matches() {
+        let mut call = json!({
+            "method": "eth_subscribe",
+            "params": ["logs", {"blutgang": {
+                "addresses": ["0x01", "0x02"],
+                "topics": [null, ["0xaa", "0xbb"]],
+                "excludeRemoved": true,
+            }}],
+        });
+        let filter = EventFilter::from_subscription(&mut call).unwrap().unwrap();
+
+        assert!(filter.matches(&event("0x01", json!(["0x00", "0xAA"]), false)));
+        assert!(filter.matches(&event("0x02", json!(["0x00", "0xbb", "0xcc"]), false)));
+        // Wrong address
+        assert!(!filter.matches(&event("0x03", json!(["0x00", "0xaa"]), false)));
+        // Wrong or missing topic
+        assert!(!filter.matches(&event("0x01", json!(["0x00", "0xcc"]), false)));
+        assert!(!filter.matches(&event("0x01", json!(["0x00"]), false)));
+        // Retracted by a reorg
+        assert!(!filter.matches(&event("0x01", json!(["0x00", "0xaa"]), true)));
+    }
+}
@@ -0,0 +1,554 @@
+// Subscriptions over long-polling, for clients that can't hold a connection
+// open at all, like serverless functions.
+//
+// `GET /poll?subscribe=newHeads` subscribes like `eth_subscribe` would over WS,
+// with `params` for the second param like over SSE, and answers with the id of
+// the poller and the cursor to start from:
+//
+// {"id": "0x...", "subscription": "0x...", "cursor": 0}
+//
+// Events are kept in a ring buffer per poller. `GET /poll?id=<id>&cursor=<n>`
+// answers with the events since `cursor` and the cursor to poll with next,
+// waiting up to `wait` ms for one if there aren't any yet:
+//
+// {"id": "0x...", "cursor": 2, "missed": 0, "events": [{...}, {...}]}
+//
+// `missed` counts the events that were dropped from the buffer before the
+// client polled for them. `DELETE /poll?id=<id>` unsubscribes, and pollers
+// nobody polls for `POLLER_TTL` are dropped. Pollers only live on the
+// instance that created them, and only whoever created them, the same client
+// identity and tenant, can poll them.
+use crate::{
+    balancer::processing::CacheArgs,
+    log_info,
+    websocket::{
+        sse::{
+            add_subscriber,
+            subscription_call,
+            SseError,
+        },
+        types::{
+            IncomingResponse,
+            RequestResult,
+            SubscriptionData,
+            WsconnMessage,
+        },
+    },
+};
+
+use std::{
+    collections::{
+        HashMap,
+        VecDeque,
+    },
+    fmt,
+    sync::{
+        Arc,
+        Mutex,
+        RwLock,
+    },
+    time::{
+        Duration,
+        Instant,
+    },
+};
+
+use http_body_util::Full;
+use hyper::{
+    body::Bytes,
+    Response,
+};
+use rand::random;
+use serde_json::{
+    json,
+    Value,
+};
+use tokio::{
+    sync::{
+        broadcast,
+        mpsc,
+        watch,
+    },
+    time::{
+        interval,
+        timeout,
+    },
+};
+
+pub const POLL_PATH: &str = "/poll";
+
+// Events we keep for each poller
+const RING_SIZE: usize = 1024;
+
+// Pollers are dropped if nobody polls them for this long
+const POLLER_TTL: Duration = Duration::from_secs(60);
+
+// Longest we hold a poll open, and how long if the client doesn't say
+const MAX_WAIT: Duration = Duration::from_secs(30);
+const DEFAULT_WAIT: Duration = Duration::from_secs(25);
+
+// Errors
+#[derive(Debug, PartialEq)]
+pub enum PollError {
+    Subscribe(SseError),
+    MissingId,
+    UnknownPoller(String),
+    InvalidCursor(String),
+    InvalidWait(String),
+}
+
+impl fmt::Display for PollError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PollError::Subscribe(e) => write!(f, "{}", e),
+            PollError::MissingId => write!(f, "Expected ?id=<poller id>"),
+            PollError::UnknownPoller(id) => write!(f, "No poller with id {}", id),
+            PollError::InvalidCursor(cursor) => write!(f, "Invalid cursor: {}", cursor),
+            PollError::InvalidWait(wait) => write!(f, "wait must be in ms, got {}", wait),
+        }
+    }
+}
+
+impl std::error::Error for PollError {}
+
+impl From<SseError> for PollError {
+    fn from(e: SseError) -> Self {
+        PollError::Subscribe(e)
+    }
+}
+
+impl PollError {
+    pub fn into_response(self) -> Response<Full<Bytes>> {
+        let status = match self {
+            PollError::Subscribe(e) => return e.into_response(),
+            PollError::UnknownPoller(_) => 404,
+            _ => 400,
+        };
+        let body = json!({
+            "jsonrpc": "2.0",
+            "id": null,
+            "error": {"code": -32602, "message": self.to_string()},
+        });
+
+        Response::builder()
+            .status(status)
+            .header("Content-Type", "application/json")
+            .body(Full::new(Bytes::from(body.to_string())))
+            .unwrap()
+    }
+}
+
+// Events of a poller, numbered from 0 as they come in
+#[derive(Debug)]
+struct Ring {
+    events: VecDeque<Value>,
+    // Number of the oldest event we still have
+    first: u64,
+    last_poll: Instant,
+}
+
+impl Ring {
+    fn next(&self) -> u64 {
+        self.first + self.events.len() as u64
+    }
+
+    fn push(&mut self, event: Value) {
+        if self.events.len() == RING_SIZE {
+            self.events.pop_front();
+            self.first += 1;
+        }
+        self.events.push_back(event);
+    }
+
+    // Events from `cursor` on, and how many of them we dropped already
+    fn since(&self, cursor: u64) -> (Vec<Value>, u64) {
+        let skip = cursor.saturating_sub(self.first) as usize;
+        let events = self.events.iter().skip(skip).cloned().collect();
+        (events, self.first.saturating_sub(cursor))
+    }
+}
+
+#[derive(Debug)]
+struct Poller {
+    user_id: u32,
+    // Identity and tenant that subscribed
+    owner: Option<String>,
+    ring: Mutex<Ring>,
+    // Number the next event will get
+    next: watch::Sender<u64>,
+}
+
+// Long-poll subscriptions by poller id
+#[derive(Debug, Default)]
+pub struct LongPoll {
+    pollers: RwLock<HashMap<String, Arc<Poller>>>,
+}
+
+impl LongPoll {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn poller(&self, id: &str, owner: Option<&str>) -> Result<Arc<Poller>, PollError> {
+        self.pollers
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(id)
+            .filter(|poller| poller.owner.as_deref() == owner)
+            .cloned()
+            .ok_or_else(|| PollError::UnknownPoller(id.to_string()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.pollers.read().unwrap_or_else(|e| e.into_inner()).len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Subscribe a new poller for `owner` with `call`
+    pub async fn subscribe(
+        self: &Arc<Self>,
+        call: Value,
+        owner: Option<String>,
+        sub_data: &Arc<SubscriptionData>,
+        incoming_tx: &mpsc::UnboundedSender<WsconnMessage>,
+        outgoing_rx: &broadcast::Receiver<IncomingResponse>,
+        cache_args: &CacheArgs,
+    ) -> Result<Value, PollError> {
+        let (user_id, rx, rax) =
+            add_subscriber(call, sub_data, incoming_tx, outgoing_rx, cache_args).await?;
+        let subscription = serde_json::from_str::<Value>(&rax)
+            .map(|rax| rax["result"].clone())
+            .unwrap_or_default();
+
+        let id = format!("0x{:032x}", random::<u128>());
+        let poller = Arc::new(Poller {
+            user_id,
+            owner,
+            ring: Mutex::new(Ring {
+                events: VecDeque::new(),
+                first: 0,
+                last_poll: Instant::now(),
+            }),
+            next: watch::channel(0).0,
+        });
+        self.pollers
+            .write()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(id.clone(), poller.clone());
+        log_info!("Adding long-poll user {}", user_id);
+
+        tokio::spawn(collect_events(
+            self.clone(),
+            id.clone(),
+            poller,
+            rx,
+            sub_data.clone(),
+        ));
+
+        Ok(json!({"id": id, "subscription": subscription, "cursor": 0}))
+    }
+
+    // Events of poller `id` since `cursor`, waiting up to `wait` for one
+    pub async fn poll(
+        &self,
+        id: &str,
+        owner: Option<&str>,
+        cursor: u64,
+        wait: Duration,
+    ) -> Result<Value, PollError> {
+        let poller = self.poller(id, owner)?;
+        let mut next = poller.next.subscribe();
+        if cursor > *next.borrow() {
+            return Err(PollError::InvalidCursor(cursor.to_string()));
+        }
+
+        poller
+            .ring
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .last_poll = Instant::now();
+        let _ = timeout(wait.min(MAX_WAIT), next.wait_for(|next| *next > cursor)).await;
+
+        let mut ring = poller.ring.lock().unwrap_or_else(|e| e.into_inner());
+        ring.last_poll = Instant::now();
+        let (events, missed) = ring.since(cursor);
+
+        Ok(json!({
+            "id": id,
+            "cursor": ring.next(),
+            "missed": missed,
+            "events": events,
+        }))
+    }
+
+    // Drop poller `id` and its subscription
+    pub fn unsubscribe(
+        &self,
+        id: &str,
+        owner: Option<&str>,
+        sub_data: &SubscriptionData,
+    ) -> Result<(), PollError> {
+        let mut pollers = self.pollers.write().unwrap_or_else(|e| e.into_inner());
+        if pollers.get(id).map(|poller| poller.owner.as_deref()) != Some(owner) {
+            return Err(PollError::UnknownPoller(id.to_string()));
+        }
+        if let Some(poller) = pollers.remove(id) {
+            sub_data.remove_user(poller.user_id);
+        }
+        Ok(())
+    }
+}
+
+// Keep the events of `poller` until it's unsubscribed or abandoned
+async fn collect_events(
+    long_poll: Arc<LongPoll>,
+    id: String,
+    poller: Arc<Poller>,
+    mut rx: mpsc::UnboundedReceiver<RequestResult>,
+    sub_data: Arc<SubscriptionData>,
+) {
+    let mut expiry = interval(POLLER_TTL / 4);
+
+    loop {
+        let msg = tokio::select! {
+            msg = rx.recv() => match msg {
+                Some(msg) => msg,
+                None => break,
+            },
+            _ = expiry.tick() => {
+                let last_poll = poller.ring.lock().unwrap_or_else(|e| e.into_inner()).last_poll;
+                if last_poll.elapsed() > POLLER_TTL {
+                    break;
+                }
+                continue;
+            }
+        };
+
+        let event = match msg {
+            RequestResult::Subscription(event) => event,
+            RequestResult::Shared(shared) => shared.event.clone(),
+            // Rate limited users lose their subscriptions
+            RequestResult::Close(_) => break,
+            RequestResult::Call(_) | RequestResult::Ping(_) => continue,
+        };
+        let next = {
+            let mut ring = poller.ring.lock().unwrap_or_else(|e| e.into_inner());
+            ring.push(event);
+            ring.next()
+        };
+        poller.next.send_replace(next);
+    }
+
+    // Already gone if it was unsubscribed
+    if long_poll
+        .unsubscribe(&id, poller.owner.as_deref(), &sub_data)
+        .is_ok()
+    {
+        log_info!("Removing long-poll user {}", poller.user_id);
+    }
+}
+
+// What a request to `POLL_PATH` asks for
+#[derive(Debug, PartialEq)]
+pub enum PollRequest {
+    Subscribe(Value),
+    Poll {
+        id: String,
+        cursor: u64,
+        wait: Duration,
+    },
+    Unsubscribe(String),
+}
+
+impl PollRequest {
+    pub fn from_query(delete: bool, query: Option<&str>) -> Result<Self, PollError> {
+        let mut id = None;
+        let mut cursor = None;
+        let mut wait = None;
+        for (key, value) in url::form_urlencoded::parse(query.unwrap_or_default().as_bytes()) {
+            match key.as_ref() {
+                "id" => id = Some(value.into_owned()),
+                "cursor" => cursor = Some(value.into_owned()),
+                "wait" => wait = Some(value.into_owned()),
+                _ => {}
+            }
+        }
+
+        let id = match (id, delete) {
+            (Some(id), true) => return Ok(PollRequest::Unsubscribe(id)),
+            (None, true) => return Err(PollError::MissingId),
+            (Some(id), false) => id,
+            (None, false) => return Ok(PollRequest::Subscribe(subscription_call(query)?)),
+        };
+        let cursor = match cursor {
+            Some(cursor) => {
+                cursor
+                    .parse()
+                    .map_err(|_| PollError::InvalidCursor(cursor))?
+            }
+            None => 0,
+        };
+        let wait = match wait {
+            Some(wait) => {
+                wait.parse()
+                    .map(Duration::from_millis)
+                    .map_err(|_| PollError::InvalidWait(wait))?
+            }
+            None => DEFAULT_WAIT,
+        };
+
+        Ok(PollRequest::Poll { id, cursor, wait })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ring(events: u64) -> Ring {
+        let mut ring = Ring {
+            events: VecDeque::new(),
+            first: 0,
+            last_poll: Instant::now(),
+        };
+        for event in 0..events {
+            ring.push(json!(event));
+        }
+        ring
+    }
+
+    #[test]
+    fn test_ring() {
+        let small = ring(3);
+        assert_eq!(small.next(), 3);
+        assert_eq!(small.since(1), (vec![json!(1), json!(2)], 0));
+        assert_eq!(small.since(3), (vec![], 0));
+
+        // The oldest events are dropped once it's full
+        let full = ring(RING_SIZE as u64 + 2);
+        assert_eq!(full.first, 2);
+        assert_eq!(full.since(0).0.len(), RING_SIZE);
+        assert_eq!(full.since(0).1, 2);
+        assert_eq!(full.since(RING_SIZE as u64 + 1), (vec![json!(1025)], 0));
+    }
+
+    #[test]
+    fn test_poll_request() {
+        assert_eq!(
+            PollRequest::from_query(false, Some("id=0x1&cursor=4&wait=100")),
+            Ok(PollRequest::Poll {
+                id: "0x1".to_string(),
+                cursor: 4,
+                wait: Duration::from_millis(100),
+            })
+        );
+        assert!(matches!(
+            PollRequest::from_query(false, Some("subscribe=newHeads")),
+            Ok(PollRequest::Subscribe(call)) if call["params"] == json!(["newHeads"])
+        ));
+        assert_eq!(
+            PollRequest::from_query(true, Some("id=0x1")),
+            Ok(PollRequest::Unsubscribe("0x1".to_string()))
+        );
+        assert_eq!(
+            PollRequest::from_query(false, None),
+            Err(PollError::Subscribe(SseError::MissingSubscription))
+        );
+        assert_eq!(
+            PollRequest::from_query(false, Some("id=0x1&cursor=-1")),
+            Err(PollError::InvalidCursor("-1".to_string()))
+        );
+        assert_eq!(
+            PollRequest::from_query(true, None),
+            Err(PollError::MissingId)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_long_poll() {
+        let sub_data = Arc::new(SubscriptionData::new());
+        let (incoming_tx, mut incoming_rx) = mpsc::unbounded_channel::<WsconnMessage>();
+        let (outgoing_tx, outgoing_rx) = broadcast::channel::<IncomingResponse>(16);
+
+        // Node that answers subscriptions with `0xabc`
+        tokio::spawn(async move {
+            while let Some(WsconnMessage::Message(call, _)) = incoming_rx.recv().await {
+                let _ = outgoing_tx.send(IncomingResponse {
+                    content: json!({"jsonrpc": "2.0", "id": call["id"], "result": "0xabc"}),
+                    node_id: 0,
+                });
+            }
+        });
+
+        let long_poll = Arc::new(LongPoll::new());
+        let call = subscription_call(Some("subscribe=newHeads")).unwrap();
+        let subscribed = long_poll
+            .subscribe(
+                call,
+                Some("acme".to_string()),
+                &sub_data,
+                &incoming_tx,
+                &outgoing_rx,
+                &CacheArgs::default(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(subscribed["subscription"], "0xabc");
+        assert_eq!(subscribed["cursor"], 0);
+        let id = subscribed["id"].as_str().unwrap();
+
+        // Nothing yet
+        let polled = long_poll
+            .poll(id, Some("acme"), 0, Duration::from_millis(10))
+            .await
+            .unwrap();
+        assert_eq!(polled["events"], json!([]));
+        assert_eq!(polled["cursor"], 0);
+
+        // Waiting polls are answered as soon as an event comes in
+        let waiting = {
+            let long_poll = long_poll.clone();
+            let id = id.to_string();
+            tokio::spawn(async move {
+                long_poll
+                    .poll(&id, Some("acme"), 0, Duration::from_secs(5))
+                    .await
+            })
+        };
+        let event = json!({"jsonrpc": "2.0", "method": "eth_subscription", "params": {"subscription": "0xabc", "result": {"number": "0x1"}}});
+        sub_data
+            .dispatch_to_subscribers("0xabc", 0, &RequestResult::Subscription(event.clone()))
+            .await
+            .unwrap();
+        let polled = waiting.await.unwrap().unwrap();
+        assert_eq!(polled["events"], json!([event]));
+        assert_eq!(polled["cursor"], 1);
+        assert_eq!(polled["missed"], 0);
+
+        assert_eq!(
+            long_poll.poll(id, Some("acme"), 2, Duration::ZERO).await,
+            Err(PollError::InvalidCursor("2".to_string()))
+        );
+
+        // Nobody else can read or drop it
+        assert_eq!(
+            long_poll.poll(id, None, 1, Duration::ZERO).await,
+            Err(PollError::UnknownPoller(id.to_string()))
+        );
+        assert_eq!(
+            long_poll.unsubscribe(id, Some("other"), &sub_data),
+            Err(PollError::UnknownPoller(id.to_string()))
+        );
+
+        long_poll.unsubscribe(id, Some("acme"), &sub_data).unwrap();
+        assert!(long_poll.is_empty());
+        assert_eq!(sub_data.user_count(), 0);
+        assert_eq!(
+            long_poll.poll(id, Some("acme"), 1, Duration::ZERO).await,
+            Err(PollError::UnknownPoller(id.to_string()))
+        );
+    }
+}
@@ -1,5 +1,15 @@
 pub mod client;
+pub mod coalesce;
+pub mod confirmed;
 pub mod error;
+pub mod filter;
+pub mod long_poll;
+pub mod rate_limit;
+pub mod reorgs;
 pub mod server;
+pub mod sessions;
+pub mod sse;
+pub mod stream;
 pub mod subscription_manager;
+pub mod tx_status;
 pub mod types;
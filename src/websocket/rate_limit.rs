@@ -0,0 +1,236 @@
+// Per user limits on subscription events.
+//
+// A single broad log filter can produce thousands of events per block, and
+// every one of them is queued for the user whether or not they keep up. With
+// an `[blutgang.event_rate_limit]` table every user gets a token bucket that
+// refills at `events_per_second` and holds up to `burst`, and events that
+// find it empty are handled according to `policy`.
+use crate::{
+    config::types::{
+        EventLimitPolicy,
+        EventRateLimitSettings,
+    },
+    websocket::types::SubscriptionData,
+};
+
+use std::{
+    collections::HashMap,
+    sync::{
+        Arc,
+        Mutex,
+    },
+    time::{
+        Duration,
+        Instant,
+    },
+};
+
+use serde_json::Value;
+
+// How often held back events are checked against their user's budget
+const RELEASE_INTERVAL: Duration = Duration::from_millis(50);
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn new(settings: &EventRateLimitSettings) -> Self {
+        Bucket {
+            tokens: settings.burst,
+            updated: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self, settings: &EventRateLimitSettings) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * settings.events_per_second).min(settings.burst);
+        self.updated = now;
+    }
+
+    fn take(&mut self, settings: &EventRateLimitSettings) -> bool {
+        self.refill(settings);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+// What to do with an event
+#[derive(Debug, PartialEq)]
+pub enum Verdict {
+    Deliver,
+    // Dropped, or held back to be sent later if we're coalescing
+    Withhold,
+    Disconnect,
+}
+
+#[derive(Debug, Default)]
+pub struct EventLimiter {
+    settings: Option<EventRateLimitSettings>,
+    buckets: Mutex<HashMap<u32, Bucket>>,
+    // Latest event we held back, by user and subscription id
+    held: Mutex<HashMap<(u32, String), Value>>,
+}
+
+impl EventLimiter {
+    pub fn new(settings: Option<EventRateLimitSettings>) -> Self {
+        EventLimiter {
+            settings,
+            ..Default::default()
+        }
+    }
+
+    pub fn is_coalescing(&self) -> bool {
+        self.settings
+            .as_ref()
+            .is_some_and(|settings| settings.policy == EventLimitPolicy::Coalesce)
+    }
+
+    // Spend a token of `user_id` on `event`, or decide what to do without one
+    pub fn check(&self, user_id: u32, subscription_id: &str, event: &Value) -> Verdict {
+        let settings = match &self.settings {
+            Some(settings) => settings,
+            None => return Verdict::Deliver,
+        };
+
+        let mut buckets = self.buckets.lock().unwrap_or_else(|e| e.into_inner());
+        let bucket = buckets
+            .entry(user_id)
+            .or_insert_with(|| Bucket::new(settings));
+        // Coalesced events go out in order, so anything new waits behind them
+        let key = (user_id, subscription_id.to_string());
+        let mut held = self.held.lock().unwrap_or_else(|e| e.into_inner());
+        if !held.contains_key(&key) && bucket.take(settings) {
+            return Verdict::Deliver;
+        }
+
+        match settings.policy {
+            EventLimitPolicy::Coalesce => {
+                held.insert(key, event.clone());
+                Verdict::Withhold
+            }
+            EventLimitPolicy::Sample => Verdict::Withhold,
+            EventLimitPolicy::Disconnect => Verdict::Disconnect,
+        }
+    }
+
+    // Held back events of users who have budget again, taking a token for each
+    pub fn release(&self) -> Vec<(u32, Value)> {
+        let settings = match &self.settings {
+            Some(settings) => settings,
+            None => return Vec::new(),
+        };
+
+        let mut buckets = self.buckets.lock().unwrap_or_else(|e| e.into_inner());
+        let mut held = self.held.lock().unwrap_or_else(|e| e.into_inner());
+        let mut released = Vec::new();
+        held.retain(|(user_id, _), event| {
+            let bucket = buckets
+                .entry(*user_id)
+                .or_insert_with(|| Bucket::new(settings));
+            if bucket.take(settings) {
+                released.push((*user_id, event.take()));
+                false
+            } else {
+                true
+            }
+        });
+
+        released
+    }
+
+    pub fn remove_user(&self, user_id: u32) {
+        self.buckets
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(&user_id);
+        self.held
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .retain(|(user, _), _| *user != user_id);
+    }
+}
+
+// Send coalesced events as soon as their users have budget for them
+pub async fn release_withheld_events(sub_data: Arc<SubscriptionData>) {
+    if !sub_data.coalesces_events() {
+        return;
+    }
+
+    let mut interval = tokio::time::interval(RELEASE_INTERVAL);
+    loop {
+        interval.tick().await;
+        sub_data.release_withheld();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn limiter(policy: EventLimitPolicy) -> EventLimiter {
+        EventLimiter::new(Some(EventRateLimitSettings {
+            events_per_second: 20.0,
+            burst: 2.0,
+            policy,
+        }))
+    }
+
+    #[test]
+    fn test_event_limiter_sample() {
+        let limiter = limiter(EventLimitPolicy::Sample);
+        let verdicts: Vec<Verdict> = (0..3)
+            .map(|head| limiter.check(1, "0x1", &json!(head)))
+            .collect();
+        assert_eq!(
+            verdicts,
+            vec![Verdict::Deliver, Verdict::Deliver, Verdict::Withhold]
+        );
+        // Other users have their own budget
+        assert_eq!(limiter.check(2, "0x1", &json!(0)), Verdict::Deliver);
+        assert!(limiter.release().is_empty());
+
+        std::thread::sleep(Duration::from_millis(60));
+        assert_eq!(limiter.check(1, "0x1", &json!(3)), Verdict::Deliver);
+
+        assert_eq!(
+            EventLimiter::default().check(1, "0x1", &json!(0)),
+            Verdict::Deliver
+        );
+    }
+
+    #[test]
+    fn test_event_limiter_coalesce() {
+        let limiter = limiter(EventLimitPolicy::Coalesce);
+        for head in 0..5 {
+            limiter.check(1, "0x1", &json!(head));
+        }
+        assert!(limiter.release().is_empty());
+
+        // Only the latest of the held back heads is sent
+        std::thread::sleep(Duration::from_millis(60));
+        assert_eq!(limiter.release(), vec![(1, json!(4))]);
+        assert!(limiter.release().is_empty());
+
+        limiter.check(1, "0x2", &json!(0));
+        limiter.remove_user(1);
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(limiter.release().is_empty());
+    }
+
+    #[test]
+    fn test_event_limiter_disconnect() {
+        let limiter = limiter(EventLimitPolicy::Disconnect);
+        limiter.check(1, "0x1", &json!(0));
+        limiter.check(1, "0x1", &json!(1));
+        assert_eq!(limiter.check(1, "0x1", &json!(2)), Verdict::Disconnect);
+    }
+}
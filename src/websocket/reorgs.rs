@@ -0,0 +1,169 @@
+// `blutgang_subscribe("reorgs")` subscriptions.
+//
+// The head tracker feeds every new head into a `ReorgDetector`. When the new
+// head doesn't build on the previous one, we send all reorg subscribers an
+// event with the old head, the new head and how many blocks got replaced:
+//
+// {"jsonrpc": "2.0", "method": "blutgang_subscription", "params": {"subscription": "0x..",
+//   "result": {"oldHead": {"number": "0x..", "hash": "0x.."}, "newHead": {...}, "depth": 1}}}
+use serde_json::{
+    json,
+    Value,
+};
+
+pub const REORGS: &str = "reorgs";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Head {
+    pub number: u64,
+    pub hash: String,
+}
+
+impl Head {
+    // Parse the result of a `newHeads` event
+    pub fn from_new_head(header: &Value) -> Option<(Self, String)> {
+        let number =
+            u64::from_str_radix(header["number"].as_str()?.trim_start_matches("0x"), 16).ok()?;
+        let hash = header["hash"].as_str()?.to_lowercase();
+        let parent_hash = header["parentHash"].as_str()?.to_lowercase();
+
+        Some((Head { number, hash }, parent_hash))
+    }
+
+    fn to_json(&self) -> Value {
+        json!({"number": format!("0x{:x}", self.number), "hash": self.hash})
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Reorg {
+    pub old_head: Head,
+    pub new_head: Head,
+    pub depth: u64,
+}
+
+impl Reorg {
+    // Event sent to reorg subscribers
+    pub fn to_event(&self, subscription_id: &str) -> Value {
+        json!({
+            "jsonrpc": "2.0",
+            "method": "blutgang_subscription",
+            "params": {
+                "subscription": subscription_id,
+                "result": {
+                    "oldHead": self.old_head.to_json(),
+                    "newHead": self.new_head.to_json(),
+                    "depth": self.depth,
+                },
+            },
+        })
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct ReorgDetector {
+    last: Option<Head>,
+}
+
+impl ReorgDetector {
+    // Track a new head and return the reorg it caused, if any
+    pub fn observe(&mut self, head: Head, parent_hash: &str) -> Option<Reorg> {
+        let last = self.last.replace(head.clone())?;
+
+        // Same head reported twice
+        if last == head {
+            return None;
+        }
+
+        // Either we went back, or the new head has a different parent than
+        // our previous head. Depth is the number of blocks we had that got replaced.
+        let depth = if head.number <= last.number {
+            last.number - head.number + 1
+        } else if head.number == last.number + 1 && parent_hash != last.hash {
+            1
+        } else {
+            return None;
+        };
+
+        Some(Reorg {
+            old_head: last,
+            new_head: head,
+            depth,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn head(number: u64, hash: &str) -> Head {
+        Head {
+            number,
+            hash: hash.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_no_reorg_on_chain_extension() {
+        let mut detector = ReorgDetector::default();
+
+        assert_eq!(detector.observe(head(1, "0xa"), "0x0"), None);
+        assert_eq!(detector.observe(head(2, "0xb"), "0xa"), None);
+        assert_eq!(detector.observe(head(2, "0xb"), "0xa"), None);
+        // Skipped blocks are not a reorg on their own
+        assert_eq!(detector.observe(head(5, "0xe"), "0xd"), None);
+    }
+
+    #[test]
+    fn test_reorg_detected() {
+        let mut detector = ReorgDetector::default();
+        detector.observe(head(1, "0xa"), "0x0");
+        detector.observe(head(2, "0xb"), "0xa");
+
+        // Sibling of the previous head
+        assert_eq!(
+            detector.observe(head(2, "0xc"), "0xa"),
+            Some(Reorg {
+                old_head: head(2, "0xb"),
+                new_head: head(2, "0xc"),
+                depth: 1,
+            })
+        );
+
+        // New head that doesn't build on the previous one
+        let reorg = detector.observe(head(3, "0xd"), "0xb").unwrap();
+        assert_eq!(reorg.depth, 1);
+
+        // Going back
+        let reorg = detector.observe(head(1, "0xf"), "0x0").unwrap();
+        assert_eq!(reorg.depth, 3);
+    }
+
+    #[test]
+    fn test_reorg_event() {
+        let reorg = Reorg {
+            old_head: head(16, "0xb"),
+            new_head: head(16, "0xc"),
+            depth: 1,
+        };
+        let event = reorg.to_event("0x1");
+
+        assert_eq!(event["method"], "blutgang_subscription");
+        assert_eq!(event["params"]["subscription"], "0x1");
+        assert_eq!(event["params"]["result"]["oldHead"]["number"], "0x10");
+        assert_eq!(event["params"]["result"]["newHead"]["hash"], "0xc");
+        assert_eq!(event["params"]["result"]["depth"], 1);
+    }
+
+    #[test]
+    fn test_head_from_new_head() {
+        let header = json!({"number": "0x10", "hash": "0xAB", "parentHash": "0xCD"});
+
+        assert_eq!(
+            Head::from_new_head(&header),
+            Some((head(16, "0xab"), "0xcd".to_string()))
+        );
+        assert_eq!(Head::from_new_head(&json!({"number": "0x10"})), None);
+    }
+}
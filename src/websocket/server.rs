@@ -1,11 +1,50 @@
-use std::sync::Arc;
+use std::{
+    collections::VecDeque,
+    sync::Arc,
+};
 
 use crate::{
-    balancer::processing::CacheArgs,
+    admin::gauges::WS_CONNECTIONS,
+    balancer::{
+        block_range::{
+            BlockRange,
+            BLOCK_RANGE,
+        },
+        error_map::map_errors,
+        format::enforce_jsonrpc,
+        mtls::ClientIdentity,
+        processing::CacheArgs,
+        wallet::{
+            into_raw_transaction,
+            is_wallet_method,
+            sign_for_broadcast,
+            wallet_call,
+        },
+    },
+    config::types::{
+        ErrorMapSettings,
+        IdleSettings,
+        JsonRpcMode,
+        WalletPolicy,
+    },
     log_info,
+    middleware::types::{
+        MiddlewareStack,
+        RequestAction,
+    },
+    notify::usage::UsageTracker,
     websocket::{
         client::execute_ws_call,
+        coalesce::latest_head,
         error::WsError,
+        sessions::{
+            session_call,
+            Sessions,
+        },
+        stream::{
+            self,
+            StreamOptions,
+        },
         types::{
             IncomingResponse,
             RequestResult,
@@ -17,30 +56,239 @@ use crate::{
 
 use rand::random;
 
-use tokio::sync::{
-    broadcast,
-    mpsc,
+use tokio::{
+    sync::{
+        broadcast,
+        mpsc,
+    },
+    time::{
+        timeout_at,
+        Instant,
+    },
 };
 
 use simd_json::from_str;
 
 use futures::{
-    sink::SinkExt,
-    stream::StreamExt,
+    sink::{
+        Sink,
+        SinkExt,
+    },
+    stream::{
+        Stream,
+        StreamExt,
+    },
+};
+use serde_json::{
+    json,
+    Value,
 };
 
 use hyper_tungstenite::HyperWebsocket;
-use tungstenite::Message;
+use tungstenite::{
+    protocol::{
+        frame::coding::CloseCode,
+        CloseFrame,
+    },
+    Message,
+};
+
+fn stream_error(id: &Value, code: i64, message: String) -> Value {
+    json!({"jsonrpc": "2.0", "id": id, "error": {"code": code, "message": message}})
+}
+
+// Upstream calls we make for a streamed call, and how to split their results
+struct StreamPlan {
+    calls: Vec<Value>,
+    options: Option<StreamOptions>,
+}
+
+// Plan for `call` if its result should be streamed.
+//
+// Block ranges are always streamed one block per frame, everything else
+// only if the client asked for it. Log ranges are split so every part fits
+// in `max_log_range` blocks.
+fn stream_plan(call: &mut Value, max_log_range: Option<u64>) -> Option<Result<StreamPlan, Value>> {
+    if call["method"] == BLOCK_RANGE {
+        let plan = match BlockRange::from_params(&call["params"]) {
+            Ok(range) => {
+                Ok(StreamPlan {
+                    calls: range.requests().map(|(_, request)| request).collect(),
+                    options: None,
+                })
+            }
+            Err(err) => Err(stream_error(&call["id"], err.code(), err.to_string())),
+        };
+        return Some(plan);
+    }
+
+    match StreamOptions::from_call(call) {
+        Ok(Some(mut options)) => {
+            if let Some(max_log_range) = max_log_range {
+                options.blocks = options.blocks.min(max_log_range);
+            }
+            match options.split_call(call.clone()) {
+                Ok(calls) => {
+                    Some(Ok(StreamPlan {
+                        calls,
+                        options: Some(options),
+                    }))
+                }
+                Err(e) => Some(Err(stream_error(&call["id"], -32602, e.to_string()))),
+            }
+        }
+        Ok(None) => None,
+        Err(e) => Some(Err(stream_error(&call["id"], -32602, e.to_string()))),
+    }
+}
+
+// Make `calls` one after another and send their results as stream frames.
+//
+// Calls can't run concurrently since responses for a user are matched by
+// their user id.
+#[allow(clippy::too_many_arguments)]
+async fn stream_calls<S>(
+    sink: &mut S,
+    id: &Value,
+    plan: StreamPlan,
+    user_id: u32,
+    incoming_tx: &mpsc::UnboundedSender<WsconnMessage>,
+    outgoing_rx: &broadcast::Receiver<IncomingResponse>,
+    sub_data: &Arc<SubscriptionData>,
+    cache_args: &CacheArgs,
+) -> Result<(), WsError>
+where
+    S: Sink<Message, Error = tungstenite::Error> + Unpin,
+{
+    let mut seq = 0;
+    for call in plan.calls {
+        let response = execute_ws_call(
+            call,
+            user_id,
+            incoming_tx,
+            outgoing_rx.resubscribe(),
+            sub_data,
+            cache_args,
+        )
+        .await
+        .and_then(|rax| serde_json::from_str::<Value>(&rax).map_err(|_| WsError::FailedParsing()));
+
+        let mut response = match response {
+            Ok(response) if response.get("error").is_none() => response,
+            Ok(response) => {
+                let rax = json!({"jsonrpc": "2.0", "id": id, "error": response["error"]});
+                sink.send(Message::text::<String>(rax.to_string())).await?;
+                return Ok(());
+            }
+            Err(e) => {
+                let rax = stream_error(id, -32603, e.to_string());
+                sink.send(Message::text::<String>(rax.to_string())).await?;
+                return Ok(());
+            }
+        };
+
+        let result = response["result"].take();
+        let parts = match &plan.options {
+            Some(options) => options.chunks(result),
+            None => vec![result],
+        };
+        for part in parts {
+            sink.send(Message::text::<String>(
+                stream::frame(id, seq, part).to_string(),
+            ))
+            .await?;
+            seq += 1;
+        }
+    }
+
+    sink.send(Message::text::<String>(
+        stream::terminator(id, seq).to_string(),
+    ))
+    .await?;
+    Ok(())
+}
+
+fn idle_notice(warning: std::time::Duration) -> Value {
+    json!({
+        "jsonrpc": "2.0",
+        "method": "blutgang_idle",
+        "params": {"closeIn": warning.as_millis() as u64},
+    })
+}
+
+// Pings and pongs are answered by the client's WS stack on its own, they
+// don't tell us anyone still uses the connection
+fn is_activity<E>(message: &Result<Message, E>) -> bool {
+    !matches!(
+        message,
+        Ok(Message::Ping(_) | Message::Pong(_) | Message::Frame(_))
+    )
+}
+
+// Next frame from the client. With `idle` set, clients we don't hear from
+// in time get a warning, and if they don't answer that their connection is
+// closed and we return `None` like the stream ended. Frames `is_activity`
+// doesn't count are skipped while we wait.
+async fn next_message<S>(
+    stream: &mut S,
+    idle: Option<&IdleSettings>,
+    is_activity: impl Fn(&S::Item) -> bool,
+    tx: &mpsc::UnboundedSender<RequestResult>,
+) -> Option<S::Item>
+where
+    S: Stream + Unpin,
+{
+    let idle = match idle {
+        Some(idle) => idle,
+        None => return stream.next().await,
+    };
+
+    let warn_at = Instant::now() + idle.timeout;
+    let mut warned = false;
+    loop {
+        let deadline = match warned {
+            true => warn_at + idle.warning,
+            false => warn_at,
+        };
+        match timeout_at(deadline, stream.next()).await {
+            Ok(Some(message)) if !is_activity(&message) => continue,
+            Ok(message) => return message,
+            Err(_) if !warned => {
+                tx.send(RequestResult::Ping(idle_notice(idle.warning)))
+                    .unwrap_or(());
+                warned = true;
+            }
+            Err(_) => break,
+        }
+    }
+
+    log_info!("Closing idle WS connection");
+    tx.send(RequestResult::Close("Idle for too long".to_string()))
+        .unwrap_or(());
+    None
+}
 
 /// Handle a websocket connection.
+#[allow(clippy::too_many_arguments)]
 pub async fn serve_websocket(
     websocket: HyperWebsocket,
     incoming_tx: mpsc::UnboundedSender<WsconnMessage>,
     outgoing_rx: broadcast::Receiver<IncomingResponse>,
     sub_data: Arc<SubscriptionData>,
+    sessions: Arc<Sessions>,
     cache_args: CacheArgs,
+    jsonrpc_mode: JsonRpcMode,
+    wallet: WalletPolicy,
+    middleware: Arc<MiddlewareStack>,
+    identity: Option<ClientIdentity>,
+    usage: Arc<UsageTracker>,
+    coalesce_new_heads: bool,
+    idle: Option<IdleSettings>,
+    error_map: Option<ErrorMapSettings>,
+    max_log_range: Option<u64>,
 ) -> Result<(), WsError> {
     let websocket = websocket.await?;
+    let _connected = WS_CONNECTIONS.track();
 
     // Split the Sink so we can do async send/recv
     let (mut websocket_sink, mut websocket_stream) = websocket.split();
@@ -59,15 +307,164 @@ pub async fn serve_websocket(
     sub_data.add_user(user_id, user_data);
 
     let sub_data_clone = sub_data.clone();
+    let sessions_clone = sessions.clone();
 
     // Spawn taks for sending messages to the client
     tokio::spawn(async move {
-        while let Some(msg) = rx.recv().await {
+        // Messages we took off `rx` while looking for newer heads
+        let mut backlog = VecDeque::new();
+        loop {
+            let msg = match backlog.pop_front() {
+                Some(msg) => msg,
+                None => {
+                    match rx.recv().await {
+                        Some(msg) => msg,
+                        None => break,
+                    }
+                }
+            };
+
             // Forward the message to the best available RPC
             //
             // If we received a subscription, just send it to the client
             match msg {
-                RequestResult::Call(call) => {
+                RequestResult::Call(mut call) => {
+                    // Reply with an error right away if the call is malformed
+                    if let Err(err) = enforce_jsonrpc(&mut call, jsonrpc_mode) {
+                        match websocket_sink
+                            .send(Message::text::<String>(err.to_string()))
+                            .await
+                        {
+                            Ok(_) => continue,
+                            Err(e) => {
+                                sub_data_clone.remove_user(user_id);
+                                println!("\x1b[93mWrn:\x1b[0m Error sending call: {}", e);
+                                break;
+                            }
+                        }
+                    }
+
+                    // Certificate identities can only call what their policy allows
+                    if let Some(rax) = identity.as_ref().and_then(|identity| identity.deny(&call)) {
+                        match websocket_sink
+                            .send(Message::text::<String>(rax.to_string()))
+                            .await
+                        {
+                            Ok(_) => continue,
+                            Err(e) => {
+                                sub_data_clone.remove_user(user_id);
+                                println!("\x1b[93mWrn:\x1b[0m Error sending call: {}", e);
+                                break;
+                            }
+                        }
+                    }
+
+                    // Count the call towards the client's usage
+                    usage.record(
+                        identity.as_ref().map(|identity| identity.name.as_str()),
+                        call["method"].as_str().unwrap_or_default(),
+                    );
+
+                    // Let middleware modify the call or answer it on its own
+                    if let RequestAction::Respond(rax) = middleware.on_request(&mut call) {
+                        match websocket_sink
+                            .send(Message::text::<String>(rax.to_string()))
+                            .await
+                        {
+                            Ok(_) => continue,
+                            Err(e) => {
+                                sub_data_clone.remove_user(user_id);
+                                println!("\x1b[93mWrn:\x1b[0m Error sending call: {}", e);
+                                break;
+                            }
+                        }
+                    }
+
+                    // Wallet methods never go to the regular rotation. Transactions signed
+                    // by an external signer get broadcast like any other raw transaction.
+                    if call["method"].as_str().is_some_and(is_wallet_method) {
+                        let rax = match sign_for_broadcast(&call, &wallet).await {
+                            Some(Ok(raw)) => {
+                                into_raw_transaction(&mut call, raw);
+                                None
+                            }
+                            Some(Err(rax)) => Some(rax),
+                            None => Some(wallet_call(&call, &wallet).await),
+                        };
+
+                        if let Some(rax) = rax {
+                            match websocket_sink
+                                .send(Message::text::<String>(rax.to_string()))
+                                .await
+                            {
+                                Ok(_) => continue,
+                                Err(e) => {
+                                    sub_data_clone.remove_user(user_id);
+                                    println!("\x1b[93mWrn:\x1b[0m Error sending call: {}", e);
+                                    break;
+                                }
+                            }
+                        }
+                    }
+
+                    // Sessions are handled by us
+                    if let Some(rax) = session_call(
+                        &call,
+                        user_id,
+                        &sessions_clone,
+                        &incoming_tx,
+                        &outgoing_rx,
+                        &sub_data_clone,
+                        &cache_args,
+                    )
+                    .await
+                    {
+                        match websocket_sink
+                            .send(Message::text::<String>(rax.to_string()))
+                            .await
+                        {
+                            Ok(_) => continue,
+                            Err(e) => {
+                                sub_data_clone.remove_user(user_id);
+                                println!("\x1b[93mWrn:\x1b[0m Error sending call: {}", e);
+                                break;
+                            }
+                        }
+                    }
+
+                    // Stream large results over several frames
+                    if let Some(plan) = stream_plan(&mut call, max_log_range) {
+                        let sent = match plan {
+                            Ok(plan) => {
+                                stream_calls(
+                                    &mut websocket_sink,
+                                    &call["id"],
+                                    plan,
+                                    user_id,
+                                    &incoming_tx,
+                                    &outgoing_rx,
+                                    &sub_data_clone,
+                                    &cache_args,
+                                )
+                                .await
+                            }
+                            Err(rax) => {
+                                websocket_sink
+                                    .send(Message::text::<String>(rax.to_string()))
+                                    .await
+                                    .map_err(WsError::from)
+                            }
+                        };
+                        if let Err(e) = sent {
+                            sub_data_clone.remove_user(user_id);
+                            println!("\x1b[93mWrn:\x1b[0m Error sending call: {}", e);
+                            break;
+                        }
+                        continue;
+                    }
+
+                    let original_call = (!middleware.is_empty()).then(|| call.clone());
+                    let tracked_call = sessions_clone.has_session(user_id).then(|| call.clone());
                     let resp = match execute_ws_call(
                         call,
                         user_id,
@@ -81,6 +478,18 @@ pub async fn serve_websocket(
                         Ok(rax) => rax,
                         Err(e) => format!("{{\"error\": \"{}\"}}", e),
                     };
+                    // Remember the subscriptions of users with a session
+                    if let Some(tracked_call) = tracked_call {
+                        sessions_clone.track(user_id, &tracked_call, &resp);
+                    }
+                    let resp = match &error_map {
+                        Some(error_map) => map_errors(resp, error_map),
+                        None => resp,
+                    };
+                    let resp = match original_call {
+                        Some(original_call) => middleware.on_response(&original_call, resp),
+                        None => resp,
+                    };
 
                     match websocket_sink.send(Message::text::<String>(resp)).await {
                         Ok(_) => {}
@@ -92,11 +501,18 @@ pub async fn serve_websocket(
                         }
                     }
                 }
-                RequestResult::Subscription(sub) => {
-                    match websocket_sink
-                        .send(Message::text::<String>(sub.to_string()))
-                        .await
-                    {
+                sub @ (RequestResult::Subscription(_) | RequestResult::Shared(_)) => {
+                    let sub = if coalesce_new_heads {
+                        latest_head(sub, &mut rx, &mut backlog)
+                    } else {
+                        sub
+                    };
+                    // Events shared with other users are already serialized
+                    let frame = match sub {
+                        RequestResult::Shared(shared) => shared.frame.clone(),
+                        sub => Value::from(sub).to_string(),
+                    };
+                    match websocket_sink.send(Message::text::<String>(frame)).await {
                         Ok(_) => {}
                         Err(e) => {
                             // Remove the user from the sink map
@@ -105,12 +521,36 @@ pub async fn serve_websocket(
                         }
                     }
                 }
+                RequestResult::Ping(notice) => {
+                    let sent = websocket_sink
+                        .send(Message::text::<String>(notice.to_string()))
+                        .await;
+                    let sent = match sent {
+                        Ok(_) => websocket_sink.send(Message::Ping(Default::default())).await,
+                        Err(e) => Err(e),
+                    };
+                    if let Err(e) = sent {
+                        sub_data_clone.remove_user(user_id);
+                        return Err(WsError::MessageSendFailed((e).to_string()));
+                    }
+                }
+                RequestResult::Close(reason) => {
+                    sub_data_clone.remove_user(user_id);
+                    let frame = CloseFrame {
+                        code: CloseCode::Policy,
+                        reason: reason.into(),
+                    };
+                    let _ = websocket_sink.send(Message::Close(Some(frame))).await;
+                    break;
+                }
             }
         }
         Ok(())
     });
 
-    while let Some(message) = websocket_stream.next().await {
+    while let Some(message) =
+        next_message(&mut websocket_stream, idle.as_ref(), is_activity, &tx).await
+    {
         match message {
             Ok(Message::Text(mut msg)) => {
                 log_info!("Received WS text message: {}", msg);
@@ -136,11 +576,65 @@ pub async fn serve_websocket(
             Err(e) => {
                 // Remove the user from the sink map
                 sub_data.remove_user(user_id);
+                sessions.disconnect(user_id);
                 return Err(WsError::MessageReceptionFailed(e.to_string()));
             }
             _ => {}
         }
     }
 
+    // Their session can be resumed on another connection for a while
+    sessions.disconnect(user_id);
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::stream;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_next_message_idle() {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let idle = IdleSettings {
+            timeout: Duration::from_millis(10),
+            warning: Duration::from_millis(10),
+        };
+
+        let counts = |_: &u32| true;
+        let mut active = stream::iter(vec![1]);
+        assert_eq!(
+            next_message(&mut active, Some(&idle), counts, &tx).await,
+            Some(1)
+        );
+        assert!(rx.try_recv().is_err());
+
+        // Warned first, then closed
+        let mut silent = stream::pending::<u32>();
+        assert_eq!(
+            next_message(&mut silent, Some(&idle), counts, &tx).await,
+            None
+        );
+        match rx.try_recv() {
+            Ok(RequestResult::Ping(notice)) => assert_eq!(notice["params"]["closeIn"], 10),
+            other => panic!("Expected a warning, got {:?}", other),
+        }
+        assert!(matches!(rx.try_recv(), Ok(RequestResult::Close(_))));
+
+        // Pongs don't keep the connection open
+        let pongs = stream::repeat(()).then(|_| {
+            async {
+                tokio::time::sleep(Duration::from_millis(2)).await;
+                Ok::<_, ()>(Message::Pong(Default::default()))
+            }
+        });
+        let mut pongs = Box::pin(pongs);
+        assert!(next_message(&mut pongs, Some(&idle), is_activity, &tx)
+            .await
+            .is_none());
+        assert!(matches!(rx.try_recv(), Ok(RequestResult::Ping(_))));
+        assert!(matches!(rx.try_recv(), Ok(RequestResult::Close(_))));
+        assert!(is_activity::<()>(&Ok(Message::text("{}"))));
+    }
+}
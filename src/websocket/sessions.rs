@@ -0,0 +1,585 @@
+// Resumable WS sessions.
+//
+// A client that calls `blutgang_session` gets a token, and every subscription
+// it makes from then on is saved under that token in sled. When its
+// connection drops, or we restart, it can call `blutgang_resume` with the
+// token on a new connection within `ttl` and gets subscribed to all of them
+// again, along with a map of the old subscription ids to the new ones.
+//
+// Sessions are kept in their own tree next to the cache, so cache evictions
+// and wipes leave them alone. On startup we subscribe upstream to everything
+// the saved sessions were subscribed to before any client is back, so events
+// are already flowing when they resume.
+use crate::{
+    balancer::processing::CacheArgs,
+    config::{
+        system::{
+            MAGIC,
+            WS_SUB_MANAGER_ID,
+        },
+        types::WsSessionSettings,
+    },
+    log_info,
+    log_wrn,
+    websocket::{
+        client::execute_ws_call,
+        confirmed,
+        filter::EventFilter,
+        types::{
+            IncomingResponse,
+            SubscriptionData,
+            WsconnMessage,
+        },
+    },
+};
+
+use std::{
+    collections::{
+        HashMap,
+        HashSet,
+    },
+    sync::{
+        Arc,
+        RwLock,
+    },
+    time::{
+        Duration,
+        SystemTime,
+        UNIX_EPOCH,
+    },
+};
+
+use rand::random;
+use serde::{
+    Deserialize,
+    Serialize,
+};
+use serde_json::{
+    json,
+    Map,
+    Value,
+};
+use sled::{
+    Db,
+    Tree,
+};
+use tokio::{
+    sync::{
+        broadcast::{
+            self,
+            error::RecvError,
+        },
+        mpsc,
+    },
+    time::timeout,
+};
+
+pub const SESSION_START: &str = "blutgang_session";
+pub const SESSION_RESUME: &str = "blutgang_resume";
+
+const TREE: &str = "ws_sessions";
+// Ids of the upstream subscriptions we make on startup
+const RESTORE_ID: u32 = WS_SUB_MANAGER_ID + 2 * MAGIC;
+// How long we wait for nodes to answer them
+const RESTORE_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SavedSubscription {
+    // Id the client knows the subscription by
+    pub id: String,
+    // Call the client subscribed with
+    pub request: Value,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Session {
+    subscriptions: Vec<SavedSubscription>,
+    // Unix ms after which the session can't be resumed, unset while connected
+    expires: Option<u64>,
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+#[derive(Debug, Default)]
+pub struct Sessions {
+    ttl: Duration,
+    tree: Option<Tree>,
+    // Session of every connected user that has one
+    active: RwLock<HashMap<u32, String>>,
+}
+
+impl Sessions {
+    // Sessions saved in `cache`, disabled if `settings` aren't set
+    pub fn open(cache: &Db, settings: Option<&WsSessionSettings>) -> Result<Self, sled::Error> {
+        let settings = match settings {
+            Some(settings) => settings,
+            None => return Ok(Sessions::default()),
+        };
+
+        Ok(Sessions {
+            ttl: settings.ttl,
+            tree: Some(cache.open_tree(TREE)?),
+            active: RwLock::new(HashMap::new()),
+        })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.tree.is_some()
+    }
+
+    pub fn ttl(&self) -> Duration {
+        self.ttl
+    }
+
+    pub fn has_session(&self, user_id: u32) -> bool {
+        self.active
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .contains_key(&user_id)
+    }
+
+    fn load(&self, token: &str) -> Option<Session> {
+        let session = self.tree.as_ref()?.get(token).ok()??;
+        serde_json::from_slice(&session).ok()
+    }
+
+    fn save(&self, token: &str, session: &Session) {
+        let tree = match &self.tree {
+            Some(tree) => tree,
+            None => return,
+        };
+        if let Err(e) = tree.insert(token, serde_json::to_vec(session).unwrap_or_default()) {
+            log_wrn!("Could not save WS session: {}", e);
+        }
+    }
+
+    fn token(&self, user_id: u32) -> Option<String> {
+        self.active
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(&user_id)
+            .cloned()
+    }
+
+    // Make `token` the session of `user_id`, letting go of the one it had
+    fn activate(&self, user_id: u32, token: &str) {
+        let mut active = self.active.write().unwrap_or_else(|e| e.into_inner());
+        // Sessions are only resumed by one connection at a time
+        active.retain(|_, active| active != token);
+        let previous = active.insert(user_id, token.to_string());
+        drop(active);
+
+        if let Some(previous) = previous.filter(|previous| previous != token) {
+            self.expire(&previous);
+        }
+    }
+
+    fn expire(&self, token: &str) {
+        if let Some(mut session) = self.load(token) {
+            session.expires = Some(now_ms() + self.ttl.as_millis() as u64);
+            self.save(token, &session);
+        }
+    }
+
+    // Start a new session for `user_id`
+    pub fn start(&self, user_id: u32) -> Option<String> {
+        self.tree.as_ref()?;
+
+        let token = hex::encode(random::<[u8; 16]>());
+        self.save(&token, &Session::default());
+        self.activate(user_id, &token);
+        Some(token)
+    }
+
+    // Subscriptions of the session `token`, which now belongs to `user_id`.
+    // `None` if there's no such session or it expired.
+    pub fn claim(&self, user_id: u32, token: &str) -> Option<Vec<SavedSubscription>> {
+        let mut session = self.load(token)?;
+        if session.expires.is_some_and(|expires| expires < now_ms()) {
+            if let Some(tree) = &self.tree {
+                let _ = tree.remove(token);
+            }
+            return None;
+        }
+
+        session.expires = None;
+        self.save(token, &session);
+        self.activate(user_id, token);
+        Some(session.subscriptions)
+    }
+
+    // Replace the subscriptions of the session of `user_id`
+    pub fn replace(&self, user_id: u32, subscriptions: Vec<SavedSubscription>) {
+        let token = match self.token(user_id) {
+            Some(token) => token,
+            None => return,
+        };
+        let mut session = self.load(&token).unwrap_or_default();
+        session.subscriptions = subscriptions;
+        self.save(&token, &session);
+    }
+
+    // Save or forget the subscription `request` made or cancelled, if the
+    // user it's from has a session
+    pub fn track(&self, user_id: u32, request: &Value, response: &str) {
+        let token = match self.token(user_id) {
+            Some(token) => token,
+            None => return,
+        };
+        let response: Value = match serde_json::from_str(response) {
+            Ok(response) => response,
+            Err(_) => return,
+        };
+        let mut session = self.load(&token).unwrap_or_default();
+
+        match request["method"].as_str() {
+            Some("eth_subscribe" | "blutgang_subscribe") => {
+                let id = match response["result"].as_str() {
+                    Some(id) => id.to_string(),
+                    None => return,
+                };
+                let mut request = request.clone();
+                if let Some(request) = request.as_object_mut() {
+                    request.remove("id");
+                }
+                session
+                    .subscriptions
+                    .push(SavedSubscription { id, request });
+            }
+            Some("eth_unsubscribe" | "blutgang_unsubscribe") if response["result"] == true => {
+                session
+                    .subscriptions
+                    .retain(|subscription| request["params"][0] != subscription.id.as_str());
+            }
+            _ => return,
+        }
+
+        self.save(&token, &session);
+    }
+
+    // The connection of `user_id` went away, its session can be resumed for `ttl`
+    pub fn disconnect(&self, user_id: u32) {
+        let token = self
+            .active
+            .write()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(&user_id);
+        if let Some(token) = token {
+            self.expire(&token);
+        }
+    }
+
+    // Upstream subscriptions of the sessions that can still be resumed,
+    // dropping the ones that can't. Sessions whose connections were still
+    // open when we stopped can be resumed for `ttl` from now.
+    //
+    // Only meant for startup, when nobody is connected yet.
+    pub fn upstream_requests(&self) -> Vec<Value> {
+        let tree = match &self.tree {
+            Some(tree) => tree,
+            None => return Vec::new(),
+        };
+
+        let now = now_ms();
+        let mut seen = HashSet::new();
+        let mut requests = Vec::new();
+        for (token, session) in tree.iter().flatten() {
+            let mut session: Session = match serde_json::from_slice(&session) {
+                Ok(session) => session,
+                Err(_) => {
+                    let _ = tree.remove(&token);
+                    continue;
+                }
+            };
+            match session.expires {
+                Some(expires) if expires < now => {
+                    let _ = tree.remove(&token);
+                    continue;
+                }
+                Some(_) => {}
+                None => {
+                    session.expires = Some(now + self.ttl.as_millis() as u64);
+                    let _ = tree.insert(&token, serde_json::to_vec(&session).unwrap_or_default());
+                }
+            }
+
+            for subscription in &session.subscriptions {
+                if let Some(request) = upstream_request(&subscription.request) {
+                    if seen.insert(request["params"].to_string()) {
+                        requests.push(request);
+                    }
+                }
+            }
+        }
+
+        requests
+    }
+}
+
+// `request` as we make it upstream, without the options we handle ourselves
+fn upstream_request(request: &Value) -> Option<Value> {
+    if request["method"] != "eth_subscribe" {
+        return None;
+    }
+
+    let mut request = request.clone();
+    confirmed::from_subscription(&mut request).ok()?;
+    EventFilter::from_subscription(&mut request).ok()?;
+    Some(request)
+}
+
+fn session_error(id: &Value, code: i64, message: &str) -> Value {
+    json!({"jsonrpc": "2.0", "id": id, "error": {"code": code, "message": message}})
+}
+
+// Answer `blutgang_session` and `blutgang_resume` calls, `None` for anything else
+pub async fn session_call(
+    call: &Value,
+    user_id: u32,
+    sessions: &Sessions,
+    incoming_tx: &mpsc::UnboundedSender<WsconnMessage>,
+    outgoing_rx: &broadcast::Receiver<IncomingResponse>,
+    sub_data: &Arc<SubscriptionData>,
+    cache_args: &CacheArgs,
+) -> Option<Value> {
+    let id = &call["id"];
+    let method = call["method"].as_str()?;
+    if method != SESSION_START && method != SESSION_RESUME {
+        return None;
+    }
+    if !sessions.is_enabled() {
+        return Some(session_error(id, -32601, "WS sessions are disabled!"));
+    }
+
+    if method == SESSION_START {
+        let token = sessions.start(user_id);
+        return Some(json!({"jsonrpc": "2.0", "id": id, "result": token}));
+    }
+
+    let token = match call["params"][0].as_str() {
+        Some(token) => token,
+        None => return Some(session_error(id, -32602, "Expected a session token!")),
+    };
+    let saved = match sessions.claim(user_id, token) {
+        Some(saved) => saved,
+        None => return Some(session_error(id, -32602, "Unknown or expired session!")),
+    };
+
+    let mut resumed = Vec::with_capacity(saved.len());
+    let mut ids = Map::new();
+    for subscription in saved {
+        let response = execute_ws_call(
+            subscription.request.clone(),
+            user_id,
+            incoming_tx,
+            outgoing_rx.resubscribe(),
+            sub_data,
+            cache_args,
+        )
+        .await
+        .ok()
+        .and_then(|rax| serde_json::from_str::<Value>(&rax).ok());
+
+        match response.as_ref().and_then(|rax| rax["result"].as_str()) {
+            Some(new_id) => {
+                ids.insert(subscription.id, new_id.into());
+                resumed.push(SavedSubscription {
+                    id: new_id.to_string(),
+                    request: subscription.request,
+                });
+            }
+            None => {
+                log_wrn!(
+                    "Could not resume subscription {} of user {}",
+                    subscription.id,
+                    user_id
+                );
+            }
+        }
+    }
+    sessions.replace(user_id, resumed);
+
+    Some(json!({
+        "jsonrpc": "2.0",
+        "id": id,
+        "result": {"session": token, "subscriptions": ids},
+    }))
+}
+
+// Subscribe upstream to everything saved sessions were subscribed to, so
+// events are flowing by the time their clients resume. Whatever nobody
+// resumed within `ttl` is unsubscribed from again.
+pub async fn restore_subscriptions(
+    incoming_tx: mpsc::UnboundedSender<WsconnMessage>,
+    mut rx: broadcast::Receiver<IncomingResponse>,
+    sub_data: Arc<SubscriptionData>,
+    sessions: Arc<Sessions>,
+) {
+    let requests = sessions.upstream_requests();
+    if requests.is_empty() {
+        return;
+    }
+
+    let mut pending = HashMap::new();
+    for (request_id, mut request) in (RESTORE_ID..).zip(requests) {
+        request["id"] = request_id.into();
+        pending.insert(request_id, request.clone());
+        let _ = incoming_tx.send(WsconnMessage::Message(request, None));
+    }
+
+    let mut restored = Vec::new();
+    let _ = timeout(RESTORE_TIMEOUT, async {
+        while !pending.is_empty() {
+            let response = match rx.recv().await {
+                Ok(response) => response,
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => break,
+            };
+            let request = match response.content["id"]
+                .as_u64()
+                .and_then(|request_id| pending.remove(&(request_id as u32)))
+            {
+                Some(request) => request,
+                None => continue,
+            };
+
+            if let Some(subscription_id) = response.content["result"].as_str() {
+                let params = request["params"].to_string();
+                sub_data.register_subscription(
+                    request,
+                    subscription_id.to_string(),
+                    response.node_id,
+                );
+                restored.push((params, subscription_id.to_string(), response.node_id));
+            }
+        }
+    })
+    .await;
+    log_info!(
+        "Restored {} upstream subscriptions of WS sessions",
+        restored.len()
+    );
+
+    tokio::time::sleep(sessions.ttl()).await;
+    for (params, subscription_id, node_id) in restored {
+        let unclaimed = sub_data
+            .get_users_for_subscription(&subscription_id)
+            .is_empty()
+            && sub_data.get_sub_id_by_params(&params).as_deref() == Some(subscription_id.as_str());
+        if unclaimed {
+            sub_data.unregister_subscription(params);
+            let unsub = json!({"jsonrpc": "2.0", "id": WS_SUB_MANAGER_ID, "method": "eth_unsubscribe", "params": [subscription_id]});
+            let _ = incoming_tx.send(WsconnMessage::Message(unsub, Some(node_id)));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sessions(cache: &Db) -> Sessions {
+        Sessions::open(cache, Some(&WsSessionSettings::default())).unwrap()
+    }
+
+    fn subscribe(params: Value) -> Value {
+        json!({"jsonrpc": "2.0", "id": 1, "method": "eth_subscribe", "params": params})
+    }
+
+    #[test]
+    fn test_sessions_track() {
+        let cache = sled::Config::new().temporary(true).open().unwrap();
+        let sessions = sessions(&cache);
+
+        // Nothing is saved for users without a session
+        sessions.track(1, &subscribe(json!(["newHeads"])), r#"{"result":"0x1"}"#);
+        assert!(!sessions.has_session(1));
+
+        let token = sessions.start(1).unwrap();
+        sessions.track(1, &subscribe(json!(["newHeads"])), r#"{"result":"0x1"}"#);
+        sessions.track(
+            1,
+            &subscribe(json!(["logs", {"address": "0x0"}])),
+            r#"{"result":"0x2"}"#,
+        );
+        sessions.track(
+            1,
+            &json!({"method": "eth_unsubscribe", "params": ["0x1"]}),
+            r#"{"result":true}"#,
+        );
+        // Failed subscriptions aren't saved
+        sessions.track(
+            1,
+            &subscribe(json!(["bogus"])),
+            r#"{"error":{"code":-32602}}"#,
+        );
+        sessions.disconnect(1);
+        assert!(!sessions.has_session(1));
+
+        let saved = sessions.claim(2, &token).unwrap();
+        assert_eq!(saved.len(), 1);
+        assert_eq!(saved[0].id, "0x2");
+        assert!(saved[0].request.get("id").is_none());
+        assert!(sessions.has_session(2));
+
+        assert!(sessions.claim(2, "unknown").is_none());
+
+        // Disabled without settings
+        let disabled = Sessions::open(&cache, None).unwrap();
+        assert!(!disabled.is_enabled());
+        assert!(disabled.start(1).is_none());
+    }
+
+    #[test]
+    fn test_sessions_expire() {
+        let cache = sled::Config::new().temporary(true).open().unwrap();
+        let sessions = Sessions::open(
+            &cache,
+            Some(&WsSessionSettings {
+                ttl: Duration::from_millis(10),
+            }),
+        )
+        .unwrap();
+
+        let token = sessions.start(1).unwrap();
+        sessions.disconnect(1);
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(sessions.claim(2, &token).is_none());
+    }
+
+    #[test]
+    fn test_sessions_upstream_requests() {
+        let cache = sled::Config::new().temporary(true).open().unwrap();
+        let sessions = sessions(&cache);
+
+        sessions.start(1).unwrap();
+        sessions.track(1, &subscribe(json!(["newHeads"])), r#"{"result":"0x1"}"#);
+        sessions.start(2).unwrap();
+        sessions.track(2, &subscribe(json!(["newHeads"])), r#"{"result":"0x1"}"#);
+        sessions.track(
+            2,
+            &subscribe(json!(["blutgang_confirmedLogs", {"confirmations": 3}])),
+            r#"{"result":"0x2"}"#,
+        );
+        sessions.track(
+            2,
+            &json!({"method": "blutgang_subscribe", "params": ["reorgs"]}),
+            r#"{"result":"0x3"}"#,
+        );
+
+        // Reopened like after a restart
+        drop(sessions);
+        let sessions = self::sessions(&cache);
+        let requests = sessions.upstream_requests();
+        let params: Vec<Value> = requests
+            .iter()
+            .map(|request| request["params"].clone())
+            .collect();
+        assert_eq!(params, vec![json!(["newHeads"]), json!(["logs", {}])]);
+    }
+}
@@ -0,0 +1,416 @@
+// Subscriptions over server-sent events, for clients behind proxies that
+// break WebSockets.
+//
+// `GET /sse?subscribe=newHeads` subscribes like `eth_subscribe` would over WS,
+// and `params` is the URL encoded JSON of the second param, e.g. the filter of
+// a `logs` subscription. SSE clients are users of `SubscriptionData` like WS
+// clients, so they share upstream subscriptions with them. The first event is
+// the answer to the subscription, every event after that the same JSON frame a
+// WS client would get:
+//
+// data: {"jsonrpc":"2.0","id":1,"result":"0x..."}
+//
+// data: {"jsonrpc":"2.0","method":"eth_subscription","params":{...}}
+//
+// Clients are unsubscribed once they disconnect.
+use crate::{
+    balancer::processing::CacheArgs,
+    log_info,
+    websocket::{
+        client::execute_ws_call,
+        coalesce::latest_head,
+        types::{
+            IncomingResponse,
+            RequestResult,
+            SubscriptionData,
+            WsconnMessage,
+        },
+    },
+};
+
+use std::{
+    collections::VecDeque,
+    convert::Infallible,
+    fmt,
+    sync::{
+        Arc,
+        Mutex,
+    },
+    time::Duration,
+};
+
+use http_body_util::{
+    Either,
+    Full,
+    StreamBody,
+};
+use hyper::{
+    body::{
+        Bytes,
+        Frame,
+    },
+    Response,
+};
+use rand::random;
+use serde_json::{
+    json,
+    Value,
+};
+use tokio::{
+    sync::{
+        broadcast,
+        mpsc,
+    },
+    time::interval,
+};
+use tokio_stream::wrappers::ReceiverStream;
+
+pub const SSE_PATH: &str = "/sse";
+
+// Comment we send this often so proxies don't close quiet connections
+const KEEPALIVE: Duration = Duration::from_secs(15);
+
+// Events we hold for a client that's slow to read them
+const EVENT_BUFFER: usize = 256;
+
+pub type SseFrame = Result<Frame<Bytes>, Infallible>;
+pub type SseBody = StreamBody<ReceiverStream<SseFrame>>;
+
+// Errors
+#[derive(Debug, PartialEq)]
+pub enum SseError {
+    MissingSubscription,
+    InvalidParams(String),
+    Disabled,
+    Subscribe(String),
+}
+
+impl fmt::Display for SseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SseError::MissingSubscription => write!(f, "Expected ?subscribe=<kind>"),
+            SseError::InvalidParams(params) => write!(f, "params must be JSON, got {}", params),
+            SseError::Disabled => write!(f, "Subscriptions are disabled"),
+            SseError::Subscribe(e) => write!(f, "Could not subscribe: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for SseError {}
+
+impl SseError {
+    pub fn code(&self) -> i64 {
+        match self {
+            SseError::MissingSubscription | SseError::InvalidParams(_) => -32602,
+            SseError::Disabled => -32005,
+            SseError::Subscribe(_) => -32603,
+        }
+    }
+
+    pub fn into_response(self) -> Response<Full<Bytes>> {
+        let status = match self {
+            SseError::MissingSubscription | SseError::InvalidParams(_) => 400,
+            SseError::Disabled => 503,
+            SseError::Subscribe(_) => 502,
+        };
+        let body = json!({
+            "jsonrpc": "2.0",
+            "id": null,
+            "error": {"code": self.code(), "message": self.to_string()},
+        });
+
+        Response::builder()
+            .status(status)
+            .header("Content-Type", "application/json")
+            .body(Full::new(Bytes::from(body.to_string())))
+            .unwrap()
+    }
+}
+
+// Events of an SSE response. Our responses are `Full` everywhere else, so the
+// events ride along in its extensions until the transport takes them.
+#[derive(Debug, Clone)]
+pub struct SseEvents(Arc<Mutex<Option<mpsc::Receiver<SseFrame>>>>);
+
+impl SseEvents {
+    pub fn take(&self) -> Option<mpsc::Receiver<SseFrame>> {
+        self.0.lock().unwrap_or_else(|e| e.into_inner()).take()
+    }
+}
+
+// Stream the events of `response` if it has any
+pub fn into_streaming(response: Response<Full<Bytes>>) -> Response<Either<Full<Bytes>, SseBody>> {
+    let (mut parts, body) = response.into_parts();
+    match parts
+        .extensions
+        .remove::<SseEvents>()
+        .and_then(|events| events.take())
+    {
+        Some(events) => {
+            Response::from_parts(
+                parts,
+                Either::Right(StreamBody::new(ReceiverStream::new(events))),
+            )
+        }
+        None => Response::from_parts(parts, Either::Left(body)),
+    }
+}
+
+// `eth_subscribe` call `?subscribe=<kind>&params=<json>` in `query` stands for
+pub fn subscription_call(query: Option<&str>) -> Result<Value, SseError> {
+    let mut kind = None;
+    let mut params = None;
+    for (key, value) in url::form_urlencoded::parse(query.unwrap_or_default().as_bytes()) {
+        match key.as_ref() {
+            "subscribe" => kind = Some(value.into_owned()),
+            "params" => params = Some(value.into_owned()),
+            _ => {}
+        }
+    }
+
+    let kind = kind
+        .filter(|kind| !kind.is_empty())
+        .ok_or(SseError::MissingSubscription)?;
+    let params = match params {
+        Some(params) => {
+            let parsed = serde_json::from_str::<Value>(&params)
+                .map_err(|_| SseError::InvalidParams(params))?;
+            json!([kind, parsed])
+        }
+        None => json!([kind]),
+    };
+
+    Ok(json!({"jsonrpc": "2.0", "id": 1, "method": "eth_subscribe", "params": params}))
+}
+
+fn event(data: &str) -> SseFrame {
+    Ok(Frame::data(Bytes::from(format!("data: {}\n\n", data))))
+}
+
+// Subscribe a new user with `call`. Returns their id, where their events
+// go and the answer to the subscription.
+pub async fn add_subscriber(
+    call: Value,
+    sub_data: &Arc<SubscriptionData>,
+    incoming_tx: &mpsc::UnboundedSender<WsconnMessage>,
+    outgoing_rx: &broadcast::Receiver<IncomingResponse>,
+    cache_args: &CacheArgs,
+) -> Result<(u32, mpsc::UnboundedReceiver<RequestResult>, String), SseError> {
+    let user_id = random::<u32>();
+    let (tx, rx) = mpsc::unbounded_channel::<RequestResult>();
+    sub_data.add_user(user_id, tx);
+
+    let rax = execute_ws_call(
+        call,
+        user_id,
+        incoming_tx,
+        outgoing_rx.resubscribe(),
+        sub_data,
+        cache_args,
+    )
+    .await
+    .map_err(|e| SseError::Subscribe(e.to_string()))
+    .and_then(|rax| {
+        let subscribed = serde_json::from_str::<Value>(&rax)
+            .is_ok_and(|response| response["result"].is_string());
+        match subscribed {
+            true => Ok(rax),
+            false => Err(SseError::Subscribe(rax)),
+        }
+    });
+    match rax {
+        Ok(rax) => Ok((user_id, rx, rax)),
+        Err(e) => {
+            sub_data.remove_user(user_id);
+            Err(e)
+        }
+    }
+}
+
+// Subscribe a new user with `call` and answer with the stream of its events
+pub async fn subscribe(
+    call: Value,
+    sub_data: &Arc<SubscriptionData>,
+    incoming_tx: &mpsc::UnboundedSender<WsconnMessage>,
+    outgoing_rx: &broadcast::Receiver<IncomingResponse>,
+    cache_args: &CacheArgs,
+    coalesce_new_heads: bool,
+) -> Result<Response<Full<Bytes>>, SseError> {
+    let (user_id, rx, rax) =
+        add_subscriber(call, sub_data, incoming_tx, outgoing_rx, cache_args).await?;
+    log_info!("Adding SSE user {}", user_id);
+
+    let (events_tx, events_rx) = mpsc::channel(EVENT_BUFFER);
+    let _ = events_tx.try_send(event(&rax));
+    tokio::spawn(forward_events(
+        user_id,
+        rx,
+        events_tx,
+        sub_data.clone(),
+        coalesce_new_heads,
+    ));
+
+    let mut response = Response::builder()
+        .status(200)
+        .header("Content-Type", "text/event-stream")
+        .header("Cache-Control", "no-cache")
+        // Buffering proxies would hold events back
+        .header("X-Accel-Buffering", "no")
+        .body(Full::new(Bytes::new()))
+        .unwrap();
+    response
+        .extensions_mut()
+        .insert(SseEvents(Arc::new(Mutex::new(Some(events_rx)))));
+
+    Ok(response)
+}
+
+// Pass the events of `user_id` on until they disconnect
+async fn forward_events(
+    user_id: u32,
+    mut rx: mpsc::UnboundedReceiver<RequestResult>,
+    events_tx: mpsc::Sender<SseFrame>,
+    sub_data: Arc<SubscriptionData>,
+    coalesce_new_heads: bool,
+) {
+    let mut backlog = VecDeque::new();
+    let mut keepalive = interval(KEEPALIVE);
+    keepalive.tick().await;
+
+    loop {
+        let msg = match backlog.pop_front() {
+            Some(msg) => msg,
+            None => {
+                tokio::select! {
+                    msg = rx.recv() => match msg {
+                        Some(msg) => msg,
+                        None => break,
+                    },
+                    _ = keepalive.tick() => {
+                        let keepalive = Ok(Frame::data(Bytes::from_static(b": keepalive\n\n")));
+                        if events_tx.send(keepalive).await.is_err() {
+                            break;
+                        }
+                        continue;
+                    }
+                    _ = events_tx.closed() => break,
+                }
+            }
+        };
+
+        let frame = match msg {
+            sub @ (RequestResult::Subscription(_) | RequestResult::Shared(_)) => {
+                let sub = match coalesce_new_heads {
+                    true => latest_head(sub, &mut rx, &mut backlog),
+                    false => sub,
+                };
+                match sub {
+                    RequestResult::Shared(shared) => event(&shared.frame),
+                    sub => event(&Value::from(sub).to_string()),
+                }
+            }
+            RequestResult::Close(reason) => {
+                let close = format!("event: close\ndata: {}\n\n", reason);
+                let _ = events_tx.send(Ok(Frame::data(Bytes::from(close)))).await;
+                break;
+            }
+            // Calls and idle pings are for WS clients
+            RequestResult::Call(_) | RequestResult::Ping(_) => continue,
+        };
+        if events_tx.send(frame).await.is_err() {
+            break;
+        }
+    }
+
+    log_info!("Removing SSE user {}", user_id);
+    sub_data.remove_user(user_id);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http_body_util::BodyExt;
+
+    async fn next_event(body: &mut Either<Full<Bytes>, SseBody>) -> String {
+        let frame = body.frame().await.unwrap().unwrap();
+        String::from_utf8(frame.into_data().unwrap().to_vec()).unwrap()
+    }
+
+    #[test]
+    fn test_subscription_call() {
+        assert_eq!(
+            subscription_call(Some("subscribe=newHeads")).unwrap()["params"],
+            json!(["newHeads"])
+        );
+        assert_eq!(
+            subscription_call(Some(
+                "subscribe=logs&params=%7B%22address%22%3A%220xabc%22%7D"
+            ))
+            .unwrap()["params"],
+            json!(["logs", {"address": "0xabc"}])
+        );
+        assert_eq!(
+            subscription_call(Some("params=%5B%5D")),
+            Err(SseError::MissingSubscription)
+        );
+        assert_eq!(subscription_call(None), Err(SseError::MissingSubscription));
+        assert_eq!(
+            subscription_call(Some("subscribe=logs&params={")),
+            Err(SseError::InvalidParams("{".to_string()))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_subscribe() {
+        let sub_data = Arc::new(SubscriptionData::new());
+        let (incoming_tx, mut incoming_rx) = mpsc::unbounded_channel::<WsconnMessage>();
+        let (outgoing_tx, outgoing_rx) = broadcast::channel::<IncomingResponse>(16);
+
+        // Node that answers subscriptions with `0xabc`
+        tokio::spawn(async move {
+            while let Some(WsconnMessage::Message(call, _)) = incoming_rx.recv().await {
+                let _ = outgoing_tx.send(IncomingResponse {
+                    content: json!({"jsonrpc": "2.0", "id": call["id"], "result": "0xabc"}),
+                    node_id: 0,
+                });
+            }
+        });
+
+        let call = subscription_call(Some("subscribe=newHeads")).unwrap();
+        let response = subscribe(
+            call,
+            &sub_data,
+            &incoming_tx,
+            &outgoing_rx,
+            &CacheArgs::default(),
+            false,
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.headers()["content-type"], "text/event-stream");
+        assert_eq!(sub_data.user_count(), 1);
+
+        let event = json!({"jsonrpc": "2.0", "method": "eth_subscription", "params": {"subscription": "0xabc", "result": {"number": "0x1"}}});
+        sub_data
+            .dispatch_to_subscribers("0xabc", 0, &RequestResult::Subscription(event.clone()))
+            .await
+            .unwrap();
+
+        let mut body = into_streaming(response).into_body();
+        assert_eq!(
+            next_event(&mut body).await,
+            "data: {\"id\":1,\"jsonrpc\":\"2.0\",\"result\":\"0xabc\"}\n\n"
+        );
+        assert_eq!(next_event(&mut body).await, format!("data: {}\n\n", event));
+
+        // Gone once they disconnect
+        drop(body);
+        for _ in 0..100 {
+            if sub_data.user_count() == 0 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(sub_data.user_count(), 0);
+    }
+}
@@ -0,0 +1,268 @@
+// Frames for streaming one result over several WS messages.
+//
+// Every part of the result is sent in order in its own frame, followed by a
+// terminator once everything was sent:
+//
+// {"jsonrpc": "2.0", "id": 1, "blutgang_stream": {"seq": 0, "result": ...}}
+// {"jsonrpc": "2.0", "id": 1, "blutgang_stream": {"seq": 1, "result": ...}}
+// {"jsonrpc": "2.0", "id": 1, "blutgang_stream": {"seq": 2, "done": true}}
+//
+// If something fails midway we send a regular JSON-RPC error instead of the
+// terminator.
+//
+// Clients opt into streaming for any call by adding a `blutgang_stream`
+// member to the request:
+//
+// {"jsonrpc": "2.0", "id": 1, "method": "eth_getLogs", "params": [...],
+//   "blutgang_stream": {"chunkSize": 1000, "blocks": 500}}
+//
+// or just `"blutgang_stream": true` for the defaults. Array results are split
+// into frames of `chunkSize` items. `eth_getLogs` calls over a numbered block
+// range are also split into upstream requests of `blocks` blocks, so we never
+// hold the whole result in memory. Ranges that would take more than
+// `MAX_CALLS` of them are rejected.
+use crate::websocket::error::WsError;
+
+use serde_json::{
+    json,
+    Value,
+};
+
+pub const STREAM_OPTION: &str = "blutgang_stream";
+
+// Items of an array result per frame
+const DEFAULT_CHUNK_SIZE: usize = 1000;
+// Blocks per upstream `eth_getLogs` request
+const DEFAULT_LOG_BLOCKS: u64 = 1000;
+// Most upstream requests we split a call into
+pub const MAX_CALLS: u64 = 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StreamOptions {
+    pub chunk_size: usize,
+    pub blocks: u64,
+}
+
+impl Default for StreamOptions {
+    fn default() -> Self {
+        StreamOptions {
+            chunk_size: DEFAULT_CHUNK_SIZE,
+            blocks: DEFAULT_LOG_BLOCKS,
+        }
+    }
+}
+
+impl StreamOptions {
+    // Remove `blutgang_stream` from the call and parse it.
+    //
+    // Returns `None` if the client didn't ask for streaming.
+    pub fn from_call(call: &mut Value) -> Result<Option<Self>, WsError> {
+        let raw = match call
+            .as_object_mut()
+            .and_then(|call| call.remove(STREAM_OPTION))
+        {
+            Some(raw) => raw,
+            None => return Ok(None),
+        };
+
+        match raw {
+            Value::Bool(false) => Ok(None),
+            Value::Bool(true) => Ok(Some(StreamOptions::default())),
+            Value::Object(raw) => {
+                let mut options = StreamOptions::default();
+                if let Some(chunk_size) = raw.get("chunkSize") {
+                    options.chunk_size = match chunk_size.as_u64() {
+                        Some(chunk_size) if chunk_size > 0 => chunk_size as usize,
+                        _ => {
+                            return Err(WsError::InvalidData(
+                                "chunkSize must be a positive number".to_string(),
+                            ))
+                        }
+                    };
+                }
+                if let Some(blocks) = raw.get("blocks") {
+                    options.blocks = match blocks.as_u64() {
+                        Some(blocks) if blocks > 0 => blocks,
+                        _ => {
+                            return Err(WsError::InvalidData(
+                                "blocks must be a positive number".to_string(),
+                            ))
+                        }
+                    };
+                }
+                Ok(Some(options))
+            }
+            _ => {
+                Err(WsError::InvalidData(format!(
+                    "{} must be a boolean or an object",
+                    STREAM_OPTION
+                )))
+            }
+        }
+    }
+
+    // Split a call into the upstream calls we make for it, in order
+    pub fn split_call(&self, call: Value) -> Result<Vec<Value>, WsError> {
+        if call["method"] != "eth_getLogs" {
+            return Ok(vec![call]);
+        }
+
+        let from = parse_hex(&call["params"][0]["fromBlock"]);
+        let to = parse_hex(&call["params"][0]["toBlock"]);
+        let (from, to) = match (from, to) {
+            (Some(from), Some(to)) if from <= to => (from, to),
+            _ => return Ok(vec![call]),
+        };
+
+        if (to - from) / self.blocks >= MAX_CALLS {
+            return Err(WsError::InvalidData(format!(
+                "blocks {} to {} take more than {} requests of {} blocks",
+                from, to, MAX_CALLS, self.blocks
+            )));
+        }
+
+        Ok((from..=to)
+            .step_by(self.blocks as usize)
+            .map(|start| {
+                let end = to.min(start.saturating_add(self.blocks - 1));
+                let mut call = call.clone();
+                call["params"][0]["fromBlock"] = format!("0x{:x}", start).into();
+                call["params"][0]["toBlock"] = format!("0x{:x}", end).into();
+                call
+            })
+            .collect())
+    }
+
+    // Split a result into the parts we send in separate frames
+    pub fn chunks(&self, result: Value) -> Vec<Value> {
+        match result {
+            Value::Array(items) => {
+                items
+                    .chunks(self.chunk_size)
+                    .map(|chunk| Value::Array(chunk.to_vec()))
+                    .collect()
+            }
+            result => vec![result],
+        }
+    }
+}
+
+fn parse_hex(value: &Value) -> Option<u64> {
+    u64::from_str_radix(value.as_str()?.strip_prefix("0x")?, 16).ok()
+}
+
+pub fn frame(id: &Value, seq: usize, result: Value) -> Value {
+    json!({"jsonrpc": "2.0", "id": id, "blutgang_stream": {"seq": seq, "result": result}})
+}
+
+pub fn terminator(id: &Value, seq: usize) -> Value {
+    json!({"jsonrpc": "2.0", "id": id, "blutgang_stream": {"seq": seq, "done": true}})
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_frames() {
+        let id = json!(7);
+
+        let frame = frame(&id, 0, json!({"number": "0x1"}));
+        assert_eq!(frame["id"], 7);
+        assert_eq!(frame["blutgang_stream"]["seq"], 0);
+        assert_eq!(frame["blutgang_stream"]["result"]["number"], "0x1");
+
+        let terminator = terminator(&id, 1);
+        assert_eq!(terminator["blutgang_stream"]["seq"], 1);
+        assert_eq!(terminator["blutgang_stream"]["done"], true);
+        assert!(terminator["blutgang_stream"].get("result").is_none());
+    }
+
+    #[test]
+    fn test_stream_options_from_call() {
+        let mut call = json!({"method": "eth_getLogs", "params": [{}], "blutgang_stream": true});
+        assert_eq!(
+            StreamOptions::from_call(&mut call).unwrap(),
+            Some(StreamOptions::default())
+        );
+        assert_eq!(call, json!({"method": "eth_getLogs", "params": [{}]}));
+
+        let mut call = json!({"method": "eth_getLogs", "blutgang_stream": {"chunkSize": 10}});
+        assert_eq!(
+            StreamOptions::from_call(&mut call).unwrap(),
+            Some(StreamOptions {
+                chunk_size: 10,
+                blocks: DEFAULT_LOG_BLOCKS,
+            })
+        );
+
+        let mut call = json!({"method": "eth_getLogs", "blutgang_stream": false});
+        assert_eq!(StreamOptions::from_call(&mut call).unwrap(), None);
+        let mut call = json!({"method": "eth_getLogs"});
+        assert_eq!(StreamOptions::from_call(&mut call).unwrap(), None);
+
+        let mut call = json!({"method": "eth_getLogs", "blutgang_stream": {"chunkSize": 0}});
+        assert!(StreamOptions::from_call(&mut call).is_err());
+        let mut call = json!({"method": "eth_getLogs", "blutgang_stream": "yes"});
+        assert!(StreamOptions::from_call(&mut call).is_err());
+    }
+
+    #[test]
+    fn test_split_get_logs() {
+        let options = StreamOptions {
+            chunk_size: 10,
+            blocks: 100,
+        };
+        let call = json!({
+            "method": "eth_getLogs",
+            "params": [{"address": "0x01", "fromBlock": "0x0", "toBlock": "0xfa"}],
+        });
+
+        let calls = options.split_call(call).unwrap();
+        assert_eq!(calls.len(), 3);
+        assert_eq!(calls[0]["params"][0]["fromBlock"], "0x0");
+        assert_eq!(calls[0]["params"][0]["toBlock"], "0x63");
+        assert_eq!(calls[2]["params"][0]["fromBlock"], "0xc8");
+        assert_eq!(calls[2]["params"][0]["toBlock"], "0xfa");
+        assert_eq!(calls[2]["params"][0]["address"], "0x01");
+
+        // Named blocks and other methods are sent as they are
+        let call =
+            json!({"method": "eth_getLogs", "params": [{"fromBlock": "0x0", "toBlock": "latest"}]});
+        assert_eq!(options.split_call(call.clone()).unwrap(), vec![call]);
+        let call = json!({"method": "trace_block", "params": ["0x1"]});
+        assert_eq!(options.split_call(call.clone()).unwrap(), vec![call]);
+
+        // Up to `MAX_CALLS` requests
+        let call = json!({
+            "method": "eth_getLogs",
+            "params": [{"fromBlock": "0x0", "toBlock": format!("0x{:x}", MAX_CALLS * 100 - 1)}],
+        });
+        assert_eq!(options.split_call(call).unwrap().len(), MAX_CALLS as usize);
+        let call = json!({
+            "method": "eth_getLogs",
+            "params": [{"fromBlock": "0x0", "toBlock": format!("0x{:x}", MAX_CALLS * 100)}],
+        });
+        assert!(options.split_call(call).is_err());
+        let call = json!({
+            "method": "eth_getLogs",
+            "params": [{"fromBlock": "0x0", "toBlock": format!("0x{:x}", u64::MAX)}],
+        });
+        assert!(options.split_call(call).is_err());
+    }
+
+    #[test]
+    fn test_chunks() {
+        let options = StreamOptions {
+            chunk_size: 2,
+            blocks: 1,
+        };
+
+        assert_eq!(
+            options.chunks(json!([1, 2, 3])),
+            vec![json!([1, 2]), json!([3])]
+        );
+        assert_eq!(options.chunks(json!({"a": 1})), vec![json!({"a": 1})]);
+        assert!(options.chunks(json!([])).is_empty());
+    }
+}
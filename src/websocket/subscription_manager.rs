@@ -1,9 +1,13 @@
 use crate::{
+    admin::gauges::WS_RESPONSE_QUEUE,
     config::system::{
+        DebugModule,
         MAGIC,
         WS_SUB_MANAGER_ID,
     },
+    log_dbg,
     log_err,
+    middleware::types::MiddlewareStack,
     websocket::{
         error::WsError,
         types::{
@@ -26,6 +30,7 @@ use tokio::sync::{
         error::RecvError,
     },
     mpsc,
+    watch,
 };
 
 use serde_json::json;
@@ -35,6 +40,7 @@ pub async fn subscription_dispatcher(
     mut rx: broadcast::Receiver<IncomingResponse>,
     incoming_tx: mpsc::UnboundedSender<WsconnMessage>,
     sub_data: Arc<SubscriptionData>,
+    middleware: Arc<MiddlewareStack>,
 ) -> Result<(), WsError> {
     loop {
         // Receive the WS response
@@ -43,14 +49,15 @@ pub async fn subscription_dispatcher(
             Err(RecvError::Closed) => return Err(WsError::ChannelClosed()),
             Err(RecvError::Lagged(_)) => return Err(WsError::ReceiverLagged()),
         };
+        WS_RESPONSE_QUEUE.set(rx.len() as u64);
 
         // Check if its a subscription
         if response.content["method"] != "eth_subscription" {
             continue;
         }
 
-        #[cfg(feature = "debug-verbose")]
-        println!(
+        log_dbg!(
+            DebugModule::Ws,
             "subscription_dispatcher: received subscription: {}",
             response.content
         );
@@ -63,13 +70,13 @@ pub async fn subscription_dispatcher(
             None => continue, // if this doesnt exist something in the pipeline is wrong and should be ignored
         };
 
+        // Let middleware rewrite the event before anyone sees it
+        let mut content = resp_clone.content;
+        middleware.on_subscription_event(&mut content);
+
         // Send the response to all the users
         match sub_data
-            .dispatch_to_subscribers(
-                id,
-                response.node_id,
-                &RequestResult::Subscription(resp_clone.content),
-            )
+            .dispatch_to_subscribers(id, response.node_id, &RequestResult::Subscription(content))
             .await
         {
             // Getting true means that we should unsubscribe from the subscription
@@ -88,6 +95,21 @@ pub async fn subscription_dispatcher(
     }
 }
 
+// Send buffered `blutgang_confirmedLogs` events once they're confirmed.
+// Runs every time we get a new head.
+pub async fn confirmed_logs_releaser(
+    sub_data: Arc<SubscriptionData>,
+    mut blocknum_rx: watch::Receiver<u64>,
+    finalized_rx: watch::Receiver<u64>,
+) {
+    while blocknum_rx.changed().await.is_ok() {
+        let head = *blocknum_rx.borrow_and_update();
+        let finalized = *finalized_rx.borrow();
+
+        sub_data.release_confirmed(head, finalized);
+    }
+}
+
 // Moves all subscriptions from one node to another one.
 // Used during node failiure. Do not use this liberally as it is very heavy.
 pub async fn move_subscriptions(
@@ -183,7 +205,13 @@ mod tests {
             .unwrap();
 
         tokio::spawn(async move {
-            let _ = subscription_dispatcher(rx, incoming_tx, Arc::clone(&sub_data)).await;
+            let _ = subscription_dispatcher(
+                rx,
+                incoming_tx,
+                Arc::clone(&sub_data),
+                Arc::new(MiddlewareStack::default()),
+            )
+            .await;
         });
 
         let subscription_content =
@@ -195,9 +223,9 @@ mod tests {
         tx.send(incoming_response).unwrap();
 
         // Check if the user receives the message
-        if let Some(RequestResult::Subscription(msg)) = user_rx.recv().await {
+        if let Some(msg) = user_rx.recv().await.as_ref().and_then(RequestResult::event) {
             assert_eq!(
-                msg,
+                *msg,
                 json!({"method": "eth_subscription", "params": {"subscription": subscription_id}})
             );
         } else {
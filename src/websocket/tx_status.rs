@@ -0,0 +1,305 @@
+// `blutgang_subscribe("txStatus", txHash)` subscriptions.
+//
+// Every new head we poll the receipts of all watched transactions, and send
+// subscribers an event whenever a transaction changes status:
+//
+// {"jsonrpc": "2.0", "method": "blutgang_subscription", "params": {"subscription": "0x..",
+//   "result": {"transactionHash": "0x..", "status": "included", "blockNumber": "0x..", "blockHash": "0x.."}}}
+//
+// Transactions go `pending` -> `included` -> `finalized`, and back to `pending`
+// if the block that included them gets reorged out. They're `replaced` if
+// another transaction used their nonce, and `dropped` if no node has heard of
+// them for a while. Subscriptions end once the transaction is finalized,
+// replaced or dropped.
+use crate::{
+    balancer::selection::select::pick_pending,
+    rpc::types::hex_to_decimal,
+    websocket::types::SubscriptionData,
+    Rpc,
+};
+
+use std::{
+    collections::HashMap,
+    sync::{
+        Arc,
+        RwLock,
+    },
+    time::Duration,
+};
+
+use futures::future::join_all;
+use serde_json::{
+    json,
+    Value,
+};
+use tokio::{
+    sync::watch,
+    time::timeout,
+};
+
+pub const TX_STATUS: &str = "txStatus";
+
+// Heads a transaction can be missing from the mempool before it's dropped
+const DROPPED_AFTER: u32 = 5;
+
+// Transactions a single user can watch at once. We poll every one of them
+// on every head, so this bounds the upstream calls a user can cause.
+pub const MAX_WATCHED_PER_USER: usize = 64;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TxStatus {
+    Pending,
+    Included { number: u64, hash: String },
+    Finalized { number: u64, hash: String },
+    Replaced,
+    Dropped,
+}
+
+impl TxStatus {
+    // No more events after these
+    pub fn is_final(&self) -> bool {
+        matches!(
+            self,
+            TxStatus::Finalized { .. } | TxStatus::Replaced | TxStatus::Dropped
+        )
+    }
+
+    // Event sent to the subscribers of `tx_hash`
+    pub fn to_event(&self, subscription_id: &str, tx_hash: &str) -> Value {
+        let mut result = json!({"transactionHash": tx_hash});
+        let status = match self {
+            TxStatus::Pending => "pending",
+            TxStatus::Included { number, hash } | TxStatus::Finalized { number, hash } => {
+                result["blockNumber"] = json!(format!("0x{:x}", number));
+                result["blockHash"] = json!(hash);
+                if matches!(self, TxStatus::Included { .. }) {
+                    "included"
+                } else {
+                    "finalized"
+                }
+            }
+            TxStatus::Replaced => "replaced",
+            TxStatus::Dropped => "dropped",
+        };
+        result["status"] = json!(status);
+
+        json!({
+            "jsonrpc": "2.0",
+            "method": "blutgang_subscription",
+            "params": {
+                "subscription": subscription_id,
+                "result": result,
+            },
+        })
+    }
+}
+
+// Returns true if `hash` looks like a transaction hash
+pub fn is_tx_hash(hash: &str) -> bool {
+    hash.strip_prefix("0x")
+        .is_some_and(|hash| hash.len() == 64 && hash.bytes().all(|b| b.is_ascii_hexdigit()))
+}
+
+// What we know about a watched transaction between heads
+#[derive(Debug, Default)]
+struct WatchedTx {
+    status: Option<TxStatus>,
+    // Sender and nonce, once a node has told us
+    sender: Option<(String, u64)>,
+    // Heads in a row no node knew about it
+    missing: u32,
+}
+
+async fn call(rpc: &Rpc, method: &str, params: Value, ttl: u128) -> Option<Value> {
+    timeout(
+        Duration::from_millis(ttl.try_into().unwrap_or(u64::MAX)),
+        rpc.call(method, params),
+    )
+    .await
+    .ok()?
+    .ok()
+}
+
+// Current status of `tx_hash`, or None if it didn't change or we couldn't tell
+async fn poll_status(
+    rpc: &Rpc,
+    tx_hash: &str,
+    watched: &mut WatchedTx,
+    finalized: u64,
+    ttl: u128,
+) -> Option<TxStatus> {
+    let receipt = call(rpc, "eth_getTransactionReceipt", json!([tx_hash]), ttl).await?;
+    if !receipt.is_null() {
+        let number = hex_to_decimal(receipt["blockNumber"].as_str()?).ok()?;
+        let hash = receipt["blockHash"].as_str()?.to_lowercase();
+        watched.missing = 0;
+
+        return Some(if finalized != 0 && number <= finalized {
+            TxStatus::Finalized { number, hash }
+        } else {
+            TxStatus::Included { number, hash }
+        });
+    }
+
+    let tx = call(rpc, "eth_getTransactionByHash", json!([tx_hash]), ttl).await?;
+    if !tx.is_null() {
+        if let (Some(from), Some(nonce)) = (tx["from"].as_str(), tx["nonce"].as_str()) {
+            watched.sender = hex_to_decimal(nonce)
+                .ok()
+                .map(|nonce| (from.to_lowercase(), nonce));
+        }
+        watched.missing = 0;
+        return Some(TxStatus::Pending);
+    }
+
+    // Gone from the mempool, replaced if something else used its nonce
+    if let Some((from, nonce)) = &watched.sender {
+        let count = call(rpc, "eth_getTransactionCount", json!([from, "latest"]), ttl).await?;
+        if hex_to_decimal(count.as_str()?).ok()? > *nonce {
+            return Some(TxStatus::Replaced);
+        }
+    }
+
+    watched.missing += 1;
+    (watched.missing >= DROPPED_AFTER).then_some(TxStatus::Dropped)
+}
+
+// Check on watched transactions every time we get a new head
+pub async fn tx_status_watcher(
+    rpc_list: Arc<RwLock<Vec<Rpc>>>,
+    sub_data: Arc<SubscriptionData>,
+    mut blocknum_rx: watch::Receiver<u64>,
+    finalized_rx: watch::Receiver<u64>,
+    ttl: u128,
+) {
+    let mut watched: HashMap<String, WatchedTx> = HashMap::new();
+
+    while blocknum_rx.changed().await.is_ok() {
+        blocknum_rx.borrow_and_update();
+        let finalized = *finalized_rx.borrow();
+
+        let tx_hashes = sub_data.tx_status_hashes();
+        watched.retain(|tx_hash, _| tx_hashes.contains(tx_hash));
+        if tx_hashes.is_empty() {
+            continue;
+        }
+
+        // Nodes with pending state know best what's in the mempool
+        let rpc = {
+            let mut rpc_list = rpc_list.write().unwrap_or_else(|e| e.into_inner());
+            match pick_pending(&mut rpc_list) {
+                (rpc, Some(_)) => rpc,
+                (_, None) => continue,
+            }
+        };
+
+        for tx_hash in tx_hashes {
+            watched.entry(tx_hash).or_default();
+        }
+        let updates = join_all(watched.iter_mut().map(|(tx_hash, watched)| {
+            let rpc = &rpc;
+            async move {
+                let status = poll_status(rpc, tx_hash, watched, finalized, ttl).await?;
+                if watched.status.as_ref() == Some(&status) {
+                    return None;
+                }
+                watched.status = Some(status.clone());
+                Some((tx_hash.clone(), status))
+            }
+        }))
+        .await;
+
+        for (tx_hash, status) in updates.into_iter().flatten() {
+            sub_data.dispatch_tx_status(&tx_hash, &status);
+            if status.is_final() {
+                sub_data.remove_tx_status(&tx_hash);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::node::MockNode;
+
+    const TX_HASH: &str = "0x88df016429689c079f3b2f6ad39fa052532c56795b733da78a91ebe6a713944b";
+
+    #[test]
+    fn test_tx_status_event() {
+        let status = TxStatus::Included {
+            number: 16,
+            hash: "0xb".to_string(),
+        };
+        let event = status.to_event("0x1", TX_HASH);
+
+        assert_eq!(event["method"], "blutgang_subscription");
+        assert_eq!(event["params"]["subscription"], "0x1");
+        assert_eq!(event["params"]["result"]["status"], "included");
+        assert_eq!(event["params"]["result"]["blockNumber"], "0x10");
+        assert_eq!(event["params"]["result"]["transactionHash"], TX_HASH);
+
+        let event = TxStatus::Dropped.to_event("0x1", TX_HASH);
+        assert_eq!(event["params"]["result"]["status"], "dropped");
+        assert!(event["params"]["result"].get("blockNumber").is_none());
+
+        assert!(is_tx_hash(TX_HASH));
+        assert!(!is_tx_hash("0x1234"));
+    }
+
+    #[tokio::test]
+    async fn test_poll_status() {
+        let node = MockNode::spawn(1).await.unwrap();
+        let rpc = Rpc::new(node.http_url(), None, 6, 0, 10.0);
+        let mut watched = WatchedTx::default();
+
+        node.set_response("eth_getTransactionReceipt", Value::Null);
+        node.set_response(
+            "eth_getTransactionByHash",
+            json!({"hash": TX_HASH, "from": "0xAB", "nonce": "0x3"}),
+        );
+        assert_eq!(
+            poll_status(&rpc, TX_HASH, &mut watched, 0, 1000).await,
+            Some(TxStatus::Pending)
+        );
+        assert_eq!(watched.sender, Some(("0xab".to_string(), 3)));
+
+        node.set_response(
+            "eth_getTransactionReceipt",
+            json!({"blockNumber": "0xa", "blockHash": "0xB"}),
+        );
+        assert_eq!(
+            poll_status(&rpc, TX_HASH, &mut watched, 9, 1000).await,
+            Some(TxStatus::Included {
+                number: 10,
+                hash: "0xb".to_string()
+            })
+        );
+        assert!(poll_status(&rpc, TX_HASH, &mut watched, 10, 1000)
+            .await
+            .unwrap()
+            .is_final());
+
+        // Nonce used by something else
+        node.set_response("eth_getTransactionReceipt", Value::Null);
+        node.set_response("eth_getTransactionByHash", Value::Null);
+        node.set_response("eth_getTransactionCount", json!("0x4"));
+        assert_eq!(
+            poll_status(&rpc, TX_HASH, &mut watched, 10, 1000).await,
+            Some(TxStatus::Replaced)
+        );
+
+        // Nobody knows about it
+        let mut watched = WatchedTx::default();
+        for _ in 1..DROPPED_AFTER {
+            assert_eq!(
+                poll_status(&rpc, TX_HASH, &mut watched, 10, 1000).await,
+                None
+            );
+        }
+        assert_eq!(
+            poll_status(&rpc, TX_HASH, &mut watched, 10, 1000).await,
+            Some(TxStatus::Dropped)
+        );
+    }
+}
@@ -7,21 +7,74 @@ use std::{
         Arc,
         RwLock,
     },
+    time::Instant,
 };
 
 use crate::{
+    admin::gauges::FANOUT_BACKLOG,
+    config::{
+        system::DebugModule,
+        types::EventRateLimitSettings,
+    },
+    log_dbg,
     log_info,
     log_wrn,
-    websocket::error::WsError,
+    websocket::{
+        confirmed::ConfirmedLogs,
+        error::WsError,
+        filter::EventFilter,
+        rate_limit::{
+            EventLimiter,
+            Verdict,
+        },
+        reorgs::Reorg,
+        tx_status::{
+            TxStatus,
+            MAX_WATCHED_PER_USER,
+        },
+    },
 };
 use serde_json::Value;
 use tokio::sync::mpsc;
 
+// Subscription event going to every subscriber as is. It's serialized once
+// and the same frame is sent to all of them. We don't negotiate
+// permessage-deflate, so there's nothing else to do per user.
+#[derive(Debug)]
+pub struct SharedEvent {
+    pub event: Value,
+    pub frame: String,
+}
+
+impl SharedEvent {
+    pub fn new(event: Value) -> Arc<Self> {
+        let frame = event.to_string();
+        Arc::new(SharedEvent { event, frame })
+    }
+}
+
 // RequestResult enum
 #[derive(Debug, Clone)]
 pub enum RequestResult {
     Call(Value),
     Subscription(Value),
+    // Subscription event shared by many users
+    Shared(Arc<SharedEvent>),
+    // Close the user's connection, and why
+    Close(String),
+    // Notice for the user, followed by a ping they have to answer
+    Ping(Value),
+}
+
+impl RequestResult {
+    // Subscription event this carries, shared or not
+    pub fn event(&self) -> Option<&Value> {
+        match self {
+            RequestResult::Subscription(event) => Some(event),
+            RequestResult::Shared(shared) => Some(&shared.event),
+            _ => None,
+        }
+    }
 }
 
 impl From<RequestResult> for Value {
@@ -29,6 +82,9 @@ impl From<RequestResult> for Value {
         match req {
             RequestResult::Call(call) => call,
             RequestResult::Subscription(sub) => sub,
+            RequestResult::Shared(shared) => shared.event.clone(),
+            RequestResult::Close(_) => Value::Null,
+            RequestResult::Ping(notice) => notice,
         }
     }
 }
@@ -58,6 +114,9 @@ pub enum WsChannelErr {
 
 pub type UserData = mpsc::UnboundedSender<RequestResult>;
 
+// Upstream subscription id and the confirmed logs buffered from it
+type UpstreamLogs = (String, ConfirmedLogs);
+
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct NodeSubInfo {
     pub node_id: usize,
@@ -70,6 +129,15 @@ pub struct IncomingResponse {
     pub node_id: usize,
 }
 
+// Subscription event for the users of one dispatch shard
+#[derive(Debug)]
+struct DispatchJob {
+    subscription_id: String,
+    message: Arc<RequestResult>,
+    shared: RequestResult,
+    users: Vec<u32>,
+}
+
 // Main struct for storing data related to subscriptions and the associated users
 // TODO: we should probably store more data for the sake of compute performance
 #[derive(Debug, Clone)]
@@ -77,6 +145,28 @@ pub struct SubscriptionData {
     users: Arc<RwLock<HashMap<u32, UserData>>>,
     subscriptions: Arc<RwLock<HashMap<NodeSubInfo, HashSet<u32>>>>,
     incoming_subscriptions: Arc<RwLock<HashMap<String, NodeSubInfo>>>,
+    // When each upstream subscription last produced an event
+    last_event: Arc<RwLock<HashMap<String, Instant>>>,
+    // Blutgang side filters, by user and subscription id
+    filters: Arc<RwLock<HashMap<(u32, String), EventFilter>>>,
+    // Logs waiting for confirmations, by user and the id the user knows the
+    // subscription by, with the upstream subscription they come from
+    confirmed: Arc<RwLock<HashMap<(u32, String), UpstreamLogs>>>,
+    // Reorg subscriptions and the user they belong to
+    reorg_subscriptions: Arc<RwLock<HashMap<String, u32>>>,
+    // Transaction status subscriptions, with their user and transaction hash
+    tx_status_subscriptions: Arc<RwLock<HashMap<String, (u32, String)>>>,
+    // Event budget of every user
+    limiter: Arc<EventLimiter>,
+    // Workers delivering events to the users with `user_id % shards.len()`
+    // equal to their index. Events are delivered by the caller if empty.
+    shards: Arc<Vec<mpsc::UnboundedSender<DispatchJob>>>,
+}
+
+impl Default for SubscriptionData {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl SubscriptionData {
@@ -85,9 +175,54 @@ impl SubscriptionData {
             users: Arc::new(RwLock::new(HashMap::new())),
             subscriptions: Arc::new(RwLock::new(HashMap::new())),
             incoming_subscriptions: Arc::new(RwLock::new(HashMap::new())),
+            last_event: Arc::new(RwLock::new(HashMap::new())),
+            filters: Arc::new(RwLock::new(HashMap::new())),
+            confirmed: Arc::new(RwLock::new(HashMap::new())),
+            reorg_subscriptions: Arc::new(RwLock::new(HashMap::new())),
+            tx_status_subscriptions: Arc::new(RwLock::new(HashMap::new())),
+            limiter: Arc::new(EventLimiter::default()),
+            shards: Arc::new(Vec::new()),
         }
     }
 
+    // Limit how many events every user gets, if set
+    pub fn with_event_rate_limit(mut self, settings: Option<EventRateLimitSettings>) -> Self {
+        self.limiter = Arc::new(EventLimiter::new(settings));
+        self
+    }
+
+    // Deliver subscription events on `workers` tasks, each serving its share
+    // of users, instead of one user after the other on the dispatcher.
+    // Workers see the settings we have when this is called, so call it last.
+    pub fn with_dispatch_workers(mut self, workers: usize) -> Self {
+        // Workers don't hold on to the shards, so they stop once we're dropped
+        let worker_data = SubscriptionData {
+            shards: Arc::new(Vec::new()),
+            ..self.clone()
+        };
+
+        let shards = (0..workers)
+            .map(|_| {
+                let (tx, mut rx) = mpsc::unbounded_channel::<DispatchJob>();
+                let sub_data = worker_data.clone();
+                tokio::task::spawn(async move {
+                    while let Some(job) = rx.recv().await {
+                        sub_data.deliver(
+                            &job.subscription_id,
+                            &job.message,
+                            &job.shared,
+                            &job.users,
+                        );
+                        FANOUT_BACKLOG.sub(job.users.len() as u64);
+                    }
+                });
+                tx
+            })
+            .collect();
+        self.shards = Arc::new(shards);
+        self
+    }
+
     pub fn add_user(&self, user_id: u32, user_data: UserData) {
         let mut users = self.users.write().unwrap_or_else(|e| e.into_inner());
 
@@ -97,6 +232,7 @@ impl SubscriptionData {
     pub fn remove_user(&self, user_id: u32) {
         // Remove the user from all subscriptions before doing anything
         self.unsubscribe_user_from_all(user_id);
+        self.limiter.remove_user(user_id);
 
         let mut users = self.users.write().unwrap_or_else(|e| e.into_inner());
 
@@ -131,6 +267,7 @@ impl SubscriptionData {
             "Register_subscription inserting: {}",
             subscription.to_owned()
         );
+        self.touch_subscription(&subscription_id);
         incoming_subscriptions.insert(
             subscription.to_owned(),
             NodeSubInfo {
@@ -146,7 +283,10 @@ impl SubscriptionData {
             .write()
             .unwrap_or_else(|e| e.into_inner());
 
-        incoming_subscriptions.remove(&subscription_request);
+        if let Some(node_sub_info) = incoming_subscriptions.remove(&subscription_request) {
+            let mut last_event = self.last_event.write().unwrap_or_else(|e| e.into_inner());
+            last_event.remove(&node_sub_info.subscription_id);
+        }
     }
 
     // Subscribe user to existing subscription and return the subscription id
@@ -188,6 +328,17 @@ impl SubscriptionData {
 
     // Unsubscribe a user from a subscription
     pub fn unsubscribe_user(&self, user_id: u32, subscription_id: String) {
+        self.filters
+            .write()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(&(user_id, subscription_id.clone()));
+        self.confirmed
+            .write()
+            .unwrap_or_else(|e| e.into_inner())
+            .retain(|(user, _), (upstream_id, _)| {
+                *user != user_id || *upstream_id != subscription_id
+            });
+
         let mut subscriptions = self
             .subscriptions
             .write()
@@ -203,6 +354,23 @@ impl SubscriptionData {
 
     // Unsubscribe a user from all of their subscriptions
     pub fn unsubscribe_user_from_all(&self, user_id: u32) {
+        self.filters
+            .write()
+            .unwrap_or_else(|e| e.into_inner())
+            .retain(|(user, _), _| *user != user_id);
+        self.confirmed
+            .write()
+            .unwrap_or_else(|e| e.into_inner())
+            .retain(|(user, _), _| *user != user_id);
+        self.reorg_subscriptions
+            .write()
+            .unwrap_or_else(|e| e.into_inner())
+            .retain(|_, user| *user != user_id);
+        self.tx_status_subscriptions
+            .write()
+            .unwrap_or_else(|e| e.into_inner())
+            .retain(|_, (user, _)| *user != user_id);
+
         let mut subscriptions = self
             .subscriptions
             .write()
@@ -234,6 +402,268 @@ impl SubscriptionData {
             })
     }
 
+    // Set the filter applied to events `user_id` receives from `subscription_id`.
+    // `None` removes it.
+    pub fn set_filter(&self, user_id: u32, subscription_id: &str, filter: Option<EventFilter>) {
+        let mut filters = self.filters.write().unwrap_or_else(|e| e.into_inner());
+        let key = (user_id, subscription_id.to_string());
+
+        match filter {
+            Some(filter) => filters.insert(key, filter),
+            None => filters.remove(&key),
+        };
+    }
+
+    // Check `message` against the filter `user_id` set for `subscription_id`, if any
+    fn passes_filter(&self, user_id: u32, subscription_id: &str, message: &RequestResult) -> bool {
+        let filters = self.filters.read().unwrap_or_else(|e| e.into_inner());
+
+        match (
+            filters.get(&(user_id, subscription_id.to_string())),
+            message,
+        ) {
+            (Some(filter), RequestResult::Subscription(event)) => filter.matches(event),
+            _ => true,
+        }
+    }
+
+    // Buffer events `user_id` receives from `subscription_id` until they are
+    // confirmed. Returns the subscription id the user should be given.
+    //
+    // While buffering, the user doesn't receive events from `subscription_id` directly.
+    pub fn add_confirmed(
+        &self,
+        user_id: u32,
+        subscription_id: &str,
+        logs: ConfirmedLogs,
+    ) -> String {
+        let mut confirmed = self.confirmed.write().unwrap_or_else(|e| e.into_inner());
+        let client_id = logs.subscription_id.clone();
+
+        confirmed.insert(
+            (user_id, client_id.clone()),
+            (subscription_id.to_string(), logs),
+        );
+
+        client_id
+    }
+
+    // Stop buffering for the confirmed subscription `client_id`.
+    // Returns the upstream subscription id if it existed.
+    pub fn remove_confirmed(&self, user_id: u32, client_id: &str) -> Option<String> {
+        self.confirmed
+            .write()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(&(user_id, client_id.to_string()))
+            .map(|(upstream_id, _)| upstream_id)
+    }
+
+    // Whether the user has confirmed subscriptions buffering `subscription_id`
+    pub fn has_confirmed(&self, user_id: u32, subscription_id: &str) -> bool {
+        self.confirmed
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .iter()
+            .any(|((user, _), (upstream_id, _))| *user == user_id && upstream_id == subscription_id)
+    }
+
+    // Buffer `message` if the user is waiting for confirmations on `subscription_id`
+    fn buffer_confirmed(
+        &self,
+        user_id: u32,
+        subscription_id: &str,
+        message: &RequestResult,
+    ) -> bool {
+        let event = match message {
+            RequestResult::Subscription(event) => event,
+            _ => return false,
+        };
+
+        let mut confirmed = self.confirmed.write().unwrap_or_else(|e| e.into_inner());
+        let mut buffered = false;
+        for ((user, _), (upstream_id, logs)) in confirmed.iter_mut() {
+            if *user == user_id && upstream_id == subscription_id {
+                logs.push(event);
+                buffered = true;
+            }
+        }
+        buffered
+    }
+
+    // Send all logs that are confirmed at `head`/`finalized` to their users
+    pub fn release_confirmed(&self, head: u64, finalized: u64) {
+        let mut confirmed = self.confirmed.write().unwrap_or_else(|e| e.into_inner());
+        let users = self.users.read().unwrap_or_else(|e| e.into_inner());
+
+        for ((user_id, _), (_, logs)) in confirmed.iter_mut() {
+            let user = match users.get(user_id) {
+                Some(user) => user,
+                None => continue,
+            };
+
+            for event in logs.release(head, finalized) {
+                let _ = user.send(RequestResult::Subscription(event));
+            }
+        }
+    }
+
+    // Whether events over a user's budget are held back to be sent later
+    pub fn coalesces_events(&self) -> bool {
+        self.limiter.is_coalescing()
+    }
+
+    // Send held back events to users that have budget for them again
+    pub fn release_withheld(&self) {
+        let users = self.users.read().unwrap_or_else(|e| e.into_inner());
+
+        for (user_id, event) in self.limiter.release() {
+            if let Some(user) = users.get(&user_id) {
+                let _ = user.send(RequestResult::Subscription(event));
+            }
+        }
+    }
+
+    // Bytes held back for confirmed logs subscriptions
+    pub fn buffered_bytes(&self) -> usize {
+        let confirmed = self.confirmed.read().unwrap_or_else(|e| e.into_inner());
+        confirmed
+            .values()
+            .map(|(_, logs)| logs.buffered_bytes())
+            .sum()
+    }
+
+    // Connected users
+    pub fn user_count(&self) -> usize {
+        self.users.read().unwrap_or_else(|e| e.into_inner()).len()
+    }
+
+    // Subscriptions we have open with our nodes
+    pub fn subscription_count(&self) -> usize {
+        self.subscriptions
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .len()
+    }
+
+    // Subscribe a user to reorg events and return the subscription id
+    pub fn subscribe_reorgs(&self, user_id: u32) -> String {
+        let mut reorg_subscriptions = self
+            .reorg_subscriptions
+            .write()
+            .unwrap_or_else(|e| e.into_inner());
+        let subscription_id = format!("0x{:032x}", rand::random::<u128>());
+
+        reorg_subscriptions.insert(subscription_id.clone(), user_id);
+
+        subscription_id
+    }
+
+    // Returns false if the user had no such reorg subscription
+    pub fn unsubscribe_reorgs(&self, user_id: u32, subscription_id: &str) -> bool {
+        let mut reorg_subscriptions = self
+            .reorg_subscriptions
+            .write()
+            .unwrap_or_else(|e| e.into_inner());
+
+        if reorg_subscriptions.get(subscription_id) != Some(&user_id) {
+            return false;
+        }
+
+        reorg_subscriptions.remove(subscription_id).is_some()
+    }
+
+    // Send `reorg` to everyone subscribed to reorgs
+    pub fn dispatch_reorg(&self, reorg: &Reorg) {
+        let reorg_subscriptions = self
+            .reorg_subscriptions
+            .read()
+            .unwrap_or_else(|e| e.into_inner());
+        let users = self.users.read().unwrap_or_else(|e| e.into_inner());
+
+        for (subscription_id, user_id) in reorg_subscriptions.iter() {
+            if let Some(user) = users.get(user_id) {
+                let _ = user.send(RequestResult::Subscription(reorg.to_event(subscription_id)));
+            }
+        }
+    }
+
+    // Subscribe a user to status updates of `tx_hash` and return the subscription id.
+    // Returns None if the user is already watching `MAX_WATCHED_PER_USER` transactions.
+    pub fn subscribe_tx_status(&self, user_id: u32, tx_hash: &str) -> Option<String> {
+        let mut tx_status_subscriptions = self
+            .tx_status_subscriptions
+            .write()
+            .unwrap_or_else(|e| e.into_inner());
+        let watching = tx_status_subscriptions
+            .values()
+            .filter(|(subscriber, _)| *subscriber == user_id)
+            .count();
+        if watching >= MAX_WATCHED_PER_USER {
+            return None;
+        }
+        let subscription_id = format!("0x{:032x}", rand::random::<u128>());
+
+        tx_status_subscriptions.insert(subscription_id.clone(), (user_id, tx_hash.to_lowercase()));
+
+        Some(subscription_id)
+    }
+
+    // Returns false if the user had no such transaction status subscription
+    pub fn unsubscribe_tx_status(&self, user_id: u32, subscription_id: &str) -> bool {
+        let mut tx_status_subscriptions = self
+            .tx_status_subscriptions
+            .write()
+            .unwrap_or_else(|e| e.into_inner());
+
+        if tx_status_subscriptions
+            .get(subscription_id)
+            .map(|(user, _)| *user)
+            != Some(user_id)
+        {
+            return false;
+        }
+
+        tx_status_subscriptions.remove(subscription_id).is_some()
+    }
+
+    // Transactions someone is waiting on
+    pub fn tx_status_hashes(&self) -> HashSet<String> {
+        self.tx_status_subscriptions
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .values()
+            .map(|(_, tx_hash)| tx_hash.clone())
+            .collect()
+    }
+
+    // Send `status` to everyone subscribed to `tx_hash`
+    pub fn dispatch_tx_status(&self, tx_hash: &str, status: &TxStatus) {
+        let tx_status_subscriptions = self
+            .tx_status_subscriptions
+            .read()
+            .unwrap_or_else(|e| e.into_inner());
+        let users = self.users.read().unwrap_or_else(|e| e.into_inner());
+
+        for (subscription_id, (user_id, _)) in tx_status_subscriptions
+            .iter()
+            .filter(|(_, (_, hash))| hash == tx_hash)
+        {
+            if let Some(user) = users.get(user_id) {
+                let _ = user.send(RequestResult::Subscription(
+                    status.to_event(subscription_id, tx_hash),
+                ));
+            }
+        }
+    }
+
+    // Drop all subscriptions to `tx_hash`
+    pub fn remove_tx_status(&self, tx_hash: &str) {
+        self.tx_status_subscriptions
+            .write()
+            .unwrap_or_else(|e| e.into_inner())
+            .retain(|_, (_, hash)| hash != tx_hash);
+    }
+
     // Return all sub ids for a given node_id
     pub fn get_sub_id_by_node(&self, node_id: usize) -> Vec<String> {
         let incoming_subscriptions = self
@@ -242,8 +672,8 @@ impl SubscriptionData {
             .unwrap_or_else(|e| e.into_inner());
 
         incoming_subscriptions
-            .iter()
-            .filter_map(|(_, node_sub_info)| {
+            .values()
+            .filter_map(|node_sub_info| {
                 if node_sub_info.node_id == node_id {
                     Some(node_sub_info.subscription_id.to_owned())
                 } else {
@@ -278,6 +708,30 @@ impl SubscriptionData {
             .collect()
     }
 
+    // Return the node and id of the subscription made with `params`
+    pub fn get_node_sub_info_by_params(&self, params: &str) -> Option<NodeSubInfo> {
+        let incoming_subscriptions = self
+            .incoming_subscriptions
+            .read()
+            .unwrap_or_else(|e| e.into_inner());
+
+        incoming_subscriptions.get(params).cloned()
+    }
+
+    // Mark that a subscription just produced an event
+    pub fn touch_subscription(&self, subscription_id: &str) {
+        let mut last_event = self.last_event.write().unwrap_or_else(|e| e.into_inner());
+
+        last_event.insert(subscription_id.to_string(), Instant::now());
+    }
+
+    // Return when a subscription last produced an event, or when it was registered
+    pub fn last_event(&self, subscription_id: &str) -> Option<Instant> {
+        let last_event = self.last_event.read().unwrap_or_else(|e| e.into_inner());
+
+        last_event.get(subscription_id).copied()
+    }
+
     pub fn get_sub_id_by_params(&self, params: &str) -> Option<String> {
         let incoming_subscriptions = self
             .incoming_subscriptions
@@ -340,53 +794,137 @@ impl SubscriptionData {
         Ok(())
     }
 
+    // Send `shared` to every one of `user_ids` that wants `message`, the event
+    // it was made from
+    fn deliver(
+        &self,
+        subscription_id: &str,
+        message: &RequestResult,
+        shared: &RequestResult,
+        user_ids: &[u32],
+    ) {
+        let event = match message.event() {
+            Some(event) => event,
+            None => return,
+        };
+
+        let mut disconnected = Vec::new();
+        let users = self.users.read().unwrap_or_else(|e| e.into_inner());
+        for &user_id in user_ids {
+            // Skip events the user filtered out
+            if !self.passes_filter(user_id, subscription_id, message) {
+                continue;
+            }
+
+            // Held back until it has enough confirmations
+            if self.buffer_confirmed(user_id, subscription_id, message) {
+                continue;
+            }
+
+            // Over the user's event budget
+            match self.limiter.check(user_id, subscription_id, event) {
+                Verdict::Deliver => {}
+                Verdict::Withhold => continue,
+                Verdict::Disconnect => {
+                    disconnected.push(user_id);
+                    continue;
+                }
+            }
+
+            if let Some(user) = users.get(&user_id) {
+                log_dbg!(
+                    DebugModule::Ws,
+                    "Sending user_id {:?} subscription: {:?}",
+                    user_id,
+                    message
+                );
+                match user.send(shared.clone()) {
+                    Ok(_) => {}
+                    Err(_) => {
+                        log_wrn!(
+                            "user_id {} unsubscribed without closing channel! Removing.",
+                            user_id
+                        );
+                        let _ = &self.unsubscribe_user(user_id, subscription_id.to_string());
+                    }
+                };
+            }
+        }
+
+        for user_id in disconnected {
+            log_wrn!(
+                "user_id {} went over its event rate limit! Disconnecting.",
+                user_id
+            );
+            if let Some(user) = users.get(&user_id) {
+                let _ = user.send(RequestResult::Close(
+                    "Subscription event rate limit exceeded".to_string(),
+                ));
+            }
+            self.unsubscribe_user_from_all(user_id);
+        }
+    }
+
     pub async fn dispatch_to_subscribers(
         &self,
         subscription_id: &str,
         node_id: usize,
         message: &RequestResult,
     ) -> Result<bool, WsError> {
-        if let RequestResult::Call(_) = message {
-            return Err(WsError::InvalidData(
-                "Trying to send a call as a subscription!".to_string(),
-            ));
-        }
+        let event = match message {
+            RequestResult::Subscription(event) => event,
+            _ => {
+                return Err(WsError::InvalidData(
+                    "Trying to send a call as a subscription!".to_string(),
+                ))
+            }
+        };
+
+        self.touch_subscription(subscription_id);
 
         let node_sub_info = NodeSubInfo {
             node_id,
             subscription_id: subscription_id.to_string(),
         };
 
-        let users = self.users.read().unwrap_or_else(|e| e.into_inner());
-        if let Some(subscribers) = self.subscriptions.read().unwrap().get(&node_sub_info) {
-            if subscribers.is_empty() {
-                self.unregister_subscription(subscription_id.to_string());
-                println!(
-                    "No more users to send subscription to. Unsubscribing from ID: {}",
-                    subscription_id
-                );
-                return Ok(true);
-            }
-            for &user_id in subscribers {
-                if let Some(user) = users.get(&user_id) {
-                    #[cfg(feature = "debug-verbose")]
-                    println!(
-                        "Sending user_id {:?} subscription: {:?}",
-                        user_id,
-                        message.clone()
-                    );
-                    match user.send(message.clone()) {
-                        Ok(_) => {}
-                        Err(_) => {
-                            log_wrn!(
-                                "user_id {} unsubscribed without closing channel! Removing.",
-                                user_id
-                            );
-                            let _ = &self.unsubscribe_user(user_id, subscription_id.to_string());
-                        }
-                    };
-                }
+        // Don't hold on to the subscription while we go through its users
+        let subscribers: Vec<u32> = match self.subscriptions.read().unwrap().get(&node_sub_info) {
+            Some(subscribers) => subscribers.iter().copied().collect(),
+            None => return Ok(false),
+        };
+        if subscribers.is_empty() {
+            self.unregister_subscription(subscription_id.to_string());
+            println!(
+                "No more users to send subscription to. Unsubscribing from ID: {}",
+                subscription_id
+            );
+            return Ok(true);
+        }
+
+        // Serialized once for everyone who gets it
+        let shared = RequestResult::Shared(SharedEvent::new(event.clone()));
+        if self.shards.is_empty() {
+            self.deliver(subscription_id, message, &shared, &subscribers);
+            return Ok(false);
+        }
+
+        // Users always land on the same shard, so they get events in order
+        let mut slices = vec![Vec::new(); self.shards.len()];
+        for user_id in subscribers {
+            slices[user_id as usize % self.shards.len()].push(user_id);
+        }
+        let message = Arc::new(message.clone());
+        for (shard, users) in self.shards.iter().zip(slices) {
+            if users.is_empty() {
+                continue;
             }
+            FANOUT_BACKLOG.add(users.len() as u64);
+            let _ = shard.send(DispatchJob {
+                subscription_id: subscription_id.to_string(),
+                message: message.clone(),
+                shared: shared.clone(),
+                users,
+            });
         }
 
         Ok(false)
@@ -396,6 +934,7 @@ impl SubscriptionData {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::websocket::confirmed::Confirmation;
     use serde_json::json;
 
     fn setup_user_and_subscription_data() -> (
@@ -556,9 +1095,211 @@ mod tests {
             .unwrap();
 
         match rx.recv().await {
-            Some(RequestResult::Subscription(msg)) => assert_eq!(msg, "test message"),
+            Some(msg) => assert_eq!(msg.event().unwrap(), "test message"),
+            _ => panic!("Expected to receive a subscription message"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_dispatch_shares_frame() {
+        let (subscription_data, user_id, mut rx) = setup_user_and_subscription_data();
+        let (tx, mut other_rx) = mpsc::unbounded_channel();
+        subscription_data.add_user(user_id + 1, tx);
+        let subscription_request =
+            json!({"jsonrpc":"2.0","id": 2, "method": "eth_subscribe", "params": ["newHeads"]});
+        subscription_data.register_subscription(subscription_request.clone(), "300".to_string(), 1);
+        for user_id in [user_id, user_id + 1] {
+            subscription_data
+                .subscribe_user(user_id, subscription_request.clone())
+                .unwrap();
+        }
+
+        let event = json!({"method": "eth_subscription", "params": {"subscription": "300"}});
+        subscription_data
+            .dispatch_to_subscribers("300", 1, &RequestResult::Subscription(event.clone()))
+            .await
+            .unwrap();
+
+        // Both users get the same bytes, serialized once
+        match (rx.recv().await, other_rx.recv().await) {
+            (Some(RequestResult::Shared(first)), Some(RequestResult::Shared(second))) => {
+                assert!(Arc::ptr_eq(&first, &second));
+                assert_eq!(first.frame, event.to_string());
+            }
+            _ => panic!("Expected both users to receive the shared event"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_dispatch_workers() {
+        let subscription_data = SubscriptionData::new().with_dispatch_workers(4);
+        let subscription_request =
+            json!({"jsonrpc":"2.0","id": 2, "method": "eth_subscribe", "params": ["newHeads"]});
+        subscription_data.register_subscription(subscription_request.clone(), "300".to_string(), 1);
+
+        let mut receivers = Vec::new();
+        for user_id in 0..10 {
+            let (tx, rx) = mpsc::unbounded_channel();
+            subscription_data.add_user(user_id, tx);
+            subscription_data
+                .subscribe_user(user_id, subscription_request.clone())
+                .unwrap();
+            receivers.push(rx);
+        }
+
+        for number in 0..3 {
+            let message = RequestResult::Subscription(json!({"params": {"result": number}}));
+            subscription_data
+                .dispatch_to_subscribers("300", 1, &message)
+                .await
+                .unwrap();
+        }
+
+        // Every user gets every event, in order, whichever worker has them
+        for rx in receivers.iter_mut() {
+            for number in 0..3 {
+                let msg = rx.recv().await.unwrap();
+                assert_eq!(msg.event().unwrap()["params"]["result"], number);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_dispatch_over_event_rate_limit() {
+        let subscription_data =
+            SubscriptionData::new().with_event_rate_limit(Some(EventRateLimitSettings {
+                events_per_second: 1.0,
+                burst: 1.0,
+                policy: crate::config::types::EventLimitPolicy::Disconnect,
+            }));
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        subscription_data.add_user(100, tx);
+        let subscription_request =
+            json!({"jsonrpc":"2.0","id": 2, "method": "eth_subscribe", "params": ["newHeads"]});
+        subscription_data.register_subscription(subscription_request.clone(), "300".to_string(), 1);
+        subscription_data
+            .subscribe_user(100, subscription_request)
+            .unwrap();
+
+        let message = RequestResult::Subscription(json!("head"));
+        for _ in 0..2 {
+            subscription_data
+                .dispatch_to_subscribers("300", 1, &message)
+                .await
+                .unwrap();
+        }
+
+        assert!(rx.recv().await.unwrap().event().is_some());
+        assert!(matches!(rx.recv().await, Some(RequestResult::Close(_))));
+        assert!(subscription_data
+            .get_users_for_subscription("300")
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_dispatch_with_filter() {
+        let (subscription_data, user_id, mut rx) = setup_user_and_subscription_data();
+        let mut subscription_request = json!({
+            "jsonrpc": "2.0",
+            "id": 2,
+            "method": "eth_subscribe",
+            "params": ["logs", {"blutgang": {"addresses": ["0x01"]}}],
+        });
+        let filter = EventFilter::from_subscription(&mut subscription_request).unwrap();
+        let subscription_id = "400".to_string();
+        let node_id = 1;
+
+        subscription_data.register_subscription(
+            subscription_request.clone(),
+            subscription_id.clone(),
+            node_id,
+        );
+        subscription_data
+            .subscribe_user(user_id, subscription_request)
+            .unwrap();
+        subscription_data.set_filter(user_id, &subscription_id, filter);
+
+        for address in ["0x02", "0x01"] {
+            let message = RequestResult::Subscription(json!({
+                "method": "eth_subscription",
+                "params": {"subscription": "400", "result": {"address": address}},
+            }));
+            subscription_data
+                .dispatch_to_subscribers(&subscription_id, node_id, &message)
+                .await
+                .unwrap();
+        }
+
+        // Only the event from 0x01 should make it through
+        match rx.try_recv() {
+            Ok(msg) => {
+                assert_eq!(msg.event().unwrap()["params"]["result"]["address"], "0x01")
+            }
             _ => panic!("Expected to receive a subscription message"),
         }
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_dispatch_confirmed() {
+        let (subscription_data, user_id, mut rx) = setup_user_and_subscription_data();
+        let subscription_request = json!({
+            "jsonrpc": "2.0",
+            "id": 2,
+            "method": "eth_subscribe",
+            "params": ["logs", {}],
+        });
+        let subscription_id = "500".to_string();
+        let node_id = 1;
+
+        subscription_data.register_subscription(
+            subscription_request.clone(),
+            subscription_id.clone(),
+            node_id,
+        );
+        subscription_data
+            .subscribe_user(user_id, subscription_request)
+            .unwrap();
+
+        // Two confirmed subscriptions sharing the same upstream one
+        let shallow = subscription_data.add_confirmed(
+            user_id,
+            &subscription_id,
+            ConfirmedLogs::new(Confirmation::Blocks(1)),
+        );
+        let deep = subscription_data.add_confirmed(
+            user_id,
+            &subscription_id,
+            ConfirmedLogs::new(Confirmation::Blocks(5)),
+        );
+
+        let message = RequestResult::Subscription(json!({
+            "method": "eth_subscription",
+            "params": {"subscription": "500", "result": {"blockNumber": "0xa"}},
+        }));
+        subscription_data
+            .dispatch_to_subscribers(&subscription_id, node_id, &message)
+            .await
+            .unwrap();
+        assert!(rx.try_recv().is_err());
+
+        subscription_data.release_confirmed(11, 0);
+        let released = rx.try_recv().unwrap();
+        assert_eq!(released.event().unwrap()["params"]["subscription"], shallow);
+        assert!(rx.try_recv().is_err());
+
+        // Removing one leaves the other buffering
+        assert_eq!(
+            subscription_data.remove_confirmed(user_id, &shallow),
+            Some(subscription_id.clone())
+        );
+        assert!(subscription_data.has_confirmed(user_id, &subscription_id));
+        subscription_data.release_confirmed(15, 0);
+        let released = rx.try_recv().unwrap();
+        assert_eq!(released.event().unwrap()["params"]["subscription"], deep);
+
+        assert!(subscription_data.remove_confirmed(user_id, &deep).is_some());
+        assert!(!subscription_data.has_confirmed(user_id, &subscription_id));
     }
 
     #[tokio::test]
@@ -672,6 +1413,13 @@ mod tests {
             users: Arc::new(RwLock::new(HashMap::new())),
             subscriptions: Arc::new(RwLock::new(HashMap::new())),
             incoming_subscriptions: Arc::new(RwLock::new(HashMap::new())),
+            last_event: Arc::new(RwLock::new(HashMap::new())),
+            filters: Arc::new(RwLock::new(HashMap::new())),
+            confirmed: Arc::new(RwLock::new(HashMap::new())),
+            reorg_subscriptions: Arc::new(RwLock::new(HashMap::new())),
+            tx_status_subscriptions: Arc::new(RwLock::new(HashMap::new())),
+            limiter: Arc::new(EventLimiter::default()),
+            shards: Arc::new(Vec::new()),
         };
 
         // Mock subscription data
@@ -809,8 +1557,8 @@ mod tests {
         // Ensure there are no subscribers to the moved subscription
         let subscriptions = subscription_data.subscriptions.read().unwrap();
         assert!(
-            subscriptions.get(&node_sub_info).is_none()
-                || subscriptions.get(&node_sub_info).unwrap().is_empty()
+            subscriptions.get(node_sub_info).is_none()
+                || subscriptions.get(node_sub_info).unwrap().is_empty()
         );
     }
 